@@ -1,16 +1,105 @@
+use std::time::Duration;
+
 use anyhow::Context as _;
 use axum::{
     Json,
     body::Body,
-    http::{Response, StatusCode},
+    http::{Response, StatusCode, header::RETRY_AFTER},
     response::IntoResponse,
 };
+use schemars::JsonSchema;
 use serde::Serialize;
 
+/// Machine-readable error codes returned in the `code` field of error responses. Agents use these to distinguish
+/// errors they should retry later from errors that require them to stop sending requests.
+#[derive(Clone, Copy, Debug, Eq, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// `429 Too Many Requests`: The account has exceeded a usage quota. Retry after the `Retry-After` period.
+    QuotaExceeded,
+    /// `403 Forbidden`: The credential is not permitted to perform the request. Do not retry.
+    ScopeViolation,
+    /// `403 Forbidden`: The account has been suspended. Stop sending requests.
+    AccountSuspended,
+    /// `503 Service Unavailable`: Ingestion is temporarily paused for the account. Retry after the `Retry-After`
+    /// period.
+    IngestionPaused,
+    /// `422 Unprocessable Entity`: The payload schema version is not supported by this backend. Do not retry without
+    /// changing the payload schema version.
+    SchemaVersionUnsupported,
+    /// `429 Too Many Requests`: Storage is temporarily throttling the account. Retry after the `Retry-After` period.
+    StorageThrottled,
+    /// `429 Too Many Requests`: The credential exceeded its request rate limit. Retry after the `Retry-After` period.
+    RateLimited,
+    /// `401 Unauthorized`: The action requires the user to have signed in recently. Sign in again before retrying.
+    ReauthenticationRequired,
+    /// `413 Payload Too Large`: The request exceeds a limit described by the `limit` field. Do not retry without
+    /// following its remediation.
+    LimitExceeded,
+    /// `402 Payment Required`: The account has reached a quota of its plan. Do not retry until the account's usage is
+    /// reduced or its plan is upgraded.
+    PlanQuotaExceeded,
+    /// `503 Service Unavailable`: The backend instance is a read-only standby and refuses writes. Retry after the
+    /// `Retry-After` period.
+    ReadOnlyStandby,
+    /// `409 Conflict`: The report's sequence number is not higher than the highest sequence number already ingested
+    /// with its report key, so it is a replay or was sent by a cloned agent. Do not retry.
+    SequenceRegression,
+}
+
+impl ErrorCode {
+    #[must_use]
+    pub fn status_code(self) -> StatusCode {
+        match self {
+            ErrorCode::QuotaExceeded | ErrorCode::StorageThrottled | ErrorCode::RateLimited => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            ErrorCode::ScopeViolation | ErrorCode::AccountSuspended => StatusCode::FORBIDDEN,
+            ErrorCode::IngestionPaused | ErrorCode::ReadOnlyStandby => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ErrorCode::SchemaVersionUnsupported => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::ReauthenticationRequired => StatusCode::UNAUTHORIZED,
+            ErrorCode::LimitExceeded => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::PlanQuotaExceeded => StatusCode::PAYMENT_REQUIRED,
+            ErrorCode::SequenceRegression => StatusCode::CONFLICT,
+        }
+    }
+
+    #[must_use]
+    pub fn is_retryable(self) -> bool {
+        matches!(
+            self,
+            ErrorCode::QuotaExceeded
+                | ErrorCode::IngestionPaused
+                | ErrorCode::StorageThrottled
+                | ErrorCode::RateLimited
+                | ErrorCode::ReadOnlyStandby
+        )
+    }
+}
+
+/// Limit exceeded by a request, returned in the `limit` field of `limit_exceeded` error responses
+#[derive(Clone, Debug, JsonSchema, Serialize)]
+pub struct ExceededLimit {
+    /// Name of the limit, e.g. `report_body_bytes`
+    pub name: &'static str,
+    /// Largest value allowed by the limit
+    pub limit: u64,
+    /// Value of the request, if known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub observed: Option<u64>,
+    /// How to bring the request within the limit
+    pub remediation: &'static str,
+}
+
 #[derive(Debug)]
 pub struct PublicError {
     status_code: axum::http::StatusCode,
+    code: Option<ErrorCode>,
     message: String,
+    retry_after: Option<Duration>,
+    limit: Option<ExceededLimit>,
 }
 
 // Generates strings like "409 Conflict: Account already exists"
@@ -24,28 +113,82 @@ impl PublicError {
     pub fn new<S: Into<String>>(status_code: StatusCode, message: S) -> Self {
         Self {
             status_code,
+            code: None,
+            message: message.into(),
+            retry_after: None,
+            limit: None,
+        }
+    }
+
+    pub fn with_code<S: Into<String>>(code: ErrorCode, message: S) -> Self {
+        Self {
+            status_code: code.status_code(),
+            code: Some(code),
             message: message.into(),
+            retry_after: None,
+            limit: None,
+        }
+    }
+
+    pub fn limit_exceeded<S: Into<String>>(message: S, limit: ExceededLimit) -> Self {
+        Self {
+            limit: Some(limit),
+            ..Self::with_code(ErrorCode::LimitExceeded, message)
         }
     }
+
+    #[must_use]
+    pub fn retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = Some(retry_after);
+        self
+    }
 }
 
 pub type Result<T> = std::result::Result<T, PublicError>;
 
+/// Body of error responses
+#[derive(Clone, JsonSchema, Serialize)]
+pub struct PublicErrorMessage {
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<ErrorCode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retryable: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    limit: Option<ExceededLimit>,
+    /// ID of the request, also returned in the `X-Request-ID` response header. Include it when reporting errors.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+impl PublicErrorMessage {
+    pub fn set_request_id(&mut self, request_id: String) {
+        self.request_id = Some(request_id);
+    }
+}
+
 // Tell axum how to convert `Error` into a response.
 impl IntoResponse for PublicError {
     fn into_response(self) -> Response<Body> {
-        #[derive(Serialize)]
-        struct PublicErrorMessage {
-            message: String,
+        let message = PublicErrorMessage {
+            message: self.message,
+            code: self.code,
+            retryable: self.code.map(ErrorCode::is_retryable),
+            limit: self.limit,
+            request_id: None,
+        };
+
+        // Kept in the response extensions so middleware can add the request ID to the body
+        let mut response = (self.status_code, Json(message.clone())).into_response();
+        response.extensions_mut().insert(message);
+
+        if let Some(retry_after) = self.retry_after {
+            // Retry-After is expressed in whole seconds, so round up to avoid clients retrying too early
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            response.headers_mut().insert(RETRY_AFTER, seconds.into());
         }
 
-        (
-            self.status_code,
-            Json(PublicErrorMessage {
-                message: self.message,
-            }),
-        )
-            .into_response()
+        response
     }
 }
 
@@ -65,7 +208,7 @@ where
             };
         }
 
-        eprintln!("{err:?}\n\n");
+        tracing::error!(?err, "Internal server error");
 
         Self::new(
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -150,6 +293,16 @@ macro_rules! conflict {
         };
     }
 
+#[macro_export]
+macro_rules! coded_error {
+        ($code:expr, $msg:literal $(,)?) => {
+            $crate::bail!($crate::PublicError::with_code($code, format!($msg)))
+        };
+        ($code:expr, $fmt:expr, $($arg:tt)*) => {
+            $crate::bail!($crate::PublicError::with_code($code, format!($fmt, $($arg)*)))
+        };
+    }
+
 pub mod anyhow {
     pub use anyhow::Context;
     pub use anyhow::Error;
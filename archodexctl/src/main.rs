@@ -0,0 +1,524 @@
+//! Operator CLI for the Archodex backend. Commands call the same dashboard API the Archodex Dashboard uses and print
+//! responses as JSON. `migrate` and `bundle import` call the admin API instead, authenticated with the instance's admin
+//! token.
+//!
+//! Profiles are read from `$ARCHODEXCTL_CONFIG`, or `~/.config/archodexctl/config.json` by default:
+//!
+//! ```json
+//! { "profiles": { "default": { "endpoint": "https://api.archodex.com", "access_token": "...", "admin_token": "..." } } }
+//! ```
+//!
+//! `ARCHODEX_ACCESS_TOKEN` and `ARCHODEX_ADMIN_TOKEN` override the tokens of the selected profile.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write as _,
+    time::Duration,
+};
+
+use anyhow::{Context as _, anyhow, bail};
+use reqwest::Method;
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+const USAGE: &str = "Usage: archodexctl [--profile <name>] <command>
+
+Commands:
+  accounts list
+  keys list <account id>
+  keys create <account id> [description]
+  keys reveal <account id> <report key id> <reveal token>
+  keys revoke <account id> <report key id> [--hard]
+  keys restore <account id> <report key id>
+  keys rotate <account id> <report key id> [--grace-period-hours <hours>]
+  members list <account id>
+  health <account id>
+  statements <account id>
+  export <account id> [json|graphml|dot] [--encrypted]
+  audit <account id> [--follow]
+  migrate <account id>
+  bundle import <account id> <bundle file>";
+
+const AUDIT_FOLLOW_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize)]
+struct Config {
+    profiles: HashMap<String, Profile>,
+}
+
+#[derive(Deserialize)]
+struct Profile {
+    endpoint: String,
+    access_token: Option<String>,
+    admin_token: Option<String>,
+}
+
+fn config_path() -> anyhow::Result<String> {
+    if let Ok(path) = std::env::var("ARCHODEXCTL_CONFIG") {
+        return Ok(path);
+    }
+
+    let home = std::env::var("HOME").context("HOME is not set, set ARCHODEXCTL_CONFIG instead")?;
+
+    Ok(format!("{home}/.config/archodexctl/config.json"))
+}
+
+fn load_profile(name: &str) -> anyhow::Result<Profile> {
+    let path = config_path()?;
+    let config = std::fs::read(&path).with_context(|| format!("Failed to read config {path}"))?;
+    let mut config = serde_json::from_slice::<Config>(&config)
+        .with_context(|| format!("Failed to parse config {path}"))?;
+
+    let Some(mut profile) = config.profiles.remove(name) else {
+        bail!("Profile {name:?} not found in {path}");
+    };
+
+    if let Ok(access_token) = std::env::var("ARCHODEX_ACCESS_TOKEN") {
+        profile.access_token = Some(access_token);
+    }
+
+    if let Ok(admin_token) = std::env::var("ARCHODEX_ADMIN_TOKEN") {
+        profile.admin_token = Some(admin_token);
+    }
+
+    profile.endpoint = profile.endpoint.trim_end_matches('/').to_owned();
+
+    Ok(profile)
+}
+
+// Dashboard API requests are authenticated with the user's access token, and admin API requests with the admin token
+#[derive(Clone, Copy)]
+enum Token {
+    Access,
+    Admin,
+}
+
+struct Client {
+    http: reqwest::Client,
+    profile_name: String,
+    profile: Profile,
+}
+
+fn parse_body(bytes: &[u8]) -> Value {
+    if bytes.is_empty() {
+        Value::Null
+    } else {
+        serde_json::from_slice(bytes)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(bytes).into_owned()))
+    }
+}
+
+impl Client {
+    fn token(&self, token: Token) -> anyhow::Result<&str> {
+        let (token, field, var) = match token {
+            Token::Access => (
+                &self.profile.access_token,
+                "access_token",
+                "ARCHODEX_ACCESS_TOKEN",
+            ),
+            Token::Admin => (
+                &self.profile.admin_token,
+                "admin_token",
+                "ARCHODEX_ADMIN_TOKEN",
+            ),
+        };
+
+        token.as_deref().ok_or_else(|| {
+            anyhow!(
+                "Profile {:?} has no {field} and {var} is not set",
+                self.profile_name
+            )
+        })
+    }
+
+    // Returns the response once its status is known to be successful, so its body can be read or streamed
+    async fn send(
+        &self,
+        token: Token,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> anyhow::Result<reqwest::Response> {
+        let mut request = self
+            .http
+            .request(method.clone(), format!("{}{path}", self.profile.endpoint))
+            .bearer_auth(self.token(token)?);
+
+        if let Some(body) = body {
+            request = request
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(serde_json::to_vec(&body)?);
+        }
+
+        let response = request
+            .send()
+            .await
+            .with_context(|| format!("Failed to send {method} {path} request"))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let bytes = response
+                .bytes()
+                .await
+                .with_context(|| format!("Failed to read {method} {path} response"))?;
+
+            bail!(
+                "{method} {path} failed with {status}: {}",
+                parse_body(&bytes)
+            );
+        }
+
+        Ok(response)
+    }
+
+    async fn request_with(
+        &self,
+        token: Token,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> anyhow::Result<Value> {
+        let bytes = self
+            .send(token, method.clone(), path, body)
+            .await?
+            .bytes()
+            .await
+            .with_context(|| format!("Failed to read {method} {path} response"))?;
+
+        Ok(parse_body(&bytes))
+    }
+
+    async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> anyhow::Result<Value> {
+        self.request_with(Token::Access, method, path, body).await
+    }
+
+    async fn admin_request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> anyhow::Result<Value> {
+        self.request_with(Token::Admin, method, path, body).await
+    }
+
+    async fn get(&self, path: &str) -> anyhow::Result<Value> {
+        self.request(Method::GET, path, None).await
+    }
+}
+
+// Exchanges the reveal token of a created or rotated key for its value. Reveal tokens can only be exchanged once, and
+// only by the user who created or rotated the key.
+async fn reveal_key(
+    client: &Client,
+    account_id: &str,
+    key_id: &str,
+    reveal_token: &str,
+) -> anyhow::Result<Value> {
+    client
+        .request(
+            Method::POST,
+            &format!("/account/{account_id}/report_api_key/{key_id}/value"),
+            Some(json!({ "report_api_key_value_reveal_token": reveal_token })),
+        )
+        .await
+}
+
+// Replaces the reveal token of a create or rotate response with the key value, as the value can't be revealed again
+// once the command exits. Responses for keys awaiting admin approval are returned as is.
+async fn reveal_new_key(
+    client: &Client,
+    account_id: &str,
+    mut response: Value,
+) -> anyhow::Result<Value> {
+    let (Some(key_id), Some(reveal_token)) = (
+        response["report_api_key"]["id"].as_u64(),
+        response["report_api_key_value_reveal_token"]
+            .as_str()
+            .map(str::to_owned),
+    ) else {
+        return Ok(response);
+    };
+
+    let mut revealed = reveal_key(client, account_id, &key_id.to_string(), &reveal_token).await?;
+
+    if let Some(response) = response.as_object_mut() {
+        response.remove("report_api_key_value_reveal_token");
+        response.remove("report_api_key_value_reveal_expires_at");
+        response.insert(
+            "report_api_key_value".to_owned(),
+            revealed["report_api_key_value"].take(),
+        );
+    }
+
+    Ok(response)
+}
+
+async fn rotate_key(
+    client: &Client,
+    account_id: &str,
+    key_id: &str,
+    grace_period_hours: Option<&str>,
+) -> anyhow::Result<Value> {
+    let grace_period_hours = grace_period_hours
+        .map(|hours| {
+            hours
+                .parse::<u32>()
+                .with_context(|| format!("Invalid grace period hours {hours:?}"))
+        })
+        .transpose()?;
+
+    let rotated = client
+        .request(
+            Method::POST,
+            &format!("/account/{account_id}/report_api_key/{key_id}/rotate"),
+            Some(json!({ "grace_period_hours": grace_period_hours })),
+        )
+        .await?;
+
+    reveal_new_key(client, account_id, rotated).await
+}
+
+// Writes the export to stdout as it is received, as exports of large accounts may not fit in memory
+async fn export(client: &Client, account_id: &str, args: &[&str]) -> anyhow::Result<()> {
+    let mut format = "json";
+    let mut encrypted = false;
+
+    for arg in args {
+        match *arg {
+            "--encrypted" => encrypted = true,
+            "json" | "graphml" | "dot" => format = arg,
+            _ => bail!("{USAGE}"),
+        }
+    }
+
+    let path = format!("/account/{account_id}/export?format={format}&encrypted={encrypted}");
+
+    let mut response = client.send(Token::Access, Method::GET, &path, None).await?;
+
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .with_context(|| format!("Failed to read GET {path} response"))?
+    {
+        std::io::stdout()
+            .write_all(&chunk)
+            .context("Failed to write export")?;
+    }
+
+    std::io::stdout()
+        .flush()
+        .context("Failed to write export")?;
+
+    Ok(())
+}
+
+// Prints the latest page of audit log entries oldest first, one entry per line. With `--follow`, keeps polling for
+// entries that occurred since the last printed entry. Entries that occurred at the same time as the last printed entry
+// are returned again, so they are skipped if they were already printed.
+async fn audit(client: &Client, account_id: &str, follow: bool) -> anyhow::Result<()> {
+    let mut since = None::<String>;
+    let mut printed_since = HashSet::<String>::new();
+
+    loop {
+        let mut entries = Vec::new();
+        let mut cursor = None::<String>;
+
+        loop {
+            let mut params = Vec::new();
+            if let Some(since) = &since {
+                params.push(format!("since={since}"));
+            }
+            if let Some(cursor) = &cursor {
+                params.push(format!("cursor={cursor}"));
+            }
+
+            let mut page = client
+                .get(&format!(
+                    "/account/{account_id}/audit_log?{}",
+                    params.join("&")
+                ))
+                .await?;
+
+            if let Some(page_entries) = page["entries"].as_array_mut() {
+                entries.append(page_entries);
+            }
+
+            cursor = page["next_cursor"].as_str().map(str::to_owned);
+
+            // Only the latest page is printed at first, and every entry since the last printed entry afterwards
+            if since.is_none() || cursor.is_none() {
+                break;
+            }
+        }
+
+        for entry in entries.into_iter().rev() {
+            let occurred_at = entry["occurred_at"].as_str().map(str::to_owned);
+            if occurred_at.is_some() && occurred_at != since {
+                since = occurred_at;
+                printed_since.clear();
+            }
+
+            let id = entry["id"].as_str().unwrap_or_default().to_owned();
+            if !printed_since.insert(id) {
+                continue;
+            }
+
+            println!("{entry}");
+        }
+
+        if !follow {
+            return Ok(());
+        }
+
+        tokio::time::sleep(AUDIT_FOLLOW_INTERVAL).await;
+    }
+}
+
+// Commands that print their own output, like `export` and `audit`, return null
+async fn run(client: &Client, args: &[&str]) -> anyhow::Result<Value> {
+    match args {
+        ["accounts", "list"] => client.get("/accounts").await,
+        ["keys", "list", account_id] => {
+            client
+                .get(&format!("/account/{account_id}/report_api_keys"))
+                .await
+        }
+        ["keys", "create", account_id, description @ ..] => {
+            let description = (!description.is_empty()).then(|| description.join(" "));
+
+            let created = client
+                .request(
+                    Method::POST,
+                    &format!("/account/{account_id}/report_api_keys"),
+                    Some(json!({ "description": description })),
+                )
+                .await?;
+
+            reveal_new_key(client, account_id, created).await
+        }
+        ["keys", "reveal", account_id, key_id, reveal_token] => {
+            reveal_key(client, account_id, key_id, reveal_token).await
+        }
+        ["keys", "revoke", account_id, key_id] => {
+            client
+                .request(
+                    Method::DELETE,
+                    &format!("/account/{account_id}/report_api_key/{key_id}"),
+                    None,
+                )
+                .await
+        }
+        ["keys", "revoke", account_id, key_id, "--hard"] => {
+            client
+                .request(
+                    Method::DELETE,
+                    &format!("/account/{account_id}/report_api_key/{key_id}?hard=true"),
+                    None,
+                )
+                .await
+        }
+        ["keys", "restore", account_id, key_id] => {
+            client
+                .request(
+                    Method::POST,
+                    &format!("/account/{account_id}/report_api_key/{key_id}/restore"),
+                    None,
+                )
+                .await
+        }
+        ["keys", "rotate", account_id, key_id] => {
+            rotate_key(client, account_id, key_id, None).await
+        }
+        [
+            "keys",
+            "rotate",
+            account_id,
+            key_id,
+            "--grace-period-hours",
+            hours,
+        ] => rotate_key(client, account_id, key_id, Some(hours)).await,
+        ["members", "list", account_id] => {
+            client.get(&format!("/account/{account_id}/members")).await
+        }
+        ["health", account_id] => client.get(&format!("/account/{account_id}/health")).await,
+        ["statements", account_id] => {
+            client
+                .get(&format!("/account/{account_id}/statements"))
+                .await
+        }
+        ["export", account_id, args @ ..] => {
+            export(client, account_id, args).await?;
+            Ok(Value::Null)
+        }
+        ["audit", account_id] => {
+            audit(client, account_id, false).await?;
+            Ok(Value::Null)
+        }
+        ["audit", account_id, "--follow"] => {
+            audit(client, account_id, true).await?;
+            Ok(Value::Null)
+        }
+        ["migrate", account_id] => {
+            client
+                .admin_request(
+                    Method::POST,
+                    &format!("/admin/account/{account_id}/migrate"),
+                    None,
+                )
+                .await
+        }
+        ["bundle", "import", account_id, path] => {
+            let bundle = std::fs::read(path).with_context(|| format!("Failed to read {path}"))?;
+            let bundle = serde_json::from_slice::<Value>(&bundle)
+                .with_context(|| format!("Failed to parse bundle {path}"))?;
+
+            client
+                .admin_request(
+                    Method::POST,
+                    &format!("/admin/account/{account_id}/bundle"),
+                    Some(bundle),
+                )
+                .await
+        }
+        _ => bail!("{USAGE}"),
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let mut args = args.iter().map(String::as_str).collect::<Vec<_>>();
+
+    let profile = match args.as_slice() {
+        ["--profile", profile, ..] => {
+            let profile = (*profile).to_owned();
+            args.drain(..2);
+            profile
+        }
+        _ => std::env::var("ARCHODEXCTL_PROFILE").unwrap_or_else(|_| "default".to_owned()),
+    };
+
+    if args.is_empty() || matches!(args[0], "-h" | "--help" | "help") {
+        println!("{USAGE}");
+        return Ok(());
+    }
+
+    let client = Client {
+        http: reqwest::Client::new(),
+        profile: load_profile(&profile)?,
+        profile_name: profile,
+    };
+
+    let value = run(&client, &args).await?;
+
+    if !value.is_null() {
+        println!("{}", serde_json::to_string_pretty(&value)?);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,21 @@
+//! Renders every fixture dataset to `fixtures/<dataset>.ndjson`
+
+use std::path::Path;
+
+use archodex_backend::fixtures::Dataset;
+
+fn main() -> std::io::Result<()> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+
+    std::fs::create_dir_all(&dir)?;
+
+    for dataset in Dataset::ALL {
+        let path = dir.join(format!("{}.ndjson", dataset.name()));
+
+        std::fs::write(&path, dataset.ndjson())?;
+
+        println!("Wrote {}", path.display());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,29 @@
+//! Writes TypeScript definitions of the API response bodies to `types/api.d.ts`. With `--check`, fails instead if the
+//! checked in definitions are out of date.
+
+use std::{path::Path, process::ExitCode};
+
+fn main() -> std::io::Result<ExitCode> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("types/api.d.ts");
+    let definitions = archodex_backend::typescript::definitions();
+
+    if std::env::args().any(|arg| arg == "--check") {
+        if std::fs::read_to_string(&path).ok().as_deref() == Some(definitions.as_str()) {
+            return Ok(ExitCode::SUCCESS);
+        }
+
+        eprintln!(
+            "{} is out of date, run `cargo run --example generate_types` to regenerate it",
+            path.display()
+        );
+
+        return Ok(ExitCode::FAILURE);
+    }
+
+    std::fs::create_dir_all(path.parent().expect("Types path should have a parent"))?;
+    std::fs::write(&path, definitions)?;
+
+    println!("Wrote {}", path.display());
+
+    Ok(ExitCode::SUCCESS)
+}
@@ -15,7 +15,11 @@ fn setup_logging() {
 
     let fmt = fmt().with_env_filter(env_filter);
 
-    fmt.with_ansi(false).init();
+    if std::env::var("LOG_FORMAT").is_ok_and(|value| value == "json") {
+        fmt.json().init();
+    } else {
+        fmt.with_ansi(false).init();
+    }
 }
 
 fn main() -> Result<(), io::Error> {
@@ -84,3 +84,16 @@ pub async fn migrate_accounts_database(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn schemas_parse() {
+        for schema in [
+            include_str!("accounts.surql"),
+            include_str!("resources.surql"),
+        ] {
+            surrealdb::sql::parse(schema).unwrap();
+        }
+    }
+}
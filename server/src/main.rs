@@ -1,20 +1,64 @@
-use anyhow::Context as _;
+use anyhow::{Context as _, anyhow};
 use archodex_backend::env::Env;
+use clap::{Parser, Subcommand};
 use tracing::{info, warn};
 
+mod tls;
+
 #[cfg(debug_assertions)]
 const RUNTIME_STACK_SIZE: usize = 20 * 1024 * 1024; // 20MiB in debug mode
 #[cfg(not(debug_assertions))]
 const RUNTIME_STACK_SIZE: usize = 10 * 1024 * 1024; // 10MiB in release mode
 
-fn setup_logging() {
+/// Archodex backend server. Configured through env vars.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Migrate the accounts database and serve the API (default)
+    Serve,
+    /// Migrate the accounts database and exit
+    Migrate,
+    /// Validate the configuration env vars and exit without starting the server
+    CheckConfig,
+    /// Create a report key for an account and print its value, e.g. to bootstrap agents from automation
+    #[cfg(not(feature = "archodex-com"))]
+    CreateReportKey {
+        /// ID of the account to create the report key for
+        #[arg(long)]
+        account: String,
+        /// Description of the report key
+        #[arg(long)]
+        description: Option<String>,
+    },
+}
+
+// Commands other than `serve` log to stderr so their output can be captured from stdout
+fn setup_logging(to_stderr: bool) {
     use std::io::IsTerminal;
     use tracing_subscriber::{
         filter::{EnvFilter, LevelFilter},
         fmt,
     };
 
-    let color = std::io::stdout().is_terminal()
+    let (is_terminal, writer) = if to_stderr {
+        (
+            std::io::stderr().is_terminal(),
+            fmt::writer::BoxMakeWriter::new(std::io::stderr),
+        )
+    } else {
+        (
+            std::io::stdout().is_terminal(),
+            fmt::writer::BoxMakeWriter::new(std::io::stdout),
+        )
+    };
+
+    let color = is_terminal
         && (match std::env::var("COLORTERM") {
             Ok(value) => value == "truecolor" || value == "24bit",
             _ => false,
@@ -27,9 +71,13 @@ fn setup_logging() {
         .with_default_directive(LevelFilter::INFO.into())
         .from_env_lossy();
 
-    let fmt = fmt().with_env_filter(env_filter);
+    let fmt = fmt().with_env_filter(env_filter).with_writer(writer);
 
-    if color {
+    // JSON log lines include the fields of the spans they were logged within, like the `request_id` of the request
+    // span, so log aggregators can correlate them
+    if std::env::var("LOG_FORMAT").is_ok_and(|value| value == "json") {
+        fmt.json().init();
+    } else if color {
         fmt.event_format(fmt::format().pretty())
             .with_ansi(color)
             .init();
@@ -102,11 +150,117 @@ async fn shutdown_signal() {
     }
 }
 
+fn spawn_background_jobs() {
+    #[cfg(not(feature = "archodex-com"))]
+    tokio::spawn(archodex_backend::account_link::sync_periodically());
+
+    tokio::spawn(archodex_backend::usage::roll_up_periodically());
+
+    tokio::spawn(archodex_backend::webhooks::deliver_periodically());
+
+    tokio::spawn(archodex_backend::lifecycle::archive_stale_resources_periodically());
+
+    tokio::spawn(archodex_backend::audit::expire_audit_log_entries_periodically());
+
+    tokio::spawn(archodex_backend::event_retention::prune_expired_events_periodically());
+
+    tokio::spawn(archodex_backend::idempotency::prune_expired_keys_periodically());
+
+    tokio::spawn(archodex_backend::api_tokens::prune_expired_api_tokens_periodically());
+
+    tokio::spawn(archodex_backend::members::prune_expired_invitations_periodically());
+
+    #[cfg(feature = "archodex-com")]
+    tokio::spawn(archodex_backend::provisioning::resume_provisioning_periodically());
+}
+
+async fn migrate() -> anyhow::Result<()> {
+    migrator::migrate_accounts_database(Env::accounts_surrealdb_url(), Env::surrealdb_creds())
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to migrate accounts database for URL {}",
+                Env::accounts_surrealdb_url()
+            )
+        })
+}
+
+async fn serve() -> anyhow::Result<()> {
+    // Standbys may run against a read-only replica of the primary's database, and are migrated when they are promoted
+    if archodex_backend::standby::is_standby() {
+        info!("Starting as a read-only standby, writes are refused until the instance is promoted");
+    } else {
+        migrate().await?;
+    }
+
+    tokio::spawn(async {
+        archodex_backend::standby::promoted().await;
+        spawn_background_jobs();
+    });
+
+    archodex_backend::description::log_banner();
+
+    let port = Env::port();
+
+    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{port}"))
+        .await
+        .unwrap_or_else(|_| panic!("Failed to listen on port {port}"));
+
+    let router = archodex_backend::router::router();
+
+    let shutdown = async {
+        shutdown_signal().await;
+        archodex_backend::shutdown::initiate();
+    };
+
+    // Plain HTTP connections may also use HTTP/2 with prior knowledge
+    let server = async {
+        match Env::tls_paths() {
+            Some((cert_path, key_path)) => {
+                info!("Listening on port {port} with TLS");
+
+                tls::serve(listener, router, cert_path, key_path, shutdown).await
+            }
+            None => {
+                info!("Listening on port {port}");
+
+                Ok(axum::serve(listener, router)
+                    .with_graceful_shutdown(shutdown)
+                    .await?)
+            }
+        }
+    };
+
+    // Connections are drained once shutdown is initiated, but requests that don't complete in time are dropped so the
+    // process exits before the orchestrator kills it
+    let drain_timeout = async {
+        archodex_backend::shutdown::initiated().await;
+        tokio::time::sleep(Env::shutdown_drain_timeout()).await;
+    };
+
+    tokio::select! {
+        result = server => result?,
+        () = drain_timeout => {
+            warn!(
+                drain_timeout = ?Env::shutdown_drain_timeout(),
+                "Connections did not drain before the drain timeout, exiting with requests in flight"
+            );
+        }
+    }
+
+    info!("Shut down");
+
+    Ok(())
+}
+
 fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Command::Serve);
+
     // This is safe to call first thing at process start before any threads may be spawned (e.g. by tokio)
     unsafe { setup_surrealdb_env_vars() };
 
-    setup_logging();
+    setup_logging(!matches!(command, Command::Serve));
 
     tokio::runtime::Builder::new_multi_thread()
         .enable_all()
@@ -114,34 +268,37 @@ fn main() -> anyhow::Result<()> {
         .build()
         .unwrap()
         .block_on(async {
-            {
-                migrator::migrate_accounts_database(
-                    Env::accounts_surrealdb_url(),
-                    Env::surrealdb_creds(),
-                )
-                .await
-                .with_context(|| {
-                    format!(
-                        "Failed to migrate accounts database for URL {}",
-                        Env::accounts_surrealdb_url()
-                    )
-                })?;
-            }
+            match command {
+                Command::Serve => serve().await,
+                Command::Migrate => {
+                    migrate().await?;
 
-            let port = Env::port();
+                    info!("Migrated accounts database");
 
-            let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{port}"))
-                .await
-                .unwrap_or_else(|_| panic!("Failed to listen on port {port}"));
+                    Ok(())
+                }
+                Command::CheckConfig => {
+                    // `Env` panics describing the first invalid env var, which the panic hook prints
+                    std::panic::catch_unwind(Env::check)
+                        .map_err(|_| anyhow!("Invalid configuration"))?;
 
-            info!("Listening on port {port}");
+                    info!("Configuration is valid");
 
-            axum::serve(listener, archodex_backend::router::router())
-                .with_graceful_shutdown(shutdown_signal())
-                .await?;
+                    Ok(())
+                }
+                #[cfg(not(feature = "archodex-com"))]
+                Command::CreateReportKey {
+                    account,
+                    description,
+                } => {
+                    let report_api_key =
+                        archodex_backend::bootstrap::create_report_api_key(&account, description)
+                            .await?;
 
-            anyhow::Ok(())
-        })?;
+                    println!("{}", report_api_key.value);
 
-    Ok(())
+                    Ok(())
+                }
+            }
+        })
 }
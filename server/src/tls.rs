@@ -0,0 +1,216 @@
+// TLS termination for self-hosted backends served without a load balancer, see `Env::tls_paths`. Connections negotiate
+// HTTP/2 or HTTP/1.1 with ALPN. The certificate and key files are checked for changes periodically and reloaded, so
+// certificates renewed in place, e.g. by certbot or cert-manager, are served to new connections without a restart.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context as _, bail};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{conn::auto, graceful::GracefulShutdown},
+    service::TowerToHyperService,
+};
+use rustls::{
+    ServerConfig,
+    crypto::CryptoProvider,
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject as _};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info, warn};
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+// Clients that don't complete a handshake in time are disconnected so they don't hold connections open
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Serves the most recently loaded certificate to new connections
+#[derive(Debug)]
+struct CertificateResolver {
+    certified_key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.certified_key.read().unwrap().clone())
+    }
+}
+
+fn load_certified_key(
+    provider: &CryptoProvider,
+    cert_path: &Path,
+    key_path: &Path,
+) -> anyhow::Result<CertifiedKey> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+        .with_context(|| {
+            format!(
+                "Failed to read TLS certificates from {}",
+                cert_path.display()
+            )
+        })?;
+
+    if certs.is_empty() {
+        bail!("No TLS certificates found in {}", cert_path.display());
+    }
+
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .with_context(|| format!("Failed to read TLS private key from {}", key_path.display()))?;
+
+    let signing_key = provider
+        .key_provider
+        .load_private_key(key)
+        .with_context(|| format!("Failed to load TLS private key from {}", key_path.display()))?;
+
+    let certified_key = CertifiedKey::new(certs, signing_key);
+
+    // Keys whose public key can't be determined can't be checked, and fail handshakes instead
+    if let Err(err @ rustls::Error::InconsistentKeys(rustls::InconsistentKeys::KeyMismatch)) =
+        certified_key.keys_match()
+    {
+        return Err(err).with_context(|| {
+            format!(
+                "TLS certificate {} doesn't match private key {}",
+                cert_path.display(),
+                key_path.display()
+            )
+        });
+    }
+
+    Ok(certified_key)
+}
+
+fn modified_at(cert_path: &Path, key_path: &Path) -> Option<(SystemTime, SystemTime)> {
+    let modified_at =
+        |path: &Path| std::fs::metadata(path).and_then(|metadata| metadata.modified());
+
+    Some((modified_at(cert_path).ok()?, modified_at(key_path).ok()?))
+}
+
+// Renewals may replace the certificate and key files one at a time, so a certificate that fails to load is retried once
+// either file changes again. The previous certificate is served until then.
+async fn reload_periodically(
+    resolver: Arc<CertificateResolver>,
+    provider: Arc<CryptoProvider>,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+) {
+    let mut last_modified_at = modified_at(&cert_path, &key_path);
+
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        let modified_at = modified_at(&cert_path, &key_path);
+        if modified_at == last_modified_at {
+            continue;
+        }
+        last_modified_at = modified_at;
+
+        match load_certified_key(&provider, &cert_path, &key_path) {
+            Ok(certified_key) => {
+                *resolver.certified_key.write().unwrap() = Arc::new(certified_key);
+
+                info!("Reloaded TLS certificate");
+            }
+            Err(err) => {
+                warn!(
+                    ?err,
+                    "Failed to reload TLS certificate, serving the previous certificate"
+                );
+            }
+        }
+    }
+}
+
+// Serves the router over TLS until `shutdown` completes, then waits for open connections to complete their requests
+pub(crate) async fn serve(
+    listener: TcpListener,
+    router: axum::Router,
+    cert_path: &Path,
+    key_path: &Path,
+    shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<()> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let resolver = Arc::new(CertificateResolver {
+        certified_key: RwLock::new(Arc::new(load_certified_key(
+            &provider, cert_path, key_path,
+        )?)),
+    });
+
+    let mut config = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .context("Failed to configure TLS protocol versions")?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver.clone());
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    tokio::spawn(reload_periodically(
+        resolver,
+        provider,
+        cert_path.to_path_buf(),
+        key_path.to_path_buf(),
+    ));
+
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let graceful = GracefulShutdown::new();
+
+    tokio::pin!(shutdown);
+
+    loop {
+        let (stream, remote_addr) = tokio::select! {
+            result = listener.accept() => match result {
+                Ok(connection) => connection,
+                Err(err) => {
+                    warn!(?err, "Failed to accept connection");
+                    continue;
+                }
+            },
+            () = &mut shutdown => break,
+        };
+
+        let acceptor = acceptor.clone();
+        let builder = builder.clone();
+        let service = TowerToHyperService::new(router.clone());
+        let watcher = graceful.watcher();
+
+        tokio::spawn(async move {
+            let stream =
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(err)) => {
+                        debug!(%remote_addr, ?err, "TLS handshake failed");
+                        return;
+                    }
+                    Err(_) => {
+                        debug!(%remote_addr, "TLS handshake timed out");
+                        return;
+                    }
+                };
+
+            let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+
+            if let Err(err) = watcher.watch(connection).await {
+                debug!(%remote_addr, ?err, "Failed to serve connection");
+            }
+        });
+    }
+
+    // Stop accepting connections while open connections complete their requests
+    drop(listener);
+
+    graceful.shutdown().await;
+
+    Ok(())
+}
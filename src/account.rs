@@ -1,23 +1,37 @@
 use chrono::{DateTime, Utc};
 use rand::Rng;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use surrealdb::sql::statements::{BeginStatement, CommitStatement};
+#[cfg(not(feature = "archodex-com"))]
 use tracing::instrument;
 
 use crate::{
-    db::{DBConnection, migrate_service_data_database, resources_db},
-    env::Env,
-    next_binding, surrealdb_deserializers,
+    billing::{Entitlements, Plan, QuotaOverrides},
+    db::{DBConnection, InstrumentedQuery, resources_db},
+    export_encryption::ExportEncryptionKey,
+    ingestion_pipeline::ProcessorConfig,
+    next_binding,
+    report_api_keys::DEFAULT_REPORT_API_KEY_RESTORE_WINDOW_DAYS,
+    surrealdb_deserializers,
     user::User,
 };
+#[cfg(not(feature = "archodex-com"))]
+use crate::{db::migrate_service_data_database, env::Env};
 use archodex_error::anyhow;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct Account {
     #[serde(deserialize_with = "surrealdb_deserializers::string::deserialize")]
     id: String,
+    #[serde(default)]
+    name: Option<String>,
     #[cfg(feature = "archodex-com")]
     endpoint: String,
+    // Accounts created before data regions were selectable have no recorded location
+    #[cfg(feature = "archodex-com")]
+    #[serde(default)]
+    service_data_location: Option<crate::provisioning::ServiceDataLocation>,
     #[cfg(feature = "archodex-com")]
     service_data_surrealdb_url: Option<String>,
     #[serde(deserialize_with = "surrealdb_deserializers::bytes::deserialize")]
@@ -32,48 +46,116 @@ pub(crate) struct Account {
     created_by: Option<User>,
     deleted_at: Option<DateTime<Utc>>,
     deleted_by: Option<User>,
+    #[serde(default)]
+    plan: Plan,
+    #[serde(default)]
+    suspended_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    ingestion_paused_until: Option<DateTime<Utc>>,
+    #[serde(default)]
+    debug_capture_until: Option<DateTime<Utc>>,
+    #[serde(default)]
+    default_environment: Option<String>,
+    #[serde(default)]
+    report_api_key_approval_required: bool,
+    #[serde(default)]
+    ingestion_pipeline: Vec<ProcessorConfig>,
+    #[serde(default)]
+    resource_staleness_days: Option<u32>,
+    #[serde(default)]
+    dead_lettering_enabled: bool,
+    #[serde(default)]
+    audit_log_retention_days: Option<u32>,
+    #[serde(default)]
+    audit_log_write_once: bool,
+    #[serde(default)]
+    report_api_key_restore_window_days: Option<u32>,
+    #[serde(default)]
+    event_retention_days: Option<u32>,
+    #[serde(default)]
+    export_encryption_key: Option<ExportEncryptionKey>,
+    #[serde(default)]
+    quotas: QuotaOverrides,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, JsonSchema, Serialize)]
 pub(crate) struct AccountPublic {
     pub(crate) id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) name: Option<String>,
     #[cfg(feature = "archodex-com")]
     pub(crate) endpoint: String,
+    #[cfg(feature = "archodex-com")]
+    pub(crate) plan: Plan,
+    // AWS region the account's service data is stored in
+    #[cfg(feature = "archodex-com")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) data_region: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) default_environment: Option<String>,
 }
 
 impl From<Account> for AccountPublic {
     fn from(record: Account) -> Self {
         Self {
             id: record.id,
+            name: record.name,
             #[cfg(feature = "archodex-com")]
             endpoint: record.endpoint,
+            #[cfg(feature = "archodex-com")]
+            plan: record.plan,
+            #[cfg(feature = "archodex-com")]
+            data_region: record.service_data_location.map(|location| location.region),
+            default_environment: record.default_environment,
         }
     }
 }
 
+// Account IDs are ten digit numbers, see `User::next_account_id`. IDs from unauthenticated requests or other backends
+// are checked before they are used in URLs or queries.
+pub(crate) fn is_well_formed_account_id(account_id: &str) -> bool {
+    account_id.len() == 10
+        && account_id.bytes().all(|byte| byte.is_ascii_digit())
+        && !account_id.starts_with('0')
+}
+
 impl Account {
+    // Service data databases of archodex.com accounts are created and migrated beforehand, see `provisioning`
     #[cfg(feature = "archodex-com")]
-    #[instrument(err)]
-    pub(crate) async fn new(endpoint: String, id: String, principal: User) -> anyhow::Result<Self> {
-        let service_data_surrealdb_url = if endpoint == Env::endpoint() {
-            let service_data_surrealdb_url =
-                archodex_com::create_account_service_database(&id).await?;
-            migrate_service_data_database(&service_data_surrealdb_url, &id).await?;
-            Some(service_data_surrealdb_url)
-        } else {
-            None
-        };
-
-        Ok(Self {
+    pub(crate) fn new(
+        endpoint: String,
+        id: String,
+        service_data_location: crate::provisioning::ServiceDataLocation,
+        service_data_surrealdb_url: Option<String>,
+        principal: User,
+    ) -> Self {
+        Self {
             id,
+            name: None,
             endpoint,
+            service_data_location: Some(service_data_location),
             service_data_surrealdb_url,
             salt: rand::thread_rng().r#gen::<[u8; 16]>().to_vec(),
             created_at: None,
             created_by: Some(principal),
             deleted_at: None,
             deleted_by: None,
-        })
+            plan: Plan::default(),
+            suspended_at: None,
+            ingestion_paused_until: None,
+            debug_capture_until: None,
+            default_environment: None,
+            report_api_key_approval_required: false,
+            ingestion_pipeline: Vec::new(),
+            resource_staleness_days: None,
+            dead_lettering_enabled: false,
+            audit_log_retention_days: None,
+            audit_log_write_once: false,
+            report_api_key_restore_window_days: None,
+            event_retention_days: None,
+            export_encryption_key: None,
+            quotas: QuotaOverrides::default(),
+        }
     }
 
     #[cfg(not(feature = "archodex-com"))]
@@ -99,20 +181,41 @@ impl Account {
 
         Ok(Self {
             id,
+            name: None,
             salt: rand::thread_rng().r#gen::<[u8; 16]>().to_vec(),
             api_private_key,
             created_at: None,
             created_by: Some(principal),
             deleted_at: None,
             deleted_by: None,
+            plan: Plan::default(),
+            suspended_at: None,
+            ingestion_paused_until: None,
+            debug_capture_until: None,
+            default_environment: None,
+            report_api_key_approval_required: false,
+            ingestion_pipeline: Vec::new(),
+            resource_staleness_days: None,
+            dead_lettering_enabled: false,
+            audit_log_retention_days: None,
+            audit_log_write_once: false,
+            report_api_key_restore_window_days: None,
+            event_retention_days: None,
+            export_encryption_key: None,
+            quotas: QuotaOverrides::default(),
         })
     }
 
-    #[cfg(feature = "archodex-com")]
     pub(crate) fn id(&self) -> &str {
         &self.id
     }
 
+    // Unset for accounts created before their creator was recorded
+    #[cfg(not(feature = "archodex-com"))]
+    pub(crate) fn created_by(&self) -> Option<&User> {
+        self.created_by.as_ref()
+    }
+
     #[cfg(feature = "archodex-com")]
     pub(crate) fn service_data_surrealdb_url(&self) -> Option<&str> {
         self.service_data_surrealdb_url.as_deref()
@@ -122,52 +225,158 @@ impl Account {
         &self.salt
     }
 
-    pub(crate) async fn resources_db(&self) -> anyhow::Result<DBConnection> {
+    // Plan entitlements only apply to archodex.com accounts. Self-hosted accounts are unlimited unless operators set
+    // quotas for them.
+    pub(crate) fn entitlements(&self) -> Entitlements {
+        #[cfg(feature = "archodex-com")]
+        let entitlements = Entitlements::for_plan(self.plan);
+
         #[cfg(not(feature = "archodex-com"))]
-        let service_data_surrealdb_url = Env::surrealdb_url();
+        let entitlements = Entitlements::UNLIMITED;
+
+        entitlements.with_overrides(self.quotas)
+    }
+
+    // Checks whether agents may currently report data to this account, returning a coded error telling the agent
+    // whether it should retry later or stop sending reports.
+    pub(crate) fn check_ingestion_allowed(&self) -> crate::Result<()> {
+        use archodex_error::{ErrorCode, PublicError, coded_error};
+
+        if self.suspended_at.is_some() {
+            coded_error!(ErrorCode::AccountSuspended, "Account is suspended");
+        }
+
+        if let Some(ingestion_paused_until) = self.ingestion_paused_until {
+            let now = Utc::now();
+
+            if ingestion_paused_until > now {
+                let retry_after = (ingestion_paused_until - now).to_std().unwrap_or_default();
+
+                return Err(PublicError::with_code(
+                    ErrorCode::IngestionPaused,
+                    "Ingestion is temporarily paused for this account",
+                )
+                .retry_after(retry_after));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn report_api_key_approval_required(&self) -> bool {
+        self.report_api_key_approval_required
+    }
+
+    pub(crate) fn ingestion_pipeline(&self) -> &[ProcessorConfig] {
+        &self.ingestion_pipeline
+    }
+
+    // Number of days after which resources that have not been seen are archived, if stale resources are archived
+    pub(crate) fn resource_staleness_days(&self) -> Option<u32> {
+        self.resource_staleness_days
+    }
+
+    // Whether reports that fail to be ingested are kept as dead letters, see `dead_letter`
+    pub(crate) fn dead_lettering_enabled(&self) -> bool {
+        self.dead_lettering_enabled
+    }
+
+    // Number of days after which audit log entries are deleted, if they expire
+    pub(crate) fn audit_log_retention_days(&self) -> Option<u32> {
+        self.audit_log_retention_days
+    }
+
+    // Whether audit log entries cannot be deleted until their retention expires, see `audit`
+    pub(crate) fn audit_log_write_once(&self) -> bool {
+        self.audit_log_write_once
+    }
+
+    // Number of days after which revoked report keys can no longer be restored, see `report_api_keys`
+    pub(crate) fn report_api_key_restore_window_days(&self) -> u32 {
+        self.report_api_key_restore_window_days
+            .unwrap_or(DEFAULT_REPORT_API_KEY_RESTORE_WINDOW_DAYS)
+    }
+
+    // Number of days after which events that have not been seen are pruned, if events expire
+    pub(crate) fn event_retention_days(&self) -> Option<u32> {
+        self.event_retention_days
+    }
+
+    // Key exports are encrypted with, if the account registered one
+    pub(crate) fn export_encryption_key(&self) -> Option<&ExportEncryptionKey> {
+        self.export_encryption_key.as_ref()
+    }
+
+    // Resolves the environment to scope resource queries to from an `environment` query parameter, falling back to the
+    // account's default environment. The `all` environment disables scoping.
+    pub(crate) fn scoped_environment(&self, environment: Option<String>) -> Option<String> {
+        match environment {
+            Some(environment) if environment == "all" => None,
+            Some(environment) => Some(environment),
+            None => self.default_environment.clone(),
+        }
+    }
+
+    pub(crate) fn debug_capture_enabled(&self) -> bool {
+        self.debug_capture_until
+            .is_some_and(|debug_capture_until| debug_capture_until > Utc::now())
+    }
+
+    // URL of the SurrealDB instance hosting the account's resources database
+    pub(crate) fn resources_db_url(&self) -> anyhow::Result<&str> {
+        #[cfg(not(feature = "archodex-com"))]
+        return Ok(Env::surrealdb_url());
+
         #[cfg(feature = "archodex-com")]
-        let Some(service_data_surrealdb_url) = &self.service_data_surrealdb_url else {
-            use archodex_error::anyhow::bail;
+        {
+            let Some(service_data_surrealdb_url) = &self.service_data_surrealdb_url else {
+                use archodex_error::anyhow::bail;
 
-            bail!(
-                "No service data SurrealDB URL configured for account {}",
-                self.id
-            );
-        };
+                bail!(
+                    "No service data SurrealDB URL configured for account {}",
+                    self.id
+                );
+            };
+
+            Ok(service_data_surrealdb_url)
+        }
+    }
 
-        resources_db(service_data_surrealdb_url, &self.id).await
+    pub(crate) async fn resources_db(&self) -> anyhow::Result<DBConnection> {
+        resources_db(self.resources_db_url()?, &self.id).await
     }
 }
 
-pub(crate) trait AccountQueries<'r, C: surrealdb::Connection> {
-    fn create_account_query(
-        &'r self,
-        account: &Account,
-        principal: &User,
-    ) -> surrealdb::method::Query<'r, C>;
-    fn get_account_by_id(&'r self, account_id: String) -> surrealdb::method::Query<'r, C>;
-    fn delete_account_query(
-        &'r self,
-        account: &Account,
-        principal: &User,
-    ) -> surrealdb::method::Query<'r, C>;
+pub(crate) trait AccountQueries<'r> {
+    fn create_account_query(&'r self, account: &Account, principal: &User)
+    -> InstrumentedQuery<'r>;
+    fn get_account_by_id(&'r self, account_id: String) -> InstrumentedQuery<'r>;
+    fn delete_account_query(&'r self, account: &Account, principal: &User)
+    -> InstrumentedQuery<'r>;
 }
 
-impl<'r, C: surrealdb::Connection> AccountQueries<'r, C> for surrealdb::Surreal<C> {
+impl<'r> AccountQueries<'r> for DBConnection {
     fn create_account_query(
         &'r self,
         account: &Account,
         principal: &User,
-    ) -> surrealdb::method::Query<'r, C> {
+    ) -> InstrumentedQuery<'r> {
         let account_binding = next_binding();
         let endpoint_binding = next_binding();
+        let service_data_location_binding = next_binding();
         let service_data_surrealdb_url_binding = next_binding();
         let salt_binding = next_binding();
         let api_private_key_binding = next_binding();
         let created_by_binding = next_binding();
 
         #[cfg(not(feature = "archodex-com"))]
-        let (endpoint_value, service_data_surrealdb_url_value, api_private_key_value) = (
+        let (
+            endpoint_value,
+            service_data_location_value,
+            service_data_surrealdb_url_value,
+            api_private_key_value,
+        ) = (
+            Option::<String>::None,
             Option::<String>::None,
             Option::<String>::None,
             account
@@ -176,17 +385,24 @@ impl<'r, C: surrealdb::Connection> AccountQueries<'r, C> for surrealdb::Surreal<
                 .map(surrealdb::sql::Bytes::from),
         );
         #[cfg(feature = "archodex-com")]
-        let (endpoint_value, service_data_surrealdb_url_value, api_private_key_value) = (
+        let (
+            endpoint_value,
+            service_data_location_value,
+            service_data_surrealdb_url_value,
+            api_private_key_value,
+        ) = (
             account.endpoint.clone(),
+            account.service_data_location.clone(),
             account.service_data_surrealdb_url.clone(),
             Option::<surrealdb::sql::Bytes>::None,
         );
 
         let query = self
             .query(BeginStatement::default())
-            .query(format!("CREATE ${account_binding} CONTENT {{ endpoint: ${endpoint_binding}, service_data_surrealdb_url: ${service_data_surrealdb_url_binding}, salt: ${salt_binding}, api_private_key: ${api_private_key_binding}, created_by: ${created_by_binding} }} RETURN NONE"))
+            .query(format!("CREATE ${account_binding} CONTENT {{ endpoint: ${endpoint_binding}, service_data_location: ${service_data_location_binding}, service_data_surrealdb_url: ${service_data_surrealdb_url_binding}, salt: ${salt_binding}, api_private_key: ${api_private_key_binding}, created_by: ${created_by_binding} }} RETURN NONE"))
             .bind((account_binding, surrealdb::sql::Thing::from(account)))
             .bind((endpoint_binding, endpoint_value))
+            .bind((service_data_location_binding, service_data_location_value))
             .bind((service_data_surrealdb_url_binding, service_data_surrealdb_url_value))
             .bind((salt_binding, surrealdb::sql::Bytes::from(account.salt.clone())))
             .bind((api_private_key_binding, api_private_key_value))
@@ -204,25 +420,31 @@ impl<'r, C: surrealdb::Connection> AccountQueries<'r, C> for surrealdb::Surreal<
             .query(CommitStatement::default())
     }
 
-    fn get_account_by_id(&'r self, account_id: String) -> surrealdb::method::Query<'r, C> {
+    fn get_account_by_id(&'r self, account_id: String) -> InstrumentedQuery<'r> {
         let account_binding = next_binding();
 
-        self.query(format!("SELECT * FROM ONLY ${account_binding}"))
-            .bind((
-                account_binding,
-                surrealdb::sql::Thing::from(("account", surrealdb::sql::Id::String(account_id))),
-            ))
+        self.query(format!(
+            "SELECT * FROM ONLY ${account_binding} WHERE deleted_at IS NONE"
+        ))
+        .bind((
+            account_binding,
+            surrealdb::sql::Thing::from(("account", surrealdb::sql::Id::String(account_id))),
+        ))
     }
 
     fn delete_account_query(
         &'r self,
         account: &Account,
         principal: &User,
-    ) -> surrealdb::method::Query<'r, C> {
+    ) -> InstrumentedQuery<'r> {
         let account_binding = next_binding();
         let deleted_by_binding = next_binding();
 
-        self.query(format!("UPDATE ${account_binding} CONTENT {{ deleted_at: time::now(), deleted_by: ${deleted_by_binding} }}"))
+        // Access relations are removed so the deleted account is no longer listed for or accessible by its users
+        self.query(BeginStatement::default())
+            .query(format!("UPDATE ${account_binding} SET deleted_at = time::now(), deleted_by = ${deleted_by_binding} RETURN NONE"))
+            .query(format!("DELETE has_access WHERE out == ${account_binding}"))
+            .query(CommitStatement::default())
             .bind((
                 account_binding,
                 surrealdb::sql::Thing::from(account)
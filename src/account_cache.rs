@@ -0,0 +1,174 @@
+// Caches account records, which are looked up on every dashboard and report request. Each entry is tagged with the
+// cache generation it was loaded in, and bumping the generation invalidates every entry at once. Entries also expire
+// after `ACCOUNT_CACHE_TTL` so that changes made by other backend instances, or by operators directly in the database,
+// are eventually picked up.
+//
+// With the `live-query` feature, a SurrealDB live query on the `account` table bumps the generation as soon as any
+// account changes. If the live query can't be started or its stream ends, the cache falls back to explicit invalidation
+// and TTL expiry until the live query is re-established.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        LazyLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::RwLock;
+use tracing::instrument;
+
+use archodex_error::anyhow::Context as _;
+
+use crate::{
+    Result,
+    account::{Account, AccountQueries as _},
+    db::{QueryCheckFirstRealError as _, accounts_db},
+};
+
+const ACCOUNT_CACHE_TTL: Duration = Duration::from_secs(30);
+
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+static ACCOUNTS: LazyLock<RwLock<HashMap<String, CachedAccount>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+struct CachedAccount {
+    generation: u64,
+    loaded_at: Instant,
+    account: Account,
+}
+
+// Invalidates all cached accounts. Must be called after modifying an account record.
+pub(crate) fn invalidate() {
+    GENERATION.fetch_add(1, Ordering::AcqRel);
+}
+
+#[instrument(err)]
+pub(crate) async fn get_account(account_id: &str) -> Result<Option<Account>> {
+    #[cfg(feature = "live-query")]
+    live_query::ensure_started();
+
+    let generation = GENERATION.load(Ordering::Acquire);
+
+    if let Some(cached) = ACCOUNTS.read().await.get(account_id)
+        && cached.generation == generation
+        && cached.loaded_at.elapsed() < ACCOUNT_CACHE_TTL
+    {
+        return Ok(Some(cached.account.clone()));
+    }
+
+    load_account(account_id, generation).await
+}
+
+// Loads the account record from the database, bypassing the cache, and refreshes the cached record. Used by requests
+// that must reflect writes made moments ago, possibly by other backend instances.
+#[instrument(err)]
+pub(crate) async fn get_fresh_account(account_id: &str) -> Result<Option<Account>> {
+    let generation = GENERATION.load(Ordering::Acquire);
+
+    load_account(account_id, generation).await
+}
+
+async fn load_account(account_id: &str, generation: u64) -> Result<Option<Account>> {
+    let account = accounts_db()
+        .await?
+        .get_account_by_id(account_id.to_owned())
+        .await?
+        .check_first_real_error()?
+        .take::<Option<Account>>(0)
+        .with_context(|| format!("Failed to get record for account ID {account_id:?}"))?;
+
+    let mut accounts = ACCOUNTS.write().await;
+
+    match &account {
+        // If the cache was invalidated while the account was loading, the loaded record may already be stale
+        Some(account) if GENERATION.load(Ordering::Acquire) == generation => {
+            accounts.insert(
+                account_id.to_owned(),
+                CachedAccount {
+                    generation,
+                    loaded_at: Instant::now(),
+                    account: account.clone(),
+                },
+            );
+        }
+        _ => {
+            accounts.remove(account_id);
+        }
+    }
+
+    Ok(account)
+}
+
+#[cfg(feature = "live-query")]
+mod live_query {
+    use std::{sync::Once, time::Duration};
+
+    use futures::StreamExt as _;
+    use tracing::{info, warn};
+
+    use surrealdb::{Surreal, engine::any::Any};
+
+    use archodex_error::anyhow::bail;
+
+    use super::invalidate;
+    use crate::{Result, db::accounts_db, env::Env};
+
+    const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+    pub(super) fn ensure_started() {
+        static STARTED: Once = Once::new();
+
+        STARTED.call_once(|| {
+            // The embedded RocksDB connection is shared with resources database queries and can't be held open for a
+            // live query. All account changes are then made by this process, so explicit invalidation is sufficient.
+            if Env::accounts_surrealdb_url().starts_with("rocksdb:") {
+                return;
+            }
+
+            tokio::spawn(watch_accounts());
+        });
+    }
+
+    async fn watch_accounts() {
+        loop {
+            match subscribe().await {
+                Ok(()) => {
+                    warn!("Account live query ended, falling back to TTL expiry until it restarts")
+                }
+                Err(err) => warn!(
+                    ?err,
+                    "Failed to run account live query, falling back to TTL expiry until it restarts"
+                ),
+            }
+
+            // Changes may have been missed while the live query was down
+            invalidate();
+
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+    }
+
+    async fn subscribe() -> Result<()> {
+        let db: Surreal<Any> = (*accounts_db().await?).clone();
+
+        let mut notifications = db.select::<Vec<surrealdb::Value>>("account").live().await?;
+
+        info!("Account live query started");
+
+        // Accounts may have changed before the live query started
+        invalidate();
+
+        while let Some(notification) = notifications.next().await {
+            if let Err(err) = notification {
+                bail!(err);
+            }
+
+            invalidate();
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,427 @@
+// Account linking lets a self-hosted deployment opt in to sharing a small amount of metadata (which accounts exist and
+// their usage stats) with an archodex.com account. Link tokens are generated from the archodex.com account and then
+// configured on the self-hosted side via the `ARCHODEX_LINK_ENDPOINT` and `ARCHODEX_LINK_TOKEN` env vars. Nothing is
+// sent unless both are set.
+
+#[cfg(not(feature = "archodex-com"))]
+use hmac::{Hmac, Mac as _};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+#[cfg(not(feature = "archodex-com"))]
+use sha2::Sha256;
+
+#[cfg(feature = "archodex-com")]
+pub(crate) use hosted::{
+    CreateAccountLinkResponse, ListAccountLinksResponse, account_link_thing, account_thing,
+    create_account_link, list_account_links, revoke_account_link, sync_account_link,
+};
+#[cfg(not(feature = "archodex-com"))]
+pub use self_hosted::sync_periodically;
+
+// Prefix of the bearer credential archodex.com authenticates federated requests to a linked self-hosted backend with
+pub(crate) const FEDERATION_TOKEN_PREFIX: &str = "archodex_federation_";
+
+// Account routes federated requests may be proxied to, relative to the account. Only read-only queries are federated.
+pub(crate) const FEDERATED_ROUTES: &[&str] = &["principal_chain", "query/all", "query/secrets"];
+
+// Federated requests are authenticated with a credential derived from the link token rather than with the credentials
+// of the user making the request, which are never sent to the self-hosted backend. archodex.com only stores a hash of
+// the link token, so the self-hosted backend sends the derived credential with each sync.
+#[cfg(not(feature = "archodex-com"))]
+pub(crate) fn federation_token(link_token: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(link_token.as_bytes())
+        .expect("HMAC can take a key of any size");
+    mac.update(b"archodex federation token");
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct LinkedAccountMetadata {
+    account_id: String,
+    resource_count: u64,
+    active_report_api_key_count: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct LinkSyncRequest {
+    backend_version: String,
+    // Endpoint archodex.com may proxy read-only dashboard queries to. Only sent when federation is enabled.
+    #[serde(default)]
+    federation_endpoint: Option<String>,
+    // Credential archodex.com authenticates federated requests with, sent along with the federation endpoint
+    #[serde(default)]
+    federation_token: Option<String>,
+    accounts: Vec<LinkedAccountMetadata>,
+}
+
+#[cfg(feature = "archodex-com")]
+mod hosted {
+    use std::collections::HashMap;
+
+    use axum::{
+        Extension, Json,
+        extract::Path,
+        http::{HeaderMap, header::AUTHORIZATION},
+    };
+    use chrono::{DateTime, Utc};
+    use rand::Rng as _;
+    use schemars::JsonSchema;
+    use serde::{Deserialize, Serialize};
+    use sha2::{Digest as _, Sha256};
+    use tracing::{info, instrument, warn};
+    use uuid::Uuid;
+
+    use archodex_error::{
+        anyhow::{Context as _, bail},
+        bad_request, not_found, unauthorized,
+    };
+
+    use super::{LinkSyncRequest, LinkedAccountMetadata};
+    use crate::{
+        Result,
+        account::Account,
+        auth::DashboardAuth,
+        db::{QueryCheckFirstRealError as _, accounts_db},
+        env::Env,
+        outbound::{self, AllowedAddresses},
+        surrealdb_deserializers,
+    };
+
+    #[derive(Debug, Deserialize)]
+    struct AccountLink {
+        #[serde(deserialize_with = "surrealdb_deserializers::string::deserialize")]
+        id: String,
+        description: Option<String>,
+        created_at: Option<DateTime<Utc>>,
+        last_synced_at: Option<DateTime<Utc>>,
+        backend_version: Option<String>,
+        allowed_federation_endpoint: Option<String>,
+        federation_endpoint: Option<String>,
+        #[serde(default)]
+        linked_accounts: Vec<LinkedAccountMetadata>,
+    }
+
+    #[derive(Debug, JsonSchema, Serialize)]
+    pub(crate) struct AccountLinkPublic {
+        id: String,
+        description: Option<String>,
+        created_at: Option<DateTime<Utc>>,
+        last_synced_at: Option<DateTime<Utc>>,
+        backend_version: Option<String>,
+        allowed_federation_endpoint: Option<String>,
+        federated: bool,
+        linked_accounts: Vec<LinkedAccountMetadata>,
+    }
+
+    impl From<AccountLink> for AccountLinkPublic {
+        fn from(record: AccountLink) -> Self {
+            Self {
+                id: record.id,
+                description: record.description,
+                created_at: record.created_at,
+                last_synced_at: record.last_synced_at,
+                backend_version: record.backend_version,
+                allowed_federation_endpoint: record.allowed_federation_endpoint,
+                federated: record.federation_endpoint.is_some(),
+                linked_accounts: record.linked_accounts,
+            }
+        }
+    }
+
+    pub(crate) fn account_link_thing(link_id: &str) -> surrealdb::sql::Thing {
+        surrealdb::sql::Thing::from((
+            "account_link",
+            surrealdb::sql::Id::String(link_id.to_owned()),
+        ))
+    }
+
+    pub(crate) fn account_thing(account: &Account) -> surrealdb::sql::Thing {
+        surrealdb::sql::Thing::from((
+            "account",
+            surrealdb::sql::Id::String(account.id().to_owned()),
+        ))
+    }
+
+    #[derive(JsonSchema, Serialize)]
+    pub(crate) struct ListAccountLinksResponse {
+        account_links: Vec<AccountLinkPublic>,
+    }
+
+    #[instrument(err, skip_all)]
+    pub(crate) async fn list_account_links(
+        Extension(account): Extension<Account>,
+    ) -> Result<Json<ListAccountLinksResponse>> {
+        let account_links = accounts_db()
+            .await?
+            .query("SELECT * FROM account_link WHERE account = $account AND revoked_at IS NONE")
+            .bind(("account", account_thing(&account)))
+            .await
+            .context("Failed to submit query to list account links")?
+            .check_first_real_error()
+            .context("Failed to list account links")?
+            .take::<Vec<AccountLink>>(0)?
+            .into_iter()
+            .map(AccountLinkPublic::from)
+            .collect();
+
+        Ok(Json(ListAccountLinksResponse { account_links }))
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub(crate) struct CreateAccountLinkRequest {
+        description: Option<String>,
+        // Only endpoint the linked backend may enable federation with. Federation stays disabled if none is set.
+        #[serde(default)]
+        federation_endpoint: Option<String>,
+    }
+
+    #[derive(JsonSchema, Serialize)]
+    pub(crate) struct CreateAccountLinkResponse {
+        account_link: AccountLinkPublic,
+        link_endpoint: &'static str,
+        link_token: String,
+    }
+
+    // Link tokens have the form `<link ID>.<hex encoded secret>`. Only a hash of the secret is stored, so the token
+    // value is only available in the response to this request.
+    #[instrument(err, skip(auth, account))]
+    pub(crate) async fn create_account_link(
+        Extension(auth): Extension<DashboardAuth>,
+        Extension(account): Extension<Account>,
+        Json(req): Json<CreateAccountLinkRequest>,
+    ) -> Result<Json<CreateAccountLinkResponse>> {
+        let link_id = Uuid::now_v7().simple().to_string();
+        let secret = rand::thread_rng().r#gen::<[u8; 32]>();
+        let link_token = format!("{link_id}.{}", hex::encode(secret));
+
+        if let Some(federation_endpoint) = &req.federation_endpoint
+            && let Err(err) =
+                outbound::check_url(federation_endpoint, &["https"], AllowedAddresses::Public)
+        {
+            bad_request!("Invalid federation endpoint: {err}");
+        }
+
+        let account_link = accounts_db()
+            .await?
+            .query("CREATE $account_link CONTENT { account: $account, token_hash: $token_hash, description: $description, allowed_federation_endpoint: $allowed_federation_endpoint, created_by: $created_by }")
+            .bind(("account_link", account_link_thing(&link_id)))
+            .bind(("account", account_thing(&account)))
+            .bind((
+                "token_hash",
+                surrealdb::sql::Bytes::from(Sha256::digest(secret).to_vec()),
+            ))
+            .bind(("description", req.description))
+            .bind(("allowed_federation_endpoint", req.federation_endpoint))
+            .bind(("created_by", surrealdb::sql::Thing::from(auth.principal())))
+            .await
+            .context("Failed to submit query to create account link")?
+            .check_first_real_error()
+            .context("Failed to create account link")?
+            .take::<Option<AccountLink>>(0)?
+            .expect("Create account link query should return an account link instance");
+
+        info!(account_link_id = account_link.id, "Created account link");
+
+        Ok(Json(CreateAccountLinkResponse {
+            account_link: AccountLinkPublic::from(account_link),
+            link_endpoint: Env::endpoint(),
+            link_token,
+        }))
+    }
+
+    #[instrument(err, skip(auth, account))]
+    pub(crate) async fn revoke_account_link(
+        Extension(auth): Extension<DashboardAuth>,
+        Extension(account): Extension<Account>,
+        Path(params): Path<HashMap<String, String>>,
+    ) -> Result<Json<()>> {
+        let Some(link_id) = params.get("account_link_id") else {
+            bail!("Missing account_link_id");
+        };
+
+        let revoked = accounts_db()
+            .await?
+            .query("UPDATE $account_link SET revoked_at = time::now(), revoked_by = $revoked_by WHERE account = $account AND revoked_at IS NONE RETURN VALUE id")
+            .bind(("account_link", account_link_thing(link_id)))
+            .bind(("account", account_thing(&account)))
+            .bind(("revoked_by", surrealdb::sql::Thing::from(auth.principal())))
+            .await
+            .context("Failed to submit query to revoke account link")?
+            .check_first_real_error()
+            .context("Failed to revoke account link")?
+            .take::<Vec<surrealdb::RecordId>>(0)?;
+
+        if revoked.is_empty() {
+            not_found!("Account link not found");
+        }
+
+        Ok(Json(()))
+    }
+
+    // Receives metadata from a linked self-hosted backend. The request is authenticated by the link token rather than
+    // by user credentials, and is rejected once the link is revoked or the archodex.com account is deleted.
+    #[instrument(err, skip_all, fields(account_link_id))]
+    pub(crate) async fn sync_account_link(
+        headers: HeaderMap,
+        Json(req): Json<LinkSyncRequest>,
+    ) -> Result<Json<()>> {
+        let Some(link_token) = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+        else {
+            warn!("Missing or invalid Authorization header");
+            unauthorized!();
+        };
+
+        let Some((link_id, secret)) = link_token.split_once('.') else {
+            warn!("Invalid link token format");
+            unauthorized!();
+        };
+
+        let Ok(secret) = hex::decode(secret) else {
+            warn!("Invalid link token format");
+            unauthorized!();
+        };
+
+        tracing::Span::current().record("account_link_id", link_id);
+
+        // Federation is only enabled for the endpoint allowed when the link was created, and only with a credential
+        let (federation_endpoint, federation_token) =
+            match (req.federation_endpoint, req.federation_token) {
+                (Some(federation_endpoint), Some(federation_token)) => {
+                    (Some(federation_endpoint), Some(federation_token))
+                }
+                _ => (None, None),
+            };
+
+        let synced = accounts_db()
+            .await?
+            .query("UPDATE $account_link SET last_synced_at = time::now(), backend_version = $backend_version, federation_endpoint = IF $federation_endpoint != NONE AND $federation_endpoint = allowed_federation_endpoint THEN $federation_endpoint ELSE NONE END, federation_token = IF $federation_endpoint != NONE AND $federation_endpoint = allowed_federation_endpoint THEN $federation_token ELSE NONE END, linked_accounts = $linked_accounts WHERE token_hash = $token_hash AND revoked_at IS NONE AND account.deleted_at IS NONE RETURN VALUE id")
+            .bind(("account_link", account_link_thing(link_id)))
+            .bind((
+                "token_hash",
+                surrealdb::sql::Bytes::from(Sha256::digest(secret).to_vec()),
+            ))
+            .bind(("backend_version", req.backend_version))
+            .bind(("federation_endpoint", federation_endpoint))
+            .bind(("federation_token", federation_token))
+            .bind(("linked_accounts", req.accounts))
+            .await
+            .context("Failed to submit query to sync account link")?
+            .check_first_real_error()
+            .context("Failed to sync account link")?
+            .take::<Vec<surrealdb::RecordId>>(0)?;
+
+        if synced.is_empty() {
+            warn!("Account link not found, revoked, or token does not match");
+            unauthorized!();
+        }
+
+        Ok(Json(()))
+    }
+}
+
+#[cfg(not(feature = "archodex-com"))]
+mod self_hosted {
+    use std::time::Duration;
+
+    use reqwest::header::CONTENT_TYPE;
+    use tracing::{info, instrument, warn};
+
+    use archodex_error::anyhow::{Context as _, ensure};
+
+    use super::{LinkSyncRequest, LinkedAccountMetadata};
+    use crate::{
+        Result,
+        account::Account,
+        db::{QueryCheckFirstRealError as _, accounts_db},
+        env::Env,
+    };
+
+    const LINK_SYNC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+    /// Periodically sends account metadata to the linked archodex.com account. Returns immediately if account linking
+    /// has not been configured.
+    pub async fn sync_periodically() {
+        let Some((link_endpoint, link_token)) = Env::link() else {
+            return;
+        };
+
+        info!("Account linking enabled, syncing account metadata to {link_endpoint}");
+
+        let client = reqwest::Client::new();
+        let mut interval = tokio::time::interval(LINK_SYNC_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            if let Err(err) = sync(&client, link_endpoint, link_token).await {
+                warn!(?err, "Failed to sync account metadata to linked account");
+            }
+        }
+    }
+
+    #[instrument(err, skip_all)]
+    async fn sync(client: &reqwest::Client, link_endpoint: &str, link_token: &str) -> Result<()> {
+        let accounts = accounts_db()
+            .await?
+            .query("SELECT * FROM account WHERE deleted_at IS NONE")
+            .await
+            .context("Failed to submit query to list accounts")?
+            .check_first_real_error()
+            .context("Failed to list accounts")?
+            .take::<Vec<Account>>(0)?;
+
+        let mut linked_accounts = Vec::with_capacity(accounts.len());
+
+        for account in accounts {
+            let mut counts = account
+                .resources_db()
+                .await?
+                .query("SELECT count() AS count FROM resource GROUP ALL")
+                .query("SELECT count() AS count FROM report_api_key WHERE revoked_at IS NONE GROUP ALL")
+                .await
+                .context("Failed to submit query to count account resources")?
+                .check_first_real_error()
+                .context("Failed to count account resources")?;
+
+            linked_accounts.push(LinkedAccountMetadata {
+                account_id: account.id().to_owned(),
+                resource_count: counts
+                    .take::<Option<u64>>((0, "count"))?
+                    .unwrap_or_default(),
+                active_report_api_key_count: counts
+                    .take::<Option<u64>>((1, "count"))?
+                    .unwrap_or_default(),
+            });
+        }
+
+        let body = serde_json::to_vec(&LinkSyncRequest {
+            backend_version: env!("CARGO_PKG_VERSION").to_owned(),
+            federation_endpoint: Env::link_federation_endpoint().map(str::to_owned),
+            federation_token: Env::link_federation_endpoint()
+                .map(|_| super::federation_token(link_token)),
+            accounts: linked_accounts,
+        })?;
+
+        let response = client
+            .post(format!("{link_endpoint}/link/sync"))
+            .bearer_auth(link_token)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
+            .context("Failed to send account link sync request")?;
+
+        ensure!(
+            response.status().is_success(),
+            "Account link sync request failed with status {}",
+            response.status()
+        );
+
+        Ok(())
+    }
+}
@@ -1,17 +1,31 @@
+use std::{collections::HashMap, future::Future, time::Duration};
+
+#[cfg(feature = "archodex-com")]
+use axum::http::StatusCode;
 use axum::{Extension, Json};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use tracing::instrument;
+use tracing::{instrument, warn};
+use uuid::Uuid;
 
-use archodex_error::anyhow::Context as _;
+use archodex_error::{anyhow::Context as _, bad_request};
 
+#[cfg(feature = "archodex-com")]
+use crate::provisioning::{self, AccountStatusResponse};
 use crate::{
     Result,
     account::{Account, AccountPublic, AccountQueries},
-    auth::DashboardAuth,
+    account_cache,
+    audit::{self, AuditRequest, AuditTarget},
+    auth::{DashboardAuth, StepUp},
     db::{QueryCheckFirstRealError, accounts_db},
+    ingestion_pipeline::ProcessorConfig,
+    job::Job,
+    report_api_keys::MAX_REPORT_API_KEY_RESTORE_WINDOW_DAYS,
+    user::User,
 };
 
-#[derive(Serialize)]
+#[derive(JsonSchema, Serialize)]
 pub(crate) struct ListAccountsResponse {
     accounts: Vec<AccountPublic>,
 }
@@ -30,29 +44,69 @@ pub(crate) async fn list_accounts(
     Ok(Json(ListAccountsResponse { accounts }))
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub(super) struct CreateAccountRequest {
     #[cfg(not(feature = "archodex-com"))]
     account_id: String,
     #[cfg(feature = "archodex-com")]
     endpoint: Option<String>,
+    // AWS region to store the account's service data in, from the regions this backend supports. Defaults to the
+    // backend's default region.
+    #[cfg(feature = "archodex-com")]
+    #[serde(default)]
+    region: Option<String>,
+    // Optional dashboard generated ID to publish account creation progress to, see `job`
+    #[serde(default)]
+    job_id: Option<Uuid>,
 }
 
-#[instrument(err, skip(auth))]
+#[cfg(not(feature = "archodex-com"))]
+#[instrument(err, skip(auth, audit_request))]
 pub(crate) async fn create_account(
     Extension(auth): Extension<DashboardAuth>,
+    audit_request: AuditRequest,
     Json(req): Json<CreateAccountRequest>,
 ) -> Result<Json<AccountPublic>> {
-    #[cfg(not(feature = "archodex-com"))]
-    {
-        create_local_account(auth, req).await
-    }
+    let principal = auth.principal().clone();
+    let job = Job::new(req.job_id, &principal);
 
-    #[cfg(feature = "archodex-com")]
-    {
-        create_archodex_com_account(auth, req).await
+    let res = create_local_account(auth, req, &job).await;
+
+    if let Ok(Json(account)) = &res {
+        record_account_creation(&account.id, &principal, audit_request).await;
     }
+
+    job.finish(&res, None, None).await;
+
+    res
+}
+
+// Reserves an account ID and responds with 202 while the account is provisioned in the background, see `provisioning`
+#[cfg(feature = "archodex-com")]
+#[instrument(err, skip(auth, audit_request))]
+pub(crate) async fn create_account(
+    Extension(auth): Extension<DashboardAuth>,
+    audit_request: AuditRequest,
+    Json(req): Json<CreateAccountRequest>,
+) -> Result<(StatusCode, Json<AccountStatusResponse>)> {
+    use crate::env::Env;
+
+    let principal = auth.principal();
+    let job = Job::new(req.job_id, principal);
+
+    let endpoint = req.endpoint.as_deref().unwrap_or(Env::endpoint());
+
+    let status = provisioning::start(principal, endpoint, req.region.as_deref(), job).await?;
+
+    record_account_creation(status.id(), principal, audit_request).await;
+
+    Ok((StatusCode::ACCEPTED, Json(status)))
+}
+
+// Failures are logged by `audit::record`, and must not fail the account creation that already happened
+async fn record_account_creation(account_id: &str, principal: &User, audit_request: AuditRequest) {
+    let _ = audit::record(account_id, principal, "account.create", None, audit_request).await;
 }
 
 #[cfg(not(feature = "archodex-com"))]
@@ -60,16 +114,21 @@ pub(crate) async fn create_account(
 pub(crate) async fn create_local_account(
     auth: DashboardAuth,
     req: CreateAccountRequest,
+    job: &Job,
 ) -> Result<Json<AccountPublic>> {
     verify_no_local_accounts_exist().await?;
 
     let principal = auth.principal();
     principal.ensure_user_record_exists().await?;
 
+    job.step(10, "Creating account database");
+
     let account = Account::new(req.account_id, principal.clone())
         .await
         .context("Failed to create new account")?;
 
+    job.step(90, "Creating account record");
+
     accounts_db()
         .await?
         .create_account_query(&account, principal)
@@ -102,56 +161,374 @@ async fn verify_no_local_accounts_exist() -> Result<()> {
     Ok(())
 }
 
-#[cfg(feature = "archodex-com")]
-pub(crate) async fn create_archodex_com_account(
-    auth: DashboardAuth,
-    req: CreateAccountRequest,
-) -> Result<Json<AccountPublic>> {
-    use crate::env::Env;
+const ACCOUNT_NAME_MAX_LENGTH: usize = 100;
 
-    let endpoint = if let Some(endpoint) = req.endpoint {
-        endpoint
-    } else {
-        Env::endpoint().to_string()
-    };
+fn ensure_valid_default_environment(environment: Option<&str>) -> Result<()> {
+    if let Some(environment) = environment
+        && (environment.is_empty() || environment == "all")
+    {
+        bad_request!("Default environment must not be empty or `all`");
+    }
 
-    let accounts_db = accounts_db().await?;
+    Ok(())
+}
 
-    let principal = auth.principal();
-    principal.ensure_user_record_exists().await?;
+fn ensure_valid_resource_staleness_days(days: Option<u32>) -> Result<()> {
+    if days == Some(0) {
+        bad_request!("Resource staleness window must be at least one day");
+    }
+
+    Ok(())
+}
 
-    let next_account_id = principal.next_account_id().await?;
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SetDefaultEnvironmentRequest {
+    environment: Option<String>,
+}
+
+// Sets the environment that resource queries and searches of the account are scoped to unless the request asks for
+// another environment or `all` environments. A `null` environment removes the default.
+#[instrument(err, skip(account))]
+pub(crate) async fn set_default_environment(
+    Extension(account): Extension<Account>,
+    Json(req): Json<SetDefaultEnvironmentRequest>,
+) -> Result<()> {
+    ensure_valid_default_environment(req.environment.as_deref())?;
 
-    let account = Account::new(endpoint, next_account_id, principal.clone())
+    accounts_db()
+        .await?
+        .query("UPDATE $account SET default_environment = $environment RETURN NONE")
+        .bind(("account", surrealdb::sql::Thing::from(&account)))
+        .bind(("environment", req.environment))
         .await
-        .context("Failed to create new account")?;
+        .context("Failed to submit query to set account default environment")?
+        .check_first_real_error()
+        .context("Failed to set account default environment")?;
 
-    accounts_db
-        .create_account_query(&account, principal)
+    account_cache::invalidate();
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SetReportApiKeyApprovalRequest {
+    required: bool,
+}
+
+// Sets whether report keys created by account members must be approved by an account admin
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn set_report_api_key_approval(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Json(req): Json<SetReportApiKeyApprovalRequest>,
+) -> Result<()> {
+    auth.ensure_account_admin(account.id()).await?;
+
+    accounts_db()
+        .await?
+        .query("UPDATE $account SET report_api_key_approval_required = $required RETURN NONE")
+        .bind(("account", surrealdb::sql::Thing::from(&account)))
+        .bind(("required", req.required))
         .await
-        .context("Failed to commit account creation transaction")?
+        .context("Failed to submit query to set account report key approval")?
         .check_first_real_error()
-        .context("Failed to create new account record in accounts database")?;
+        .context("Failed to set account report key approval")?;
 
-    Ok(Json(account.into()))
+    account_cache::invalidate();
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SetDeadLetteringRequest {
+    enabled: bool,
+}
+
+// Sets whether reports that fail to be ingested are kept as dead letters, see `dead_letter`
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn set_dead_lettering(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Json(req): Json<SetDeadLetteringRequest>,
+) -> Result<()> {
+    auth.ensure_account_admin(account.id()).await?;
+
+    accounts_db()
+        .await?
+        .query("UPDATE $account SET dead_lettering_enabled = $enabled RETURN NONE")
+        .bind(("account", surrealdb::sql::Thing::from(&account)))
+        .bind(("enabled", req.enabled))
+        .await
+        .context("Failed to submit query to set account dead-lettering")?
+        .check_first_real_error()
+        .context("Failed to set account dead-lettering")?;
+
+    account_cache::invalidate();
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SetResourceStalenessRequest {
+    days: Option<u32>,
 }
 
+// Sets the number of days after which resources that have not been seen are archived. A `null` value disables archival
+// of stale resources.
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn set_resource_staleness(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Json(req): Json<SetResourceStalenessRequest>,
+) -> Result<()> {
+    auth.ensure_account_admin(account.id()).await?;
+
+    ensure_valid_resource_staleness_days(req.days)?;
+
+    accounts_db()
+        .await?
+        .query("UPDATE $account SET resource_staleness_days = $days RETURN NONE")
+        .bind(("account", surrealdb::sql::Thing::from(&account)))
+        .bind(("days", req.days))
+        .await
+        .context("Failed to submit query to set account resource staleness")?
+        .check_first_real_error()
+        .context("Failed to set account resource staleness")?;
+
+    account_cache::invalidate();
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct IngestionPipelineConfig {
+    processors: Vec<ProcessorConfig>,
+}
+
+#[instrument(err, skip_all)]
+pub(crate) async fn get_ingestion_pipeline(
+    Extension(account): Extension<Account>,
+) -> Result<Json<IngestionPipelineConfig>> {
+    Ok(Json(IngestionPipelineConfig {
+        processors: account.ingestion_pipeline().to_vec(),
+    }))
+}
+
+// Replaces the ordered list of pre-processors that reports sent to the account pass through before they are ingested
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn set_ingestion_pipeline(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Json(req): Json<IngestionPipelineConfig>,
+) -> Result<()> {
+    auth.ensure_account_admin(account.id()).await?;
+
+    accounts_db()
+        .await?
+        .query("UPDATE $account SET ingestion_pipeline = $ingestion_pipeline RETURN NONE")
+        .bind(("account", surrealdb::sql::Thing::from(&account)))
+        .bind(("ingestion_pipeline", req.processors))
+        .await
+        .context("Failed to submit query to set account ingestion pipeline")?
+        .check_first_real_error()
+        .context("Failed to set account ingestion pipeline")?;
+
+    account_cache::invalidate();
+
+    Ok(())
+}
+
+// Distinguishes fields set to `null` from fields left out of a request, which deserialize as `None`
+fn deserialize_some<'de, T, D>(deserializer: D) -> std::result::Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct UpdateAccountRequest {
+    // Display name of the account. `null` removes the name.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    name: Option<Option<String>>,
+    // See `set_default_environment`. `null` removes the default.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    default_environment: Option<Option<String>>,
+    // See `set_resource_staleness`. `null` disables archival of stale resources.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    resource_staleness_days: Option<Option<u32>>,
+    report_api_key_approval_required: Option<bool>,
+    dead_lettering_enabled: Option<bool>,
+    ingestion_pipeline: Option<Vec<ProcessorConfig>>,
+    // Number of days revoked report keys can be restored for, `0` to disallow restoring them. `null` restores the
+    // default window.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    report_api_key_restore_window_days: Option<Option<u32>>,
+}
+
+impl UpdateAccountRequest {
+    // Account members may only change the default environment, as with `set_default_environment`
+    fn requires_admin(&self) -> bool {
+        self.name.is_some()
+            || self.resource_staleness_days.is_some()
+            || self.report_api_key_approval_required.is_some()
+            || self.dead_lettering_enabled.is_some()
+            || self.ingestion_pipeline.is_some()
+            || self.report_api_key_restore_window_days.is_some()
+    }
+}
+
+// Updates the settings included in the request, leaving the others unchanged. The changed settings and their new values
+// are recorded in the audit log.
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn update_account(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Json(req): Json<UpdateAccountRequest>,
+) -> Result<(Extension<AuditTarget>, Json<AccountPublic>)> {
+    if req.requires_admin() {
+        auth.ensure_account_admin(account.id()).await?;
+    }
+
+    let name = req
+        .name
+        .map(|name| name.map(|name| name.trim().to_string()));
+    if let Some(Some(name)) = &name
+        && (name.is_empty() || name.chars().count() > ACCOUNT_NAME_MAX_LENGTH)
+    {
+        bad_request!("Account name must be between 1 and {ACCOUNT_NAME_MAX_LENGTH} characters");
+    }
+
+    if let Some(default_environment) = &req.default_environment {
+        ensure_valid_default_environment(default_environment.as_deref())?;
+    }
+
+    if let Some(days) = req.resource_staleness_days {
+        ensure_valid_resource_staleness_days(days)?;
+    }
+
+    if let Some(Some(days)) = req.report_api_key_restore_window_days
+        && days > MAX_REPORT_API_KEY_RESTORE_WINDOW_DAYS
+    {
+        bad_request!(
+            "Report key restore window must be at most {MAX_REPORT_API_KEY_RESTORE_WINDOW_DAYS} days"
+        );
+    }
+
+    let mut changes = HashMap::new();
+    let mut change = |setting: &str, value: serde_json::Value| {
+        changes.insert(setting.to_string(), value.to_string());
+        format!("{setting} = ${setting}")
+    };
+
+    let mut settings = Vec::new();
+    if let Some(name) = &name {
+        settings.push(change("name", serde_json::json!(name)));
+    }
+    if let Some(default_environment) = &req.default_environment {
+        settings.push(change(
+            "default_environment",
+            serde_json::json!(default_environment),
+        ));
+    }
+    if let Some(days) = req.resource_staleness_days {
+        settings.push(change("resource_staleness_days", serde_json::json!(days)));
+    }
+    if let Some(required) = req.report_api_key_approval_required {
+        settings.push(change(
+            "report_api_key_approval_required",
+            serde_json::json!(required),
+        ));
+    }
+    if let Some(enabled) = req.dead_lettering_enabled {
+        settings.push(change("dead_lettering_enabled", serde_json::json!(enabled)));
+    }
+    if let Some(ingestion_pipeline) = &req.ingestion_pipeline {
+        settings.push(change(
+            "ingestion_pipeline",
+            serde_json::to_value(ingestion_pipeline)?,
+        ));
+    }
+    if let Some(days) = req.report_api_key_restore_window_days {
+        settings.push(change(
+            "report_api_key_restore_window_days",
+            serde_json::json!(days),
+        ));
+    }
+
+    if settings.is_empty() {
+        bad_request!("Request must include at least one account setting");
+    }
+
+    let account = accounts_db()
+        .await?
+        .query(format!(
+            "UPDATE $account SET {} RETURN AFTER",
+            settings.join(", ")
+        ))
+        .bind(("account", surrealdb::sql::Thing::from(&account)))
+        .bind(("name", name.flatten()))
+        .bind(("default_environment", req.default_environment.flatten()))
+        .bind((
+            "resource_staleness_days",
+            req.resource_staleness_days.flatten(),
+        ))
+        .bind((
+            "report_api_key_approval_required",
+            req.report_api_key_approval_required,
+        ))
+        .bind(("dead_lettering_enabled", req.dead_lettering_enabled))
+        .bind(("ingestion_pipeline", req.ingestion_pipeline))
+        .bind((
+            "report_api_key_restore_window_days",
+            req.report_api_key_restore_window_days.flatten(),
+        ))
+        .await
+        .context("Failed to submit query to update account")?
+        .check_first_real_error()
+        .context("Failed to update account")?
+        .take::<Option<Account>>(0)?
+        .context("Account update should return the account")?;
+
+    account_cache::invalidate();
+
+    Ok((
+        Extension(AuditTarget(changes)),
+        Json(AccountPublic::from(account)),
+    ))
+}
+
+// Tears down the account's resources database, which removes its report keys, then marks the account record deleted and
+// removes access to it. Deleted accounts are no longer found by dashboard or report requests.
 #[instrument(err, skip_all)]
 pub(crate) async fn delete_account(
     Extension(auth): Extension<DashboardAuth>,
     Extension(account): Extension<Account>,
+    _step_up: StepUp,
 ) -> Result<()> {
+    auth.ensure_account_admin(account.id()).await?;
+
     auth.principal().ensure_user_record_exists().await?;
 
     let db = accounts_db().await?;
 
     #[cfg(not(feature = "archodex-com"))]
     {
-        db.query("REMOVE DATABASE resources")
-            .await
-            .context("Failed to submit query to delete data in resources database")?
-            .check_first_real_error()
-            .context("Failed to delete data in resources database")?;
+        db.query(format!(
+            "REMOVE DATABASE {}",
+            crate::tenant_naming::TenantNaming::current().resources_database()
+        ))
+        .await
+        .context("Failed to submit query to delete data in resources database")?
+        .check_first_real_error()
+        .context("Failed to delete data in resources database")?;
 
         // This will force the regeneration of the API private key if a new account is created
         crate::env::Env::clear_api_private_key().await;
@@ -169,5 +546,60 @@ pub(crate) async fn delete_account(
         .check_first_real_error()
         .context("Failed to delete account record in accounts database")?;
 
+    account_cache::invalidate();
+
     Ok(())
 }
+
+// Lists the accounts whose resources databases this backend serves. archodex.com accounts served by the backends of
+// other endpoints are left to those backends.
+#[instrument(err)]
+pub(crate) async fn list_served_accounts() -> Result<Vec<Account>> {
+    let db = accounts_db().await?;
+
+    #[cfg(not(feature = "archodex-com"))]
+    let query = db.query("SELECT * FROM account WHERE deleted_at IS NONE");
+
+    #[cfg(feature = "archodex-com")]
+    let query = db
+        .query("SELECT * FROM account WHERE deleted_at IS NONE AND endpoint = $endpoint")
+        .bind(("endpoint", crate::env::Env::endpoint().to_owned()));
+
+    Ok(query
+        .await
+        .context("Failed to submit query to list accounts")?
+        .check_first_real_error()
+        .context("Failed to list accounts")?
+        .take::<Vec<Account>>(0)?)
+}
+
+// Runs a background job for each account this backend serves once every interval. A failure for one account is logged
+// and doesn't stop the job for the others. Every backend instance runs the job, so jobs must tolerate running
+// concurrently for the same account.
+pub(crate) async fn for_each_account_periodically<F, Fut>(interval: Duration, job: &str, mut run: F)
+where
+    F: FnMut(Account) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let mut interval = tokio::time::interval(interval);
+
+    loop {
+        interval.tick().await;
+
+        let accounts = match list_served_accounts().await {
+            Ok(accounts) => accounts,
+            Err(err) => {
+                warn!(?err, job, "Failed to list accounts for periodic job");
+                continue;
+            }
+        };
+
+        for account in accounts {
+            let account_id = account.id().to_owned();
+
+            if let Err(err) = run(account).await {
+                warn!(?err, job, account_id, "Periodic account job failed");
+            }
+        }
+    }
+}
@@ -0,0 +1,202 @@
+// Cross-account operations for operators, served under `/admin` and authenticated with the `ADMIN_TOKEN` bearer token.
+// Prometheus metrics are also served at `/metrics` with the same authentication, and disaster recovery endpoints under
+// `/admin/dr`, see `dr`. Instances without an admin token don't serve them. archodex.com deployments should also
+// restrict `/admin` to operator IAM principals at the API gateway, as the token is shared by all operators.
+//
+// Admin requests aren't made by dashboard users, so they aren't recorded in account audit logs. Each operation is
+// logged instead.
+
+use std::collections::HashMap;
+
+use axum::{
+    Json,
+    extract::{Path, Query, Request},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument};
+
+use archodex_error::{
+    anyhow::{Context as _, bail},
+    bad_request, not_found,
+};
+
+use crate::{
+    Result,
+    account::Account,
+    account_cache,
+    auth::ensure_operator_token,
+    billing::Plan,
+    db::{QueryCheckFirstRealError as _, accounts_db, connect_resources_db, migrate_resources_db},
+    env::Env,
+    report_api_key::{ReportApiKey, ReportApiKeyQueries as _},
+    report_api_keys::report_api_key_id,
+    surrealdb_deserializers,
+};
+
+const DEFAULT_LIST_ACCOUNTS_LIMIT: u32 = 100;
+const MAX_LIST_ACCOUNTS_LIMIT: u32 = 1000;
+
+// Must be layered on the admin routes. Instances without an admin token don't reveal that the routes exist.
+pub(crate) async fn authenticate(req: Request, next: Next) -> Result<Response> {
+    let Some(admin_token) = Env::admin_token() else {
+        not_found!("Not found");
+    };
+
+    ensure_operator_token(req.headers(), admin_token, "admin")?;
+
+    Ok(next.run(req).await)
+}
+
+pub(crate) async fn account(params: &HashMap<String, String>) -> Result<Account> {
+    let Some(account_id) = params.get("account_id") else {
+        bail!("Missing account_id");
+    };
+
+    let Some(account) = account_cache::get_fresh_account(account_id).await? else {
+        not_found!("Account not found");
+    };
+
+    Ok(account)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ListAccountsRequest {
+    limit: Option<u32>,
+    // ID of the last account of the previous page
+    cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct AdminAccount {
+    #[serde(deserialize_with = "surrealdb_deserializers::string::deserialize")]
+    id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(default)]
+    plan: Plan,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deleted_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    suspended_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ingestion_paused_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ListAccountsResponse {
+    accounts: Vec<AdminAccount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+}
+
+// Lists all accounts ordered by ID, one page at a time, including deleted accounts
+#[instrument(err)]
+pub(crate) async fn list_accounts(
+    Query(req): Query<ListAccountsRequest>,
+) -> Result<Json<ListAccountsResponse>> {
+    let limit = req.limit.unwrap_or(DEFAULT_LIST_ACCOUNTS_LIMIT);
+    if limit == 0 || limit > MAX_LIST_ACCOUNTS_LIMIT {
+        bad_request!("Account `limit` must be between 1 and {MAX_LIST_ACCOUNTS_LIMIT}");
+    }
+
+    // Fetch one extra account to find out whether there is another page
+    let mut accounts = accounts_db()
+        .await?
+        .query("SELECT id, name, plan, created_at, deleted_at, suspended_at, ingestion_paused_until FROM account WHERE $cursor IS NONE OR id > type::thing('account', $cursor) ORDER BY id LIMIT $limit")
+        .bind(("cursor", req.cursor))
+        .bind(("limit", limit + 1))
+        .await
+        .context("Failed to submit query to list accounts")?
+        .check_first_real_error()
+        .context("Failed to list accounts")?
+        .take::<Vec<AdminAccount>>(0)?;
+
+    let next_cursor = if accounts.len() > limit as usize {
+        accounts.truncate(limit as usize);
+        accounts.last().map(|account| account.id.clone())
+    } else {
+        None
+    };
+
+    Ok(Json(ListAccountsResponse {
+        accounts,
+        next_cursor,
+    }))
+}
+
+// Migrates the account's resources database again, e.g. after a migration failed partway or the database was restored
+// from a backup taken before the latest migrations. Migrations are idempotent. Databases migrated before they recorded
+// the ID of their account don't pass verification once it is required, so they are connected to without it.
+#[instrument(err)]
+pub(crate) async fn migrate_account(Path(params): Path<HashMap<String, String>>) -> Result<()> {
+    let account = account(&params).await?;
+
+    let db = connect_resources_db(account.resources_db_url()?, account.id()).await?;
+
+    migrate_resources_db(&db, account.id())
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to migrate resources database of account {}",
+                account.id()
+            )
+        })?;
+
+    info!(
+        account_id = account.id(),
+        "Migrated account resources database"
+    );
+
+    Ok(())
+}
+
+// Hard-revokes a report key, e.g. one that leaked, whether or not it was already revoked. Hard-revoked keys can't be
+// restored by account admins.
+#[instrument(err)]
+pub(crate) async fn revoke_report_api_key(
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<()> {
+    let account = account(&params).await?;
+
+    let report_api_key_id = report_api_key_id(&params)?;
+
+    let db = account.resources_db().await?;
+
+    let revoked = db
+        .revoke_report_api_key_query(report_api_key_id, None, true, 0)
+        .await
+        .context("Failed to submit query to revoke report key")?
+        .check_first_real_error()
+        .context("Failed to revoke report key")?
+        .take::<Option<ReportApiKey>>(0)?;
+
+    // Keys that were already hard-revoked aren't updated
+    if revoked.is_none() {
+        let exists = db
+            .query("RETURN record::exists(type::thing('report_api_key', $report_api_key_id))")
+            .bind(("report_api_key_id", report_api_key_id))
+            .await
+            .context("Failed to submit query to get report key")?
+            .check_first_real_error()
+            .context("Failed to get report key")?
+            .take::<Option<bool>>(0)?
+            .unwrap_or(false);
+
+        if !exists {
+            not_found!("Report key not found");
+        }
+    }
+
+    info!(
+        account_id = account.id(),
+        report_api_key_id, "Force-revoked report key"
+    );
+
+    Ok(())
+}
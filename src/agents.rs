@@ -0,0 +1,211 @@
+// Agents register themselves under their report key when they start, recording their name, version, platform,
+// capabilities, and the report schema version they send. Registrations are persisted in the account's `agent` table, so
+// the dashboard can list the agents of an account and warn about agents that send an outdated report schema version.
+// An agent is identified by its report key and name. Admins retire agents that were decommissioned, and agents that
+// register again are no longer retired.
+
+use std::collections::HashMap;
+
+use axum::{Extension, Json, extract::Path};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument};
+use uuid::Uuid;
+
+use archodex_error::{
+    anyhow::{Context as _, bail},
+    bad_request, not_found,
+};
+
+use crate::{
+    Result,
+    account::Account,
+    auth::{DashboardAuth, ReportApiKeyAuth},
+    db::QueryCheckFirstRealError as _,
+    limits::ReportLimits,
+    report::REPORT_SCHEMA_VERSION,
+    surrealdb_deserializers,
+    user::User,
+};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RegisterAgentRequest {
+    name: String,
+    version: String,
+    platform: Option<String>,
+    #[serde(default)]
+    capabilities: Vec<String>,
+    report_schema_version: u32,
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
+pub(crate) struct Agent {
+    #[serde(deserialize_with = "surrealdb_deserializers::string::deserialize")]
+    id: String,
+    #[serde(deserialize_with = "surrealdb_deserializers::u32::deserialize")]
+    report_api_key_id: u32,
+    name: String,
+    version: String,
+    platform: Option<String>,
+    capabilities: Vec<String>,
+    report_schema_version: u32,
+    registered_at: DateTime<Utc>,
+    last_registered_at: DateTime<Utc>,
+    retired_at: Option<DateTime<Utc>>,
+    retired_by: Option<User>,
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct AgentStatus {
+    #[serde(flatten)]
+    agent: Agent,
+    // Whether the agent sends an older report schema version than the current version
+    report_schema_outdated: bool,
+}
+
+impl From<Agent> for AgentStatus {
+    fn from(agent: Agent) -> Self {
+        Self {
+            report_schema_outdated: agent.report_schema_version < REPORT_SCHEMA_VERSION,
+            agent,
+        }
+    }
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct RegisterAgentResponse {
+    agent: AgentStatus,
+    current_report_schema_version: u32,
+    // Limits on the contents of each report, so agents can split their captures across reports within the limits
+    report_limits: ReportLimits,
+}
+
+#[instrument(err, skip(auth, account), fields(account_id = account.id()))]
+pub(crate) async fn register(
+    Extension(auth): Extension<ReportApiKeyAuth>,
+    Extension(account): Extension<Account>,
+    Json(req): Json<RegisterAgentRequest>,
+) -> Result<Json<RegisterAgentResponse>> {
+    if req.name.trim().is_empty() {
+        bad_request!("Agent name must not be empty");
+    }
+
+    // The new ID is only used if the agent has not registered before, otherwise the unique index on the report key and
+    // name updates the existing registration
+    let agent = account
+        .resources_db()
+        .await?
+        .query(
+            "INSERT INTO agent {
+                id: $id,
+                report_api_key_id: $report_api_key_id,
+                name: $name,
+                version: $version,
+                platform: $platform,
+                capabilities: $capabilities,
+                report_schema_version: $report_schema_version,
+            } ON DUPLICATE KEY UPDATE
+                version = $input.version,
+                platform = $input.platform,
+                capabilities = $input.capabilities,
+                report_schema_version = $input.report_schema_version,
+                last_registered_at = time::now(),
+                retired_at = NONE,
+                retired_by = NONE
+            RETURN AFTER",
+        )
+        .bind(("id", Uuid::now_v7().simple().to_string()))
+        .bind(("report_api_key_id", auth.key_id()))
+        .bind(("name", req.name))
+        .bind(("version", req.version))
+        .bind(("platform", req.platform))
+        .bind(("capabilities", req.capabilities))
+        .bind(("report_schema_version", req.report_schema_version))
+        .await
+        .context("Failed to submit query to register agent")?
+        .check_first_real_error()
+        .context("Failed to register agent")?
+        .take::<Vec<Agent>>(0)?
+        .pop()
+        .expect("Register agent query should return an agent instance");
+
+    info!(
+        agent_id = agent.id,
+        name = agent.name,
+        version = agent.version,
+        report_schema_version = agent.report_schema_version,
+        "Registered agent"
+    );
+
+    Ok(Json(RegisterAgentResponse {
+        agent: AgentStatus::from(agent),
+        current_report_schema_version: REPORT_SCHEMA_VERSION,
+        report_limits: ReportLimits::current(),
+    }))
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct ListAgentsResponse {
+    agents: Vec<AgentStatus>,
+    current_report_schema_version: u32,
+}
+
+#[instrument(err, skip_all)]
+pub(crate) async fn list_agents(
+    Extension(account): Extension<Account>,
+) -> Result<Json<ListAgentsResponse>> {
+    let agents = account
+        .resources_db()
+        .await?
+        .query("SELECT * FROM agent ORDER BY report_api_key_id, name")
+        .await
+        .context("Failed to submit query to list agents")?
+        .check_first_real_error()
+        .context("Failed to list agents")?
+        .take::<Vec<Agent>>(0)?
+        .into_iter()
+        .map(AgentStatus::from)
+        .collect();
+
+    Ok(Json(ListAgentsResponse {
+        agents,
+        current_report_schema_version: REPORT_SCHEMA_VERSION,
+    }))
+}
+
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn retire_agent(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<AgentStatus>> {
+    auth.ensure_account_admin(account.id()).await?;
+
+    let Some(agent_id) = params.get("agent_id") else {
+        bail!("Missing agent_id");
+    };
+
+    let Some(agent) = account
+        .resources_db()
+        .await?
+        .query("UPDATE $agent SET retired_at = time::now(), retired_by = $user RETURN AFTER")
+        .bind((
+            "agent",
+            surrealdb::sql::Thing::from(("agent", agent_id.as_str())),
+        ))
+        .bind(("user", surrealdb::sql::Thing::from(auth.principal())))
+        .await
+        .context("Failed to submit query to retire agent")?
+        .check_first_real_error()
+        .context("Failed to retire agent")?
+        .take::<Option<Agent>>(0)?
+    else {
+        not_found!("Agent not found");
+    };
+
+    info!(agent_id, "Retired agent");
+
+    Ok(Json(AgentStatus::from(agent)))
+}
@@ -0,0 +1,387 @@
+// Account API tokens let users script read access to an account's dashboard API, e.g. to export query results from CI.
+// Members create tokens for themselves with `POST /account/:account_id/tokens`, and present them as bearer tokens in
+// place of dashboard access tokens. Requests authenticated with a token act as the member who created it, so tokens
+// stop working when the member loses access to the account.
+//
+// Tokens are limited to one account and to the routes of their scopes, all of which only read:
+//
+// * `list`: listing resources, type paths, agents, and coverage
+// * `query`: queries, GraphQL, search, and principal chains
+// * `read_only`: every route that doesn't modify the account
+//
+// Tokens have the form `archodex_pat_<token ID>.<hex encoded secret>`, so they can be told apart from dashboard access
+// tokens and found by secret scanners. Only a hash of the secret is stored, so the token value is only available in
+// the response to creating it. Tokens expire, at most `MAX_API_TOKEN_TTL_DAYS` days after creation, and are deleted
+// once revoked. Expired tokens are deleted by `prune_expired_api_tokens_periodically`.
+
+use std::{collections::HashMap, time::Duration};
+
+use axum::{Extension, Json, extract::Path};
+use chrono::{DateTime, Utc};
+use rand::Rng as _;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+use archodex_error::{
+    anyhow::{Context as _, bail},
+    bad_request, conflict, not_found, unauthorized,
+};
+
+use crate::{
+    Result,
+    account::Account,
+    accounts::for_each_account_periodically,
+    auth::{DashboardAuth, StepUp},
+    db::{QueryCheckFirstRealError as _, accounts_db},
+    standby, surrealdb_deserializers,
+    user::User,
+};
+
+pub(crate) const API_TOKEN_PREFIX: &str = "archodex_pat_";
+
+const DEFAULT_API_TOKEN_TTL_DAYS: u32 = 90;
+const MAX_API_TOKEN_TTL_DAYS: u32 = 365;
+const MAX_API_TOKENS_PER_MEMBER: usize = 20;
+const MAX_API_TOKEN_NAME_LENGTH: usize = 100;
+// Token use is recorded at most this often, so scripts polling the API don't write on every request
+const LAST_USED_AT_RESOLUTION: &str = "1m";
+
+const API_TOKEN_PRUNING_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const ACCOUNT_ROUTE_PREFIX: &str = "/account/:account_id/";
+
+// Routes of the `list` scope, relative to the account
+const LIST_ROUTES: &[&str] = &[
+    "resources",
+    "type_paths",
+    "agents",
+    "coverage",
+    "coverage/blind_spots",
+];
+
+// Routes of the `query` scope, relative to the account
+const QUERY_ROUTES: &[&str] = &[
+    "query/:type",
+    "graphql",
+    "search",
+    "principal_chain",
+    "principal_chain/traverse",
+];
+
+#[derive(
+    Clone, Copy, Debug, Deserialize, Eq, Hash, JsonSchema, Ord, PartialEq, PartialOrd, Serialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ApiTokenScope {
+    List,
+    Query,
+    ReadOnly,
+}
+
+impl ApiTokenScope {
+    // Whether the scope allows requests to the account route, relative to the account
+    fn allows(self, account_route: &str) -> bool {
+        match self {
+            ApiTokenScope::List => LIST_ROUTES.contains(&account_route),
+            ApiTokenScope::Query => QUERY_ROUTES.contains(&account_route),
+            ApiTokenScope::ReadOnly => true,
+        }
+    }
+}
+
+// Account and scopes a request authenticated with an API token is limited to
+#[derive(Clone, Debug)]
+pub(crate) struct ApiTokenGrant {
+    token_id: String,
+    account_id: String,
+    scopes: Vec<ApiTokenScope>,
+}
+
+impl ApiTokenGrant {
+    pub(crate) fn token_id(&self) -> &str {
+        &self.token_id
+    }
+
+    pub(crate) fn account_id(&self) -> &str {
+        &self.account_id
+    }
+
+    // Whether the grant allows requests to the route, given as matched by the router. Tokens only ever allow read-only
+    // requests to routes of their account.
+    pub(crate) fn allows(&self, route: Option<&str>, read_only: bool) -> bool {
+        let Some(account_route) = route.and_then(|route| route.strip_prefix(ACCOUNT_ROUTE_PREFIX))
+        else {
+            return false;
+        };
+
+        read_only && self.scopes.iter().any(|scope| scope.allows(account_route))
+    }
+}
+
+fn api_token_thing(token_id: &str) -> surrealdb::sql::Thing {
+    surrealdb::sql::Thing::from(("api_token", token_id))
+}
+
+#[instrument(err)]
+async fn record_use(token_id: &str) -> Result<()> {
+    accounts_db()
+        .await?
+        .query(format!("UPDATE $token SET last_used_at = time::now() WHERE last_used_at IS NONE OR last_used_at < time::now() - {LAST_USED_AT_RESOLUTION} RETURN NONE"))
+        .bind(("token", api_token_thing(token_id)))
+        .await
+        .context("Failed to submit query to record API token use")?
+        .check_first_real_error()
+        .context("Failed to record API token use")?;
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct VerifiedApiToken {
+    #[serde(deserialize_with = "surrealdb_deserializers::string::deserialize")]
+    account_id: String,
+    created_by: User,
+    scopes: Vec<ApiTokenScope>,
+}
+
+// Verifies an API token, without its `archodex_pat_` prefix, and returns the member who created it and what it grants
+#[instrument(err, skip_all)]
+pub(crate) async fn verify(token: &str) -> Result<(User, ApiTokenGrant)> {
+    let Some((token_id, secret)) = token.split_once('.') else {
+        warn!("Invalid API token format");
+        unauthorized!();
+    };
+
+    let Ok(secret) = hex::decode(secret) else {
+        warn!("Invalid API token format");
+        unauthorized!();
+    };
+
+    let Some(verified) = accounts_db()
+        .await?
+        .query("SELECT record::id(account) AS account_id, created_by, scopes FROM $token WHERE token_hash == $token_hash AND expires_at > time::now() AND account.deleted_at IS NONE")
+        .bind(("token", api_token_thing(token_id)))
+        .bind((
+            "token_hash",
+            surrealdb::sql::Bytes::from(Sha256::digest(secret).to_vec()),
+        ))
+        .await
+        .context("Failed to submit query to verify API token")?
+        .check_first_real_error()
+        .context("Failed to verify API token")?
+        .take::<Vec<VerifiedApiToken>>(0)?
+        .pop()
+    else {
+        warn!(token_id, "API token not found, expired, or secret is invalid");
+        unauthorized!();
+    };
+
+    // Standby databases may be read-only replicas. Failures are logged by `record_use`, and must not fail the request.
+    if !standby::is_standby() {
+        let _ = record_use(token_id).await;
+    }
+
+    Ok((
+        verified.created_by,
+        ApiTokenGrant {
+            token_id: token_id.to_string(),
+            account_id: verified.account_id,
+            scopes: verified.scopes,
+        },
+    ))
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
+pub(crate) struct ApiToken {
+    #[serde(deserialize_with = "surrealdb_deserializers::string::deserialize")]
+    id: String,
+    name: String,
+    scopes: Vec<ApiTokenScope>,
+    created_at: DateTime<Utc>,
+    created_by: User,
+    expires_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct ListApiTokensResponse {
+    api_tokens: Vec<ApiToken>,
+}
+
+// Admins see every token of the account, other members only see their own
+#[instrument(err, skip_all)]
+pub(crate) async fn list_api_tokens(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+) -> Result<Json<ListApiTokensResponse>> {
+    let is_admin = auth.is_account_admin(account.id()).await?;
+
+    let api_tokens = accounts_db()
+        .await?
+        .query("SELECT * FROM api_token WHERE account == $account AND ($is_admin OR created_by == $user) AND expires_at > time::now() ORDER BY created_at")
+        .bind(("account", surrealdb::sql::Thing::from(&account)))
+        .bind(("user", surrealdb::sql::Thing::from(auth.principal())))
+        .bind(("is_admin", is_admin))
+        .await
+        .context("Failed to submit query to list API tokens")?
+        .check_first_real_error()
+        .context("Failed to list API tokens")?
+        .take::<Vec<ApiToken>>(0)?;
+
+    Ok(Json(ListApiTokensResponse { api_tokens }))
+}
+
+/// Periodically deletes expired API tokens of every account.
+pub async fn prune_expired_api_tokens_periodically() {
+    for_each_account_periodically(
+        API_TOKEN_PRUNING_INTERVAL,
+        "API token pruning",
+        |account| async move { prune_expired_api_tokens(&account).await },
+    )
+    .await;
+}
+
+#[instrument(err, skip_all, fields(account_id = account.id()))]
+async fn prune_expired_api_tokens(account: &Account) -> Result<()> {
+    let pruned = accounts_db()
+        .await?
+        .query("RETURN array::len(DELETE api_token WHERE account == $account AND expires_at <= time::now() RETURN BEFORE)")
+        .bind(("account", surrealdb::sql::Thing::from(account)))
+        .await
+        .context("Failed to submit query to prune expired API tokens")?
+        .check_first_real_error()
+        .context("Failed to prune expired API tokens")?
+        .take::<Option<usize>>(0)?
+        .unwrap_or(0);
+
+    if pruned > 0 {
+        info!(tokens = pruned, "Pruned expired API tokens");
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CreateApiTokenRequest {
+    name: String,
+    scopes: Vec<ApiTokenScope>,
+    // Defaults to `DEFAULT_API_TOKEN_TTL_DAYS`
+    expires_in_days: Option<u32>,
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct CreateApiTokenResponse {
+    api_token: ApiToken,
+    token: String,
+}
+
+// Creating a token hands out lasting access to the account, so it requires a recent sign in, which also keeps tokens
+// from being created with other tokens
+#[instrument(err, skip(auth, account, req))]
+pub(crate) async fn create_api_token(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    _step_up: StepUp,
+    Json(req): Json<CreateApiTokenRequest>,
+) -> Result<Json<CreateApiTokenResponse>> {
+    let name = req.name.trim().to_string();
+    if name.is_empty() || name.chars().count() > MAX_API_TOKEN_NAME_LENGTH {
+        bad_request!("API token name must be between 1 and {MAX_API_TOKEN_NAME_LENGTH} characters");
+    }
+
+    let mut scopes = req.scopes;
+    scopes.sort();
+    scopes.dedup();
+    if scopes.is_empty() {
+        bad_request!("API tokens must have at least one scope");
+    }
+
+    let expires_in_days = req.expires_in_days.unwrap_or(DEFAULT_API_TOKEN_TTL_DAYS);
+    if !(1..=MAX_API_TOKEN_TTL_DAYS).contains(&expires_in_days) {
+        bad_request!("API tokens must expire within 1 to {MAX_API_TOKEN_TTL_DAYS} days");
+    }
+
+    let db = accounts_db().await?;
+
+    let member_tokens = db
+        .query("SELECT VALUE id FROM api_token WHERE account == $account AND created_by == $user AND expires_at > time::now()")
+        .bind(("account", surrealdb::sql::Thing::from(&account)))
+        .bind(("user", surrealdb::sql::Thing::from(auth.principal())))
+        .await
+        .context("Failed to submit query to count API tokens")?
+        .check_first_real_error()
+        .context("Failed to count API tokens")?
+        .take::<Vec<surrealdb::RecordId>>(0)?;
+
+    if member_tokens.len() >= MAX_API_TOKENS_PER_MEMBER {
+        conflict!(
+            "Members may have at most {MAX_API_TOKENS_PER_MEMBER} API tokens per account, revoke unused tokens first"
+        );
+    }
+
+    let token_id = Uuid::now_v7().simple().to_string();
+    let secret = rand::thread_rng().r#gen::<[u8; 32]>();
+    let token = format!("{API_TOKEN_PREFIX}{token_id}.{}", hex::encode(secret));
+
+    let api_token = db
+        .query("CREATE $token CONTENT { account: $account, name: $name, scopes: $scopes, token_hash: $token_hash, created_by: $created_by, expires_at: time::now() + duration::from::days($expires_in_days) }")
+        .bind(("token", api_token_thing(&token_id)))
+        .bind(("account", surrealdb::sql::Thing::from(&account)))
+        .bind(("name", name))
+        .bind(("scopes", scopes))
+        .bind((
+            "token_hash",
+            surrealdb::sql::Bytes::from(Sha256::digest(secret).to_vec()),
+        ))
+        .bind(("created_by", surrealdb::sql::Thing::from(auth.principal())))
+        .bind(("expires_in_days", expires_in_days))
+        .await
+        .context("Failed to submit query to create API token")?
+        .check_first_real_error()
+        .context("Failed to create API token")?
+        .take::<Option<ApiToken>>(0)?
+        .expect("Create API token query should return an API token instance");
+
+    info!(token_id, scopes = ?api_token.scopes, "Created API token");
+
+    Ok(Json(CreateApiTokenResponse { api_token, token }))
+}
+
+// Members may revoke their own tokens, and admins any token of the account
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn revoke_api_token(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<()>> {
+    let Some(token_id) = params.get("token_id") else {
+        bail!("Missing token_id");
+    };
+
+    let is_admin = auth.is_account_admin(account.id()).await?;
+
+    let revoked = accounts_db()
+        .await?
+        .query("DELETE $token WHERE account == $account AND ($is_admin OR created_by == $user) RETURN BEFORE")
+        .bind(("token", api_token_thing(token_id)))
+        .bind(("account", surrealdb::sql::Thing::from(&account)))
+        .bind(("user", surrealdb::sql::Thing::from(auth.principal())))
+        .bind(("is_admin", is_admin))
+        .await
+        .context("Failed to submit query to revoke API token")?
+        .check_first_real_error()
+        .context("Failed to revoke API token")?
+        .take::<Vec<surrealdb::sql::Object>>(0)?;
+
+    if revoked.is_empty() {
+        not_found!("API token not found");
+    }
+
+    info!(token_id, "Revoked API token");
+
+    Ok(Json(()))
+}
@@ -0,0 +1,428 @@
+// Audit log of mutations made through the dashboard API, recording who did what to which account and when. Mutations of
+// an account are recorded by a middleware of the account routes once they succeed, while mutations outside of an
+// account's routes (account creation and accepting invitations) record their entries themselves. Entries are stored in
+// the accounts database rather than the account's resources database, so they outlive the deletion of the account.
+//
+// Entries are kept indefinitely unless the account sets an audit log retention, after which they are deleted by
+// `expire_audit_log_entries_periodically`. Entries can't be deleted otherwise. Accounts with write-once retention can't
+// unset write-once retention or shorten it. Both are also enforced by the accounts database.
+
+use std::{collections::HashMap, convert::Infallible, time::Duration};
+
+use axum::{
+    Extension, Json, async_trait,
+    extract::{FromRequestParts, MatchedPath, Path, Query, Request},
+    http::{Method, header::USER_AGENT, request::Parts},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument};
+use uuid::Uuid;
+
+use archodex_error::{anyhow::Context as _, bad_request, conflict};
+
+use crate::{
+    Result,
+    account::Account,
+    account_cache,
+    accounts::for_each_account_periodically,
+    auth::DashboardAuth,
+    db::{QueryCheckFirstRealError as _, accounts_db},
+    request_id::REQUEST_ID_HEADER,
+    router, surrealdb_deserializers,
+    user::User,
+};
+
+const DEFAULT_LIST_AUDIT_LOG_LIMIT: u32 = 100;
+const MAX_LIST_AUDIT_LOG_LIMIT: u32 = 1000;
+
+const AUDIT_LOG_EXPIRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Metadata of the request that made an audited mutation
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
+pub(crate) struct AuditRequest {
+    method: String,
+    path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user_agent: Option<String>,
+    // Set by load balancers in front of the backend
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    forwarded_for: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+impl AuditRequest {
+    fn from_parts(parts: &Parts) -> Self {
+        let header = |name: &str| {
+            parts
+                .headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+
+        Self {
+            method: parts.method.to_string(),
+            path: parts.uri.path().to_string(),
+            user_agent: header(USER_AGENT.as_str()),
+            forwarded_for: header("X-Forwarded-For"),
+            request_id: header(REQUEST_ID_HEADER.as_str()),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for AuditRequest {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> std::result::Result<Self, Self::Rejection> {
+        Ok(Self::from_parts(parts))
+    }
+}
+
+// Describes the targets of a mutation that aren't path parameters of its route, e.g. the operations of a composite
+// operation. Handlers add it to their response to have it recorded with the route's path parameters.
+#[derive(Clone, Debug)]
+pub(crate) struct AuditTarget(pub(crate) HashMap<String, String>);
+
+// Names the action of a mutating account route, relative to the account's routes. Routes without a name are recorded by
+// method and route, so new routes are audited before they are named here.
+fn action(method: &Method, route: &str) -> String {
+    let route = route.strip_prefix("/account/:account_id").unwrap_or(route);
+
+    let action = match (method.as_str(), route) {
+        ("POST", "/resource/set_environments") => "resource.set_environments",
+        ("POST", "/resource/tags") => "resource.set_tags",
+        ("POST", "/environments") => "environment.create",
+        ("PUT", "/environment/:environment") => "environment.update",
+        ("DELETE", "/environment/:environment") => "environment.delete",
+        ("POST", "/resource/archive") => "resource.archive",
+        ("POST", "/resource/unarchive") => "resource.unarchive",
+        ("POST", "/resources/delete") => "resource.delete",
+        ("POST", "/import") => "resource.import",
+        ("POST", "/report_api_keys") => "report_api_key.create",
+        ("DELETE", "/report_api_key/:report_api_key_id") => "report_api_key.revoke",
+        ("POST", "/report_api_key/:report_api_key_id/value") => "report_api_key.reveal_value",
+        ("POST", "/report_api_key/:report_api_key_id/restore") => "report_api_key.restore",
+        ("POST", "/report_api_key/:report_api_key_id/rotate") => "report_api_key.rotate",
+        ("POST", "/report_api_key_request/:report_api_key_request_id/approve") => {
+            "report_api_key_request.approve"
+        }
+        ("POST", "/report_api_key_request/:report_api_key_request_id/reject") => {
+            "report_api_key_request.reject"
+        }
+        ("POST", "/report_api_key_request/:report_api_key_request_id/value") => {
+            "report_api_key_request.retrieve_value"
+        }
+        ("PUT", "/ingestion_pipeline") => "account.set_ingestion_pipeline",
+        ("PUT", "/report_api_key_approval") => "account.set_report_api_key_approval",
+        ("PUT", "/default_environment") => "account.set_default_environment",
+        ("PUT", "/resource_staleness") => "account.set_resource_staleness",
+        ("PUT", "/dead_lettering") => "account.set_dead_lettering",
+        ("DELETE", "/dead_letter_reports") => "dead_letter_report.purge_all",
+        ("DELETE", "/dead_letter_report/:dead_letter_report_id") => "dead_letter_report.purge",
+        ("POST", "/dead_letter_report/:dead_letter_report_id/retry") => "dead_letter_report.retry",
+        ("PUT", "/audit_log_retention") => "account.set_audit_log_retention",
+        ("PUT", "/event_retention") => "account.set_event_retention",
+        ("PUT", "/export_encryption_key") => "account.set_export_encryption_key",
+        ("DELETE", "/export_encryption_key") => "account.delete_export_encryption_key",
+        ("PATCH", "" | "/") => "account.update",
+        ("DELETE", "" | "/") => "account.delete",
+        ("POST", "/agent/:agent_id/retire") => "agent.retire",
+        ("POST", "/operations") => "operations.execute",
+        ("PUT", "/member/:user_id") => "member.set_role",
+        ("DELETE", "/member/:user_id") => "member.remove",
+        ("POST", "/invitations") => "invitation.create",
+        ("DELETE", "/invitation/:invitation_id") => "invitation.revoke",
+        ("POST", "/tokens") => "api_token.create",
+        ("DELETE", "/token/:token_id") => "api_token.revoke",
+        ("PUT", "/saml") => "account.set_saml_config",
+        ("DELETE", "/saml") => "account.delete_saml_config",
+        ("POST", "/webhooks") => "webhook.create",
+        ("PUT", "/webhook/:webhook_id") => "webhook.update",
+        ("DELETE", "/webhook/:webhook_id") => "webhook.delete",
+        ("POST", "/account_links") => "account_link.create",
+        ("DELETE", "/account_link/:account_link_id") => "account_link.revoke",
+        ("POST", "/linked_accounts") => "linked_account.create",
+        ("DELETE", "/linked_account/:linked_account_id") => "linked_account.delete",
+        ("POST", "/resource/share") => "resource.share",
+        _ => return format!("{method} {route}"),
+    };
+
+    action.to_string()
+}
+
+// Records successful mutations of an account. Must be layered inside middlewares that insert the `DashboardAuth` and
+// `Account` extensions. The path parameters of the route other than the account ID identify the target of the action.
+pub(crate) async fn record_mutation(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Path(mut params): Path<HashMap<String, String>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if router::is_read_only(&req) {
+        return next.run(req).await;
+    }
+
+    let route = req.extensions().get::<MatchedPath>().map_or_else(
+        || req.uri().path().to_string(),
+        |path| path.as_str().to_string(),
+    );
+    let action = action(req.method(), &route);
+
+    let (parts, body) = req.into_parts();
+    let audit_request = AuditRequest::from_parts(&parts);
+
+    let response = next.run(Request::from_parts(parts, body)).await;
+
+    if response.status().is_success() {
+        params.remove("account_id");
+
+        if let Some(AuditTarget(target)) = response.extensions().get::<AuditTarget>() {
+            params.extend(target.clone());
+        }
+
+        // The mutation has already been made, so failing to record it must not fail the request. Failures are logged by
+        // `record`.
+        let _ = record(
+            account.id(),
+            auth.principal(),
+            &action,
+            (!params.is_empty()).then_some(params),
+            audit_request,
+        )
+        .await;
+    }
+
+    response
+}
+
+#[instrument(err, skip(actor, request))]
+pub(crate) async fn record(
+    account_id: &str,
+    actor: &User,
+    action: &str,
+    target: Option<HashMap<String, String>>,
+    request: AuditRequest,
+) -> Result<()> {
+    accounts_db()
+        .await?
+        .query("CREATE $entry CONTENT { account: $account, actor: $actor, action: $action, target: $target, request: $request } RETURN NONE")
+        .bind((
+            "entry",
+            surrealdb::sql::Thing::from(("audit_log", Uuid::now_v7().simple().to_string().as_str())),
+        ))
+        .bind(("account", surrealdb::sql::Thing::from(("account", account_id))))
+        .bind(("actor", surrealdb::sql::Thing::from(actor)))
+        .bind(("action", action.to_string()))
+        .bind(("target", target))
+        .bind(("request", request))
+        .await
+        .context("Failed to submit query to record audit log entry")?
+        .check_first_real_error()
+        .context("Failed to record audit log entry")?;
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
+pub(crate) struct AuditLogEntry {
+    #[serde(deserialize_with = "surrealdb_deserializers::string::deserialize")]
+    id: String,
+    occurred_at: DateTime<Utc>,
+    actor: User,
+    action: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<HashMap<String, String>>,
+    request: AuditRequest,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ListAuditLogRequest {
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    limit: Option<u32>,
+    // ID of the last entry of the previous page
+    cursor: Option<String>,
+}
+
+#[derive(Debug, JsonSchema, Serialize)]
+pub(crate) struct ListAuditLogResponse {
+    entries: Vec<AuditLogEntry>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+}
+
+// Lists audit log entries of the account from newest to oldest, one page at a time. Entry IDs are time ordered, so the
+// ID of the last entry of a page is the cursor of the next page.
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn list_audit_log(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Query(req): Query<ListAuditLogRequest>,
+) -> Result<Json<ListAuditLogResponse>> {
+    auth.ensure_account_admin(account.id()).await?;
+
+    let limit = req.limit.unwrap_or(DEFAULT_LIST_AUDIT_LOG_LIMIT);
+    if limit == 0 || limit > MAX_LIST_AUDIT_LOG_LIMIT {
+        bad_request!("Audit log `limit` must be between 1 and {MAX_LIST_AUDIT_LOG_LIMIT}");
+    }
+
+    let cursor = match &req.cursor {
+        Some(cursor) => {
+            let Ok(cursor) = Uuid::try_parse(cursor) else {
+                bad_request!("Invalid audit log `cursor`");
+            };
+            Some(surrealdb::sql::Thing::from((
+                "audit_log",
+                cursor.simple().to_string().as_str(),
+            )))
+        }
+        None => None,
+    };
+
+    let mut conditions = vec!["account = $account"];
+
+    for (is_set, condition) in [
+        (cursor.is_some(), "id < $cursor"),
+        (req.since.is_some(), "occurred_at >= $since"),
+        (req.until.is_some(), "occurred_at < $until"),
+    ] {
+        if is_set {
+            conditions.push(condition);
+        }
+    }
+
+    // Fetch one extra entry to find out whether there is another page
+    let mut entries = accounts_db()
+        .await?
+        .query(format!(
+            "SELECT * FROM audit_log WHERE {} ORDER BY id DESC LIMIT $limit",
+            conditions.join(" AND ")
+        ))
+        .bind(("account", surrealdb::sql::Thing::from(&account)))
+        .bind(("limit", limit + 1))
+        .bind(("cursor", cursor))
+        .bind(("since", req.since.map(surrealdb::sql::Datetime::from)))
+        .bind(("until", req.until.map(surrealdb::sql::Datetime::from)))
+        .await
+        .context("Failed to submit query to list audit log")?
+        .check_first_real_error()
+        .context("Failed to list audit log")?
+        .take::<Vec<AuditLogEntry>>(0)?;
+
+    let next_cursor = if entries.len() > limit as usize {
+        entries.truncate(limit as usize);
+        entries.last().map(|entry| entry.id.clone())
+    } else {
+        None
+    };
+
+    Ok(Json(ListAuditLogResponse {
+        entries,
+        next_cursor,
+    }))
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct AuditLogRetention {
+    // Number of days after which entries are deleted. Entries are kept indefinitely when unset.
+    days: Option<u32>,
+    // Whether entries cannot be deleted until they expire. Cannot be unset once set.
+    #[serde(default)]
+    write_once: bool,
+}
+
+#[instrument(err, skip_all, fields(account_id = account.id()))]
+pub(crate) async fn get_audit_log_retention(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+) -> Result<Json<AuditLogRetention>> {
+    auth.ensure_account_admin(account.id()).await?;
+
+    Ok(Json(AuditLogRetention {
+        days: account.audit_log_retention_days(),
+        write_once: account.audit_log_write_once(),
+    }))
+}
+
+// Sets how long audit log entries are kept. Write-once retention can only be lengthened, which is checked against the
+// account record rather than the cached account so concurrent requests can't shorten it.
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn set_audit_log_retention(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Json(req): Json<AuditLogRetention>,
+) -> Result<()> {
+    auth.ensure_account_admin(account.id()).await?;
+
+    if req.days == Some(0) {
+        bad_request!("Audit log retention must be at least one day");
+    }
+
+    let updated = accounts_db()
+        .await?
+        .query("UPDATE $account SET audit_log_retention_days = $days, audit_log_write_once = $write_once WHERE audit_log_write_once != true OR ($write_once AND ($days IS NONE OR (audit_log_retention_days IS NOT NONE AND $days >= audit_log_retention_days))) RETURN VALUE id")
+        .bind(("account", surrealdb::sql::Thing::from(&account)))
+        .bind(("days", req.days))
+        .bind(("write_once", req.write_once))
+        .await
+        .context("Failed to submit query to set account audit log retention")?
+        .check_first_real_error()
+        .context("Failed to set account audit log retention")?
+        .take::<Vec<surrealdb::sql::Thing>>(0)?;
+
+    if updated.is_empty() {
+        conflict!("Write-once audit log retention cannot be unset or shortened");
+    }
+
+    account_cache::invalidate();
+
+    Ok(())
+}
+
+/// Periodically deletes audit log entries of every account that sets an audit log retention once they expire. Entries
+/// of deleted accounts are kept.
+pub async fn expire_audit_log_entries_periodically() {
+    for_each_account_periodically(
+        AUDIT_LOG_EXPIRY_INTERVAL,
+        "audit log expiry",
+        |account| async move { expire_audit_log_entries(&account).await },
+    )
+    .await;
+}
+
+#[instrument(err, skip_all, fields(account_id = account.id()))]
+async fn expire_audit_log_entries(account: &Account) -> Result<()> {
+    let Some(audit_log_retention_days) = account.audit_log_retention_days() else {
+        return Ok(());
+    };
+
+    // Expiry is computed by the database, like the expiry check of the `immutable` event of `audit_log`
+    let expired = accounts_db()
+        .await?
+        .query("DELETE audit_log WHERE account = $account AND occurred_at < time::now() - duration::from::days($days) RETURN VALUE id")
+        .bind(("account", surrealdb::sql::Thing::from(account)))
+        .bind(("days", audit_log_retention_days))
+        .await
+        .context("Failed to submit query to expire audit log entries")?
+        .check_first_real_error()
+        .context("Failed to expire audit log entries")?
+        .take::<Vec<surrealdb::sql::Thing>>(0)?;
+
+    if !expired.is_empty() {
+        info!(entries = expired.len(), "Expired audit log entries");
+    }
+
+    Ok(())
+}
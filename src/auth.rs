@@ -1,92 +1,283 @@
-use std::{collections::HashMap, time::SystemTime};
-
-use axum::{extract::Request, middleware::Next, response::Response};
-use josekit::{
-    JoseError,
-    jwk::JwkSet,
-    jws::alg::rsassa::{RsassaJwsAlgorithm, RsassaJwsVerifier},
-    jwt,
+use std::time::SystemTime;
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, MatchedPath, Request},
+    http::{HeaderMap, request::Parts},
+    middleware::Next,
+    response::Response,
 };
+use chrono::{DateTime, Utc};
+use josekit::jwt;
 use reqwest::header::AUTHORIZATION;
-use surrealdb::{Surreal, Uuid, engine::any::Any};
-use tokio::sync::OnceCell;
-use tracing::{Instrument as _, error_span, info, instrument, warn};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use surrealdb::Uuid;
+use tracing::{Instrument as _, error_span, instrument, warn};
 
 use crate::{
     Result,
+    account::Account,
+    api_tokens::{self, API_TOKEN_PREFIX, ApiTokenGrant},
     db::{QueryCheckFirstRealError, accounts_db},
     env::Env,
+    ingestion_token,
+    metrics::AUTH_FAILURES_TOTAL,
+    oidc,
     report_api_key::{ReportApiKey, ReportApiKeyIsValidQueryResponse, ReportApiKeyQueries},
+    report_key_backoff, router,
+    saml::{self, SAML_SESSION_PREFIX},
     user::User,
 };
 use archodex_error::{
-    anyhow::{Context as _, anyhow},
-    not_found, unauthorized,
+    ErrorCode, PublicError, anyhow::anyhow, coded_error, forbidden, not_found, unauthorized,
 };
 
-static JWK_SET: OnceCell<(JwkSet, HashMap<String, RsassaJwsVerifier>)> = OnceCell::const_new();
-
-pub(crate) async fn jwks(
-    jwks_issuer: &str,
-) -> &'static (JwkSet, HashMap<String, RsassaJwsVerifier>) {
-    JWK_SET
-        .get_or_init(|| async {
-            let jwks_url = format!("{jwks_issuer}/.well-known/jwks.json");
-
-            info!("Fetching JWKS from {jwks_url}");
-
-            let client = reqwest::Client::new();
-
-            let jwks_bytes = client
-                .get(jwks_url)
-                .send()
-                .await
-                .expect("Failed to request Cognito jwks")
-                .bytes()
-                .await
-                .expect("Failed to receive Cognito jwks bytes");
-
-            let jwks =
-                JwkSet::from_bytes(jwks_bytes.as_ref()).expect("Failed to parse Cognito jwks");
-
-            let verifiers = jwks
-                .keys()
-                .iter()
-                .map(|jwk| {
-                    (
-                        jwk.key_id()
-                            .expect("Cognito jwk missing 'kid' field")
-                            .to_owned(),
-                        match jwk.algorithm() {
-                            Some("RS256") => RsassaJwsAlgorithm::Rs256,
-                            Some("RS384") => RsassaJwsAlgorithm::Rs384,
-                            Some("RS512") => RsassaJwsAlgorithm::Rs512,
-                            Some(alg) => {
-                                panic!("Unsupported Cognito jwk algorithm {alg}");
-                            }
-                            None => {
-                                panic!("Cognito jwk missing 'alg' field");
-                            }
-                        }
-                        .verifier_from_jwk(jwk)
-                        .expect("Failed to create verifier from Cognito jwk"),
-                    )
-                })
-                .collect::<HashMap<_, _>>();
-
-            (jwks, verifiers)
-        })
-        .await
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AccountRole {
+    Admin,
+    Member,
+    Viewer,
+}
+
+// User IDs are namespaced by the issuer that issued them, so an identity provider can't issue tokens for the users of
+// another. Users of the primary auth issuer keep their UUID user IDs as is, as their records were created before other
+// identity providers were supported.
+fn user_uuid(primary_issuer: &str, issuer: &str, user_id: &str) -> Uuid {
+    if issuer == primary_issuer
+        && let Ok(user_id) = Uuid::parse_str(user_id)
+    {
+        return user_id;
+    }
+
+    let issuer_namespace = Uuid::new_v5(&Uuid::NAMESPACE_URL, issuer.as_bytes());
+
+    Uuid::new_v5(&issuer_namespace, user_id.as_bytes())
+}
+
+// Time the user last authenticated, from the `auth_time` claim, or the time the token was issued if the identity
+// provider doesn't include the claim. Refreshed tokens keep the `auth_time` of the original authentication.
+fn authenticated_at(payload: &jwt::JwtPayload) -> Option<DateTime<Utc>> {
+    let auth_time = match payload.claim("auth_time") {
+        Some(josekit::Value::Number(auth_time)) => auth_time
+            .as_i64()
+            .and_then(|auth_time| DateTime::from_timestamp(auth_time, 0)),
+        _ => None,
+    };
+
+    auth_time.or_else(|| payload.issued_at().map(DateTime::<Utc>::from))
+}
+
+// Emails that the issuer marks as unverified are ignored
+fn verified_email(payload: &jwt::JwtPayload, email_claim: &str) -> Option<String> {
+    if matches!(
+        payload.claim("email_verified"),
+        Some(josekit::Value::Bool(false))
+    ) || matches!(payload.claim("email_verified"), Some(josekit::Value::String(verified)) if verified == "false")
+    {
+        return None;
+    }
+
+    match payload.claim(email_claim) {
+        Some(josekit::Value::String(email)) => Some(email.to_lowercase()),
+        _ => None,
+    }
+}
+
+// Verifies a dashboard access token and returns its issuer, the user ID it was issued to, when the user authenticated,
+// and the user's verified email if the token carries it
+async fn verify_access_token(
+    access_token: &str,
+) -> Result<(&'static str, String, Option<DateTime<Utc>>, Option<String>)> {
+    #[cfg(not(feature = "archodex-com"))]
+    if let Some(issuer) = oidc::unverified_issuer(access_token)
+        && let Some(provider) = Env::oidc_providers()
+            .iter()
+            .find(|provider| provider.issuer == issuer)
+    {
+        return verify_oidc_id_token(access_token, provider).await;
+    }
+
+    let auth_claims = Env::auth_claims();
+
+    let payload = match oidc::jwks_for_token(&auth_claims.jwks_url, access_token)
+        .await?
+        .verify(access_token)
+    {
+        Ok(payload) => payload,
+        Err(err) => {
+            warn!(?err, "Failed to verify JWT");
+            unauthorized!();
+        }
+    };
+
+    let Some(josekit::Value::String(user_id)) = payload.claim(&auth_claims.user_id_claim) else {
+        warn!(
+            claim = auth_claims.user_id_claim,
+            "Missing or invalid user ID claim in JWT"
+        );
+        unauthorized!();
+    };
+
+    let mut validator = jwt::JwtPayloadValidator::new();
+
+    validator.set_base_time(SystemTime::now());
+    validator.set_issuer(&auth_claims.issuer);
+    for (name, value) in &auth_claims.required_claims {
+        validator.set_claim(name, value.as_str().into());
+    }
+
+    if let Err(err) = validator.validate(&payload) {
+        warn!(?err, "Failed to validate JWT");
+        unauthorized!();
+    }
+
+    if let Some(required_group) = &auth_claims.required_group
+        && !matches!(
+            payload.claim(&auth_claims.groups_claim),
+            Some(josekit::Value::Array(groups))
+                if groups.iter().any(|group| group.as_str() == Some(required_group))
+        )
+    {
+        warn!(required_group, "User is not a member of the required group");
+        unauthorized!();
+    }
+
+    Ok((
+        &auth_claims.issuer,
+        user_id.to_owned(),
+        authenticated_at(&payload),
+        verified_email(&payload, &auth_claims.email_claim),
+    ))
 }
 
+// ID tokens of additional OIDC providers identify users by their `sub` claim and are issued to the provider's client
+#[cfg(not(feature = "archodex-com"))]
+async fn verify_oidc_id_token(
+    id_token: &str,
+    provider: &'static oidc::OidcProvider,
+) -> Result<(&'static str, String, Option<DateTime<Utc>>, Option<String>)> {
+    let discovery = oidc::discovery(&provider.issuer).await?;
+
+    let payload = match oidc::jwks_for_token(&discovery.jwks_uri, id_token)
+        .await?
+        .verify(id_token)
+    {
+        Ok(payload) => payload,
+        Err(err) => {
+            warn!(
+                ?err,
+                provider = provider.name,
+                "Failed to verify OIDC ID token"
+            );
+            unauthorized!();
+        }
+    };
+
+    let Some(user_id) = payload.subject() else {
+        warn!(
+            provider = provider.name,
+            "Missing sub claim in OIDC ID token"
+        );
+        unauthorized!();
+    };
+
+    let mut validator = jwt::JwtPayloadValidator::new();
+
+    validator.set_base_time(SystemTime::now());
+    validator.set_issuer(&provider.issuer);
+    validator.set_audience(&provider.client_id);
+
+    if let Err(err) = validator.validate(&payload) {
+        warn!(
+            ?err,
+            provider = provider.name,
+            "Failed to validate OIDC ID token"
+        );
+        unauthorized!();
+    }
+
+    Ok((
+        &provider.issuer,
+        user_id.to_owned(),
+        authenticated_at(&payload),
+        verified_email(&payload, "email"),
+    ))
+}
+
+// Authenticates dashboard users by their access token, by an account API token created by them, see `api_tokens`, or by
+// a SAML session token, see `saml`. Requests authenticated with an API token are limited to what the token grants, and
+// never count as a recent authentication for `StepUp`. Requests authenticated with a SAML session token are limited to
+// the session's account.
 #[derive(Clone, Debug)]
 pub(crate) struct DashboardAuth {
     principal: User,
+    authenticated_at: Option<DateTime<Utc>>,
+    api_token: Option<ApiTokenGrant>,
+    saml_account_id: Option<String>,
+    // Account a federated request from the linked archodex.com account was authenticated for
+    federated_account_id: Option<String>,
+    // Verified email of users authenticated with an access token that carries it
+    email: Option<String>,
+}
+
+// Federated requests are only accepted when federation is enabled, and only for GET requests to the routes in
+// `FEDERATED_ROUTES` of well formed account IDs. Returns the account ID the request is for.
+#[cfg(not(feature = "archodex-com"))]
+fn verify_federation_token(
+    federation_token: &str,
+    method: &axum::http::Method,
+    path: &str,
+) -> Result<String> {
+    use crate::{
+        account::is_well_formed_account_id,
+        account_link::{FEDERATED_ROUTES, federation_token as link_federation_token},
+    };
+
+    let (Some((_, link_token)), Some(_)) = (Env::link(), Env::link_federation_endpoint()) else {
+        warn!("Federated request received while federation is disabled");
+        unauthorized!();
+    };
+
+    if Sha256::digest(federation_token) != Sha256::digest(link_federation_token(link_token)) {
+        warn!("Invalid federation token");
+        unauthorized!();
+    }
+
+    let Some((account_id, account_route)) = path
+        .strip_prefix("/account/")
+        .and_then(|path| path.split_once('/'))
+    else {
+        coded_error!(
+            ErrorCode::ScopeViolation,
+            "Federated requests may not access this route"
+        );
+    };
+
+    if method != axum::http::Method::GET
+        || !is_well_formed_account_id(account_id)
+        || !FEDERATED_ROUTES.contains(&account_route)
+    {
+        warn!(
+            account_route,
+            "Federated request for route that is not federated"
+        );
+        coded_error!(
+            ErrorCode::ScopeViolation,
+            "Federated requests may not access this route"
+        );
+    }
+
+    Ok(account_id.to_owned())
 }
 
 impl DashboardAuth {
     pub(crate) async fn authenticate(mut req: Request, next: Next) -> Result<Response> {
         let authorization = req.headers().get(AUTHORIZATION);
+        #[cfg(not(feature = "archodex-com"))]
+        let (method, path) = (req.method().clone(), req.uri().path().to_owned());
         let dashboard_auth = async move {
             let Some(authorization) = authorization else {
                 warn!("Missing Authorization header");
@@ -103,59 +294,83 @@ impl DashboardAuth {
                 unauthorized!();
             };
 
-            let cognito_user_pool_id = Env::cognito_user_pool_id();
-            let cognito_client_id = Env::cognito_client_id();
-
-            let jwks_issuer =
-                format!("https://cognito-idp.us-west-2.amazonaws.com/{cognito_user_pool_id}");
-
-            let (jwk_set, verifier_map) = jwks(&jwks_issuer).await;
-
-            let user_id = match jwt::decode_with_verifier_in_jwk_set(access_token, jwk_set, |jwk| {
-                Ok(verifier_map
-                    .get(jwk.key_id().ok_or(JoseError::InvalidJwkFormat(anyhow!(
-                        "Cognito jwk missing 'kid' field"
-                    )))?)
-                    .map(|verifier| verifier as &dyn josekit::jws::JwsVerifier))
-            }) {
-                Ok((payload, _header)) => {
-                    let Some(josekit::Value::String(sub)) = payload.claim("sub") else {
-                        warn!("Missing or invalid sub claim in JWT");
-                        unauthorized!();
-                    };
-
-                    let mut validator = jwt::JwtPayloadValidator::new();
+            if let Some(api_token) = access_token.strip_prefix(API_TOKEN_PREFIX) {
+                let (principal, grant) = api_tokens::verify(api_token).await?;
+
+                return Result::Ok(DashboardAuth {
+                    principal,
+                    authenticated_at: None,
+                    api_token: Some(grant),
+                    saml_account_id: None,
+                    federated_account_id: None,
+                    email: None,
+                });
+            }
 
-                    validator.set_base_time(SystemTime::now());
-                    validator.set_issuer(&jwks_issuer);
-                    validator.set_claim("client_id", cognito_client_id.into());
-                    validator.set_claim("token_use", "access".into());
+            #[cfg(not(feature = "archodex-com"))]
+            if let Some(federation_token) =
+                access_token.strip_prefix(crate::account_link::FEDERATION_TOKEN_PREFIX)
+            {
+                let account_id = verify_federation_token(federation_token, &method, &path)?;
+
+                // Federated requests aren't made by a user of this instance
+                return Result::Ok(DashboardAuth {
+                    principal: User::new(Uuid::nil()),
+                    authenticated_at: None,
+                    api_token: None,
+                    saml_account_id: None,
+                    federated_account_id: Some(account_id),
+                    email: None,
+                });
+            }
 
-                    match validator.validate(&payload) {
-                        Ok(()) => Result::Ok(sub.to_owned()),
-                        Err(err) => {
-                            warn!(?err, "Failed to validate JWT");
-                            unauthorized!();
-                        }
-                    }
-                }
-                Err(err) => {
-                    warn!(?err, "Failed to verify JWT");
-                    unauthorized!();
-                }
-            }?;
+            if let Some(session_token) = access_token.strip_prefix(SAML_SESSION_PREFIX) {
+                let (principal, account_id, authenticated_at) =
+                    saml::verify_session(session_token).await?;
+
+                return Result::Ok(DashboardAuth {
+                    principal,
+                    authenticated_at: Some(authenticated_at),
+                    api_token: None,
+                    saml_account_id: Some(account_id),
+                    federated_account_id: None,
+                    email: None,
+                });
+            }
 
-            let user_id = Uuid::parse_str(&user_id)
-                .with_context(|| format!("Failed to parse user ID {user_id:?} as UUID"))?;
+            let (issuer, user_id, authenticated_at, email) =
+                verify_access_token(access_token).await?;
 
             Result::Ok(DashboardAuth {
-                principal: User::new(user_id),
+                principal: User::new(user_uuid(&Env::auth_claims().issuer, issuer, &user_id)),
+                authenticated_at,
+                api_token: None,
+                saml_account_id: None,
+                federated_account_id: None,
+                email,
             })
         }
         .instrument(error_span!("authenticate"))
-        .await?;
-
-        tracing::Span::current().record("auth", tracing::field::debug(&dashboard_auth));
+        .await
+        .inspect_err(|_| AUTH_FAILURES_TOTAL.with_label_values(&["dashboard"]).inc())?;
+
+        if let Some(grant) = &dashboard_auth.api_token {
+            let route = req
+                .extensions()
+                .get::<MatchedPath>()
+                .map(MatchedPath::as_str);
+
+            if !grant.allows(route, router::is_read_only(&req)) {
+                warn!(
+                    token_id = grant.token_id(),
+                    route, "API token does not grant access to route"
+                );
+                coded_error!(
+                    ErrorCode::ScopeViolation,
+                    "API token does not grant access to this route"
+                );
+            }
+        }
 
         req.extensions_mut().insert(dashboard_auth);
 
@@ -166,30 +381,137 @@ impl DashboardAuth {
         &self.principal
     }
 
+    pub(crate) fn email(&self) -> Option<&str> {
+        self.email.as_deref()
+    }
+
+    // Returns the principal's role in the account
     #[instrument]
-    pub(crate) async fn validate_account_access(&self, account_id: &str) -> Result<()> {
-        if accounts_db()
+    pub(crate) async fn validate_account_access(&self, account_id: &str) -> Result<AccountRole> {
+        if let Some(grant) = &self.api_token
+            && grant.account_id() != account_id
+        {
+            warn!(
+                token_id = grant.token_id(),
+                "API token was not created for account"
+            );
+            not_found!("Account not found");
+        }
+
+        if let Some(saml_account_id) = &self.saml_account_id
+            && saml_account_id != account_id
+        {
+            warn!("SAML session was not created for account");
+            not_found!("Account not found");
+        }
+
+        // Members of the linked archodex.com account may view, but never modify, accounts of this instance
+        if let Some(federated_account_id) = &self.federated_account_id {
+            if federated_account_id != account_id {
+                warn!("Federated request was not authenticated for account");
+                not_found!("Account not found");
+            }
+
+            return Ok(AccountRole::Viewer);
+        }
+
+        let Some(role) = accounts_db()
             .await?
-            .query("SELECT 1 FROM $user->has_access->(account WHERE record::id(id) == $account_id)")
+            .query("SELECT VALUE role ?? 'admin' FROM has_access WHERE in == $user AND out == type::thing('account', $account_id)")
             .bind(("user", surrealdb::sql::Thing::from(&self.principal)))
             .bind(("account_id", account_id.to_string()))
             .await?
             .check_first_real_error()?
-            .take::<Option<u8>>((0, "1"))?
-            .is_none()
-        {
+            .take::<Vec<AccountRole>>(0)?
+            .pop()
+        else {
             warn!("Account does not exist or principal does not have access to account");
             not_found!("Account not found");
+        };
+
+        Ok(role)
+    }
+
+    pub(crate) async fn is_account_admin(&self, account_id: &str) -> Result<bool> {
+        Ok(self.validate_account_access(account_id).await? == AccountRole::Admin)
+    }
+
+    pub(crate) async fn ensure_account_admin(&self, account_id: &str) -> Result<()> {
+        if !self.is_account_admin(account_id).await? {
+            forbidden!("Only account admins may perform this action");
         }
 
         Ok(())
     }
 }
 
+// Extractor for destructive dashboard actions, which require the user to have authenticated recently so a stolen
+// session can't be used to cause lasting damage. Users who authenticated longer ago than the step-up window receive a
+// `reauthentication_required` error, prompting the dashboard to have them sign in again and retry.
+#[derive(Debug)]
+pub(crate) struct StepUp;
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for StepUp {
+    type Rejection = PublicError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self> {
+        let Some(auth) = parts.extensions.get::<DashboardAuth>() else {
+            return Err(anyhow!("StepUp extractor used without DashboardAuth").into());
+        };
+
+        // Authentication times in the future are due to clock skew, and are recent
+        let max_auth_age = Env::step_up_max_auth_age();
+        let is_recent = auth.authenticated_at.is_some_and(|authenticated_at| {
+            (Utc::now() - authenticated_at)
+                .to_std()
+                .ok()
+                .is_none_or(|auth_age| auth_age <= max_auth_age)
+        });
+
+        if !is_recent {
+            warn!(
+                authenticated_at = ?auth.authenticated_at,
+                "User must reauthenticate for destructive action"
+            );
+
+            return Err(PublicError::with_code(
+                ErrorCode::ReauthenticationRequired,
+                "Sign in again to perform this action",
+            ));
+        }
+
+        Ok(StepUp)
+    }
+}
+
+// Authenticates operator requests, like standby promotions, by the bearer token configured for them. Digests are
+// compared so the comparison doesn't leak how much of the token matched.
+pub(crate) fn ensure_operator_token(headers: &HeaderMap, token: &str, purpose: &str) -> Result<()> {
+    let Some(bearer_token) = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        unauthorized!();
+    };
+
+    if Sha256::digest(bearer_token) != Sha256::digest(token) {
+        warn!(purpose, "Invalid operator token");
+        unauthorized!();
+    }
+
+    Ok(())
+}
+
 #[derive(Clone, Debug)]
 pub(crate) struct ReportApiKeyAuth {
     account_id: String,
     key_id: u32,
+    // Fingerprint of the report key value, or None if authenticated with an ingestion token
+    value_fingerprint: Option<report_key_backoff::Fingerprint>,
+    // Account salt the report key value was issued with, or None if authenticated with an ingestion token
+    account_salt: Option<Vec<u8>>,
 }
 
 impl ReportApiKeyAuth {
@@ -206,21 +528,50 @@ impl ReportApiKeyAuth {
                 unauthorized!();
             };
 
-            let (account_id, key_id) =
-                match ReportApiKey::validate_value(report_api_key_value).await {
+            if let Some(ingestion_token) = report_api_key_value.strip_prefix("Bearer ") {
+                let (account_id, key_id) = match ingestion_token::verify(ingestion_token).await {
                     Ok((account_id, key_id)) => (account_id, key_id),
+                    Err(err) => {
+                        warn!(?err, "Failed to validate ingestion token");
+                        unauthorized!();
+                    }
+                };
+
+                return Result::Ok(ReportApiKeyAuth {
+                    account_id,
+                    key_id,
+                    value_fingerprint: None,
+                    account_salt: None,
+                });
+            }
+
+            let value_fingerprint = report_key_backoff::fingerprint(report_api_key_value);
+            report_key_backoff::check(&value_fingerprint)?;
+
+            let (account_id, key_id, account_salt) =
+                match ReportApiKey::validate_value(report_api_key_value).await {
+                    Ok((account_id, key_id, account_salt)) => (account_id, key_id, account_salt),
                     Err(err) => {
                         warn!(?err, "Failed to validate report key value");
+                        report_key_backoff::record_failure(value_fingerprint);
                         unauthorized!();
                     }
                 };
 
-            Result::Ok(ReportApiKeyAuth { account_id, key_id })
+            Result::Ok(ReportApiKeyAuth {
+                account_id,
+                key_id,
+                value_fingerprint: Some(value_fingerprint),
+                account_salt: Some(account_salt),
+            })
         }
         .instrument(error_span!("authenticate"))
-        .await?;
-
-        tracing::Span::current().record("auth", tracing::field::debug(&report_api_key_auth));
+        .await
+        .inspect_err(|_| {
+            AUTH_FAILURES_TOTAL
+                .with_label_values(&["report_api_key"])
+                .inc();
+        })?;
 
         req.extensions_mut().insert(report_api_key_auth);
 
@@ -239,23 +590,66 @@ impl ReportApiKeyAuth {
             unauthorized!();
         };
 
-        let (account_id, key_id) = match ReportApiKey::validate_value(report_api_key_value).await {
-            Ok((account_id, key_id)) => (account_id, key_id),
-            Err(err) => {
-                warn!(?err, "Failed to validate report key value");
-                unauthorized!();
-            }
-        };
+        let (account_id, key_id, account_salt) =
+            match ReportApiKey::validate_value(report_api_key_value).await {
+                Ok((account_id, key_id, account_salt)) => (account_id, key_id, account_salt),
+                Err(err) => {
+                    warn!(?err, "Failed to validate report key value");
+                    unauthorized!();
+                }
+            };
 
-        Ok(ReportApiKeyAuth { account_id, key_id })
+        Ok(ReportApiKeyAuth {
+            account_id,
+            key_id,
+            value_fingerprint: Some(report_key_backoff::fingerprint(report_api_key_value)),
+            account_salt: Some(account_salt),
+        })
     }
 
     pub(crate) fn account_id(&self) -> &str {
         &self.account_id
     }
 
-    pub(crate) async fn validate_account_access(&self, db: &Surreal<Any>) -> Result<()> {
-        let Some(response) = db
+    pub(crate) fn key_id(&self) -> u32 {
+        self.key_id
+    }
+
+    // Whether the request was authenticated with a short-lived ingestion token rather than the report key value
+    pub(crate) fn via_ingestion_token(&self) -> bool {
+        self.value_fingerprint.is_none()
+    }
+
+    pub(crate) fn value_fingerprint(&self) -> Option<&report_key_backoff::Fingerprint> {
+        self.value_fingerprint.as_ref()
+    }
+
+    // Backs off the report key value after it failed authentication for good, not after transient errors
+    pub(crate) fn record_value_failure(&self) {
+        if let Some(value_fingerprint) = self.value_fingerprint {
+            report_key_backoff::record_failure(value_fingerprint);
+        }
+    }
+
+    pub(crate) async fn validate_account_access(&self, account: &Account) -> Result<()> {
+        // Values encrypted with another account's key may claim any account ID, but carry the other account's salt
+        if self
+            .account_salt
+            .as_deref()
+            .is_some_and(|account_salt| account_salt != account.salt())
+        {
+            warn!(
+                key_id = self.key_id,
+                account_id = self.account_id,
+                "Report key value was not issued with the account's salt",
+            );
+            self.record_value_failure();
+            unauthorized!();
+        }
+
+        let Some(response) = account
+            .resources_db()
+            .await?
             .report_api_key_is_valid_query(self.key_id)
             .await?
             .check_first_real_error()?
@@ -266,6 +660,7 @@ impl ReportApiKeyAuth {
                 account_id = self.account_id,
                 "Report key does not exist in account database",
             );
+            self.record_value_failure();
             unauthorized!();
         };
 
@@ -275,9 +670,75 @@ impl ReportApiKeyAuth {
                 account_id = self.account_id,
                 "Report key was revoked in account database",
             );
+            self.record_value_failure();
             unauthorized!();
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use surrealdb::Uuid;
+
+    use super::{user_uuid, verified_email};
+
+    const PRIMARY_ISSUER: &str = "https://cognito-idp.us-west-2.amazonaws.com/us-west-2_Mf1K95El6";
+    const OTHER_ISSUER: &str = "https://accounts.example.com";
+    const SUB: &str = "5c1b6b8e-2f4a-4d7e-9a3b-1c2d3e4f5a6b";
+
+    #[test]
+    fn primary_issuer_uuids() {
+        assert_eq!(
+            user_uuid(PRIMARY_ISSUER, PRIMARY_ISSUER, SUB),
+            Uuid::parse_str(SUB).unwrap()
+        );
+    }
+
+    // Other issuers may not issue tokens for users of the primary issuer, or of each other
+    #[test]
+    fn namespaced_by_issuer() {
+        let other = user_uuid(PRIMARY_ISSUER, OTHER_ISSUER, SUB);
+
+        assert_ne!(other, Uuid::parse_str(SUB).unwrap());
+        assert_ne!(
+            other,
+            user_uuid(PRIMARY_ISSUER, "https://accounts.example.org", SUB)
+        );
+        assert_eq!(other, user_uuid(PRIMARY_ISSUER, OTHER_ISSUER, SUB));
+        assert_eq!(other.get_version_num(), 5);
+
+        assert_ne!(
+            user_uuid(PRIMARY_ISSUER, PRIMARY_ISSUER, "jane"),
+            user_uuid(PRIMARY_ISSUER, OTHER_ISSUER, "jane")
+        );
+    }
+
+    #[test]
+    fn verified_emails() {
+        let mut payload = josekit::jwt::JwtPayload::new();
+        assert_eq!(verified_email(&payload, "email"), None);
+
+        payload
+            .set_claim("email", Some("Jane.Doe@Example.com".into()))
+            .unwrap();
+        assert_eq!(
+            verified_email(&payload, "email").as_deref(),
+            Some("jane.doe@example.com")
+        );
+        assert_eq!(verified_email(&payload, "custom:email"), None);
+
+        for unverified in [josekit::Value::Bool(false), "false".into()] {
+            payload
+                .set_claim("email_verified", Some(unverified))
+                .unwrap();
+            assert_eq!(verified_email(&payload, "email"), None);
+        }
+
+        payload
+            .set_claim("email_verified", Some(true.into()))
+            .unwrap();
+        assert!(verified_email(&payload, "email").is_some());
+    }
+}
@@ -0,0 +1,340 @@
+// Imports AWS Config snapshot and configuration history files, so accounts that already record their AWS resources with
+// AWS Config see them in the resource graph without deploying an agent. Files are uploaded to `POST
+// /report/aws_config` as delivered to S3, gzipped or not, e.g. with
+// `aws s3 cp s3://<bucket>/<key> - | curl --data-binary @- ...`.
+//
+// Each configuration item becomes a resource contained by its AWS Partition, AWS Account, AWS Region, and AWS Service.
+// Resource types and services are derived from the AWS Config resource type, e.g. `AWS::DynamoDB::Table` becomes a
+// `DynamoDB Table` contained by the `DynamoDB` service. Resources of global services (e.g. IAM) are contained by the
+// service of their account, and S3 buckets are globally unique. Configuration history files may hold several items of a
+// resource, so only its latest item is imported, observed from its earliest to its latest capture time. Resources whose
+// latest item shows they were deleted are skipped.
+//
+// Files are ingested as reports of up to `RESOURCES_PER_REPORT` resources each, rather than as a single transaction.
+// If ingesting a report fails, the resources of earlier reports remain imported, and the file may be uploaded again.
+
+use std::{
+    collections::{BTreeMap, btree_map::Entry},
+    io::Read as _,
+};
+
+use axum::{Extension, Json, body::Bytes};
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, json};
+use tracing::{info, instrument};
+
+use archodex_error::bad_request;
+
+use crate::{
+    Result,
+    account::Account,
+    auth::ReportApiKeyAuth,
+    limits::Limit,
+    report::{self, Request},
+    sensor::Sensor,
+    usage,
+};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+const RESOURCES_PER_REPORT: usize = 1000;
+
+// Regions of configuration items of global resources
+const GLOBAL_REGIONS: [&str; 2] = ["global", ""];
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ConfigFile {
+    configuration_items: Vec<ConfigurationItem>,
+}
+
+// Other inventory documents are imported by converting their resources to configuration items, see `import`
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ConfigurationItem {
+    pub(crate) configuration_item_capture_time: Option<DateTime<Utc>>,
+    pub(crate) configuration_item_status: Option<String>,
+    pub(crate) aws_account_id: String,
+    pub(crate) aws_region: Option<String>,
+    pub(crate) resource_type: String,
+    pub(crate) resource_id: String,
+    pub(crate) resource_name: Option<String>,
+    #[serde(rename = "ARN")]
+    pub(crate) arn: Option<String>,
+    #[serde(default)]
+    pub(crate) configuration: Value,
+    pub(crate) tags: Option<Map<String, Value>>,
+}
+
+impl ConfigurationItem {
+    fn is_deleted(&self) -> bool {
+        matches!(
+            self.configuration_item_status.as_deref(),
+            Some("ResourceDeleted" | "ResourceDeletedNotRecorded")
+        )
+    }
+
+    // Partition of the item's ARN, e.g. `aws-cn`
+    fn partition(&self) -> &str {
+        self.arn
+            .as_deref()
+            .and_then(|arn| arn.split(':').nth(1))
+            .filter(|partition| !partition.is_empty())
+            .unwrap_or("aws")
+    }
+
+    fn region(&self) -> Option<&str> {
+        self.aws_region
+            .as_deref()
+            .filter(|region| !GLOBAL_REGIONS.contains(region))
+    }
+
+    // `AWS::DynamoDB::Table` becomes `DynamoDB Table`
+    fn archodex_resource_type(&self) -> String {
+        let mut parts = self.resource_type.split("::");
+        parts.next();
+        parts.collect::<Vec<_>>().join(" ")
+    }
+
+    // `AWS::DynamoDB::Table` is a resource of the `DynamoDB` service
+    fn service(&self) -> &str {
+        self.resource_type
+            .split("::")
+            .nth(1)
+            .unwrap_or(&self.resource_type)
+    }
+
+    // Identifies the resource the item describes across the items of a configuration history file
+    fn resource_key(&self) -> (String, String, Option<String>, String, String) {
+        (
+            self.partition().to_string(),
+            self.aws_account_id.clone(),
+            self.region().map(str::to_string),
+            self.resource_type.clone(),
+            self.resource_id.clone(),
+        )
+    }
+
+    fn into_node(mut self, first_seen_at: DateTime<Utc>, last_seen_at: DateTime<Utc>) -> Value {
+        let resource_name = self.resource_name.take().filter(|name| !name.is_empty());
+        let r#type = self.archodex_resource_type();
+
+        let mut attributes = Map::new();
+        if let Some(arn) = &self.arn {
+            attributes.insert("arn".into(), arn.clone().into());
+        }
+        if resource_name
+            .as_ref()
+            .is_some_and(|resource_name| *resource_name != self.resource_id)
+        {
+            attributes.insert(
+                "aws_config_resource_id".into(),
+                self.resource_id.clone().into(),
+            );
+        }
+        if !self.configuration.is_null() {
+            attributes.insert("configuration".into(), self.configuration);
+        }
+        if let Some(tags) = self.tags.filter(|tags| !tags.is_empty()) {
+            attributes.insert("tags".into(), tags.into());
+        }
+
+        json!({
+            "type": r#type,
+            "id": resource_name.unwrap_or(self.resource_id),
+            "globally_unique": r#type == "S3 Bucket",
+            "first_seen_at": first_seen_at,
+            "last_seen_at": last_seen_at,
+            "attributes": attributes,
+        })
+    }
+}
+
+// Containers keyed by their type and ID, so that items of the same account and region share their containers
+#[derive(Default)]
+struct Container {
+    first_seen_at: Option<DateTime<Utc>>,
+    last_seen_at: Option<DateTime<Utc>>,
+    containers: BTreeMap<(&'static str, String), Container>,
+    resources: Vec<Value>,
+}
+
+impl Container {
+    fn observe(&mut self, observed_at: DateTime<Utc>) -> &mut Self {
+        self.first_seen_at = Some(
+            self.first_seen_at
+                .map_or(observed_at, |at| at.min(observed_at)),
+        );
+        self.last_seen_at = Some(
+            self.last_seen_at
+                .map_or(observed_at, |at| at.max(observed_at)),
+        );
+        self
+    }
+
+    fn container(
+        &mut self,
+        r#type: &'static str,
+        id: &str,
+        observed_at: DateTime<Utc>,
+    ) -> &mut Self {
+        self.containers
+            .entry((r#type, id.to_string()))
+            .or_default()
+            .observe(observed_at)
+    }
+
+    fn into_nodes(self) -> Vec<Value> {
+        self.containers
+            .into_iter()
+            .map(|((r#type, id), container)| {
+                json!({
+                    "type": r#type,
+                    "id": id,
+                    "first_seen_at": container.first_seen_at,
+                    "last_seen_at": container.last_seen_at,
+                    "contains": container.into_nodes(),
+                })
+            })
+            .chain(self.resources)
+            .collect()
+    }
+}
+
+pub(crate) fn decode(body: &[u8]) -> Result<Vec<ConfigurationItem>> {
+    let max = Limit::AwsConfigFileBytes.max();
+
+    let json = if body.starts_with(&GZIP_MAGIC) {
+        let mut json = Vec::new();
+        let decompressed = GzDecoder::new(body)
+            .take(max as u64 + 1)
+            .read_to_end(&mut json);
+
+        if decompressed.is_err() {
+            bad_request!("AWS Config file is not valid gzip");
+        }
+
+        if json.len() > max {
+            return Err(Limit::AwsConfigFileBytes
+                .exceeded_by("Decompressed AWS Config file size in bytes", None));
+        }
+
+        json
+    } else {
+        body.to_vec()
+    };
+
+    match serde_json::from_slice::<ConfigFile>(&json) {
+        Ok(file) => Ok(file.configuration_items),
+        Err(err) => bad_request!("Invalid AWS Config file: {err}"),
+    }
+}
+
+// Resource ID parts only deserialize from borrowed strings, so reports are deserialized from their serialized form
+// rather than from the JSON value
+fn report(root: Container) -> Result<Request> {
+    Request::from_slice(
+        &serde_json::to_vec(&json!({
+            "resource_captures": root.into_nodes(),
+            "event_captures": [],
+        }))?,
+        "AWS Config report",
+    )
+}
+
+// Converts configuration items to reports of up to `RESOURCES_PER_REPORT` resources each, returning the number of
+// resources they import
+pub(crate) fn report_requests(items: Vec<ConfigurationItem>) -> Result<(Vec<Request>, usize)> {
+    // The latest item of each resource, and the capture time of its earliest item
+    let mut resources = BTreeMap::<_, (DateTime<Utc>, DateTime<Utc>, ConfigurationItem)>::new();
+
+    for item in items {
+        let captured_at = item
+            .configuration_item_capture_time
+            .unwrap_or_else(Utc::now);
+
+        match resources.entry(item.resource_key()) {
+            Entry::Vacant(entry) => {
+                entry.insert((captured_at, captured_at, item));
+            }
+            Entry::Occupied(mut entry) => {
+                let (first_captured_at, last_captured_at, latest) = entry.get_mut();
+
+                *first_captured_at = (*first_captured_at).min(captured_at);
+
+                if captured_at >= *last_captured_at {
+                    *last_captured_at = captured_at;
+                    *latest = item;
+                }
+            }
+        }
+    }
+
+    let mut requests = Vec::new();
+    let mut root = Container::default();
+    let mut report_resources = 0;
+    let mut imported_resources = 0;
+
+    for (first_seen_at, last_seen_at, item) in resources.into_values() {
+        if item.is_deleted() {
+            continue;
+        }
+
+        let account = root
+            .container("AWS Partition", item.partition(), last_seen_at)
+            .container("AWS Account", &item.aws_account_id, last_seen_at);
+
+        let region = match item.region() {
+            Some(region) => account.container("AWS Region", region, last_seen_at),
+            None => account,
+        };
+
+        let service = region.container("AWS Service", item.service(), last_seen_at);
+
+        service
+            .resources
+            .push(item.into_node(first_seen_at, last_seen_at));
+        report_resources += 1;
+        imported_resources += 1;
+
+        if report_resources == RESOURCES_PER_REPORT {
+            requests.push(report(std::mem::take(&mut root))?);
+            report_resources = 0;
+        }
+    }
+
+    if report_resources > 0 {
+        requests.push(report(root)?);
+    }
+
+    Ok((requests, imported_resources))
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct ImportAwsConfigResponse {
+    imported_resources: usize,
+}
+
+#[instrument(err, skip_all, fields(account_id = auth.account_id()))]
+pub(crate) async fn import(
+    Extension(auth): Extension<ReportApiKeyAuth>,
+    Extension(account): Extension<Account>,
+    body: Bytes,
+) -> Result<Json<ImportAwsConfigResponse>> {
+    let (requests, imported_resources) = report_requests(decode(&body)?)?;
+
+    let sensor = Sensor::report_api_key(auth.key_id());
+
+    for request in requests {
+        usage::check_quotas(&account).await?;
+
+        report::ingest(&account, &sensor, request, None).await?;
+    }
+
+    info!(imported_resources, "Imported AWS Config file");
+
+    Ok(Json(ImportAwsConfigResponse { imported_resources }))
+}
@@ -0,0 +1,266 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+const GIB: u64 = 1024 * 1024 * 1024;
+
+// Plan tiers for archodex.com accounts. Self-hosted accounts are not subject to plan entitlements.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Plan {
+    #[default]
+    Free,
+    Team,
+    Enterprise,
+}
+
+impl Plan {
+    #[cfg_attr(not(feature = "archodex-com"), allow(dead_code))]
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Plan::Free => "free",
+            Plan::Team => "team",
+            Plan::Enterprise => "enterprise",
+        }
+    }
+}
+
+// Limits and features available to an account. `None` means there is no limit.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Entitlements {
+    pub(crate) max_report_api_keys: Option<u32>,
+    pub(crate) max_resources: Option<u64>,
+    pub(crate) max_events: Option<u64>,
+    pub(crate) max_storage_bytes: Option<u64>,
+    pub(crate) max_reports_per_month: Option<u64>,
+}
+
+// Usage quotas set by operators for an individual account, overriding the quotas of its plan. Unlike plan entitlements,
+// these also apply to self-hosted accounts.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub(crate) struct QuotaOverrides {
+    max_resources: Option<u64>,
+    max_events: Option<u64>,
+    max_storage_bytes: Option<u64>,
+    max_reports_per_month: Option<u64>,
+}
+
+impl Entitlements {
+    pub(crate) const UNLIMITED: Self = Self {
+        max_report_api_keys: None,
+        max_resources: None,
+        max_events: None,
+        max_storage_bytes: None,
+        max_reports_per_month: None,
+    };
+
+    #[cfg_attr(not(feature = "archodex-com"), allow(dead_code))]
+    pub(crate) fn for_plan(plan: Plan) -> Self {
+        match plan {
+            Plan::Free => Self {
+                max_report_api_keys: Some(2),
+                max_resources: Some(10_000),
+                max_events: Some(100_000),
+                max_storage_bytes: Some(GIB),
+                max_reports_per_month: Some(50_000),
+            },
+            Plan::Team => Self {
+                max_report_api_keys: Some(25),
+                max_resources: Some(500_000),
+                max_events: Some(10_000_000),
+                max_storage_bytes: Some(100 * GIB),
+                max_reports_per_month: Some(5_000_000),
+            },
+            Plan::Enterprise => Self::UNLIMITED,
+        }
+    }
+
+    pub(crate) fn with_overrides(self, overrides: QuotaOverrides) -> Self {
+        Self {
+            max_resources: overrides.max_resources.or(self.max_resources),
+            max_events: overrides.max_events.or(self.max_events),
+            max_storage_bytes: overrides.max_storage_bytes.or(self.max_storage_bytes),
+            max_reports_per_month: overrides
+                .max_reports_per_month
+                .or(self.max_reports_per_month),
+            ..self
+        }
+    }
+}
+
+#[cfg(feature = "archodex-com")]
+pub(crate) use webhook::webhook;
+
+#[cfg(feature = "archodex-com")]
+mod webhook {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use axum::{body::Bytes, http::HeaderMap};
+    use hmac::{Hmac, Mac as _};
+    use serde::Deserialize;
+    use sha2::Sha256;
+    use tracing::{info, instrument, warn};
+
+    use archodex_error::{anyhow::Context as _, bad_request, unauthorized};
+
+    use super::Plan;
+    use crate::{
+        Result, account_cache,
+        db::{QueryCheckFirstRealError as _, accounts_db},
+        env::Env,
+    };
+
+    // Maximum age of a webhook signature timestamp, to limit replay of captured webhook deliveries
+    const SIGNATURE_TOLERANCE_SECONDS: u64 = 300;
+
+    #[derive(Debug, Deserialize)]
+    struct WebhookEvent {
+        r#type: String,
+        data: WebhookEventData,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct WebhookEventData {
+        object: Subscription,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Subscription {
+        metadata: SubscriptionMetadata,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct SubscriptionMetadata {
+        archodex_account_id: String,
+        archodex_plan: Option<Plan>,
+    }
+
+    // Verifies a `Stripe-Signature` style header of the form `t=<timestamp>,v1=<hex HMAC-SHA256>`, where the HMAC is
+    // computed over `<timestamp>.<body>` with the webhook signing secret.
+    fn verify_signature(secret: &str, signature_header: &str, body: &[u8]) -> bool {
+        let mut timestamp = None;
+        let mut signatures = vec![];
+
+        for part in signature_header.split(',') {
+            match part.split_once('=') {
+                Some(("t", value)) => timestamp = value.parse::<u64>().ok(),
+                Some(("v1", value)) => {
+                    if let Ok(signature) = hex::decode(value) {
+                        signatures.push(signature);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let Some(timestamp) = timestamp else {
+            return false;
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+
+        if now.abs_diff(timestamp) > SIGNATURE_TOLERANCE_SECONDS {
+            return false;
+        }
+
+        signatures.iter().any(|signature| {
+            let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+                .expect("HMAC can take a key of any size");
+            mac.update(timestamp.to_string().as_bytes());
+            mac.update(b".");
+            mac.update(body);
+            mac.verify_slice(signature).is_ok()
+        })
+    }
+
+    // Receives subscription lifecycle events from the billing provider and updates the plan of the referenced account.
+    // Subscriptions must carry the `archodex_account_id` metadata key, and `archodex_plan` for active subscriptions.
+    #[instrument(err, skip_all)]
+    pub(crate) async fn webhook(headers: HeaderMap, body: Bytes) -> Result<()> {
+        let Some(secret) = Env::billing_webhook_secret() else {
+            warn!("Received billing webhook but BILLING_WEBHOOK_SECRET is not configured");
+            unauthorized!();
+        };
+
+        let Some(signature_header) = headers
+            .get("Stripe-Signature")
+            .and_then(|value| value.to_str().ok())
+        else {
+            warn!("Missing or invalid Stripe-Signature header");
+            unauthorized!();
+        };
+
+        if !verify_signature(secret, signature_header, &body) {
+            warn!("Invalid billing webhook signature");
+            unauthorized!();
+        }
+
+        let event = match serde_json::from_slice::<serde_json::Value>(&body) {
+            Ok(event) => event,
+            Err(err) => bad_request!("Invalid webhook payload: {err}"),
+        };
+
+        let event_type = event["type"].as_str().unwrap_or_default();
+        if !matches!(
+            event_type,
+            "customer.subscription.created"
+                | "customer.subscription.updated"
+                | "customer.subscription.deleted"
+        ) {
+            info!(event_type, "Ignoring billing webhook event");
+            return Ok(());
+        }
+
+        let event = match serde_json::from_value::<WebhookEvent>(event) {
+            Ok(event) => event,
+            Err(err) => bad_request!("Invalid subscription webhook payload: {err}"),
+        };
+
+        let metadata = event.data.object.metadata;
+
+        let plan = if event.r#type == "customer.subscription.deleted" {
+            Plan::Free
+        } else {
+            let Some(plan) = metadata.archodex_plan else {
+                bad_request!("Subscription is missing `archodex_plan` metadata");
+            };
+            plan
+        };
+
+        let updated = accounts_db()
+            .await?
+            .query("UPDATE $account SET plan = $plan WHERE deleted_at IS NONE RETURN VALUE id")
+            .bind((
+                "account",
+                surrealdb::sql::Thing::from((
+                    "account",
+                    surrealdb::sql::Id::String(metadata.archodex_account_id.clone()),
+                )),
+            ))
+            .bind(("plan", plan.as_str()))
+            .await
+            .context("Failed to submit query to update account plan")?
+            .check_first_real_error()
+            .context("Failed to update account plan")?
+            .take::<Vec<surrealdb::RecordId>>(0)?;
+
+        account_cache::invalidate();
+
+        if updated.is_empty() {
+            warn!(
+                account_id = metadata.archodex_account_id,
+                "Billing webhook referenced an account that does not exist"
+            );
+        } else {
+            info!(
+                account_id = metadata.archodex_account_id,
+                plan = plan.as_str(),
+                "Updated account plan"
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+//! Setup of self-hosted backends from automation, e.g. provisioning scripts that deploy agents alongside the backend.
+//! Run through the subcommands of the server binary, which operate on the backend's database directly rather than
+//! through the dashboard API, so they need no dashboard user.
+
+use archodex_error::{
+    anyhow::{self, Context as _, anyhow},
+    conflict, not_found,
+};
+use tracing::info;
+
+use crate::{
+    Result, account_cache, db::QueryCheckFirstRealError as _, report_api_key::ReportApiKey,
+    report_api_keys::ensure_report_api_key_entitlement,
+};
+
+/// A report key created by [`create_report_api_key`]
+pub struct BootstrapReportApiKey {
+    pub id: u32,
+    pub value: String,
+}
+
+/// Creates a report key of the account and returns its value. Keys are created on behalf of the user who created the
+/// account, and unlike keys created through the dashboard API their values are returned directly.
+pub async fn create_report_api_key(
+    account_id: &str,
+    description: Option<String>,
+) -> anyhow::Result<BootstrapReportApiKey> {
+    create(account_id, description)
+        .await
+        .map_err(|err| anyhow!("Failed to create report key: {err}"))
+}
+
+async fn create(account_id: &str, description: Option<String>) -> Result<BootstrapReportApiKey> {
+    let Some(account) = account_cache::get_fresh_account(account_id).await? else {
+        not_found!("Account {account_id} not found");
+    };
+
+    let Some(created_by) = account.created_by() else {
+        conflict!(
+            "Account {account_id} has no recorded creator to create the report key on behalf of"
+        );
+    };
+
+    let db = account.resources_db().await?;
+
+    ensure_report_api_key_entitlement(&account, &db).await?;
+
+    let report_api_key = ReportApiKey::new(description, created_by.clone());
+
+    db.query("CREATE $report_api_key CONTENT { description: $description, created_by: $created_by } RETURN NONE")
+        .bind(("report_api_key", surrealdb::sql::Thing::from(&report_api_key)))
+        .bind(("description", report_api_key.description().map(str::to_owned)))
+        .bind((
+            "created_by",
+            surrealdb::sql::Thing::from(report_api_key.created_by()),
+        ))
+        .await
+        .context("Failed to submit query to create report key")?
+        .check_first_real_error()
+        .context("Failed to create report key")?;
+
+    let value = report_api_key
+        .generate_value(account.id(), account.salt().to_owned())
+        .await?;
+
+    info!(
+        report_api_key_id = report_api_key.id(),
+        "Created Report API Key"
+    );
+
+    Ok(BootstrapReportApiKey {
+        id: report_api_key.id(),
+        value,
+    })
+}
@@ -0,0 +1,205 @@
+// Offline bundles let agents in air-gapped environments write reports to disk for later upload from a connected
+// machine. A bundle is a JSON document of the form:
+//
+// {
+//   "version": 1,
+//   "manifest": "<base64 encoded manifest JSON>",
+//   "reports": ["<base64 encoded report request JSON>", ...],
+//   "hmac": "<hex encoded HMAC-SHA256 of the decoded manifest bytes, keyed with the report key value>"
+// }
+//
+// The manifest lists the SHA-256 hash of each report in order, so the HMAC covers every report in the bundle. Manifest
+// and reports are embedded as encoded bytes so that signatures are computed over exactly what the agent wrote, without
+// needing a canonical JSON serialization.
+//
+// Bundles are uploaded by operators with the admin token, not with the report key that signed them, so whoever carries
+// a bundle out of the air-gapped environment can't forge one. The manifest holds the nonce of the signing report key
+// value instead, from which the backend derives the value again to verify the HMAC. The nonce alone doesn't reveal the
+// value.
+
+use std::collections::HashMap;
+
+use axum::{Json, extract::Path, http::HeaderMap};
+use base64::{Engine as _, prelude::BASE64_STANDARD};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac as _};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use tracing::{info, instrument, warn};
+
+use archodex_error::{anyhow::Context as _, bad_request, forbidden, unauthorized};
+
+use crate::{
+    Result, admin,
+    db::QueryCheckFirstRealError as _,
+    query_cache,
+    report::{self, Request},
+    report_api_key::{ReportApiKey, ReportApiKeyIsValidQueryResponse, ReportApiKeyQueries as _},
+    sensor::Sensor,
+    usage,
+};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Bundle {
+    version: u32,
+    manifest: String,
+    reports: Vec<String>,
+    hmac: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BundleManifest {
+    account_id: String,
+    report_api_key_id: u32,
+    // Base64 encoded nonce of the report key value that signed the bundle
+    report_api_key_value_nonce: String,
+    #[serde(deserialize_with = "crate::timestamp::deserialize")]
+    created_at: DateTime<Utc>,
+    reports: Vec<BundleManifestReport>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BundleManifestReport {
+    sha256: String,
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct ImportBundleResponse {
+    ingested_reports: usize,
+}
+
+// Validates and ingests an offline bundle into the account. Only bundles signed by an active report key of the account
+// are accepted. Reports are ingested in manifest order, each in its own transaction, keeping the capture timestamps
+// recorded by the agent. Ingestion only ever widens observation windows, so a partially ingested bundle can safely be
+// uploaded again.
+#[instrument(err, skip_all)]
+pub(crate) async fn import(
+    Path(params): Path<HashMap<String, String>>,
+    headers: HeaderMap,
+    Json(bundle): Json<Bundle>,
+) -> Result<Json<ImportBundleResponse>> {
+    let account = admin::account(&params).await?;
+
+    account.check_ingestion_allowed()?;
+
+    if bundle.version != 1 {
+        bad_request!("Unsupported bundle version {}", bundle.version);
+    }
+
+    let Ok(manifest_bytes) = BASE64_STANDARD.decode(&bundle.manifest) else {
+        bad_request!("Bundle manifest is not valid base64");
+    };
+
+    let Ok(signature) = hex::decode(&bundle.hmac) else {
+        bad_request!("Bundle HMAC is not valid hex");
+    };
+
+    let manifest = match serde_json::from_slice::<BundleManifest>(&manifest_bytes) {
+        Ok(manifest) => manifest,
+        Err(err) => bad_request!("Invalid bundle manifest: {err}"),
+    };
+
+    if manifest.account_id != account.id() {
+        bad_request!("Bundle was created for a different account");
+    }
+
+    let Ok(nonce) = BASE64_STANDARD.decode(&manifest.report_api_key_value_nonce) else {
+        bad_request!("Bundle report key value nonce is not valid base64");
+    };
+
+    let report_api_key_is_valid = account
+        .resources_db()
+        .await?
+        .report_api_key_is_valid_query(manifest.report_api_key_id)
+        .await
+        .context("Failed to submit query to check bundle report key")?
+        .check_first_real_error()
+        .context("Failed to check bundle report key")?
+        .take::<Option<ReportApiKeyIsValidQueryResponse>>(0)?
+        .is_some_and(|response| response.is_valid());
+
+    if !report_api_key_is_valid {
+        forbidden!("Bundle was signed with a report key that does not exist or was revoked");
+    }
+
+    let Ok(report_api_key_value) = ReportApiKey::value_with_nonce(
+        manifest.report_api_key_id,
+        account.id(),
+        account.salt().to_owned(),
+        &nonce,
+    )
+    .await
+    else {
+        bad_request!("Bundle report key value nonce is invalid");
+    };
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(report_api_key_value.as_bytes())
+        .expect("HMAC can take a key of any size");
+    mac.update(&manifest_bytes);
+    if mac.verify_slice(&signature).is_err() {
+        warn!("Bundle HMAC does not match");
+        unauthorized!();
+    }
+
+    if manifest.reports.len() != bundle.reports.len() {
+        bad_request!(
+            "Bundle manifest lists {} reports but the bundle contains {}",
+            manifest.reports.len(),
+            bundle.reports.len()
+        );
+    }
+
+    // Validate every report before ingesting any of them so a malformed bundle is rejected as a whole
+    let mut requests = Vec::with_capacity(bundle.reports.len());
+    for (index, (report, manifest_report)) in
+        bundle.reports.iter().zip(&manifest.reports).enumerate()
+    {
+        let Ok(report_bytes) = BASE64_STANDARD.decode(report) else {
+            bad_request!("Bundle report {index} is not valid base64");
+        };
+
+        if hex::encode(Sha256::digest(&report_bytes)) != manifest_report.sha256.to_lowercase() {
+            bad_request!("Bundle report {index} does not match its manifest hash");
+        }
+
+        requests.push(Request::from_slice(
+            &report_bytes,
+            &format!("bundle report {index}"),
+        )?);
+    }
+
+    let sensor = Sensor::from_headers(&account, &headers, manifest.report_api_key_id).await?;
+
+    usage::check_quotas(&account).await?;
+
+    let ingested_reports = requests.len();
+
+    let ingested = async {
+        for request in requests {
+            report::ingest(&account, &sensor, request, None).await?;
+        }
+
+        Result::Ok(())
+    }
+    .await;
+
+    // Admin routes aren't layered with the account's query cache invalidation, and failed ingestion may have written
+    // part of the bundle
+    query_cache::invalidate(account.id());
+
+    ingested?;
+
+    info!(
+        account_id = account.id(),
+        report_api_key_id = manifest.report_api_key_id,
+        bundle_created_at = %manifest.created_at,
+        ingested_reports,
+        "Imported offline bundle"
+    );
+
+    Ok(Json(ImportBundleResponse { ingested_reports }))
+}
@@ -0,0 +1,69 @@
+// Read-after-write consistency for clients that query an account right after reporting to it. Report responses include
+// an opaque consistency token recording when the report was committed. Passing the token back in the
+// `X-Consistency-Token` header of a dashboard request makes the request bypass the account cache and read in a regular
+// transaction rather than a read-only one, so the read reflects the report.
+//
+// Once a token is older than `CONSISTENCY_TOKEN_WINDOW` every cache has converged on the write it records, and requests
+// passing it are served normally. This keeps clients that always send their last token from bypassing caches forever.
+
+use std::time::Duration;
+
+use axum::http::{HeaderMap, HeaderName};
+use base64::{Engine as _, prelude::BASE64_URL_SAFE_NO_PAD};
+use chrono::{DateTime, Utc};
+
+use archodex_error::bad_request;
+
+use crate::Result;
+
+pub(crate) const CONSISTENCY_TOKEN_HEADER: HeaderName =
+    HeaderName::from_static("x-consistency-token");
+
+const CONSISTENCY_TOKEN_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ConsistencyToken {
+    written_at: DateTime<Utc>,
+}
+
+impl ConsistencyToken {
+    // Must be called after the write has been committed
+    pub(crate) fn issue() -> String {
+        Self::issue_for(Utc::now())
+    }
+
+    // Issues a token for a write committed at a known time, e.g. a report ingested by an earlier request
+    pub(crate) fn issue_for(written_at: DateTime<Utc>) -> String {
+        BASE64_URL_SAFE_NO_PAD.encode(written_at.timestamp_millis().to_be_bytes())
+    }
+
+    fn parse(token: &str) -> Option<Self> {
+        let bytes = BASE64_URL_SAFE_NO_PAD.decode(token).ok()?;
+        let millis = i64::from_be_bytes(bytes.try_into().ok()?);
+
+        Some(Self {
+            written_at: DateTime::from_timestamp_millis(millis)?,
+        })
+    }
+
+    // Returns the token of the request if reads must bypass caches to reflect the write it records
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Result<Option<Self>> {
+        let Some(token) = headers.get(&CONSISTENCY_TOKEN_HEADER) else {
+            return Ok(None);
+        };
+
+        let Some(token) = token.to_str().ok().and_then(Self::parse) else {
+            bad_request!("Invalid X-Consistency-Token header");
+        };
+
+        Ok(token.requires_fresh_read().then_some(token))
+    }
+
+    fn requires_fresh_read(&self) -> bool {
+        match Utc::now().signed_duration_since(self.written_at).to_std() {
+            Ok(age) => age < CONSISTENCY_TOKEN_WINDOW,
+            // Tokens from the future were issued by instances with skewed clocks, and still require fresh reads
+            Err(_) => true,
+        }
+    }
+}
@@ -1,37 +1,69 @@
 use std::collections::HashMap;
+#[cfg(feature = "rocksdb")]
+use std::collections::HashSet;
+use std::future::{Future, IntoFuture};
+use std::pin::Pin;
+#[cfg(feature = "rocksdb")]
 use std::sync::LazyLock;
+use std::time::{Duration, Instant};
 
 use axum::{
     Extension,
     extract::{Path, Request},
+    http::StatusCode,
     middleware::Next,
     response::Response,
 };
+use serde::Serialize;
 use surrealdb::{
     Surreal,
     engine::any::Any,
-    opt::{Config, capabilities::Capabilities},
+    opt::{Config, IntoQuery, capabilities::Capabilities},
 };
-use tokio::sync::{OnceCell, RwLock};
+use tokio::sync::OnceCell;
+#[cfg(feature = "rocksdb")]
+use tokio::sync::RwLock;
 use tracing::{info, instrument, warn};
 
 use crate::{
     Result,
-    account::{Account, AccountQueries},
-    auth::{DashboardAuth, ReportApiKeyAuth},
+    account::Account,
+    account_cache,
+    auth::{AccountRole, DashboardAuth, ReportApiKeyAuth},
+    consistency::ConsistencyToken,
     env::Env,
+    metrics::{
+        STORAGE_THROTTLES_TOTAL, SURREALDB_QUERY_DURATION_SECONDS, SURREALDB_QUERY_ERRORS_TOTAL,
+        SURREALDB_QUERY_STATEMENTS, current_span_label,
+    },
+    report_key_backoff, resources_db_pool, router,
+    tenant_naming::TenantNaming,
 };
 use archodex_error::{
-    anyhow::{self, Context as _},
-    not_found,
+    ErrorCode, PublicError,
+    anyhow::{self, Context as _, ensure},
+    forbidden, not_found,
 };
 
 #[derive(Default)]
-pub(crate) struct BeginReadonlyStatement;
+pub(crate) struct BeginReadonlyStatement {
+    // Consistent transactions reflect every committed write, see `consistency`
+    consistent: bool,
+}
+
+impl BeginReadonlyStatement {
+    pub(crate) fn consistent_with(consistency_token: Option<ConsistencyToken>) -> Self {
+        Self {
+            consistent: consistency_token.is_some(),
+        }
+    }
+}
 
 impl surrealdb::opt::IntoQuery for BeginReadonlyStatement {
     fn into_query(self) -> surrealdb::Result<Vec<surrealdb::sql::Statement>> {
-        let begin = {
+        let begin = if self.consistent {
+            surrealdb::sql::statements::BeginStatement::default()
+        } else {
             #[cfg(not(feature = "archodex-com"))]
             {
                 surrealdb::sql::statements::BeginStatement::default()
@@ -57,25 +89,58 @@ pub(crate) async fn migrate_service_data_database(
     // above. But the resource policy can take 30+ seconds to propagate.
     // Instead, we'll use the customer data management role to migrate the
     // database.
-    let db = resources_db(service_data_surrealdb_url, archodex_account_id)
+    let db = connect_resources_db(service_data_surrealdb_url, archodex_account_id)
         .await
         .context("Failed to get SurrealDB client")?;
 
     #[cfg(not(feature = "archodex-com"))]
-    db.query("DEFINE DATABASE resources;")
-        .await?
-        .check()
-        .context("Failed to define 'resources' SurrealDB database")?;
+    db.query(format!(
+        "DEFINE DATABASE {};",
+        TenantNaming::current().resources_database()
+    ))
+    .await?
+    .check()
+    .context("Failed to define 'resources' SurrealDB database")?;
 
-    migrator::migrate_account_resources_database(&db)
-        .await
-        .context("Failed to migrate 'resources' database")?;
+    migrate_resources_db(&db, archodex_account_id).await?;
 
     info!("Service data SurrealDB Database 'resources' migrated and ready for use");
 
     Ok(())
 }
 
+// Records the ID of the account a resources database belongs to before migrating it, refusing databases that belong to
+// another account. Databases migrated before account IDs were recorded are backfilled by migrating them again with
+// `POST /admin/account/:account_id/migrate`.
+#[instrument(err, skip(db))]
+pub(crate) async fn migrate_resources_db(
+    db: &DBConnection,
+    account_id: &str,
+) -> anyhow::Result<()> {
+    match resources_db_account_id(db).await? {
+        Some(database_account_id) => ensure!(
+            database_account_id == account_id,
+            "Resources database for account {account_id} belongs to account {database_account_id}"
+        ),
+        None => {
+            info!("Recording account ID in resources database");
+
+            db.query(DEFINE_ACCOUNT_ID_PARAM)
+                .bind(("account_id", account_id.to_string()))
+                .await
+                .context("Failed to submit query to record resources database account ID")?
+                .check_first_real_error()
+                .context("Failed to record resources database account ID")?;
+        }
+    }
+
+    migrator::migrate_account_resources_database(db)
+        .await
+        .context("Failed to migrate 'resources' database")?;
+
+    Ok(())
+}
+
 #[cfg(feature = "rocksdb")]
 #[derive(PartialEq)]
 enum ArchodexSurrealDatabase {
@@ -114,7 +179,10 @@ async fn get_nonconcurrent_db_connection(
                     .context("Failed to sign in to SurrealDB with SURREALDB_USERNAME and SURREALDB_PASSWORD environment values")?;
             }
 
-            db.use_ns("archodex").use_db("accounts").await?;
+            let naming = TenantNaming::current();
+            db.use_ns(naming.accounts_namespace())
+                .use_db(naming.accounts_database())
+                .await?;
 
             anyhow::Ok(Mutex::new(NonconcurrentDBState { connection: db, current_database: ArchodexSurrealDatabase::Accounts }))
         })
@@ -130,7 +198,11 @@ async fn get_concurrent_db_connection(url: &str) -> anyhow::Result<Surreal<Any>>
             let db = surrealdb::engine::any::connect((
                 url,
                 Config::default()
-                    .capabilities(Capabilities::default().with_live_query_notifications(false))
+                    // Live queries on the accounts database drive account cache invalidation when enabled
+                    .capabilities(
+                        Capabilities::default()
+                            .with_live_query_notifications(cfg!(feature = "live-query")),
+                    )
                     .strict(),
             ))
             .await?;
@@ -141,7 +213,10 @@ async fn get_concurrent_db_connection(url: &str) -> anyhow::Result<Surreal<Any>>
                     .context("Failed to sign in to SurrealDB with SURREALDB_USERNAME and SURREALDB_PASSWORD environment values")?;
             }
 
-            db.use_ns("archodex").use_db("accounts").await?;
+            let naming = TenantNaming::current();
+            db.use_ns(naming.accounts_namespace())
+                .use_db(naming.accounts_database())
+                .await?;
 
             anyhow::Ok(db)
         })
@@ -167,6 +242,217 @@ impl std::ops::Deref for DBConnection {
     }
 }
 
+impl DBConnection {
+    // Shadows `Surreal::query` so that every query issued through a `DBConnection` is instrumented.
+    pub(crate) fn query(&self, query: impl IntoQuery) -> InstrumentedQuery<'_> {
+        InstrumentedQuery {
+            query: std::ops::Deref::deref(self).query(query),
+            binding_names: vec![],
+            statements: 1,
+        }
+    }
+}
+
+// Wraps a SurrealDB query to record its duration, number of statements, and errors in metrics. Queries slower than the
+// configured threshold are logged with the names of their bound parameters. Values are never logged as they may
+// contain customer data.
+pub(crate) struct InstrumentedQuery<'r> {
+    query: surrealdb::method::Query<'r, Any>,
+    binding_names: Vec<String>,
+    statements: usize,
+}
+
+impl<'r> InstrumentedQuery<'r> {
+    pub(crate) fn query(mut self, query: impl IntoQuery) -> Self {
+        self.query = self.query.query(query);
+        self.statements += 1;
+        self
+    }
+
+    // Number of times `query` was called, which counts statements given as strings of several statements once
+    pub(crate) fn statements(&self) -> usize {
+        self.statements
+    }
+
+    pub(crate) fn bind<N: Into<String>, V: Serialize + 'static>(
+        mut self,
+        (name, value): (N, V),
+    ) -> Self {
+        let name = name.into();
+        self.binding_names.push(name.clone());
+        self.query = self.query.bind((name, value));
+        self
+    }
+
+    // Fails the query with `504 Gateway Timeout` if it takes longer than `SURREALDB_QUERY_TIMEOUT_SECONDS`
+    pub(crate) fn timeout(self) -> TimedQuery<'r> {
+        TimedQuery {
+            query: self,
+            timeout: Env::surrealdb_query_timeout(),
+        }
+    }
+}
+
+impl std::fmt::Debug for InstrumentedQuery<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.query.fmt(f)
+    }
+}
+
+impl<'r> IntoFuture for InstrumentedQuery<'r> {
+    type Output = surrealdb::Result<surrealdb::Response>;
+    type IntoFuture = Pin<Box<dyn Future<Output = Self::Output> + Send + 'r>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        let label = current_span_label();
+        let InstrumentedQuery {
+            query,
+            binding_names,
+            ..
+        } = self;
+
+        Box::pin(async move {
+            let start = Instant::now();
+            let result = query.await;
+            let duration = start.elapsed();
+
+            SURREALDB_QUERY_DURATION_SECONDS
+                .with_label_values(&[label])
+                .observe(duration.as_secs_f64());
+
+            match &result {
+                Ok(response) => {
+                    #[allow(clippy::cast_precision_loss)]
+                    SURREALDB_QUERY_STATEMENTS
+                        .with_label_values(&[label])
+                        .observe(response.num_statements() as f64);
+                }
+                Err(err) => {
+                    SURREALDB_QUERY_ERRORS_TOTAL
+                        .with_label_values(&[label, error_type(err)])
+                        .inc();
+                }
+            }
+
+            if duration >= Env::surrealdb_slow_query_threshold() {
+                warn!(
+                    query = label,
+                    duration_ms = duration.as_millis(),
+                    statements = result.as_ref().ok().map(surrealdb::Response::num_statements),
+                    binding_names = ?binding_names,
+                    "Slow SurrealDB query"
+                );
+            }
+
+            result
+        })
+    }
+}
+
+// A query that request handlers stop waiting for after a timeout, so that long-running graph queries don't hold
+// requests open indefinitely. The query's future is dropped on timeout, which cancels queries that haven't been sent
+// yet, but SurrealDB finishes executing queries it has already received. Statements that may run long should also set a
+// `TIMEOUT` clause, see `statement_timeout`, so SurrealDB stops executing them too.
+pub(crate) struct TimedQuery<'r> {
+    query: InstrumentedQuery<'r>,
+    timeout: Duration,
+}
+
+impl<'r> IntoFuture for TimedQuery<'r> {
+    type Output = anyhow::Result<surrealdb::Response>;
+    type IntoFuture = Pin<Box<dyn Future<Output = Self::Output> + Send + 'r>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        let label = current_span_label();
+        let TimedQuery { query, timeout } = self;
+
+        Box::pin(async move {
+            if let Ok(result) = tokio::time::timeout(timeout, query).await {
+                return Ok(result?);
+            }
+
+            SURREALDB_QUERY_ERRORS_TOTAL
+                .with_label_values(&[label, "query_timed_out"])
+                .inc();
+
+            warn!(query = label, ?timeout, "SurrealDB query timed out");
+
+            Err(anyhow::anyhow!(PublicError::new(
+                StatusCode::GATEWAY_TIMEOUT,
+                "Query took too long to complete",
+            )))
+        })
+    }
+}
+
+// Grace period before SurrealDB stops executing statements of timed out queries, so that the query times out first and
+// fails with `504 Gateway Timeout` rather than a statement timeout error
+const STATEMENT_TIMEOUT_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+// `TIMEOUT` clause for statements of queries with a timeout, which makes SurrealDB stop executing the statement once
+// the query has timed out
+pub(crate) fn statement_timeout() -> String {
+    format!(
+        "TIMEOUT {}ms",
+        (Env::surrealdb_query_timeout() + STATEMENT_TIMEOUT_GRACE_PERIOD).as_millis()
+    )
+}
+
+fn error_type(err: &surrealdb::Error) -> &'static str {
+    match err {
+        err if is_throttling_error(err) => "throttled",
+        surrealdb::Error::Db(surrealdb::error::Db::QueryNotExecuted) => "query_not_executed",
+        surrealdb::Error::Db(surrealdb::error::Db::QueryTimedout) => "query_timed_out",
+        surrealdb::Error::Db(surrealdb::error::Db::QueryCancelled) => "query_cancelled",
+        surrealdb::Error::Db(surrealdb::error::Db::TxRetryable) => "transaction_conflict",
+        surrealdb::Error::Db(_) => "db",
+        surrealdb::Error::Api(_) => "api",
+    }
+}
+
+// The DynamoDB storage engine used by archodex.com surfaces throttled table requests as errors naming the DynamoDB
+// exception
+const THROTTLING_ERRORS: [&str; 3] = [
+    "ProvisionedThroughputExceededException",
+    "ThrottlingException",
+    "RequestLimitExceeded",
+];
+
+// Throttled requests are retried with backoff by the storage engine before they fail, so agents wait a while longer
+const STORAGE_THROTTLED_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+fn is_throttling_error(err: &surrealdb::Error) -> bool {
+    let message = err.to_string();
+
+    THROTTLING_ERRORS
+        .iter()
+        .any(|throttling_error| message.contains(throttling_error))
+}
+
+// Turns storage throttling into a retryable error with a specific code, so agents back off instead of receiving opaque
+// internal errors. Other errors remain internal errors.
+pub(crate) fn storage_throttled_error(account: &Account, err: surrealdb::Error) -> PublicError {
+    if !is_throttling_error(&err) {
+        return err.into();
+    }
+
+    STORAGE_THROTTLES_TOTAL
+        .with_label_values(&[account.id()])
+        .inc();
+
+    warn!(
+        ?err,
+        account_id = account.id(),
+        "Storage throttled account query"
+    );
+
+    PublicError::with_code(
+        ErrorCode::StorageThrottled,
+        "Storage capacity for the account is temporarily exceeded",
+    )
+    .retry_after(STORAGE_THROTTLED_RETRY_AFTER)
+}
+
 #[instrument(err)]
 pub(crate) async fn accounts_db() -> Result<DBConnection> {
     #[cfg(feature = "archodex-com")]
@@ -180,7 +466,10 @@ pub(crate) async fn accounts_db() -> Result<DBConnection> {
         let mut db_state = connection.lock().await;
 
         if db_state.current_database != ArchodexSurrealDatabase::Accounts {
-            db_state.connection.use_db("accounts").await?;
+            db_state
+                .connection
+                .use_db(TenantNaming::current().accounts_database())
+                .await?;
             db_state.current_database = ArchodexSurrealDatabase::Accounts;
         }
 
@@ -195,55 +484,147 @@ pub(crate) async fn accounts_db() -> Result<DBConnection> {
     ))
 }
 
+// Every resources database records the ID of the account it belongs to in its `$archodex_account_id` parameter. Each
+// connection to a resources database is checked against it before first use, so that a misconfigured service data URL
+// or namespace selection fails loudly instead of serving one account's records to another. SurrealDB table permissions
+// only apply to record users, not to the system user the backend connects as, so they can't provide this guarantee.
+#[cfg(feature = "rocksdb")]
+static VERIFIED_EMBEDDED_RESOURCES_DBS: LazyLock<RwLock<HashSet<String>>> =
+    LazyLock::new(|| RwLock::new(HashSet::new()));
+
+const DEFINE_ACCOUNT_ID_PARAM: &str =
+    "DEFINE PARAM OVERWRITE $archodex_account_id VALUE $account_id;";
+
 #[instrument(err)]
 pub(crate) async fn resources_db(
     service_data_surrealdb_url: &str,
     account_id: &str,
 ) -> anyhow::Result<DBConnection> {
-    static DBS_BY_URL: LazyLock<RwLock<HashMap<String, Surreal<Any>>>> =
-        LazyLock::new(|| RwLock::new(HashMap::new()));
-
     #[cfg(feature = "rocksdb")]
     if service_data_surrealdb_url.starts_with("rocksdb:") {
-        let connection = get_nonconcurrent_db_connection(service_data_surrealdb_url).await?;
-        let mut db_state = connection.lock().await;
+        let db = embedded_resources_db(service_data_surrealdb_url).await?;
+
+        if !VERIFIED_EMBEDDED_RESOURCES_DBS
+            .read()
+            .await
+            .contains(account_id)
+        {
+            verify_resources_db_account(&db, account_id).await?;
 
-        if db_state.current_database != ArchodexSurrealDatabase::Resources {
-            db_state.connection.use_db("resources").await?;
-            db_state.current_database = ArchodexSurrealDatabase::Resources;
+            VERIFIED_EMBEDDED_RESOURCES_DBS
+                .write()
+                .await
+                .insert(account_id.to_string());
         }
 
-        return Ok(DBConnection::Nonconcurrent(
-            tokio::sync::MutexGuard::try_map(db_state, |state| Some(&mut state.connection))
-                .unwrap_or_else(|_| unreachable!()),
-        ));
+        return Ok(db);
     }
 
-    let dbs_by_url = DBS_BY_URL.read().await;
+    Ok(DBConnection::Concurrent(
+        resources_db_pool::get(service_data_surrealdb_url, account_id).await?,
+    ))
+}
 
-    let db = if let Some(db) = dbs_by_url.get(service_data_surrealdb_url) {
-        db.clone()
-    } else {
-        drop(dbs_by_url);
+async fn resources_db_account_id(db: &DBConnection) -> anyhow::Result<Option<String>> {
+    Ok(db
+        .query("RETURN $archodex_account_id;")
+        .await
+        .context("Failed to submit query to get resources database account ID")?
+        .check_first_real_error()
+        .context("Failed to get resources database account ID")?
+        .take::<Option<String>>(0)?)
+}
 
-        let mut dbs_by_url = DBS_BY_URL.write().await;
+// Verification costs a single query per connection, recorded in `surrealdb_query_duration_seconds` under this
+// function's name. It never writes, so standbys verify read-only replicas like any other database.
+#[instrument(err, skip(db))]
+pub(crate) async fn verify_resources_db_account(
+    db: &DBConnection,
+    account_id: &str,
+) -> anyhow::Result<()> {
+    match resources_db_account_id(db).await? {
+        Some(database_account_id) => ensure!(
+            database_account_id == account_id,
+            "Resources database for account {account_id} belongs to account {database_account_id}"
+        ),
+        // Databases migrated before account IDs were recorded are served until `RESOURCES_DB_ACCOUNT_ID_REQUIRED` is
+        // set, once operators have backfilled them, see `migrate_resources_db`
+        None => {
+            ensure!(
+                !Env::resources_db_account_id_required(),
+                "Resources database for account {account_id} doesn't record its account ID, migrate it with `POST /admin/account/{account_id}/migrate`"
+            );
+
+            warn!(
+                "Resources database doesn't record its account ID, migrate it with `POST /admin/account/:account_id/migrate`"
+            );
+        }
+    }
 
-        if let Some(db) = dbs_by_url.get(service_data_surrealdb_url) {
-            db.clone()
-        } else {
-            let db = surrealdb::engine::any::connect((
-                service_data_surrealdb_url,
-                Config::default()
-                    .capabilities(Capabilities::default().with_live_query_notifications(false))
-                    .strict(),
-            ))
+    Ok(())
+}
+
+// Connects to a resources database without checking which account it belongs to, for migrations that record it
+#[instrument(err)]
+pub(crate) async fn connect_resources_db(
+    service_data_surrealdb_url: &str,
+    account_id: &str,
+) -> anyhow::Result<DBConnection> {
+    #[cfg(feature = "rocksdb")]
+    if service_data_surrealdb_url.starts_with("rocksdb:") {
+        return embedded_resources_db(service_data_surrealdb_url).await;
+    }
+
+    Ok(DBConnection::Concurrent(
+        open_resources_db_connection(service_data_surrealdb_url, account_id, false).await?,
+    ))
+}
+
+#[cfg(feature = "rocksdb")]
+async fn embedded_resources_db(service_data_surrealdb_url: &str) -> anyhow::Result<DBConnection> {
+    let naming = TenantNaming::current();
+
+    // The connection stays in the accounts namespace and switches databases within it
+    ensure!(
+        naming.shares_namespace(),
+        "Embedded databases can't store resources databases in a namespace per account"
+    );
+
+    let connection = get_nonconcurrent_db_connection(service_data_surrealdb_url).await?;
+    let mut db_state = connection.lock().await;
+
+    if db_state.current_database != ArchodexSurrealDatabase::Resources {
+        db_state
+            .connection
+            .use_db(naming.resources_database())
             .await?;
+        db_state.current_database = ArchodexSurrealDatabase::Resources;
+    }
 
-            dbs_by_url.insert(service_data_surrealdb_url.to_string(), db.clone());
+    Ok(DBConnection::Nonconcurrent(
+        tokio::sync::MutexGuard::try_map(db_state, |state| Some(&mut state.connection))
+            .unwrap_or_else(|_| unreachable!()),
+    ))
+}
 
-            db
-        }
-    };
+// Opens a connection to the resources database of an account. SurrealDB connections track the selected namespace and
+// database for every clone of the connection, so each connection only ever selects a single account's namespace. Only
+// connections dedicated to live queries receive live query notifications.
+#[instrument(err)]
+pub(crate) async fn open_resources_db_connection(
+    service_data_surrealdb_url: &str,
+    account_id: &str,
+    live_query_notifications: bool,
+) -> anyhow::Result<Surreal<Any>> {
+    let db = surrealdb::engine::any::connect((
+        service_data_surrealdb_url,
+        Config::default()
+            .capabilities(
+                Capabilities::default().with_live_query_notifications(live_query_notifications),
+            )
+            .strict(),
+    ))
+    .await?;
 
     if let Some(creds) = Env::surrealdb_creds() {
         db.signin(creds)
@@ -251,15 +632,12 @@ pub(crate) async fn resources_db(
             .with_context(|| format!("Failed to sign in to SurrealDB instance {service_data_surrealdb_url} with SURREALDB_USERNAME and SURREALDB_PASSWORD environment values"))?;
     }
 
-    let namespace = if cfg!(feature = "archodex-com") {
-        format!("a{account_id}")
-    } else {
-        "archodex".to_string()
-    };
-
-    db.use_ns(namespace).use_db("resources").await?;
+    let naming = TenantNaming::current();
+    db.use_ns(naming.resources_namespace(account_id))
+        .use_db(naming.resources_database())
+        .await?;
 
-    Ok(DBConnection::Concurrent(db))
+    Ok(db)
 }
 
 #[instrument(err, skip_all)]
@@ -273,15 +651,19 @@ pub(crate) async fn dashboard_auth_account(
         .get("account_id")
         .expect(":account_id should be in path for dashboard account authentication");
 
-    auth.validate_account_access(account_id).await?;
+    let role = auth.validate_account_access(account_id).await?;
 
-    let account = accounts_db()
-        .await?
-        .get_account_by_id(account_id.to_owned())
-        .await?
-        .check_first_real_error()?
-        .take::<Option<Account>>(0)
-        .with_context(|| format!("Failed to get record for account ID {account_id:?}"))?;
+    if role == AccountRole::Viewer && !router::is_read_only(&req) {
+        forbidden!("Account viewers may not modify the account");
+    }
+
+    let consistency_token = ConsistencyToken::from_headers(req.headers())?;
+
+    let account = if consistency_token.is_some() {
+        account_cache::get_fresh_account(account_id).await?
+    } else {
+        account_cache::get_account(account_id).await?
+    };
 
     let Some(account) = account else {
         not_found!("Account not found");
@@ -289,6 +671,11 @@ pub(crate) async fn dashboard_auth_account(
 
     req.extensions_mut().insert(account);
 
+    // Handlers check for the token to read in transactions that reflect the write it records
+    if let Some(consistency_token) = consistency_token {
+        req.extensions_mut().insert(consistency_token);
+    }
+
     Ok(next.run(req).await)
 }
 
@@ -298,20 +685,20 @@ pub(crate) async fn report_api_key_account(
     mut req: Request,
     next: Next,
 ) -> Result<Response> {
-    let account = accounts_db()
-        .await?
-        .get_account_by_id(auth.account_id().to_owned())
-        .await?
-        .check_first_real_error()?
-        .take::<Option<Account>>(0)
-        .context("Failed to get account record")?;
-
-    let Some(account) = account else {
+    let Some(account) = account_cache::get_account(auth.account_id()).await? else {
+        auth.record_value_failure();
         not_found!("Account not found");
     };
 
-    auth.validate_account_access(&*(account.resources_db().await?))
-        .await?;
+    account.check_ingestion_allowed()?;
+
+    // Ingestion tokens are verified statelessly to keep the report hot path cheap. They are short-lived, so revoking a
+    // report key stops ingestion once its outstanding tokens expire.
+    if let Some(value_fingerprint) = auth.value_fingerprint() {
+        auth.validate_account_access(&account).await?;
+
+        report_key_backoff::record_success(value_fingerprint);
+    }
 
     req.extensions_mut().insert(account);
 
@@ -338,6 +725,13 @@ impl QueryCheckFirstRealError for surrealdb::Response {
             return Ok(self);
         }
 
+        let label = current_span_label();
+        for err in errors.values() {
+            SURREALDB_QUERY_ERRORS_TOTAL
+                .with_label_values(&[label, error_type(err)])
+                .inc();
+        }
+
         if let Some((_, err)) = errors
             .into_iter()
             .filter(|(_, result)| {
@@ -356,3 +750,23 @@ impl QueryCheckFirstRealError for surrealdb::Response {
         Err(surrealdb::Error::Db(surrealdb::error::Db::QueryNotExecuted))
     }
 }
+
+// Parses the statements of a transaction, returning the statements with results in the order of their results.
+// SurrealDB returns no results for `BEGIN`, `COMMIT`, and `CANCEL` statements.
+#[cfg(test)]
+pub(crate) fn transaction_results(statements: &[&str]) -> Vec<surrealdb::sql::Statement> {
+    use surrealdb::sql::Statement;
+
+    surrealdb::sql::parse(&format!("BEGIN; {} COMMIT;", statements.join("\n")))
+        .expect("Transaction statements should parse")
+        .0
+        .0
+        .into_iter()
+        .filter(|statement| {
+            !matches!(
+                statement,
+                Statement::Begin(_) | Statement::Commit(_) | Statement::Cancel(_)
+            )
+        })
+        .collect()
+}
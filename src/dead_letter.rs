@@ -0,0 +1,334 @@
+// Reports whose ingestion fails are lost unless the agent sends them again, and agents only see the error response.
+// Accounts that enable dead-lettering with `PUT /account/:account_id/dead_lettering` keep the body of each `/report`
+// request that fails to be ingested in the `dead_letter_report` table of their resources database, with the error, the
+// sensor that sent it, and the request ID to find the failure in the backend's logs. Account members can list and
+// inspect dead letters, and account admins can retry them once the cause is fixed, or purge them.
+//
+// Reports that are not valid reports are rejected before ingestion and are not dead-lettered. Retried reports are not
+// checked against the sequence numbers of their report key, and are refused once their report key is revoked. Dead
+// letters count toward the account's storage quota, see `usage`. Dead letters are deleted after
+// `DEAD_LETTER_RETENTION`, and failed reports are not recorded while an account holds `MAX_DEAD_LETTER_REPORTS`.
+
+use std::collections::HashMap;
+
+use axum::{Extension, Json, extract::Path, http::HeaderMap};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+use archodex_error::{
+    PublicError,
+    anyhow::{Context as _, bail},
+    forbidden, not_found,
+};
+
+use crate::{
+    Result,
+    account::Account,
+    auth::DashboardAuth,
+    consistency::ConsistencyToken,
+    db::QueryCheckFirstRealError as _,
+    report::{self, Request},
+    report_api_key::{ReportApiKeyIsValidQueryResponse, ReportApiKeyQueries as _},
+    request_id::REQUEST_ID_HEADER,
+    sensor::Sensor,
+    surrealdb_deserializers, usage,
+};
+
+const DEAD_LETTER_RETENTION: &str = "30d";
+
+const MAX_DEAD_LETTER_REPORTS: u32 = 1000;
+
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
+pub(crate) struct DeadLetterReport {
+    #[serde(deserialize_with = "surrealdb_deserializers::string::deserialize")]
+    id: String,
+    #[serde(deserialize_with = "surrealdb_deserializers::u32::deserialize")]
+    report_api_key_id: u32,
+    agent_id: Option<String>,
+    // Error the report failed with, or failed with when it was last retried
+    error: String,
+    // `X-Request-ID` header of the failed request
+    request_id: Option<String>,
+    failed_at: DateTime<Utc>,
+    last_failed_at: DateTime<Utc>,
+    // Number of times ingestion of the report failed
+    attempts: u64,
+    payload_bytes: u64,
+    // Body of the report, only returned when a single dead letter is requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    payload: Option<String>,
+}
+
+fn dead_letter_report_thing(dead_letter_report_id: &str) -> surrealdb::sql::Thing {
+    surrealdb::sql::Thing::from(("dead_letter_report", dead_letter_report_id))
+}
+
+fn dead_letter_report_id(params: &HashMap<String, String>) -> Result<String> {
+    let Some(dead_letter_report_id) = params.get("dead_letter_report_id") else {
+        bail!("Missing dead_letter_report_id");
+    };
+
+    Ok(dead_letter_report_id.clone())
+}
+
+// Records a report that failed to be ingested, if the account dead-letters failed reports. Failing to record a dead
+// letter must never change the response to the failed report.
+pub(crate) async fn record(
+    account: &Account,
+    sensor: &Sensor,
+    headers: &HeaderMap,
+    payload: &[u8],
+    error: &PublicError,
+) {
+    if !account.dead_lettering_enabled() {
+        return;
+    }
+
+    let request_id = headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+
+    if let Err(err) = create(account, sensor, request_id, payload, error).await {
+        warn!(?err, "Failed to record dead-lettered report");
+    }
+}
+
+#[instrument(err, skip_all, fields(account_id = account.id()))]
+async fn create(
+    account: &Account,
+    sensor: &Sensor,
+    request_id: Option<String>,
+    payload: &[u8],
+    error: &PublicError,
+) -> Result<()> {
+    // Reports are parsed before they are ingested, so failed payloads are valid UTF-8
+    let payload = String::from_utf8_lossy(payload).into_owned();
+
+    let dead_letter_report_id = Uuid::now_v7().simple().to_string();
+
+    account
+        .resources_db()
+        .await?
+        .query(format!(
+            "DELETE dead_letter_report WHERE failed_at < time::now() - {DEAD_LETTER_RETENTION};"
+        ))
+        .query("IF ((SELECT count() AS count FROM dead_letter_report GROUP ALL)[0].count ?? 0) < $max {
+            CREATE $dead_letter_report CONTENT { report_api_key_id: $report_api_key_id, agent_id: $agent_id, error: $error, request_id: $request_id, payload: $payload } RETURN NONE;
+        };")
+        .bind(("max", MAX_DEAD_LETTER_REPORTS))
+        .bind((
+            "dead_letter_report",
+            dead_letter_report_thing(&dead_letter_report_id),
+        ))
+        .bind(("report_api_key_id", sensor.report_api_key_id()))
+        .bind(("agent_id", sensor.agent_id().map(str::to_string)))
+        .bind(("error", error.to_string()))
+        .bind(("request_id", request_id))
+        .bind(("payload", payload))
+        .await
+        .context("Failed to submit query to record dead-lettered report")?
+        .check_first_real_error()
+        .context("Failed to record dead-lettered report")?;
+
+    info!(dead_letter_report_id, "Recorded dead-lettered report");
+
+    Ok(())
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct ListDeadLetterReportsResponse {
+    dead_letter_reports: Vec<DeadLetterReport>,
+}
+
+#[instrument(err, skip_all, fields(account_id = account.id()))]
+pub(crate) async fn list_dead_letter_reports(
+    Extension(account): Extension<Account>,
+) -> Result<Json<ListDeadLetterReportsResponse>> {
+    let dead_letter_reports = account
+        .resources_db()
+        .await?
+        .query(format!(
+            "SELECT *, string::len(payload) AS payload_bytes OMIT payload FROM dead_letter_report WHERE failed_at >= time::now() - {DEAD_LETTER_RETENTION} ORDER BY id DESC"
+        ))
+        .await
+        .context("Failed to submit query to list dead-lettered reports")?
+        .check_first_real_error()
+        .context("Failed to list dead-lettered reports")?
+        .take::<Vec<DeadLetterReport>>(0)?;
+
+    Ok(Json(ListDeadLetterReportsResponse {
+        dead_letter_reports,
+    }))
+}
+
+async fn get(account: &Account, dead_letter_report_id: &str) -> Result<DeadLetterReport> {
+    let Some(dead_letter_report) = account
+        .resources_db()
+        .await?
+        .query("SELECT *, string::len(payload) AS payload_bytes FROM $dead_letter_report")
+        .bind((
+            "dead_letter_report",
+            dead_letter_report_thing(dead_letter_report_id),
+        ))
+        .await
+        .context("Failed to submit query to get dead-lettered report")?
+        .check_first_real_error()
+        .context("Failed to get dead-lettered report")?
+        .take::<Option<DeadLetterReport>>(0)?
+    else {
+        not_found!("Dead-lettered report not found");
+    };
+
+    Ok(dead_letter_report)
+}
+
+#[instrument(err, skip(account))]
+pub(crate) async fn get_dead_letter_report(
+    Extension(account): Extension<Account>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<DeadLetterReport>> {
+    let dead_letter_report_id = dead_letter_report_id(&params)?;
+
+    Ok(Json(get(&account, &dead_letter_report_id).await?))
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct RetryDeadLetterReportResponse {
+    // Reflects the ingested report, see `report::ReportResponse`
+    consistency_token: String,
+}
+
+// Ingests a dead-lettered report again, as the sensor that sent it. Dead letters are deleted once they are ingested,
+// and record the error of failed retries.
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn retry_dead_letter_report(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<RetryDeadLetterReportResponse>> {
+    auth.ensure_account_admin(account.id()).await?;
+
+    let dead_letter_report_id = dead_letter_report_id(&params)?;
+    let dead_letter_report = get(&account, &dead_letter_report_id).await?;
+
+    account.check_ingestion_allowed()?;
+
+    let mut req = Request::from_slice(
+        dead_letter_report.payload.unwrap_or_default().as_bytes(),
+        "dead-lettered report",
+    )?;
+
+    // Reports sent after the dead-lettered report have usually been ingested since
+    req.clear_sequence();
+
+    usage::check_quotas(&account).await?;
+
+    let sensor = Sensor::new(
+        dead_letter_report.report_api_key_id,
+        dead_letter_report.agent_id,
+    );
+
+    let db = account.resources_db().await?;
+
+    let report_api_key_is_valid = db
+        .report_api_key_is_valid_query(dead_letter_report.report_api_key_id)
+        .await
+        .context("Failed to submit query to check dead-lettered report key")?
+        .check_first_real_error()
+        .context("Failed to check dead-lettered report key")?
+        .take::<Option<ReportApiKeyIsValidQueryResponse>>(0)?
+        .is_some_and(|response| response.is_valid());
+
+    if !report_api_key_is_valid {
+        forbidden!(
+            "Dead-lettered report was sent with a report key that no longer exists or was revoked"
+        );
+    }
+
+    if let Err(err) = report::ingest(&account, &sensor, req, None).await {
+        db.query("UPDATE $dead_letter_report SET error = $error, last_failed_at = time::now(), attempts += 1 RETURN NONE")
+            .bind((
+                "dead_letter_report",
+                dead_letter_report_thing(&dead_letter_report_id),
+            ))
+            .bind(("error", err.to_string()))
+            .await
+            .context("Failed to submit query to record failed retry of dead-lettered report")?
+            .check_first_real_error()
+            .context("Failed to record failed retry of dead-lettered report")?;
+
+        return Err(err);
+    }
+
+    db.query("DELETE $dead_letter_report")
+        .bind((
+            "dead_letter_report",
+            dead_letter_report_thing(&dead_letter_report_id),
+        ))
+        .await
+        .context("Failed to submit query to delete retried dead-lettered report")?
+        .check_first_real_error()
+        .context("Failed to delete retried dead-lettered report")?;
+
+    info!(dead_letter_report_id, "Ingested dead-lettered report");
+
+    Ok(Json(RetryDeadLetterReportResponse {
+        consistency_token: ConsistencyToken::issue(),
+    }))
+}
+
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn purge_dead_letter_report(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<()> {
+    auth.ensure_account_admin(account.id()).await?;
+
+    let dead_letter_report_id = dead_letter_report_id(&params)?;
+
+    let deleted = account
+        .resources_db()
+        .await?
+        .query("DELETE $dead_letter_report RETURN BEFORE")
+        .bind((
+            "dead_letter_report",
+            dead_letter_report_thing(&dead_letter_report_id),
+        ))
+        .await
+        .context("Failed to submit query to purge dead-lettered report")?
+        .check_first_real_error()
+        .context("Failed to purge dead-lettered report")?
+        .take::<Vec<surrealdb::sql::Object>>(0)?;
+
+    if deleted.is_empty() {
+        not_found!("Dead-lettered report not found");
+    }
+
+    Ok(())
+}
+
+#[instrument(err, skip_all, fields(account_id = account.id()))]
+pub(crate) async fn purge_dead_letter_reports(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+) -> Result<()> {
+    auth.ensure_account_admin(account.id()).await?;
+
+    account
+        .resources_db()
+        .await?
+        .query("DELETE dead_letter_report")
+        .await
+        .context("Failed to submit query to purge dead-lettered reports")?
+        .check_first_real_error()
+        .context("Failed to purge dead-lettered reports")?;
+
+    info!("Purged dead-lettered reports");
+
+    Ok(())
+}
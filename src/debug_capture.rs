@@ -0,0 +1,216 @@
+// Operators can enable debug capture for a single account by setting its `debug_capture_until` field. Until that time
+// passes, the request and response payloads of the account's dashboard and report API calls are recorded in the
+// `debug_capture` table of the account's own resources database, to help debug agent or dashboard issues that are hard
+// to reproduce.
+//
+// Credentials are never recorded. Sensitive headers are redacted, values of JSON object keys that look like they hold
+// credentials are redacted, and non-JSON bodies are omitted. Captures are deleted after `DEBUG_CAPTURE_RETENTION`.
+//
+// Only bodies whose size is known up front and within `DEBUG_CAPTURE_MAX_BODY_BYTES` are buffered to capture them.
+// Streaming bodies, like batch reports, exports, and event streams, are passed through as is.
+
+use std::collections::BTreeMap;
+
+use axum::{
+    Extension,
+    body::{Body, Bytes, HttpBody as _, to_bytes},
+    extract::Request,
+    http::{HeaderMap, header::CONTENT_TYPE},
+    middleware::Next,
+    response::Response,
+};
+use tracing::{instrument, warn};
+
+use archodex_error::anyhow::Context as _;
+
+use crate::{
+    Result, account::Account, db::QueryCheckFirstRealError as _, standby,
+    value::surrealdb_value_from_json_value,
+};
+
+const DEBUG_CAPTURE_RETENTION: &str = "7d";
+
+// Larger payloads, such as offline bundles, are omitted from captures
+const DEBUG_CAPTURE_MAX_BODY_BYTES: usize = 64 * 1024;
+
+const STREAMING_CONTENT_TYPES: &[&str] = &["application/x-ndjson", "text/event-stream"];
+
+const REDACTED: &str = "[REDACTED]";
+
+const SENSITIVE_HEADERS: &[&str] = &[
+    "authorization",
+    "cookie",
+    "proxy-authorization",
+    "set-cookie",
+    "stripe-signature",
+    "x-api-key",
+];
+
+const SENSITIVE_KEY_FRAGMENTS: &[&str] = &[
+    "authorization",
+    "cookie",
+    "key",
+    "password",
+    "secret",
+    "token",
+];
+
+fn sanitized_headers(headers: &HeaderMap) -> surrealdb::sql::Value {
+    surrealdb::sql::Object::from(
+        headers
+            .iter()
+            .map(|(name, value)| {
+                let value = if SENSITIVE_HEADERS.contains(&name.as_str()) {
+                    REDACTED.to_string()
+                } else {
+                    String::from_utf8_lossy(value.as_bytes()).into_owned()
+                };
+
+                (name.to_string(), surrealdb::sql::Value::from(value))
+            })
+            .collect::<BTreeMap<_, _>>(),
+    )
+    .into()
+}
+
+fn redact_json(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                let key = key.to_lowercase();
+
+                if SENSITIVE_KEY_FRAGMENTS
+                    .iter()
+                    .any(|fragment| key.contains(fragment))
+                {
+                    *value = serde_json::Value::String(REDACTED.to_string());
+                } else {
+                    redact_json(value);
+                }
+            }
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(redact_json),
+        _ => {}
+    }
+}
+
+// Describes why the body is omitted from the capture, or returns None if it may be buffered and captured
+fn omitted_body(headers: &HeaderMap, body: &Body) -> Option<surrealdb::sql::Value> {
+    let streaming = headers
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| {
+            STREAMING_CONTENT_TYPES
+                .iter()
+                .any(|streaming| content_type.starts_with(streaming))
+        });
+
+    match body.size_hint().exact() {
+        Some(size) if !streaming && size <= DEBUG_CAPTURE_MAX_BODY_BYTES as u64 => None,
+        Some(size) if !streaming => Some(surrealdb::sql::Value::from(format!(
+            "[{size} byte body omitted, larger than capture limit]"
+        ))),
+        _ => Some(surrealdb::sql::Value::from("[streaming body omitted]")),
+    }
+}
+
+fn sanitized_body(body: &Bytes) -> surrealdb::sql::Value {
+    if body.is_empty() {
+        return surrealdb::sql::Value::None;
+    }
+
+    match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(mut value) => {
+            redact_json(&mut value);
+            surrealdb_value_from_json_value(value)
+        }
+        Err(_) => {
+            surrealdb::sql::Value::from(format!("[{} byte non-JSON body omitted]", body.len()))
+        }
+    }
+}
+
+// Records sanitized request and response payloads for accounts with debug capture enabled. Must be layered inside a
+// middleware that inserts the `Account` extension.
+pub(crate) async fn capture(
+    Extension(account): Extension<Account>,
+    req: Request,
+    next: Next,
+) -> Result<Response> {
+    // Standbys don't write, even to record captures of read-only requests
+    if !account.debug_capture_enabled() || standby::is_standby() {
+        return Ok(next.run(req).await);
+    }
+
+    let (parts, body) = req.into_parts();
+    let (body, captured_request_body) = match omitted_body(&parts.headers, &body) {
+        Some(omitted) => (body, omitted),
+        None => {
+            let body = to_bytes(body, DEBUG_CAPTURE_MAX_BODY_BYTES)
+                .await
+                .context("Failed to read request body for debug capture")?;
+            let captured = sanitized_body(&body);
+
+            (Body::from(body), captured)
+        }
+    };
+
+    let mut capture = surrealdb::sql::Object::default();
+    capture.insert("method".to_string(), parts.method.to_string().into());
+    capture.insert("path".to_string(), parts.uri.path().into());
+    capture.insert(
+        "request_headers".to_string(),
+        sanitized_headers(&parts.headers),
+    );
+    capture.insert("request_body".to_string(), captured_request_body);
+
+    let response = next.run(Request::from_parts(parts, body)).await;
+
+    let (parts, body) = response.into_parts();
+    let (body, captured_response_body) = match omitted_body(&parts.headers, &body) {
+        Some(omitted) => (body, omitted),
+        None => {
+            let body = to_bytes(body, DEBUG_CAPTURE_MAX_BODY_BYTES)
+                .await
+                .context("Failed to read response body for debug capture")?;
+            let captured = sanitized_body(&body);
+
+            (Body::from(body), captured)
+        }
+    };
+
+    capture.insert(
+        "response_status".to_string(),
+        i64::from(parts.status.as_u16()).into(),
+    );
+    capture.insert(
+        "response_headers".to_string(),
+        sanitized_headers(&parts.headers),
+    );
+    capture.insert("response_body".to_string(), captured_response_body);
+
+    // Failing to record a capture must never fail the request being debugged
+    if let Err(err) = record(&account, capture).await {
+        warn!(?err, "Failed to record debug capture");
+    }
+
+    Ok(Response::from_parts(parts, body))
+}
+
+#[instrument(err, skip_all, fields(account_id = account.id()))]
+async fn record(account: &Account, capture: surrealdb::sql::Object) -> Result<()> {
+    account
+        .resources_db()
+        .await?
+        .query(format!(
+            "DELETE debug_capture WHERE captured_at < time::now() - {DEBUG_CAPTURE_RETENTION};"
+        ))
+        .query("CREATE debug_capture CONTENT $capture RETURN NONE;")
+        .bind(("capture", capture))
+        .await
+        .context("Failed to submit query to record debug capture")?
+        .check_first_real_error()
+        .context("Failed to record debug capture")?;
+
+    Ok(())
+}
@@ -0,0 +1,601 @@
+// Structured description of what this crate mounts and how it is configured, for hosts embedding `router::router()` to
+// introspect and for operators of deployments mixing self-hosted and archodex.com builds. `log_banner` logs the same
+// description at startup.
+//
+// Axum routers can't be inspected once built, so `ROUTES` is listed by hand and must be kept in step with `router`.
+
+use serde::Serialize;
+use tracing::info;
+
+use crate::env::Env;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMode {
+    // Requests are not authenticated
+    None,
+    // Access tokens of dashboard users, issued by the configured identity provider or an additional OIDC provider,
+    // account API tokens created by them, or SAML session tokens
+    Dashboard,
+    // Report key values, or ingestion tokens exchanged for them at `/report/token`
+    ReportApiKey,
+    // Token configured with `STANDBY_PROMOTION_TOKEN`
+    StandbyPromotionToken,
+    // Token configured with `ADMIN_TOKEN`
+    AdminToken,
+    // Token of an account link between a self-hosted instance and archodex.com
+    LinkToken,
+    // Signature of a billing provider webhook
+    BillingWebhookSignature,
+    // Signature of a SAML response or assertion of an account's identity provider
+    SamlSignature,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Route {
+    pub method: &'static str,
+    // Path in axum's syntax, e.g. `/account/:account_id/query/:type`
+    pub path: &'static str,
+    pub auth: AuthMode,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Storage {
+    // SurrealDB engine of the accounts database, e.g. `rocksdb` or `ws`
+    pub engine: &'static str,
+    // Whether each account's resources are stored in a database of their own rather than the accounts database
+    pub per_account_databases: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Description {
+    pub version: &'static str,
+    // Cargo features this crate was built with
+    pub features: Vec<&'static str>,
+    // Modes requests may be authenticated with in this configuration
+    pub auth_modes: Vec<AuthMode>,
+    // Names of the additional OIDC providers accepted as dashboard credentials
+    pub oidc_providers: Vec<&'static str>,
+    pub storage: Storage,
+    pub standby: bool,
+    pub routes: &'static [Route],
+}
+
+const fn route(method: &'static str, path: &'static str, auth: AuthMode) -> Route {
+    Route { method, path, auth }
+}
+
+const ROUTES: &[Route] = &[
+    route(
+        "POST",
+        "/account/:account_id/resource/set_environments",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "POST",
+        "/account/:account_id/resource/tags",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "GET",
+        "/account/:account_id/environments",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "POST",
+        "/account/:account_id/environments",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "PUT",
+        "/account/:account_id/environment/:environment",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "DELETE",
+        "/account/:account_id/environment/:environment",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "POST",
+        "/account/:account_id/resource/archive",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "POST",
+        "/account/:account_id/resource/unarchive",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "POST",
+        "/account/:account_id/resources/delete",
+        AuthMode::Dashboard,
+    ),
+    route("GET", "/account/:account_id/resources", AuthMode::Dashboard),
+    route("POST", "/account/:account_id/import", AuthMode::Dashboard),
+    route("GET", "/account/:account_id/search", AuthMode::Dashboard),
+    route("POST", "/account/:account_id/graphql", AuthMode::Dashboard),
+    route(
+        "GET",
+        "/account/:account_id/query/:type",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "GET",
+        "/account/:account_id/principal_chain",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "GET",
+        "/account/:account_id/principal_chain/traverse",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "GET",
+        "/account/:account_id/type_paths",
+        AuthMode::Dashboard,
+    ),
+    route("GET", "/account/:account_id/export", AuthMode::Dashboard),
+    route(
+        "GET",
+        "/account/:account_id/export_encryption_key",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "PUT",
+        "/account/:account_id/export_encryption_key",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "DELETE",
+        "/account/:account_id/export_encryption_key",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "GET",
+        "/account/:account_id/report_api_keys",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "POST",
+        "/account/:account_id/report_api_keys",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "DELETE",
+        "/account/:account_id/report_api_key/:report_api_key_id",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "POST",
+        "/account/:account_id/report_api_key/:report_api_key_id/value",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "POST",
+        "/account/:account_id/report_api_key/:report_api_key_id/restore",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "POST",
+        "/account/:account_id/report_api_key/:report_api_key_id/rotate",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "GET",
+        "/account/:account_id/report_api_key_requests",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "POST",
+        "/account/:account_id/report_api_key_request/:report_api_key_request_id/approve",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "POST",
+        "/account/:account_id/report_api_key_request/:report_api_key_request_id/reject",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "POST",
+        "/account/:account_id/report_api_key_request/:report_api_key_request_id/value",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "GET",
+        "/account/:account_id/ingestion_pipeline",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "PUT",
+        "/account/:account_id/ingestion_pipeline",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "PUT",
+        "/account/:account_id/report_api_key_approval",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "GET",
+        "/account/:account_id/statements",
+        AuthMode::Dashboard,
+    ),
+    route("GET", "/account/:account_id/usage", AuthMode::Dashboard),
+    route("GET", "/account/:account_id/agents", AuthMode::Dashboard),
+    route(
+        "POST",
+        "/account/:account_id/agent/:agent_id/retire",
+        AuthMode::Dashboard,
+    ),
+    route("GET", "/account/:account_id/coverage", AuthMode::Dashboard),
+    route(
+        "GET",
+        "/account/:account_id/coverage/blind_spots",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "POST",
+        "/account/:account_id/operations",
+        AuthMode::Dashboard,
+    ),
+    route("GET", "/account/:account_id/health", AuthMode::Dashboard),
+    route("GET", "/account/:account_id/stream", AuthMode::Dashboard),
+    route("GET", "/account/:account_id/members", AuthMode::Dashboard),
+    route(
+        "PUT",
+        "/account/:account_id/member/:user_id",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "DELETE",
+        "/account/:account_id/member/:user_id",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "GET",
+        "/account/:account_id/invitations",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "POST",
+        "/account/:account_id/invitations",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "DELETE",
+        "/account/:account_id/invitation/:invitation_id",
+        AuthMode::Dashboard,
+    ),
+    route("GET", "/account/:account_id/tokens", AuthMode::Dashboard),
+    route("POST", "/account/:account_id/tokens", AuthMode::Dashboard),
+    route(
+        "DELETE",
+        "/account/:account_id/token/:token_id",
+        AuthMode::Dashboard,
+    ),
+    route("GET", "/account/:account_id/saml", AuthMode::Dashboard),
+    route("PUT", "/account/:account_id/saml", AuthMode::Dashboard),
+    route("DELETE", "/account/:account_id/saml", AuthMode::Dashboard),
+    route("GET", "/account/:account_id/webhooks", AuthMode::Dashboard),
+    route("POST", "/account/:account_id/webhooks", AuthMode::Dashboard),
+    route(
+        "PUT",
+        "/account/:account_id/webhook/:webhook_id",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "DELETE",
+        "/account/:account_id/webhook/:webhook_id",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "PUT",
+        "/account/:account_id/default_environment",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "PUT",
+        "/account/:account_id/resource_staleness",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "PUT",
+        "/account/:account_id/dead_lettering",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "GET",
+        "/account/:account_id/dead_letter_reports",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "DELETE",
+        "/account/:account_id/dead_letter_reports",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "GET",
+        "/account/:account_id/dead_letter_report/:dead_letter_report_id",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "DELETE",
+        "/account/:account_id/dead_letter_report/:dead_letter_report_id",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "POST",
+        "/account/:account_id/dead_letter_report/:dead_letter_report_id/retry",
+        AuthMode::Dashboard,
+    ),
+    route("GET", "/account/:account_id/audit_log", AuthMode::Dashboard),
+    route(
+        "GET",
+        "/account/:account_id/audit_log_retention",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "PUT",
+        "/account/:account_id/audit_log_retention",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "GET",
+        "/account/:account_id/event_retention",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "PUT",
+        "/account/:account_id/event_retention",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "GET",
+        "/account/:account_id/integrity_checks",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "POST",
+        "/account/:account_id/integrity_checks",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "GET",
+        "/account/:account_id/integrity_check/:integrity_check_id",
+        AuthMode::Dashboard,
+    ),
+    route("PATCH", "/account/:account_id", AuthMode::Dashboard),
+    route("DELETE", "/account/:account_id", AuthMode::Dashboard),
+    #[cfg(feature = "archodex-com")]
+    route(
+        "GET",
+        "/account/:account_id/account_links",
+        AuthMode::Dashboard,
+    ),
+    #[cfg(feature = "archodex-com")]
+    route(
+        "POST",
+        "/account/:account_id/account_links",
+        AuthMode::Dashboard,
+    ),
+    #[cfg(feature = "archodex-com")]
+    route(
+        "DELETE",
+        "/account/:account_id/account_link/:account_link_id",
+        AuthMode::Dashboard,
+    ),
+    #[cfg(feature = "archodex-com")]
+    route(
+        "GET",
+        "/account/:account_id/account_link/:account_link_id/federated/:linked_account_id/*path",
+        AuthMode::Dashboard,
+    ),
+    #[cfg(feature = "archodex-com")]
+    route(
+        "GET",
+        "/account/:account_id/linked_accounts",
+        AuthMode::Dashboard,
+    ),
+    #[cfg(feature = "archodex-com")]
+    route(
+        "POST",
+        "/account/:account_id/linked_accounts",
+        AuthMode::Dashboard,
+    ),
+    #[cfg(feature = "archodex-com")]
+    route(
+        "DELETE",
+        "/account/:account_id/linked_account/:linked_account_id",
+        AuthMode::Dashboard,
+    ),
+    #[cfg(feature = "archodex-com")]
+    route(
+        "POST",
+        "/account/:account_id/resource/share",
+        AuthMode::Dashboard,
+    ),
+    #[cfg(feature = "archodex-com")]
+    route("GET", "/account/:account_id/status", AuthMode::Dashboard),
+    route("GET", "/accounts", AuthMode::Dashboard),
+    route("POST", "/accounts", AuthMode::Dashboard),
+    route("GET", "/search", AuthMode::Dashboard),
+    route("POST", "/invitations/accept", AuthMode::Dashboard),
+    route("GET", "/jobs/:job_id/events", AuthMode::Dashboard),
+    route("GET", "/notifications", AuthMode::Dashboard),
+    route("GET", "/notifications/unread_count", AuthMode::Dashboard),
+    route("POST", "/notifications/read", AuthMode::Dashboard),
+    route(
+        "POST",
+        "/notification/:notification_id/read",
+        AuthMode::Dashboard,
+    ),
+    route(
+        "POST",
+        "/notification/:notification_id/unread",
+        AuthMode::Dashboard,
+    ),
+    route("GET", "/health", AuthMode::None),
+    route("GET", "/health/ready", AuthMode::None),
+    route("GET", "/report/schema/:version", AuthMode::None),
+    route("GET", "/types.d.ts", AuthMode::None),
+    route("GET", "/openapi.json", AuthMode::None),
+    route("GET", "/docs", AuthMode::None),
+    route("GET", "/docs/swagger-ui.css", AuthMode::None),
+    route("GET", "/docs/swagger-ui-bundle.js", AuthMode::None),
+    route("GET", "/graphql/schema.graphql", AuthMode::None),
+    route(
+        "POST",
+        crate::standby::PROMOTE_PATH,
+        AuthMode::StandbyPromotionToken,
+    ),
+    route("GET", "/metrics", AuthMode::AdminToken),
+    route("GET", "/admin/accounts", AuthMode::AdminToken),
+    route("GET", crate::dr::BACKUPS_PATH, AuthMode::AdminToken),
+    route("POST", crate::dr::BACKUPS_PATH, AuthMode::AdminToken),
+    route("GET", "/admin/dr/readiness", AuthMode::AdminToken),
+    route("POST", crate::dr::DRILLS_PATH, AuthMode::AdminToken),
+    route("GET", "/admin/dr/job/:job_id", AuthMode::AdminToken),
+    #[cfg(feature = "archodex-com")]
+    route(
+        "GET",
+        "/admin/account/:account_id/provisioning",
+        AuthMode::AdminToken,
+    ),
+    route(
+        "POST",
+        "/admin/account/:account_id/migrate",
+        AuthMode::AdminToken,
+    ),
+    route(
+        "POST",
+        "/admin/account/:account_id/report_api_key/:report_api_key_id/revoke",
+        AuthMode::AdminToken,
+    ),
+    route(
+        "POST",
+        "/admin/account/:account_id/bundle",
+        AuthMode::AdminToken,
+    ),
+    #[cfg(feature = "archodex-com")]
+    route("POST", "/link/sync", AuthMode::LinkToken),
+    #[cfg(not(feature = "archodex-com"))]
+    route("GET", "/oidc/providers", AuthMode::None),
+    #[cfg(not(feature = "archodex-com"))]
+    route("POST", "/oidc/:provider/token", AuthMode::None),
+    route("GET", "/saml/:account_id/metadata", AuthMode::None),
+    route("GET", "/saml/:account_id/login", AuthMode::None),
+    route("POST", "/saml/:account_id/acs", AuthMode::SamlSignature),
+    route("POST", "/saml/:account_id/session", AuthMode::None),
+    #[cfg(feature = "archodex-com")]
+    route(
+        "POST",
+        "/billing/webhook",
+        AuthMode::BillingWebhookSignature,
+    ),
+    route("POST", "/report", AuthMode::ReportApiKey),
+    route("POST", "/report/batch", AuthMode::ReportApiKey),
+    route("POST", "/report/validate", AuthMode::ReportApiKey),
+    route("POST", "/report/token", AuthMode::ReportApiKey),
+    route("POST", "/report/agent", AuthMode::ReportApiKey),
+    route("POST", "/report/aws_config", AuthMode::ReportApiKey),
+];
+
+fn features() -> Vec<&'static str> {
+    [
+        ("archodex-com", cfg!(feature = "archodex-com")),
+        ("fixtures", cfg!(feature = "fixtures")),
+        ("live-query", cfg!(feature = "live-query")),
+        ("rocksdb", cfg!(feature = "rocksdb")),
+    ]
+    .into_iter()
+    .filter_map(|(feature, enabled)| enabled.then_some(feature))
+    .collect()
+}
+
+fn auth_modes() -> Vec<AuthMode> {
+    let mut auth_modes = vec![AuthMode::None, AuthMode::Dashboard, AuthMode::ReportApiKey];
+
+    if Env::standby_promotion_token().is_some() {
+        auth_modes.push(AuthMode::StandbyPromotionToken);
+    }
+
+    if Env::admin_token().is_some() {
+        auth_modes.push(AuthMode::AdminToken);
+    }
+
+    if Env::saml_base_url().is_some() {
+        auth_modes.push(AuthMode::SamlSignature);
+    }
+
+    #[cfg(feature = "archodex-com")]
+    {
+        auth_modes.push(AuthMode::LinkToken);
+
+        if Env::billing_webhook_secret().is_some() {
+            auth_modes.push(AuthMode::BillingWebhookSignature);
+        }
+    }
+
+    auth_modes
+}
+
+fn oidc_providers() -> Vec<&'static str> {
+    #[cfg(not(feature = "archodex-com"))]
+    return Env::oidc_providers()
+        .iter()
+        .map(|provider| provider.name.as_str())
+        .collect();
+
+    #[cfg(feature = "archodex-com")]
+    Vec::new()
+}
+
+fn storage() -> Storage {
+    let url = Env::accounts_surrealdb_url();
+
+    Storage {
+        engine: url.split_once("://").map_or(url, |(engine, _)| engine),
+        per_account_databases: cfg!(feature = "archodex-com"),
+    }
+}
+
+/// Describes the routes mounted by `router::router()`, the features this crate was built with, and how it is
+/// configured.
+///
+/// # Panics
+///
+/// Will panic if the environment is not configured correctly.
+#[must_use]
+pub fn describe() -> Description {
+    Description {
+        version: env!("CARGO_PKG_VERSION"),
+        features: features(),
+        auth_modes: auth_modes(),
+        oidc_providers: oidc_providers(),
+        storage: storage(),
+        standby: Env::standby(),
+        routes: ROUTES,
+    }
+}
+
+/// Logs a summary of `describe()`, e.g. at startup.
+///
+/// # Panics
+///
+/// Will panic if the environment is not configured correctly.
+pub fn log_banner() {
+    let description = describe();
+
+    info!(
+        version = description.version,
+        features = ?description.features,
+        auth_modes = ?description.auth_modes,
+        oidc_providers = ?description.oidc_providers,
+        storage_engine = description.storage.engine,
+        per_account_databases = description.storage.per_account_databases,
+        standby = description.standby,
+        routes = description.routes.len(),
+        "Archodex backend"
+    );
+}
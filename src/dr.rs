@@ -0,0 +1,520 @@
+// Disaster recovery runbook steps automated as operator endpoints, so they can be rehearsed in drills rather than
+// scripted by hand during an incident. The endpoints are served under `/admin`, see `admin`.
+//
+// Backups are SurrealQL exports of the accounts database and of each account's resources database, written to
+// `DR_BACKUP_DIRECTORY` as `accounts/<timestamp>.surql` and `account/<account ID>/<timestamp>.surql`. Operators mount
+// storage that is replicated to another region there. SurrealDB only exports databases through its local engines and
+// HTTP connections. Exports snapshot the database they export when they start, so database connections are released
+// while the export is written, rather than blocking other requests to the embedded RocksDB database. Backups and drills
+// never write to the databases, so standby instances may run them too. Backups are never deleted, so their retention is
+// left to the mounted storage, e.g. with lifecycle rules expiring backups older than the recovery window.
+//
+// Backups and drills run as jobs whose progress operators poll from `GET /admin/dr/job/:job_id`. Each instance runs one
+// at a time.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use axum::{Json, extract::Path as PathParams};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use futures::StreamExt as _;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt as _;
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+use archodex_error::{
+    anyhow::{self, Context as _, ensure},
+    conflict, not_found,
+};
+
+use crate::{
+    Result,
+    account::Account,
+    accounts::list_served_accounts,
+    db::{DBConnection, QueryCheckFirstRealError as _, accounts_db},
+    env::Env,
+    job::{self, Job, JobProgress},
+};
+
+pub(crate) const BACKUPS_PATH: &str = "/admin/dr/backups";
+pub(crate) const DRILLS_PATH: &str = "/admin/dr/drills";
+
+const BACKUP_EXTENSION: &str = "surql";
+const BACKUP_TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+static JOB_RUNNING: AtomicBool = AtomicBool::new(false);
+
+fn backup_directory() -> Result<&'static Path> {
+    let Some(directory) = Env::dr_backup_directory() else {
+        conflict!("Backups are disabled, set DR_BACKUP_DIRECTORY to enable them");
+    };
+
+    Ok(directory)
+}
+
+// A database that is backed up: the accounts database, or the resources database of an account
+enum Database {
+    Accounts,
+    Resources(Box<Account>),
+}
+
+impl Database {
+    fn account_id(&self) -> Option<&str> {
+        match self {
+            Database::Accounts => None,
+            Database::Resources(account) => Some(account.id()),
+        }
+    }
+
+    fn name(&self) -> String {
+        match self {
+            Database::Accounts => "accounts database".to_string(),
+            Database::Resources(account) => format!("account {}", account.id()),
+        }
+    }
+
+    fn backup_directory(&self, directory: &Path) -> PathBuf {
+        match self {
+            Database::Accounts => directory.join("accounts"),
+            Database::Resources(account) => directory.join("account").join(account.id()),
+        }
+    }
+
+    async fn db(&self) -> Result<DBConnection> {
+        Ok(match self {
+            Database::Accounts => accounts_db().await?,
+            Database::Resources(account) => account.resources_db().await?,
+        })
+    }
+
+    async fn probe(&self) -> Result<()> {
+        self.db()
+            .await?
+            .query("RETURN true")
+            .timeout()
+            .await?
+            .check_first_real_error()?;
+
+        Ok(())
+    }
+
+    // Exports are written to a partial file first, so incomplete backups are never mistaken for the latest backup.
+    // Partial files of failed exports are removed.
+    async fn back_up(&self, directory: &Path) -> Result<PathBuf> {
+        let directory = self.backup_directory(directory);
+        tokio::fs::create_dir_all(&directory)
+            .await
+            .with_context(|| {
+                format!("Failed to create backup directory {}", directory.display())
+            })?;
+
+        let backup = directory.join(format!(
+            "{}.{BACKUP_EXTENSION}",
+            Utc::now().format(BACKUP_TIMESTAMP_FORMAT)
+        ));
+        let partial = backup.with_extension(format!("{BACKUP_EXTENSION}.partial"));
+
+        if let Err(err) = self.export(&partial).await {
+            match tokio::fs::remove_file(&partial).await {
+                Ok(()) => {}
+                Err(remove_err) if remove_err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(remove_err) => warn!(
+                    err = ?remove_err,
+                    partial = %partial.display(),
+                    "Failed to remove partial backup"
+                ),
+            }
+
+            return Err(err);
+        }
+
+        tokio::fs::rename(&partial, &backup)
+            .await
+            .with_context(|| format!("Failed to finish backup {}", backup.display()))?;
+
+        Ok(backup)
+    }
+
+    async fn export(&self, partial: &Path) -> Result<()> {
+        // The connection is dropped once the export has started
+        let mut export = self
+            .db()
+            .await?
+            .export(())
+            .await
+            .with_context(|| format!("Failed to export {}", self.name()))?;
+
+        let mut file = tokio::fs::File::create(partial)
+            .await
+            .with_context(|| format!("Failed to create backup {}", partial.display()))?;
+
+        while let Some(chunk) = export.next().await {
+            let chunk = chunk.with_context(|| format!("Failed to export {}", self.name()))?;
+
+            file.write_all(&chunk)
+                .await
+                .with_context(|| format!("Failed to write backup {}", partial.display()))?;
+        }
+
+        file.sync_all()
+            .await
+            .with_context(|| format!("Failed to write backup {}", partial.display()))?;
+
+        Ok(())
+    }
+
+    // Backups are named by the time they were taken
+    async fn latest_backup(&self, directory: &Path) -> Result<Option<Backup>> {
+        let directory = self.backup_directory(directory);
+
+        let mut entries = match tokio::fs::read_dir(&directory).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(anyhow::Error::new(err)
+                    .context(format!(
+                        "Failed to list backup directory {}",
+                        directory.display()
+                    ))
+                    .into());
+            }
+        };
+
+        let mut latest: Option<Backup> = None;
+
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .with_context(|| format!("Failed to list backup directory {}", directory.display()))?
+        {
+            let path = entry.path();
+
+            if path.extension().and_then(|extension| extension.to_str()) != Some(BACKUP_EXTENSION) {
+                continue;
+            }
+
+            let Some(taken_at) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| NaiveDateTime::parse_from_str(stem, BACKUP_TIMESTAMP_FORMAT).ok())
+                .map(|taken_at| taken_at.and_utc())
+            else {
+                continue;
+            };
+
+            if latest
+                .as_ref()
+                .is_none_or(|latest| taken_at > latest.taken_at)
+            {
+                let size_bytes = tokio::fs::metadata(&path)
+                    .await
+                    .with_context(|| format!("Failed to read backup {}", path.display()))?
+                    .len();
+
+                latest = Some(Backup {
+                    taken_at,
+                    size_bytes,
+                });
+            }
+        }
+
+        Ok(latest)
+    }
+
+    async fn backup_status(&self, directory: &Path) -> Result<BackupStatus> {
+        let latest_backup = self.latest_backup(directory).await?;
+
+        let fresh = latest_backup.as_ref().is_some_and(|backup| {
+            (Utc::now() - backup.taken_at)
+                .to_std()
+                .is_ok_and(|age| age <= Env::dr_backup_max_age())
+        });
+
+        Ok(BackupStatus {
+            account_id: self.account_id().map(str::to_owned),
+            latest_backup,
+            fresh,
+        })
+    }
+}
+
+async fn databases() -> Result<Vec<Database>> {
+    Ok(std::iter::once(Database::Accounts)
+        .chain(
+            list_served_accounts()
+                .await?
+                .into_iter()
+                .map(|account| Database::Resources(Box::new(account))),
+        )
+        .collect())
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Backup {
+    taken_at: DateTime<Utc>,
+    size_bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct BackupStatus {
+    // Account whose resources database was backed up, or none for the accounts database
+    #[serde(skip_serializing_if = "Option::is_none")]
+    account_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latest_backup: Option<Backup>,
+    // Whether the latest backup was taken within `DR_BACKUP_MAX_AGE_HOURS`
+    fresh: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ListBackupsResponse {
+    databases: Vec<BackupStatus>,
+}
+
+// Lists the latest backup of the accounts database and of each account's resources database
+#[instrument(err, skip_all)]
+pub(crate) async fn list_backups() -> Result<Json<ListBackupsResponse>> {
+    let directory = backup_directory()?;
+
+    let mut statuses = Vec::new();
+    for database in databases().await? {
+        statuses.push(database.backup_status(directory).await?);
+    }
+
+    Ok(Json(ListBackupsResponse {
+        databases: statuses,
+    }))
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct StartJobResponse {
+    job_id: Uuid,
+}
+
+// Runs a job in the background, unless another backup or drill is already running on this instance
+fn start_job<F, Fut>(name: &'static str, run: F) -> Result<Json<StartJobResponse>>
+where
+    F: FnOnce(Job) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<()>> + Send,
+{
+    if JOB_RUNNING.swap(true, Ordering::AcqRel) {
+        conflict!("Another backup or drill is already running on this instance");
+    }
+
+    let (job_id, job) = Job::operator();
+
+    tokio::spawn(async move {
+        let result = run(job.clone()).await;
+
+        job.finish(&result, None, None).await;
+
+        match &result {
+            Ok(()) => info!(%job_id, job = name, "Disaster recovery job succeeded"),
+            Err(err) => warn!(%job_id, job = name, ?err, "Disaster recovery job failed"),
+        }
+
+        JOB_RUNNING.store(false, Ordering::Release);
+    });
+
+    Ok(Json(StartJobResponse { job_id }))
+}
+
+// Maps the progress of the `index`th of `count` steps into the `start..end` percent range of a job
+fn percent(start: u8, end: u8, index: usize, count: usize) -> u8 {
+    let progress = usize::from(end - start) * index / count.max(1);
+
+    start.saturating_add(u8::try_from(progress).unwrap_or(end - start))
+}
+
+async fn back_up_databases(
+    databases: &[Database],
+    directory: &Path,
+    job: &Job,
+    (start, end): (u8, u8),
+) -> Result<Vec<PathBuf>> {
+    let mut backups = Vec::with_capacity(databases.len());
+
+    for (index, database) in databases.iter().enumerate() {
+        job.step(
+            percent(start, end, index, databases.len()),
+            &format!("Backing up {}", database.name()),
+        );
+
+        backups.push(database.back_up(directory).await?);
+    }
+
+    Ok(backups)
+}
+
+// Starts backing up the accounts database and every account's resources database
+#[instrument(err, skip_all)]
+pub(crate) async fn start_backup() -> Result<Json<StartJobResponse>> {
+    let directory = backup_directory()?;
+
+    start_job("backup", move |job| async move {
+        let databases = databases().await?;
+        back_up_databases(&databases, directory, &job, (0, 100)).await?;
+
+        Ok(())
+    })
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct AccountRestoreReadiness {
+    account_id: String,
+    ready: bool,
+    // Why the account can't be restored, if it isn't ready
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    issues: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latest_backup: Option<Backup>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct RestoreReadinessResponse {
+    // Whether the accounts database and every account are ready to be restored
+    ready: bool,
+    // Why the accounts database can't be restored, if it isn't ready
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    accounts_database_issues: Vec<String>,
+    accounts: Vec<AccountRestoreReadiness>,
+}
+
+// Databases are ready to be restored once they are reachable and have a fresh backup to restore from
+async fn database_issues(
+    database: &Database,
+    directory: &Path,
+) -> Result<(Vec<String>, Option<Backup>)> {
+    let mut issues = Vec::new();
+
+    let reachable = database.probe().await;
+    if let Err(err) = &reachable {
+        warn!(?err, database = database.name(), "Database is unreachable");
+    }
+    if reachable.is_err() {
+        issues.push("Database is unreachable".to_string());
+    }
+
+    let status = database.backup_status(directory).await?;
+    match &status.latest_backup {
+        None => issues.push("Database has no backup".to_string()),
+        Some(backup) if !status.fresh => issues.push(format!(
+            "Latest backup was taken at {}, longer than DR_BACKUP_MAX_AGE_HOURS ago",
+            backup.taken_at
+        )),
+        Some(_) => {}
+    }
+
+    Ok((issues, status.latest_backup))
+}
+
+async fn restore_readiness(directory: &Path) -> Result<RestoreReadinessResponse> {
+    let (accounts_database_issues, _) = database_issues(&Database::Accounts, directory).await?;
+
+    let mut accounts = Vec::new();
+    for account in list_served_accounts().await? {
+        let account_id = account.id().to_string();
+        let (issues, latest_backup) =
+            database_issues(&Database::Resources(Box::new(account)), directory).await?;
+
+        accounts.push(AccountRestoreReadiness {
+            account_id,
+            ready: issues.is_empty(),
+            issues,
+            latest_backup,
+        });
+    }
+
+    Ok(RestoreReadinessResponse {
+        ready: accounts_database_issues.is_empty() && accounts.iter().all(|account| account.ready),
+        accounts_database_issues,
+        accounts,
+    })
+}
+
+// Reports whether the accounts database and each account could be restored from their backups
+#[instrument(err, skip_all)]
+pub(crate) async fn get_restore_readiness() -> Result<Json<RestoreReadinessResponse>> {
+    Ok(Json(restore_readiness(backup_directory()?).await?))
+}
+
+// Backups are parsed rather than restored, so drills don't need a database to restore into
+async fn verify_backup(backup: PathBuf) -> Result<()> {
+    let contents = tokio::fs::read_to_string(&backup)
+        .await
+        .with_context(|| format!("Failed to read backup {}", backup.display()))?;
+
+    tokio::task::spawn_blocking(move || {
+        surrealdb::sql::parse(&contents)
+            .with_context(|| format!("Backup {} is not valid SurrealQL", backup.display()))?;
+
+        anyhow::Ok(())
+    })
+    .await
+    .context("Failed to verify backup")??;
+
+    Ok(())
+}
+
+async fn drill(directory: &Path, job: &Job) -> Result<()> {
+    job.step(0, "Listing accounts");
+    let databases = databases().await?;
+
+    let backups = back_up_databases(&databases, directory, job, (0, 60)).await?;
+
+    for (index, (database, backup)) in databases.iter().zip(backups).enumerate() {
+        job.step(
+            percent(60, 90, index, databases.len()),
+            &format!("Verifying backup of {}", database.name()),
+        );
+
+        verify_backup(backup).await?;
+    }
+
+    job.step(90, "Checking restore readiness");
+    let readiness = restore_readiness(directory).await?;
+
+    let unready = readiness
+        .accounts
+        .iter()
+        .filter(|account| !account.ready)
+        .map(|account| account.account_id.as_str())
+        .collect::<Vec<_>>();
+
+    ensure!(
+        readiness.accounts_database_issues.is_empty(),
+        "Accounts database is not ready to be restored: {}",
+        readiness.accounts_database_issues.join(", ")
+    );
+    ensure!(
+        unready.is_empty(),
+        "Accounts are not ready to be restored: {}",
+        unready.join(", ")
+    );
+
+    Ok(())
+}
+
+// Starts a drill, which backs up every database, verifies that each backup parses as SurrealQL, and checks that every
+// database is ready to be restored
+#[instrument(err, skip_all)]
+pub(crate) async fn start_drill() -> Result<Json<StartJobResponse>> {
+    let directory = backup_directory()?;
+
+    start_job(
+        "drill",
+        move |job| async move { drill(directory, &job).await },
+    )
+}
+
+#[instrument(err)]
+pub(crate) async fn get_job(PathParams(job_id): PathParams<Uuid>) -> Result<Json<JobProgress>> {
+    let Some(progress) = job::operator_job_progress(job_id) else {
+        not_found!("Job not found");
+    };
+
+    Ok(Json(progress))
+}
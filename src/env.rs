@@ -1,19 +1,71 @@
-use std::sync::LazyLock;
+use std::{ops::RangeInclusive, path::PathBuf, sync::LazyLock, time::Duration};
 
 #[cfg(not(feature = "archodex-com"))]
 use tokio::sync::RwLock;
 
+// How dashboard access tokens are verified and mapped to users. Defaults to the Archodex Cognito user pool, but
+// enterprises federating their IdPs through their own Cognito user pool, or using another OIDC provider directly, may
+// configure which claims identify and authorize users.
+pub(crate) struct AuthClaims {
+    pub(crate) issuer: String,
+    pub(crate) jwks_url: String,
+    // Claim holding the stable user ID. Values that are not UUIDs are mapped to deterministic UUIDs.
+    pub(crate) user_id_claim: String,
+    // Claims that must have exactly these string values, e.g. Cognito's `token_use` or a tenant ID claim
+    pub(crate) required_claims: Vec<(String, String)>,
+    pub(crate) groups_claim: String,
+    // Claim holding the user's email, which must match the email of invitations they accept. Cognito only adds it to
+    // access tokens with a pre token generation trigger.
+    pub(crate) email_claim: String,
+    // Group that users must be a member of, if any
+    pub(crate) required_group: Option<String>,
+}
+
 pub struct Env {
     port: u16,
     archodex_domain: String,
+    allowed_origins: Vec<crate::router::AllowedOrigin>,
+    cors_max_age: Duration,
+    shutdown_drain_timeout: Duration,
+    tls_paths: Option<(PathBuf, PathBuf)>,
+    dashboard_url: String,
+    saml_base_url: Option<String>,
     accounts_surrealdb_url: String,
     #[cfg(not(feature = "archodex-com"))]
     surrealdb_url: String,
     surrealdb_creds: Option<surrealdb::opt::auth::Root<'static>>,
+    surrealdb_slow_query_threshold: Duration,
+    surrealdb_query_timeout: Duration,
+    report_max_resource_tree_depth: usize,
+    report_max_resource_tree_nodes: usize,
+    report_max_events: usize,
+    report_max_edges: usize,
+    report_max_attribute_bytes: usize,
+    report_max_statements: usize,
+    report_rate_limit: Option<(f64, f64)>,
+    attribute_document_min_bytes: Option<usize>,
+    step_up_max_auth_age: Duration,
+    resources_db_account_id_required: bool,
+    standby: bool,
+    standby_promotion_token: Option<String>,
+    dr_backup_directory: Option<PathBuf>,
+    dr_backup_max_age: Duration,
+    admin_token: Option<String>,
     #[cfg(feature = "archodex-com")]
     endpoint: String,
-    cognito_user_pool_id: String,
-    cognito_client_id: String,
+    #[cfg(feature = "archodex-com")]
+    billing_webhook_secret: Option<String>,
+    #[cfg(feature = "archodex-com")]
+    service_data_locations: Vec<crate::provisioning::ServiceDataLocation>,
+    ingestion_shard_range: Option<RangeInclusive<u16>>,
+    ingestion_shards: Vec<(RangeInclusive<u16>, String)>,
+    auth_claims: AuthClaims,
+    #[cfg(not(feature = "archodex-com"))]
+    oidc_providers: Vec<crate::oidc::OidcProvider>,
+    #[cfg(not(feature = "archodex-com"))]
+    link: Option<(String, String)>,
+    #[cfg(not(feature = "archodex-com"))]
+    link_federation_endpoint: Option<String>,
     #[cfg(not(feature = "archodex-com"))]
     api_private_key: RwLock<Option<aes_gcm::Key<aes_gcm::Aes128Gcm>>>,
 }
@@ -38,6 +90,58 @@ impl Env {
 
             let archodex_domain = env_with_default_for_empty("ARCHODEX_DOMAIN", "archodex.com");
 
+            let shutdown_drain_timeout = Duration::from_secs(
+                env_with_default_for_empty("SHUTDOWN_DRAIN_TIMEOUT_SECONDS", "30")
+                    .parse::<u64>()
+                    .expect("Failed to parse SHUTDOWN_DRAIN_TIMEOUT_SECONDS env var as u64"),
+            );
+
+            let tls_paths = match (
+                std::env::var("TLS_CERT_PATH")
+                    .ok()
+                    .filter(|path| !path.is_empty()),
+                std::env::var("TLS_KEY_PATH")
+                    .ok()
+                    .filter(|path| !path.is_empty()),
+            ) {
+                (Some(cert_path), Some(key_path)) => {
+                    Some((PathBuf::from(cert_path), PathBuf::from(key_path)))
+                }
+                (None, None) => None,
+                _ => panic!("TLS_CERT_PATH and TLS_KEY_PATH env vars must be set together"),
+            };
+
+            let allowed_origins = env_with_default_for_empty(
+                "ALLOWED_ORIGINS",
+                &format!("https://app.{archodex_domain},http://localhost:5173"),
+            )
+            .split(',')
+            .filter(|origin| !origin.trim().is_empty())
+            .map(|origin| {
+                crate::router::parse_allowed_origin(origin).unwrap_or_else(|| {
+                    panic!("Failed to parse ALLOWED_ORIGINS env var origin {origin:?} as <scheme>://<host>[:<port>], optionally with a `*.` wildcard subdomain prefix")
+                })
+            })
+            .collect::<Vec<_>>();
+
+            let dashboard_url = env_with_default_for_empty(
+                "DASHBOARD_URL",
+                &format!("https://app.{archodex_domain}"),
+            )
+            .trim_end_matches('/')
+            .to_string();
+
+            let saml_base_url = std::env::var("SAML_BASE_URL")
+                .ok()
+                .filter(|url| !url.is_empty())
+                .map(|url| url.trim_end_matches('/').to_string());
+
+            let cors_max_age = Duration::from_secs(
+                env_with_default_for_empty("CORS_MAX_AGE_SECONDS", "7200")
+                    .parse::<u64>()
+                    .expect("Failed to parse CORS_MAX_AGE_SECONDS env var as u64"),
+            );
+
             #[cfg(not(feature = "archodex-com"))]
             let (_, surrealdb_url) = (
                 std::env::var("ACCOUNTS_SURREALDB_URL").expect_err(
@@ -82,9 +186,245 @@ impl Env {
                 ),
             };
 
+            let surrealdb_slow_query_threshold = Duration::from_millis(
+                env_with_default_for_empty("SURREALDB_SLOW_QUERY_THRESHOLD_MS", "1000")
+                    .parse::<u64>()
+                    .expect("Failed to parse SURREALDB_SLOW_QUERY_THRESHOLD_MS env var as u64"),
+            );
+
+            let dr_backup_max_age = Duration::from_secs(
+                env_with_default_for_empty("DR_BACKUP_MAX_AGE_HOURS", "24")
+                    .parse::<u64>()
+                    .expect("Failed to parse DR_BACKUP_MAX_AGE_HOURS env var as u64")
+                    * 60
+                    * 60,
+            );
+
+            let surrealdb_query_timeout = Duration::from_secs(
+                env_with_default_for_empty("SURREALDB_QUERY_TIMEOUT_SECONDS", "60")
+                    .parse::<u64>()
+                    .expect("Failed to parse SURREALDB_QUERY_TIMEOUT_SECONDS env var as u64"),
+            );
+
+            let report_max_resource_tree_depth =
+                env_with_default_for_empty("REPORT_MAX_RESOURCE_TREE_DEPTH", "32")
+                    .parse::<usize>()
+                    .expect("Failed to parse REPORT_MAX_RESOURCE_TREE_DEPTH env var as usize");
+
+            let report_max_resource_tree_nodes =
+                env_with_default_for_empty("REPORT_MAX_RESOURCE_TREE_NODES", "100000")
+                    .parse::<usize>()
+                    .expect("Failed to parse REPORT_MAX_RESOURCE_TREE_NODES env var as usize");
+
+            let report_max_events = env_with_default_for_empty("REPORT_MAX_EVENTS", "10000")
+                .parse::<usize>()
+                .expect("Failed to parse REPORT_MAX_EVENTS env var as usize");
+
+            let report_max_edges = env_with_default_for_empty("REPORT_MAX_EDGES", "100000")
+                .parse::<usize>()
+                .expect("Failed to parse REPORT_MAX_EDGES env var as usize");
+
+            let report_max_attribute_bytes =
+                env_with_default_for_empty("REPORT_MAX_ATTRIBUTE_BYTES", "262144")
+                    .parse::<usize>()
+                    .expect("Failed to parse REPORT_MAX_ATTRIBUTE_BYTES env var as usize");
+
+            let report_max_statements =
+                env_with_default_for_empty("REPORT_MAX_STATEMENTS", "500000")
+                    .parse::<usize>()
+                    .expect("Failed to parse REPORT_MAX_STATEMENTS env var as usize");
+
+            let report_rate_limit_per_second =
+                env_with_default_for_empty("REPORT_RATE_LIMIT_PER_SECOND", "10")
+                    .parse::<f64>()
+                    .expect("Failed to parse REPORT_RATE_LIMIT_PER_SECOND env var as f64");
+            let report_rate_limit_burst =
+                env_with_default_for_empty("REPORT_RATE_LIMIT_BURST", "60")
+                    .parse::<f64>()
+                    .expect("Failed to parse REPORT_RATE_LIMIT_BURST env var as f64");
+
+            assert!(
+                report_rate_limit_per_second >= 0.0 && report_rate_limit_burst >= 1.0,
+                "REPORT_RATE_LIMIT_PER_SECOND must not be negative and REPORT_RATE_LIMIT_BURST must be at least 1"
+            );
+
+            let report_rate_limit = (report_rate_limit_per_second > 0.0)
+                .then_some((report_rate_limit_per_second, report_rate_limit_burst));
+
+            let attribute_document_min_bytes =
+                env_with_default_for_empty("ATTRIBUTE_DOCUMENT_MIN_BYTES", "0")
+                    .parse::<usize>()
+                    .expect("Failed to parse ATTRIBUTE_DOCUMENT_MIN_BYTES env var as usize");
+            let attribute_document_min_bytes =
+                (attribute_document_min_bytes > 0).then_some(attribute_document_min_bytes);
+
+            let step_up_max_auth_age = Duration::from_secs(
+                env_with_default_for_empty("STEP_UP_MAX_AUTH_AGE_SECONDS", "900")
+                    .parse::<u64>()
+                    .expect("Failed to parse STEP_UP_MAX_AUTH_AGE_SECONDS env var as u64"),
+            );
+
+            let resources_db_account_id_required =
+                env_with_default_for_empty("RESOURCES_DB_ACCOUNT_ID_REQUIRED", "false")
+                    .parse::<bool>()
+                    .expect("Failed to parse RESOURCES_DB_ACCOUNT_ID_REQUIRED env var as bool");
+
+            let standby = env_with_default_for_empty("STANDBY", "false")
+                .parse::<bool>()
+                .expect("Failed to parse STANDBY env var as bool");
+
+            #[cfg(not(feature = "archodex-com"))]
+            let link = match (
+                std::env::var("ARCHODEX_LINK_ENDPOINT")
+                    .ok()
+                    .filter(|endpoint| !endpoint.is_empty()),
+                std::env::var("ARCHODEX_LINK_TOKEN")
+                    .ok()
+                    .filter(|token| !token.is_empty()),
+            ) {
+                (Some(link_endpoint), Some(link_token)) => Some((link_endpoint, link_token)),
+                (None, None) => None,
+                _ => panic!(
+                    "Both ARCHODEX_LINK_ENDPOINT and ARCHODEX_LINK_TOKEN must be set or unset together"
+                ),
+            };
+
+            #[cfg(not(feature = "archodex-com"))]
+            let link_federation_endpoint = std::env::var("ARCHODEX_LINK_FEDERATION_ENDPOINT")
+                .ok()
+                .filter(|endpoint| !endpoint.is_empty());
+
+            #[cfg(not(feature = "archodex-com"))]
+            assert!(
+                link_federation_endpoint.is_none() || link.is_some(),
+                "ARCHODEX_LINK_FEDERATION_ENDPOINT requires ARCHODEX_LINK_ENDPOINT and ARCHODEX_LINK_TOKEN to be set"
+            );
+
+            let ingestion_shard_range = std::env::var("INGESTION_SHARD_RANGE")
+                .ok()
+                .filter(|range| !range.is_empty())
+                .map(|range| {
+                    crate::shard::parse_shard_range(&range)
+                        .expect("Failed to parse INGESTION_SHARD_RANGE env var as <start>-<end>")
+                });
+
+            let ingestion_shards = std::env::var("INGESTION_SHARDS")
+                .unwrap_or_default()
+                .split(',')
+                .filter(|shard| !shard.trim().is_empty())
+                .map(|shard| {
+                    shard
+                        .split_once('=')
+                        .and_then(|(range, endpoint)| {
+                            Some((
+                                crate::shard::parse_shard_range(range)?,
+                                endpoint.trim().to_string(),
+                            ))
+                        })
+                        .expect("Failed to parse INGESTION_SHARDS env var as comma separated <start>-<end>=<endpoint> values")
+                })
+                .collect::<Vec<_>>();
+
+            assert!(
+                ingestion_shard_range.is_some() || ingestion_shards.is_empty(),
+                "INGESTION_SHARDS env var requires INGESTION_SHARD_RANGE to be set"
+            );
+
+            #[cfg(feature = "archodex-com")]
+            let service_data_locations = std::env::var("SERVICE_DATA_LOCATIONS")
+                .expect("Missing SERVICE_DATA_LOCATIONS env var")
+                .split(',')
+                .filter(|location| !location.trim().is_empty())
+                .map(|location| {
+                    location
+                        .split_once('=')
+                        .map(|(region, aws_account_id)| crate::provisioning::ServiceDataLocation {
+                            region: region.trim().to_string(),
+                            aws_account_id: aws_account_id.trim().to_string(),
+                        })
+                        .expect("Failed to parse SERVICE_DATA_LOCATIONS env var as comma separated <region>=<aws account id> values")
+                })
+                .collect::<Vec<_>>();
+
+            #[cfg(feature = "archodex-com")]
+            assert!(
+                !service_data_locations.is_empty(),
+                "SERVICE_DATA_LOCATIONS env var must list at least one location"
+            );
+
+            let cognito_user_pool_id =
+                env_with_default_for_empty("COGNITO_USER_POOL_ID", "us-west-2_Mf1K95El6");
+            let cognito_client_id =
+                env_with_default_for_empty("COGNITO_CLIENT_ID", "1a5vsre47o6pa39p3p81igfken");
+
+            let issuer = env_with_default_for_empty(
+                "AUTH_ISSUER",
+                &format!("https://cognito-idp.us-west-2.amazonaws.com/{cognito_user_pool_id}"),
+            );
+
+            let auth_claims = AuthClaims {
+                jwks_url: env_with_default_for_empty(
+                    "AUTH_JWKS_URL",
+                    &format!("{issuer}/.well-known/jwks.json"),
+                ),
+                issuer,
+                user_id_claim: env_with_default_for_empty("AUTH_USER_ID_CLAIM", "sub"),
+                // Unlike other env vars, an empty value is used as is, so issuers can be configured to require no
+                // claims
+                required_claims: match std::env::var("AUTH_REQUIRED_CLAIMS") {
+                    Err(std::env::VarError::NotPresent) => {
+                        format!("client_id={cognito_client_id},token_use=access")
+                    }
+                    Ok(required_claims) => required_claims,
+                    Err(err) => panic!("Invalid AUTH_REQUIRED_CLAIMS env var: {err:?}"),
+                }
+                .split(',')
+                .filter(|claim| !claim.trim().is_empty())
+                .map(|claim| {
+                    claim
+                        .split_once('=')
+                        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+                        .expect("Failed to parse AUTH_REQUIRED_CLAIMS env var as comma separated <claim>=<value> values")
+                })
+                .collect(),
+                groups_claim: env_with_default_for_empty("AUTH_GROUPS_CLAIM", "cognito:groups"),
+                email_claim: env_with_default_for_empty("AUTH_EMAIL_CLAIM", "email"),
+                required_group: std::env::var("AUTH_REQUIRED_GROUP")
+                    .ok()
+                    .filter(|group| !group.is_empty()),
+            };
+
+            #[cfg(not(feature = "archodex-com"))]
+            let oidc_providers = std::env::var("OIDC_PROVIDERS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(|name| {
+                    let prefix = format!("OIDC_{}", name.to_uppercase());
+
+                    crate::oidc::OidcProvider {
+                        name: name.to_string(),
+                        issuer: std::env::var(format!("{prefix}_ISSUER"))
+                            .unwrap_or_else(|_| panic!("Missing {prefix}_ISSUER env var")),
+                        client_id: std::env::var(format!("{prefix}_CLIENT_ID"))
+                            .unwrap_or_else(|_| panic!("Missing {prefix}_CLIENT_ID env var")),
+                        client_secret: std::env::var(format!("{prefix}_CLIENT_SECRET"))
+                            .ok()
+                            .filter(|secret| !secret.is_empty()),
+                    }
+                })
+                .collect::<Vec<_>>();
+
             Env {
                 port,
                 archodex_domain,
+                allowed_origins,
+                cors_max_age,
+                shutdown_drain_timeout,
+                tls_paths,
+                dashboard_url,
+                saml_base_url,
                 #[cfg(feature = "archodex-com")]
                 accounts_surrealdb_url,
                 #[cfg(not(feature = "archodex-com"))]
@@ -92,16 +432,47 @@ impl Env {
                 #[cfg(not(feature = "archodex-com"))]
                 surrealdb_url,
                 surrealdb_creds,
+                surrealdb_slow_query_threshold,
+                surrealdb_query_timeout,
+                report_max_resource_tree_depth,
+                report_max_resource_tree_nodes,
+                report_max_events,
+                report_max_edges,
+                report_max_attribute_bytes,
+                report_max_statements,
+                report_rate_limit,
+                attribute_document_min_bytes,
+                step_up_max_auth_age,
+                resources_db_account_id_required,
+                standby,
+                standby_promotion_token: std::env::var("STANDBY_PROMOTION_TOKEN")
+                    .ok()
+                    .filter(|token| !token.is_empty()),
+                dr_backup_directory: std::env::var("DR_BACKUP_DIRECTORY")
+                    .ok()
+                    .filter(|directory| !directory.is_empty())
+                    .map(PathBuf::from),
+                dr_backup_max_age,
+                admin_token: std::env::var("ADMIN_TOKEN")
+                    .ok()
+                    .filter(|token| !token.is_empty()),
                 #[cfg(feature = "archodex-com")]
                 endpoint: std::env::var("ENDPOINT").expect("Missing ENDPOINT env var"),
-                cognito_user_pool_id: env_with_default_for_empty(
-                    "COGNITO_USER_POOL_ID",
-                    "us-west-2_Mf1K95El6",
-                ),
-                cognito_client_id: env_with_default_for_empty(
-                    "COGNITO_CLIENT_ID",
-                    "1a5vsre47o6pa39p3p81igfken",
-                ),
+                #[cfg(feature = "archodex-com")]
+                billing_webhook_secret: std::env::var("BILLING_WEBHOOK_SECRET")
+                    .ok()
+                    .filter(|secret| !secret.is_empty()),
+                #[cfg(feature = "archodex-com")]
+                service_data_locations,
+                ingestion_shard_range,
+                ingestion_shards,
+                auth_claims,
+                #[cfg(not(feature = "archodex-com"))]
+                oidc_providers,
+                #[cfg(not(feature = "archodex-com"))]
+                link,
+                #[cfg(not(feature = "archodex-com"))]
+                link_federation_endpoint,
                 #[cfg(not(feature = "archodex-com"))]
                 api_private_key: RwLock::new(None),
             }
@@ -110,6 +481,11 @@ impl Env {
         &ENV
     }
 
+    /// Parses all configuration env vars. Panics describing the first env var that is missing or invalid.
+    pub fn check() {
+        Self::get();
+    }
+
     #[must_use]
     pub fn port() -> u16 {
         Self::get().port
@@ -120,6 +496,35 @@ impl Env {
         Self::get().archodex_domain.as_str()
     }
 
+    // Origins of dashboards allowed to make cross-origin requests. Defaults to the archodex.com dashboard and the local
+    // dashboard development server.
+    pub(crate) fn allowed_origins() -> &'static [crate::router::AllowedOrigin] {
+        &Self::get().allowed_origins
+    }
+
+    // How long browsers may cache CORS preflight responses. Browsers cap this at their own maximum, e.g. two hours for
+    // Chromium.
+    pub(crate) fn cors_max_age() -> Duration {
+        Self::get().cors_max_age
+    }
+
+    // How long in-flight requests may take to complete once shutdown is initiated before the server exits regardless.
+    // Should be shorter than the grace period of the orchestrator, e.g. Kubernetes' `terminationGracePeriodSeconds`.
+    #[must_use]
+    pub fn shutdown_drain_timeout() -> Duration {
+        Self::get().shutdown_drain_timeout
+    }
+
+    // PEM encoded certificate chain and private key files the server terminates TLS with, if it serves TLS rather than
+    // plain HTTP behind a load balancer
+    #[must_use]
+    pub fn tls_paths() -> Option<(&'static std::path::Path, &'static std::path::Path)> {
+        Self::get()
+            .tls_paths
+            .as_ref()
+            .map(|(cert_path, key_path)| (cert_path.as_path(), key_path.as_path()))
+    }
+
     #[must_use]
     pub fn accounts_surrealdb_url() -> &'static str {
         Self::get().accounts_surrealdb_url.as_str()
@@ -135,17 +540,154 @@ impl Env {
         Self::get().surrealdb_creds
     }
 
+    pub(crate) fn surrealdb_slow_query_threshold() -> Duration {
+        Self::get().surrealdb_slow_query_threshold
+    }
+
+    // Maximum duration of queries that request handlers wait for, see `db::InstrumentedQuery::timeout`
+    pub(crate) fn surrealdb_query_timeout() -> Duration {
+        Self::get().surrealdb_query_timeout
+    }
+
+    // Maximum nesting depth of a resource tree in a report
+    pub(crate) fn report_max_resource_tree_depth() -> usize {
+        Self::get().report_max_resource_tree_depth
+    }
+
+    // Maximum number of resources across all resource trees in a report
+    pub(crate) fn report_max_resource_tree_nodes() -> usize {
+        Self::get().report_max_resource_tree_nodes
+    }
+
+    // Maximum number of events across all event captures in a report
+    pub(crate) fn report_max_events() -> usize {
+        Self::get().report_max_events
+    }
+
+    // Maximum number of event relations a report records, i.e. principals × resources × events summed over its event
+    // captures
+    pub(crate) fn report_max_edges() -> usize {
+        Self::get().report_max_edges
+    }
+
+    // Maximum serialized size in bytes of a single resource attribute value in a report
+    pub(crate) fn report_max_attribute_bytes() -> usize {
+        Self::get().report_max_attribute_bytes
+    }
+
+    // Maximum number of statements in the transaction ingesting a report
+    pub(crate) fn report_max_statements() -> usize {
+        Self::get().report_max_statements
+    }
+
+    // Sustained requests per second and burst size allowed per report key, if report requests are rate limited
+    pub(crate) fn report_rate_limit() -> Option<(f64, f64)> {
+        Self::get().report_rate_limit
+    }
+
+    // Minimum serialized size of resource attribute values stored as shared attribute documents, if enabled
+    pub(crate) fn attribute_document_min_bytes() -> Option<usize> {
+        Self::get().attribute_document_min_bytes
+    }
+
+    // Maximum time since a user authenticated for them to perform destructive actions without signing in again
+    pub(crate) fn step_up_max_auth_age() -> Duration {
+        Self::get().step_up_max_auth_age
+    }
+
+    // Whether resources databases that don't record the ID of their account are refused, see
+    // `db::verify_resources_db_account`
+    pub(crate) fn resources_db_account_id_required() -> bool {
+        Self::get().resources_db_account_id_required
+    }
+
+    // Whether the instance starts as a read-only standby, see `standby`
+    pub(crate) fn standby() -> bool {
+        Self::get().standby
+    }
+
+    // Bearer token authenticating requests to promote a standby instance, if it can be promoted
+    pub(crate) fn standby_promotion_token() -> Option<&'static str> {
+        Self::get().standby_promotion_token.as_deref()
+    }
+
+    // Directory disaster recovery backups are written to, if backups are enabled
+    pub(crate) fn dr_backup_directory() -> Option<&'static std::path::Path> {
+        Self::get().dr_backup_directory.as_deref()
+    }
+
+    // Age after which the latest backup of a database is stale, and the database is not ready to be restored
+    pub(crate) fn dr_backup_max_age() -> Duration {
+        Self::get().dr_backup_max_age
+    }
+
+    // Bearer token authenticating requests to the admin endpoints, if they are served, see `admin`
+    pub(crate) fn admin_token() -> Option<&'static str> {
+        Self::get().admin_token.as_deref()
+    }
+
     #[cfg(feature = "archodex-com")]
     pub(crate) fn endpoint() -> &'static str {
         Self::get().endpoint.as_str()
     }
 
-    pub(crate) fn cognito_user_pool_id() -> &'static str {
-        Self::get().cognito_user_pool_id.as_str()
+    #[cfg(feature = "archodex-com")]
+    pub(crate) fn billing_webhook_secret() -> Option<&'static str> {
+        Self::get().billing_webhook_secret.as_deref()
+    }
+
+    // AWS regions accounts may pin their service data to, each with the customer data AWS account service data
+    // databases are created in. Accounts that don't select a region are created in the first.
+    #[cfg(feature = "archodex-com")]
+    pub(crate) fn service_data_locations() -> &'static [crate::provisioning::ServiceDataLocation] {
+        &Self::get().service_data_locations
+    }
+
+    // Endpoint and token of the archodex.com account this self-hosted instance is linked to, if linking is enabled
+    #[cfg(not(feature = "archodex-com"))]
+    pub(crate) fn link() -> Option<(&'static str, &'static str)> {
+        Self::get()
+            .link
+            .as_ref()
+            .map(|(link_endpoint, link_token)| (link_endpoint.as_str(), link_token.as_str()))
     }
 
-    pub(crate) fn cognito_client_id() -> &'static str {
-        Self::get().cognito_client_id.as_str()
+    // Publicly reachable endpoint of this instance that the linked archodex.com account may proxy read-only queries to
+    #[cfg(not(feature = "archodex-com"))]
+    pub(crate) fn link_federation_endpoint() -> Option<&'static str> {
+        Self::get().link_federation_endpoint.as_deref()
+    }
+
+    // Range of shard buckets whose accounts this instance ingests reports for, if ingestion sharding is enabled
+    pub(crate) fn ingestion_shard_range() -> Option<&'static RangeInclusive<u16>> {
+        Self::get().ingestion_shard_range.as_ref()
+    }
+
+    // Shard bucket ranges and endpoints of all ingestion shards, used to redirect reports to the owning shard
+    pub(crate) fn ingestion_shards() -> &'static [(RangeInclusive<u16>, String)] {
+        &Self::get().ingestion_shards
+    }
+
+    // Dashboard users are redirected to after signing in with SAML
+    pub(crate) fn dashboard_url() -> &'static str {
+        Self::get().dashboard_url.as_str()
+    }
+
+    // Public URL of this backend that SAML service provider entity IDs and endpoints are under, if SAML sign in is
+    // enabled
+    pub(crate) fn saml_base_url() -> Option<&'static str> {
+        Self::get().saml_base_url.as_deref()
+    }
+
+    pub(crate) fn auth_claims() -> &'static AuthClaims {
+        &Self::get().auth_claims
+    }
+
+    // Additional OpenID Connect providers whose ID tokens are accepted as dashboard credentials, configured with
+    // `OIDC_PROVIDERS=<name>,...` and `OIDC_<NAME>_ISSUER`, `OIDC_<NAME>_CLIENT_ID`, and `OIDC_<NAME>_CLIENT_SECRET`
+    #[cfg(not(feature = "archodex-com"))]
+    pub(crate) fn oidc_providers() -> &'static [crate::oidc::OidcProvider] {
+        &Self::get().oidc_providers
     }
 
     pub(crate) async fn api_private_key() -> aes_gcm::Key<aes_gcm::Aes128Gcm> {
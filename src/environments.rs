@@ -0,0 +1,285 @@
+// Environments, like `prod` and `staging`, group an account's resources. Account members define environments, with the
+// color, description, and position the dashboard shows them with, and assign resources to them with
+// `POST /account/:account_id/resource/set_environments` or composite operations, which only accept defined
+// environments. Environments are identified by their name, which can't be changed.
+//
+// Deleting an environment removes it from the resources in it, and clears the account's default environment if it was
+// the default.
+
+use std::collections::{HashMap, HashSet};
+
+use axum::{Extension, Json, extract::Path};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::statements::{BeginStatement, CommitStatement};
+use tracing::{info, instrument};
+
+use archodex_error::{
+    anyhow::{Context as _, bail},
+    bad_request, conflict, not_found,
+};
+
+use crate::{
+    Result,
+    account::Account,
+    account_cache,
+    db::{DBConnection, QueryCheckFirstRealError as _, accounts_db},
+    surrealdb_deserializers,
+};
+
+const MAX_ENVIRONMENTS: usize = 100;
+const MAX_ENVIRONMENT_NAME_LENGTH: usize = 64;
+const MAX_ENVIRONMENT_DESCRIPTION_LENGTH: usize = 1000;
+
+// Queries and searches of `all` environments aren't scoped to an environment, see `Account::scoped_environment`
+const RESERVED_ENVIRONMENT_NAME: &str = "all";
+
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
+pub(crate) struct Environment {
+    #[serde(
+        rename(deserialize = "id"),
+        deserialize_with = "surrealdb_deserializers::string::deserialize"
+    )]
+    name: String,
+    // Hex encoded RGB color, e.g. `#1f77b4`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    color: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    // Environments are listed in ascending position, then name
+    position: u32,
+    created_at: DateTime<Utc>,
+}
+
+fn environment_thing(name: &str) -> surrealdb::sql::Thing {
+    surrealdb::sql::Thing::from(("environment", name))
+}
+
+fn environment_name(params: &HashMap<String, String>) -> Result<&str> {
+    let Some(name) = params.get("environment") else {
+        bail!("Missing environment");
+    };
+
+    Ok(name)
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || name.trim() != name
+        || name.len() > MAX_ENVIRONMENT_NAME_LENGTH
+        || name == RESERVED_ENVIRONMENT_NAME
+    {
+        bad_request!(
+            "Invalid environment name {name:?}, environment names must be at most {MAX_ENVIRONMENT_NAME_LENGTH} bytes without leading or trailing whitespace, and must not be `{RESERVED_ENVIRONMENT_NAME}`"
+        );
+    }
+
+    Ok(())
+}
+
+// Returns the color normalized to lowercase
+fn validate_attributes(color: Option<String>, description: Option<&str>) -> Result<Option<String>> {
+    if description.is_some_and(|description| description.len() > MAX_ENVIRONMENT_DESCRIPTION_LENGTH)
+    {
+        bad_request!(
+            "Environment descriptions must be at most {MAX_ENVIRONMENT_DESCRIPTION_LENGTH} bytes"
+        );
+    }
+
+    let Some(color) = color else {
+        return Ok(None);
+    };
+
+    if !color
+        .strip_prefix('#')
+        .is_some_and(|rgb| rgb.len() == 6 && rgb.bytes().all(|byte| byte.is_ascii_hexdigit()))
+    {
+        bad_request!("Invalid environment color {color:?}, colors must be of the form `#rrggbb`");
+    }
+
+    Ok(Some(color.to_ascii_lowercase()))
+}
+
+// Checks that every environment is defined in the account's resources database
+pub(crate) async fn ensure_defined(
+    db: &DBConnection,
+    environments: &HashSet<String>,
+) -> Result<()> {
+    if environments.is_empty() {
+        return Ok(());
+    }
+
+    let defined = db
+        .query("SELECT VALUE record::id(id) FROM environment")
+        .await
+        .context("Failed to submit query to list environment names")?
+        .check_first_real_error()
+        .context("Failed to list environment names")?
+        .take::<Vec<String>>(0)?
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+    let mut undefined = environments.difference(&defined).collect::<Vec<_>>();
+
+    if !undefined.is_empty() {
+        undefined.sort();
+
+        bad_request!(
+            "Environments {undefined:?} are not defined, define them with `POST /account/:account_id/environments` first"
+        );
+    }
+
+    Ok(())
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct ListEnvironmentsResponse {
+    environments: Vec<Environment>,
+}
+
+#[instrument(err, skip_all)]
+pub(crate) async fn list_environments(
+    Extension(account): Extension<Account>,
+) -> Result<Json<ListEnvironmentsResponse>> {
+    let environments = account
+        .resources_db()
+        .await?
+        .query("SELECT * FROM environment ORDER BY position, id")
+        .await
+        .context("Failed to submit query to list environments")?
+        .check_first_real_error()
+        .context("Failed to list environments")?
+        .take::<Vec<Environment>>(0)?;
+
+    Ok(Json(ListEnvironmentsResponse { environments }))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CreateEnvironmentRequest {
+    name: String,
+    color: Option<String>,
+    description: Option<String>,
+    // Defaults to after every other environment
+    position: Option<u32>,
+}
+
+#[instrument(err, skip(account))]
+pub(crate) async fn create_environment(
+    Extension(account): Extension<Account>,
+    Json(req): Json<CreateEnvironmentRequest>,
+) -> Result<Json<Environment>> {
+    validate_name(&req.name)?;
+    let color = validate_attributes(req.color, req.description.as_deref())?;
+
+    let mut res = account
+        .resources_db()
+        .await?
+        .query("LET $environments = count(SELECT id FROM environment);")
+        .query("IF $environments < $max_environments AND !record::exists($environment) { CREATE $environment CONTENT { color: $color, description: $description, position: $position ?? $environments } RETURN AFTER };")
+        .query("RETURN $environments >= $max_environments;")
+        .bind(("environment", environment_thing(&req.name)))
+        .bind(("color", color))
+        .bind(("description", req.description))
+        .bind(("position", req.position))
+        .bind(("max_environments", MAX_ENVIRONMENTS))
+        .await
+        .context("Failed to submit query to create environment")?
+        .check_first_real_error()
+        .context("Failed to create environment")?;
+
+    let Some(environment) = res.take::<Option<Environment>>(1)? else {
+        if res.take::<Option<bool>>(2)? == Some(true) {
+            conflict!("Accounts may define at most {MAX_ENVIRONMENTS} environments");
+        }
+
+        conflict!("Environment {:?} already exists", req.name);
+    };
+
+    info!(environment = req.name, "Created environment");
+
+    Ok(Json(environment))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct UpdateEnvironmentRequest {
+    color: Option<String>,
+    description: Option<String>,
+    position: u32,
+}
+
+#[instrument(err, skip(account))]
+pub(crate) async fn update_environment(
+    Extension(account): Extension<Account>,
+    Path(params): Path<HashMap<String, String>>,
+    Json(req): Json<UpdateEnvironmentRequest>,
+) -> Result<Json<Environment>> {
+    let name = environment_name(&params)?;
+    let color = validate_attributes(req.color, req.description.as_deref())?;
+
+    let Some(environment) = account
+        .resources_db()
+        .await?
+        .query("UPDATE $environment SET color = $color, description = $description, position = $position RETURN AFTER")
+        .bind(("environment", environment_thing(name)))
+        .bind(("color", color))
+        .bind(("description", req.description))
+        .bind(("position", req.position))
+        .await
+        .context("Failed to submit query to update environment")?
+        .check_first_real_error()
+        .context("Failed to update environment")?
+        .take::<Option<Environment>>(0)?
+    else {
+        not_found!("Environment not found");
+    };
+
+    info!(environment = name, "Updated environment");
+
+    Ok(Json(environment))
+}
+
+#[instrument(err, skip(account))]
+pub(crate) async fn delete_environment(
+    Extension(account): Extension<Account>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<()>> {
+    let name = environment_name(&params)?;
+
+    let deleted = account
+        .resources_db()
+        .await?
+        .query(BeginStatement::default())
+        .query("DELETE $environment RETURN BEFORE;")
+        .query("UPDATE resource SET environments -= $name WHERE $name INSIDE environments RETURN NONE;")
+        .query(CommitStatement::default())
+        .bind(("environment", environment_thing(name)))
+        .bind(("name", name.to_string()))
+        .await
+        .context("Failed to submit query to delete environment")?
+        .check_first_real_error()
+        .context("Failed to delete environment")?
+        .take::<Vec<surrealdb::sql::Object>>(0)?;
+
+    if deleted.is_empty() {
+        not_found!("Environment not found");
+    }
+
+    accounts_db()
+        .await?
+        .query("UPDATE $account SET default_environment = NONE WHERE default_environment == $name RETURN NONE")
+        .bind(("account", surrealdb::sql::Thing::from(&account)))
+        .bind(("name", name.to_string()))
+        .await
+        .context("Failed to submit query to clear account default environment")?
+        .check_first_real_error()
+        .context("Failed to clear account default environment")?;
+
+    account_cache::invalidate();
+
+    info!(environment = name, "Deleted environment");
+
+    Ok(Json(()))
+}
@@ -1,10 +1,14 @@
 use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use tracing::instrument;
 
-use crate::{principal_chain::PrincipalChainId, resource::ResourceId};
+use crate::{
+    db::statement_timeout, principal_chain::PrincipalChainId, resource::ResourceId,
+    sensor::Observation,
+};
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, JsonSchema, Serialize)]
 pub(crate) struct Event {
     pub(crate) principal: ResourceId,
     pub(crate) r#type: String,
@@ -12,6 +16,12 @@ pub(crate) struct Event {
     pub(crate) principal_chains: Vec<PrincipalChainId>,
     pub(crate) first_seen_at: DateTime<Utc>,
     pub(crate) last_seen_at: DateTime<Utc>,
+    // When a report removed the event, see `report::remove_events`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) gone_at: Option<DateTime<Utc>>,
+    // Sensors that observed the event, see `sensor`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) observed_by: Vec<Observation>,
 }
 
 impl<'de> Deserialize<'de> for Event {
@@ -39,6 +49,8 @@ impl<'de> Deserialize<'de> for Event {
                 let mut principal_chains: Option<Vec<PrincipalChainId>> = None;
                 let mut first_seen_at: Option<DateTime<Utc>> = None;
                 let mut last_seen_at: Option<DateTime<Utc>> = None;
+                let mut gone_at: Option<DateTime<Utc>> = None;
+                let mut observed_by: Option<Vec<Observation>> = None;
 
                 while let Some(key) = map.next_key::<String>()? {
                     match key.as_str() {
@@ -64,6 +76,8 @@ impl<'de> Deserialize<'de> for Event {
                         }
                         "first_seen_at" => first_seen_at = Some(map.next_value()?),
                         "last_seen_at" => last_seen_at = Some(map.next_value()?),
+                        "gone_at" => gone_at = map.next_value()?,
+                        "observed_by" => observed_by = map.next_value()?,
                         _ => {
                             return Err(serde::de::Error::unknown_field(
                                 &key,
@@ -78,6 +92,8 @@ impl<'de> Deserialize<'de> for Event {
                                     "has_direct_principal_chain",
                                     "first_seen_at",
                                     "last_seen_at",
+                                    "gone_at",
+                                    "observed_by",
                                 ],
                             ));
                         }
@@ -94,6 +110,8 @@ impl<'de> Deserialize<'de> for Event {
                         .ok_or_else(|| serde::de::Error::missing_field("first_seen_at"))?,
                     last_seen_at: last_seen_at
                         .ok_or_else(|| serde::de::Error::missing_field("last_seen_at"))?,
+                    gone_at,
+                    observed_by: observed_by.unwrap_or_default(),
                 })
             }
         }
@@ -103,7 +121,10 @@ impl<'de> Deserialize<'de> for Event {
 }
 
 impl Event {
-    pub(crate) fn get_all() -> &'static str {
-        "$events = SELECT * OMIT id FROM event PARALLEL;"
+    pub(crate) fn get_all() -> String {
+        format!(
+            "$events = SELECT * OMIT id FROM event {} PARALLEL;",
+            statement_timeout()
+        )
     }
 }
@@ -0,0 +1,135 @@
+// Reports only ever upsert events, so events that are no longer observed would otherwise accumulate in the account's
+// resources database forever. Accounts may set an event retention, after which events that have not been seen are
+// pruned by `prune_expired_events_periodically`. Events are kept indefinitely when unset.
+//
+// Events are pruned in batches of `EVENT_PRUNING_BATCH_SIZE`, and at most `MAX_EVENT_PRUNING_BATCHES` batches per
+// account each interval, so pruning a large backlog neither runs one long transaction nor holds up other accounts. The
+// rest of the backlog is pruned in later intervals. Each batch acquires its own database connection, so requests
+// waiting for the connection of a self-hosted instance are served between batches. Principal chains left without events
+// are deleted with the batch that pruned their last event.
+
+use std::time::Duration;
+
+use axum::{Extension, Json};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::statements::{BeginStatement, CommitStatement};
+use tracing::{info, instrument};
+
+use archodex_error::{anyhow::Context as _, bad_request};
+
+use crate::{
+    Result,
+    account::Account,
+    account_cache,
+    accounts::for_each_account_periodically,
+    auth::DashboardAuth,
+    db::{QueryCheckFirstRealError as _, accounts_db},
+    metrics::EVENTS_PRUNED_TOTAL,
+    query_cache,
+};
+
+const EVENT_PRUNING_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const EVENT_PRUNING_BATCH_SIZE: usize = 1000;
+const MAX_EVENT_PRUNING_BATCHES: usize = 100;
+
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct EventRetention {
+    // Number of days after which events that have not been seen are pruned. Events are kept indefinitely when unset.
+    days: Option<u32>,
+}
+
+#[instrument(err, skip_all, fields(account_id = account.id()))]
+pub(crate) async fn get_event_retention(
+    Extension(account): Extension<Account>,
+) -> Result<Json<EventRetention>> {
+    Ok(Json(EventRetention {
+        days: account.event_retention_days(),
+    }))
+}
+
+// Sets how long events that have not been seen are kept. A `null` value keeps events indefinitely.
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn set_event_retention(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Json(req): Json<EventRetention>,
+) -> Result<()> {
+    auth.ensure_account_admin(account.id()).await?;
+
+    if req.days == Some(0) {
+        bad_request!("Event retention must be at least one day");
+    }
+
+    accounts_db()
+        .await?
+        .query("UPDATE $account SET event_retention_days = $days RETURN NONE")
+        .bind(("account", surrealdb::sql::Thing::from(&account)))
+        .bind(("days", req.days))
+        .await
+        .context("Failed to submit query to set account event retention")?
+        .check_first_real_error()
+        .context("Failed to set account event retention")?;
+
+    account_cache::invalidate();
+
+    Ok(())
+}
+
+/// Periodically prunes events of every account that sets an event retention once they have not been seen within it.
+pub async fn prune_expired_events_periodically() {
+    for_each_account_periodically(
+        EVENT_PRUNING_INTERVAL,
+        "event pruning",
+        |account| async move { prune_expired_events(&account).await },
+    )
+    .await;
+}
+
+#[instrument(err, skip_all, fields(account_id = account.id()))]
+async fn prune_expired_events(account: &Account) -> Result<()> {
+    let Some(event_retention_days) = account.event_retention_days() else {
+        return Ok(());
+    };
+
+    let mut pruned = 0;
+
+    for _ in 0..MAX_EVENT_PRUNING_BATCHES {
+        // Expiry is computed by the database, like the expiry of audit log entries
+        let batch = account
+            .resources_db()
+            .await?
+            .query(BeginStatement::default())
+            .query("LET $expired = SELECT VALUE id FROM event WHERE last_seen_at < time::now() - duration::from::days($days) LIMIT $limit;")
+            .query("LET $principal_chains = array::distinct(array::flatten(SELECT VALUE principal_chains FROM $expired));")
+            .query("DELETE $expired RETURN NONE;")
+            .query("DELETE $principal_chains WHERE array::is_empty(SELECT VALUE id FROM event WHERE principal_chains CONTAINS $parent.id LIMIT 1) RETURN NONE;")
+            .query("RETURN array::len($expired);")
+            .query(CommitStatement::default())
+            .bind(("days", event_retention_days))
+            .bind(("limit", EVENT_PRUNING_BATCH_SIZE))
+            .await
+            .context("Failed to submit query to prune expired events")?
+            .check_first_real_error()
+            .context("Failed to prune expired events")?
+            .take::<Option<usize>>(4)?
+            .unwrap_or(0);
+
+        pruned += batch;
+        EVENTS_PRUNED_TOTAL.inc_by(batch as u64);
+
+        if batch < EVENT_PRUNING_BATCH_SIZE {
+            break;
+        }
+    }
+
+    if pruned > 0 {
+        info!(events = pruned, "Pruned expired events");
+
+        query_cache::invalidate(account.id());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,546 @@
+// Exports the full resource graph of an account for analysis in tools such as Gephi or Graphviz. The graph is read one
+// page at a time and streamed to the client as it is serialized, so large graphs are never buffered in memory. Pages
+// are read in separate transactions, so resources and events recorded while an export is running may be omitted.
+//
+// Containment edges include both the hierarchy encoded in resource IDs and the `contains` relations of globally unique
+// resources.
+//
+// Exports are encrypted with the account's export encryption key when requested with `encrypted=true` or when the key
+// requires it, see `export_encryption`.
+
+use std::collections::HashMap;
+
+use axum::{
+    Extension,
+    body::Body,
+    extract::Query,
+    http::header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+    response::{AppendHeaders, IntoResponse, Response},
+};
+use futures::{StreamExt as _, stream};
+use serde::{Deserialize, de::DeserializeOwned};
+use tracing::instrument;
+
+use archodex_error::{
+    anyhow::{self, Context as _},
+    bad_request,
+};
+
+use crate::{
+    Result,
+    account::Account,
+    audit::{self, AuditRequest},
+    auth::DashboardAuth,
+    db::QueryCheckFirstRealError as _,
+    event::Event,
+    export_encryption::{EXPORT_KEY_VERSION_HEADER, Encryptor},
+    resource::{RESOURCE_FIELDS, Resource, ResourceId},
+};
+
+const EXPORT_PAGE_SIZE: u32 = 1000;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ExportFormat {
+    #[default]
+    Json,
+    Graphml,
+    Dot,
+}
+
+impl ExportFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "application/json",
+            ExportFormat::Graphml => "application/graphml+xml",
+            ExportFormat::Dot => "text/vnd.graphviz",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "json",
+            ExportFormat::Graphml => "graphml",
+            ExportFormat::Dot => "dot",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ExportParams {
+    #[serde(default)]
+    format: ExportFormat,
+    // Encrypts the export with the account's export encryption key
+    #[serde(default)]
+    encrypted: bool,
+}
+
+#[instrument(err, skip(auth, account, audit_request))]
+pub(crate) async fn export(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Query(params): Query<ExportParams>,
+    audit_request: AuditRequest,
+) -> Result<Response> {
+    let format = params.format;
+    let mut filename = format!("archodex-{}.{}", account.id(), format.extension());
+    let mut content_type = format.content_type().to_string();
+    let mut headers = Vec::new();
+
+    let encryption_key = account
+        .export_encryption_key()
+        .filter(|key| params.encrypted || key.required)
+        .cloned();
+
+    if params.encrypted && encryption_key.is_none() {
+        bad_request!("Account has no export encryption key");
+    }
+
+    let (encryptor, preamble) = match &encryption_key {
+        Some(key) => {
+            let (encryptor, preamble) = Encryptor::new(&key.recipient)?;
+
+            filename.push_str(".age");
+            content_type = "application/octet-stream".to_string();
+            headers.push((EXPORT_KEY_VERSION_HEADER, key.version.to_string()));
+
+            // Failures are logged by `audit::record`, and must not fail the export
+            let _ = audit::record(
+                account.id(),
+                auth.principal(),
+                "account.export_encrypted",
+                Some(HashMap::from([
+                    ("format".to_string(), format.extension().to_string()),
+                    ("key_version".to_string(), key.version.to_string()),
+                ])),
+                audit_request,
+            )
+            .await;
+
+            (Some(encryptor), Some(preamble))
+        }
+        None => (None, None),
+    };
+
+    let exporter = Exporter {
+        account,
+        format,
+        encryptor,
+        phase: Some(Phase::Start),
+        cursor: None,
+        section_empty: true,
+    };
+
+    let chunks = stream::unfold(Some(exporter), |exporter| async move {
+        let mut exporter = exporter?;
+
+        match exporter.next_chunk().await {
+            Ok(Some(chunk)) => Some((Ok(chunk), Some(exporter))),
+            Ok(None) => None,
+            // The response has already started, so errors can only abort it
+            Err(err) => Some((Err(err), None)),
+        }
+    });
+
+    // The age header precedes the encrypted graph
+    let chunks = stream::iter(preamble.map(Ok)).chain(chunks);
+
+    headers.push((CONTENT_TYPE, content_type));
+    headers.push((
+        CONTENT_DISPOSITION,
+        format!("attachment; filename=\"{filename}\""),
+    ));
+
+    Ok((AppendHeaders(headers), Body::from_stream(chunks)).into_response())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Phase {
+    Start,
+    Resources,
+    Hierarchy,
+    Contains,
+    Events,
+    End,
+}
+
+impl Phase {
+    // Phases whose elements share a section of the output. Hierarchy and `contains` relation edges share a section.
+    fn section(self) -> Option<Phase> {
+        match self {
+            Phase::Start | Phase::End => None,
+            Phase::Resources => Some(Phase::Resources),
+            Phase::Hierarchy | Phase::Contains => Some(Phase::Hierarchy),
+            Phase::Events => Some(Phase::Events),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Contained {
+    id: ResourceId,
+}
+
+#[derive(Deserialize)]
+struct Contains {
+    #[serde(rename = "in")]
+    container: ResourceId,
+    #[serde(rename = "out")]
+    contained: ResourceId,
+}
+
+struct Exporter {
+    account: Account,
+    format: ExportFormat,
+    encryptor: Option<Encryptor>,
+    phase: Option<Phase>,
+    // ID of the last record of the previous page of the current phase. Pages start after it rather than at an offset,
+    // so each page is read without skipping over the records of all previous pages.
+    cursor: Option<surrealdb::sql::Thing>,
+    section_empty: bool,
+}
+
+impl Exporter {
+    // Returns the next chunk of the export, encrypted if the export is encrypted, or `None` once the export is complete
+    async fn next_chunk(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        let plaintext = self.next_plaintext_chunk().await?;
+
+        let Some(encryptor) = &mut self.encryptor else {
+            return Ok(plaintext.map(String::into_bytes));
+        };
+
+        match plaintext {
+            Some(plaintext) => encryptor.update(plaintext.as_bytes()).map(Some),
+            None => self.encryptor.take().map(Encryptor::finish).transpose(),
+        }
+    }
+
+    // Returns the next chunk of the serialized graph, or `None` once the graph has been serialized
+    async fn next_plaintext_chunk(&mut self) -> anyhow::Result<Option<String>> {
+        let Some(phase) = self.phase else {
+            return Ok(None);
+        };
+
+        let mut chunk = String::new();
+
+        match phase {
+            Phase::Start => {
+                chunk.push_str(begin(self.format));
+                self.next_phase(Phase::Resources, &mut chunk);
+            }
+            Phase::Resources => {
+                let resources = self
+                    .page::<Resource>(RESOURCE_FIELDS, "resource", "id != resource:[]")
+                    .await?;
+
+                for resource in &resources {
+                    self.separate(&mut chunk);
+                    write_resource(self.format, &mut chunk, resource)?;
+                }
+
+                self.next_page(resources.len(), Phase::Hierarchy, &mut chunk);
+            }
+            Phase::Hierarchy => {
+                let resources = self
+                    .page::<Contained>("id", "resource", "array::len(record::id(id)) > 1")
+                    .await?;
+
+                for Contained { id } in &resources {
+                    if let Some(container) = id.container() {
+                        self.separate(&mut chunk);
+                        write_contains(self.format, &mut chunk, &container, id)?;
+                    }
+                }
+
+                self.next_page(resources.len(), Phase::Contains, &mut chunk);
+            }
+            Phase::Contains => {
+                let contains = self
+                    .page::<Contains>("id, in, out", "contains", "true")
+                    .await?;
+
+                for contains in &contains {
+                    self.separate(&mut chunk);
+                    write_contains(
+                        self.format,
+                        &mut chunk,
+                        &contains.container,
+                        &contains.contained,
+                    )?;
+                }
+
+                self.next_page(contains.len(), Phase::Events, &mut chunk);
+            }
+            Phase::Events => {
+                let events = self.page::<Event>("*", "event", "true").await?;
+
+                for event in &events {
+                    self.separate(&mut chunk);
+                    write_event(self.format, &mut chunk, event)?;
+                }
+
+                self.next_page(events.len(), Phase::End, &mut chunk);
+            }
+            Phase::End => {
+                chunk.push_str(end(self.format));
+                self.phase = None;
+            }
+        }
+
+        Ok(Some(chunk))
+    }
+
+    // Reads the page of records of the table after the cursor, moving the cursor to the last record of the page. Fields
+    // must include the record ID.
+    async fn page<T: DeserializeOwned>(
+        &mut self,
+        fields: &str,
+        table: &str,
+        condition: &str,
+    ) -> anyhow::Result<Vec<T>> {
+        let cursor_condition = match self.cursor {
+            Some(_) => "id > $cursor",
+            None => "true",
+        };
+
+        let mut res = self
+            .account
+            .resources_db()
+            .await?
+            .query(format!(
+                "LET $page = SELECT {fields} FROM {table} WHERE {cursor_condition} AND ({condition}) ORDER BY id LIMIT $limit;"
+            ))
+            .query("RETURN $page;")
+            .query("RETURN array::last($page).id;")
+            .bind(("cursor", self.cursor.clone()))
+            .bind(("limit", EXPORT_PAGE_SIZE))
+            .await
+            .context("Failed to submit query to export account graph")?
+            .check_first_real_error()
+            .context("Failed to export account graph")?;
+
+        let page = res
+            .take::<Vec<T>>(1)
+            .context("Failed to deserialize exported account graph")?;
+
+        self.cursor = res.take::<Option<surrealdb::sql::Thing>>(2)?;
+
+        Ok(page)
+    }
+
+    // Moves to the next page of the current phase, or to the next phase once a page is not full
+    fn next_page(&mut self, page_len: usize, next_phase: Phase, chunk: &mut String) {
+        if page_len < EXPORT_PAGE_SIZE as usize {
+            self.next_phase(next_phase, chunk);
+        }
+    }
+
+    fn next_phase(&mut self, next_phase: Phase, chunk: &mut String) {
+        let section = self.phase.and_then(Phase::section);
+        let next_section = next_phase.section();
+
+        if section != next_section {
+            if section.is_some() {
+                chunk.push_str(section_end(self.format));
+            }
+
+            if let Some(next_section) = next_section {
+                chunk.push_str(section_start(self.format, next_section));
+            }
+
+            self.section_empty = true;
+        }
+
+        self.phase = Some(next_phase);
+        self.cursor = None;
+    }
+
+    // Separates the elements of JSON arrays
+    fn separate(&mut self, chunk: &mut String) {
+        if matches!(self.format, ExportFormat::Json) && !self.section_empty {
+            chunk.push(',');
+        }
+
+        self.section_empty = false;
+    }
+}
+
+fn begin(format: ExportFormat) -> &'static str {
+    match format {
+        ExportFormat::Json => "{",
+        ExportFormat::Graphml => concat!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+            "\n",
+            r#"<graphml xmlns="http://graphml.graphdrawing.org/xmlns">"#,
+            "\n",
+            r#"<key id="label" for="all" attr.name="label" attr.type="string"/>"#,
+            "\n",
+            r#"<key id="first_seen_at" for="all" attr.name="first_seen_at" attr.type="string"/>"#,
+            "\n",
+            r#"<key id="last_seen_at" for="all" attr.name="last_seen_at" attr.type="string"/>"#,
+            "\n",
+            r#"<graph edgedefault="directed">"#,
+            "\n",
+        ),
+        ExportFormat::Dot => "digraph archodex {\n",
+    }
+}
+
+fn end(format: ExportFormat) -> &'static str {
+    match format {
+        ExportFormat::Json => "}",
+        ExportFormat::Graphml => "</graph>\n</graphml>\n",
+        ExportFormat::Dot => "}\n",
+    }
+}
+
+// Only JSON output is divided into sections
+fn section_start(format: ExportFormat, section: Phase) -> &'static str {
+    match (format, section) {
+        (ExportFormat::Json, Phase::Resources) => r#""resources":["#,
+        (ExportFormat::Json, Phase::Events) => r#","events":["#,
+        (ExportFormat::Json, _) => r#","contains":["#,
+        (ExportFormat::Graphml | ExportFormat::Dot, _) => "",
+    }
+}
+
+fn section_end(format: ExportFormat) -> &'static str {
+    match format {
+        ExportFormat::Json => "]",
+        ExportFormat::Graphml | ExportFormat::Dot => "",
+    }
+}
+
+// Graph node IDs are the JSON serializations of resource IDs
+fn node_id(resource_id: &ResourceId) -> anyhow::Result<String> {
+    serde_json::to_string(resource_id).context("Failed to serialize resource ID")
+}
+
+fn node_label(resource_id: &ResourceId) -> String {
+    resource_id
+        .last()
+        .map(|part| format!("{} {}", part.r#type, part.id))
+        .unwrap_or_default()
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn escape_dot(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn write_resource(
+    format: ExportFormat,
+    chunk: &mut String,
+    resource: &Resource,
+) -> anyhow::Result<()> {
+    match format {
+        ExportFormat::Json => {
+            chunk.push_str(
+                &serde_json::to_string(resource).context("Failed to serialize resource")?,
+            );
+        }
+        ExportFormat::Graphml => {
+            chunk.push_str(&format!(
+                r#"<node id="{}"><data key="label">{}</data>"#,
+                escape_xml(&node_id(&resource.id)?),
+                escape_xml(&node_label(&resource.id)),
+            ));
+
+            for (key, timestamp) in [
+                ("first_seen_at", resource.first_seen_at),
+                ("last_seen_at", resource.last_seen_at),
+            ] {
+                if let Some(timestamp) = timestamp {
+                    chunk.push_str(&format!(
+                        r#"<data key="{key}">{}</data>"#,
+                        timestamp.to_rfc3339()
+                    ));
+                }
+            }
+
+            chunk.push_str("</node>\n");
+        }
+        ExportFormat::Dot => {
+            chunk.push_str(&format!(
+                "  \"{}\" [label=\"{}\"];\n",
+                escape_dot(&node_id(&resource.id)?),
+                escape_dot(&node_label(&resource.id)),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn write_contains(
+    format: ExportFormat,
+    chunk: &mut String,
+    container: &ResourceId,
+    contained: &ResourceId,
+) -> anyhow::Result<()> {
+    match format {
+        ExportFormat::Json => {
+            chunk.push_str(
+                &serde_json::to_string(&serde_json::json!({
+                    "container": container,
+                    "contained": contained,
+                }))
+                .context("Failed to serialize containment")?,
+            );
+        }
+        ExportFormat::Graphml => {
+            chunk.push_str(&format!(
+                r#"<edge source="{}" target="{}"><data key="label">contains</data></edge>"#,
+                escape_xml(&node_id(container)?),
+                escape_xml(&node_id(contained)?),
+            ));
+            chunk.push('\n');
+        }
+        ExportFormat::Dot => {
+            chunk.push_str(&format!(
+                "  \"{}\" -> \"{}\" [label=\"contains\", style=dashed];\n",
+                escape_dot(&node_id(container)?),
+                escape_dot(&node_id(contained)?),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn write_event(format: ExportFormat, chunk: &mut String, event: &Event) -> anyhow::Result<()> {
+    match format {
+        ExportFormat::Json => {
+            chunk.push_str(&serde_json::to_string(event).context("Failed to serialize event")?);
+        }
+        ExportFormat::Graphml => {
+            chunk.push_str(&format!(
+                r#"<edge source="{}" target="{}"><data key="label">{}</data><data key="first_seen_at">{}</data><data key="last_seen_at">{}</data></edge>"#,
+                escape_xml(&node_id(&event.principal)?),
+                escape_xml(&node_id(&event.resource)?),
+                escape_xml(&event.r#type),
+                event.first_seen_at.to_rfc3339(),
+                event.last_seen_at.to_rfc3339(),
+            ));
+            chunk.push('\n');
+        }
+        ExportFormat::Dot => {
+            chunk.push_str(&format!(
+                "  \"{}\" -> \"{}\" [label=\"{}\"];\n",
+                escape_dot(&node_id(&event.principal)?),
+                escape_dot(&node_id(&event.resource)?),
+                escape_dot(&event.r#type),
+            ));
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,405 @@
+// Regulated customers must not hold exports of their resource graph in plaintext, so accounts may register an age
+// (https://age-encryption.org) public key to encrypt exports with before they leave the backend. Exports are encrypted
+// while they are streamed, and only the holder of the matching identity can decrypt them, e.g. with
+// `age --decrypt -i key.txt archodex-<account ID>.json.age`. Accounts may require every export to be encrypted.
+//
+// Registering a key replaces the previous key and increments the key version. Encrypted exports carry the version of
+// the key that encrypted them in the `X-Archodex-Export-Key-Version` header and are recorded in the audit log with it,
+// so the key needed to decrypt an export can be found after keys are rotated.
+//
+// Files are encrypted for a single X25519 recipient following the age v1 format: a random file key is wrapped for the
+// recipient in the header, and the payload is encrypted with ChaCha20-Poly1305 in 64 KiB chunks.
+
+use axum::{Extension, Json, http::HeaderName};
+use base64::{Engine as _, prelude::BASE64_STANDARD_NO_PAD};
+use chrono::{DateTime, Utc};
+use ring::{
+    aead::{self, Aad, CHACHA20_POLY1305, LessSafeKey, Nonce, UnboundKey},
+    agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519},
+    hkdf, hmac,
+    rand::{SecureRandom as _, SystemRandom},
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument};
+
+use archodex_error::{
+    anyhow::{self, Context as _, anyhow},
+    bad_request,
+};
+
+use crate::{
+    Result,
+    account::Account,
+    account_cache,
+    auth::DashboardAuth,
+    db::{QueryCheckFirstRealError as _, accounts_db},
+};
+
+pub(crate) const EXPORT_KEY_VERSION_HEADER: HeaderName =
+    HeaderName::from_static("x-archodex-export-key-version");
+
+const RECIPIENT_HRP: &str = "age";
+const BECH32_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+const VERSION_LINE: &str = "age-encryption.org/v1";
+const X25519_INFO: &[u8] = b"age-encryption.org/v1/X25519";
+const FILE_KEY_BYTES: usize = 16;
+const PAYLOAD_NONCE_BYTES: usize = 16;
+const CHUNK_BYTES: usize = 64 * 1024;
+
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
+pub(crate) struct ExportEncryptionKey {
+    // Incremented each time a key is registered
+    pub(crate) version: u32,
+    // age recipient, e.g. `age1...`
+    pub(crate) recipient: String,
+    // Whether exports are encrypted even if the request doesn't ask for it
+    pub(crate) required: bool,
+    pub(crate) created_at: DateTime<Utc>,
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct GetExportEncryptionKeyResponse {
+    key: Option<ExportEncryptionKey>,
+}
+
+#[instrument(err, skip_all)]
+pub(crate) async fn get_export_encryption_key(
+    Extension(account): Extension<Account>,
+) -> Result<Json<GetExportEncryptionKeyResponse>> {
+    Ok(Json(GetExportEncryptionKeyResponse {
+        key: account.export_encryption_key().cloned(),
+    }))
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SetExportEncryptionKeyRequest {
+    recipient: String,
+    #[serde(default)]
+    required: bool,
+}
+
+// Registers the age public key exports of the account are encrypted with, replacing the previous key
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn set_export_encryption_key(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Json(req): Json<SetExportEncryptionKeyRequest>,
+) -> Result<Json<ExportEncryptionKey>> {
+    auth.ensure_account_admin(account.id()).await?;
+
+    let recipient = req.recipient.trim().to_lowercase();
+    if Recipient::parse(&recipient).is_none() {
+        bad_request!("Export encryption key must be an age X25519 recipient, e.g. `age1...`");
+    }
+
+    let key = accounts_db()
+        .await?
+        .query("UPDATE $account SET export_encryption_key = { version: (export_encryption_key.version ?? 0) + 1, recipient: $recipient, required: $required, created_at: time::now() } RETURN VALUE export_encryption_key")
+        .bind(("account", surrealdb::sql::Thing::from(&account)))
+        .bind(("recipient", recipient))
+        .bind(("required", req.required))
+        .await
+        .context("Failed to submit query to set account export encryption key")?
+        .check_first_real_error()
+        .context("Failed to set account export encryption key")?
+        .take::<Vec<ExportEncryptionKey>>(0)?
+        .pop()
+        .ok_or_else(|| anyhow!("Set export encryption key query should return the key"))?;
+
+    account_cache::invalidate();
+
+    info!(version = key.version, "Set export encryption key");
+
+    Ok(Json(key))
+}
+
+// Removes the export encryption key, so exports are no longer encrypted
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn delete_export_encryption_key(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+) -> Result<()> {
+    auth.ensure_account_admin(account.id()).await?;
+
+    accounts_db()
+        .await?
+        .query("UPDATE $account SET export_encryption_key = NONE RETURN NONE")
+        .bind(("account", surrealdb::sql::Thing::from(&account)))
+        .await
+        .context("Failed to submit query to delete account export encryption key")?
+        .check_first_real_error()
+        .context("Failed to delete account export encryption key")?;
+
+    account_cache::invalidate();
+
+    Ok(())
+}
+
+// X25519 public key of an age recipient, encoded as Bech32 with the `age` human readable part
+struct Recipient([u8; 32]);
+
+impl Recipient {
+    fn parse(recipient: &str) -> Option<Self> {
+        let (hrp, data) = recipient.rsplit_once('1')?;
+        if hrp != RECIPIENT_HRP || data.len() < 6 {
+            return None;
+        }
+
+        let values = data
+            .bytes()
+            .map(|char| {
+                BECH32_CHARSET
+                    .iter()
+                    .position(|&value| value == char)
+                    .and_then(|value| u8::try_from(value).ok())
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        let mut checksummed = hrp.bytes().map(|char| char >> 5).collect::<Vec<_>>();
+        checksummed.push(0);
+        checksummed.extend(hrp.bytes().map(|char| char & 31));
+        checksummed.extend(&values);
+        if bech32_polymod(&checksummed) != 1 {
+            return None;
+        }
+
+        // Regroups the 5-bit values, without their checksum, into bytes
+        let mut bytes = Vec::new();
+        let mut acc = 0u32;
+        let mut bits = 0;
+        for value in &values[..values.len() - 6] {
+            acc = (acc << 5) | u32::from(*value);
+            bits += 5;
+            if bits >= 8 {
+                bits -= 8;
+                bytes.push(((acc >> bits) & 0xff) as u8);
+            }
+        }
+        if bits >= 5 || (acc << (8 - bits)) & 0xff != 0 {
+            return None;
+        }
+
+        Some(Self(bytes.try_into().ok()?))
+    }
+}
+
+fn bech32_polymod(values: &[u8]) -> u32 {
+    const GENERATOR: [u32; 5] = [
+        0x3b6a_57b2,
+        0x2650_8e6d,
+        0x1ea1_19fa,
+        0x3d42_33dd,
+        0x2a14_62b3,
+    ];
+
+    values.iter().fold(1, |chk, value| {
+        let top = chk >> 25;
+        let chk = ((chk & 0x01ff_ffff) << 5) ^ u32::from(*value);
+        (0..5)
+            .filter(|i| (top >> i) & 1 == 1)
+            .fold(chk, |chk, i| chk ^ GENERATOR[i])
+    })
+}
+
+fn hkdf_sha256(salt: &[u8], ikm: &[u8], info: &[u8]) -> anyhow::Result<[u8; 32]> {
+    let mut okm = [0; 32];
+    hkdf::Salt::new(hkdf::HKDF_SHA256, salt)
+        .extract(ikm)
+        .expand(&[info], hkdf::HKDF_SHA256)
+        .and_then(|okm_expander| okm_expander.fill(&mut okm))
+        .map_err(|_| anyhow!("Failed to derive key"))?;
+    Ok(okm)
+}
+
+fn chacha20_poly1305_key(key: &[u8; 32]) -> anyhow::Result<LessSafeKey> {
+    Ok(LessSafeKey::new(
+        UnboundKey::new(&CHACHA20_POLY1305, key).map_err(|_| anyhow!("Invalid key"))?,
+    ))
+}
+
+// Encrypts a stream of plaintext for an age recipient. Chunks are only sealed once more plaintext follows them, as the
+// last chunk is sealed differently.
+pub(crate) struct Encryptor {
+    payload_key: LessSafeKey,
+    counter: u64,
+    buffer: Vec<u8>,
+}
+
+impl Encryptor {
+    // Returns the encryptor and the header and nonce that precede the payload
+    pub(crate) fn new(recipient: &str) -> anyhow::Result<(Self, Vec<u8>)> {
+        let recipient = Recipient::parse(recipient).context("Invalid age recipient")?;
+        let rng = SystemRandom::new();
+
+        let mut file_key = [0; FILE_KEY_BYTES];
+        rng.fill(&mut file_key)
+            .map_err(|_| anyhow!("Failed to generate file key"))?;
+
+        let ephemeral_key = EphemeralPrivateKey::generate(&X25519, &rng)
+            .map_err(|_| anyhow!("Failed to generate ephemeral key"))?;
+        let ephemeral_share = ephemeral_key
+            .compute_public_key()
+            .map_err(|_| anyhow!("Failed to compute ephemeral share"))?;
+        let shared_secret = agreement::agree_ephemeral(
+            ephemeral_key,
+            &UnparsedPublicKey::new(&X25519, recipient.0),
+            <[u8]>::to_vec,
+        )
+        .map_err(|_| anyhow!("Failed to agree on shared secret with recipient"))?;
+
+        let salt = [ephemeral_share.as_ref(), &recipient.0].concat();
+        let wrap_key = hkdf_sha256(&salt, &shared_secret, X25519_INFO)?;
+
+        let mut wrapped_file_key = file_key.to_vec();
+        chacha20_poly1305_key(&wrap_key)?
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key([0; aead::NONCE_LEN]),
+                Aad::empty(),
+                &mut wrapped_file_key,
+            )
+            .map_err(|_| anyhow!("Failed to wrap file key"))?;
+
+        let mut header = format!(
+            "{VERSION_LINE}\n-> X25519 {}\n{}\n---",
+            BASE64_STANDARD_NO_PAD.encode(ephemeral_share.as_ref()),
+            BASE64_STANDARD_NO_PAD.encode(&wrapped_file_key),
+        );
+
+        let mac_key = hkdf_sha256(&[], &file_key, b"header")?;
+        let mac = hmac::sign(
+            &hmac::Key::new(hmac::HMAC_SHA256, &mac_key),
+            header.as_bytes(),
+        );
+        header.push_str(&format!(" {}\n", BASE64_STANDARD_NO_PAD.encode(mac)));
+
+        let mut payload_nonce = [0; PAYLOAD_NONCE_BYTES];
+        rng.fill(&mut payload_nonce)
+            .map_err(|_| anyhow!("Failed to generate payload nonce"))?;
+
+        let payload_key = hkdf_sha256(&payload_nonce, &file_key, b"payload")?;
+
+        let mut preamble = header.into_bytes();
+        preamble.extend_from_slice(&payload_nonce);
+
+        Ok((
+            Self {
+                payload_key: chacha20_poly1305_key(&payload_key)?,
+                counter: 0,
+                buffer: Vec::new(),
+            },
+            preamble,
+        ))
+    }
+
+    // Returns the ciphertext of the chunks completed by the plaintext
+    pub(crate) fn update(&mut self, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.buffer.extend_from_slice(plaintext);
+
+        let mut ciphertext = Vec::new();
+        while self.buffer.len() > CHUNK_BYTES {
+            let chunk = self.buffer.drain(..CHUNK_BYTES).collect::<Vec<_>>();
+            ciphertext.extend(self.seal(chunk, false)?);
+        }
+
+        Ok(ciphertext)
+    }
+
+    // Returns the ciphertext of the last chunk
+    pub(crate) fn finish(mut self) -> anyhow::Result<Vec<u8>> {
+        let chunk = std::mem::take(&mut self.buffer);
+        self.seal(chunk, true)
+    }
+
+    fn seal(&mut self, mut chunk: Vec<u8>, last: bool) -> anyhow::Result<Vec<u8>> {
+        // 11 byte big-endian chunk counter followed by a flag marking the last chunk
+        let mut nonce = [0; aead::NONCE_LEN];
+        nonce[3..11].copy_from_slice(&self.counter.to_be_bytes());
+        nonce[11] = u8::from(last);
+
+        self.payload_key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut chunk,
+            )
+            .map_err(|_| anyhow!("Failed to encrypt export chunk"))?;
+
+        self.counter += 1;
+
+        Ok(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read as _;
+
+    use super::{CHUNK_BYTES, Encryptor};
+
+    fn encrypt(recipient: &str, plaintext: &[u8], write_bytes: usize) -> Vec<u8> {
+        let (mut encryptor, mut ciphertext) =
+            Encryptor::new(recipient).expect("Recipient should be valid");
+
+        for write in plaintext.chunks(write_bytes) {
+            ciphertext.extend(encryptor.update(write).expect("Chunk should encrypt"));
+        }
+        ciphertext.extend(encryptor.finish().expect("Last chunk should encrypt"));
+
+        ciphertext
+    }
+
+    fn decrypt(identity: &age::x25519::Identity, ciphertext: &[u8]) -> Vec<u8> {
+        let mut plaintext = Vec::new();
+
+        age::Decryptor::new(ciphertext)
+            .expect("Header should be valid")
+            .decrypt(std::iter::once(identity as &dyn age::Identity))
+            .expect("File key should unwrap")
+            .read_to_end(&mut plaintext)
+            .expect("Payload should decrypt");
+
+        plaintext
+    }
+
+    // Exports must be readable by the reference age implementation, including payloads that end on a chunk boundary
+    #[test]
+    fn age_round_trip() {
+        let identity = age::x25519::Identity::generate();
+        let recipient = identity.to_public().to_string();
+
+        for (len, write_bytes) in [
+            (0, 1),
+            (17, 5),
+            (CHUNK_BYTES - 1, 4096),
+            (CHUNK_BYTES, CHUNK_BYTES),
+            (CHUNK_BYTES + 1, 1000),
+            (2 * CHUNK_BYTES + 17, 3 * CHUNK_BYTES),
+        ] {
+            let plaintext = (0..len).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+            assert_eq!(
+                decrypt(&identity, &encrypt(&recipient, &plaintext, write_bytes)),
+                plaintext,
+                "{len} byte export should round trip"
+            );
+        }
+    }
+
+    #[test]
+    fn other_identity() {
+        let recipient = age::x25519::Identity::generate().to_public().to_string();
+        let ciphertext = encrypt(&recipient, b"{}", 2);
+
+        assert!(
+            age::Decryptor::new(ciphertext.as_slice())
+                .expect("Header should be valid")
+                .decrypt(std::iter::once(
+                    &age::x25519::Identity::generate() as &dyn age::Identity
+                ))
+                .is_err()
+        );
+    }
+}
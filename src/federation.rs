@@ -0,0 +1,128 @@
+use std::{collections::HashMap, sync::LazyLock, time::Duration};
+
+use axum::{
+    Extension,
+    extract::{Path, RawQuery},
+    http::{StatusCode, header::CONTENT_TYPE},
+    response::{IntoResponse as _, Response},
+};
+use serde::Deserialize;
+use tracing::{instrument, warn};
+
+use archodex_error::{
+    PublicError,
+    anyhow::{Context as _, bail},
+    not_found,
+};
+
+use crate::{
+    Result,
+    account::{Account, is_well_formed_account_id},
+    account_link::{FEDERATED_ROUTES, FEDERATION_TOKEN_PREFIX, account_link_thing, account_thing},
+    db::{QueryCheckFirstRealError as _, accounts_db},
+    outbound::{self, AllowedAddresses},
+};
+
+const FEDERATED_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+static CLIENT: LazyLock<reqwest::Client> =
+    LazyLock::new(|| outbound::client(FEDERATED_REQUEST_TIMEOUT, AllowedAddresses::Public));
+
+#[derive(Deserialize)]
+struct FederationTarget {
+    federation_endpoint: String,
+    federation_token: String,
+}
+
+// Proxies a read-only dashboard request to an account of a linked self-hosted backend. The user's own credentials are
+// never forwarded. Requests are authenticated with the link's federation credential instead, which the self-hosted
+// backend only accepts for the read-only routes in `FEDERATED_ROUTES`. Federation is only enabled for the endpoint
+// allowed when the link was created.
+#[instrument(err, skip(account))]
+pub(crate) async fn federated_get(
+    Extension(account): Extension<Account>,
+    Path(params): Path<HashMap<String, String>>,
+    RawQuery(query): RawQuery,
+) -> Result<Response> {
+    let (Some(account_link_id), Some(linked_account_id), Some(path)) = (
+        params.get("account_link_id"),
+        params.get("linked_account_id"),
+        params.get("path"),
+    ) else {
+        bail!("Missing federated request path parameters");
+    };
+
+    if !FEDERATED_ROUTES.contains(&path.as_str()) {
+        not_found!("Federated path not found");
+    }
+
+    if !is_well_formed_account_id(linked_account_id) {
+        not_found!("Federated account not found");
+    }
+
+    let Some(target) = accounts_db()
+        .await?
+        .query("SELECT federation_endpoint, federation_token FROM $account_link WHERE account = $account AND revoked_at IS NONE AND federation_endpoint != NONE AND federation_endpoint = allowed_federation_endpoint AND federation_token != NONE AND $linked_account_id INSIDE linked_accounts.account_id")
+        .bind(("account_link", account_link_thing(account_link_id)))
+        .bind(("account", account_thing(&account)))
+        .bind(("linked_account_id", linked_account_id.to_owned()))
+        .await
+        .context("Failed to submit query to get account link federation endpoint")?
+        .check_first_real_error()
+        .context("Failed to get account link federation endpoint")?
+        .take::<Vec<FederationTarget>>(0)?
+        .into_iter()
+        .next()
+    else {
+        not_found!("Federated account not found");
+    };
+
+    let mut url = format!(
+        "{}/account/{linked_account_id}/{path}",
+        target.federation_endpoint.trim_end_matches('/')
+    );
+    if let Some(query) = query {
+        url.push('?');
+        url.push_str(&query);
+    }
+
+    let url = outbound::check_url(&url, &["https"], AllowedAddresses::Public)
+        .context("Federation endpoint is no longer allowed")?;
+
+    let request = CLIENT.get(url).bearer_auth(format!(
+        "{FEDERATION_TOKEN_PREFIX}{}",
+        target.federation_token
+    ));
+
+    let response = match request.send().await {
+        Ok(response) => response,
+        Err(err) => {
+            warn!(?err, "Failed to send federated request");
+            return Err(PublicError::new(
+                StatusCode::BAD_GATEWAY,
+                "Linked self-hosted backend is unreachable",
+            ));
+        }
+    };
+
+    let status = response.status();
+    let content_type = response.headers().get(CONTENT_TYPE).cloned();
+
+    let body = match response.bytes().await {
+        Ok(body) => body,
+        Err(err) => {
+            warn!(?err, "Failed to receive federated response");
+            return Err(PublicError::new(
+                StatusCode::BAD_GATEWAY,
+                "Linked self-hosted backend is unreachable",
+            ));
+        }
+    };
+
+    let mut response = (status, body).into_response();
+    if let Some(content_type) = content_type {
+        response.headers_mut().insert(CONTENT_TYPE, content_type);
+    }
+
+    Ok(response)
+}
@@ -0,0 +1,433 @@
+//! Representative synthetic datasets of reports, shared by tooling that needs realistic data to ingest. Each dataset is
+//! built programmatically and deterministically, so the same dataset always produces the same reports. Reports are in
+//! the `/report` request format, and [`Dataset::ndjson`] renders a dataset in the `/report/batch` format with one
+//! report per line. Rendered datasets are checked in under `fixtures/` and regenerated with
+//! `cargo run -p archodex-backend --example generate_fixtures --features fixtures`.
+
+use serde_json::{Value, json};
+
+const FIRST_SEEN_AT: &str = "2025-01-01T00:00:00Z";
+const LAST_SEEN_AT: &str = "2025-01-31T00:00:00Z";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Dataset {
+    /// A single AWS account running a small serverless SaaS application deployed from one GitHub repository
+    SmallSaas,
+    /// An AWS organization with many accounts and regions, whose workloads access databases, queues, and secrets
+    EnterpriseCloud,
+    /// A GitHub organization with many repositories whose workflows deploy to AWS and read secrets
+    CiHeavy,
+}
+
+impl Dataset {
+    pub const ALL: [Dataset; 3] = [
+        Dataset::SmallSaas,
+        Dataset::EnterpriseCloud,
+        Dataset::CiHeavy,
+    ];
+
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Dataset::SmallSaas => "small_saas",
+            Dataset::EnterpriseCloud => "enterprise_cloud",
+            Dataset::CiHeavy => "ci_heavy",
+        }
+    }
+
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|dataset| dataset.name() == name)
+    }
+
+    /// Builds the reports of the dataset
+    #[must_use]
+    pub fn reports(self) -> Vec<Value> {
+        match self {
+            Dataset::SmallSaas => small_saas(),
+            Dataset::EnterpriseCloud => enterprise_cloud(),
+            Dataset::CiHeavy => ci_heavy(),
+        }
+    }
+
+    /// Renders the reports of the dataset as NDJSON, one report per line
+    #[must_use]
+    pub fn ndjson(self) -> String {
+        self.reports()
+            .iter()
+            .map(|report| format!("{report}\n"))
+            .collect()
+    }
+}
+
+fn part(r#type: &str, id: impl ToString) -> Value {
+    json!({ "type": r#type, "id": id.to_string() })
+}
+
+fn node(r#type: &str, id: impl ToString, contains: Vec<Value>) -> Value {
+    json!({
+        "type": r#type,
+        "id": id.to_string(),
+        "first_seen_at": FIRST_SEEN_AT,
+        "last_seen_at": LAST_SEEN_AT,
+        "contains": contains,
+    })
+}
+
+fn principal(id: Vec<Value>, event: Option<&str>) -> Value {
+    json!({ "id": id, "event": event })
+}
+
+fn event_capture(
+    principals: Vec<Value>,
+    resources: Vec<Vec<Value>>,
+    event_types: &[&str],
+) -> Value {
+    json!({
+        "principals": principals,
+        "resources": resources,
+        "events": event_types
+            .iter()
+            .map(|r#type| json!({
+                "type": r#type,
+                "first_seen_at": FIRST_SEEN_AT,
+                "last_seen_at": LAST_SEEN_AT,
+            }))
+            .collect::<Vec<_>>(),
+    })
+}
+
+fn report(resource_captures: Vec<Value>, event_captures: Vec<Value>) -> Value {
+    json!({
+        "resource_captures": resource_captures,
+        "event_captures": event_captures,
+    })
+}
+
+fn aws_region_id(account_id: &str, region: &str) -> Vec<Value> {
+    vec![
+        part("AWS Partition", "aws"),
+        part("AWS Account", account_id),
+        part("AWS Region", region),
+    ]
+}
+
+fn with(mut id: Vec<Value>, r#type: &str, resource_id: impl ToString) -> Vec<Value> {
+    id.push(part(r#type, resource_id));
+    id
+}
+
+fn github_repository_id(org: &str, repository: &str) -> Vec<Value> {
+    vec![
+        part("GitHub Service", "github.com"),
+        part("GitHub Organization", org),
+        part("Git Repository", repository),
+    ]
+}
+
+fn small_saas() -> Vec<Value> {
+    const ACCOUNT_ID: &str = "111122223333";
+    const REGION: &str = "us-east-1";
+    const FUNCTIONS: [&str; 3] = ["api", "billing", "notifications"];
+    const TABLES: [&str; 2] = ["users", "subscriptions"];
+    const PARAMETERS: [&str; 2] = ["/prod/stripe-api-key", "/prod/sendgrid-api-key"];
+
+    let region_id = aws_region_id(ACCOUNT_ID, REGION);
+
+    let mut region_resources = FUNCTIONS
+        .iter()
+        .map(|function| node("Lambda Function", function, vec![]))
+        .collect::<Vec<_>>();
+    region_resources.extend(
+        TABLES
+            .iter()
+            .map(|table| node("DynamoDB Table", table, vec![])),
+    );
+    region_resources.extend(
+        PARAMETERS
+            .iter()
+            .map(|parameter| node("SSM Parameter", parameter, vec![])),
+    );
+
+    let resource_captures = vec![
+        node(
+            "AWS Partition",
+            "aws",
+            vec![node(
+                "AWS Account",
+                ACCOUNT_ID,
+                vec![
+                    node("AWS Region", REGION, region_resources),
+                    node("IAM Role", "github-deploy", vec![]),
+                ],
+            )],
+        ),
+        node(
+            "GitHub Service",
+            "github.com",
+            vec![node(
+                "GitHub Organization",
+                "acme-saas",
+                vec![node(
+                    "Git Repository",
+                    "app",
+                    vec![node("GitHub Actions Workflow", "deploy.yaml", vec![])],
+                )],
+            )],
+        ),
+    ];
+
+    let deploy_chain = vec![
+        principal(
+            with(
+                github_repository_id("acme-saas", "app"),
+                "GitHub Actions Workflow",
+                "deploy.yaml",
+            ),
+            None,
+        ),
+        principal(
+            vec![
+                part("AWS Partition", "aws"),
+                part("AWS Account", ACCOUNT_ID),
+                part("IAM Role", "github-deploy"),
+            ],
+            Some("Assumed"),
+        ),
+    ];
+
+    let mut event_captures = vec![event_capture(
+        deploy_chain,
+        FUNCTIONS
+            .iter()
+            .map(|function| with(region_id.clone(), "Lambda Function", function))
+            .collect(),
+        &["lambda:UpdateFunctionCode"],
+    )];
+
+    for function in FUNCTIONS {
+        event_captures.push(event_capture(
+            vec![principal(
+                with(region_id.clone(), "Lambda Function", function),
+                None,
+            )],
+            TABLES
+                .iter()
+                .map(|table| with(region_id.clone(), "DynamoDB Table", table))
+                .collect(),
+            &["dynamodb:GetItem", "dynamodb:PutItem"],
+        ));
+    }
+
+    event_captures.push(event_capture(
+        vec![principal(
+            with(region_id.clone(), "Lambda Function", "billing"),
+            None,
+        )],
+        vec![with(
+            region_id.clone(),
+            "SSM Parameter",
+            "/prod/stripe-api-key",
+        )],
+        &["ssm:GetParameter"],
+    ));
+
+    event_captures.push(event_capture(
+        vec![principal(
+            with(region_id.clone(), "Lambda Function", "notifications"),
+            None,
+        )],
+        vec![with(region_id, "SSM Parameter", "/prod/sendgrid-api-key")],
+        &["ssm:GetParameter"],
+    ));
+
+    vec![report(resource_captures, event_captures)]
+}
+
+// One report per AWS account, as sent by an agent deployed in each account
+fn enterprise_cloud() -> Vec<Value> {
+    const ACCOUNTS: usize = 12;
+    const REGIONS: [&str; 4] = ["us-east-1", "us-west-2", "eu-west-1", "ap-southeast-2"];
+    const SERVICES: usize = 6;
+
+    (0..ACCOUNTS)
+        .map(|account| {
+            let account_id = format!("{:012}", 100_000_000_000 + account);
+
+            let mut event_captures = Vec::new();
+
+            let regions = REGIONS
+                .iter()
+                .map(|region| {
+                    let region_id = aws_region_id(&account_id, region);
+
+                    let mut resources = Vec::new();
+
+                    for service in 0..SERVICES {
+                        let role = format!("service-{service}");
+                        let table = format!("service-{service}-state");
+                        let queue = format!("service-{service}-jobs");
+                        let secret = format!("service-{service}/database-password");
+
+                        resources.push(node("DynamoDB Table", &table, vec![]));
+                        resources.push(node("SQS Queue", &queue, vec![]));
+                        resources.push(node("Secrets Manager Secret", &secret, vec![]));
+
+                        event_captures.push(event_capture(
+                            vec![principal(
+                                vec![
+                                    part("AWS Partition", "aws"),
+                                    part("AWS Account", &account_id),
+                                    part("IAM Role", &role),
+                                ],
+                                None,
+                            )],
+                            vec![
+                                with(region_id.clone(), "DynamoDB Table", &table),
+                                with(region_id.clone(), "SQS Queue", &queue),
+                            ],
+                            &["dynamodb:Query", "sqs:SendMessage"],
+                        ));
+
+                        event_captures.push(event_capture(
+                            vec![principal(
+                                vec![
+                                    part("AWS Partition", "aws"),
+                                    part("AWS Account", &account_id),
+                                    part("IAM Role", &role),
+                                ],
+                                None,
+                            )],
+                            vec![with(region_id.clone(), "Secrets Manager Secret", &secret)],
+                            &["secretsmanager:GetSecretValue"],
+                        ));
+                    }
+
+                    node("AWS Region", region, resources)
+                })
+                .collect::<Vec<_>>();
+
+            let mut account_resources = regions;
+            account_resources.extend(
+                (0..SERVICES).map(|service| node("IAM Role", format!("service-{service}"), vec![])),
+            );
+
+            report(
+                vec![node(
+                    "AWS Partition",
+                    "aws",
+                    vec![node("AWS Account", &account_id, account_resources)],
+                )],
+                event_captures,
+            )
+        })
+        .collect()
+}
+
+// One report per repository, as sent by an agent observing the repository's workflow runs
+fn ci_heavy() -> Vec<Value> {
+    const ORG: &str = "acme-platform";
+    const REPOSITORIES: usize = 40;
+    const WORKFLOWS: [&str; 3] = ["ci.yaml", "release.yaml", "deploy.yaml"];
+    const ACCOUNT_ID: &str = "444455556666";
+
+    (0..REPOSITORIES)
+        .map(|repository| {
+            let repository = format!("service-{repository:02}");
+            let repository_id = github_repository_id(ORG, &repository);
+
+            let workflows = WORKFLOWS
+                .iter()
+                .map(|workflow| node("GitHub Actions Workflow", workflow, vec![]))
+                .collect::<Vec<_>>();
+
+            let secrets = ["NPM_TOKEN", "DOCKERHUB_TOKEN"]
+                .iter()
+                .map(|secret| node("GitHub Actions Secret", secret, vec![]))
+                .collect::<Vec<_>>();
+
+            let mut repository_resources = workflows;
+            repository_resources.extend(secrets);
+
+            let mut event_captures = Vec::new();
+
+            for workflow in WORKFLOWS {
+                event_captures.push(event_capture(
+                    vec![principal(
+                        with(repository_id.clone(), "GitHub Actions Workflow", workflow),
+                        None,
+                    )],
+                    vec![
+                        with(repository_id.clone(), "GitHub Actions Secret", "NPM_TOKEN"),
+                        with(
+                            repository_id.clone(),
+                            "GitHub Actions Secret",
+                            "DOCKERHUB_TOKEN",
+                        ),
+                    ],
+                    &["Read"],
+                ));
+            }
+
+            let deploy_role = format!("{repository}-deploy");
+
+            event_captures.push(event_capture(
+                vec![
+                    principal(
+                        with(
+                            repository_id.clone(),
+                            "GitHub Actions Workflow",
+                            "deploy.yaml",
+                        ),
+                        None,
+                    ),
+                    principal(
+                        vec![
+                            part("AWS Partition", "aws"),
+                            part("AWS Account", ACCOUNT_ID),
+                            part("IAM Role", &deploy_role),
+                        ],
+                        Some("Assumed"),
+                    ),
+                ],
+                vec![with(
+                    aws_region_id(ACCOUNT_ID, "us-east-1"),
+                    "ECS Service",
+                    &repository,
+                )],
+                &["ecs:UpdateService"],
+            ));
+
+            report(
+                vec![
+                    node(
+                        "GitHub Service",
+                        "github.com",
+                        vec![node(
+                            "GitHub Organization",
+                            ORG,
+                            vec![node("Git Repository", &repository, repository_resources)],
+                        )],
+                    ),
+                    node(
+                        "AWS Partition",
+                        "aws",
+                        vec![node(
+                            "AWS Account",
+                            ACCOUNT_ID,
+                            vec![
+                                node("IAM Role", &deploy_role, vec![]),
+                                node(
+                                    "AWS Region",
+                                    "us-east-1",
+                                    vec![node("ECS Service", &repository, vec![])],
+                                ),
+                            ],
+                        )],
+                    ),
+                ],
+                event_captures,
+            )
+        })
+        .collect()
+}
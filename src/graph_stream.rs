@@ -0,0 +1,235 @@
+// The dashboard streams `GET /account/:account_id/stream` to update its views of an account's resource graph as reports
+// are ingested, rather than polling. Each ingested report publishes a `report` event summarizing what it added or
+// modified, including a consistency token the dashboard passes back so its follow-up queries reflect the report.
+//
+// Updates are relayed to subscribers over a broadcast channel per account, registered in memory while the account has
+// subscribers. With the `live-query` feature, reports publish updates to the `graph_update:latest` record of the
+// account's resources database, and each backend instance with subscribers for the account relays changes to it with a
+// live query, so subscribers receive updates for reports ingested by any instance. Otherwise, and for embedded RocksDB
+// databases, which can't be held open for a live query, updates are only published to subscribers of the ingesting
+// instance. Subscribers that fall behind by more than `CHANNEL_CAPACITY` updates receive a `lagged` event instead of
+// the updates they missed, and should refetch what they display.
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::{LazyLock, Mutex},
+};
+
+use axum::{
+    Extension,
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures::{Stream, StreamExt as _, stream};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::instrument;
+
+use crate::{account::Account, db::DBConnection, shutdown};
+
+const CHANNEL_CAPACITY: usize = 64;
+
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
+pub(crate) struct GraphUpdate {
+    // Number of resources the report added or modified
+    pub(crate) resources: u64,
+    // Number of event relations the report added or modified
+    pub(crate) events: u64,
+    // Types of the events the report recorded
+    pub(crate) event_types: Vec<String>,
+    // Passed back in the `X-Consistency-Token` header of queries that must reflect the report, see `consistency`
+    pub(crate) consistency_token: String,
+}
+
+static CHANNELS: LazyLock<Mutex<HashMap<String, broadcast::Sender<GraphUpdate>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn subscribe(account: &Account) -> broadcast::Receiver<GraphUpdate> {
+    let mut channels = CHANNELS.lock().expect("Graph stream mutex poisoned");
+
+    channels.retain(|_, sender| sender.receiver_count() > 0);
+
+    if let Some(sender) = channels.get(account.id()) {
+        return sender.subscribe();
+    }
+
+    let sender = broadcast::Sender::new(CHANNEL_CAPACITY);
+    let receiver = sender.subscribe();
+
+    #[cfg(feature = "live-query")]
+    if relayed(account) {
+        tokio::spawn(live_query::relay(account.clone(), sender.clone()));
+    }
+
+    channels.insert(account.id().to_owned(), sender);
+
+    receiver
+}
+
+// Whether updates for the account are relayed from its resources database with a live query
+#[cfg(feature = "live-query")]
+fn relayed(account: &Account) -> bool {
+    account
+        .resources_db_url()
+        .is_ok_and(|url| !url.starts_with("rocksdb:"))
+}
+
+// Whether every subscriber of the channel has disconnected, in which case the channel is unregistered so the relay can
+// stop. Checked with the registry locked, so a subscriber can't join the channel after its relay stopped.
+#[cfg(feature = "live-query")]
+fn unsubscribed(account_id: &str, sender: &broadcast::Sender<GraphUpdate>) -> bool {
+    let mut channels = CHANNELS.lock().expect("Graph stream mutex poisoned");
+
+    if sender.receiver_count() > 0 {
+        return false;
+    }
+
+    if channels
+        .get(account_id)
+        .is_some_and(|registered| registered.same_channel(sender))
+    {
+        channels.remove(account_id);
+    }
+
+    true
+}
+
+fn publish_locally(account_id: &str, update: GraphUpdate) {
+    let mut channels = CHANNELS.lock().expect("Graph stream mutex poisoned");
+
+    let Some(sender) = channels.get(account_id) else {
+        return;
+    };
+
+    if sender.send(update).is_err() {
+        // Every subscriber has disconnected
+        channels.remove(account_id);
+    }
+}
+
+// Must be called after the report has been committed. Failing to publish an update doesn't fail the report, subscribers
+// will see its changes the next time they refetch.
+pub(crate) async fn publish(account: &Account, db: &DBConnection, update: GraphUpdate) {
+    #[cfg(feature = "live-query")]
+    if relayed(account) {
+        if let Err(err) = db
+            .query("UPSERT graph_update:latest CONTENT $update RETURN NONE;")
+            .bind(("update", update))
+            .await
+            .and_then(surrealdb::Response::check)
+        {
+            tracing::warn!(?err, "Failed to publish graph update");
+        }
+
+        return;
+    }
+
+    #[cfg(not(feature = "live-query"))]
+    let _ = db;
+
+    publish_locally(account.id(), update);
+}
+
+#[instrument(skip_all, fields(account_id = account.id()))]
+pub(crate) async fn stream(
+    Extension(account): Extension<Account>,
+) -> Sse<impl Stream<Item = std::result::Result<Event, Infallible>>> {
+    let receiver = subscribe(&account);
+
+    let events = stream::unfold(receiver, |mut receiver| async move {
+        let event = match receiver.recv().await {
+            Ok(update) => Event::default()
+                .event("report")
+                .json_data(&update)
+                .unwrap_or_else(|_| Event::default().event("lagged")),
+            Err(RecvError::Lagged(missed)) => {
+                Event::default().event("lagged").data(missed.to_string())
+            }
+            // The sender is kept in the registry while it has receivers, so this doesn't happen
+            Err(RecvError::Closed) => return None,
+        };
+
+        Some((Ok(event), receiver))
+    })
+    .take_until(shutdown::initiated());
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+#[cfg(feature = "live-query")]
+mod live_query {
+    use std::time::Duration;
+
+    use futures::StreamExt as _;
+    use surrealdb::Action;
+    use tokio::sync::broadcast;
+    use tracing::{info, instrument, warn};
+
+    use super::{GraphUpdate, unsubscribed};
+    use crate::{
+        Result,
+        account::Account,
+        db::{DBConnection, open_resources_db_connection, verify_resources_db_account},
+    };
+
+    const RETRY_DELAY: Duration = Duration::from_secs(10);
+    // How often the relay checks whether the account still has subscribers while no updates arrive
+    const SUBSCRIBER_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+    // Relays updates to the account's subscribers until they have all disconnected
+    #[instrument(skip_all, fields(account_id = account.id()))]
+    pub(super) async fn relay(account: Account, sender: broadcast::Sender<GraphUpdate>) {
+        loop {
+            if let Err(err) = watch(&account, &sender).await {
+                warn!(
+                    ?err,
+                    "Failed to run graph update live query, retrying after a delay"
+                );
+            }
+
+            if unsubscribed(account.id(), &sender) {
+                break;
+            }
+
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+    }
+
+    async fn watch(account: &Account, sender: &broadcast::Sender<GraphUpdate>) -> Result<()> {
+        // Live query notifications are only delivered to connections that enable them, so the relay uses a dedicated
+        // connection rather than one from the resources database pool
+        let db = DBConnection::Concurrent(
+            open_resources_db_connection(account.resources_db_url()?, account.id(), true).await?,
+        );
+        verify_resources_db_account(&db, account.id()).await?;
+
+        let mut notifications = db.select::<Vec<GraphUpdate>>("graph_update").live().await?;
+
+        info!("Graph update live query started");
+
+        let mut subscriber_check = tokio::time::interval(SUBSCRIBER_CHECK_INTERVAL);
+
+        loop {
+            tokio::select! {
+                notification = notifications.next() => {
+                    let Some(notification) = notification else {
+                        return Ok(());
+                    };
+
+                    let notification = notification?;
+
+                    // Sending fails while every subscriber has disconnected, which the next check handles
+                    if notification.action != Action::Delete {
+                        let _ = sender.send(notification.data);
+                    }
+                }
+                _ = subscriber_check.tick() => {
+                    if sender.receiver_count() == 0 {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+}
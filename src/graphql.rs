@@ -0,0 +1,499 @@
+// GraphQL API over an account's resource graph, served at `POST /account/:account_id/graphql`. Dashboard views that
+// need resources together with their containment, events, and principal chains fetch exactly that shape in a single
+// request rather than one REST request per relation. The schema only has queries, so GraphQL requests never write.
+//
+// Each relation is resolved with its own query, so the complexity of a request is bounded by counting every resource or
+// event a list field may return. The schema is served at `GET /graphql/schema.graphql` for client code generation.
+
+use std::sync::LazyLock;
+
+use async_graphql::{
+    Context, EmptyMutation, EmptySubscription, Enum, InputObject, Object, Schema, SimpleObject,
+};
+use axum::{Extension, Json, http::header::CONTENT_TYPE, response::IntoResponse};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use tracing::instrument;
+
+use archodex_error::bad_request;
+
+use crate::{
+    account::Account,
+    db::QueryCheckFirstRealError as _,
+    event,
+    principal_chain::{DEFAULT_TRAVERSAL_DEPTH, PrincipalChainId, Traversal, TraversalDirection},
+    resource::{
+        self, ListResourcesRequest, RESOURCE_FIELDS, ResourceId, ResourceIdPart,
+        surrealdb_thing_from_resource_id,
+    },
+};
+
+const MAX_QUERY_DEPTH: usize = 12;
+const MAX_QUERY_COMPLEXITY: usize = 10_000;
+const DEFAULT_LIST_LIMIT: usize = 100;
+const MAX_LIST_LIMIT: usize = 1000;
+
+type GraphQLSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+static SCHEMA: LazyLock<GraphQLSchema> = LazyLock::new(|| {
+    Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
+        .limit_depth(MAX_QUERY_DEPTH)
+        .limit_complexity(MAX_QUERY_COMPLEXITY)
+        .finish()
+});
+
+#[instrument(skip_all)]
+pub(crate) async fn graphql(
+    Extension(account): Extension<Account>,
+    Json(req): Json<async_graphql::Request>,
+) -> Json<async_graphql::Response> {
+    Json(SCHEMA.execute(req.data(account)).await)
+}
+
+pub(crate) async fn schema() -> impl IntoResponse {
+    ([(CONTENT_TYPE, "application/graphql")], SCHEMA.sdl())
+}
+
+fn list_limit(first: usize) -> crate::Result<usize> {
+    if first == 0 || first > MAX_LIST_LIMIT {
+        bad_request!("`first` must be between 1 and {MAX_LIST_LIMIT}");
+    }
+
+    Ok(first)
+}
+
+async fn load_resources(account: &Account, ids: Vec<ResourceId>) -> crate::Result<Vec<Resource>> {
+    Ok(account
+        .resources_db()
+        .await?
+        .query(format!("SELECT {RESOURCE_FIELDS} FROM $resources"))
+        .bind((
+            "resources",
+            ids.into_iter()
+                .map(surrealdb_thing_from_resource_id)
+                .collect::<Vec<_>>(),
+        ))
+        .await?
+        .check_first_real_error()?
+        .take::<Vec<resource::Resource>>(0)?
+        .into_iter()
+        .map(Resource)
+        .collect())
+}
+
+async fn load_resource(account: &Account, id: ResourceId) -> crate::Result<Option<Resource>> {
+    Ok(load_resources(account, vec![id]).await?.pop())
+}
+
+async fn load_contained_resources(
+    account: &Account,
+    id: ResourceId,
+    limit: usize,
+) -> crate::Result<Vec<Resource>> {
+    Ok(account
+        .resources_db()
+        .await?
+        .query(format!("SELECT {RESOURCE_FIELDS} FROM array::flatten((SELECT VALUE ->contains->resource FROM $resource)) ORDER BY id LIMIT $limit"))
+        .bind(("resource", surrealdb_thing_from_resource_id(id)))
+        .bind(("limit", limit))
+        .await?
+        .check_first_real_error()?
+        .take::<Vec<resource::Resource>>(0)?
+        .into_iter()
+        .map(Resource)
+        .collect())
+}
+
+async fn load_events(
+    account: &Account,
+    id: ResourceId,
+    direction: EventDirection,
+    event_types: Option<Vec<String>>,
+    limit: usize,
+) -> crate::Result<Vec<Event>> {
+    Ok(Traversal {
+        depth: 1,
+        event_types,
+        max_events: limit,
+        ..Traversal::new(id, direction.into())
+    }
+    .run(account)
+    .await?
+    .events
+    .into_iter()
+    .map(|traversed| Event(traversed.event))
+    .collect())
+}
+
+#[derive(Clone, Copy, Enum, Eq, PartialEq)]
+enum EventDirection {
+    // Events of the resource acting as a principal on other resources
+    Downstream,
+    // Events of principals acting on the resource
+    Upstream,
+}
+
+impl From<EventDirection> for TraversalDirection {
+    fn from(direction: EventDirection) -> Self {
+        match direction {
+            EventDirection::Downstream => TraversalDirection::Downstream,
+            EventDirection::Upstream => TraversalDirection::Upstream,
+        }
+    }
+}
+
+#[derive(InputObject)]
+struct ResourceIdPartInput {
+    r#type: String,
+    id: String,
+}
+
+fn resource_id(parts: Vec<ResourceIdPartInput>) -> ResourceId {
+    parts
+        .into_iter()
+        .map(|ResourceIdPartInput { r#type, id }| ResourceIdPart { r#type, id })
+        .collect()
+}
+
+#[derive(SimpleObject)]
+#[graphql(name = "ResourceIdPart")]
+struct ResourceIdPartObject {
+    r#type: String,
+    id: String,
+}
+
+fn resource_id_parts(id: &ResourceId) -> Vec<ResourceIdPartObject> {
+    id.iter()
+        .map(|part| ResourceIdPartObject {
+            r#type: part.r#type.clone(),
+            id: part.id.clone(),
+        })
+        .collect()
+}
+
+struct QueryRoot;
+
+#[Object(name = "Query")]
+impl QueryRoot {
+    async fn resource(
+        &self,
+        ctx: &Context<'_>,
+        id: Vec<ResourceIdPartInput>,
+    ) -> async_graphql::Result<Option<Resource>> {
+        Ok(load_resource(ctx.data::<Account>()?, resource_id(id)).await?)
+    }
+
+    // Pages through resources in resource ID order, like `GET /account/:account_id/resources`
+    #[graphql(complexity = "first * child_complexity")]
+    #[allow(clippy::too_many_arguments)]
+    async fn resources(
+        &self,
+        ctx: &Context<'_>,
+        resource_type: Option<String>,
+        environment: Option<String>,
+        tag: Option<String>,
+        #[graphql(default = false)] include_archived: bool,
+        #[graphql(default_with = "DEFAULT_LIST_LIMIT")] first: usize,
+        after: Option<String>,
+    ) -> async_graphql::Result<ResourcePage> {
+        let limit = list_limit(first)?;
+
+        let page = resource::list(
+            ctx.data::<Account>()?,
+            ListResourcesRequest {
+                resource_type,
+                environment,
+                tag,
+                include_archived,
+                limit: Some(u32::try_from(limit)?),
+                cursor: after,
+                ..ListResourcesRequest::default()
+            },
+        )
+        .await?;
+
+        Ok(ResourcePage {
+            nodes: page.resources.into_iter().map(Resource).collect(),
+            next_cursor: page.next_cursor,
+        })
+    }
+
+    // Principal chain IDs are JSON encoded, like the `id` parameter of `GET /account/:account_id/principal_chain`
+    async fn principal_chain(
+        &self,
+        ctx: &Context<'_>,
+        id: String,
+    ) -> async_graphql::Result<Option<PrincipalChain>> {
+        let id: PrincipalChainId = match serde_json::from_str(&id) {
+            Ok(id) => id,
+            Err(err) => bad_request!("Invalid principal chain `id`: {err}"),
+        };
+
+        let chain = PrincipalChain(id);
+
+        Ok(chain.observed(ctx.data::<Account>()?).await?.map(|_| chain))
+    }
+}
+
+#[derive(SimpleObject)]
+struct ResourcePage {
+    nodes: Vec<Resource>,
+    // Passed as `after` to fetch the next page, if there is one
+    next_cursor: Option<String>,
+}
+
+struct Resource(resource::Resource);
+
+#[Object]
+impl Resource {
+    async fn id(&self) -> Vec<ResourceIdPartObject> {
+        resource_id_parts(&self.0.id)
+    }
+
+    async fn environments(&self) -> Vec<String> {
+        let mut environments = self.0.environments.iter().cloned().collect::<Vec<_>>();
+        environments.sort();
+        environments
+    }
+
+    async fn tags(&self) -> Vec<String> {
+        let mut tags = self.0.tags.iter().cloned().collect::<Vec<_>>();
+        tags.sort();
+        tags
+    }
+
+    async fn first_seen_at(&self) -> Option<DateTime<Utc>> {
+        self.0.first_seen_at
+    }
+
+    async fn last_seen_at(&self) -> Option<DateTime<Utc>> {
+        self.0.last_seen_at
+    }
+
+    async fn archived_at(&self) -> Option<DateTime<Utc>> {
+        self.0.archived_at
+    }
+
+    async fn gone_at(&self) -> Option<DateTime<Utc>> {
+        self.0.gone_at
+    }
+
+    // The resource containing this resource, if it is not a top-level resource
+    async fn container(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<Resource>> {
+        let Some(container) = self.0.id.container() else {
+            return Ok(None);
+        };
+
+        Ok(load_resource(ctx.data::<Account>()?, container).await?)
+    }
+
+    // Resources directly contained by this resource
+    #[graphql(complexity = "first * child_complexity")]
+    async fn contains(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default_with = "DEFAULT_LIST_LIMIT")] first: usize,
+    ) -> async_graphql::Result<Vec<Resource>> {
+        let limit = list_limit(first)?;
+
+        Ok(load_contained_resources(ctx.data::<Account>()?, self.0.id.clone(), limit).await?)
+    }
+
+    // Events of this resource, optionally only of the given types
+    #[graphql(complexity = "first * child_complexity")]
+    async fn events(
+        &self,
+        ctx: &Context<'_>,
+        direction: EventDirection,
+        types: Option<Vec<String>>,
+        #[graphql(default_with = "DEFAULT_LIST_LIMIT")] first: usize,
+    ) -> async_graphql::Result<Vec<Event>> {
+        let limit = list_limit(first)?;
+
+        Ok(load_events(
+            ctx.data::<Account>()?,
+            self.0.id.clone(),
+            direction,
+            types,
+            limit,
+        )
+        .await?)
+    }
+
+    // Events reached by following events from this resource for up to `depth` hops, like
+    // `GET /account/:account_id/principal_chain/traverse`
+    #[graphql(complexity = "first * child_complexity")]
+    async fn traverse(
+        &self,
+        ctx: &Context<'_>,
+        direction: EventDirection,
+        #[graphql(default_with = "DEFAULT_TRAVERSAL_DEPTH")] depth: u32,
+        types: Option<Vec<String>>,
+        #[graphql(default_with = "DEFAULT_LIST_LIMIT")] first: usize,
+    ) -> async_graphql::Result<EventTraversal> {
+        let limit = list_limit(first)?;
+
+        let traversed = Traversal {
+            depth,
+            event_types: types,
+            max_events: limit,
+            ..Traversal::new(self.0.id.clone(), direction.into())
+        }
+        .run(ctx.data::<Account>()?)
+        .await?;
+
+        let events = (0..traversed.events.len())
+            .map(|index| TraversedEvent {
+                depth: traversed.events[index].depth,
+                chain: traversed
+                    .chain(index)
+                    .into_iter()
+                    .cloned()
+                    .map(Event)
+                    .collect(),
+            })
+            .collect();
+
+        Ok(EventTraversal {
+            events,
+            truncated: traversed.truncated,
+        })
+    }
+}
+
+#[derive(SimpleObject)]
+struct EventTraversal {
+    events: Vec<TraversedEvent>,
+    // Whether the traversal stopped early because it found `first` events
+    truncated: bool,
+}
+
+#[derive(SimpleObject)]
+struct TraversedEvent {
+    // Number of hops from the starting resource, starting at 1
+    depth: u32,
+    // Events followed from the starting resource to reach this event, ending with this event
+    chain: Vec<Event>,
+}
+
+struct Event(event::Event);
+
+#[Object]
+impl Event {
+    async fn r#type(&self) -> &str {
+        &self.0.r#type
+    }
+
+    async fn principal_id(&self) -> Vec<ResourceIdPartObject> {
+        resource_id_parts(&self.0.principal)
+    }
+
+    async fn principal(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<Resource>> {
+        Ok(load_resource(ctx.data::<Account>()?, self.0.principal.clone()).await?)
+    }
+
+    async fn resource_id(&self) -> Vec<ResourceIdPartObject> {
+        resource_id_parts(&self.0.resource)
+    }
+
+    async fn resource(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<Resource>> {
+        Ok(load_resource(ctx.data::<Account>()?, self.0.resource.clone()).await?)
+    }
+
+    async fn principal_chains(&self) -> Vec<PrincipalChain> {
+        self.0
+            .principal_chains
+            .iter()
+            .cloned()
+            .map(PrincipalChain)
+            .collect()
+    }
+
+    async fn first_seen_at(&self) -> DateTime<Utc> {
+        self.0.first_seen_at
+    }
+
+    async fn last_seen_at(&self) -> DateTime<Utc> {
+        self.0.last_seen_at
+    }
+
+    async fn gone_at(&self) -> Option<DateTime<Utc>> {
+        self.0.gone_at
+    }
+}
+
+#[derive(Deserialize)]
+struct PrincipalChainObserved {
+    first_seen_at: DateTime<Utc>,
+    last_seen_at: DateTime<Utc>,
+}
+
+struct PrincipalChain(PrincipalChainId);
+
+impl PrincipalChain {
+    async fn observed(&self, account: &Account) -> crate::Result<Option<PrincipalChainObserved>> {
+        Ok(account
+            .resources_db()
+            .await?
+            .query("SELECT first_seen_at, last_seen_at FROM type::thing('principal_chain', $id)")
+            .bind(("id", surrealdb::sql::Array::from(self.0.clone())))
+            .await?
+            .check_first_real_error()?
+            .take::<Option<PrincipalChainObserved>>(0)?)
+    }
+}
+
+#[Object]
+impl PrincipalChain {
+    // Principals of the chain in the order they acted, starting with the original principal
+    async fn principals(&self) -> Vec<PrincipalChainPrincipal> {
+        self.0
+            .iter()
+            .map(|part| PrincipalChainPrincipal {
+                id: part.id.clone(),
+                event: part.event.clone(),
+            })
+            .collect()
+    }
+
+    async fn first_seen_at(
+        &self,
+        ctx: &Context<'_>,
+    ) -> async_graphql::Result<Option<DateTime<Utc>>> {
+        Ok(self
+            .observed(ctx.data::<Account>()?)
+            .await?
+            .map(|observed| observed.first_seen_at))
+    }
+
+    async fn last_seen_at(
+        &self,
+        ctx: &Context<'_>,
+    ) -> async_graphql::Result<Option<DateTime<Utc>>> {
+        Ok(self
+            .observed(ctx.data::<Account>()?)
+            .await?
+            .map(|observed| observed.last_seen_at))
+    }
+}
+
+struct PrincipalChainPrincipal {
+    id: ResourceId,
+    // Type of the event through which the principal acted as the next principal of the chain
+    event: Option<String>,
+}
+
+#[Object]
+impl PrincipalChainPrincipal {
+    async fn id(&self) -> Vec<ResourceIdPartObject> {
+        resource_id_parts(&self.id)
+    }
+
+    async fn event(&self) -> Option<&str> {
+        self.event.as_deref()
+    }
+
+    async fn resource(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<Resource>> {
+        Ok(load_resource(ctx.data::<Account>()?, self.id.clone()).await?)
+    }
+}
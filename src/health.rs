@@ -0,0 +1,115 @@
+// Aggregates operational signals of an account into a single response for the dashboard's integration health page.
+// Agents do not send heartbeats, so the last report ingested with each report key is the best signal of whether its
+// agent is alive.
+
+use axum::{Extension, Json};
+use chrono::{DateTime, Duration, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use archodex_error::anyhow::Context as _;
+
+use crate::{
+    Result,
+    account::Account,
+    db::QueryCheckFirstRealError as _,
+    report_api_key::ACTIVE_REPORT_API_KEY_CONDITION,
+    surrealdb_deserializers,
+    usage::{self, QuotaUtilization},
+};
+
+const HEALTH_WINDOW_DAYS: i64 = 30;
+
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
+pub(crate) struct ReportApiKeyHealth {
+    #[serde(deserialize_with = "surrealdb_deserializers::u32::deserialize")]
+    id: u32,
+    description: Option<String>,
+    last_report_at: Option<DateTime<Utc>>,
+    // Highest sequence number ingested with the key, if its agent numbers its reports, see `report::check_sequence`
+    last_sequence: Option<u64>,
+    // Number of skipped sequence numbers, i.e. reports that were likely lost
+    #[serde(default)]
+    sequence_gaps: u64,
+    // Number of rejected reports numbered at or below the highest sequence number, i.e. replays or cloned agents
+    #[serde(default)]
+    sequence_regressions: u64,
+    last_sequence_regression_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
+pub(crate) struct DailyHealth {
+    day: String,
+    reports_ingested: u64,
+    reports_failed: u64,
+    peak_graph_size: u64,
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct Quotas {
+    report_api_keys: QuotaUtilization,
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct AccountHealth {
+    ingestion_allowed: bool,
+    last_report_at: Option<DateTime<Utc>>,
+    report_api_keys: Vec<ReportApiKeyHealth>,
+    // Share of reports that failed to ingest over the health window, or `None` if no reports were received
+    report_error_rate: Option<f64>,
+    // Daily ingestion counts and resource graph size over the health window, oldest first
+    daily: Vec<DailyHealth>,
+    quotas: Quotas,
+    pending_report_api_key_requests: u64,
+}
+
+#[instrument(err, skip_all)]
+pub(crate) async fn health(Extension(account): Extension<Account>) -> Result<Json<AccountHealth>> {
+    let mut res = account
+        .resources_db()
+        .await?
+        .query(format!("SELECT id, description, last_report_at, last_sequence, sequence_gaps ?? 0 AS sequence_gaps, sequence_regressions ?? 0 AS sequence_regressions, last_sequence_regression_at FROM report_api_key WHERE {ACTIVE_REPORT_API_KEY_CONDITION} ORDER BY id"))
+        .query("SELECT record::id(id) AS day, reports_ingested, reports_failed ?? 0 AS reports_failed, peak_graph_size FROM usage_daily WHERE record::id(id) >= $since ORDER BY day")
+        .query("(SELECT count() AS count FROM report_api_key_request WHERE status == 'pending' GROUP ALL)[0].count ?? 0")
+        .bind((
+            "since",
+            usage::usage_day(Utc::now() - Duration::days(HEALTH_WINDOW_DAYS - 1)),
+        ))
+        .await
+        .context("Failed to submit query to get account health")?
+        .check_first_real_error()
+        .context("Failed to get account health")?;
+
+    let report_api_keys = res.take::<Vec<ReportApiKeyHealth>>(0)?;
+    let daily = res.take::<Vec<DailyHealth>>(1)?;
+    let pending_report_api_key_requests = res.take::<Option<u64>>(2)?.unwrap_or_default();
+
+    let last_report_at = report_api_keys
+        .iter()
+        .filter_map(|report_api_key| report_api_key.last_report_at)
+        .max();
+
+    let reports_ingested = daily.iter().map(|day| day.reports_ingested).sum::<u64>();
+    let reports_failed = daily.iter().map(|day| day.reports_failed).sum::<u64>();
+    let reports_received = reports_ingested + reports_failed;
+
+    #[allow(clippy::cast_precision_loss)]
+    let report_error_rate =
+        (reports_received > 0).then(|| reports_failed as f64 / reports_received as f64);
+
+    Ok(Json(AccountHealth {
+        ingestion_allowed: account.check_ingestion_allowed().is_ok(),
+        last_report_at,
+        quotas: Quotas {
+            report_api_keys: QuotaUtilization {
+                used: report_api_keys.len() as u64,
+                limit: account.entitlements().max_report_api_keys.map(u64::from),
+            },
+        },
+        report_api_keys,
+        report_error_rate,
+        daily,
+        pending_report_api_key_requests,
+    }))
+}
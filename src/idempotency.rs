@@ -0,0 +1,164 @@
+// Agents retry `/report` requests that fail or time out, even though the report may have been ingested. Agents send an
+// `Idempotency-Key` header with each report, and reuse it when retrying. The key is recorded by the transaction that
+// ingests the report, so a key is recorded if and only if its report was ingested. Reports sent with a key that has
+// already been recorded for the same report key are not ingested again, and get the result of the first ingestion.
+// Keys are recorded with the hash of their report, so reusing a key for a different report is refused rather than
+// silently dropping the report. Expired keys are deleted by `prune_expired_keys_periodically`.
+
+use std::time::Duration;
+
+use axum::http::HeaderMap;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use sha2::{Digest as _, Sha256};
+use tracing::{info, instrument};
+
+use archodex_error::{anyhow::Context as _, bad_request, conflict};
+
+use crate::{
+    Result,
+    account::Account,
+    accounts::for_each_account_periodically,
+    db::{InstrumentedQuery, QueryCheckFirstRealError as _},
+};
+
+const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+const IDEMPOTENCY_KEY_MAX_LENGTH: usize = 255;
+
+// Agents retry for much less than this, so a retry never finds its key expired
+const IDEMPOTENCY_KEY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+const IDEMPOTENCY_KEY_PRUNING_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Debug)]
+pub(crate) struct IdempotencyKey {
+    report_api_key_id: u32,
+    key: String,
+    // Hex SHA-256 hash of the request body of the report
+    payload_hash: String,
+}
+
+impl IdempotencyKey {
+    pub(crate) fn from_headers(
+        headers: &HeaderMap,
+        report_api_key_id: u32,
+        payload: &[u8],
+    ) -> Result<Option<Self>> {
+        let Some(key) = headers.get(IDEMPOTENCY_KEY_HEADER) else {
+            return Ok(None);
+        };
+
+        let Ok(key) = key.to_str() else {
+            bad_request!("{IDEMPOTENCY_KEY_HEADER} header must be printable ASCII");
+        };
+
+        if key.is_empty() || key.len() > IDEMPOTENCY_KEY_MAX_LENGTH {
+            bad_request!(
+                "{IDEMPOTENCY_KEY_HEADER} header must be between 1 and {IDEMPOTENCY_KEY_MAX_LENGTH} characters"
+            );
+        }
+
+        Ok(Some(Self {
+            report_api_key_id,
+            key: key.to_string(),
+            payload_hash: hex::encode(Sha256::digest(payload)),
+        }))
+    }
+
+    fn thing(&self) -> surrealdb::sql::Thing {
+        surrealdb::sql::Thing::from((
+            "report_idempotency_key",
+            surrealdb::sql::Id::Array(surrealdb::sql::Array::from(vec![
+                surrealdb::sql::Value::from(i64::from(self.report_api_key_id)),
+                surrealdb::sql::Value::from(self.key.as_str()),
+            ])),
+        ))
+    }
+}
+
+// Returns when the report sent with the idempotency key was ingested, if it was. Fails if the key was recorded for a
+// different report.
+#[instrument(err, skip(account))]
+pub(crate) async fn ingested_at(
+    account: &Account,
+    idempotency_key: &IdempotencyKey,
+) -> Result<Option<DateTime<Utc>>> {
+    #[derive(Deserialize)]
+    struct IngestedAt {
+        ingested_at: DateTime<Utc>,
+        payload_hash: String,
+    }
+
+    let Some(ingested_at) = account
+        .resources_db()
+        .await?
+        .query(
+            "SELECT ingested_at, payload_hash FROM $idempotency_key WHERE expires_at > time::now()",
+        )
+        .bind(("idempotency_key", idempotency_key.thing()))
+        .await
+        .context("Failed to submit query to look up report idempotency key")?
+        .check_first_real_error()
+        .context("Failed to look up report idempotency key")?
+        .take::<Option<IngestedAt>>(0)?
+    else {
+        return Ok(None);
+    };
+
+    if ingested_at.payload_hash != idempotency_key.payload_hash {
+        conflict!("{IDEMPOTENCY_KEY_HEADER} header was already used for a different report");
+    }
+
+    Ok(Some(ingested_at.ingested_at))
+}
+
+// Records the idempotency key in the ingestion transaction of its report. The transaction fails if the key was recorded
+// concurrently. The key may have been recorded before and expired without being pruned yet, so an expired record of the
+// key is replaced.
+pub(crate) fn record<'r>(
+    query: InstrumentedQuery<'r>,
+    idempotency_key: &IdempotencyKey,
+) -> InstrumentedQuery<'r> {
+    query
+        .query("DELETE $idempotency_key WHERE expires_at <= time::now() RETURN NONE;")
+        .query("CREATE $idempotency_key SET expires_at = time::now() + $idempotency_key_ttl, payload_hash = $idempotency_key_payload_hash RETURN NONE;")
+        .bind(("idempotency_key", idempotency_key.thing()))
+        .bind((
+            "idempotency_key_ttl",
+            surrealdb::sql::Duration::from(IDEMPOTENCY_KEY_TTL),
+        ))
+        .bind((
+            "idempotency_key_payload_hash",
+            idempotency_key.payload_hash.clone(),
+        ))
+}
+
+/// Periodically deletes expired idempotency keys of every account.
+pub async fn prune_expired_keys_periodically() {
+    for_each_account_periodically(
+        IDEMPOTENCY_KEY_PRUNING_INTERVAL,
+        "idempotency key pruning",
+        |account| async move { prune_expired_keys(&account).await },
+    )
+    .await;
+}
+
+#[instrument(err, skip_all, fields(account_id = account.id()))]
+async fn prune_expired_keys(account: &Account) -> Result<()> {
+    let pruned = account
+        .resources_db()
+        .await?
+        .query("RETURN array::len(DELETE report_idempotency_key WHERE expires_at <= time::now() RETURN BEFORE)")
+        .await
+        .context("Failed to submit query to prune expired report idempotency keys")?
+        .check_first_real_error()
+        .context("Failed to prune expired report idempotency keys")?
+        .take::<Option<usize>>(0)?
+        .unwrap_or(0);
+
+    if pruned > 0 {
+        info!(keys = pruned, "Pruned expired report idempotency keys");
+    }
+
+    Ok(())
+}
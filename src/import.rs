@@ -0,0 +1,306 @@
+// Seeds an account's resource graph from a cloud inventory document, so users see their resources before deploying
+// agents. Documents are uploaded by account admins to `POST /account/:account_id/import?format=<format>`, and their
+// resources are ingested like reports, observed by the `import` sensor. Like reports, imports are refused while the
+// account is suspended or its ingestion is paused. Supported formats are:
+//
+// * `aws_config`: AWS Config snapshot and configuration history files, imported like `POST /report/aws_config` imports
+//   them, see `aws_config`
+// * `terraform_state`: Terraform state files (format version 4, i.e. Terraform 0.12 and newer), e.g. from
+//   `terraform state pull`
+//
+// Terraform resources are converted to AWS Config configuration items, so they are named like imported AWS Config
+// items. Only managed resources of the AWS resource types in `TERRAFORM_RESOURCE_TYPES` with an ARN are imported, and
+// others are counted as skipped. Only the tags of Terraform resources are recorded, as their other attributes may hold
+// secrets. Resources whose ARN doesn't name an AWS account, like S3 buckets, are contained by the account most other
+// resources of the state belong to.
+
+use std::collections::{BTreeMap, HashMap};
+
+use axum::{Extension, Json, body::Bytes, extract::Query};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tracing::{info, instrument};
+
+use archodex_error::bad_request;
+
+use crate::{
+    Result,
+    account::Account,
+    audit::AuditTarget,
+    auth::DashboardAuth,
+    aws_config::{self, ConfigurationItem},
+    report,
+    sensor::Sensor,
+    usage,
+};
+
+const TERRAFORM_STATE_VERSION: u32 = 4;
+
+// Terraform resource types imported from Terraform states, with their AWS Config resource type and the attribute naming
+// them
+const TERRAFORM_RESOURCE_TYPES: &[(&str, &str, &str)] = &[
+    ("aws_db_instance", "AWS::RDS::DBInstance", "identifier"),
+    ("aws_dynamodb_table", "AWS::DynamoDB::Table", "name"),
+    ("aws_ecr_repository", "AWS::ECR::Repository", "name"),
+    ("aws_ecs_cluster", "AWS::ECS::Cluster", "name"),
+    ("aws_eks_cluster", "AWS::EKS::Cluster", "name"),
+    ("aws_iam_policy", "AWS::IAM::Policy", "name"),
+    ("aws_iam_role", "AWS::IAM::Role", "name"),
+    ("aws_iam_user", "AWS::IAM::User", "name"),
+    ("aws_instance", "AWS::EC2::Instance", "id"),
+    ("aws_kinesis_stream", "AWS::Kinesis::Stream", "name"),
+    ("aws_kms_key", "AWS::KMS::Key", "key_id"),
+    (
+        "aws_lambda_function",
+        "AWS::Lambda::Function",
+        "function_name",
+    ),
+    ("aws_s3_bucket", "AWS::S3::Bucket", "bucket"),
+    (
+        "aws_secretsmanager_secret",
+        "AWS::SecretsManager::Secret",
+        "name",
+    ),
+    ("aws_sns_topic", "AWS::SNS::Topic", "name"),
+    ("aws_sqs_queue", "AWS::SQS::Queue", "name"),
+    ("aws_ssm_parameter", "AWS::SSM::Parameter", "name"),
+];
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ImportFormat {
+    AwsConfig,
+    TerraformState,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ImportParams {
+    format: ImportFormat,
+}
+
+#[derive(Debug, JsonSchema, Serialize)]
+pub(crate) struct ImportResponse {
+    imported_resources: usize,
+    // Resources of the document that couldn't be imported, e.g. Terraform resources of unsupported types
+    skipped_resources: usize,
+}
+
+#[derive(Deserialize)]
+struct TerraformState {
+    version: u32,
+    #[serde(default)]
+    resources: Vec<TerraformResource>,
+}
+
+#[derive(Deserialize)]
+struct TerraformResource {
+    mode: String,
+    r#type: String,
+    #[serde(default)]
+    instances: Vec<TerraformResourceInstance>,
+}
+
+#[derive(Deserialize)]
+struct TerraformResourceInstance {
+    #[serde(default)]
+    attributes: Map<String, Value>,
+}
+
+// Fields of an ARN, e.g. `arn:aws:dynamodb:us-east-1:123456789012:table/Orders`. The partition is read from the ARN
+// by `aws_config`.
+struct Arn<'a> {
+    region: &'a str,
+    account_id: &'a str,
+}
+
+fn parse_arn(arn: &str) -> Option<Arn<'_>> {
+    let mut fields = arn.splitn(6, ':');
+
+    if fields.next()? != "arn" {
+        return None;
+    }
+
+    let _partition = fields.next().filter(|partition| !partition.is_empty())?;
+    let _service = fields.next()?;
+    let region = fields.next()?;
+    let account_id = fields.next()?;
+    let _resource = fields.next()?;
+
+    Some(Arn { region, account_id })
+}
+
+// Terraform resource instance converted to a configuration item, before the AWS account of resources whose ARN doesn't
+// name one is known
+struct TerraformItem {
+    account_id: Option<String>,
+    item: ConfigurationItem,
+}
+
+fn terraform_item(
+    config_resource_type: &str,
+    name_attribute: &str,
+    attributes: &Map<String, Value>,
+) -> Option<TerraformItem> {
+    let attribute = |name: &str| {
+        attributes
+            .get(name)
+            .and_then(Value::as_str)
+            .filter(|value| !value.is_empty())
+    };
+
+    let arn = attribute("arn")?;
+    let parsed_arn = parse_arn(arn)?;
+    let name = attribute(name_attribute)?;
+
+    // Regional resources whose ARN doesn't name their region, like S3 buckets, have a `region` attribute
+    let region = attribute("region").unwrap_or(parsed_arn.region);
+
+    let tags = attributes
+        .get("tags")
+        .and_then(Value::as_object)
+        .filter(|tags| !tags.is_empty())
+        .cloned();
+
+    Some(TerraformItem {
+        account_id: Some(parsed_arn.account_id)
+            .filter(|account_id| !account_id.is_empty())
+            .map(str::to_string),
+        item: ConfigurationItem {
+            configuration_item_capture_time: None,
+            configuration_item_status: None,
+            aws_account_id: String::new(),
+            aws_region: Some(region.to_string()),
+            resource_type: config_resource_type.to_string(),
+            resource_id: name.to_string(),
+            resource_name: None,
+            arn: Some(arn.to_string()),
+            configuration: Value::Null,
+            tags,
+        },
+    })
+}
+
+// Returns the configuration items of the state and the number of resources skipped
+fn terraform_state_items(body: &[u8]) -> Result<(Vec<ConfigurationItem>, usize)> {
+    let state = match serde_json::from_slice::<TerraformState>(body) {
+        Ok(state) => state,
+        Err(err) => bad_request!("Invalid Terraform state: {err}"),
+    };
+
+    if state.version != TERRAFORM_STATE_VERSION {
+        bad_request!(
+            "Unsupported Terraform state version {}, only version {TERRAFORM_STATE_VERSION} (Terraform 0.12 and newer) is supported",
+            state.version
+        );
+    }
+
+    let resource_types = TERRAFORM_RESOURCE_TYPES
+        .iter()
+        .map(|(r#type, config_resource_type, name_attribute)| {
+            (*r#type, (*config_resource_type, *name_attribute))
+        })
+        .collect::<HashMap<_, _>>();
+
+    let mut items = Vec::new();
+    let mut skipped_resources = 0;
+
+    for resource in state.resources {
+        // Data sources describe resources managed elsewhere
+        if resource.mode != "managed" {
+            continue;
+        }
+
+        let Some((config_resource_type, name_attribute)) =
+            resource_types.get(resource.r#type.as_str())
+        else {
+            skipped_resources += resource.instances.len();
+            continue;
+        };
+
+        for instance in resource.instances {
+            match terraform_item(config_resource_type, name_attribute, &instance.attributes) {
+                Some(item) => items.push(item),
+                None => skipped_resources += 1,
+            }
+        }
+    }
+
+    // A state usually manages a single AWS account, so resources whose ARN doesn't name one belong to the account most
+    // other resources belong to
+    let mut account_resources = BTreeMap::<&str, usize>::new();
+    for item in &items {
+        if let Some(account_id) = &item.account_id {
+            *account_resources.entry(account_id).or_default() += 1;
+        }
+    }
+    let default_account_id = account_resources
+        .into_iter()
+        .max_by_key(|(_, resources)| *resources)
+        .map(|(account_id, _)| account_id.to_string());
+
+    let mut config_items = Vec::new();
+
+    for TerraformItem {
+        account_id,
+        mut item,
+    } in items
+    {
+        let Some(account_id) = account_id.or_else(|| default_account_id.clone()) else {
+            skipped_resources += 1;
+            continue;
+        };
+
+        item.aws_account_id = account_id;
+        config_items.push(item);
+    }
+
+    Ok((config_items, skipped_resources))
+}
+
+#[instrument(err, skip(auth, account, body), fields(account_id = account.id()))]
+pub(crate) async fn import(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Query(params): Query<ImportParams>,
+    body: Bytes,
+) -> Result<(Extension<AuditTarget>, Json<ImportResponse>)> {
+    auth.ensure_account_admin(account.id()).await?;
+
+    account.check_ingestion_allowed()?;
+
+    let (items, skipped_resources) = match params.format {
+        ImportFormat::AwsConfig => (aws_config::decode(&body)?, 0),
+        ImportFormat::TerraformState => terraform_state_items(&body)?,
+    };
+
+    let (requests, imported_resources) = aws_config::report_requests(items)?;
+
+    for request in requests {
+        usage::check_quotas(&account).await?;
+
+        report::ingest(&account, &Sensor::import(), request, None).await?;
+    }
+
+    info!(
+        imported_resources,
+        skipped_resources, "Imported inventory document"
+    );
+
+    let format = match params.format {
+        ImportFormat::AwsConfig => "aws_config",
+        ImportFormat::TerraformState => "terraform_state",
+    };
+
+    Ok((
+        Extension(AuditTarget(HashMap::from([
+            ("format".to_string(), format.to_string()),
+            ("resources".to_string(), imported_resources.to_string()),
+        ]))),
+        Json(ImportResponse {
+            imported_resources,
+            skipped_resources,
+        }),
+    ))
+}
@@ -0,0 +1,203 @@
+// Reports pass through an ordered pipeline of pre-processors before they are ingested. Each account configures which
+// processors run, with what settings, and in what order in its `ingestion_pipeline` field. Processors only transform
+// the deserialized report, so each one can be exercised in isolation from the database.
+
+use std::collections::HashSet;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::report::{Request, ResourceTreeNode};
+
+pub(crate) trait ReportProcessor: Send + Sync {
+    fn process(&self, report: &mut Request);
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(tag = "processor", rename_all = "snake_case")]
+pub(crate) enum ProcessorConfig {
+    // Removes resource attributes with the given names before they are stored
+    DropAttributes { attributes: Vec<String> },
+}
+
+impl ProcessorConfig {
+    fn processor(&self) -> Box<dyn ReportProcessor> {
+        match self {
+            Self::DropAttributes { attributes } => Box::new(DropAttributes {
+                attributes: attributes.iter().cloned().collect(),
+            }),
+        }
+    }
+}
+
+pub(crate) struct IngestionPipeline {
+    processors: Vec<Box<dyn ReportProcessor>>,
+}
+
+impl IngestionPipeline {
+    pub(crate) fn new(config: &[ProcessorConfig]) -> Self {
+        Self {
+            processors: config.iter().map(ProcessorConfig::processor).collect(),
+        }
+    }
+
+    pub(crate) fn run(&self, report: &mut Request) {
+        for processor in &self.processors {
+            processor.process(report);
+        }
+    }
+}
+
+struct DropAttributes {
+    attributes: HashSet<String>,
+}
+
+// Resource trees are walked with an explicit work stack rather than by recursion, like `report::upsert_resource_tree`,
+// so deeply nested trees cannot overflow the stack.
+impl ReportProcessor for DropAttributes {
+    fn process(&self, report: &mut Request) {
+        let mut stack = report
+            .resource_captures
+            .iter_mut()
+            .collect::<Vec<&mut ResourceTreeNode>>();
+
+        while let Some(resource_tree_node) = stack.pop() {
+            if let Some(attributes) = &mut resource_tree_node.attributes {
+                attributes.retain(|name, _| !self.attributes.contains(name));
+            }
+
+            stack.extend(resource_tree_node.contains.iter_mut().flatten());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::{IngestionPipeline, ProcessorConfig, ReportProcessor};
+    use crate::report::Request;
+
+    fn report() -> Request {
+        Request::from_slice(
+            br#"{
+                "resource_captures": [{
+                    "type": "AWS Partition",
+                    "id": "aws",
+                    "first_seen_at": "2025-01-01T00:00:00Z",
+                    "last_seen_at": "2025-01-01T00:00:00Z",
+                    "attributes": {"owner": "platform", "region": "us-east-1"},
+                    "contains": [{
+                        "type": "AWS Account",
+                        "id": "123456789012",
+                        "first_seen_at": "2025-01-01T00:00:00Z",
+                        "last_seen_at": "2025-01-01T00:00:00Z",
+                        "attributes": {"owner": "platform", "name": "production"}
+                    }]
+                }],
+                "event_captures": []
+            }"#,
+            "report",
+        )
+        .unwrap()
+    }
+
+    fn attribute_names(report: &Request) -> Vec<Vec<String>> {
+        let partition = &report.resource_captures[0];
+        let account = &partition.contains.as_ref().unwrap()[0];
+
+        [partition, account]
+            .iter()
+            .map(|resource_tree_node| {
+                let mut names = resource_tree_node
+                    .attributes
+                    .iter()
+                    .flatten()
+                    .map(|(name, _)| name.clone())
+                    .collect::<Vec<_>>();
+                names.sort();
+                names
+            })
+            .collect()
+    }
+
+    #[test]
+    fn drop_nested_attributes() {
+        let mut report = report();
+
+        IngestionPipeline::new(&[ProcessorConfig::DropAttributes {
+            attributes: vec!["owner".to_string()],
+        }])
+        .run(&mut report);
+
+        assert_eq!(
+            attribute_names(&report),
+            vec![vec!["region".to_string()], vec!["name".to_string()]]
+        );
+    }
+
+    #[test]
+    fn drop_unknown_attributes() {
+        let mut report = report();
+
+        IngestionPipeline::new(&[ProcessorConfig::DropAttributes {
+            attributes: vec!["cost_center".to_string()],
+        }])
+        .run(&mut report);
+
+        assert_eq!(
+            attribute_names(&report),
+            vec![
+                vec!["owner".to_string(), "region".to_string()],
+                vec!["name".to_string(), "owner".to_string()]
+            ]
+        );
+    }
+
+    struct RecordAttributes {
+        attribute_names: Arc<Mutex<Vec<Vec<Vec<String>>>>>,
+    }
+
+    impl ReportProcessor for RecordAttributes {
+        fn process(&self, report: &mut Request) {
+            self.attribute_names
+                .lock()
+                .unwrap()
+                .push(attribute_names(report));
+        }
+    }
+
+    #[test]
+    fn processor_order() {
+        let mut report = report();
+        let recorded = Arc::new(Mutex::new(Vec::new()));
+
+        let mut pipeline = IngestionPipeline::new(&[
+            ProcessorConfig::DropAttributes {
+                attributes: vec!["owner".to_string()],
+            },
+            ProcessorConfig::DropAttributes {
+                attributes: vec!["region".to_string()],
+            },
+        ]);
+        // Record the attributes seen between the two configured processors and after both of them
+        pipeline.processors.insert(
+            1,
+            Box::new(RecordAttributes {
+                attribute_names: recorded.clone(),
+            }),
+        );
+        pipeline.processors.push(Box::new(RecordAttributes {
+            attribute_names: recorded.clone(),
+        }));
+        pipeline.run(&mut report);
+
+        assert_eq!(
+            *recorded.lock().unwrap(),
+            vec![
+                vec![vec!["region".to_string()], vec!["name".to_string()]],
+                vec![Vec::new(), vec!["name".to_string()]]
+            ]
+        );
+    }
+}
@@ -0,0 +1,130 @@
+use std::time::{Duration, SystemTime};
+
+use axum::{Extension, Json};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac as _};
+use josekit::{
+    jws::{HS256, JwsHeader},
+    jwt::{self, JwtPayload, JwtPayloadValidator},
+};
+use schemars::JsonSchema;
+use serde::Serialize;
+use sha2::Sha256;
+use tracing::{info, instrument};
+
+use archodex_error::{
+    ErrorCode,
+    anyhow::{self, Context as _, anyhow, bail, ensure},
+    coded_error,
+};
+
+use crate::{Result, auth::ReportApiKeyAuth, env::Env};
+
+const INGESTION_TOKEN_LIFETIME: Duration = Duration::from_secs(15 * 60);
+const INGESTION_TOKEN_AUDIENCE: &str = "archodex-report";
+
+// Ingestion tokens are signed with a key derived from the API private key rather than the API private key itself, so
+// the same key material is never used for two different algorithms.
+async fn signing_key() -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&Env::api_private_key().await)
+        .expect("HMAC can take a key of any size");
+    mac.update(b"archodex ingestion token signing key");
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[instrument(err)]
+async fn issue(account_id: &str, key_id: u32) -> anyhow::Result<(String, SystemTime)> {
+    let issued_at = SystemTime::now();
+    let expires_at = issued_at + INGESTION_TOKEN_LIFETIME;
+
+    let mut header = JwsHeader::new();
+    header.set_token_type("JWT");
+
+    let mut payload = JwtPayload::new();
+    payload.set_subject(account_id);
+    payload.set_audience(vec![INGESTION_TOKEN_AUDIENCE]);
+    payload.set_issued_at(&issued_at);
+    payload.set_expires_at(&expires_at);
+    payload
+        .set_claim("key_id", Some(key_id.into()))
+        .context("Failed to set key_id claim")?;
+
+    let signer = HS256
+        .signer_from_bytes(signing_key().await)
+        .context("Failed to create ingestion token signer")?;
+
+    let token = jwt::encode_with_signer(&payload, &header, &signer)
+        .context("Failed to sign ingestion token")?;
+
+    Ok((token, expires_at))
+}
+
+// Verifies the signature and expiration of an ingestion token and returns the account and report key IDs it was issued
+// for. Verification is stateless, so a revoked report key's tokens remain valid until they expire.
+#[instrument(err, skip_all)]
+pub(crate) async fn verify(ingestion_token: &str) -> anyhow::Result<(String, u32)> {
+    let verifier = HS256
+        .verifier_from_bytes(signing_key().await)
+        .context("Failed to create ingestion token verifier")?;
+
+    let (payload, _header) = jwt::decode_with_verifier(ingestion_token, &verifier)
+        .context("Failed to verify ingestion token signature")?;
+
+    let mut validator = JwtPayloadValidator::new();
+    validator.set_base_time(SystemTime::now());
+    validator.set_audience(INGESTION_TOKEN_AUDIENCE);
+    validator
+        .validate(&payload)
+        .context("Failed to validate ingestion token claims")?;
+
+    // The validator only checks expiration if the claim is present, so make sure it is
+    ensure!(
+        payload.expires_at().is_some(),
+        "Ingestion token is missing exp claim"
+    );
+
+    let Some(account_id) = payload.subject() else {
+        bail!("Ingestion token is missing sub claim");
+    };
+
+    let key_id = payload
+        .claim("key_id")
+        .and_then(josekit::Value::as_u64)
+        .and_then(|key_id| u32::try_from(key_id).ok())
+        .ok_or_else(|| anyhow!("Ingestion token is missing or has an invalid key_id claim"))?;
+
+    Ok((account_id.to_owned(), key_id))
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct ExchangeResponse {
+    ingestion_token: String,
+    expires_at: DateTime<Utc>,
+}
+
+// Exchanges a report key value for a short-lived ingestion token that `/report` accepts as a Bearer token. The report
+// key has already been fully validated against the account database by the report key middleware.
+#[instrument(err, skip_all)]
+pub(crate) async fn exchange(
+    Extension(auth): Extension<ReportApiKeyAuth>,
+) -> Result<Json<ExchangeResponse>> {
+    if auth.via_ingestion_token() {
+        coded_error!(
+            ErrorCode::ScopeViolation,
+            "Ingestion tokens can't be exchanged for new ingestion tokens"
+        );
+    }
+
+    let (ingestion_token, expires_at) = issue(auth.account_id(), auth.key_id()).await?;
+
+    info!(
+        account_id = auth.account_id(),
+        key_id = auth.key_id(),
+        "Issued ingestion token"
+    );
+
+    Ok(Json(ExchangeResponse {
+        ingestion_token,
+        expires_at: expires_at.into(),
+    }))
+}
@@ -0,0 +1,580 @@
+// Integrity checks of an account's resource graph, our equivalent of fsck. Account admins run them before and after
+// migrations, or when the dashboard shows something unexpected, to find data that ingestion and the schema's events
+// should have prevented:
+//
+// - Referential integrity: relations and record links pointing at records that don't exist
+// - Timestamp sanity: observation windows that end before they start, or end in the future
+// - Counter accuracy: counters maintained by events that disagree with the records they count
+// - Index health: unique indexes holding duplicates, and indexes that disagree with a scan of their table
+//
+// Checks only read the graph, and run in the background because scanning large accounts takes longer than API gateways
+// allow a request to run. Each run is persisted as an `integrity_check` record holding its findings, so runs before and
+// after a migration can be compared.
+
+use std::collections::HashMap;
+
+use axum::{Extension, Json, extract::Path, http::StatusCode};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+use archodex_error::{
+    anyhow::{Context as _, bail},
+    conflict, not_found,
+};
+
+use crate::{
+    Result,
+    account::Account,
+    auth::DashboardAuth,
+    db::QueryCheckFirstRealError as _,
+    job::Job,
+    notifications::{NewNotification, NotificationKind},
+    surrealdb_deserializers,
+    user::User,
+};
+
+// Number of failing record IDs stored with each finding
+const FINDING_EXAMPLES: u32 = 10;
+
+// Checks still running after this long were interrupted, e.g. by a restart of the backend instance running them
+const INTEGRITY_CHECK_TIMEOUT: &str = "1h";
+
+// Observation windows ending this far in the future were reported by agents with skewed clocks
+const FUTURE_TIMESTAMP_TOLERANCE: &str = "1d";
+
+// Tables holding observation windows
+const OBSERVED_TABLES: [&str; 4] = ["resource", "contains", "event", "principal_chain"];
+
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Severity {
+    // The graph is inconsistent, and queries may return wrong results
+    Error,
+    // The graph is consistent, but holds data that is likely wrong
+    Warning,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
+pub(crate) struct Finding {
+    check: String,
+    severity: Severity,
+    description: String,
+    // Number of records failing the check
+    count: u64,
+    // IDs of up to `FINDING_EXAMPLES` records failing the check
+    examples: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum IntegrityCheckStatus {
+    Running,
+    Completed,
+    Failed,
+    Interrupted,
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
+pub(crate) struct IntegrityCheck {
+    #[serde(deserialize_with = "surrealdb_deserializers::string::deserialize")]
+    id: String,
+    status: IntegrityCheckStatus,
+    started_at: DateTime<Utc>,
+    started_by: User,
+    finished_at: Option<DateTime<Utc>>,
+    findings: Vec<Finding>,
+    // Why the check failed to run, if it did
+    error: Option<String>,
+}
+
+fn integrity_check_thing(integrity_check_id: &str) -> surrealdb::sql::Thing {
+    surrealdb::sql::Thing::from(("integrity_check", integrity_check_id))
+}
+
+fn integrity_check_id(params: &HashMap<String, String>) -> Result<String> {
+    let Some(integrity_check_id) = params.get("integrity_check_id") else {
+        bail!("Missing integrity_check_id");
+    };
+
+    Ok(integrity_check_id.clone())
+}
+
+// Reports checks that outlived `INTEGRITY_CHECK_TIMEOUT` as interrupted
+fn select_integrity_checks(from: &str) -> String {
+    format!(
+        "SELECT *, IF status = 'running' AND started_at < time::now() - {INTEGRITY_CHECK_TIMEOUT} THEN 'interrupted' ELSE status END AS status FROM {from}"
+    )
+}
+
+// A check of the records of a table failing a condition
+struct RecordCheck {
+    check: String,
+    severity: Severity,
+    description: String,
+    table: &'static str,
+    condition: String,
+}
+
+fn record_checks() -> Vec<RecordCheck> {
+    let mut checks = vec![
+        RecordCheck {
+            check: "contains_dangling".to_string(),
+            severity: Severity::Error,
+            description: "Containment relations of resources that don't exist".to_string(),
+            table: "contains",
+            condition: "!record::exists(in) OR !record::exists(out)".to_string(),
+        },
+        RecordCheck {
+            check: "event_dangling".to_string(),
+            severity: Severity::Error,
+            description: "Events of resources that don't exist".to_string(),
+            table: "event",
+            condition: "!record::exists(in) OR !record::exists(out)".to_string(),
+        },
+        RecordCheck {
+            check: "event_principal_chains_dangling".to_string(),
+            severity: Severity::Error,
+            description: "Events referencing principal chains that don't exist".to_string(),
+            table: "event",
+            condition: "principal_chains.any(|$principal_chain| !record::exists($principal_chain))"
+                .to_string(),
+        },
+        RecordCheck {
+            check: "resource_attribute_documents_dangling".to_string(),
+            severity: Severity::Error,
+            description:
+                "Resources with attributes referencing attribute documents that don't exist"
+                    .to_string(),
+            table: "resource",
+            condition:
+                "fn::attribute_documents(attributes).any(|$document| !record::exists($document))"
+                    .to_string(),
+        },
+    ];
+
+    for table in OBSERVED_TABLES {
+        checks.push(RecordCheck {
+            check: format!("{table}_first_seen_after_last_seen"),
+            severity: Severity::Error,
+            description: format!("Records of `{table}` first seen after they were last seen"),
+            table,
+            condition: "first_seen_at > last_seen_at".to_string(),
+        });
+
+        checks.push(RecordCheck {
+            check: format!("{table}_last_seen_in_future"),
+            severity: Severity::Warning,
+            description: format!(
+                "Records of `{table}` last seen more than {FUTURE_TIMESTAMP_TOLERANCE} in the future"
+            ),
+            table,
+            condition: format!("last_seen_at > time::now() + {FUTURE_TIMESTAMP_TOLERANCE}"),
+        });
+    }
+
+    checks
+}
+
+#[derive(Debug, Deserialize)]
+struct RecordCheckResult {
+    count: u64,
+    examples: Vec<String>,
+}
+
+async fn run_record_check(account: &Account, check: &RecordCheck) -> Result<Option<Finding>> {
+    let RecordCheck {
+        table, condition, ..
+    } = check;
+
+    let result = account
+        .resources_db()
+        .await?
+        .query(format!(
+            "RETURN {{
+                count: (SELECT count() FROM {table} WHERE {condition} GROUP ALL)[0].count ?? 0,
+                examples: (SELECT VALUE <string> id FROM {table} WHERE {condition} LIMIT $examples),
+            }}"
+        ))
+        .bind(("examples", FINDING_EXAMPLES))
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to submit query to run integrity check {}",
+                check.check
+            )
+        })?
+        .check_first_real_error()
+        .with_context(|| format!("Failed to run integrity check {}", check.check))?
+        .take::<Option<RecordCheckResult>>(0)?
+        .context("Integrity check query should return a result")?;
+
+    Ok((result.count > 0).then(|| Finding {
+        check: check.check.clone(),
+        severity: check.severity,
+        description: check.description.clone(),
+        count: result.count,
+        examples: result.examples,
+    }))
+}
+
+// Compares counters maintained by events with counts of the records they count. `counters` and `counts` return rows of
+// `{ key, count }`, where keys are rendered as strings.
+async fn run_counter_check(
+    account: &Account,
+    check: &str,
+    description: &str,
+    counters: &str,
+    counts: &str,
+) -> Result<Option<Finding>> {
+    #[derive(Deserialize)]
+    struct Row {
+        key: String,
+        count: i64,
+    }
+
+    let mut res = account
+        .resources_db()
+        .await?
+        .query(counters)
+        .query(counts)
+        .await
+        .with_context(|| format!("Failed to submit query to run integrity check {check}"))?
+        .check_first_real_error()
+        .with_context(|| format!("Failed to run integrity check {check}"))?;
+
+    let mut counters = res
+        .take::<Vec<Row>>(0)?
+        .into_iter()
+        .map(|Row { key, count }| (key, count))
+        .collect::<HashMap<_, _>>();
+    let counts = res.take::<Vec<Row>>(1)?;
+
+    let mut mismatched = Vec::new();
+
+    for Row { key, count } in counts {
+        if counters.remove(&key).unwrap_or(0) != count {
+            mismatched.push(key);
+        }
+    }
+
+    // Counters of keys without any records must have dropped to zero
+    mismatched.extend(
+        counters
+            .into_iter()
+            .filter(|(_, count)| *count != 0)
+            .map(|(key, _)| key),
+    );
+
+    mismatched.sort();
+
+    Ok((!mismatched.is_empty()).then(|| Finding {
+        check: check.to_string(),
+        severity: Severity::Error,
+        description: description.to_string(),
+        count: mismatched.len() as u64,
+        examples: mismatched
+            .into_iter()
+            .take(FINDING_EXAMPLES as usize)
+            .collect(),
+    }))
+}
+
+async fn run_counter_checks(account: &Account) -> Result<Vec<Finding>> {
+    let type_path_resources = run_counter_check(
+        account,
+        "type_path_resources",
+        "Type paths whose resource count differs from the resources and containment relations at the path",
+        "SELECT <string> record::id(id) AS key, resources AS count FROM type_path",
+        "SELECT key, count() AS count FROM array::concat(
+            (SELECT <string> fn::type_path(id) AS key FROM resource WHERE record::id(id) != []),
+            (SELECT <string> array::append(fn::type_path(in), array::last(fn::type_path(out))) AS key FROM contains)
+        ) GROUP BY key",
+    )
+    .await?;
+
+    let attribute_document_references = run_counter_check(
+        account,
+        "attribute_document_reference_count",
+        "Attribute documents whose reference count differs from the resources referencing them",
+        "SELECT <string> id AS key, reference_count AS count FROM attribute_document",
+        "SELECT key, count() AS count FROM (
+            SELECT <string> document AS key FROM (SELECT fn::attribute_documents(attributes) AS document FROM resource SPLIT document)
+        ) GROUP BY key",
+    )
+    .await?;
+
+    Ok(type_path_resources
+        .into_iter()
+        .chain(attribute_document_references)
+        .collect())
+}
+
+// Checks that an index returns the same records as a scan of its table, by comparing the number of records of each
+// value of the indexed field
+async fn run_index_check(
+    account: &Account,
+    table: &str,
+    index: &str,
+    field: &str,
+) -> Result<Option<Finding>> {
+    let check = format!("{table}_{index}_index");
+
+    let mismatched = account
+        .resources_db()
+        .await?
+        .query(format!(
+            "SELECT VALUE <string> value FROM (
+                SELECT value, scanned, (SELECT count() FROM {table} WITH INDEX {index} WHERE {field} = $parent.value GROUP ALL)[0].count ?? 0 AS indexed
+                FROM (SELECT {field} AS value, count() AS scanned FROM {table} WITH NOINDEX GROUP BY value)
+            ) WHERE indexed != scanned ORDER BY value"
+        ))
+        .await
+        .with_context(|| format!("Failed to submit query to run integrity check {check}"))?
+        .check_first_real_error()
+        .with_context(|| format!("Failed to run integrity check {check}"))?
+        .take::<Vec<String>>(0)?;
+
+    Ok((!mismatched.is_empty()).then(|| Finding {
+        check,
+        severity: Severity::Error,
+        description: format!(
+            "Values of `{table}.{field}` whose records found through the `{index}` index differ from a scan of the table"
+        ),
+        count: mismatched.len() as u64,
+        examples: mismatched
+            .into_iter()
+            .take(FINDING_EXAMPLES as usize)
+            .collect(),
+    }))
+}
+
+// Checks that no two records hold the same values of the fields of a unique index
+async fn run_unique_index_check(
+    account: &Account,
+    table: &str,
+    fields: &str,
+) -> Result<Option<Finding>> {
+    let check = format!("{table}_unique_index");
+
+    let check_result = account
+        .resources_db()
+        .await?
+        .query(format!(
+            "LET $duplicates = SELECT VALUE <string> value FROM (
+                SELECT [{fields}] AS value, count() AS records FROM {table} WITH NOINDEX GROUP BY value
+            ) WHERE records > 1;
+            RETURN {{ count: array::len($duplicates), examples: array::slice($duplicates, 0, $examples) }};"
+        ))
+        .bind(("examples", FINDING_EXAMPLES))
+        .await
+        .with_context(|| format!("Failed to submit query to run integrity check {check}"))?
+        .check_first_real_error()
+        .with_context(|| format!("Failed to run integrity check {check}"))?
+        .take::<Option<RecordCheckResult>>(1)?
+        .context("Integrity check query should return a result")?;
+
+    Ok((check_result.count > 0).then(|| Finding {
+        check,
+        severity: Severity::Error,
+        description: format!("Values of `{table}` fields {fields} held by more than one record despite their unique index"),
+        count: check_result.count,
+        examples: check_result.examples,
+    }))
+}
+
+async fn run_checks(account: &Account, job: &Job) -> Result<Vec<Finding>> {
+    let mut findings = Vec::new();
+
+    job.step(10, "Checking referential integrity and timestamps");
+
+    for check in record_checks() {
+        findings.extend(run_record_check(account, &check).await?);
+    }
+
+    job.step(50, "Checking counters");
+
+    findings.extend(run_counter_checks(account).await?);
+
+    job.step(75, "Checking indexes");
+
+    findings.extend(run_unique_index_check(account, "contains", "out").await?);
+    findings.extend(run_unique_index_check(account, "event", "in, out, type").await?);
+    findings.extend(run_index_check(account, "resource", "resource_type", "resource_type").await?);
+    findings.extend(run_index_check(account, "event", "type", "type").await?);
+
+    Ok(findings)
+}
+
+async fn run(account: Account, integrity_check_id: String, job: Job) {
+    let res = run_checks(&account, &job).await;
+
+    let (status, findings, error) = match &res {
+        Ok(findings) => {
+            info!(
+                account_id = account.id(),
+                integrity_check_id,
+                findings = findings.len(),
+                "Integrity check completed"
+            );
+            (IntegrityCheckStatus::Completed, findings.clone(), None)
+        }
+        Err(err) => {
+            warn!(
+                ?err,
+                account_id = account.id(),
+                integrity_check_id,
+                "Integrity check failed"
+            );
+            (IntegrityCheckStatus::Failed, vec![], Some(err.to_string()))
+        }
+    };
+
+    let recorded = async {
+        account
+            .resources_db()
+            .await?
+            .query("UPDATE $integrity_check SET status = $status, finished_at = time::now(), findings = $findings, error = $error RETURN NONE")
+            .bind(("integrity_check", integrity_check_thing(&integrity_check_id)))
+            .bind(("status", status))
+            .bind(("findings", findings))
+            .bind(("error", error))
+            .await
+            .context("Failed to submit query to record integrity check result")?
+            .check_first_real_error()
+            .context("Failed to record integrity check result")?;
+
+        Result::Ok(())
+    }
+    .await;
+
+    if let Err(err) = &recorded {
+        warn!(
+            ?err,
+            account_id = account.id(),
+            integrity_check_id,
+            "Failed to record integrity check result"
+        );
+    }
+
+    let message = match &res {
+        Ok(findings) if findings.is_empty() => "Integrity check found no problems".to_string(),
+        Ok(findings) => format!("Integrity check found {} problems", findings.len()),
+        Err(_) => "Integrity check failed".to_string(),
+    };
+
+    job.finish(
+        &res.and(recorded),
+        Some(account.id()),
+        Some(NewNotification {
+            kind: NotificationKind::IntegrityCheckFinished,
+            message,
+            details: HashMap::from([(
+                "integrity_check_id".to_string(),
+                integrity_check_id.clone(),
+            )]),
+        }),
+    )
+    .await;
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct StartIntegrityCheckRequest {
+    // Optional dashboard generated ID to publish check progress to, see `job`
+    #[serde(default)]
+    job_id: Option<Uuid>,
+}
+
+// Starts an integrity check in the background. Only one check of an account may run at a time.
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn start_integrity_check(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Json(req): Json<StartIntegrityCheckRequest>,
+) -> Result<(StatusCode, Json<IntegrityCheck>)> {
+    auth.ensure_account_admin(account.id()).await?;
+
+    let integrity_check_id = Uuid::now_v7().simple().to_string();
+
+    let integrity_check = account
+        .resources_db()
+        .await?
+        .query(format!(
+            "IF array::is_empty(SELECT VALUE id FROM integrity_check WHERE status = 'running' AND started_at >= time::now() - {INTEGRITY_CHECK_TIMEOUT}) {{
+                CREATE $integrity_check CONTENT {{ started_by: $started_by }} RETURN AFTER;
+            }}"
+        ))
+        .bind(("integrity_check", integrity_check_thing(&integrity_check_id)))
+        .bind(("started_by", surrealdb::sql::Thing::from(auth.principal())))
+        .await
+        .context("Failed to submit query to start integrity check")?
+        .check_first_real_error()
+        .context("Failed to start integrity check")?
+        .take::<Option<IntegrityCheck>>(0)?;
+
+    let Some(integrity_check) = integrity_check else {
+        conflict!("An integrity check of the account is already running");
+    };
+
+    info!(integrity_check_id, "Started integrity check");
+
+    let job = Job::new(req.job_id, auth.principal());
+    tokio::spawn(run(account, integrity_check_id, job));
+
+    Ok((StatusCode::ACCEPTED, Json(integrity_check)))
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct ListIntegrityChecksResponse {
+    integrity_checks: Vec<IntegrityCheck>,
+}
+
+#[instrument(err, skip_all)]
+pub(crate) async fn list_integrity_checks(
+    Extension(account): Extension<Account>,
+) -> Result<Json<ListIntegrityChecksResponse>> {
+    let integrity_checks = account
+        .resources_db()
+        .await?
+        .query(format!(
+            "{} ORDER BY id DESC",
+            select_integrity_checks("integrity_check")
+        ))
+        .await
+        .context("Failed to submit query to list integrity checks")?
+        .check_first_real_error()
+        .context("Failed to list integrity checks")?
+        .take::<Vec<IntegrityCheck>>(0)?;
+
+    Ok(Json(ListIntegrityChecksResponse { integrity_checks }))
+}
+
+#[instrument(err, skip(account))]
+pub(crate) async fn get_integrity_check(
+    Extension(account): Extension<Account>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<IntegrityCheck>> {
+    let integrity_check_id = integrity_check_id(&params)?;
+
+    let Some(integrity_check) = account
+        .resources_db()
+        .await?
+        .query(select_integrity_checks("$integrity_check"))
+        .bind((
+            "integrity_check",
+            integrity_check_thing(&integrity_check_id),
+        ))
+        .await
+        .context("Failed to submit query to get integrity check")?
+        .check_first_real_error()
+        .context("Failed to get integrity check")?
+        .take::<Option<IntegrityCheck>>(0)?
+    else {
+        not_found!("Integrity check not found");
+    };
+
+    Ok(Json(integrity_check))
+}
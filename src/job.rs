@@ -0,0 +1,231 @@
+// Long running jobs, such as account creation, publish progress updates that the dashboard streams from
+// `GET /jobs/:job_id/events` to show real progress bars. Job IDs are generated by the dashboard and sent with the
+// request that starts the job, so the dashboard can subscribe before that request completes. Jobs are tracked in memory
+// by the backend instance running them and are forgotten `JOB_RETENTION` after their last update.
+//
+// Operators start jobs through the disaster recovery endpoints instead, see `dr`. Their job IDs are generated by the
+// backend, and operators poll their progress.
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    Extension,
+    extract::Path,
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures::{Stream, StreamExt as _, stream};
+use schemars::JsonSchema;
+use serde::Serialize;
+use tokio::sync::watch;
+use tracing::{instrument, warn};
+use uuid::Uuid;
+
+use archodex_error::not_found;
+
+use crate::{
+    Result,
+    auth::DashboardAuth,
+    notifications::{self, NewNotification},
+    shutdown,
+    user::User,
+};
+
+const JOB_RETENTION: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Clone, Copy, Debug, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum JobStatus {
+    Pending,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Clone, Debug, JsonSchema, Serialize)]
+pub(crate) struct JobProgress {
+    status: JobStatus,
+    percent: u8,
+    step: Option<String>,
+}
+
+impl JobProgress {
+    const PENDING: Self = Self {
+        status: JobStatus::Pending,
+        percent: 0,
+        step: None,
+    };
+
+    fn is_finished(&self) -> bool {
+        matches!(self.status, JobStatus::Succeeded | JobStatus::Failed)
+    }
+}
+
+#[derive(Clone, PartialEq)]
+enum JobOwner {
+    User(User),
+    Operator,
+}
+
+struct JobEntry {
+    owner: JobOwner,
+    progress: watch::Sender<JobProgress>,
+    updated_at: Instant,
+}
+
+static JOBS: LazyLock<Mutex<HashMap<Uuid, JobEntry>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// Returns the progress channel of a job, registering the job if neither its runner nor a subscriber has yet. Returns
+// `None` if the job ID is already used by another owner.
+fn job_progress(job_id: Uuid, owner: &JobOwner) -> Option<watch::Sender<JobProgress>> {
+    let mut jobs = JOBS.lock().expect("Jobs mutex poisoned");
+
+    jobs.retain(|_, job| job.updated_at.elapsed() < JOB_RETENTION);
+
+    let job = jobs.entry(job_id).or_insert_with(|| JobEntry {
+        owner: owner.clone(),
+        progress: watch::Sender::new(JobProgress::PENDING),
+        updated_at: Instant::now(),
+    });
+
+    (job.owner == *owner).then(|| job.progress.clone())
+}
+
+// Publishes progress of a job started by a dashboard request, and notifies the user who started it once it finishes.
+// Progress is not published if the request did not include a job ID.
+#[derive(Clone)]
+pub(crate) struct Job {
+    id: Option<Uuid>,
+    progress: Option<watch::Sender<JobProgress>>,
+    // User whose inbox the job's notification is written to, `None` for operator jobs
+    owner: Option<User>,
+}
+
+impl Job {
+    pub(crate) fn new(job_id: Option<Uuid>, owner: &User) -> Self {
+        let progress = job_id.and_then(|job_id| {
+            let progress = job_progress(job_id, &JobOwner::User(owner.clone()));
+            if progress.is_none() {
+                warn!(%job_id, "Job ID is already used by another user, not publishing progress");
+            }
+            progress
+        });
+
+        Self {
+            id: job_id,
+            progress,
+            owner: Some(owner.clone()),
+        }
+    }
+
+    // Registers a job started by an operator under a new job ID
+    pub(crate) fn operator() -> (Uuid, Self) {
+        let job_id = Uuid::now_v7();
+
+        let job = Self {
+            id: Some(job_id),
+            progress: job_progress(job_id, &JobOwner::Operator),
+            owner: None,
+        };
+
+        (job_id, job)
+    }
+
+    fn publish(&self, progress: JobProgress) {
+        let Some(sender) = &self.progress else {
+            return;
+        };
+
+        sender.send_replace(progress);
+
+        if let Some(job_id) = self.id
+            && let Some(job) = JOBS.lock().expect("Jobs mutex poisoned").get_mut(&job_id)
+        {
+            job.updated_at = Instant::now();
+        }
+    }
+
+    pub(crate) fn step(&self, percent: u8, step: &str) {
+        self.publish(JobProgress {
+            status: JobStatus::Running,
+            percent: percent.min(100),
+            step: Some(step.to_string()),
+        });
+    }
+
+    // Publishes the result of the job, and writes `notification` to the inbox of the user who started it, optionally
+    // about one of the user's accounts
+    pub(crate) async fn finish<T, E>(
+        &self,
+        result: &std::result::Result<T, E>,
+        account_id: Option<&str>,
+        notification: Option<NewNotification>,
+    ) {
+        if let (Some(owner), Some(notification)) = (&self.owner, notification) {
+            notifications::notify_user(owner, account_id, notification).await;
+        }
+
+        self.publish(match result {
+            Ok(_) => JobProgress {
+                status: JobStatus::Succeeded,
+                percent: 100,
+                step: None,
+            },
+            Err(_) => JobProgress {
+                status: JobStatus::Failed,
+                percent: self
+                    .progress
+                    .as_ref()
+                    .map_or(0, |sender| sender.borrow().percent),
+                step: None,
+            },
+        });
+    }
+}
+
+// Streams progress updates of a job as server-sent events until the job finishes. The current progress is sent first,
+// so subscribers that connect late or reconnect immediately see where the job is.
+#[instrument(err, skip(auth))]
+pub(crate) async fn events(
+    Extension(auth): Extension<DashboardAuth>,
+    Path(job_id): Path<Uuid>,
+) -> Result<Sse<impl Stream<Item = std::result::Result<Event, Infallible>>>> {
+    let Some(progress) = job_progress(job_id, &JobOwner::User(auth.principal().clone())) else {
+        not_found!("Job not found");
+    };
+
+    let mut receiver = progress.subscribe();
+    receiver.mark_changed();
+
+    let events = stream::unfold(Some(receiver), |receiver| async move {
+        let mut receiver = receiver?;
+
+        // The sender is kept in the job registry, so this only fails once the job has been forgotten
+        receiver.changed().await.ok()?;
+
+        let progress = receiver.borrow_and_update().clone();
+        let event = Event::default()
+            .event("progress")
+            .json_data(&progress)
+            .unwrap_or_else(|_| Event::default().event("progress"));
+
+        Some((Ok(event), (!progress.is_finished()).then_some(receiver)))
+    })
+    .take_until(shutdown::initiated());
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+// Current progress of a job started by an operator, if it has not been forgotten
+pub(crate) fn operator_job_progress(job_id: Uuid) -> Option<JobProgress> {
+    JOBS.lock()
+        .expect("Jobs mutex poisoned")
+        .get(&job_id)
+        .filter(|job| job.owner == JobOwner::Operator && job.updated_at.elapsed() < JOB_RETENTION)
+        .map(|job| job.progress.borrow().clone())
+}
@@ -1,21 +1,90 @@
 mod account;
+mod account_cache;
 mod accounts;
+mod admin;
+mod agents;
 mod auth;
+mod aws_config;
+mod billing;
+mod bundle;
+mod consistency;
 mod db;
+mod dead_letter;
+mod debug_capture;
+mod dr;
+mod environments;
 mod event;
+mod export;
+mod export_encryption;
+#[cfg(feature = "archodex-com")]
+mod federation;
 mod global_container;
+mod graph_stream;
+mod graphql;
+mod health;
+mod import;
+mod ingestion_pipeline;
+mod ingestion_token;
+mod integrity;
+mod job;
+mod limits;
+mod metrics;
+mod notifications;
+mod oidc;
+mod openapi;
+mod operations;
+mod outbound;
 mod principal_chain;
 mod query;
+mod query_cache;
+mod rate_limit;
+mod readiness;
 mod report;
 mod report_api_key;
+mod report_api_key_requests;
 mod report_api_keys;
+mod report_key_backoff;
+mod request_id;
 mod resource;
+mod resource_deletion;
+#[cfg(feature = "archodex-com")]
+mod resource_sharing;
+mod resources_db_pool;
+mod saml;
+mod search;
+mod secret_hash;
+mod sensor;
+mod shard;
+mod span_context;
 mod surrealdb_deserializers;
+mod tenant_naming;
+mod timestamp;
+mod type_path;
 mod user;
 mod value;
+mod xml_dsig;
 
+pub mod account_link;
+pub mod api_tokens;
+pub mod audit;
+#[cfg(not(feature = "archodex-com"))]
+pub mod bootstrap;
+pub mod description;
 pub mod env;
+pub mod event_retention;
+#[cfg(feature = "fixtures")]
+pub mod fixtures;
+pub mod idempotency;
+pub mod lifecycle;
+pub mod members;
+#[cfg(feature = "archodex-com")]
+pub mod provisioning;
 pub mod router;
+pub mod shutdown;
+pub mod standby;
+pub mod typescript;
+pub mod usage;
+pub mod webhooks;
 
 use std::sync::atomic::AtomicU64;
 
@@ -0,0 +1,171 @@
+// Reports only ever upsert resources, so resources that are no longer observed would otherwise remain in the graph
+// forever. Resources may be archived explicitly by account members, e.g. once they were deleted, or automatically once
+// they have not been seen for the account's staleness window. Archiving a resource archives the resources it contains.
+// Archived resources are excluded from queries unless requested. Resources archived because they were stale become
+// active again when a report observes them after they were archived, while resources archived by account members remain
+// archived until they are unarchived.
+
+use std::{collections::HashMap, time::Duration};
+
+use axum::{Extension, Json};
+use chrono::Utc;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument};
+
+use archodex_error::{anyhow::Context as _, bad_request};
+
+use crate::{
+    Result,
+    account::Account,
+    accounts::for_each_account_periodically,
+    auth::DashboardAuth,
+    db::QueryCheckFirstRealError as _,
+    notifications::{self, NewNotification, NotificationKind},
+    query_cache,
+    resource::{ResourceId, surrealdb_thing_from_resource_id},
+};
+
+const STALE_RESOURCE_ARCHIVAL_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Resource IDs encode their hierarchy, so the subtree of a resource is every resource whose ID starts with its ID.
+// Globally unique resources are linked to their container by `contains` relations instead, and their subtrees are
+// included however deeply they are nested, see `fn::contained_subtrees`. Generates the statements assigning the subtree
+// of the resource bound to `$<resource>`, whose ID has `$<depth>` parts, to `$<subtree>`.
+pub(crate) fn subtree_statements(subtree: &str, resource: &str, depth: &str) -> [String; 2] {
+    [
+        format!(
+            "LET ${subtree} = SELECT VALUE id FROM resource WHERE array::slice(record::id(id), 0, ${depth}) = record::id(${resource});"
+        ),
+        format!("LET ${subtree} = fn::contained_subtrees(${subtree});"),
+    ]
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ArchiveResourceRequest {
+    resource_id: ResourceId,
+}
+
+#[derive(Debug, JsonSchema, Serialize)]
+pub(crate) struct ArchiveResourceResponse {
+    // Resources whose lifecycle changed, including contained resources
+    resources: usize,
+}
+
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn archive_resource(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Json(req): Json<ArchiveResourceRequest>,
+) -> Result<Json<ArchiveResourceResponse>> {
+    if req.resource_id.is_empty() {
+        bad_request!("The root resource cannot be archived");
+    }
+
+    let depth = req.resource_id.len();
+
+    let archived = account
+        .resources_db()
+        .await?
+        .query(subtree_statements("subtree", "resource", "depth").join("\n"))
+        .query("UPDATE $subtree SET archived_at = time::now(), archived_by = $user WHERE archived_at IS NONE RETURN VALUE id")
+        .bind(("resource", surrealdb_thing_from_resource_id(req.resource_id)))
+        .bind(("depth", depth))
+        .bind(("user", surrealdb::sql::Thing::from(auth.principal())))
+        .await
+        .context("Failed to submit query to archive resource")?
+        .check_first_real_error()
+        .context("Failed to archive resource")?
+        .take::<Vec<ResourceId>>(2)?;
+
+    info!(resources = archived.len(), "Archived resources");
+
+    Ok(Json(ArchiveResourceResponse {
+        resources: archived.len(),
+    }))
+}
+
+#[instrument(err, skip(account))]
+pub(crate) async fn unarchive_resource(
+    Extension(account): Extension<Account>,
+    Json(req): Json<ArchiveResourceRequest>,
+) -> Result<Json<ArchiveResourceResponse>> {
+    if req.resource_id.is_empty() {
+        bad_request!("The root resource cannot be unarchived");
+    }
+
+    let depth = req.resource_id.len();
+
+    let unarchived = account
+        .resources_db()
+        .await?
+        .query(subtree_statements("subtree", "resource", "depth").join("\n"))
+        .query("UPDATE $subtree SET archived_at = NONE, archived_by = NONE WHERE archived_at IS NOT NONE RETURN VALUE id")
+        .bind(("resource", surrealdb_thing_from_resource_id(req.resource_id)))
+        .bind(("depth", depth))
+        .await
+        .context("Failed to submit query to unarchive resource")?
+        .check_first_real_error()
+        .context("Failed to unarchive resource")?
+        .take::<Vec<ResourceId>>(2)?;
+
+    info!(resources = unarchived.len(), "Unarchived resources");
+
+    Ok(Json(ArchiveResourceResponse {
+        resources: unarchived.len(),
+    }))
+}
+
+/// Periodically archives resources of every account that has a staleness window and whose resources have not been seen
+/// within it.
+pub async fn archive_stale_resources_periodically() {
+    for_each_account_periodically(
+        STALE_RESOURCE_ARCHIVAL_INTERVAL,
+        "stale resource archival",
+        |account| async move { archive_stale_resources(&account).await },
+    )
+    .await;
+}
+
+// Stale resources are archived without an `archived_by` user
+#[instrument(err, skip_all, fields(account_id = account.id()))]
+async fn archive_stale_resources(account: &Account) -> Result<()> {
+    let Some(resource_staleness_days) = account.resource_staleness_days() else {
+        return Ok(());
+    };
+
+    let stale_before = Utc::now() - chrono::Duration::days(resource_staleness_days.into());
+
+    let archived = account
+        .resources_db()
+        .await?
+        .query("UPDATE resource SET archived_at = time::now() WHERE id != resource:[] AND archived_at IS NONE AND last_seen_at < $stale_before RETURN VALUE id")
+        .bind(("stale_before", surrealdb::sql::Datetime::from(stale_before)))
+        .await
+        .context("Failed to submit query to archive stale resources")?
+        .check_first_real_error()
+        .context("Failed to archive stale resources")?
+        .take::<Vec<ResourceId>>(0)?;
+
+    if !archived.is_empty() {
+        info!(resources = archived.len(), "Archived stale resources");
+
+        query_cache::invalidate(account.id());
+
+        notifications::notify_account_members(
+            account,
+            NewNotification {
+                kind: NotificationKind::StaleResourcesArchived,
+                message: format!(
+                    "{} resources not seen in {resource_staleness_days} days were archived",
+                    archived.len()
+                ),
+                details: HashMap::new(),
+            },
+        )
+        .await;
+    }
+
+    Ok(())
+}
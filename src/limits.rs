@@ -0,0 +1,263 @@
+// Limits on the size of requests. Requests exceeding a limit are rejected with a `limit_exceeded` error describing the
+// limit, the value of the request where it is known, and how to bring the request within the limit, so agents and
+// operators can act on the error without looking up the limit.
+
+use axum::{
+    extract::{Request, State},
+    http::{
+        StatusCode,
+        header::{CONTENT_LENGTH, CONTENT_TYPE},
+    },
+    middleware::Next,
+    response::{IntoResponse as _, Response},
+};
+
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use archodex_error::{ExceededLimit, PublicError};
+
+use crate::env::Env;
+
+// Matches axum's default body limit, which applied to every request before limits were declared here
+const DEFAULT_MAX_BODY_BYTES: usize = 2 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Limit {
+    RequestBodyBytes,
+    ReportBodyBytes,
+    ReportBatchLineBytes,
+    ReportBatchLines,
+    BundleBodyBytes,
+    AwsConfigFileBytes,
+    ImportBodyBytes,
+    ResourceTreeDepth,
+    ResourceTreeNodes,
+    ReportEvents,
+    ReportEdges,
+    ReportStatements,
+    AttributeBytes,
+    SamlResponseBytes,
+    Operations,
+    DeleteResourceIds,
+    DeletedResources,
+    ResourceTags,
+    TagBytes,
+}
+
+impl Limit {
+    pub(crate) fn max(self) -> usize {
+        match self {
+            Limit::RequestBodyBytes | Limit::ReportBodyBytes => DEFAULT_MAX_BODY_BYTES,
+            // Lines are buffered in memory before they are ingested, so they get a larger limit than `/report` bodies
+            // but are still bounded
+            Limit::ReportBatchLineBytes => 16 * 1024 * 1024,
+            // Bounds how much a single request ingests, as batch bodies aren't limited in size
+            Limit::ReportBatchLines => 1_000,
+            // Bundles are uploaded out-of-band and can hold many reports, so they get a larger limit than `/report`
+            Limit::BundleBodyBytes => 64 * 1024 * 1024,
+            // AWS Config files describe every resource recorded in a region, so they get the same limit as bundles.
+            // Gzipped files are limited both before and after they are decompressed.
+            Limit::AwsConfigFileBytes => 64 * 1024 * 1024,
+            // Imported inventory documents are AWS Config files or Terraform states, which describe as many resources
+            Limit::ImportBodyBytes => 64 * 1024 * 1024,
+            Limit::ResourceTreeDepth => Env::report_max_resource_tree_depth(),
+            Limit::ResourceTreeNodes => Env::report_max_resource_tree_nodes(),
+            Limit::ReportEvents => Env::report_max_events(),
+            Limit::ReportEdges => Env::report_max_edges(),
+            Limit::ReportStatements => Env::report_max_statements(),
+            Limit::AttributeBytes => Env::report_max_attribute_bytes(),
+            Limit::SamlResponseBytes => 256 * 1024,
+            Limit::Operations | Limit::DeleteResourceIds => 100,
+            Limit::DeletedResources => 10_000,
+            Limit::ResourceTags => 50,
+            Limit::TagBytes => 64,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Limit::RequestBodyBytes => "request_body_bytes",
+            Limit::ReportBodyBytes => "report_body_bytes",
+            Limit::ReportBatchLineBytes => "report_batch_line_bytes",
+            Limit::ReportBatchLines => "report_batch_lines",
+            Limit::BundleBodyBytes => "bundle_body_bytes",
+            Limit::AwsConfigFileBytes => "aws_config_file_bytes",
+            Limit::ImportBodyBytes => "import_body_bytes",
+            Limit::ResourceTreeDepth => "resource_tree_depth",
+            Limit::ResourceTreeNodes => "resource_tree_nodes",
+            Limit::ReportEvents => "report_events",
+            Limit::ReportEdges => "report_edges",
+            Limit::ReportStatements => "report_statements",
+            Limit::AttributeBytes => "attribute_bytes",
+            Limit::SamlResponseBytes => "saml_response_bytes",
+            Limit::Operations => "operations",
+            Limit::DeleteResourceIds => "delete_resource_ids",
+            Limit::DeletedResources => "deleted_resources",
+            Limit::ResourceTags => "resource_tags",
+            Limit::TagBytes => "tag_bytes",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Limit::RequestBodyBytes
+            | Limit::ReportBodyBytes
+            | Limit::BundleBodyBytes
+            | Limit::AwsConfigFileBytes
+            | Limit::ImportBodyBytes => "Request body size in bytes",
+            Limit::ReportBatchLineBytes => "Report batch line size in bytes",
+            Limit::ReportBatchLines => "Number of report batch lines",
+            Limit::ResourceTreeDepth => "Resource tree depth",
+            Limit::ResourceTreeNodes => "Number of resources in report",
+            Limit::ReportEvents => "Number of events in report",
+            Limit::ReportEdges => "Number of event relations in report",
+            Limit::ReportStatements => "Number of statements ingesting report",
+            Limit::AttributeBytes => "Resource attribute value size in bytes",
+            Limit::SamlResponseBytes => "SAML response size in bytes",
+            Limit::Operations => "Number of operations",
+            Limit::DeleteResourceIds => "Number of resource IDs",
+            Limit::DeletedResources => {
+                "Number of resources to delete, including contained resources"
+            }
+            Limit::ResourceTags => "Number of resource tags",
+            Limit::TagBytes => "Tag size in bytes",
+        }
+    }
+
+    fn remediation(self) -> &'static str {
+        match self {
+            Limit::RequestBodyBytes => "Split the request into multiple smaller requests",
+            Limit::ReportBodyBytes => {
+                "Split the captures across multiple reports, or stream them to `/report/batch` as NDJSON"
+            }
+            Limit::ReportBatchLineBytes => "Split the line into multiple smaller reports",
+            Limit::ReportBatchLines => {
+                "Split the lines across multiple `/report/batch` requests. Lines before the limit was exceeded were ingested"
+            }
+            Limit::BundleBodyBytes => "Split the reports across multiple bundles",
+            Limit::AwsConfigFileBytes => {
+                "Import the configuration history files of the period instead of its snapshot"
+            }
+            Limit::ImportBodyBytes => {
+                "Split the inventory across multiple documents, e.g. one Terraform state per workspace"
+            }
+            Limit::ResourceTreeDepth => {
+                "Reduce the nesting of the resource tree, or raise `REPORT_MAX_RESOURCE_TREE_DEPTH` on self-hosted backends"
+            }
+            Limit::ResourceTreeNodes => {
+                "Split the resource captures across multiple reports, or raise `REPORT_MAX_RESOURCE_TREE_NODES` on self-hosted backends"
+            }
+            Limit::ReportEvents => {
+                "Split the event captures across multiple reports, or raise `REPORT_MAX_EVENTS` on self-hosted backends"
+            }
+            Limit::ReportEdges => {
+                "Split event captures with many principals, resources, and events across multiple reports, or raise `REPORT_MAX_EDGES` on self-hosted backends"
+            }
+            Limit::ReportStatements => {
+                "Split the captures across multiple reports, or raise `REPORT_MAX_STATEMENTS` on self-hosted backends"
+            }
+            Limit::AttributeBytes => {
+                "Omit or truncate the attribute, or raise `REPORT_MAX_ATTRIBUTE_BYTES` on self-hosted backends"
+            }
+            Limit::SamlResponseBytes => {
+                "Reduce the attributes the identity provider includes in its SAML responses"
+            }
+            Limit::Operations => "Split the operations across multiple requests",
+            Limit::DeleteResourceIds => "Split the resource IDs across multiple requests",
+            Limit::DeletedResources => {
+                "Delete contained resources in separate requests before deleting their containers"
+            }
+            Limit::ResourceTags => "Remove tags from the resource before adding more",
+            Limit::TagBytes => "Shorten the tag",
+        }
+    }
+
+    pub(crate) fn exceeded(self, observed: Option<usize>) -> PublicError {
+        self.exceeded_by(self.description(), observed)
+    }
+
+    // Like `exceeded`, but describes what exceeded the limit more precisely, e.g. which line of a report batch
+    pub(crate) fn exceeded_by(self, subject: &str, observed: Option<usize>) -> PublicError {
+        let max = self.max();
+        let remediation = self.remediation();
+
+        let message = match observed {
+            Some(observed) => {
+                format!("{subject} of {observed} exceeds the limit of {max}. {remediation}")
+            }
+            None => format!("{subject} exceeds the limit of {max}. {remediation}"),
+        };
+
+        PublicError::limit_exceeded(
+            message,
+            ExceededLimit {
+                name: self.name(),
+                limit: max as u64,
+                observed: observed.map(|observed| observed as u64),
+                remediation,
+            },
+        )
+    }
+}
+
+// Limits on the contents of a single report, handed to agents when they register so they can chunk their captures
+// into reports within the limits rather than have reports rejected
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct ReportLimits {
+    // Largest `/report` request body in bytes
+    max_body_bytes: usize,
+    // Deepest nesting of a resource tree
+    max_resource_tree_depth: usize,
+    // Most resources across all resource trees
+    max_resources: usize,
+    // Most events across all event captures
+    max_events: usize,
+    // Most event relations, i.e. principals × resources × events summed over all event captures
+    max_edges: usize,
+    // Largest serialized resource attribute value in bytes
+    max_attribute_bytes: usize,
+}
+
+impl ReportLimits {
+    pub(crate) fn current() -> Self {
+        Self {
+            max_body_bytes: Limit::ReportBodyBytes.max(),
+            max_resource_tree_depth: Limit::ResourceTreeDepth.max(),
+            max_resources: Limit::ResourceTreeNodes.max(),
+            max_events: Limit::ReportEvents.max(),
+            max_edges: Limit::ReportEdges.max(),
+            max_attribute_bytes: Limit::AttributeBytes.max(),
+        }
+    }
+}
+
+// Replaces the plain text responses axum sends when a body exceeds its `DefaultBodyLimit` with a `limit_exceeded`
+// error. The layer must be given the same limit as the `DefaultBodyLimit` of the routes it wraps.
+pub(crate) async fn body_limit_error(
+    State(limit): State<Limit>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let content_length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|content_length| content_length.to_str().ok())
+        .and_then(|content_length| content_length.parse::<usize>().ok());
+
+    let response = next.run(req).await;
+
+    if response.status() != StatusCode::PAYLOAD_TOO_LARGE
+        || response
+            .headers()
+            .get(CONTENT_TYPE)
+            .is_some_and(|content_type| content_type == "application/json")
+    {
+        return response;
+    }
+
+    // Bodies without a length header are rejected as soon as the limit is reached, before their size is known
+    let observed = content_length.filter(|content_length| *content_length > limit.max());
+
+    limit.exceeded(observed).into_response()
+}
@@ -0,0 +1,460 @@
+// Account membership. Users are members of an account through their `has_access` relation to it, which carries their
+// role. Admins invite users by email, change member roles, and remove members. The backend does not send email, so
+// creating an invitation returns an invitation token for the admin to share with the invitee, who accepts it while
+// signed in to the dashboard with the invited email. Only a hash of the token secret is stored. Expired invitations are
+// deleted by `prune_expired_invitations_periodically`.
+
+use std::{collections::HashMap, time::Duration};
+
+use axum::{Extension, Json, extract::Path};
+use chrono::{DateTime, Utc};
+use rand::Rng as _;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use surrealdb::sql::statements::{BeginStatement, CommitStatement};
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+use archodex_error::{
+    anyhow::{Context as _, bail},
+    bad_request, conflict, forbidden, not_found,
+};
+
+use crate::{
+    Result,
+    account::{Account, AccountPublic},
+    account_cache,
+    accounts::for_each_account_periodically,
+    audit::{self, AuditRequest},
+    auth::{AccountRole, DashboardAuth, StepUp},
+    db::{QueryCheckFirstRealError as _, accounts_db},
+    surrealdb_deserializers,
+    user::User,
+};
+
+const INVITATION_TTL: &str = "7d";
+
+const INVITATION_PRUNING_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
+pub(crate) struct Member {
+    user: User,
+    role: AccountRole,
+    // Email of members who sign in with SAML, if the account maps an attribute to emails
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct ListMembersResponse {
+    members: Vec<Member>,
+}
+
+#[instrument(err, skip_all)]
+pub(crate) async fn list_members(
+    Extension(account): Extension<Account>,
+) -> Result<Json<ListMembersResponse>> {
+    let members = accounts_db()
+        .await?
+        .query("SELECT in AS user, role ?? 'admin' AS role, in.email AS email, created_at FROM has_access WHERE out == $account ORDER BY created_at")
+        .bind(("account", surrealdb::sql::Thing::from(&account)))
+        .await
+        .context("Failed to submit query to list account members")?
+        .check_first_real_error()
+        .context("Failed to list account members")?
+        .take::<Vec<Member>>(0)?;
+
+    Ok(Json(ListMembersResponse { members }))
+}
+
+fn member_user(params: &HashMap<String, String>) -> Result<User> {
+    let Some(user_id) = params.get("user_id") else {
+        bail!("Missing user_id");
+    };
+
+    let Ok(user_id) = Uuid::parse_str(user_id) else {
+        bad_request!("Invalid user ID");
+    };
+
+    Ok(User::new(user_id))
+}
+
+// Accounts must always keep at least one admin. Member changes run these statements in the same transaction, so changes
+// that would leave the account without an admin fail. The admin relations of the account are rewritten by every change,
+// so concurrent changes to different admins conflict rather than each seeing the other admin remain.
+const NO_ADMIN_REMAINS: &str = "Account must have at least one admin";
+const LOCK_ADMINS_STATEMENT: &str = "UPDATE has_access SET role = role WHERE out == $account AND (role ?? 'admin') == 'admin' RETURN NONE;";
+const ENSURE_ADMIN_REMAINS_STATEMENT: &str = "IF count(SELECT 1 FROM has_access WHERE out == $account AND (role ?? 'admin') == 'admin') == 0 { THROW $no_admin_remains };";
+
+fn check_admin_remains(
+    response: surrealdb::Response,
+    context: &'static str,
+) -> Result<surrealdb::Response> {
+    match response.check_first_real_error() {
+        Err(surrealdb::Error::Db(surrealdb::error::Db::Thrown(message)))
+            if message == NO_ADMIN_REMAINS =>
+        {
+            conflict!("{NO_ADMIN_REMAINS}");
+        }
+        response => Ok(response.context(context)?),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SetMemberRoleRequest {
+    role: AccountRole,
+}
+
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn set_member_role(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Path(params): Path<HashMap<String, String>>,
+    Json(req): Json<SetMemberRoleRequest>,
+) -> Result<Json<Member>> {
+    auth.ensure_account_admin(account.id()).await?;
+
+    let user = member_user(&params)?;
+
+    // SurrealDB returns no results for `BEGIN` and `COMMIT`, so the updated member is the second result
+    let Some(member) = check_admin_remains(
+        accounts_db()
+            .await?
+            .query(BeginStatement::default())
+            .query(LOCK_ADMINS_STATEMENT)
+            .query("UPDATE has_access SET role = $role WHERE in == $user AND out == $account RETURN in AS user, role, created_at;")
+            .query(ENSURE_ADMIN_REMAINS_STATEMENT)
+            .query(CommitStatement::default())
+            .bind(("account", surrealdb::sql::Thing::from(&account)))
+            .bind(("user", surrealdb::sql::Thing::from(&user)))
+            .bind(("role", req.role))
+            .bind(("no_admin_remains", NO_ADMIN_REMAINS))
+            .await
+            .context("Failed to submit query to set account member role")?,
+        "Failed to set account member role",
+    )?
+    .take::<Vec<Member>>(1)?
+    .pop()
+    else {
+        not_found!("Member not found");
+    };
+
+    info!(?user, role = ?req.role, "Set account member role");
+
+    Ok(Json(member))
+}
+
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn remove_member(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    _step_up: StepUp,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<()>> {
+    auth.ensure_account_admin(account.id()).await?;
+
+    let user = member_user(&params)?;
+
+    // SurrealDB returns no results for `BEGIN` and `COMMIT`, so the removed member is the second result
+    let removed = check_admin_remains(
+        accounts_db()
+            .await?
+            .query(BeginStatement::default())
+            .query(LOCK_ADMINS_STATEMENT)
+            .query("DELETE has_access WHERE in == $user AND out == $account RETURN BEFORE;")
+            .query(ENSURE_ADMIN_REMAINS_STATEMENT)
+            .query(CommitStatement::default())
+            .bind(("account", surrealdb::sql::Thing::from(&account)))
+            .bind(("user", surrealdb::sql::Thing::from(&user)))
+            .bind(("no_admin_remains", NO_ADMIN_REMAINS))
+            .await
+            .context("Failed to submit query to remove account member")?,
+        "Failed to remove account member",
+    )?
+    .take::<Vec<surrealdb::sql::Object>>(1)?;
+
+    if removed.is_empty() {
+        not_found!("Member not found");
+    }
+
+    info!(?user, "Removed account member");
+
+    Ok(Json(()))
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
+pub(crate) struct Invitation {
+    #[serde(deserialize_with = "surrealdb_deserializers::string::deserialize")]
+    id: String,
+    email: String,
+    role: AccountRole,
+    created_at: DateTime<Utc>,
+    created_by: User,
+    expires_at: DateTime<Utc>,
+}
+
+fn invitation_thing(invitation_id: &str) -> surrealdb::sql::Thing {
+    surrealdb::sql::Thing::from(("account_invitation", invitation_id))
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct ListInvitationsResponse {
+    invitations: Vec<Invitation>,
+}
+
+#[instrument(err, skip_all)]
+pub(crate) async fn list_invitations(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+) -> Result<Json<ListInvitationsResponse>> {
+    auth.ensure_account_admin(account.id()).await?;
+
+    let invitations = accounts_db()
+        .await?
+        .query("SELECT * FROM account_invitation WHERE account == $account AND expires_at > time::now() ORDER BY created_at")
+        .bind(("account", surrealdb::sql::Thing::from(&account)))
+        .await
+        .context("Failed to submit query to list account invitations")?
+        .check_first_real_error()
+        .context("Failed to list account invitations")?
+        .take::<Vec<Invitation>>(0)?;
+
+    Ok(Json(ListInvitationsResponse { invitations }))
+}
+
+/// Periodically deletes expired invitations of every account.
+pub async fn prune_expired_invitations_periodically() {
+    for_each_account_periodically(
+        INVITATION_PRUNING_INTERVAL,
+        "invitation pruning",
+        |account| async move { prune_expired_invitations(&account).await },
+    )
+    .await;
+}
+
+#[instrument(err, skip_all, fields(account_id = account.id()))]
+async fn prune_expired_invitations(account: &Account) -> Result<()> {
+    let pruned = accounts_db()
+        .await?
+        .query("RETURN array::len(DELETE account_invitation WHERE account == $account AND expires_at <= time::now() RETURN BEFORE)")
+        .bind(("account", surrealdb::sql::Thing::from(account)))
+        .await
+        .context("Failed to submit query to prune expired account invitations")?
+        .check_first_real_error()
+        .context("Failed to prune expired account invitations")?
+        .take::<Option<usize>>(0)?
+        .unwrap_or(0);
+
+    if pruned > 0 {
+        info!(invitations = pruned, "Pruned expired account invitations");
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CreateInvitationRequest {
+    email: String,
+    role: AccountRole,
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct CreateInvitationResponse {
+    invitation: Invitation,
+    invitation_token: String,
+}
+
+// Invitation tokens have the form `<invitation ID>.<hex encoded secret>`. Only a hash of the secret is stored, so the
+// token value is only available in the response to this request.
+#[instrument(err, skip(auth, account, req))]
+pub(crate) async fn create_invitation(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Json(req): Json<CreateInvitationRequest>,
+) -> Result<Json<CreateInvitationResponse>> {
+    auth.ensure_account_admin(account.id()).await?;
+
+    let email = req.email.trim().to_lowercase();
+    if !email.contains('@') {
+        bad_request!("Invalid email address");
+    }
+
+    let invitation_id = Uuid::now_v7().simple().to_string();
+    let secret = rand::thread_rng().r#gen::<[u8; 32]>();
+    let invitation_token = format!("{invitation_id}.{}", hex::encode(secret));
+
+    let invitation = accounts_db()
+        .await?
+        .query(format!("CREATE $invitation CONTENT {{ account: $account, email: $email, role: $role, token_hash: $token_hash, created_by: $created_by, expires_at: time::now() + {INVITATION_TTL} }}"))
+        .bind(("invitation", invitation_thing(&invitation_id)))
+        .bind(("account", surrealdb::sql::Thing::from(&account)))
+        .bind(("email", email))
+        .bind(("role", req.role))
+        .bind((
+            "token_hash",
+            surrealdb::sql::Bytes::from(Sha256::digest(secret).to_vec()),
+        ))
+        .bind(("created_by", surrealdb::sql::Thing::from(auth.principal())))
+        .await
+        .context("Failed to submit query to create account invitation")?
+        .check_first_real_error()
+        .context("Failed to create account invitation")?
+        .take::<Option<Invitation>>(0)?
+        .expect("Create account invitation query should return an invitation instance");
+
+    info!(invitation_id, role = ?req.role, "Created account invitation");
+
+    Ok(Json(CreateInvitationResponse {
+        invitation,
+        invitation_token,
+    }))
+}
+
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn revoke_invitation(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<()>> {
+    auth.ensure_account_admin(account.id()).await?;
+
+    let Some(invitation_id) = params.get("invitation_id") else {
+        bail!("Missing invitation_id");
+    };
+
+    let revoked = accounts_db()
+        .await?
+        .query("DELETE $invitation WHERE account == $account RETURN BEFORE")
+        .bind(("invitation", invitation_thing(invitation_id)))
+        .bind(("account", surrealdb::sql::Thing::from(&account)))
+        .await
+        .context("Failed to submit query to revoke account invitation")?
+        .check_first_real_error()
+        .context("Failed to revoke account invitation")?
+        .take::<Vec<surrealdb::sql::Object>>(0)?;
+
+    if revoked.is_empty() {
+        not_found!("Invitation not found");
+    }
+
+    Ok(Json(()))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct AcceptInvitationRequest {
+    invitation_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct AcceptedInvitation {
+    #[serde(deserialize_with = "surrealdb_deserializers::string::deserialize")]
+    account_id: String,
+    role: AccountRole,
+    email: String,
+    // Email of the accepting user if they sign in with SAML, see `saml`
+    user_email: Option<String>,
+}
+
+// Grants the signed in user access to the invitation's account if the invitation was sent to their email. Users who
+// already have access keep their current role.
+#[instrument(err, skip_all)]
+pub(crate) async fn accept_invitation(
+    Extension(auth): Extension<DashboardAuth>,
+    audit_request: AuditRequest,
+    Json(req): Json<AcceptInvitationRequest>,
+) -> Result<Json<AccountPublic>> {
+    let Some((invitation_id, secret)) = req.invitation_token.split_once('.') else {
+        bad_request!("Invalid invitation token");
+    };
+
+    let Ok(secret) = hex::decode(secret) else {
+        bad_request!("Invalid invitation token");
+    };
+
+    let token_hash = surrealdb::sql::Bytes::from(Sha256::digest(secret).to_vec());
+
+    auth.principal().ensure_user_record_exists().await?;
+
+    let db = accounts_db().await?;
+
+    let Some(invitation) = db
+        .query("SELECT record::id(account) AS account_id, role, email, $user.email AS user_email FROM $invitation WHERE token_hash == $token_hash AND expires_at > time::now() AND account.deleted_at IS NONE")
+        .bind(("invitation", invitation_thing(invitation_id)))
+        .bind(("token_hash", token_hash.clone()))
+        .bind(("user", surrealdb::sql::Thing::from(auth.principal())))
+        .await
+        .context("Failed to submit query to get account invitation")?
+        .check_first_real_error()
+        .context("Failed to get account invitation")?
+        .take::<Vec<AcceptedInvitation>>(0)?
+        .pop()
+    else {
+        warn!(invitation_id, "Invitation not found, expired, or token is invalid");
+        not_found!("Invitation not found");
+    };
+
+    let Some(user_email) = auth
+        .email()
+        .map(str::to_owned)
+        .or(invitation.user_email)
+        .map(|email| email.to_lowercase())
+    else {
+        warn!(invitation_id, "Accepting user has no verified email");
+        forbidden!("Invitations can only be accepted by users signed in with a verified email");
+    };
+
+    if user_email != invitation.email {
+        warn!(invitation_id, "Invitation was sent to another email");
+        forbidden!("Invitation was sent to another email");
+    }
+
+    // The invitation may have been accepted or revoked since it was fetched above, so it is claimed before access is
+    // granted. Concurrent accepts conflict on deleting the invitation.
+    db.query(BeginStatement::default())
+        .query("LET $accepted = DELETE $invitation WHERE token_hash == $token_hash AND expires_at > time::now() RETURN BEFORE;")
+        .query("IF array::len($accepted) == 0 { THROW \"Invitation is no longer pending\" };")
+        .query("IF count(SELECT 1 FROM has_access WHERE in == $user AND out == $account) == 0 { RELATE $user->has_access->$account SET role = $role RETURN NONE };")
+        .query(CommitStatement::default())
+        .bind(("invitation", invitation_thing(invitation_id)))
+        .bind(("token_hash", token_hash))
+        .bind((
+            "account",
+            surrealdb::sql::Thing::from(("account", invitation.account_id.as_str())),
+        ))
+        .bind(("user", surrealdb::sql::Thing::from(auth.principal())))
+        .bind(("role", invitation.role))
+        .await
+        .context("Failed to submit query to accept account invitation")?
+        .check_first_real_error()
+        .context("Failed to accept account invitation")?;
+
+    info!(
+        invitation_id,
+        account_id = invitation.account_id,
+        "Accepted account invitation"
+    );
+
+    // Failures are logged by `audit::record`, and must not fail the accepted invitation
+    let _ = audit::record(
+        &invitation.account_id,
+        auth.principal(),
+        "invitation.accept",
+        Some(HashMap::from([(
+            "invitation_id".to_string(),
+            invitation_id.to_string(),
+        )])),
+        audit_request,
+    )
+    .await;
+
+    let Some(account) = account_cache::get_account(&invitation.account_id).await? else {
+        not_found!("Account not found");
+    };
+
+    Ok(Json(account.into()))
+}
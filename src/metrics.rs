@@ -0,0 +1,240 @@
+use std::{sync::LazyLock, time::Instant};
+
+use axum::{
+    extract::{MatchedPath, Request},
+    http::{StatusCode, header::CONTENT_TYPE},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use prometheus::{
+    Encoder as _, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    Opts, Registry, TextEncoder, core::Collector,
+};
+use tracing::warn;
+
+pub(crate) static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::new);
+
+fn register<M: Collector + Clone + 'static>(metric: M) -> M {
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("metric should only be registered once");
+
+    metric
+}
+
+pub(crate) static SURREALDB_QUERY_DURATION_SECONDS: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "surrealdb_query_duration_seconds",
+                "Duration of SurrealDB queries, labeled by the span issuing the query",
+            ),
+            &["query"],
+        )
+        .expect("surrealdb_query_duration_seconds metric should be valid"),
+    )
+});
+
+pub(crate) static SURREALDB_QUERY_STATEMENTS: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "surrealdb_query_statements",
+                "Number of statement results returned by SurrealDB queries",
+            )
+            .buckets(
+                prometheus::exponential_buckets(1.0, 4.0, 8).expect("buckets should be valid"),
+            ),
+            &["query"],
+        )
+        .expect("surrealdb_query_statements metric should be valid"),
+    )
+});
+
+pub(crate) static SURREALDB_QUERY_ERRORS_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "surrealdb_query_errors_total",
+                "Number of SurrealDB query errors, labeled by error type",
+            ),
+            &["query", "error_type"],
+        )
+        .expect("surrealdb_query_errors_total metric should be valid"),
+    )
+});
+
+pub(crate) static HTTP_REQUESTS_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "http_requests_total",
+                "Number of HTTP requests, labeled by method, route, and response status",
+            ),
+            &["method", "route", "status"],
+        )
+        .expect("http_requests_total metric should be valid"),
+    )
+});
+
+pub(crate) static HTTP_REQUEST_DURATION_SECONDS: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Duration of HTTP requests, labeled by method and route",
+            ),
+            &["method", "route"],
+        )
+        .expect("http_request_duration_seconds metric should be valid"),
+    )
+});
+
+pub(crate) static REPORT_INGESTION_DURATION_SECONDS: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(
+            HistogramVec::new(
+                HistogramOpts::new(
+                    "report_ingestion_duration_seconds",
+                    "Duration of report ingestion, labeled by whether the report was ingested or failed",
+                ),
+                &["outcome"],
+            )
+            .expect("report_ingestion_duration_seconds metric should be valid"),
+        )
+});
+
+pub(crate) static REPORT_RESOURCE_TREE_DEPTH: LazyLock<Histogram> = LazyLock::new(|| {
+    register(
+        Histogram::with_opts(
+            HistogramOpts::new(
+                "report_resource_tree_depth",
+                "Depth of resource trees in ingested reports",
+            )
+            .buckets(prometheus::linear_buckets(1.0, 2.0, 16).expect("buckets should be valid")),
+        )
+        .expect("report_resource_tree_depth metric should be valid"),
+    )
+});
+
+// Counted rather than logged, as a single report from an older agent can contain thousands of timestamps. Tracks when
+// the epoch seconds deprecation window in `timestamp` can close.
+pub(crate) static DEPRECATED_EPOCH_TIMESTAMPS_TOTAL: LazyLock<IntCounter> = LazyLock::new(|| {
+    register(
+        IntCounter::new(
+            "deprecated_epoch_timestamps_total",
+            "Number of request timestamps received as deprecated epoch seconds instead of RFC3339 datetimes",
+        )
+        .expect("deprecated_epoch_timestamps_total metric should be valid"),
+    )
+});
+
+pub(crate) static AUTH_FAILURES_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "auth_failures_total",
+                "Number of rejected request credentials, labeled by authentication method",
+            ),
+            &["method"],
+        )
+        .expect("auth_failures_total metric should be valid"),
+    )
+});
+
+pub(crate) static REPORT_KEY_BACKOFF_REJECTIONS_TOTAL: LazyLock<IntCounter> = LazyLock::new(|| {
+    register(
+        IntCounter::new(
+            "report_key_backoff_rejections_total",
+            "Number of report requests refused without authentication because their report key value recently failed",
+        )
+        .expect("report_key_backoff_rejections_total metric should be valid"),
+    )
+});
+
+pub(crate) static REPORT_KEY_BACKOFF_TRACKED_VALUES: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(
+        IntGauge::new(
+            "report_key_backoff_tracked_values",
+            "Number of recently failed report key values tracked for backoff",
+        )
+        .expect("report_key_backoff_tracked_values metric should be valid"),
+    )
+});
+
+pub(crate) static SURREALDB_POOLED_CONNECTIONS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(
+        IntGauge::new(
+            "surrealdb_pooled_connections",
+            "Number of open connections to account resources databases in the connection pool",
+        )
+        .expect("surrealdb_pooled_connections metric should be valid"),
+    )
+});
+
+// Labeled by account so capacity alerts can identify the accounts whose ingestion outgrows their storage capacity.
+// Throttling is rare, so only few accounts are ever labeled.
+pub(crate) static STORAGE_THROTTLES_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "storage_throttles_total",
+                "Number of report ingestions rejected because storage throttled the account's queries",
+            ),
+            &["account_id"],
+        )
+        .expect("storage_throttles_total metric should be valid"),
+    )
+});
+
+pub(crate) static EVENTS_PRUNED_TOTAL: LazyLock<IntCounter> = LazyLock::new(|| {
+    register(
+        IntCounter::new(
+            "events_pruned_total",
+            "Number of events deleted because they were not seen within their account's event retention",
+        )
+        .expect("events_pruned_total metric should be valid"),
+    )
+});
+
+// Records request counts and durations. Must be added as a route layer so the matched route is available, which keeps
+// labels low-cardinality by omitting path parameters such as account IDs.
+pub(crate) async fn record_request(req: Request, next: Next) -> Response {
+    let method = req.method().to_string();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "unmatched".to_string(), |path| path.as_str().to_string());
+
+    let start = Instant::now();
+    let response = next.run(req).await;
+
+    HTTP_REQUEST_DURATION_SECONDS
+        .with_label_values(&[&method, &route])
+        .observe(start.elapsed().as_secs_f64());
+    HTTP_REQUESTS_TOTAL
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .inc();
+
+    response
+}
+
+// Serves all metrics in the Prometheus text format. Like the admin endpoints, `/metrics` is authenticated with the
+// `ADMIN_TOKEN` bearer token and isn't served by instances without one, see `admin`.
+pub(crate) async fn metrics() -> Response {
+    let mut buffer = Vec::new();
+
+    if let Err(err) = TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer) {
+        warn!(?err, "Failed to encode metrics");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    ([(CONTENT_TYPE, prometheus::TEXT_FORMAT)], buffer).into_response()
+}
+
+// Returns a low-cardinality label for the current operation. All handlers and database helpers are instrumented, so
+// the name of the current span identifies the code issuing a query.
+pub(crate) fn current_span_label() -> &'static str {
+    tracing::Span::current()
+        .metadata()
+        .map_or("unknown", tracing::Metadata::name)
+}
@@ -0,0 +1,317 @@
+// In-app notification inbox of dashboard users, for users who don't receive notifications through webhooks. Work that
+// users should hear about, like finished integrity checks and archived stale resources, writes a `notification` to the
+// inbox of the users concerned: the user who started the work, or every member of the account. Jobs started by users
+// write their notification when they finish, see `Job::finish`. The dashboard lists the inbox, shows the number of
+// unread notifications as a badge, and marks notifications read.
+//
+// Inboxes are per user, across all of the user's accounts. Notifications of accounts the user no longer has access to
+// are hidden, and notifications are deleted after `NOTIFICATION_RETENTION`. Failing to write a notification never fails
+// the work it is about.
+
+use std::collections::HashMap;
+
+use axum::{
+    Extension, Json,
+    extract::{Path, Query},
+};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::{instrument, warn};
+use uuid::Uuid;
+
+use archodex_error::{
+    anyhow::{Context as _, bail},
+    bad_request, not_found,
+};
+
+use crate::{
+    Result,
+    account::Account,
+    auth::DashboardAuth,
+    db::{QueryCheckFirstRealError as _, accounts_db},
+    surrealdb_deserializers,
+    user::User,
+};
+
+const NOTIFICATION_RETENTION: &str = "90d";
+
+const DEFAULT_LIST_NOTIFICATIONS_LIMIT: u32 = 50;
+const MAX_LIST_NOTIFICATIONS_LIMIT: u32 = 200;
+
+// Notifications of accounts the user is no longer a member of are hidden
+const VISIBLE_NOTIFICATIONS_CONDITION: &str = "user == $user AND (account IS NONE OR account INSIDE (SELECT VALUE out FROM has_access WHERE in == $user))";
+
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum NotificationKind {
+    // An integrity check started by the user finished, see `integrity`
+    IntegrityCheckFinished,
+    // Resources of the account were archived because they were not seen within its staleness window, see `lifecycle`
+    StaleResourcesArchived,
+    // A member of the account requested a report key that needs admin approval, see `report_api_key_requests`
+    ReportApiKeyRequested,
+    // An account created by the user finished provisioning, see `provisioning`
+    #[cfg(feature = "archodex-com")]
+    AccountProvisioned,
+}
+
+// A notification to write to inboxes
+pub(crate) struct NewNotification {
+    pub(crate) kind: NotificationKind,
+    pub(crate) message: String,
+    // Identifiers the dashboard links the notification to, e.g. `{ integrity_check_id: "..." }`
+    pub(crate) details: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
+pub(crate) struct Notification {
+    #[serde(deserialize_with = "surrealdb_deserializers::string::deserialize")]
+    id: String,
+    // Account the notification is about, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    account_id: Option<String>,
+    kind: NotificationKind,
+    message: String,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    details: HashMap<String, String>,
+    created_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    read_at: Option<DateTime<Utc>>,
+}
+
+fn notification_thing(notification_id: &str) -> Result<surrealdb::sql::Thing> {
+    let Ok(notification_id) = Uuid::try_parse(notification_id) else {
+        bad_request!("Invalid notification ID");
+    };
+
+    Ok(surrealdb::sql::Thing::from((
+        "notification",
+        notification_id.hyphenated().to_string().as_str(),
+    )))
+}
+
+enum Recipients<'a> {
+    User(&'a User),
+    AccountMembers,
+}
+
+// Writes a notification to the inbox of a user, optionally about one of the user's accounts
+pub(crate) async fn notify_user(
+    user: &User,
+    account_id: Option<&str>,
+    notification: NewNotification,
+) {
+    if let Err(err) = create(Recipients::User(user), account_id, notification).await {
+        warn!(?err, "Failed to write notification to user inbox");
+    }
+}
+
+// Writes a notification about an account to the inboxes of all its members
+pub(crate) async fn notify_account_members(account: &Account, notification: NewNotification) {
+    if let Err(err) = create(Recipients::AccountMembers, Some(account.id()), notification).await {
+        warn!(
+            ?err,
+            "Failed to write notification to account member inboxes"
+        );
+    }
+}
+
+#[instrument(err, skip(recipients, notification), fields(kind = ?notification.kind))]
+async fn create(
+    recipients: Recipients<'_>,
+    account_id: Option<&str>,
+    notification: NewNotification,
+) -> Result<()> {
+    let (recipients, user) = match recipients {
+        Recipients::User(user) => ("[$user]", Some(user)),
+        Recipients::AccountMembers => (
+            "(SELECT VALUE in FROM has_access WHERE out == $account)",
+            None,
+        ),
+    };
+
+    accounts_db()
+        .await?
+        .query(format!("LET $recipients = {recipients};"))
+        .query(format!(
+            "DELETE notification WHERE user INSIDE $recipients AND created_at < time::now() - {NOTIFICATION_RETENTION};"
+        ))
+        .query("FOR $recipient IN $recipients {
+            CREATE type::thing('notification', <string> rand::uuid::v7()) CONTENT { user: $recipient, account: $account, kind: $kind, message: $message, details: $details } RETURN NONE;
+        };")
+        .bind(("user", user.map(surrealdb::sql::Thing::from)))
+        .bind((
+            "account",
+            account_id.map(|account_id| surrealdb::sql::Thing::from(("account", account_id))),
+        ))
+        .bind(("kind", notification.kind))
+        .bind(("message", notification.message))
+        .bind(("details", notification.details))
+        .await
+        .context("Failed to submit query to write notification")?
+        .check_first_real_error()
+        .context("Failed to write notification")?;
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ListNotificationsRequest {
+    // Only lists unread notifications
+    #[serde(default)]
+    unread: bool,
+    limit: Option<u32>,
+    // ID of the last notification of the previous page
+    cursor: Option<String>,
+}
+
+#[derive(Debug, JsonSchema, Serialize)]
+pub(crate) struct ListNotificationsResponse {
+    notifications: Vec<Notification>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+}
+
+// Lists notifications in the user's inbox from newest to oldest, one page at a time. Notification IDs are time ordered,
+// so the ID of the last notification of a page is the cursor of the next page.
+#[instrument(err, skip(auth))]
+pub(crate) async fn list_notifications(
+    Extension(auth): Extension<DashboardAuth>,
+    Query(req): Query<ListNotificationsRequest>,
+) -> Result<Json<ListNotificationsResponse>> {
+    let limit = req.limit.unwrap_or(DEFAULT_LIST_NOTIFICATIONS_LIMIT);
+    if limit == 0 || limit > MAX_LIST_NOTIFICATIONS_LIMIT {
+        bad_request!("Notification `limit` must be between 1 and {MAX_LIST_NOTIFICATIONS_LIMIT}");
+    }
+
+    let cursor = req.cursor.as_deref().map(notification_thing).transpose()?;
+
+    let mut conditions = vec![VISIBLE_NOTIFICATIONS_CONDITION];
+
+    for (is_set, condition) in [
+        (cursor.is_some(), "id < $cursor"),
+        (req.unread, "read_at IS NONE"),
+    ] {
+        if is_set {
+            conditions.push(condition);
+        }
+    }
+
+    // Fetch one extra notification to find out whether there is another page
+    let mut notifications = accounts_db()
+        .await?
+        .query(format!(
+            "SELECT id, (IF account {{ record::id(account) }}) AS account_id, kind, message, details, created_at, read_at FROM notification WHERE {} ORDER BY id DESC LIMIT $limit",
+            conditions.join(" AND ")
+        ))
+        .bind(("user", surrealdb::sql::Thing::from(auth.principal())))
+        .bind(("limit", limit + 1))
+        .bind(("cursor", cursor))
+        .await
+        .context("Failed to submit query to list notifications")?
+        .check_first_real_error()
+        .context("Failed to list notifications")?
+        .take::<Vec<Notification>>(0)?;
+
+    let next_cursor = if notifications.len() > limit as usize {
+        notifications.truncate(limit as usize);
+        notifications
+            .last()
+            .map(|notification| notification.id.clone())
+    } else {
+        None
+    };
+
+    Ok(Json(ListNotificationsResponse {
+        notifications,
+        next_cursor,
+    }))
+}
+
+#[derive(Debug, JsonSchema, Serialize)]
+pub(crate) struct UnreadNotificationCountResponse {
+    unread: u64,
+}
+
+// Counts the unread notifications in the user's inbox, for the dashboard's notification badge
+#[instrument(err, skip(auth))]
+pub(crate) async fn unread_notification_count(
+    Extension(auth): Extension<DashboardAuth>,
+) -> Result<Json<UnreadNotificationCountResponse>> {
+    let unread = accounts_db()
+        .await?
+        .query(format!(
+            "RETURN (SELECT count() AS count FROM notification WHERE {VISIBLE_NOTIFICATIONS_CONDITION} AND read_at IS NONE GROUP ALL)[0].count ?? 0"
+        ))
+        .bind(("user", surrealdb::sql::Thing::from(auth.principal())))
+        .await
+        .context("Failed to submit query to count unread notifications")?
+        .check_first_real_error()
+        .context("Failed to count unread notifications")?
+        .take::<Option<u64>>(0)?
+        .unwrap_or(0);
+
+    Ok(Json(UnreadNotificationCountResponse { unread }))
+}
+
+fn notification_id(params: &HashMap<String, String>) -> Result<surrealdb::sql::Thing> {
+    let Some(notification_id) = params.get("notification_id") else {
+        bail!("Missing notification_id");
+    };
+
+    notification_thing(notification_id)
+}
+
+async fn set_read(user: &User, notification: surrealdb::sql::Thing, read: bool) -> Result<()> {
+    let updated = accounts_db()
+        .await?
+        .query("UPDATE $notification SET read_at = IF $read { read_at ?? time::now() } ELSE { NONE } WHERE user == $user RETURN VALUE id")
+        .bind(("notification", notification))
+        .bind(("user", surrealdb::sql::Thing::from(user)))
+        .bind(("read", read))
+        .await
+        .context("Failed to submit query to mark notification")?
+        .check_first_real_error()
+        .context("Failed to mark notification")?
+        .take::<Vec<surrealdb::sql::Thing>>(0)?;
+
+    if updated.is_empty() {
+        not_found!("Notification not found");
+    }
+
+    Ok(())
+}
+
+#[instrument(err, skip(auth))]
+pub(crate) async fn mark_notification_read(
+    Extension(auth): Extension<DashboardAuth>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<()> {
+    set_read(auth.principal(), notification_id(&params)?, true).await
+}
+
+#[instrument(err, skip(auth))]
+pub(crate) async fn mark_notification_unread(
+    Extension(auth): Extension<DashboardAuth>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<()> {
+    set_read(auth.principal(), notification_id(&params)?, false).await
+}
+
+#[instrument(err, skip(auth))]
+pub(crate) async fn mark_all_notifications_read(
+    Extension(auth): Extension<DashboardAuth>,
+) -> Result<()> {
+    accounts_db()
+        .await?
+        .query("UPDATE notification SET read_at = time::now() WHERE user == $user AND read_at IS NONE RETURN NONE")
+        .bind(("user", surrealdb::sql::Thing::from(auth.principal())))
+        .await
+        .context("Failed to submit query to mark all notifications read")?
+        .check_first_real_error()
+        .context("Failed to mark all notifications read")?;
+
+    Ok(())
+}
@@ -0,0 +1,433 @@
+// Dashboard access tokens are JWTs verified against the JWKS of their issuer. The primary issuer is configured by the
+// `AUTH_*` env vars and defaults to the Archodex Cognito user pool. Self-hosted deployments may also configure generic
+// OpenID Connect providers, e.g. Google or Apple, whose ID tokens are then accepted as dashboard credentials. The JWKS
+// and endpoints of these providers are found through their discovery documents.
+//
+// The dashboard signs users in to these providers with the authorization code flow. The backend exchanges
+// authorization codes for tokens on behalf of the dashboard, so provider client secrets never leave the backend.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use josekit::{
+    jwk::JwkSet,
+    jws::alg::rsassa::{RsassaJwsAlgorithm, RsassaJwsVerifier},
+    jwt::{self, JwtPayload},
+};
+use tracing::{debug, info, instrument, warn};
+
+use archodex_error::anyhow::{self, Context as _, bail, ensure};
+
+// Providers rotate their signing keys, so cached JWKS and discovery documents are refreshed periodically
+const OIDC_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+// Minimum age of a cached JWKS before it is fetched again for a token signed with an unknown key
+const JWKS_REFETCH_INTERVAL: Duration = Duration::from_secs(60);
+
+pub(crate) struct Jwks {
+    verifiers: HashMap<String, RsassaJwsVerifier>,
+}
+
+impl Jwks {
+    // Providers may publish keys for other algorithms or uses alongside their RSA signing keys. Keys that can't verify
+    // JWTs here are skipped, rather than failing verification with every key.
+    fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let set = JwkSet::from_bytes(bytes).context("Failed to parse JWKS")?;
+
+        let verifiers = set
+            .keys()
+            .iter()
+            .filter_map(|jwk| {
+                let Some(key_id) = jwk.key_id() else {
+                    debug!("Skipping JWK without 'kid' field");
+                    return None;
+                };
+
+                let algorithm = match jwk.algorithm() {
+                    Some("RS256") => RsassaJwsAlgorithm::Rs256,
+                    Some("RS384") => RsassaJwsAlgorithm::Rs384,
+                    Some("RS512") => RsassaJwsAlgorithm::Rs512,
+                    alg => {
+                        debug!(
+                            key_id,
+                            alg, "Skipping JWK with unsupported or missing algorithm"
+                        );
+                        return None;
+                    }
+                };
+
+                match algorithm.verifier_from_jwk(jwk) {
+                    Ok(verifier) => Some((key_id.to_owned(), verifier)),
+                    Err(err) => {
+                        warn!(
+                            key_id,
+                            ?err,
+                            "Skipping JWK that a verifier can't be created from"
+                        );
+                        None
+                    }
+                }
+            })
+            .collect::<HashMap<_, _>>();
+
+        ensure!(!verifiers.is_empty(), "JWKS has no supported keys");
+
+        Ok(Self { verifiers })
+    }
+
+    fn has_key(&self, token: &str) -> bool {
+        key_id(token).is_some_and(|key_id| self.verifiers.contains_key(&key_id))
+    }
+
+    // Verifies the signature of a JWT with the key named by its `kid` header and returns its payload. Claims are
+    // validated by the caller.
+    pub(crate) fn verify(&self, token: &str) -> anyhow::Result<JwtPayload> {
+        let Some(key_id) = key_id(token) else {
+            bail!("Failed to read 'kid' field of JWT header");
+        };
+
+        let Some(verifier) = self.verifiers.get(&key_id) else {
+            bail!("JWT signed with unknown key {key_id:?}");
+        };
+
+        let (payload, _header) =
+            jwt::decode_with_verifier(token, verifier).context("Failed to verify JWT signature")?;
+
+        Ok(payload)
+    }
+}
+
+fn key_id(token: &str) -> Option<String> {
+    jwt::decode_header(token)
+        .ok()?
+        .claim("kid")?
+        .as_str()
+        .map(str::to_owned)
+}
+
+type Cache<T> = LazyLock<Mutex<HashMap<String, (Instant, Arc<T>)>>>;
+
+static JWKS_CACHE: Cache<Jwks> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// Concurrent requests may fetch the same URL while it is not cached. That is harmless, as the fetched values are the
+// same.
+async fn cached<T, F, Fut>(
+    cache: &Cache<T>,
+    url: &str,
+    max_age: Duration,
+    fetch: F,
+) -> anyhow::Result<Arc<T>>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    if let Some((fetched_at, value)) = cache.lock().expect("OIDC cache mutex poisoned").get(url)
+        && fetched_at.elapsed() < max_age
+    {
+        return Ok(value.clone());
+    }
+
+    let value = Arc::new(fetch().await?);
+
+    cache
+        .lock()
+        .expect("OIDC cache mutex poisoned")
+        .insert(url.to_owned(), (Instant::now(), value.clone()));
+
+    Ok(value)
+}
+
+async fn fetch(url: &str) -> anyhow::Result<Vec<u8>> {
+    let response = reqwest::get(url)
+        .await
+        .with_context(|| format!("Failed to request {url}"))?;
+
+    ensure!(
+        response.status().is_success(),
+        "Request to {url} failed with status {}",
+        response.status()
+    );
+
+    Ok(response
+        .bytes()
+        .await
+        .with_context(|| format!("Failed to receive response from {url}"))?
+        .to_vec())
+}
+
+async fn fetch_jwks(jwks_url: &str, max_age: Duration) -> anyhow::Result<Arc<Jwks>> {
+    cached(&JWKS_CACHE, jwks_url, max_age, || async {
+        info!("Fetching JWKS from {jwks_url}");
+
+        Jwks::from_bytes(&fetch(jwks_url).await?)
+    })
+    .await
+}
+
+#[instrument(err)]
+pub(crate) async fn jwks(jwks_url: &str) -> anyhow::Result<Arc<Jwks>> {
+    fetch_jwks(jwks_url, OIDC_CACHE_TTL).await
+}
+
+// Returns the JWKS to verify the token with. Providers may sign tokens with new keys before the cached JWKS expires, so
+// the JWKS is fetched again once if the token is signed with an unknown key. Tokens with made up keys can't make the
+// JWKS be fetched more than once every `JWKS_REFETCH_INTERVAL`.
+#[instrument(err, skip(token))]
+pub(crate) async fn jwks_for_token(jwks_url: &str, token: &str) -> anyhow::Result<Arc<Jwks>> {
+    let jwks = jwks(jwks_url).await?;
+
+    if jwks.has_key(token) {
+        return Ok(jwks);
+    }
+
+    fetch_jwks(jwks_url, JWKS_REFETCH_INTERVAL).await
+}
+
+#[cfg(not(feature = "archodex-com"))]
+pub(crate) use providers::{
+    ExchangeCodeRequest, ListProvidersResponse, OidcProvider, discovery, exchange_code,
+    list_providers, unverified_issuer,
+};
+
+#[cfg(not(feature = "archodex-com"))]
+mod providers {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, LazyLock, Mutex},
+    };
+
+    use axum::{Json, extract::Path};
+    use base64::{Engine as _, prelude::BASE64_URL_SAFE_NO_PAD};
+    use schemars::JsonSchema;
+    use serde::{Deserialize, Serialize};
+    use tracing::{info, instrument, warn};
+
+    use archodex_error::{
+        anyhow::{self, Context as _, bail, ensure},
+        bad_request, not_found,
+    };
+
+    use super::{Cache, OIDC_CACHE_TTL, cached, fetch};
+    use crate::{Result, env::Env};
+
+    #[derive(Debug, Deserialize)]
+    pub(crate) struct DiscoveryDocument {
+        pub(crate) issuer: String,
+        pub(crate) jwks_uri: String,
+        pub(crate) authorization_endpoint: Option<String>,
+        pub(crate) token_endpoint: Option<String>,
+    }
+
+    static DISCOVERY_CACHE: Cache<DiscoveryDocument> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+    #[instrument(err)]
+    pub(crate) async fn discovery(issuer: &str) -> anyhow::Result<Arc<DiscoveryDocument>> {
+        let discovery_url = format!(
+            "{}/.well-known/openid-configuration",
+            issuer.trim_end_matches('/')
+        );
+
+        cached(&DISCOVERY_CACHE, &discovery_url, OIDC_CACHE_TTL, || async {
+            info!("Fetching OIDC discovery document from {discovery_url}");
+
+            let document =
+                serde_json::from_slice::<DiscoveryDocument>(&fetch(&discovery_url).await?)
+                    .context("Failed to parse OIDC discovery document")?;
+
+            ensure!(
+                document.issuer == issuer,
+                "OIDC discovery document issuer {:?} does not match configured issuer {issuer:?}",
+                document.issuer
+            );
+
+            Ok(document)
+        })
+        .await
+    }
+
+    // Reads the issuer of a JWT without verifying it, to select the provider whose keys the JWT is then verified with
+    pub(crate) fn unverified_issuer(token: &str) -> Option<String> {
+        #[derive(Deserialize)]
+        struct Claims {
+            iss: Option<String>,
+        }
+
+        let payload = token.split('.').nth(1)?;
+        let payload = BASE64_URL_SAFE_NO_PAD.decode(payload).ok()?;
+
+        serde_json::from_slice::<Claims>(&payload).ok()?.iss
+    }
+
+    /// A generic OpenID Connect provider configured with the `OIDC_<NAME>_*` env vars
+    pub(crate) struct OidcProvider {
+        pub(crate) name: String,
+        pub(crate) issuer: String,
+        pub(crate) client_id: String,
+        // Apple client secrets are JWTs signed by the client, which must be regenerated before they expire
+        pub(crate) client_secret: Option<String>,
+    }
+
+    #[derive(JsonSchema, Serialize)]
+    pub(crate) struct OidcProviderPublic {
+        name: String,
+        issuer: String,
+        client_id: String,
+        authorization_endpoint: Option<String>,
+    }
+
+    #[derive(JsonSchema, Serialize)]
+    pub(crate) struct ListProvidersResponse {
+        providers: Vec<OidcProviderPublic>,
+    }
+
+    // Lists configured providers so the dashboard can offer them as sign in options
+    #[instrument(err)]
+    pub(crate) async fn list_providers() -> Result<Json<ListProvidersResponse>> {
+        let mut providers = Vec::new();
+
+        for provider in Env::oidc_providers() {
+            let discovery = discovery(&provider.issuer).await?;
+
+            providers.push(OidcProviderPublic {
+                name: provider.name.clone(),
+                issuer: provider.issuer.clone(),
+                client_id: provider.client_id.clone(),
+                authorization_endpoint: discovery.authorization_endpoint.clone(),
+            });
+        }
+
+        Ok(Json(ListProvidersResponse { providers }))
+    }
+
+    #[derive(Debug, Deserialize, JsonSchema)]
+    #[serde(deny_unknown_fields)]
+    pub(crate) struct ExchangeCodeRequest {
+        code: String,
+        redirect_uri: String,
+        code_verifier: Option<String>,
+    }
+
+    // Returns the provider's token response, whose `id_token` the dashboard uses as its access token
+    #[instrument(err, skip(req))]
+    pub(crate) async fn exchange_code(
+        Path(params): Path<HashMap<String, String>>,
+        Json(req): Json<ExchangeCodeRequest>,
+    ) -> Result<Json<serde_json::Value>> {
+        let Some(provider_name) = params.get("provider") else {
+            bail!("Missing provider");
+        };
+
+        let Some(provider) = Env::oidc_providers()
+            .iter()
+            .find(|provider| provider.name == *provider_name)
+        else {
+            not_found!("OIDC provider not found");
+        };
+
+        let discovery = discovery(&provider.issuer).await?;
+
+        let Some(token_endpoint) = &discovery.token_endpoint else {
+            bail!(
+                "OIDC provider {} does not have a token endpoint",
+                provider.name
+            );
+        };
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", req.code.as_str()),
+            ("redirect_uri", req.redirect_uri.as_str()),
+            ("client_id", provider.client_id.as_str()),
+        ];
+
+        if let Some(client_secret) = &provider.client_secret {
+            form.push(("client_secret", client_secret.as_str()));
+        }
+
+        if let Some(code_verifier) = &req.code_verifier {
+            form.push(("code_verifier", code_verifier.as_str()));
+        }
+
+        let response = reqwest::Client::new()
+            .post(token_endpoint)
+            .form(&form)
+            .send()
+            .await
+            .context("Failed to send authorization code exchange request")?;
+
+        let status = response.status();
+        let body = response
+            .bytes()
+            .await
+            .context("Failed to receive authorization code exchange response")?;
+        let body = serde_json::from_slice::<serde_json::Value>(&body)
+            .context("Failed to parse authorization code exchange response")?;
+
+        if !status.is_success() {
+            warn!(%status, error = %body["error"], "Authorization code exchange failed");
+            bad_request!("Authorization code exchange failed");
+        }
+
+        Ok(Json(body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use josekit::{
+        jws::{JwsHeader, alg::rsassa::RsassaJwsAlgorithm},
+        jwt::{self, JwtPayload},
+    };
+
+    use super::Jwks;
+
+    #[test]
+    fn skips_unsupported_keys() {
+        let key_pair = RsassaJwsAlgorithm::Rs256.generate_key_pair(2048).unwrap();
+
+        let mut supported = key_pair.to_jwk_public_key();
+        supported.set_key_id("supported");
+        supported.set_algorithm("RS256");
+
+        let mut missing_alg = key_pair.to_jwk_public_key();
+        missing_alg.set_key_id("missing_alg");
+        missing_alg.set_parameter("alg", None).unwrap();
+
+        let mut unsupported_alg = key_pair.to_jwk_public_key();
+        unsupported_alg.set_key_id("unsupported_alg");
+        unsupported_alg.set_algorithm("ES256");
+
+        let mut missing_kid = key_pair.to_jwk_public_key();
+        missing_kid.set_algorithm("RS256");
+
+        let jwks = serde_json::json!({
+            "keys": [missing_alg.as_ref(), unsupported_alg.as_ref(), missing_kid.as_ref(), supported.as_ref()],
+        });
+        let jwks = Jwks::from_bytes(jwks.to_string().as_bytes()).unwrap();
+
+        assert_eq!(jwks.verifiers.keys().collect::<Vec<_>>(), vec!["supported"]);
+
+        let signer = RsassaJwsAlgorithm::Rs256
+            .signer_from_jwk(&key_pair.to_jwk_private_key())
+            .unwrap();
+        let mut payload = JwtPayload::new();
+        payload.set_subject("user");
+
+        for (key_id, verifies) in [("supported", true), ("missing_alg", false)] {
+            let mut header = JwsHeader::new();
+            header.set_key_id(key_id);
+            let token = jwt::encode_with_signer(&payload, &header, &signer).unwrap();
+
+            assert_eq!(jwks.has_key(&token), verifies);
+            assert_eq!(jwks.verify(&token).is_ok(), verifies);
+        }
+    }
+
+    #[test]
+    fn no_supported_keys() {
+        assert!(Jwks::from_bytes(br#"{"keys": []}"#).is_err());
+    }
+}
@@ -0,0 +1,666 @@
+// OpenAPI document of the routes integrators call, served at `GET /openapi.json` and browsable with Swagger UI at
+// `GET /docs`. Like the TypeScript definitions served by `typescript`, request and response schemas are generated from
+// the types the handlers (de)serialize, so they can't drift from the handlers. Axum routers can't be inspected once
+// built, so routes are listed by hand in `document`. A test checks that every route `router` mounts for the handlers
+// of the modules integrators call is documented.
+
+use std::sync::LazyLock;
+
+use axum::{
+    Json,
+    http::header::{CACHE_CONTROL, CONTENT_TYPE},
+    response::{Html, IntoResponse},
+};
+use schemars::{JsonSchema, SchemaGenerator, generate::SchemaSettings};
+use serde_json::{Map, Value, json};
+
+use crate::{accounts, query, report, report_api_key, report_api_keys};
+
+static DOCUMENT: LazyLock<Value> = LazyLock::new(document);
+
+// Swagger UI 5.17.14 is vendored in `assets/swagger-ui` and served alongside the page, so the docs don't run scripts
+// from a third party origin on the API's own origin
+const DOCS: &str = r##"<!DOCTYPE html>
+<html lang="en">
+  <head>
+    <meta charset="utf-8" />
+    <title>Archodex API</title>
+    <link rel="stylesheet" href="/docs/swagger-ui.css" />
+  </head>
+  <body>
+    <div id="swagger-ui"></div>
+    <script src="/docs/swagger-ui-bundle.js"></script>
+    <script>
+      window.onload = () => {
+        window.ui = SwaggerUIBundle({ url: "/openapi.json", dom_id: "#swagger-ui" });
+      };
+    </script>
+  </body>
+</html>
+"##;
+
+const SWAGGER_UI_CSS: &str = include_str!("../assets/swagger-ui/swagger-ui.css");
+const SWAGGER_UI_BUNDLE: &str = include_str!("../assets/swagger-ui/swagger-ui-bundle.js");
+
+// Cached briefly, as the asset paths stay the same when the vendored version is upgraded
+const ASSET_CACHE_CONTROL: &str = "public, max-age=3600";
+
+pub(crate) async fn openapi() -> impl IntoResponse {
+    Json(&*DOCUMENT)
+}
+
+pub(crate) async fn docs() -> Html<&'static str> {
+    Html(DOCS)
+}
+
+pub(crate) async fn swagger_ui_css() -> impl IntoResponse {
+    (
+        [
+            (CONTENT_TYPE, "text/css"),
+            (CACHE_CONTROL, ASSET_CACHE_CONTROL),
+        ],
+        SWAGGER_UI_CSS,
+    )
+}
+
+pub(crate) async fn swagger_ui_bundle() -> impl IntoResponse {
+    (
+        [
+            (CONTENT_TYPE, "text/javascript"),
+            (CACHE_CONTROL, ASSET_CACHE_CONTROL),
+        ],
+        SWAGGER_UI_BUNDLE,
+    )
+}
+
+#[derive(Clone, Copy)]
+enum Auth {
+    None,
+    Dashboard,
+    ReportApiKey,
+}
+
+struct Document {
+    // Requests are described as they are deserialized and responses as they are serialized, e.g. fields skipped when
+    // empty are optional in responses
+    requests: SchemaGenerator,
+    responses: SchemaGenerator,
+    paths: Map<String, Value>,
+}
+
+impl Document {
+    fn new() -> Self {
+        let generator = |settings: SchemaSettings| {
+            settings
+                .with(|settings| settings.definitions_path = "/components/schemas".into())
+                .into_generator()
+        };
+
+        Self {
+            requests: generator(SchemaSettings::draft2020_12().for_deserialize()),
+            responses: generator(SchemaSettings::draft2020_12().for_serialize()),
+            paths: Map::new(),
+        }
+    }
+
+    // Adds an operation for a route path in axum's syntax, e.g. `/account/:account_id/query/:type`
+    fn operation(
+        &mut self,
+        method: &'static str,
+        route: &str,
+        tag: &str,
+        auth: Auth,
+        summary: &str,
+    ) -> Operation<'_> {
+        let mut parameters = Vec::new();
+        let path = route
+            .split('/')
+            .map(|segment| match segment.strip_prefix(':') {
+                Some(name) => {
+                    parameters.push(json!({
+                        "name": name,
+                        "in": "path",
+                        "required": true,
+                        "schema": { "type": "string" },
+                    }));
+                    format!("{{{name}}}")
+                }
+                None => segment.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+
+        let security = match auth {
+            Auth::None => json!([]),
+            Auth::Dashboard => json!([{ "dashboard": [] }]),
+            Auth::ReportApiKey => json!([{ "report_api_key": [] }]),
+        };
+
+        let operation = json!({
+            "tags": [tag],
+            "summary": summary,
+            "security": security,
+            "parameters": parameters,
+            "responses": {
+                "200": { "description": "Success" },
+                "default": {
+                    "description": "Error",
+                    "content": {
+                        "application/json": {
+                            "schema": self.responses.subschema_for::<archodex_error::PublicErrorMessage>(),
+                        },
+                    },
+                },
+            },
+        });
+
+        self.paths
+            .entry(path.clone())
+            .or_insert_with(|| json!({}))
+            .as_object_mut()
+            .expect("OpenAPI path item should be an object")
+            .insert(method.to_string(), operation);
+
+        Operation {
+            document: self,
+            path,
+            method,
+        }
+    }
+
+    fn into_value(mut self) -> Value {
+        // Request and response schemas share a namespace, so types used in both must have the same schema either way
+        let mut schemas = self.responses.take_definitions(true);
+        for (name, schema) in self.requests.take_definitions(true) {
+            match schemas.get(&name) {
+                Some(existing) => debug_assert_eq!(
+                    *existing, schema,
+                    "OpenAPI schema {name} differs between requests and responses"
+                ),
+                None => {
+                    schemas.insert(name, schema);
+                }
+            }
+        }
+
+        json!({
+            "openapi": "3.1.0",
+            "info": {
+                "title": "Archodex API",
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+            "paths": self.paths,
+            "components": {
+                "schemas": schemas,
+                "securitySchemes": {
+                    "dashboard": {
+                        "type": "http",
+                        "scheme": "bearer",
+                        "bearerFormat": "JWT",
+                        "description": "Access token of a dashboard user",
+                    },
+                    "report_api_key": {
+                        "type": "apiKey",
+                        "in": "header",
+                        "name": "Authorization",
+                        "description": "Report key value, or `Bearer <ingestion token>` for a token exchanged at `/report/token`",
+                    },
+                },
+            },
+        })
+    }
+}
+
+struct Operation<'d> {
+    document: &'d mut Document,
+    path: String,
+    method: &'static str,
+}
+
+impl Operation<'_> {
+    fn get(&mut self) -> &mut Map<String, Value> {
+        self.document.paths[&self.path][self.method]
+            .as_object_mut()
+            .expect("OpenAPI operation should be an object")
+    }
+
+    fn request<T: JsonSchema>(self) -> Self {
+        self.request_content::<T>("application/json")
+    }
+
+    fn request_content<T: JsonSchema>(mut self, content_type: &str) -> Self {
+        let schema = self.document.requests.subschema_for::<T>();
+
+        self.get().insert(
+            "requestBody".to_string(),
+            json!({
+                "required": true,
+                "content": { content_type: { "schema": schema } },
+            }),
+        );
+
+        self
+    }
+
+    // Describes the fields of `T` as query parameters
+    fn query<T: JsonSchema>(mut self) -> Self {
+        let schema = self.document.requests.root_schema_for::<T>();
+        let required = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .cloned()
+            .unwrap_or_default();
+
+        let parameters = schema
+            .get("properties")
+            .and_then(Value::as_object)
+            .into_iter()
+            .flatten()
+            .map(|(name, schema)| {
+                json!({
+                    "name": name,
+                    "in": "query",
+                    "required": required.contains(&Value::String(name.clone())),
+                    "schema": schema,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        if let Some(Value::Array(existing)) = self.get().get_mut("parameters") {
+            existing.extend(parameters);
+        }
+
+        self
+    }
+
+    fn response<T: JsonSchema>(self) -> Self {
+        self.response_status::<T>("200")
+    }
+
+    fn response_status<T: JsonSchema>(mut self, status: &str) -> Self {
+        let schema = self.document.responses.subschema_for::<T>();
+
+        let responses = self
+            .get()
+            .get_mut("responses")
+            .and_then(Value::as_object_mut)
+            .expect("OpenAPI responses should be an object");
+
+        // Replaces the default success response, which has no body
+        if responses
+            .get("200")
+            .is_some_and(|response| response.get("content").is_none())
+        {
+            responses.remove("200");
+        }
+
+        responses.insert(
+            status.to_string(),
+            json!({
+                "description": "Success",
+                "content": { "application/json": { "schema": schema } },
+            }),
+        );
+
+        self
+    }
+}
+
+fn document() -> Value {
+    let mut document = Document::new();
+
+    document
+        .operation(
+            "get",
+            "/accounts",
+            "accounts",
+            Auth::Dashboard,
+            "List the accounts of the user",
+        )
+        .response::<accounts::ListAccountsResponse>();
+
+    #[cfg(not(feature = "archodex-com"))]
+    document
+        .operation(
+            "post",
+            "/accounts",
+            "accounts",
+            Auth::Dashboard,
+            "Create the account of this backend",
+        )
+        .request::<accounts::CreateAccountRequest>()
+        .response::<crate::account::AccountPublic>();
+
+    #[cfg(feature = "archodex-com")]
+    document
+        .operation(
+            "post",
+            "/accounts",
+            "accounts",
+            Auth::Dashboard,
+            "Start provisioning an account",
+        )
+        .request::<accounts::CreateAccountRequest>()
+        .response_status::<crate::provisioning::AccountStatusResponse>("202");
+
+    document
+        .operation(
+            "patch",
+            "/account/:account_id",
+            "accounts",
+            Auth::Dashboard,
+            "Rename the account or update its settings",
+        )
+        .request::<accounts::UpdateAccountRequest>()
+        .response::<crate::account::AccountPublic>();
+
+    document.operation(
+        "delete",
+        "/account/:account_id",
+        "accounts",
+        Auth::Dashboard,
+        "Delete the account. Requires a recent sign in.",
+    );
+
+    document
+        .operation(
+            "get",
+            "/account/:account_id/ingestion_pipeline",
+            "accounts",
+            Auth::Dashboard,
+            "Get the processors applied to reports before they are ingested",
+        )
+        .response::<accounts::IngestionPipelineConfig>();
+
+    document
+        .operation(
+            "put",
+            "/account/:account_id/ingestion_pipeline",
+            "accounts",
+            Auth::Dashboard,
+            "Set the processors applied to reports before they are ingested",
+        )
+        .request::<accounts::IngestionPipelineConfig>();
+
+    document
+        .operation(
+            "put",
+            "/account/:account_id/report_api_key_approval",
+            "accounts",
+            Auth::Dashboard,
+            "Set whether report keys created by members must be approved by an admin",
+        )
+        .request::<accounts::SetReportApiKeyApprovalRequest>();
+
+    document
+        .operation(
+            "put",
+            "/account/:account_id/default_environment",
+            "accounts",
+            Auth::Dashboard,
+            "Set the environment queries are scoped to by default",
+        )
+        .request::<accounts::SetDefaultEnvironmentRequest>();
+
+    document
+        .operation(
+            "put",
+            "/account/:account_id/resource_staleness",
+            "accounts",
+            Auth::Dashboard,
+            "Set the number of days after which unseen resources are archived",
+        )
+        .request::<accounts::SetResourceStalenessRequest>();
+
+    document
+        .operation(
+            "put",
+            "/account/:account_id/dead_lettering",
+            "accounts",
+            Auth::Dashboard,
+            "Set whether reports that fail to be ingested are kept as dead letters",
+        )
+        .request::<accounts::SetDeadLetteringRequest>();
+
+    document
+        .operation(
+            "get",
+            "/account/:account_id/report_api_keys",
+            "report_api_keys",
+            Auth::Dashboard,
+            "List the active report keys of the account, or its revoked report keys",
+        )
+        .query::<report_api_keys::ListReportApiKeysRequest>()
+        .response::<report_api_keys::ListReportApiKeysResponse>();
+
+    document
+        .operation(
+            "post",
+            "/account/:account_id/report_api_keys",
+            "report_api_keys",
+            Auth::Dashboard,
+            "Create a report key, or request one if the account requires approval",
+        )
+        .request::<report_api_keys::CreateReportApiKeyRequest>()
+        .response::<report_api_keys::CreateReportApiKeyResponse>()
+        .response_status::<report_api_keys::CreateReportApiKeyResponse>("202");
+
+    document
+        .operation(
+            "delete",
+            "/account/:account_id/report_api_key/:report_api_key_id",
+            "report_api_keys",
+            Auth::Dashboard,
+            "Revoke a report key, permanently if hard-revoked",
+        )
+        .query::<report_api_keys::RevokeReportApiKeyRequest>();
+
+    document
+        .operation(
+            "post",
+            "/account/:account_id/report_api_key/:report_api_key_id/value",
+            "report_api_keys",
+            Auth::Dashboard,
+            "Reveal the value of a newly created report key in exchange for its reveal token",
+        )
+        .request::<report_api_keys::RevealReportApiKeyValueRequest>()
+        .response::<report_api_keys::RevealReportApiKeyValueResponse>();
+
+    document
+        .operation(
+            "post",
+            "/account/:account_id/report_api_key/:report_api_key_id/restore",
+            "report_api_keys",
+            Auth::Dashboard,
+            "Restore a revoked report key within the account's restore window",
+        )
+        .response::<report_api_key::ReportApiKeyPublic>();
+
+    document
+        .operation(
+            "post",
+            "/account/:account_id/report_api_key/:report_api_key_id/rotate",
+            "report_api_keys",
+            Auth::Dashboard,
+            "Replace a report key with a new key, keeping the rotated key valid for a grace period",
+        )
+        .request::<report_api_keys::RotateReportApiKeyRequest>()
+        .response_status::<report_api_keys::RotateReportApiKeyResponse>("201");
+
+    document
+        .operation(
+            "get",
+            "/account/:account_id/query/:type",
+            "query",
+            Auth::Dashboard,
+            "Query the resources and events of the account",
+        )
+        .query::<query::QueryParams>()
+        .response::<query::QueryResponse>();
+
+    document
+        .operation(
+            "post",
+            "/report",
+            "report",
+            Auth::ReportApiKey,
+            "Ingest a report",
+        )
+        .request::<report::RequestV2>()
+        .response::<report::ReportResponse>();
+
+    document
+        .operation(
+            "post",
+            "/report/batch",
+            "report",
+            Auth::ReportApiKey,
+            "Ingest a stream of reports, one JSON report per line",
+        )
+        .request_content::<report::RequestV2>("application/x-ndjson")
+        .response::<report::ReportBatchResponse>();
+
+    document
+        .operation(
+            "post",
+            "/report/validate",
+            "report",
+            Auth::ReportApiKey,
+            "Validate a report without ingesting it",
+        )
+        .request::<report::RequestV2>()
+        .response::<report::ReportValidationResponse>();
+
+    document.operation(
+        "get",
+        "/report/schema/:version",
+        "report",
+        Auth::None,
+        "Get the JSON Schema of a report format version",
+    );
+
+    document
+        .operation(
+            "post",
+            "/saml/:account_id/session",
+            "saml",
+            Auth::None,
+            "Exchange the code of a SAML sign in for a session token",
+        )
+        .request::<crate::saml::CreateSessionRequest>()
+        .response::<crate::saml::CreateSessionResponse>();
+
+    #[cfg(not(feature = "archodex-com"))]
+    {
+        document
+            .operation(
+                "get",
+                "/oidc/providers",
+                "oidc",
+                Auth::None,
+                "List the identity providers users sign in with",
+            )
+            .response::<crate::oidc::ListProvidersResponse>();
+
+        document
+            .operation(
+                "post",
+                "/oidc/:provider/token",
+                "oidc",
+                Auth::None,
+                "Exchange an authorization code for the identity provider's tokens",
+            )
+            .request::<crate::oidc::ExchangeCodeRequest>();
+    }
+
+    document.into_value()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::document;
+
+    // Modules whose handlers integrators call, all of which are documented
+    const DOCUMENTED_MODULES: &[&str] = &["accounts", "report_api_keys", "query", "report", "oidc"];
+
+    // Prefix of the routes of `router`'s account router, which is nested under it
+    const ACCOUNT_ROUTER_PREFIX: &str = "/account/:account_id";
+
+    // Lists the `(method, path)` of each `.route("<path>", <method>(<module>::<handler>)...)` call in `router` for a
+    // handler of a documented module
+    fn routed_operations() -> Vec<(String, String)> {
+        let source = include_str!("router.rs");
+        // Routes of the account router are declared before the router it is nested in
+        let account_router_end = source
+            .find("let dashboard_authed_router")
+            .expect("router should declare the dashboard authed router");
+
+        let mut operations = Vec::new();
+
+        for (offset, _) in source.match_indices(".route(") {
+            let call = source[offset + ".route(".len()..].trim_start();
+
+            // Routes with constant paths don't belong to documented modules
+            let Some(call) = call.strip_prefix('"') else {
+                continue;
+            };
+            let (path, call) = call
+                .split_once('"')
+                .expect("route path should be terminated");
+            let call = call.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+            let (method, call) = call
+                .split_once('(')
+                .expect("route should have a method router");
+            let Some((module, _)) = call.split_once("::") else {
+                continue;
+            };
+
+            if !DOCUMENTED_MODULES.contains(&module) {
+                continue;
+            }
+
+            // OIDC sign in is only mounted by self-hosted builds
+            if cfg!(feature = "archodex-com") && module == "oidc" {
+                continue;
+            }
+
+            let path = if offset < account_router_end {
+                format!("{ACCOUNT_ROUTER_PREFIX}{}", path.trim_end_matches('/'))
+            } else {
+                path.to_string()
+            };
+
+            operations.push((method.to_string(), path));
+        }
+
+        operations
+    }
+
+    #[test]
+    fn documents_every_route() {
+        let document = document();
+        let operations = routed_operations();
+
+        assert!(
+            operations.iter().any(|(_, path)| path == "/report"),
+            "routes should be found in router"
+        );
+
+        for (method, path) in operations {
+            let path = path
+                .split('/')
+                .map(|segment| match segment.strip_prefix(':') {
+                    Some(name) => format!("{{{name}}}"),
+                    None => segment.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join("/");
+
+            assert!(
+                document["paths"][&path].get(&method).is_some(),
+                "{} {path} is routed but not documented",
+                method.to_uppercase()
+            );
+        }
+    }
+}
@@ -0,0 +1,475 @@
+// Composite operations let the dashboard submit several mutations of an account together, e.g. revoking a report key,
+// creating its replacement, and re-tagging the resources it reported, and have them applied in a single transaction of
+// the account's resources database with all-or-nothing semantics. Only the mutations listed in `Operation` may be
+// combined. Mutations of the accounts database, such as member roles or account settings, can't share a transaction
+// with the resources database and are not supported.
+//
+// An operation that can't be applied, e.g. revoking a key that doesn't exist, aborts the transaction and the request
+// fails with a conflict naming the operation. The request is audited as a single `operations.execute` entry whose
+// target lists every operation.
+
+use std::collections::{HashMap, HashSet};
+
+use axum::{Extension, Json, http::StatusCode};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::statements::{BeginStatement, CommitStatement};
+use tracing::{info, instrument};
+
+use archodex_error::{PublicError, bad_request, forbidden};
+
+use crate::{
+    Result,
+    account::Account,
+    audit::AuditTarget,
+    auth::DashboardAuth,
+    db::QueryCheckFirstRealError,
+    environments,
+    lifecycle::subtree_statements,
+    limits::Limit,
+    next_binding,
+    report_api_key::{ReportApiKey, ReportApiKeyPublic},
+    report_api_keys::{
+        REPORT_API_KEY_VALUE_REVEAL_TTL, ensure_report_api_keys_entitlement,
+        new_value_reveal_token, value_reveal_token_hash,
+    },
+    resource::{ResourceId, surrealdb_thing_from_resource_id},
+};
+
+// Prefixes the errors thrown when an operation can't be applied, so they can be told apart from other errors
+const OPERATION_FAILED: &str = "Operation failed: ";
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+enum Operation {
+    CreateReportApiKey {
+        description: Option<String>,
+    },
+    RevokeReportApiKey {
+        report_api_key_id: u32,
+    },
+    SetResourceEnvironments {
+        resource_id: ResourceId,
+        environments: HashSet<String>,
+    },
+    ArchiveResource {
+        resource_id: ResourceId,
+    },
+    UnarchiveResource {
+        resource_id: ResourceId,
+    },
+    RetireAgent {
+        agent_id: String,
+    },
+}
+
+impl Operation {
+    // Matches the actions of the corresponding routes in the audit log
+    fn action(&self) -> &'static str {
+        match self {
+            Operation::CreateReportApiKey { .. } => "report_api_key.create",
+            Operation::RevokeReportApiKey { .. } => "report_api_key.revoke",
+            Operation::SetResourceEnvironments { .. } => "resource.set_environments",
+            Operation::ArchiveResource { .. } => "resource.archive",
+            Operation::UnarchiveResource { .. } => "resource.unarchive",
+            Operation::RetireAgent { .. } => "agent.retire",
+        }
+    }
+
+    fn target(&self) -> Option<String> {
+        match self {
+            Operation::CreateReportApiKey { .. } => None,
+            Operation::RevokeReportApiKey { report_api_key_id } => {
+                Some(report_api_key_id.to_string())
+            }
+            Operation::SetResourceEnvironments { resource_id, .. }
+            | Operation::ArchiveResource { resource_id }
+            | Operation::UnarchiveResource { resource_id } => {
+                serde_json::to_string(resource_id).ok()
+            }
+            Operation::RetireAgent { agent_id } => Some(agent_id.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ExecuteOperationsRequest {
+    operations: Vec<Operation>,
+}
+
+#[derive(JsonSchema, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub(crate) enum OperationResult {
+    // The creator exchanges the reveal token for the key value, see `report_api_keys::reveal_report_api_key_value`
+    CreateReportApiKey {
+        report_api_key: ReportApiKeyPublic,
+        report_api_key_value_reveal_token: String,
+        report_api_key_value_reveal_expires_at: DateTime<Utc>,
+    },
+    RevokeReportApiKey,
+    SetResourceEnvironments,
+    ArchiveResource {
+        // Resources archived, including contained resources
+        resources: usize,
+    },
+    UnarchiveResource {
+        // Resources unarchived, including contained resources
+        resources: usize,
+    },
+    RetireAgent,
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct ExecuteOperationsResponse {
+    // Results of the operations, in the order they were submitted
+    results: Vec<OperationResult>,
+}
+
+// Results of operations that are only known once the transaction has been committed
+enum PendingResult {
+    Known(OperationResult),
+    CreateReportApiKey {
+        statement: usize,
+        value_reveal_token: String,
+        value_reveal_expires_at: DateTime<Utc>,
+    },
+    ArchiveResource {
+        statement: usize,
+    },
+    UnarchiveResource {
+        statement: usize,
+    },
+}
+
+// Statements of the transaction and their bindings. Statements are submitted one by one between the `BEGIN` and
+// `COMMIT` statements, which SurrealDB returns no results for, so the index of a statement's result is its position.
+#[derive(Default)]
+struct Transaction {
+    statements: Vec<String>,
+    bindings: Vec<(String, surrealdb::sql::Value)>,
+}
+
+impl Transaction {
+    fn bind(&mut self, value: impl Into<surrealdb::sql::Value>) -> String {
+        let binding = next_binding();
+        self.bindings.push((binding.clone(), value.into()));
+        binding
+    }
+
+    // Returns the index of the statement's result
+    fn statement(&mut self, statement: String) -> usize {
+        self.statements.push(statement);
+        self.statements.len() - 1
+    }
+
+    // Updates the resource and the resources it contains, like `lifecycle::archive_resource`. Returns the index of the
+    // result listing the updated resources.
+    fn update_subtree(&mut self, resource_id: ResourceId, update: &str) -> usize {
+        let subtree_var = next_binding();
+        let depth_binding = self.bind(resource_id.len() as i64);
+        let resource_binding = self.bind(surrealdb_thing_from_resource_id(resource_id));
+
+        for statement in subtree_statements(&subtree_var, &resource_binding, &depth_binding) {
+            self.statement(statement);
+        }
+
+        self.statement(format!("UPDATE ${subtree_var} {update} RETURN VALUE id;"))
+    }
+
+    // Assigns the records updated by `update` to a variable, aborting the transaction if none were updated
+    fn update_or_throw(&mut self, update: &str, operation: usize, reason: &str) {
+        let updated_var = next_binding();
+        let message = self.bind(format!(
+            "{OPERATION_FAILED}Operation {operation} can't be applied: {reason}"
+        ));
+
+        self.statement(format!("LET ${updated_var} = {update};"));
+        self.statement(format!(
+            "IF array::len(${updated_var}) == 0 {{ THROW ${message} }};"
+        ));
+    }
+}
+
+fn operation_failed_error(err: surrealdb::Error) -> PublicError {
+    let message = err.to_string();
+
+    match message.split_once(OPERATION_FAILED) {
+        Some((_, reason)) => PublicError::new(StatusCode::CONFLICT, reason.to_string()),
+        None => err.into(),
+    }
+}
+
+#[allow(clippy::too_many_lines)]
+#[instrument(err, skip_all, fields(account_id = account.id()))]
+pub(crate) async fn execute(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Json(req): Json<ExecuteOperationsRequest>,
+) -> Result<(Extension<AuditTarget>, Json<ExecuteOperationsResponse>)> {
+    if req.operations.is_empty() {
+        bad_request!("At least one operation must be submitted");
+    }
+
+    if req.operations.len() > Limit::Operations.max() {
+        return Err(Limit::Operations.exceeded(Some(req.operations.len())));
+    }
+
+    let mut created = 0;
+    let mut revoked = HashSet::new();
+
+    for (index, operation) in req.operations.iter().enumerate() {
+        match operation {
+            Operation::CreateReportApiKey { .. } => {
+                if account.report_api_key_approval_required()
+                    && !auth.is_account_admin(account.id()).await?
+                {
+                    forbidden!(
+                        "Operation {index}: Account requires report keys to be approved by an admin, request a key with `POST /report_api_keys` instead"
+                    );
+                }
+
+                created += 1;
+            }
+            Operation::RevokeReportApiKey { report_api_key_id } => {
+                revoked.insert(*report_api_key_id);
+            }
+            Operation::ArchiveResource { resource_id } if resource_id.is_empty() => {
+                bad_request!("Operation {index}: The root resource cannot be archived");
+            }
+            Operation::RetireAgent { .. } => auth.ensure_account_admin(account.id()).await?,
+            _ => {}
+        }
+    }
+
+    let db = account.resources_db().await?;
+
+    let environments = req
+        .operations
+        .iter()
+        .filter_map(|operation| match operation {
+            Operation::SetResourceEnvironments { environments, .. } => Some(environments),
+            _ => None,
+        })
+        .flatten()
+        .cloned()
+        .collect::<HashSet<_>>();
+
+    environments::ensure_defined(&db, &environments).await?;
+
+    if created > 0 {
+        ensure_report_api_keys_entitlement(&account, &db, created, &revoked).await?;
+    }
+
+    let audit_target = req
+        .operations
+        .iter()
+        .enumerate()
+        .map(|(index, operation)| {
+            let description = match operation.target() {
+                Some(target) => format!("{} {target}", operation.action()),
+                None => operation.action().to_string(),
+            };
+            (format!("operation_{index}"), description)
+        })
+        .collect::<HashMap<_, _>>();
+
+    let mut transaction = Transaction::default();
+    let user = transaction.bind(surrealdb::sql::Thing::from(auth.principal()));
+
+    let mut pending_results = Vec::with_capacity(req.operations.len());
+
+    for (index, operation) in req.operations.into_iter().enumerate() {
+        let pending_result = match operation {
+            Operation::CreateReportApiKey { description } => {
+                let report_api_key = ReportApiKey::new(description, auth.principal().clone());
+                let value_reveal_token = new_value_reveal_token();
+                let value_reveal_expires_at = Utc::now() + REPORT_API_KEY_VALUE_REVEAL_TTL;
+
+                let report_api_key_binding =
+                    transaction.bind(surrealdb::sql::Thing::from(&report_api_key));
+                let description_binding = transaction.bind(
+                    report_api_key
+                        .description()
+                        .map_or(surrealdb::sql::Value::None, surrealdb::sql::Value::from),
+                );
+                let value_reveal_token_hash_binding =
+                    transaction.bind(value_reveal_token_hash(&value_reveal_token));
+                let value_reveal_expires_at_binding =
+                    transaction.bind(surrealdb::sql::Datetime::from(value_reveal_expires_at));
+
+                let statement = transaction.statement(format!(
+                    "CREATE ${report_api_key_binding} CONTENT {{ description: ${description_binding}, created_by: ${user}, value_reveal_token_hash: ${value_reveal_token_hash_binding}, value_reveal_expires_at: ${value_reveal_expires_at_binding} }} RETURN AFTER;"
+                ));
+
+                PendingResult::CreateReportApiKey {
+                    statement,
+                    value_reveal_token,
+                    value_reveal_expires_at,
+                }
+            }
+            Operation::RevokeReportApiKey { report_api_key_id } => {
+                let report_api_key_binding = transaction.bind(surrealdb::sql::Thing::from((
+                    "report_api_key",
+                    surrealdb::sql::Id::from(i64::from(report_api_key_id)),
+                )));
+
+                transaction.update_or_throw(
+                    &format!(
+                        "UPDATE ${report_api_key_binding} SET revoked_at = time::now(), revoked_by = ${user} WHERE revoked_at IS NONE RETURN VALUE id"
+                    ),
+                    index,
+                    &format!("Report key {report_api_key_id} not found or already revoked"),
+                );
+
+                PendingResult::Known(OperationResult::RevokeReportApiKey)
+            }
+            Operation::SetResourceEnvironments {
+                resource_id,
+                environments,
+            } => {
+                let resource_binding =
+                    transaction.bind(surrealdb_thing_from_resource_id(resource_id));
+                let environments_binding = transaction.bind(
+                    environments
+                        .into_iter()
+                        .map(surrealdb::sql::Value::from)
+                        .collect::<Vec<_>>(),
+                );
+
+                transaction.update_or_throw(
+                    &format!(
+                        "UPDATE ${resource_binding} SET environments = ${environments_binding} RETURN VALUE id"
+                    ),
+                    index,
+                    "Resource not found",
+                );
+
+                PendingResult::Known(OperationResult::SetResourceEnvironments)
+            }
+            Operation::ArchiveResource { resource_id } => PendingResult::ArchiveResource {
+                statement: transaction.update_subtree(
+                    resource_id,
+                    &format!(
+                        "SET archived_at = time::now(), archived_by = ${user} WHERE archived_at IS NONE"
+                    ),
+                ),
+            },
+            Operation::UnarchiveResource { resource_id } => PendingResult::UnarchiveResource {
+                statement: transaction.update_subtree(
+                    resource_id,
+                    "SET archived_at = NONE, archived_by = NONE WHERE archived_at IS NOT NONE",
+                ),
+            },
+            Operation::RetireAgent { agent_id } => {
+                let agent_binding = transaction
+                    .bind(surrealdb::sql::Thing::from(("agent", agent_id.as_str())));
+
+                transaction.update_or_throw(
+                    &format!(
+                        "UPDATE ${agent_binding} SET retired_at = time::now(), retired_by = ${user} RETURN VALUE id"
+                    ),
+                    index,
+                    "Agent not found",
+                );
+
+                PendingResult::Known(OperationResult::RetireAgent)
+            }
+        };
+
+        pending_results.push(pending_result);
+    }
+
+    let mut query = db.query(BeginStatement::default());
+    for statement in transaction.statements {
+        query = query.query(statement);
+    }
+    for binding in transaction.bindings {
+        query = query.bind(binding);
+    }
+
+    let mut response = query
+        .query(CommitStatement::default())
+        .await
+        .and_then(QueryCheckFirstRealError::check_first_real_error)
+        .map_err(operation_failed_error)?;
+
+    let mut results = Vec::with_capacity(pending_results.len());
+
+    for pending_result in pending_results {
+        results.push(match pending_result {
+            PendingResult::Known(result) => result,
+            PendingResult::CreateReportApiKey {
+                statement,
+                value_reveal_token,
+                value_reveal_expires_at,
+            } => OperationResult::CreateReportApiKey {
+                report_api_key: ReportApiKeyPublic::from(
+                    response.take::<Option<ReportApiKey>>(statement)?.expect(
+                        "Create report API key statement should return a report key instance",
+                    ),
+                ),
+                report_api_key_value_reveal_token: value_reveal_token,
+                report_api_key_value_reveal_expires_at: value_reveal_expires_at,
+            },
+            PendingResult::ArchiveResource { statement } => OperationResult::ArchiveResource {
+                resources: response.take::<Vec<ResourceId>>(statement)?.len(),
+            },
+            PendingResult::UnarchiveResource { statement } => OperationResult::UnarchiveResource {
+                resources: response.take::<Vec<ResourceId>>(statement)?.len(),
+            },
+        });
+    }
+
+    info!(operations = results.len(), "Executed composite operations");
+
+    Ok((
+        Extension(AuditTarget(audit_target)),
+        Json(ExecuteOperationsResponse { results }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use surrealdb::sql::Statement;
+
+    use crate::{
+        db::transaction_results,
+        resource::{ResourceId, ResourceIdPart},
+    };
+
+    use super::Transaction;
+
+    #[test]
+    fn statement_result_indices() {
+        let mut transaction = Transaction::default();
+
+        transaction.update_or_throw(
+            "UPDATE agent:a SET retired_at = time::now()",
+            0,
+            "Not found",
+        );
+        let create = transaction.statement("CREATE report_api_key:1 RETURN AFTER;".to_string());
+        let subtree = transaction.update_subtree(
+            [ResourceIdPart {
+                r#type: "Partition".to_string(),
+                id: "aws".to_string(),
+            }]
+            .into_iter()
+            .collect::<ResourceId>(),
+            "SET archived_at = time::now()",
+        );
+
+        let statements = transaction
+            .statements
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        let results = transaction_results(&statements);
+
+        assert!(matches!(results[create], Statement::Create(_)));
+        assert!(matches!(results[subtree], Statement::Update(_)));
+        assert_eq!(subtree, results.len() - 1);
+    }
+}
@@ -0,0 +1,214 @@
+// Requests to endpoints configured by users, like webhooks and federation endpoints, must not reach the backend's own
+// network, e.g. cloud instance metadata endpoints. Clients built here don't follow redirects, and only connect to
+// allowed addresses. Host names are checked once they are resolved, so DNS records can't point an endpoint at an
+// internal address after its URL was validated. URLs with IP address hosts aren't resolved, so they are checked by
+// `check_url` before each request.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use reqwest::{
+    Url,
+    dns::{Addrs, Name, Resolve, Resolving},
+};
+
+use archodex_error::anyhow::{self, bail, ensure};
+
+// Private addresses may be allowed for endpoints of self-hosted instances, which may be on their own network. Loopback
+// addresses, which reach the backend itself, and cloud instance metadata endpoints, which use link-local addresses and
+// the EC2 IPv6 address `fd00:ec2::254`, are never allowed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum AllowedAddresses {
+    Public,
+    PublicAndPrivate,
+}
+
+// IPv6 address of the EC2 instance metadata endpoint, which is a unique local rather than a link-local address
+const EC2_IPV6_METADATA_ADDRESS: Ipv6Addr = Ipv6Addr::new(0xfd00, 0xec2, 0, 0, 0, 0, 0, 0x254);
+
+fn is_never_allowed(ip: IpAddr) -> bool {
+    let is_never_allowed_ipv4 =
+        |ip: Ipv4Addr| ip.is_link_local() || ip.is_loopback() || ip.is_unspecified();
+
+    match ip {
+        IpAddr::V4(ip) => is_never_allowed_ipv4(ip),
+        IpAddr::V6(ip) => {
+            ip.to_ipv4_mapped().is_some_and(is_never_allowed_ipv4)
+                || ip.is_unicast_link_local()
+                || ip.is_loopback()
+                || ip.is_unspecified()
+                || ip == EC2_IPV6_METADATA_ADDRESS
+        }
+    }
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // 0.0.0.0/8, shared address space 100.64.0.0/10, benchmarking 198.18.0.0/15, and reserved 240.0.0.0/4
+        || a == 0
+        || (a == 100 && (b & 0xc0) == 64)
+        || (a == 198 && (b & 0xfe) == 18)
+        || a >= 240)
+}
+
+fn is_public_ipv6(ip: Ipv6Addr) -> bool {
+    if let Some(ip) = ip.to_ipv4_mapped() {
+        return is_public_ipv4(ip);
+    }
+
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || ip.is_unique_local()
+        || ip.is_unicast_link_local()
+        // Documentation 2001:db8::/32
+        || (ip.segments()[0] == 0x2001 && ip.segments()[1] == 0xdb8))
+}
+
+fn is_allowed(ip: IpAddr, allowed: AllowedAddresses) -> bool {
+    match allowed {
+        AllowedAddresses::Public => match ip {
+            IpAddr::V4(ip) => is_public_ipv4(ip),
+            IpAddr::V6(ip) => is_public_ipv6(ip),
+        },
+        AllowedAddresses::PublicAndPrivate => !is_never_allowed(ip),
+    }
+}
+
+// Resolves host names with the system resolver, refusing to connect if any address isn't allowed
+struct CheckedResolver {
+    allowed: AllowedAddresses,
+}
+
+impl Resolve for CheckedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let allowed = self.allowed;
+
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .collect::<Vec<SocketAddr>>();
+
+            if let Some(addr) = addrs.iter().find(|addr| !is_allowed(addr.ip(), allowed)) {
+                return Err(format!(
+                    "{} resolves to address {}, which requests may not be sent to",
+                    name.as_str(),
+                    addr.ip()
+                )
+                .into());
+            }
+
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+pub(crate) fn client(timeout: Duration, allowed: AllowedAddresses) -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(timeout)
+        .redirect(reqwest::redirect::Policy::none())
+        .dns_resolver(Arc::new(CheckedResolver { allowed }))
+        .build()
+        .expect("Failed to build outbound HTTP client")
+}
+
+// Checks the URL's scheme, and that its host isn't an IP address requests may not be sent to
+pub(crate) fn check_url(
+    url: &str,
+    allowed_schemes: &[&str],
+    allowed: AllowedAddresses,
+) -> anyhow::Result<Url> {
+    let Ok(url) = Url::parse(url) else {
+        bail!("Invalid URL");
+    };
+
+    ensure!(
+        allowed_schemes.contains(&url.scheme()),
+        "URL scheme must be one of {allowed_schemes:?}"
+    );
+
+    let Some(host) = url.host_str() else {
+        bail!("URL must have a host");
+    };
+
+    // IPv6 hosts are enclosed in brackets
+    let Ok(ip) = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+    else {
+        return Ok(url);
+    };
+
+    ensure!(
+        is_allowed(ip, allowed),
+        "URL host {ip} is not an address requests may be sent to"
+    );
+
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AllowedAddresses, check_url};
+
+    #[test]
+    fn ip_hosts() {
+        for url in [
+            "https://127.0.0.1/",
+            "https://10.1.2.3/",
+            "https://169.254.169.254/latest/meta-data/",
+            "https://100.64.0.1/",
+            "https://[::1]/",
+            "https://[fd00::1]/",
+            "https://[::ffff:169.254.169.254]/",
+            "https://[fd00:ec2::254]/",
+            "https://0.0.0.0/",
+        ] {
+            assert!(
+                check_url(url, &["https"], AllowedAddresses::Public).is_err(),
+                "{url} should not be allowed"
+            );
+        }
+
+        for url in ["https://93.184.215.14/", "https://[2606:4700::1111]/"] {
+            assert!(
+                check_url(url, &["https"], AllowedAddresses::Public).is_ok(),
+                "{url} should be allowed"
+            );
+        }
+
+        assert!(
+            check_url(
+                "https://10.1.2.3/",
+                &["https"],
+                AllowedAddresses::PublicAndPrivate
+            )
+            .is_ok()
+        );
+        for url in [
+            "https://169.254.169.254/",
+            "https://[fd00:ec2::254]/",
+            "https://127.0.0.1/admin/accounts",
+            "https://[::1]/metrics",
+            "https://[::ffff:127.0.0.1]/",
+        ] {
+            assert!(
+                check_url(url, &["https"], AllowedAddresses::PublicAndPrivate).is_err(),
+                "{url} should not be allowed"
+            );
+        }
+        assert!(check_url("http://example.com/", &["https"], AllowedAddresses::Public).is_err());
+    }
+}
@@ -2,14 +2,25 @@ use std::collections::HashMap;
 
 use axum::{Extension, Json, extract::Query};
 use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 use archodex_error::{anyhow, bad_request, bail, ensure, not_found};
 use tracing::instrument;
 
-use crate::{account::Account, db::QueryCheckFirstRealError, resource::ResourceId};
+use crate::{
+    account::Account,
+    db::{QueryCheckFirstRealError, statement_timeout},
+    event::Event,
+    resource::{ResourceId, surrealdb_thing_from_resource_id},
+};
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) const DEFAULT_TRAVERSAL_DEPTH: u32 = 3;
+const MAX_TRAVERSAL_DEPTH: u32 = 10;
+// Traversals stop once they found this many events
+const MAX_TRAVERSAL_EVENTS: usize = 1000;
+
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
 pub(crate) struct PrincipalChainIdPart {
     pub(crate) id: ResourceId,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -61,7 +72,7 @@ impl TryFrom<surrealdb::sql::Object> for PrincipalChainIdPart {
     }
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, JsonSchema, Serialize)]
 pub(crate) struct PrincipalChainId(Vec<PrincipalChainIdPart>);
 
 impl std::ops::Deref for PrincipalChainId {
@@ -194,7 +205,7 @@ pub(super) struct GetRequest {
     id: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
 pub(super) struct GetResponse {
     first_seen_at: DateTime<Utc>,
     last_seen_at: DateTime<Utc>,
@@ -215,6 +226,7 @@ pub(super) async fn get(
         .await?
         .query("SELECT first_seen_at, last_seen_at FROM type::thing('principal_chain', $id)")
         .bind(("id", surrealdb::sql::Array::from(id)))
+        .timeout()
         .await?
         .check_first_real_error()?
         .take(0)?;
@@ -224,3 +236,479 @@ pub(super) async fn get(
         None => not_found!("Principal chain does not exist"),
     }
 }
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TraversalDirection {
+    // From principals to the resources they acted on
+    #[default]
+    Downstream,
+    // From resources to the principals that acted on them
+    Upstream,
+}
+
+impl TraversalDirection {
+    fn edges(self) -> &'static str {
+        match self {
+            TraversalDirection::Downstream => "->event",
+            TraversalDirection::Upstream => "<-event",
+        }
+    }
+
+    // Resource the event was followed from
+    fn origin(self, event: &Event) -> &ResourceId {
+        match self {
+            TraversalDirection::Downstream => &event.principal,
+            TraversalDirection::Upstream => &event.resource,
+        }
+    }
+
+    // Resource the traversal continues from after following the event
+    fn next(self, event: &Event) -> &ResourceId {
+        match self {
+            TraversalDirection::Downstream => &event.resource,
+            TraversalDirection::Upstream => &event.principal,
+        }
+    }
+}
+
+// Follows events from a resource one hop at a time, downstream to the resources it acted on or upstream to the
+// principals that acted on it. Unlike loading the full graph, traversals are bounded by depth and by the number of
+// events found, so they stay cheap on large graphs. Each resource is expanded at most once, so cycles don't extend
+// traversals.
+#[derive(Clone, Debug)]
+pub(crate) struct Traversal {
+    pub(crate) start: ResourceId,
+    pub(crate) direction: TraversalDirection,
+    // Number of hops to follow, between 1 and `MAX_TRAVERSAL_DEPTH`
+    pub(crate) depth: u32,
+    // Event types to follow. Events of all types are followed if unset.
+    pub(crate) event_types: Option<Vec<String>>,
+    // Traversals stop once they found this many events
+    pub(crate) max_events: usize,
+}
+
+impl Traversal {
+    pub(crate) fn new(start: ResourceId, direction: TraversalDirection) -> Self {
+        Self {
+            start,
+            direction,
+            depth: DEFAULT_TRAVERSAL_DEPTH,
+            event_types: None,
+            max_events: MAX_TRAVERSAL_EVENTS,
+        }
+    }
+
+    // Traverses the account's resources database
+    pub(crate) async fn run(&self, account: &Account) -> crate::Result<Traversed> {
+        let query = format!(
+            "SELECT * FROM array::flatten((SELECT VALUE {} FROM $frontier)) WHERE $event_types IS NONE OR type INSIDE $event_types LIMIT $limit {}",
+            self.direction.edges(),
+            statement_timeout(),
+        );
+
+        let db = account.resources_db().await?;
+
+        let (db, query) = (&db, query.as_str());
+
+        self.run_with(|frontier, limit| async move {
+            Ok(db
+                .query(query)
+                .bind((
+                    "frontier",
+                    frontier
+                        .into_iter()
+                        .map(surrealdb_thing_from_resource_id)
+                        .collect::<Vec<_>>(),
+                ))
+                .bind(("event_types", self.event_types.clone()))
+                .bind(("limit", limit))
+                .timeout()
+                .await?
+                .check_first_real_error()?
+                .take::<Vec<Event>>(0)?)
+        })
+        .await
+    }
+
+    // Traverses the graph whose events are fetched by `fetch_hop`, which returns at most `limit` events of the
+    // traversal's direction and event types from the given resources
+    pub(crate) async fn run_with<F, Fut>(&self, mut fetch_hop: F) -> crate::Result<Traversed>
+    where
+        F: FnMut(Vec<ResourceId>, usize) -> Fut,
+        Fut: Future<Output = crate::Result<Vec<Event>>>,
+    {
+        if self.depth == 0 || self.depth > MAX_TRAVERSAL_DEPTH {
+            bad_request!("Traversal `depth` must be between 1 and {MAX_TRAVERSAL_DEPTH}");
+        }
+
+        // Index of the event that first reached each expanded resource, unset for the starting resource
+        let mut expanded = HashMap::from([(self.start.clone(), None)]);
+        let mut frontier = vec![self.start.clone()];
+        let mut events = Vec::new();
+        let mut truncated = false;
+
+        for hop in 1..=self.depth {
+            if frontier.is_empty() {
+                break;
+            }
+
+            let remaining = self.max_events - events.len();
+
+            // Fetch one extra event to find out whether the traversal is truncated
+            let mut hop_events = fetch_hop(std::mem::take(&mut frontier), remaining + 1).await?;
+
+            if hop_events.len() > remaining {
+                hop_events.truncate(remaining);
+                truncated = true;
+            }
+
+            for event in hop_events {
+                let parent = expanded
+                    .get(self.direction.origin(&event))
+                    .copied()
+                    .flatten();
+
+                let next = self.direction.next(&event);
+                if !expanded.contains_key(next) {
+                    expanded.insert(next.clone(), Some(events.len()));
+                    frontier.push(next.clone());
+                }
+
+                events.push(TraversedEvent {
+                    depth: hop,
+                    parent,
+                    event,
+                });
+            }
+
+            if truncated {
+                break;
+            }
+        }
+
+        Ok(Traversed { events, truncated })
+    }
+}
+
+#[derive(Debug, JsonSchema, Serialize)]
+pub(crate) struct TraversedEvent {
+    // Number of hops from the starting resource, starting at 1
+    pub(crate) depth: u32,
+    // Index of the event that reached the resource this event was followed from. Unset for events of the starting
+    // resource.
+    #[serde(skip)]
+    pub(crate) parent: Option<usize>,
+    #[serde(flatten)]
+    pub(crate) event: Event,
+}
+
+#[derive(Debug)]
+pub(crate) struct Traversed {
+    // Events in the order they were found, hop by hop
+    pub(crate) events: Vec<TraversedEvent>,
+    // Whether the traversal stopped early because it found the maximum number of events
+    pub(crate) truncated: bool,
+}
+
+impl Traversed {
+    // Events leading from the starting resource to the event at `index`, ending with that event. Resources are reached
+    // by the first event found for them, so each event has a single chain.
+    pub(crate) fn chain(&self, index: usize) -> Vec<&Event> {
+        let mut chain = Vec::new();
+        let mut next = Some(index);
+
+        while let Some(index) = next {
+            let traversed = &self.events[index];
+            chain.push(&traversed.event);
+            next = traversed.parent;
+        }
+
+        chain.reverse();
+        chain
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(super) struct TraverseRequest {
+    // JSON encoded resource ID to start from
+    resource_id: String,
+    #[serde(default)]
+    direction: TraversalDirection,
+    depth: Option<u32>,
+    // Comma separated event types to follow. Events of all types are followed if unset.
+    event_types: Option<String>,
+}
+
+#[derive(Debug, JsonSchema, Serialize)]
+pub(super) struct TraverseResponse {
+    events: Vec<TraversedEvent>,
+    // Whether the traversal stopped early because it found the maximum number of events
+    truncated: bool,
+}
+
+#[instrument(err, skip(account))]
+pub(super) async fn traverse(
+    Extension(account): Extension<Account>,
+    Query(req): Query<TraverseRequest>,
+) -> crate::Result<Json<TraverseResponse>> {
+    let resource_id: ResourceId = match serde_json::from_str(&req.resource_id) {
+        Ok(resource_id) => resource_id,
+        Err(err) => bad_request!("Invalid `resource_id` query parameter: {err}"),
+    };
+
+    let event_types = req.event_types.map(|event_types| {
+        event_types
+            .split(',')
+            .map(str::trim)
+            .filter(|event_type| !event_type.is_empty())
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+    });
+
+    let Traversed { events, truncated } = Traversal {
+        depth: req.depth.unwrap_or(DEFAULT_TRAVERSAL_DEPTH),
+        event_types,
+        ..Traversal::new(resource_id, req.direction)
+    }
+    .run(&account)
+    .await?;
+
+    Ok(Json(TraverseResponse { events, truncated }))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::{Traversal, TraversalDirection, Traversed};
+    use crate::{
+        event::Event,
+        resource::{ResourceId, ResourceIdPart},
+    };
+
+    fn resource(id: &str) -> ResourceId {
+        [ResourceIdPart {
+            r#type: "Resource".to_string(),
+            id: id.to_string(),
+        }]
+        .into_iter()
+        .collect()
+    }
+
+    fn event(principal: &str, r#type: &str, resource_id: &str) -> Event {
+        Event {
+            principal: resource(principal),
+            r#type: r#type.to_string(),
+            resource: resource(resource_id),
+            principal_chains: vec![],
+            first_seen_at: Utc::now(),
+            last_seen_at: Utc::now(),
+            gone_at: None,
+            observed_by: vec![],
+        }
+    }
+
+    // Traverses a synthetic graph of `(principal, type, resource)` events, fetching hops the way the account query does
+    async fn traverse(traversal: &Traversal, graph: &[(&str, &str, &str)]) -> Traversed {
+        let graph = graph
+            .iter()
+            .map(|(principal, r#type, resource)| event(principal, r#type, resource))
+            .collect::<Vec<_>>();
+
+        traversal
+            .run_with(|frontier, limit| {
+                let events = graph
+                    .iter()
+                    .filter(|event| frontier.contains(traversal.direction.origin(event)))
+                    .filter(|event| {
+                        traversal
+                            .event_types
+                            .as_ref()
+                            .is_none_or(|event_types| event_types.contains(&event.r#type))
+                    })
+                    .take(limit)
+                    .cloned()
+                    .collect::<Vec<_>>();
+
+                async move { Ok(events) }
+            })
+            .await
+            .expect("Traversal should succeed")
+    }
+
+    fn summary(traversed: &Traversed) -> Vec<(u32, String, String)> {
+        traversed
+            .events
+            .iter()
+            .map(|traversed| {
+                (
+                    traversed.depth,
+                    traversed.event.principal[0].id.clone(),
+                    traversed.event.resource[0].id.clone(),
+                )
+            })
+            .collect()
+    }
+
+    fn chain(traversed: &Traversed, index: usize) -> Vec<String> {
+        traversed
+            .chain(index)
+            .into_iter()
+            .map(|event| format!("{}->{}", event.principal[0].id, event.resource[0].id))
+            .collect()
+    }
+
+    const CHAIN: &[(&str, &str, &str)] = &[
+        ("user", "AssumeRole", "role"),
+        ("role", "GetSecretValue", "secret"),
+        ("secret", "Decrypt", "key"),
+        ("other", "AssumeRole", "role"),
+    ];
+
+    #[tokio::test]
+    async fn downstream() {
+        let traversed = traverse(
+            &Traversal::new(resource("user"), TraversalDirection::Downstream),
+            CHAIN,
+        )
+        .await;
+
+        assert_eq!(
+            summary(&traversed),
+            [
+                (1, "user".to_string(), "role".to_string()),
+                (2, "role".to_string(), "secret".to_string()),
+                (3, "secret".to_string(), "key".to_string()),
+            ]
+        );
+        assert!(!traversed.truncated);
+        assert_eq!(
+            chain(&traversed, 2),
+            ["user->role", "role->secret", "secret->key"]
+        );
+    }
+
+    #[tokio::test]
+    async fn upstream() {
+        let traversed = traverse(
+            &Traversal::new(resource("secret"), TraversalDirection::Upstream),
+            CHAIN,
+        )
+        .await;
+
+        assert_eq!(
+            summary(&traversed),
+            [
+                (1, "role".to_string(), "secret".to_string()),
+                (2, "user".to_string(), "role".to_string()),
+                (2, "other".to_string(), "role".to_string()),
+            ]
+        );
+        assert_eq!(chain(&traversed, 2), ["role->secret", "other->role"]);
+    }
+
+    #[tokio::test]
+    async fn depth() {
+        let traversed = traverse(
+            &Traversal {
+                depth: 2,
+                ..Traversal::new(resource("user"), TraversalDirection::Downstream)
+            },
+            CHAIN,
+        )
+        .await;
+
+        assert_eq!(traversed.events.len(), 2);
+        assert!(!traversed.truncated);
+
+        for depth in [0, 11] {
+            let traversal = Traversal {
+                depth,
+                ..Traversal::new(resource("user"), TraversalDirection::Downstream)
+            };
+
+            assert!(
+                traversal
+                    .run_with(|_, _| async { Ok(vec![]) })
+                    .await
+                    .is_err()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn event_types() {
+        let traversed = traverse(
+            &Traversal {
+                event_types: Some(vec!["AssumeRole".to_string(), "Decrypt".to_string()]),
+                ..Traversal::new(resource("user"), TraversalDirection::Downstream)
+            },
+            CHAIN,
+        )
+        .await;
+
+        assert_eq!(
+            summary(&traversed),
+            [(1, "user".to_string(), "role".to_string())]
+        );
+    }
+
+    // Resources are expanded once, so cycles end traversals and each resource is reached through the first event found
+    #[tokio::test]
+    async fn cycles() {
+        let traversed = traverse(
+            &Traversal {
+                depth: 10,
+                ..Traversal::new(resource("a"), TraversalDirection::Downstream)
+            },
+            &[
+                ("a", "Invoke", "b"),
+                ("a", "Invoke", "c"),
+                ("b", "Invoke", "c"),
+                ("c", "Invoke", "a"),
+            ],
+        )
+        .await;
+
+        assert_eq!(
+            summary(&traversed),
+            [
+                (1, "a".to_string(), "b".to_string()),
+                (1, "a".to_string(), "c".to_string()),
+                (2, "b".to_string(), "c".to_string()),
+                (2, "c".to_string(), "a".to_string()),
+            ]
+        );
+        assert_eq!(chain(&traversed, 2), ["a->b", "b->c"]);
+        assert_eq!(chain(&traversed, 3), ["a->c", "c->a"]);
+    }
+
+    #[tokio::test]
+    async fn truncated() {
+        let traversed = traverse(
+            &Traversal {
+                max_events: 2,
+                ..Traversal::new(resource("user"), TraversalDirection::Downstream)
+            },
+            CHAIN,
+        )
+        .await;
+
+        assert_eq!(traversed.events.len(), 2);
+        assert!(traversed.truncated);
+
+        let traversed = traverse(
+            &Traversal {
+                max_events: 3,
+                ..Traversal::new(resource("user"), TraversalDirection::Downstream)
+            },
+            CHAIN,
+        )
+        .await;
+
+        assert_eq!(traversed.events.len(), 3);
+        assert!(!traversed.truncated);
+    }
+}
@@ -0,0 +1,484 @@
+// Provisioning of archodex.com accounts. Creating an account's service data database, waiting for it to become active,
+// and migrating it takes longer than API gateways allow a request to run. `POST /accounts` therefore only reserves the
+// account ID and responds with 202 while the account is provisioned in the background, and the dashboard polls
+// `GET /account/:account_id/status` until the account is ready.
+//
+// Provisioning state is stored on the account ID reservation and every step is idempotent, so provisioning interrupted
+// by a restart resumes from its last completed step. Runs hold a lease on the reservation so that only one backend
+// instance provisions an account at a time. Once a lease expires, provisioning is resumed by the next status poll of
+// the account or by `resume_provisioning_periodically`.
+
+use std::{collections::HashMap, time::Duration};
+
+use axum::{Extension, Json, extract::Path};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument, warn};
+
+use archodex_error::{
+    anyhow::{Context as _, bail},
+    bad_request, not_found,
+};
+
+use crate::{
+    Result,
+    account::{Account, AccountQueries as _},
+    auth::DashboardAuth,
+    db::{QueryCheckFirstRealError as _, accounts_db, migrate_service_data_database},
+    env::Env,
+    job::Job,
+    notifications::{NewNotification, NotificationKind},
+    standby,
+    user::User,
+};
+
+// Long enough to create and migrate a service data database, including waiting for its table to become active
+const PROVISIONING_LEASE: Duration = Duration::from_secs(5 * 60);
+// Failed attempts are retried after this delay, until `MAX_PROVISIONING_ATTEMPTS` have failed
+const PROVISIONING_RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_PROVISIONING_ATTEMPTS: u32 = 3;
+const PROVISIONING_RESUME_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ProvisioningStatus {
+    Provisioning,
+    Ready,
+    Failed,
+}
+
+#[derive(Debug, JsonSchema, Serialize)]
+pub(crate) struct AccountStatusResponse {
+    id: String,
+    status: ProvisioningStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl AccountStatusResponse {
+    pub(crate) fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+// Where an account's service data is stored. Regulated customers select the region when creating the account, and the
+// service data database is created in the customer data AWS account configured for that region.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) struct ServiceDataLocation {
+    pub(crate) region: String,
+    pub(crate) aws_account_id: String,
+}
+
+impl ServiceDataLocation {
+    // Selects the configured location of a region, or the default location if no region is requested. Only the default
+    // location can be selected unless the backend is built with the `data-residency` feature.
+    fn select(region: Option<&str>) -> Result<Self> {
+        let locations = Env::service_data_locations();
+        let locations = if cfg!(feature = "data-residency") {
+            locations
+        } else {
+            &locations[..1]
+        };
+
+        let location = match region {
+            Some(region) => locations.iter().find(|location| location.region == region),
+            None => locations.first(),
+        };
+
+        match location {
+            Some(location) => Ok(location.clone()),
+            None => bad_request!(
+                "Unsupported data region {}, supported regions are: {}",
+                region.unwrap_or_default(),
+                locations
+                    .iter()
+                    .map(|location| location.region.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Reservation {
+    endpoint: String,
+    // Reservations made before data regions were selectable use the default location
+    service_data_location: Option<ServiceDataLocation>,
+    service_data_surrealdb_url: Option<String>,
+    created_by: User,
+}
+
+fn reservation_thing(account_id: &str) -> surrealdb::sql::Thing {
+    surrealdb::sql::Thing::from((
+        "account_id_reservation",
+        surrealdb::sql::Id::String(account_id.to_string()),
+    ))
+}
+
+fn account_thing(account_id: &str) -> surrealdb::sql::Thing {
+    surrealdb::sql::Thing::from((
+        "account",
+        surrealdb::sql::Id::String(account_id.to_string()),
+    ))
+}
+
+// Reserves a new account ID and starts provisioning the account in the background, storing its service data in the
+// requested region. Progress is published to the job until provisioning finishes.
+#[instrument(err, skip(job))]
+pub(crate) async fn start(
+    principal: &User,
+    endpoint: &str,
+    region: Option<&str>,
+    job: Job,
+) -> Result<AccountStatusResponse> {
+    let location = ServiceDataLocation::select(region)?;
+
+    let res = reserve(principal, endpoint, &location, &job).await;
+
+    if res.is_err() {
+        job.finish(&res, None, None).await;
+    }
+
+    let account_id = res?;
+
+    tokio::spawn(resume(account_id.clone(), Some(job)));
+
+    Ok(AccountStatusResponse {
+        id: account_id,
+        status: ProvisioningStatus::Provisioning,
+        error: None,
+    })
+}
+
+async fn reserve(
+    principal: &User,
+    endpoint: &str,
+    location: &ServiceDataLocation,
+    job: &Job,
+) -> Result<String> {
+    principal.ensure_user_record_exists().await?;
+
+    job.step(10, "Reserving account ID");
+
+    principal.next_account_id(endpoint, location).await
+}
+
+// Provisions the account unless another run holds its lease, recording failures so the attempt is retried later. Runs
+// resumed without the job of the request that created the account notify the user who created it once provisioned.
+async fn resume(account_id: String, job: Option<Job>) {
+    let reservation = match claim(&account_id).await {
+        Ok(Some(reservation)) => reservation,
+        Ok(None) => {
+            info!(
+                account_id,
+                "Account is already being provisioned by another run"
+            );
+            return;
+        }
+        Err(err) => {
+            warn!(
+                ?err,
+                account_id, "Failed to claim account provisioning lease"
+            );
+            return;
+        }
+    };
+
+    let job = job.unwrap_or_else(|| Job::new(None, &reservation.created_by));
+
+    let res = provision(&account_id, reservation, &job).await;
+
+    if let Err(err) = &res {
+        warn!(?err, account_id, "Failed to provision account");
+
+        if let Err(err) = record_failure(&account_id).await {
+            warn!(
+                ?err,
+                account_id, "Failed to record account provisioning failure"
+            );
+        }
+    }
+
+    // Failed provisioning is retried, see `resume_provisioning_periodically`
+    let notification = res.is_ok().then(|| NewNotification {
+        kind: NotificationKind::AccountProvisioned,
+        message: format!("Account {account_id} is ready"),
+        details: HashMap::new(),
+    });
+
+    job.finish(&res, Some(&account_id), notification).await;
+}
+
+#[instrument(err, skip(reservation, job))]
+async fn provision(account_id: &str, reservation: Reservation, job: &Job) -> Result<()> {
+    let location = match reservation.service_data_location {
+        Some(location) => location,
+        None => ServiceDataLocation::select(None)?,
+    };
+
+    let service_data_surrealdb_url = if reservation.endpoint == Env::endpoint() {
+        let service_data_surrealdb_url = match reservation.service_data_surrealdb_url {
+            Some(service_data_surrealdb_url) => service_data_surrealdb_url,
+            None => {
+                job.step(20, "Creating account database");
+
+                #[cfg(feature = "data-residency")]
+                let service_data_surrealdb_url = archodex_com::create_account_service_database(
+                    account_id,
+                    &location.region,
+                    &location.aws_account_id,
+                )
+                .await?;
+                #[cfg(not(feature = "data-residency"))]
+                let service_data_surrealdb_url =
+                    archodex_com::create_account_service_database(account_id).await?;
+
+                accounts_db()
+                    .await?
+                    .query("UPDATE $reservation SET service_data_surrealdb_url = $service_data_surrealdb_url RETURN NONE")
+                    .bind(("reservation", reservation_thing(account_id)))
+                    .bind(("service_data_surrealdb_url", service_data_surrealdb_url.clone()))
+                    .await
+                    .context("Failed to submit query to record account service data database")?
+                    .check_first_real_error()
+                    .context("Failed to record account service data database")?;
+
+                service_data_surrealdb_url
+            }
+        };
+
+        job.step(60, "Migrating account database");
+
+        migrate_service_data_database(&service_data_surrealdb_url, account_id).await?;
+
+        Some(service_data_surrealdb_url)
+    } else {
+        None
+    };
+
+    job.step(90, "Creating account record");
+
+    let db = accounts_db().await?;
+
+    // The account record may have been created by a run that was interrupted before marking the reservation ready
+    let account_exists = db
+        .query("RETURN record::exists($account)")
+        .bind(("account", account_thing(account_id)))
+        .await
+        .context("Failed to submit query to check whether account record exists")?
+        .check_first_real_error()?
+        .take::<Option<bool>>(0)?
+        .unwrap_or(false);
+
+    if !account_exists {
+        let account = Account::new(
+            reservation.endpoint,
+            account_id.to_string(),
+            location,
+            service_data_surrealdb_url,
+            reservation.created_by.clone(),
+        );
+
+        db.create_account_query(&account, &reservation.created_by)
+            .await
+            .context("Failed to commit account creation transaction")?
+            .check_first_real_error()
+            .context("Failed to create new account record in accounts database")?;
+    }
+
+    db.query(
+        "UPDATE $reservation SET status = 'ready', lease_until = NONE, error = NONE RETURN NONE",
+    )
+    .bind(("reservation", reservation_thing(account_id)))
+    .await
+    .context("Failed to submit query to mark account provisioned")?
+    .check_first_real_error()
+    .context("Failed to mark account provisioned")?;
+
+    info!("Account provisioned");
+
+    Ok(())
+}
+
+// Takes the lease of a reservation that is still provisioning, returning `None` if another run holds it or all attempts
+// have failed
+async fn claim(account_id: &str) -> Result<Option<Reservation>> {
+    let reservation = accounts_db()
+        .await?
+        .query("UPDATE $reservation SET lease_until = time::now() + $lease, attempts += 1 WHERE status = 'provisioning' AND (lease_until IS NONE OR lease_until < time::now()) AND attempts < $max_attempts RETURN AFTER")
+        .bind(("reservation", reservation_thing(account_id)))
+        .bind(("lease", surrealdb::sql::Duration::from(PROVISIONING_LEASE)))
+        .bind(("max_attempts", MAX_PROVISIONING_ATTEMPTS))
+        .await
+        .context("Failed to submit query to claim account provisioning lease")?
+        .check_first_real_error()
+        .context("Failed to claim account provisioning lease")?
+        .take::<Vec<Reservation>>(0)?
+        .into_iter()
+        .next();
+
+    Ok(reservation)
+}
+
+// Errors may describe internal infrastructure, so users are shown a generic message and the error is only logged
+async fn record_failure(account_id: &str) -> Result<()> {
+    accounts_db()
+        .await?
+        .query("UPDATE $reservation SET status = IF attempts >= $max_attempts THEN 'failed' ELSE 'provisioning' END, lease_until = time::now() + $retry_delay, error = IF attempts >= $max_attempts THEN $failed_error ELSE $retried_error END RETURN NONE")
+        .bind(("reservation", reservation_thing(account_id)))
+        .bind(("max_attempts", MAX_PROVISIONING_ATTEMPTS))
+        .bind(("retry_delay", surrealdb::sql::Duration::from(PROVISIONING_RETRY_DELAY)))
+        .bind(("failed_error", "Account provisioning failed. Contact support to have it retried."))
+        .bind(("retried_error", "Account provisioning failed and will be retried shortly"))
+        .await
+        .context("Failed to submit query to record account provisioning failure")?
+        .check_first_real_error()
+        .context("Failed to record account provisioning failure")?;
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct ReservationStatus {
+    status: ProvisioningStatus,
+    error: Option<String>,
+    lease_expired: bool,
+}
+
+// Reports whether an account is still being provisioned. Accounts that finished provisioning, or were created before
+// provisioning was tracked, are reported as ready to users with access to them.
+#[instrument(err, skip(auth))]
+pub(crate) async fn account_status(
+    Extension(auth): Extension<DashboardAuth>,
+    Path(account_id): Path<String>,
+) -> Result<Json<AccountStatusResponse>> {
+    let reservation = accounts_db()
+        .await?
+        .query("SELECT status ?? 'failed' AS status, error, (lease_until IS NONE OR lease_until < time::now()) AS lease_expired FROM $reservation WHERE created_by = $user AND status != 'ready' AND !record::exists($account)")
+        .bind(("reservation", reservation_thing(&account_id)))
+        .bind(("account", account_thing(&account_id)))
+        .bind(("user", surrealdb::sql::Thing::from(auth.principal())))
+        .await
+        .context("Failed to submit query to get account provisioning status")?
+        .check_first_real_error()
+        .context("Failed to get account provisioning status")?
+        .take::<Option<ReservationStatus>>(0)?;
+
+    let Some(reservation) = reservation else {
+        if auth.validate_account_access(&account_id).await.is_err() {
+            not_found!("Account not found");
+        }
+
+        return Ok(Json(AccountStatusResponse {
+            id: account_id,
+            status: ProvisioningStatus::Ready,
+            error: None,
+        }));
+    };
+
+    // Standbys don't run background jobs, so provisioning is resumed once the primary is available again
+    if reservation.status == ProvisioningStatus::Provisioning
+        && reservation.lease_expired
+        && !standby::is_standby()
+    {
+        info!(account_id, "Resuming interrupted account provisioning");
+        tokio::spawn(resume(account_id.clone(), None));
+    }
+
+    Ok(Json(AccountStatusResponse {
+        id: account_id,
+        status: reservation.status,
+        error: reservation.error,
+    }))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct AdminProvisioningResponse {
+    status: ProvisioningStatus,
+    #[serde(default)]
+    attempts: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lease_until: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    endpoint: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_at: Option<DateTime<Utc>>,
+}
+
+// Reports the provisioning state of any account to operators, including failed provisioning that users only see as an
+// error. Accounts created before provisioning was tracked are reported as ready.
+#[instrument(err)]
+pub(crate) async fn admin_provisioning_status(
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<AdminProvisioningResponse>> {
+    let Some(account_id) = params.get("account_id") else {
+        bail!("Missing account_id");
+    };
+
+    let mut res = accounts_db()
+        .await?
+        .query("SELECT (status ?? IF record::exists($account) { 'ready' } ELSE { 'failed' }) AS status, attempts, error, lease_until, endpoint, created_at FROM $reservation")
+        .query("RETURN record::exists($account)")
+        .bind(("reservation", reservation_thing(account_id)))
+        .bind(("account", account_thing(account_id)))
+        .await
+        .context("Failed to submit query to get account provisioning state")?
+        .check_first_real_error()
+        .context("Failed to get account provisioning state")?;
+
+    if let Some(reservation) = res.take::<Option<AdminProvisioningResponse>>(0)? {
+        return Ok(Json(reservation));
+    }
+
+    if !res.take::<Option<bool>>(1)?.unwrap_or(false) {
+        not_found!("Account not found");
+    }
+
+    Ok(Json(AdminProvisioningResponse {
+        status: ProvisioningStatus::Ready,
+        attempts: 0,
+        error: None,
+        lease_until: None,
+        endpoint: None,
+        created_at: None,
+    }))
+}
+
+/// Periodically resumes provisioning of accounts whose provisioning was interrupted, e.g. by a restart of the backend
+/// instance running it. Runs take a lease on the account before provisioning it, so multiple backend instances may run
+/// this concurrently.
+pub async fn resume_provisioning_periodically() {
+    let mut interval = tokio::time::interval(PROVISIONING_RESUME_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let account_ids = match list_interrupted_provisioning().await {
+            Ok(account_ids) => account_ids,
+            Err(err) => {
+                warn!(?err, "Failed to list interrupted account provisioning");
+                continue;
+            }
+        };
+
+        for account_id in account_ids {
+            resume(account_id, None).await;
+        }
+    }
+}
+
+async fn list_interrupted_provisioning() -> Result<Vec<String>> {
+    Ok(accounts_db()
+        .await?
+        .query("SELECT VALUE record::id(id) FROM account_id_reservation WHERE status = 'provisioning' AND lease_until < time::now() AND attempts < $max_attempts")
+        .bind(("max_attempts", MAX_PROVISIONING_ATTEMPTS))
+        .await
+        .context("Failed to submit query to list interrupted account provisioning")?
+        .check_first_real_error()
+        .context("Failed to list interrupted account provisioning")?
+        .take::<Vec<String>>(0)?)
+}
@@ -1,37 +1,71 @@
-use axum::{Extension, Json, extract::Path};
+use axum::{
+    Extension,
+    body::Bytes,
+    extract::{Path, Query},
+    http::header::CONTENT_TYPE,
+    response::{IntoResponse as _, Response},
+};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use tracing::instrument;
 
+use archodex_error::anyhow::Context as _;
+
 use crate::{
     Result,
     account::Account,
-    db::{BeginReadonlyStatement, QueryCheckFirstRealError},
+    consistency::ConsistencyToken,
+    db::{BeginReadonlyStatement, QueryCheckFirstRealError, statement_timeout},
     event::Event,
     global_container::GlobalContainer,
+    query_cache::{self, QueryCacheKey},
     resource::Resource,
 };
 
-#[derive(Debug, Deserialize, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, JsonSchema, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub(super) enum QueryType {
     All,
     Secrets,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(super) struct QueryParams {
+    environment: Option<String>,
+    // Scopes results to resources with this tag, like `environment`
+    tag: Option<String>,
+    #[serde(default)]
+    include_archived: bool,
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
 pub(super) struct QueryResponse {
     resources: Vec<Resource>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     global_containers: Vec<GlobalContainer>,
     #[serde(skip_serializing_if = "Option::is_none")]
     events: Option<Vec<Event>>,
+    // Shared resources of linked accounts that resolve globally unique resources of the response, see
+    // `resource_sharing`
+    #[cfg(feature = "archodex-com")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    linked_resources: Vec<crate::resource_sharing::LinkedResources>,
 }
 
+// Statements that may scan the whole account mark where their `TIMEOUT` clause goes with `{timeout}`, so SurrealDB
+// stops executing them once the query has timed out
+fn with_statement_timeout(statements: &str) -> String {
+    statements.replace("{timeout}", &statement_timeout())
+}
+
+// Responses are cached, see `query_cache`
 #[instrument(err, skip_all)]
 pub(super) async fn query(
     Path((_account_id, r#type)): Path<(String, QueryType)>,
+    Query(params): Query<QueryParams>,
     Extension(account): Extension<Account>,
-) -> Result<Json<QueryResponse>> {
+    consistency_token: Option<Extension<ConsistencyToken>>,
+) -> Result<Response> {
     const BEGIN: &str = "LET $resources: set<object> = []; LET $events: set<object> = [];";
 
     const FINISH: &str = "{
@@ -48,29 +82,111 @@ pub(super) async fn query(
     
     COMMIT;";
 
+    // Keeps resources in the environment, events involving them, and the resources on the other side of those events
+    const SCOPE_TO_ENVIRONMENT: &str = "
+    LET $unscoped_resources = $resources;
+    $resources = SELECT * FROM $unscoped_resources WHERE $environment INSIDE environments {timeout};
+    $events = SELECT * FROM $events WHERE in INSIDE $resources.id OR out INSIDE $resources.id {timeout};
+    $resources = SELECT * FROM $unscoped_resources WHERE $environment INSIDE environments OR id INSIDE $events.in OR id INSIDE $events.out {timeout};";
+
+    // Keeps resources with the tag, events involving them, and the resources on the other side of those events
+    const SCOPE_TO_TAG: &str = "
+    LET $untagged_resources = $resources;
+    $resources = SELECT * FROM $untagged_resources WHERE $tag INSIDE tags {timeout};
+    $events = SELECT * FROM $events WHERE in INSIDE $resources.id OR out INSIDE $resources.id {timeout};
+    $resources = SELECT * FROM $untagged_resources WHERE $tag INSIDE tags OR id INSIDE $events.in OR id INSIDE $events.out {timeout};";
+
+    // Keeps active resources and events between them
+    const EXCLUDE_ARCHIVED: &str = "
+    $resources = SELECT * FROM $resources WHERE archived_at IS NONE {timeout};
+    $events = SELECT * FROM $events WHERE in INSIDE $resources.id AND out INSIDE $resources.id {timeout};";
+
+    let environment = account.scoped_environment(params.environment);
+    let consistency_token = consistency_token.map(|Extension(consistency_token)| consistency_token);
+
+    let cache_key = QueryCacheKey {
+        account_id: account.id().to_owned(),
+        r#type,
+        environment: environment.clone(),
+        tag: params.tag.clone(),
+        include_archived: params.include_archived,
+    };
+
+    // Requests that must reflect a recent write bypass cached responses
+    if consistency_token.is_none()
+        && let Some(body) = query_cache::get(&cache_key)
+    {
+        return Ok(json_response(body));
+    }
+
+    let generation = query_cache::generation(account.id());
+
     let db = account.resources_db().await?;
 
+    let begin = BeginReadonlyStatement::consistent_with(consistency_token);
+
     let query = match r#type {
         QueryType::All => db
-            .query(BeginReadonlyStatement)
+            .query(begin)
             .query(BEGIN)
             .query(Resource::get_all())
-            .query(Event::get_all())
-            .query(FINISH),
+            .query(Event::get_all()),
 
         QueryType::Secrets => {
             const SECRETS_QUERY: &str = include_str!("query_secrets.surql");
 
-            db.query(BeginReadonlyStatement)
+            db.query(begin)
                 .query(BEGIN)
-                .query(SECRETS_QUERY)
-                .query(FINISH)
+                .query(with_statement_timeout(SECRETS_QUERY))
         }
     };
 
-    let mut res = query.await?.check_first_real_error()?;
+    let query = if params.include_archived {
+        query
+    } else {
+        query.query(with_statement_timeout(EXCLUDE_ARCHIVED))
+    };
+
+    let query = match environment {
+        Some(environment) => query
+            .query(with_statement_timeout(SCOPE_TO_ENVIRONMENT))
+            .bind(("environment", environment)),
+        None => query,
+    };
+
+    let query = match params.tag {
+        Some(tag) => query
+            .query(with_statement_timeout(SCOPE_TO_TAG))
+            .bind(("tag", tag)),
+        None => query,
+    }
+    .query(FINISH);
+
+    let mut res = query.timeout().await?.check_first_real_error()?;
 
     let query_response: Option<QueryResponse> = res.take(res.num_statements() - 1)?;
+    let query_response = query_response.unwrap();
+
+    #[cfg(feature = "archodex-com")]
+    let query_response = QueryResponse {
+        linked_resources: crate::resource_sharing::resolve(
+            &account,
+            &query_response.resources,
+            &query_response.global_containers,
+        )
+        .await?,
+        ..query_response
+    };
+
+    let body = Bytes::from(
+        serde_json::to_vec(&query_response).context("Failed to serialize query response")?,
+    );
+
+    query_cache::insert(cache_key, generation, body.clone());
+
+    Ok(json_response(body))
+}
 
-    Ok(Json(query_response.unwrap()))
+fn json_response(body: Bytes) -> Response {
+    ([(CONTENT_TYPE, "application/json")], body).into_response()
 }
@@ -0,0 +1,160 @@
+// Caches the responses of `query`, which the dashboard requests repeatedly with the same parameters while the results
+// only change when the account's resources database is written to. Responses are cached per account, query type, and
+// filters, and the cached responses of an account are invalidated after every write request to the account, including
+// reports. Background jobs that write to resources databases invalidate the accounts they modify themselves.
+//
+// Writes made by other backend instances aren't seen, so entries also expire after `QUERY_CACHE_TTL`. Requests passing
+// a consistency token bypass the cache, see `consistency`. The cache holds at most `QUERY_CACHE_MAX_BYTES` of
+// responses, evicting the oldest responses first.
+
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use axum::{body::Bytes, extract::Request, middleware::Next, response::Response};
+
+use crate::{account::Account, query::QueryType, router};
+
+const QUERY_CACHE_TTL: Duration = Duration::from_secs(30);
+const QUERY_CACHE_MAX_BYTES: usize = 64 * 1024 * 1024;
+// Larger responses aren't cached so that a few of them can't evict every other response
+const MAX_CACHED_RESPONSE_BYTES: usize = QUERY_CACHE_MAX_BYTES / 16;
+
+static CACHE: LazyLock<Mutex<QueryCache>> = LazyLock::new(|| Mutex::new(QueryCache::default()));
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub(crate) struct QueryCacheKey {
+    pub(crate) account_id: String,
+    pub(crate) r#type: QueryType,
+    pub(crate) environment: Option<String>,
+    pub(crate) tag: Option<String>,
+    pub(crate) include_archived: bool,
+}
+
+struct CachedResponse {
+    loaded_at: Instant,
+    body: Bytes,
+}
+
+#[derive(Default)]
+struct QueryCache {
+    // Bumped whenever the account's cached responses are invalidated
+    generations: HashMap<String, u64>,
+    responses: HashMap<QueryCacheKey, CachedResponse>,
+    bytes: usize,
+}
+
+impl QueryCache {
+    fn generation(&self, account_id: &str) -> u64 {
+        self.generations
+            .get(account_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn remove(&mut self, key: &QueryCacheKey) {
+        if let Some(response) = self.responses.remove(key) {
+            self.bytes -= response.body.len();
+        }
+    }
+}
+
+fn cache() -> MutexGuard<'static, QueryCache> {
+    CACHE.lock().expect("Query cache mutex poisoned")
+}
+
+// Must be read before querying the database for a response to cache with `insert`
+pub(crate) fn generation(account_id: &str) -> u64 {
+    cache().generation(account_id)
+}
+
+pub(crate) fn get(key: &QueryCacheKey) -> Option<Bytes> {
+    let mut cache = cache();
+
+    let response = cache.responses.get(key)?;
+
+    if response.loaded_at.elapsed() < QUERY_CACHE_TTL {
+        return Some(response.body.clone());
+    }
+
+    cache.remove(key);
+
+    None
+}
+
+pub(crate) fn insert(key: QueryCacheKey, generation: u64, body: Bytes) {
+    if body.len() > MAX_CACHED_RESPONSE_BYTES {
+        return;
+    }
+
+    let mut cache = cache();
+
+    // If the account's responses were invalidated while the response was queried, it may already be stale
+    if cache.generation(&key.account_id) != generation {
+        return;
+    }
+
+    cache.remove(&key);
+
+    while cache.bytes + body.len() > QUERY_CACHE_MAX_BYTES {
+        let Some(oldest) = cache
+            .responses
+            .iter()
+            .min_by_key(|(_, response)| response.loaded_at)
+            .map(|(key, _)| key.clone())
+        else {
+            break;
+        };
+
+        cache.remove(&oldest);
+    }
+
+    cache.bytes += body.len();
+    cache.responses.insert(
+        key,
+        CachedResponse {
+            loaded_at: Instant::now(),
+            body,
+        },
+    );
+}
+
+// Invalidates the cached responses of an account. Must be called after writing to the account's resources database.
+pub(crate) fn invalidate(account_id: &str) {
+    let mut cache = cache();
+
+    *cache.generations.entry(account_id.to_owned()).or_default() += 1;
+
+    let keys = cache
+        .responses
+        .keys()
+        .filter(|key| key.account_id == account_id)
+        .cloned()
+        .collect::<Vec<_>>();
+
+    for key in keys {
+        cache.remove(&key);
+    }
+}
+
+// Invalidates the cached responses of the request's account after write requests, whether or not they succeed, as
+// failed requests may have written part of their changes. Must be layered within the middleware resolving the account.
+pub(crate) async fn invalidate_on_write(req: Request, next: Next) -> Response {
+    let account_id = if router::is_read_only(&req) {
+        None
+    } else {
+        req.extensions()
+            .get::<Account>()
+            .map(|account| account.id().to_owned())
+    };
+
+    let response = next.run(req).await;
+
+    if let Some(account_id) = account_id {
+        invalidate(&account_id);
+    }
+
+    response
+}
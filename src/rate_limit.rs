@@ -0,0 +1,98 @@
+// Report requests are rate limited per report key with a token bucket, so a misbehaving agent cannot flood its
+// account's database. Each key's bucket holds up to `REPORT_RATE_LIMIT_BURST` requests and refills at
+// `REPORT_RATE_LIMIT_PER_SECOND` requests per second. Buckets are kept in memory, so each backend instance limits the
+// requests it receives independently. Setting `REPORT_RATE_LIMIT_PER_SECOND` to 0 disables rate limiting.
+
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{Extension, extract::Request, middleware::Next, response::Response};
+use tracing::warn;
+
+use archodex_error::{ErrorCode, PublicError};
+
+use crate::{Result, auth::ReportApiKeyAuth, env::Env};
+
+// Full buckets are equivalent to missing buckets, so they are pruned once this many keys are tracked
+const MAX_TRACKED_BUCKETS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant, rate: f64, burst: f64) {
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated_at = now;
+    }
+}
+
+// Report key IDs are only unique within an account
+static BUCKETS: LazyLock<Mutex<HashMap<(String, u32), Bucket>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// Takes a token from the bucket of a report key, or returns how long until a token is available
+fn take_token(
+    account_id: &str,
+    key_id: u32,
+    rate: f64,
+    burst: f64,
+) -> std::result::Result<(), Duration> {
+    let now = Instant::now();
+
+    let mut buckets = BUCKETS.lock().expect("Rate limit mutex poisoned");
+
+    if buckets.len() >= MAX_TRACKED_BUCKETS {
+        buckets.retain(|_, bucket| {
+            bucket.refill(now, rate, burst);
+            bucket.tokens < burst
+        });
+    }
+
+    let bucket = buckets
+        .entry((account_id.to_owned(), key_id))
+        .or_insert(Bucket {
+            tokens: burst,
+            updated_at: now,
+        });
+
+    bucket.refill(now, rate, burst);
+
+    if bucket.tokens < 1.0 {
+        return Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate));
+    }
+
+    bucket.tokens -= 1.0;
+
+    Ok(())
+}
+
+pub(crate) async fn limit_report_api_key(
+    Extension(auth): Extension<ReportApiKeyAuth>,
+    req: Request,
+    next: Next,
+) -> Result<Response> {
+    if let Some((rate, burst)) = Env::report_rate_limit()
+        && let Err(retry_after) = take_token(auth.account_id(), auth.key_id(), rate, burst)
+    {
+        warn!(
+            account_id = auth.account_id(),
+            key_id = auth.key_id(),
+            "Report key exceeded rate limit"
+        );
+
+        return Err(PublicError::with_code(
+            ErrorCode::RateLimited,
+            "Report key exceeded its request rate limit",
+        )
+        .retry_after(retry_after));
+    }
+
+    Ok(next.run(req).await)
+}
@@ -0,0 +1,170 @@
+// Readiness of the backend instance to serve requests, served at `GET /health/ready` for load balancer and orchestrator
+// readiness gates. `GET /health` only shows that the process is up, while readiness probes the dependencies every
+// dashboard request needs: the accounts database, and the JWKS that dashboard access tokens are verified with.
+// Instances that are shutting down are not ready, so they are taken out of rotation while their connections drain.
+//
+// The JWKS and OIDC identity providers are external to the backend, and an outage of one affects every instance alike.
+// Taking all instances out of rotation would also stop report ingestion, which doesn't depend on them, so their outages
+// only mark the instance as degraded.
+//
+// The endpoint is unauthenticated, so why a dependency is unavailable is only logged.
+
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use axum::{Json, http::StatusCode};
+use serde::Serialize;
+use tracing::{instrument, warn};
+
+use archodex_error::anyhow::Context as _;
+
+use crate::{
+    Result,
+    db::{QueryCheckFirstRealError as _, accounts_db},
+    env::Env,
+    oidc, shutdown,
+};
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The only dependency whose outage makes the instance unavailable
+const ACCOUNTS_DATABASE: &str = "accounts_database";
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ReadinessStatus {
+    Ok,
+    // Only identity provider dependencies are unavailable, dashboard sign in and requests may fail
+    Degraded,
+    Unavailable,
+}
+
+#[derive(Serialize)]
+pub(crate) struct DependencyReadiness {
+    status: ReadinessStatus,
+    // Time the probe took, or the probe timeout if it timed out
+    latency_ms: u64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ReadinessResponse {
+    // `ok` once the instance is not shutting down and all dependencies are available, `degraded` if only identity
+    // provider dependencies are unavailable
+    status: ReadinessStatus,
+    shutting_down: bool,
+    // Keyed by dependency, i.e. `accounts_database`, `jwks`, and `oidc:<provider name>` for each configured OIDC
+    // provider of self-hosted backends
+    dependencies: BTreeMap<String, DependencyReadiness>,
+}
+
+async fn probe(
+    dependency: String,
+    probe: impl Future<Output = Result<()>>,
+) -> (String, DependencyReadiness) {
+    let started_at = Instant::now();
+
+    let status = match tokio::time::timeout(PROBE_TIMEOUT, probe).await {
+        Ok(Ok(())) => ReadinessStatus::Ok,
+        Ok(Err(err)) => {
+            warn!(dependency, ?err, "Readiness probe failed");
+            ReadinessStatus::Unavailable
+        }
+        Err(_) => {
+            warn!(dependency, timeout = ?PROBE_TIMEOUT, "Readiness probe timed out");
+            ReadinessStatus::Unavailable
+        }
+    };
+
+    let readiness = DependencyReadiness {
+        status,
+        latency_ms: u64::try_from(started_at.elapsed().as_millis()).unwrap_or(u64::MAX),
+    };
+
+    (dependency, readiness)
+}
+
+async fn probe_accounts_database() -> Result<()> {
+    accounts_db()
+        .await?
+        .query("RETURN true")
+        .await
+        .context("Failed to submit readiness query to accounts database")?
+        .check_first_real_error()
+        .context("Failed to run readiness query in accounts database")?;
+
+    Ok(())
+}
+
+// JWKS are cached, so the JWKS is only fetched once its cached copy expires, like when verifying access tokens
+async fn probe_jwks() -> Result<()> {
+    oidc::jwks(&Env::auth_claims().jwks_url).await?;
+
+    Ok(())
+}
+
+#[cfg(not(feature = "archodex-com"))]
+async fn probe_oidc_provider(provider: &oidc::OidcProvider) -> Result<()> {
+    let discovery = oidc::discovery(&provider.issuer).await?;
+
+    oidc::jwks(&discovery.jwks_uri).await?;
+
+    Ok(())
+}
+
+// Probes all dependencies concurrently. Responds with `503 Service Unavailable` unless the instance is ready or
+// degraded.
+#[instrument(skip_all)]
+pub(crate) async fn ready() -> (StatusCode, Json<ReadinessResponse>) {
+    let (accounts_database, jwks) = futures::join!(
+        probe(ACCOUNTS_DATABASE.to_string(), probe_accounts_database()),
+        probe("jwks".to_string(), probe_jwks()),
+    );
+
+    #[cfg(not(feature = "archodex-com"))]
+    let oidc_providers = futures::future::join_all(Env::oidc_providers().iter().map(|provider| {
+        probe(
+            format!("oidc:{}", provider.name),
+            probe_oidc_provider(provider),
+        )
+    }))
+    .await;
+    #[cfg(feature = "archodex-com")]
+    let oidc_providers = Vec::new();
+
+    let dependencies = [accounts_database, jwks]
+        .into_iter()
+        .chain(oidc_providers)
+        .collect::<BTreeMap<_, _>>();
+
+    let shutting_down = shutdown::is_initiated();
+
+    let unavailable = dependencies
+        .iter()
+        .filter(|(_, dependency)| dependency.status != ReadinessStatus::Ok)
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>();
+
+    let status = if shutting_down || unavailable.contains(&ACCOUNTS_DATABASE) {
+        ReadinessStatus::Unavailable
+    } else if unavailable.is_empty() {
+        ReadinessStatus::Ok
+    } else {
+        ReadinessStatus::Degraded
+    };
+
+    let status_code = match status {
+        ReadinessStatus::Ok | ReadinessStatus::Degraded => StatusCode::OK,
+        ReadinessStatus::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    (
+        status_code,
+        Json(ReadinessResponse {
+            status,
+            shutting_down,
+            dependencies,
+        }),
+    )
+}
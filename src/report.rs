@@ -1,26 +1,51 @@
 use core::fmt::Debug;
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    time::Instant,
+};
 
-use axum::{Extension, Json};
+use axum::{
+    Extension, Json,
+    body::{Body, Bytes},
+    extract::Path,
+    http::HeaderMap,
+};
 use chrono::{DateTime, Utc};
-use serde::Deserialize;
-use surrealdb::{
-    engine::any::Any,
-    method::Query,
-    sql::statements::{BeginStatement, CommitStatement, InsertStatement, UpdateStatement},
+use futures::StreamExt as _;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use surrealdb::sql::statements::{
+    BeginStatement, CommitStatement, InsertStatement, UpdateStatement,
 };
-use tracing::{info, instrument};
+use tracing::{info, instrument, warn};
+
+use archodex_error::{ErrorCode, anyhow::Context as _, bad_request, coded_error, not_found};
 
 use crate::{
     Result,
     account::Account,
-    db::QueryCheckFirstRealError,
+    auth::ReportApiKeyAuth,
+    consistency::ConsistencyToken,
+    db::{InstrumentedQuery, QueryCheckFirstRealError, statement_timeout, storage_throttled_error},
+    dead_letter,
+    env::Env,
+    graph_stream::{self, GraphUpdate},
+    idempotency::{self, IdempotencyKey},
+    ingestion_pipeline::IngestionPipeline,
+    lifecycle,
+    limits::Limit,
+    metrics::{REPORT_INGESTION_DURATION_SECONDS, REPORT_RESOURCE_TREE_DEPTH},
     next_binding,
     resource::{ResourceId, ResourceIdPart, surrealdb_thing_from_resource_id},
+    secret_hash::SecretHash,
+    sensor::Sensor,
+    usage,
     value::surrealdb_value_from_json_value,
+    webhooks,
 };
 
-#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq)]
 #[serde(deny_unknown_fields)]
 struct Principal {
     id: ResourceId,
@@ -49,26 +74,64 @@ fn surrealdb_value_from_principal_chain(principal_chain: Vec<Principal>) -> surr
 
 // TODO: Implement deserializer to handle unknown fields. Serde's built-in
 // unknown field handling doesn't work with its flatten option.
-#[derive(Debug, Deserialize)]
-struct ResourceTreeNode {
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct ResourceTreeNode {
+    #[serde(flatten)]
+    id: ResourceIdPart,
+    globally_unique: Option<bool>,
+    #[serde(deserialize_with = "crate::timestamp::deserialize")]
+    first_seen_at: DateTime<Utc>,
+    #[serde(deserialize_with = "crate::timestamp::deserialize")]
+    last_seen_at: DateTime<Utc>,
+    pub(crate) attributes: Option<serde_json::Map<String, serde_json::Value>>,
+    // How the ID of a Secret Value resource was hashed, see `secret_hash`
+    secret_hash: Option<SecretHash>,
+    pub(crate) contains: Option<Vec<ResourceTreeNode>>,
+}
+
+// Resource tree node of report format version 1, which predates `secret_hash`
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct ResourceTreeNodeV1 {
     #[serde(flatten)]
     id: ResourceIdPart,
     globally_unique: Option<bool>,
+    #[serde(deserialize_with = "crate::timestamp::deserialize")]
     first_seen_at: DateTime<Utc>,
+    #[serde(deserialize_with = "crate::timestamp::deserialize")]
     last_seen_at: DateTime<Utc>,
     attributes: Option<serde_json::Map<String, serde_json::Value>>,
-    contains: Option<Vec<ResourceTreeNode>>,
+    contains: Option<Vec<ResourceTreeNodeV1>>,
 }
 
-#[derive(Debug, Deserialize)]
+impl From<ResourceTreeNodeV1> for ResourceTreeNode {
+    fn from(node: ResourceTreeNodeV1) -> Self {
+        Self {
+            id: node.id,
+            globally_unique: node.globally_unique,
+            first_seen_at: node.first_seen_at,
+            last_seen_at: node.last_seen_at,
+            attributes: node.attributes,
+            secret_hash: None,
+            contains: node
+                .contains
+                .map(|contains| contains.into_iter().map(Self::from).collect()),
+        }
+    }
+}
+
+// Named apart from the events returned by queries, which share a namespace with it in the OpenAPI document
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
+#[schemars(rename = "CapturedEvent")]
 struct Event {
     r#type: String,
+    #[serde(deserialize_with = "crate::timestamp::deserialize")]
     first_seen_at: DateTime<Utc>,
+    #[serde(deserialize_with = "crate::timestamp::deserialize")]
     last_seen_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 struct EventCapture {
     principals: Vec<Principal>,
@@ -76,34 +139,241 @@ struct EventCapture {
     events: Vec<Event>,
 }
 
-#[derive(Debug, Deserialize)]
+// Event relation an agent no longer observes, see `RequestV2::removed_events`
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct RemovedEvent {
+    principal: ResourceId,
+    resource: ResourceId,
+    r#type: String,
+}
+
+// Report format version 1. Reports without a `schema_version` are version 1, as agents predating the field send them.
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
-pub(super) struct Request {
+pub(crate) struct RequestV1 {
+    // Read before the report is parsed, see `Request::from_slice`
+    #[allow(dead_code)]
+    schema_version: Option<u32>,
+    resource_captures: Vec<ResourceTreeNodeV1>,
+    event_captures: Vec<EventCapture>,
+}
+
+// Report format version 2, which adds report sequence numbers, secret hash declarations of resource tree nodes, and
+// removals of resources and event relations to version 1
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RequestV2 {
+    // Read before the report is parsed, see `Request::from_slice`
+    #[allow(dead_code)]
+    schema_version: u32,
+    // Optional number the agent increments with each report it sends with its report key, see `check_sequence`
+    #[serde(default)]
+    sequence: Option<u64>,
     resource_captures: Vec<ResourceTreeNode>,
     event_captures: Vec<EventCapture>,
+    // Resources the agent observed disappear. They are marked gone along with the resources they contain, see
+    // `remove_resources`.
+    #[serde(default)]
+    removed: Vec<ResourceId>,
+    // Event relations the agent observed disappear. They are marked gone, see `remove_events`.
+    #[serde(default)]
+    removed_events: Vec<RemovedEvent>,
 }
 
+// Report formats accepted from agents. Agents and the backend are upgraded independently, so formats remain accepted
+// after newer ones are introduced and each is translated into `Request`, the format reports are ingested in. Formats
+// are frozen once released, so changes to them are introduced as a new version.
+#[derive(Debug)]
+enum VersionedRequest {
+    V1(RequestV1),
+    V2(RequestV2),
+}
+
+impl From<VersionedRequest> for Request {
+    fn from(req: VersionedRequest) -> Self {
+        match req {
+            VersionedRequest::V1(RequestV1 {
+                resource_captures,
+                event_captures,
+                ..
+            }) => Self {
+                sequence: None,
+                resource_captures: resource_captures
+                    .into_iter()
+                    .map(ResourceTreeNode::from)
+                    .collect(),
+                event_captures,
+                removed: vec![],
+                removed_events: vec![],
+            },
+            VersionedRequest::V2(RequestV2 {
+                sequence,
+                resource_captures,
+                event_captures,
+                removed,
+                removed_events,
+                ..
+            }) => Self {
+                sequence,
+                resource_captures,
+                event_captures,
+                removed,
+                removed_events,
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Request {
+    sequence: Option<u64>,
+    pub(crate) resource_captures: Vec<ResourceTreeNode>,
+    event_captures: Vec<EventCapture>,
+    removed: Vec<ResourceId>,
+    removed_events: Vec<RemovedEvent>,
+}
+
+impl Request {
+    // Parses a report of any accepted format. `description` names the report in errors, e.g. `report` or
+    // `bundle report 2`.
+    pub(crate) fn from_slice(bytes: &[u8], description: &str) -> Result<Self> {
+        #[derive(Deserialize)]
+        struct SchemaVersion {
+            schema_version: Option<u32>,
+        }
+
+        let schema_version = match serde_json::from_slice::<SchemaVersion>(bytes) {
+            Ok(SchemaVersion { schema_version }) => schema_version,
+            Err(err) => bad_request!("Invalid {description}: {err}"),
+        };
+
+        let req = match schema_version.unwrap_or(1) {
+            1 => match serde_json::from_slice(bytes) {
+                Ok(req) => VersionedRequest::V1(req),
+                Err(err) => bad_request!("Invalid {description}: {err}"),
+            },
+            2 => match serde_json::from_slice(bytes) {
+                Ok(req) => VersionedRequest::V2(req),
+                Err(err) => bad_request!("Invalid {description}: {err}"),
+            },
+            version => coded_error!(
+                ErrorCode::SchemaVersionUnsupported,
+                "Unsupported schema version {version} of {description}, the latest supported version is \
+                 {REPORT_SCHEMA_VERSION}"
+            ),
+        };
+
+        Ok(req.into())
+    }
+
+    // Reports ingested out of order on purpose, e.g. retried dead letters, are not checked against the sequence of
+    // their report key
+    pub(crate) fn clear_sequence(&mut self) {
+        self.sequence = None;
+    }
+}
+
+// Observation of a resource or event by the sensor of a report, recorded in its `observed_by` field. Generates
+// `fn::observe(observed_by, <sensor>, <first_seen_at>, <last_seen_at>)`, which widens the sensor's observation window.
+fn observed_by_update(
+    sensor: &str,
+    first_seen_at: DateTime<Utc>,
+    last_seen_at: DateTime<Utc>,
+) -> surrealdb::sql::Value {
+    surrealdb::sql::Value::Function(Box::new(surrealdb::sql::Function::Custom(
+        "observe".to_string(),
+        vec![
+            surrealdb::sql::Value::Idiom("observed_by".into()),
+            surrealdb::sql::Strand::from(sensor).into(),
+            surrealdb::sql::Datetime::from(first_seen_at).into(),
+            surrealdb::sql::Datetime::from(last_seen_at).into(),
+        ],
+    )))
+}
+
+// Reports may arrive out of order, e.g. when an offline bundle is imported after newer reports were sent directly, so
+// existing observation windows are only ever widened. Generates `<function>([<field>, <timestamp>])`.
+fn widened_timestamp(
+    function: &str,
+    field: &str,
+    timestamp: DateTime<Utc>,
+) -> surrealdb::sql::Value {
+    surrealdb::sql::Value::Function(Box::new(surrealdb::sql::Function::Normal(
+        function.to_string(),
+        vec![
+            surrealdb::sql::Array::from(vec![
+                surrealdb::sql::Value::Idiom(field.into()),
+                surrealdb::sql::Datetime::from(timestamp).into(),
+            ])
+            .into(),
+        ],
+    )))
+}
+
+// Resource trees are walked with an explicit work stack rather than by recursion, so deeply nested trees cannot
+// overflow the stack. Resources are upserted before the resources they contain.
+#[instrument(skip_all)]
+fn upsert_resource_tree<'a>(
+    mut query: InstrumentedQuery<'a>,
+    resource_tree_node: ResourceTreeNode,
+    sensor: &str,
+) -> InstrumentedQuery<'a> {
+    let mut stack = vec![(surrealdb::sql::Array::new(), resource_tree_node)];
+
+    while let Some((parent_id, mut resource_tree_node)) = stack.pop() {
+        let children = resource_tree_node.contains.take();
+
+        let resource_id;
+        (query, resource_id) =
+            upsert_resource_tree_node(query, parent_id, resource_tree_node, sensor);
+
+        // Children are pushed in reverse so they are popped in report order
+        for child in children.into_iter().flatten().rev() {
+            stack.push((resource_id.clone(), child));
+        }
+    }
+
+    query
+}
+
+// Upserts a single resource tree node and returns its resource ID
 #[instrument(skip_all)]
 fn upsert_resource_tree_node<'a>(
-    mut query: Query<'a, Any>,
-    prefix: &mut surrealdb::sql::Array,
+    mut query: InstrumentedQuery<'a>,
+    parent_id: surrealdb::sql::Array,
     resource_tree_node: ResourceTreeNode,
-) -> Query<'a, Any> {
-    // INSERT INTO resource (id, first_seen_at, last_seen_at) VALUES (<id>, <first_seen_at>, <last_seen_at>) ON DUPLICATE KEY UPDATE last_seen_at = <last_seen_at> RETURN NONE
+    sensor: &str,
+) -> (InstrumentedQuery<'a>, surrealdb::sql::Array) {
+    // INSERT INTO resource (id, first_seen_at, last_seen_at) VALUES (<id>, <first_seen_at>, <last_seen_at>) ON DUPLICATE KEY UPDATE first_seen_at = time::min([first_seen_at, <first_seen_at>]), last_seen_at = time::max([last_seen_at, <last_seen_at>]) RETURN NONE
     let mut resource_upsert = InsertStatement::default();
     resource_upsert.into = Some(surrealdb::sql::Table::from("resource").into());
 
-    let mut globally_unique_prefix = surrealdb::sql::Array::new();
-
-    let prefix = match resource_tree_node.globally_unique {
-        Some(true) => &mut globally_unique_prefix,
-        _ => prefix,
+    // Globally unique resources are recorded as the root of their own hierarchy, and linked to the resource they were
+    // observed within by a `contains` relation
+    let (container_id, mut resource_id) = match resource_tree_node.globally_unique {
+        Some(true) => (
+            (!parent_id.is_empty()).then_some(parent_id),
+            surrealdb::sql::Array::new(),
+        ),
+        _ => (None, parent_id),
     };
 
-    prefix.push(resource_tree_node.id.into());
+    resource_id.push(resource_tree_node.id.into());
 
-    resource_upsert.data = surrealdb::sql::Data::ValuesExpression(vec![vec![
-        ("id".into(), prefix.clone().into()),
+    let mut observation = surrealdb::sql::Object::default();
+    observation.insert("sensor".to_string(), sensor.into());
+    observation.insert(
+        "first_seen_at".to_string(),
+        surrealdb::sql::Datetime::from(resource_tree_node.first_seen_at).into(),
+    );
+    observation.insert(
+        "last_seen_at".to_string(),
+        surrealdb::sql::Datetime::from(resource_tree_node.last_seen_at).into(),
+    );
+
+    let mut values = vec![
+        ("id".into(), resource_id.clone().into()),
         (
             "first_seen_at".into(),
             resource_tree_node.first_seen_at.into(),
@@ -112,13 +382,51 @@ fn upsert_resource_tree_node<'a>(
             "last_seen_at".into(),
             resource_tree_node.last_seen_at.into(),
         ),
-    ]]);
+        (
+            "observed_by".into(),
+            surrealdb::sql::Array::from(vec![surrealdb::sql::Value::from(observation)]).into(),
+        ),
+    ];
+
+    let mut updates = vec![
+        (
+            "first_seen_at".into(),
+            surrealdb::sql::Operator::Equal,
+            widened_timestamp(
+                "time::min",
+                "first_seen_at",
+                resource_tree_node.first_seen_at,
+            ),
+        ),
+        (
+            "last_seen_at".into(),
+            surrealdb::sql::Operator::Equal,
+            widened_timestamp("time::max", "last_seen_at", resource_tree_node.last_seen_at),
+        ),
+        (
+            "observed_by".into(),
+            surrealdb::sql::Operator::Equal,
+            observed_by_update(
+                sensor,
+                resource_tree_node.first_seen_at,
+                resource_tree_node.last_seen_at,
+            ),
+        ),
+    ];
+
+    if let Some(secret_hash) = resource_tree_node.secret_hash {
+        let secret_hash = surrealdb::sql::Value::from(secret_hash);
+
+        values.push(("secret_hash".into(), secret_hash.clone()));
+        updates.push((
+            "secret_hash".into(),
+            surrealdb::sql::Operator::Equal,
+            secret_hash,
+        ));
+    }
 
-    resource_upsert.update = Some(surrealdb::sql::Data::UpdateExpression(vec![(
-        "last_seen_at".into(),
-        surrealdb::sql::Operator::Equal,
-        resource_tree_node.last_seen_at.into(),
-    )]));
+    resource_upsert.data = surrealdb::sql::Data::ValuesExpression(vec![values]);
+    resource_upsert.update = Some(surrealdb::sql::Data::UpdateExpression(updates));
 
     resource_upsert.output = Some(surrealdb::sql::Output::None);
 
@@ -126,6 +434,56 @@ fn upsert_resource_tree_node<'a>(
 
     query = query.query(resource_upsert);
 
+    if let Some(container_id) = container_id {
+        let id_binding = next_binding();
+        let in_binding = next_binding();
+        let out_binding = next_binding();
+        let first_seen_at_binding = next_binding();
+        let last_seen_at_binding = next_binding();
+        let sensor_binding = next_binding();
+
+        // The relation ID is the contained resource's ID, so a resource has at most one container
+        let statement = format!(
+            "INSERT RELATION INTO contains
+            (id, in, out, first_seen_at, last_seen_at, observed_by)
+            VALUES (${id_binding}, ${in_binding}, ${out_binding}, ${first_seen_at_binding}, ${last_seen_at_binding}, [{{ sensor: ${sensor_binding}, first_seen_at: ${first_seen_at_binding}, last_seen_at: ${last_seen_at_binding} }}])
+            ON DUPLICATE KEY UPDATE first_seen_at = time::min([first_seen_at, ${first_seen_at_binding}]), last_seen_at = time::max([last_seen_at, ${last_seen_at_binding}]), observed_by = fn::observe(observed_by, ${sensor_binding}, ${first_seen_at_binding}, ${last_seen_at_binding})
+            RETURN NONE;"
+        );
+
+        info!(statement = statement, "Contains relation insert statement");
+
+        query = query
+            .query(statement)
+            .bind((
+                id_binding,
+                surrealdb::sql::Thing::from((
+                    "contains",
+                    surrealdb::sql::Id::from(resource_id.clone()),
+                )),
+            ))
+            .bind((
+                in_binding,
+                surrealdb::sql::Thing::from(("resource", surrealdb::sql::Id::from(container_id))),
+            ))
+            .bind((
+                out_binding,
+                surrealdb::sql::Thing::from((
+                    "resource",
+                    surrealdb::sql::Id::from(resource_id.clone()),
+                )),
+            ))
+            .bind((
+                first_seen_at_binding,
+                surrealdb::sql::Datetime::from(resource_tree_node.first_seen_at),
+            ))
+            .bind((
+                last_seen_at_binding,
+                surrealdb::sql::Datetime::from(resource_tree_node.last_seen_at),
+            ))
+            .bind((sensor_binding, sensor.to_string()));
+    }
+
     if let Some(attributes) = resource_tree_node.attributes
         && !attributes.is_empty()
     {
@@ -133,16 +491,25 @@ fn upsert_resource_tree_node<'a>(
         let mut resource_attributes_merge = UpdateStatement::default();
 
         resource_attributes_merge.what = vec![
-            surrealdb::sql::Thing::from(("resource", surrealdb::sql::Id::from(prefix.clone())))
-                .into(),
+            surrealdb::sql::Thing::from((
+                "resource",
+                surrealdb::sql::Id::from(resource_id.clone()),
+            ))
+            .into(),
         ]
         .into();
 
+        let attributes = match Env::attribute_document_min_bytes() {
+            Some(min_bytes) => {
+                let attributes_value;
+                (query, attributes_value) = store_attribute_documents(query, attributes, min_bytes);
+                attributes_value
+            }
+            None => surrealdb_value_from_json_value(attributes.into()),
+        };
+
         let mut merge_data = surrealdb::sql::Object::default();
-        merge_data.insert(
-            "attributes".to_string(),
-            surrealdb_value_from_json_value(attributes.into()),
-        );
+        merge_data.insert("attributes".to_string(), attributes);
         resource_attributes_merge.data =
             Some(surrealdb::sql::Data::MergeExpression(merge_data.into()));
 
@@ -153,20 +520,69 @@ fn upsert_resource_tree_node<'a>(
         query = query.query(resource_attributes_merge);
     }
 
-    if let Some(children) = resource_tree_node.contains {
-        for child in children {
-            query = upsert_resource_tree_node(query, prefix, child);
+    (query, resource_id)
+}
+
+// Replaces attribute values whose serialized size is at least `min_bytes` with references to attribute documents
+// addressed by the hash of their content, inserting documents that are not stored yet. Identical documents, e.g. IAM
+// policies attached to many roles, are then stored once per account. Object keys are sorted before hashing so key order
+// doesn't affect the address.
+fn store_attribute_documents(
+    mut query: InstrumentedQuery<'_>,
+    attributes: serde_json::Map<String, serde_json::Value>,
+    min_bytes: usize,
+) -> (InstrumentedQuery<'_>, surrealdb::sql::Value) {
+    let mut stored_attributes = surrealdb::sql::Object::default();
+
+    for (name, mut value) in attributes {
+        sort_object_keys(&mut value);
+
+        let serialized = value.to_string();
+
+        if serialized.len() < min_bytes {
+            stored_attributes.insert(name, surrealdb_value_from_json_value(value));
+            continue;
         }
+
+        let document = surrealdb::sql::Thing::from((
+            "attribute_document",
+            surrealdb::sql::Id::from(hex::encode(Sha256::digest(serialized.as_bytes()))),
+        ));
+
+        let id_binding = next_binding();
+        let document_binding = next_binding();
+
+        query = query
+            .query(format!(
+                "INSERT IGNORE INTO attribute_document {{ id: ${id_binding}, document: ${document_binding} }} RETURN NONE;"
+            ))
+            .bind((id_binding, document.clone()))
+            .bind((document_binding, surrealdb_value_from_json_value(value)));
+
+        stored_attributes.insert(name, document.into());
     }
 
-    prefix.pop();
+    (query, stored_attributes.into())
+}
 
-    query
+fn sort_object_keys(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            map.sort_keys();
+            map.values_mut().for_each(sort_object_keys);
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(sort_object_keys),
+        _ => {}
+    }
 }
 
 #[allow(clippy::too_many_lines)]
 #[instrument(skip_all)]
-fn upsert_events(mut query: Query<'_, Any>, report: EventCapture) -> Query<'_, Any> {
+fn upsert_events<'a>(
+    mut query: InstrumentedQuery<'a>,
+    report: EventCapture,
+    sensor: &str,
+) -> InstrumentedQuery<'a> {
     let first_seen_at = report
         .events
         .iter()
@@ -190,7 +606,7 @@ fn upsert_events(mut query: Query<'_, Any>, report: EventCapture) -> Query<'_, A
         "${principal_chain_id_var} = INSERT INTO principal_chain
         (id, first_seen_at, last_seen_at)
         VALUES (${principals_binding}, ${first_seen_at_binding}, ${last_seen_at_binding})
-        ON DUPLICATE KEY UPDATE last_seen_at = ${last_seen_at_binding}
+        ON DUPLICATE KEY UPDATE first_seen_at = time::min([first_seen_at, ${first_seen_at_binding}]), last_seen_at = time::max([last_seen_at, ${last_seen_at_binding}])
         RETURN id;"
     );
 
@@ -238,12 +654,13 @@ fn upsert_events(mut query: Query<'_, Any>, report: EventCapture) -> Query<'_, A
                 let has_direct_principal_chain_binding = next_binding();
                 let first_seen_at_binding = next_binding();
                 let last_seen_at_binding = next_binding();
+                let sensor_binding = next_binding();
 
                 let statement = format!(
                     "INSERT RELATION INTO event
-                    (in, out, type, principal_chains, has_direct_principal_chain, first_seen_at, last_seen_at)
-                    VALUES (${principal_id_binding}, ${resource_id_binding}, ${type_binding}, [${principal_chain_id_var}[0].id], ${has_direct_principal_chain_binding}, ${first_seen_at_binding}, ${last_seen_at_binding})
-                    ON DUPLICATE KEY UPDATE principal_chains += ${principal_chain_id_var}[0].id, last_seen_at = ${last_seen_at_binding}{has_direct_principal_chain_update}
+                    (in, out, type, principal_chains, has_direct_principal_chain, first_seen_at, last_seen_at, observed_by)
+                    VALUES (${principal_id_binding}, ${resource_id_binding}, ${type_binding}, [${principal_chain_id_var}[0].id], ${has_direct_principal_chain_binding}, ${first_seen_at_binding}, ${last_seen_at_binding}, [{{ sensor: ${sensor_binding}, first_seen_at: ${first_seen_at_binding}, last_seen_at: ${last_seen_at_binding} }}])
+                    ON DUPLICATE KEY UPDATE principal_chains += ${principal_chain_id_var}[0].id, first_seen_at = time::min([first_seen_at, ${first_seen_at_binding}]), last_seen_at = time::max([last_seen_at, ${last_seen_at_binding}]), observed_by = fn::observe(observed_by, ${sensor_binding}, ${first_seen_at_binding}, ${last_seen_at_binding}){has_direct_principal_chain_update}
                     RETURN NONE;"
                 );
 
@@ -279,7 +696,8 @@ fn upsert_events(mut query: Query<'_, Any>, report: EventCapture) -> Query<'_, A
                         has_direct_principal_chain_value,
                     ))
                     .bind((first_seen_at_binding, first_seen_at_value))
-                    .bind((last_seen_at_binding, last_seen_at_value));
+                    .bind((last_seen_at_binding, last_seen_at_value))
+                    .bind((sensor_binding, sensor.to_string()));
             }
         }
     }
@@ -287,29 +705,890 @@ fn upsert_events(mut query: Query<'_, Any>, report: EventCapture) -> Query<'_, A
     query
 }
 
-#[instrument(err, skip(account))]
+// Marks resources the agent observed disappear, and the resources they contain, as gone. Resources become present again
+// when a later report observes them after they were marked gone, see `clear_gone_on_seen`. Resources that were never
+// recorded are ignored.
+#[instrument(skip_all)]
+fn remove_resources(
+    mut query: InstrumentedQuery<'_>,
+    removed: Vec<ResourceId>,
+) -> InstrumentedQuery<'_> {
+    for resource_id in removed {
+        let subtree_var = next_binding();
+        let resource_binding = next_binding();
+        let depth_binding = next_binding();
+
+        let depth = resource_id.len();
+
+        query = query
+            .query(
+                lifecycle::subtree_statements(&subtree_var, &resource_binding, &depth_binding)
+                    .join("\n"),
+            )
+            .query(format!(
+                "UPDATE ${subtree_var} SET gone_at = time::now() WHERE gone_at IS NONE RETURN NONE;"
+            ))
+            .bind((
+                resource_binding,
+                surrealdb_thing_from_resource_id(resource_id),
+            ))
+            .bind((depth_binding, depth));
+    }
+
+    query
+}
+
+// Marks event relations the agent observed disappear as gone. Like resources, they become present again when a later
+// report observes them.
+#[instrument(skip_all)]
+fn remove_events(
+    mut query: InstrumentedQuery<'_>,
+    removed_events: Vec<RemovedEvent>,
+) -> InstrumentedQuery<'_> {
+    for event in removed_events {
+        let principal_binding = next_binding();
+        let resource_binding = next_binding();
+        let type_binding = next_binding();
+
+        query = query
+            .query(format!(
+                "UPDATE event SET gone_at = time::now() WHERE in = ${principal_binding} AND out = ${resource_binding} AND type = ${type_binding} AND gone_at IS NONE RETURN NONE;"
+            ))
+            .bind((principal_binding, surrealdb_thing_from_resource_id(event.principal)))
+            .bind((resource_binding, surrealdb_thing_from_resource_id(event.resource)))
+            .bind((type_binding, event.r#type));
+    }
+
+    query
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct ReportResponse {
+    // Passed back in the `X-Consistency-Token` header of queries that must reflect this report, see `consistency`
+    consistency_token: String,
+}
+
+// Reports sent with an `Idempotency-Key` header that was already used with the same report key are not ingested again,
+// see `idempotency`
+#[instrument(err, skip(auth, account, headers, body))]
 pub(crate) async fn report(
+    Extension(auth): Extension<ReportApiKeyAuth>,
+    Extension(account): Extension<Account>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<ReportResponse>> {
+    let req = Request::from_slice(&body, "report")?;
+
+    let idempotency_key = IdempotencyKey::from_headers(&headers, auth.key_id(), &body)?;
+
+    if let Some(idempotency_key) = &idempotency_key
+        && let Some(ingested_at) = idempotency::ingested_at(&account, idempotency_key).await?
+    {
+        info!("Report was already ingested with the same idempotency key");
+
+        return Ok(Json(ReportResponse {
+            consistency_token: ConsistencyToken::issue_for(ingested_at),
+        }));
+    }
+
+    let sensor = Sensor::from_headers(&account, &headers, auth.key_id()).await?;
+
+    usage::check_quotas(&account).await?;
+
+    if let Err(err) = ingest(&account, &sensor, req, idempotency_key.as_ref()).await {
+        // A concurrent request with the same idempotency key may have ingested the report first, failing this request's
+        // transaction when it recorded the key
+        if let Some(idempotency_key) = &idempotency_key
+            && let Ok(Some(ingested_at)) = idempotency::ingested_at(&account, idempotency_key).await
+        {
+            info!("Report was concurrently ingested with the same idempotency key");
+
+            return Ok(Json(ReportResponse {
+                consistency_token: ConsistencyToken::issue_for(ingested_at),
+            }));
+        }
+
+        dead_letter::record(&account, &sensor, &headers, &body, &err).await;
+
+        return Err(err);
+    }
+
+    Ok(Json(ReportResponse {
+        consistency_token: ConsistencyToken::issue(),
+    }))
+}
+
+// Agents with captures too large for a single `/report` request, and its single transaction, stream them as NDJSON
+// instead. Each line is a report in the `/report` format and is ingested in its own transaction, so agents bound the
+// size of each transaction by how they split captures across lines. Ingestion continues after a line fails so that
+// agents can resend only the failed lines. Lines are limited to `Limit::ReportBatchLineBytes`, and larger lines fail
+// like any other line. Requests are limited to `Limit::ReportBatchLines` lines, and quotas are re-checked every
+// `QUOTA_CHECK_BATCH_LINES` lines, so a single request can't ingest without bound. Either stops ingestion with an
+// error, and the lines before it remain ingested.
+
+const QUOTA_CHECK_BATCH_LINES: usize = 100;
+
+#[derive(Debug, JsonSchema, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ReportBatchStatus {
+    Ingested,
+    Failed,
+}
+
+#[derive(Debug, JsonSchema, Serialize)]
+pub(crate) struct ReportBatchResult {
+    index: usize,
+    status: ReportBatchStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct ReportBatchResponse {
+    batches: Vec<ReportBatchResult>,
+    // Reflects every ingested line, see `ReportResponse`
+    consistency_token: String,
+}
+
+async fn ingest_batch(
+    account: &Account,
+    sensor: &Sensor,
+    index: usize,
+    line: &[u8],
+) -> ReportBatchResult {
+    let result = match Request::from_slice(line, "report") {
+        Ok(req) => ingest(account, sensor, req, None)
+            .await
+            .map_err(|err| err.to_string()),
+        Err(err) => Err(err.to_string()),
+    };
+
+    match result {
+        Ok(()) => ReportBatchResult {
+            index,
+            status: ReportBatchStatus::Ingested,
+            error: None,
+        },
+        Err(error) => ReportBatchResult {
+            index,
+            status: ReportBatchStatus::Failed,
+            error: Some(error),
+        },
+    }
+}
+
+fn oversized_batch(index: usize) -> ReportBatchResult {
+    ReportBatchResult {
+        index,
+        status: ReportBatchStatus::Failed,
+        error: Some(
+            Limit::ReportBatchLineBytes
+                .exceeded_by(&format!("Report batch line {index} size in bytes"), None)
+                .to_string(),
+        ),
+    }
+}
+
+// Checks that the line at `index` may be ingested before it counts toward the batch
+async fn check_batch_line(account: &Account, index: usize) -> Result<()> {
+    if index >= Limit::ReportBatchLines.max() {
+        return Err(Limit::ReportBatchLines.exceeded(None));
+    }
+
+    if index > 0 && index % QUOTA_CHECK_BATCH_LINES == 0 {
+        usage::check_quotas(account).await?;
+    }
+
+    Ok(())
+}
+
+#[instrument(err, skip_all, fields(account_id = account.id()))]
+pub(crate) async fn report_batch(
+    Extension(auth): Extension<ReportApiKeyAuth>,
     Extension(account): Extension<Account>,
-    Json(req): Json<Request>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Json<ReportBatchResponse>> {
+    let sensor = Sensor::from_headers(&account, &headers, auth.key_id()).await?;
+
+    usage::check_quotas(&account).await?;
+
+    let mut stream = body.into_data_stream();
+    let mut buffer = Vec::new();
+    // Bytes at the start of the buffer already searched for a newline
+    let mut scanned = 0;
+    // Set while the rest of a line exceeding the limit is discarded
+    let mut oversized = false;
+    let mut batches = Vec::new();
+    let mut index = 0;
+
+    loop {
+        let chunk = stream
+            .next()
+            .await
+            .transpose()
+            .context("Failed to read report batch body")?;
+
+        if let Some(chunk) = &chunk {
+            buffer.extend_from_slice(chunk);
+        }
+
+        // Ingest every complete line in the buffer. Once the body ends, the remainder is the last line.
+        let mut start = 0;
+        while let Some(newline) = buffer[scanned..].iter().position(|byte| *byte == b'\n') {
+            let line = buffer[start..scanned + newline].trim_ascii();
+            start = scanned + newline + 1;
+            scanned = start;
+
+            if oversized {
+                oversized = false;
+            } else if line.len() > Limit::ReportBatchLineBytes.max() {
+                check_batch_line(&account, index).await?;
+                batches.push(oversized_batch(index));
+                index += 1;
+            } else if !line.is_empty() {
+                check_batch_line(&account, index).await?;
+                batches.push(ingest_batch(&account, &sensor, index, line).await);
+                index += 1;
+            }
+        }
+        buffer.drain(..start);
+        scanned = buffer.len();
+
+        // The rest of the line has not been received yet, so its size is unknown. The line fails, and ingestion resumes
+        // with the next line.
+        if !oversized && buffer.len() > Limit::ReportBatchLineBytes.max() {
+            check_batch_line(&account, index).await?;
+            batches.push(oversized_batch(index));
+            index += 1;
+            oversized = true;
+        }
+
+        if oversized {
+            buffer.clear();
+            scanned = 0;
+        }
+
+        if chunk.is_none() {
+            break;
+        }
+    }
+
+    let line = buffer.trim_ascii();
+    if !oversized && !line.is_empty() {
+        check_batch_line(&account, index).await?;
+        batches.push(ingest_batch(&account, &sensor, index, line).await);
+    }
+
+    let failed_batches = batches
+        .iter()
+        .filter(|batch| matches!(batch.status, ReportBatchStatus::Failed))
+        .count();
+
+    info!(
+        ingested_batches = batches.len() - failed_batches,
+        failed_batches, "Ingested report batch"
+    );
+
+    Ok(Json(ReportBatchResponse {
+        batches,
+        consistency_token: ConsistencyToken::issue(),
+    }))
+}
+
+// Agents validate reports with `POST /report/validate` without ingesting them, e.g. while developing a new capture. The
+// report is validated as `/report` validates it and processed by the account's ingestion pipeline, then the resources
+// and event relations it would create, update, or remove are returned. Removals of resources and event relations that
+// were never recorded are omitted, and the resources contained by removed resources aren't listed. Nothing is written,
+// so sequence numbers aren't checked, as regressions are recorded when they are.
+
+#[derive(Debug, JsonSchema, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ReportValidationChange {
+    Create,
+    Update,
+    Remove,
+}
+
+impl ReportValidationChange {
+    fn new(exists: bool, removed: bool) -> Option<Self> {
+        match (exists, removed) {
+            (false, false) => Some(Self::Create),
+            (true, false) => Some(Self::Update),
+            (true, true) => Some(Self::Remove),
+            (false, true) => None,
+        }
+    }
+}
+
+#[derive(Debug, JsonSchema, Serialize)]
+pub(crate) struct ValidatedResource {
+    id: ResourceId,
+    change: ReportValidationChange,
+}
+
+#[derive(Debug, JsonSchema, Serialize)]
+pub(crate) struct ValidatedEvent {
+    principal: ResourceId,
+    resource: ResourceId,
+    r#type: String,
+    change: ReportValidationChange,
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct ReportValidationResponse {
+    resources: Vec<ValidatedResource>,
+    events: Vec<ValidatedEvent>,
+}
+
+// IDs of the resources of resource trees, following the same hierarchy rules as `upsert_resource_tree`, in report order
+// without duplicates. Trees must already be validated by `validate_report`.
+fn resource_tree_ids(resource_captures: &[ResourceTreeNode]) -> Vec<ResourceId> {
+    let mut stack = resource_captures
+        .iter()
+        .rev()
+        .map(|resource_tree_node| (Vec::new(), resource_tree_node))
+        .collect::<Vec<_>>();
+    let mut seen = HashSet::new();
+    let mut resource_ids = Vec::new();
+
+    while let Some((parent_id, resource_tree_node)) = stack.pop() {
+        let mut resource_id = match resource_tree_node.globally_unique {
+            Some(true) => Vec::new(),
+            _ => parent_id,
+        };
+
+        resource_id.push(resource_tree_node.id.clone());
+
+        for child in resource_tree_node.contains.iter().flatten().rev() {
+            stack.push((resource_id.clone(), child));
+        }
+
+        let resource_id = resource_id.into_iter().collect::<ResourceId>();
+        if seen.insert(resource_id.clone()) {
+            resource_ids.push(resource_id);
+        }
+    }
+
+    resource_ids
+}
+
+#[instrument(err, skip(account, body), fields(account_id = account.id()))]
+pub(crate) async fn validate(
+    Extension(account): Extension<Account>,
+    body: Bytes,
+) -> Result<Json<ReportValidationResponse>> {
+    let mut req = Request::from_slice(&body, "report")?;
+
+    validate_report(&req)?;
+
+    IngestionPipeline::new(account.ingestion_pipeline()).run(&mut req);
+
+    // Resources and event relations with whether they are removed
+    let mut resources = resource_tree_ids(&req.resource_captures)
+        .into_iter()
+        .map(|resource_id| (resource_id, false))
+        .collect::<Vec<_>>();
+    resources.extend(
+        req.removed
+            .iter()
+            .cloned()
+            .map(|resource_id| (resource_id, true)),
+    );
+
+    let mut seen = HashSet::new();
+    let mut events = Vec::new();
+    for capture in &req.event_captures {
+        for principal in &capture.principals {
+            for resource in &capture.resources {
+                for event in &capture.events {
+                    let event = (principal.id.clone(), resource.clone(), event.r#type.clone());
+                    if seen.insert(event.clone()) {
+                        events.push((event, false));
+                    }
+                }
+            }
+        }
+    }
+    events.extend(req.removed_events.iter().map(|event| {
+        (
+            (
+                event.principal.clone(),
+                event.resource.clone(),
+                event.r#type.clone(),
+            ),
+            true,
+        )
+    }));
+
+    let mut res = account
+        .resources_db()
+        .await?
+        .query("RETURN $resources.map(|$resource| record::exists($resource));")
+        .query(format!("RETURN $events.map(|$event| !array::is_empty(SELECT VALUE id FROM event WHERE in == $event.principal AND out == $event.resource AND type == $event.type LIMIT 1 {}));", statement_timeout()))
+        .bind((
+            "resources",
+            resources
+                .iter()
+                .map(|(resource_id, _)| surrealdb_thing_from_resource_id(resource_id.clone()))
+                .collect::<Vec<_>>(),
+        ))
+        .bind((
+            "events",
+            events
+                .iter()
+                .map(|((principal, resource, r#type), _)| {
+                    surrealdb::sql::Value::from(surrealdb::sql::Object::from(HashMap::from([
+                        ("principal", surrealdb_thing_from_resource_id(principal.clone())),
+                        ("resource", surrealdb_thing_from_resource_id(resource.clone())),
+                        ("type", r#type.as_str().into()),
+                    ])))
+                })
+                .collect::<Vec<_>>(),
+        ))
+        .timeout()
+        .await
+        .context("Failed to submit query to resolve report resources and events")?
+        .check_first_real_error()
+        .context("Failed to resolve report resources and events")?;
+
+    let resources_exist = res.take::<Option<Vec<bool>>>(0)?.unwrap_or_default();
+    let events_exist = res.take::<Option<Vec<bool>>>(1)?.unwrap_or_default();
+
+    let resources = resources
+        .into_iter()
+        .zip(resources_exist)
+        .filter_map(|((id, removed), exists)| {
+            Some(ValidatedResource {
+                id,
+                change: ReportValidationChange::new(exists, removed)?,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let events = events
+        .into_iter()
+        .zip(events_exist)
+        .filter_map(|(((principal, resource, r#type), removed), exists)| {
+            Some(ValidatedEvent {
+                principal,
+                resource,
+                r#type,
+                change: ReportValidationChange::new(exists, removed)?,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    info!(
+        resources = resources.len(),
+        events = events.len(),
+        "Validated report"
+    );
+
+    Ok(Json(ReportValidationResponse { resources, events }))
+}
+
+// Validates the depth and size of a resource tree and collects the IDs of its resources, following the same hierarchy
+// rules as `upsert_resource_tree`. Trees are walked with an explicit work stack, and are validated before a report is
+// processed any further, so later processing of the tree is bounded. Returns the limit the tree exceeds, if any.
+fn collect_resource_ids(
+    resource_tree_node: &ResourceTreeNode,
+    resource_ids: &mut Vec<surrealdb::sql::Value>,
+) -> std::result::Result<(), Limit> {
+    let max_depth = Limit::ResourceTreeDepth.max();
+    let max_nodes = Limit::ResourceTreeNodes.max();
+
+    let mut stack = vec![(surrealdb::sql::Array::new(), 1, resource_tree_node)];
+    let mut tree_depth = 0;
+
+    while let Some((parent_id, depth, resource_tree_node)) = stack.pop() {
+        if depth > max_depth {
+            return Err(Limit::ResourceTreeDepth);
+        }
+
+        if resource_ids.len() >= max_nodes {
+            return Err(Limit::ResourceTreeNodes);
+        }
+
+        tree_depth = tree_depth.max(depth);
+
+        let mut resource_id = match resource_tree_node.globally_unique {
+            Some(true) => surrealdb::sql::Array::new(),
+            _ => parent_id,
+        };
+
+        resource_id.push(resource_tree_node.id.clone().into());
+
+        for child in resource_tree_node.contains.iter().flatten() {
+            stack.push((resource_id.clone(), depth + 1, child));
+        }
+
+        resource_ids.push(
+            surrealdb::sql::Thing::from(("resource", surrealdb::sql::Id::from(resource_id))).into(),
+        );
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    REPORT_RESOURCE_TREE_DEPTH.observe(tree_depth as f64);
+
+    Ok(())
+}
+
+// Checks the secret hashes declared by the nodes of resource trees, see `secret_hash`, and the sizes of their attribute
+// values
+fn validate_resource_tree_nodes(resource_captures: &[ResourceTreeNode]) -> Result<()> {
+    let max_attribute_bytes = Limit::AttributeBytes.max();
+    let mut stack = resource_captures.iter().collect::<Vec<_>>();
+
+    while let Some(resource_tree_node) = stack.pop() {
+        if let Some(secret_hash) = &resource_tree_node.secret_hash {
+            secret_hash.validate(&resource_tree_node.id)?;
+        }
+
+        for (name, value) in resource_tree_node.attributes.iter().flatten() {
+            let bytes = value.to_string().len();
+
+            if bytes > max_attribute_bytes {
+                return Err(Limit::AttributeBytes.exceeded_by(
+                    &format!(
+                        "Size of attribute {name:?} of resource {:?}",
+                        resource_tree_node.id.id
+                    ),
+                    Some(bytes),
+                ));
+            }
+        }
+
+        stack.extend(resource_tree_node.contains.iter().flatten());
+    }
+
+    Ok(())
+}
+
+// Measures the resource trees of a report that exceeds a resource tree limit, so the error can include the value of the
+// report. Unlike `collect_resource_ids` this only walks the trees, which the size of the request body already bounds.
+fn measure_resource_trees(resource_captures: &[ResourceTreeNode], limit: Limit) -> usize {
+    let mut stack = resource_captures
+        .iter()
+        .map(|resource_tree_node| (1, resource_tree_node))
+        .collect::<Vec<_>>();
+    let mut max_depth = 0;
+    let mut nodes = 0;
+
+    while let Some((depth, resource_tree_node)) = stack.pop() {
+        max_depth = max_depth.max(depth);
+        nodes += 1;
+
+        for child in resource_tree_node.contains.iter().flatten() {
+            stack.push((depth + 1, child));
+        }
+    }
+
+    match limit {
+        Limit::ResourceTreeDepth => max_depth,
+        _ => nodes,
+    }
+}
+
+// Ingests a single report in one transaction. Used for reports sent directly by agents and for reports imported from
+// offline bundles. Failed ingestions are metered so account health can report error rates. The idempotency key, if any,
+// is recorded in the same transaction.
+#[instrument(err, skip(account, req), fields(account_id = account.id()))]
+pub(crate) async fn ingest(
+    account: &Account,
+    sensor: &Sensor,
+    req: Request,
+    idempotency_key: Option<&IdempotencyKey>,
+) -> Result<()> {
+    let start = Instant::now();
+    let result = ingest_report(account, sensor, req, idempotency_key).await;
+
+    REPORT_INGESTION_DURATION_SECONDS
+        .with_label_values(&[if result.is_ok() { "ingested" } else { "failed" }])
+        .observe(start.elapsed().as_secs_f64());
+
+    if result.is_err()
+        && let Err(err) = usage::meter_failed_report(account).await
+    {
+        warn!(?err, "Failed to meter failed report");
+    }
+
+    result
+}
+
+// Agents may number the reports they send with a report key, so replayed reports and reports of cloned agents are
+// detected. Each report key tracks the highest sequence number ingested with it. Reports numbered at or below it are
+// rejected and counted as regressions, and skipped numbers are counted as gaps, i.e. reports that were likely lost.
+// Both are reported for each report key by `health`. Concurrent reports may both pass the check, but the highest
+// sequence number is tracked in the ingestion transaction either way.
+async fn check_sequence(account: &Account, report_api_key_id: u32, sequence: u64) -> Result<i64> {
+    let Ok(sequence) = i64::try_from(sequence) else {
+        bad_request!("Report sequence number must be at most {}", i64::MAX);
+    };
+
+    let db = account.resources_db().await?;
+
+    let last_sequence = db
+        .query("(SELECT VALUE last_sequence FROM type::thing('report_api_key', $report_api_key_id))[0]")
+        .bind(("report_api_key_id", report_api_key_id))
+        .timeout()
+        .await
+        .context("Failed to submit query to get report key sequence")?
+        .check_first_real_error()
+        .context("Failed to get report key sequence")?
+        .take::<Option<i64>>(0)?;
+
+    if let Some(last_sequence) = last_sequence
+        && sequence <= last_sequence
+    {
+        warn!(
+            report_api_key_id,
+            sequence, last_sequence, "Rejected report with regressed sequence number"
+        );
+
+        db.query("UPDATE type::thing('report_api_key', $report_api_key_id) SET sequence_regressions += 1, last_sequence_regression_at = time::now() RETURN NONE")
+            .bind(("report_api_key_id", report_api_key_id))
+            .timeout()
+            .await
+            .context("Failed to submit query to record report key sequence regression")?
+            .check_first_real_error()
+            .context("Failed to record report key sequence regression")?;
+
+        coded_error!(
+            ErrorCode::SequenceRegression,
+            "Report sequence number {sequence} is not higher than {last_sequence}, the highest sequence number \
+             ingested with this report key"
+        );
+    }
+
+    Ok(sequence)
+}
+
+// Validates the limits and secret hashes of a report. Returns the IDs of its resources and the number of event
+// relations it records.
+fn validate_report(req: &Request) -> Result<(Vec<surrealdb::sql::Value>, usize)> {
+    // Event relations are seen within the window of their capture's events, which is undefined without events
+    if req
+        .event_captures
+        .iter()
+        .any(|capture| capture.events.is_empty())
+    {
+        bad_request!("Event captures must have at least one event");
+    }
+
+    let mut resource_ids = Vec::new();
+    for resource_tree_node in &req.resource_captures {
+        if let Err(limit) = collect_resource_ids(resource_tree_node, &mut resource_ids) {
+            let observed = measure_resource_trees(&req.resource_captures, limit);
+            return Err(limit.exceeded(Some(observed)));
+        }
+    }
+
+    validate_resource_tree_nodes(&req.resource_captures)?;
+
+    let events = req
+        .event_captures
+        .iter()
+        .map(|capture| capture.events.len())
+        .sum::<usize>();
+    if events > Limit::ReportEvents.max() {
+        return Err(Limit::ReportEvents.exceeded(Some(events)));
+    }
+
+    // Each event capture records an event relation for every combination of its principals, resources, and events
+    let edges = req
+        .event_captures
+        .iter()
+        .map(|capture| {
+            capture
+                .principals
+                .len()
+                .saturating_mul(capture.resources.len())
+                .saturating_mul(capture.events.len())
+        })
+        .fold(0, usize::saturating_add);
+    if edges > Limit::ReportEdges.max() {
+        return Err(Limit::ReportEdges.exceeded(Some(edges)));
+    }
+
+    if req.removed.len() > Limit::ResourceTreeNodes.max() {
+        return Err(Limit::ResourceTreeNodes.exceeded_by(
+            "Number of removed resources in report",
+            Some(req.removed.len()),
+        ));
+    }
+
+    if req.removed_events.len() > Limit::ReportEvents.max() {
+        return Err(Limit::ReportEvents.exceeded_by(
+            "Number of removed events in report",
+            Some(req.removed_events.len()),
+        ));
+    }
+
+    // Removing the root resource would mark every resource gone
+    if req.removed.iter().any(|resource_id| resource_id.is_empty()) {
+        bad_request!("The root resource cannot be removed");
+    }
+
+    Ok((resource_ids, edges))
+}
+
+async fn ingest_report(
+    account: &Account,
+    sensor: &Sensor,
+    mut req: Request,
+    idempotency_key: Option<&IdempotencyKey>,
 ) -> Result<()> {
+    let (resource_ids, edges) = validate_report(&req)?;
+
+    // Only reports sent with report keys are numbered
+    let sequence = match (req.sequence, sensor.report_api_key_id()) {
+        (Some(sequence), Some(report_api_key_id)) => {
+            Some(check_sequence(account, report_api_key_id, sequence).await?)
+        }
+        _ => None,
+    };
+
+    IngestionPipeline::new(account.ingestion_pipeline()).run(&mut req);
+
     let db = account.resources_db().await?;
 
+    let resources_ingested = resource_ids.len() as u64;
+    let events_recorded = edges as u64;
+
+    let event_types = req
+        .event_captures
+        .iter()
+        .flat_map(|capture| capture.events.iter().map(|event| event.r#type.clone()))
+        .collect::<Vec<_>>();
+
     let mut query = db.query(BeginStatement::default());
 
+    query =
+        webhooks::detect_new_resources_and_event_types(query, resource_ids, event_types.clone());
+
+    let sensor_id = sensor.id();
+
     for resource_tree_node in req.resource_captures {
-        query =
-            upsert_resource_tree_node(query, &mut surrealdb::sql::Array::new(), resource_tree_node);
+        query = upsert_resource_tree(query, resource_tree_node, &sensor_id);
     }
 
     for events_report in req.event_captures {
-        query = upsert_events(query, events_report);
+        query = upsert_events(query, events_report, &sensor_id);
+    }
+
+    // Removals are applied after upserts, so resources both reported and removed are marked gone
+    query = remove_resources(query, req.removed);
+    query = remove_events(query, req.removed_events);
+
+    query = usage::meter_report(query, resources_ingested, events_recorded);
+
+    // Gaps are counted against the highest sequence number before it is updated
+    if let Some(report_api_key_id) = sensor.report_api_key_id() {
+        query = query
+            .query("UPDATE type::thing('report_api_key', $report_api_key_id) SET last_report_at = time::now(), sequence_gaps += IF $report_sequence IS NOT NONE AND last_sequence IS NOT NONE AND $report_sequence > last_sequence + 1 THEN $report_sequence - last_sequence - 1 ELSE 0 END, last_sequence = IF $report_sequence IS NOT NONE AND (last_sequence IS NONE OR $report_sequence > last_sequence) THEN $report_sequence ELSE last_sequence END RETURN NONE;")
+            .bind(("report_api_key_id", report_api_key_id))
+            .bind(("report_sequence", sequence));
+    }
+
+    query = webhooks::queue_notifications(query);
+
+    if let Some(idempotency_key) = idempotency_key {
+        query = idempotency::record(query, idempotency_key);
     }
 
     query = query.query(CommitStatement::default());
 
+    // Bounds the transaction, as the statements of each resource, event relation, and removal add up
+    if query.statements() > Limit::ReportStatements.max() {
+        return Err(Limit::ReportStatements.exceeded(Some(query.statements())));
+    }
+
     info!("Full query:\n{query:?}");
 
-    query.await?.check_first_real_error()?;
+    // The transaction is not timed out client side, as SurrealDB would still commit it after the request stopped
+    // waiting, and the sensor would retry a report that was ingested
+    query
+        .await
+        .map_err(|err| storage_throttled_error(account, err))?
+        .check_first_real_error()
+        .map_err(|err| storage_throttled_error(account, err))?;
+
+    let mut event_types = event_types;
+    event_types.sort_unstable();
+    event_types.dedup();
+
+    graph_stream::publish(
+        account,
+        &db,
+        GraphUpdate {
+            resources: resources_ingested,
+            events: events_recorded,
+            event_types,
+            consistency_token: ConsistencyToken::issue(),
+        },
+    )
+    .await;
 
     Ok(())
 }
+
+// Current report schema version. Registered agents sending an older version are flagged as outdated.
+pub(crate) const REPORT_SCHEMA_VERSION: u32 = 2;
+
+// Serves the JSON Schema of a report request format so agent developers can validate payloads before sending them.
+// Every format of `VersionedRequest` must remain served here after new versions are introduced.
+#[instrument(err)]
+pub(crate) async fn schema(Path(version): Path<u32>) -> Result<Json<schemars::Schema>> {
+    match version {
+        1 => Ok(Json(schemars::schema_for!(RequestV1))),
+        2 => Ok(Json(schemars::schema_for!(RequestV2))),
+        _ => not_found!("Unknown report schema version {version}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Request, validate_report};
+
+    #[test]
+    fn schema_versions() {
+        let v1 = br#"{"resource_captures": [], "event_captures": []}"#;
+        assert!(Request::from_slice(v1, "report").is_ok());
+
+        // Version 1 is frozen, so fields introduced by later versions are rejected
+        let v1_with_removals =
+            br#"{"schema_version": 1, "resource_captures": [], "event_captures": [], "removed": []}"#;
+        assert!(Request::from_slice(v1_with_removals, "report").is_err());
+        let v1_with_sequence =
+            br#"{"schema_version": 1, "sequence": 1, "resource_captures": [], "event_captures": []}"#;
+        assert!(Request::from_slice(v1_with_sequence, "report").is_err());
+
+        let v2 = br#"{
+            "schema_version": 2,
+            "sequence": 7,
+            "resource_captures": [],
+            "event_captures": [],
+            "removed": [[{"type": "AWS Partition", "id": "aws"}]]
+        }"#;
+        let req = Request::from_slice(v2, "report").unwrap();
+        assert_eq!(req.sequence, Some(7));
+        assert_eq!(req.removed.len(), 1);
+        assert!(req.removed_events.is_empty());
+
+        let unsupported =
+            br#"{"schema_version": 3, "resource_captures": [], "event_captures": []}"#;
+        assert!(Request::from_slice(unsupported, "report").is_err());
+    }
+
+    #[test]
+    fn empty_event_captures() {
+        let req = Request::from_slice(
+            br#"{"resource_captures": [], "event_captures": [{"principals": [], "resources": [], "events": []}]}"#,
+            "report",
+        )
+        .unwrap();
+
+        assert!(validate_report(&req).is_err());
+    }
+}
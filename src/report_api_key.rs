@@ -10,12 +10,45 @@ use base64::prelude::*;
 use chrono::{DateTime, Utc};
 use prost::Message;
 use rand::Rng;
+use ring::{aead::AES_128_GCM, hkdf};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 use archodex_error::anyhow::{self, Context as _, anyhow, bail, ensure};
 use tracing::instrument;
 
-use crate::{env::Env, next_binding, surrealdb_deserializers, user::User};
+use crate::{
+    db::{DBConnection, InstrumentedQuery},
+    env::Env,
+    next_binding, surrealdb_deserializers,
+    user::User,
+};
+
+// Report key values are encrypted with a key derived for their account, so a compromised account key can only be used
+// to forge values carrying that account's salt, which are only accepted for that account. Values issued before keys
+// were derived per account are encrypted with the API private key itself and remain valid. Bump the key version when
+// changing how keys are derived.
+const REPORT_API_KEY_KEY_VERSION: u32 = 1;
+const REPORT_API_KEY_DERIVATION_INFO: &[u8] = b"archodex report key value";
+
+async fn report_api_key_cipher(key_version: u32, account_salt: &[u8]) -> anyhow::Result<Aes128Gcm> {
+    let api_private_key = Env::api_private_key().await;
+
+    match key_version {
+        0 => Ok(Aes128Gcm::new(&api_private_key)),
+        REPORT_API_KEY_KEY_VERSION => {
+            let mut account_key = aes_gcm::Key::<Aes128Gcm>::default();
+            hkdf::Salt::new(hkdf::HKDF_SHA256, account_salt)
+                .extract(&api_private_key)
+                .expand(&[REPORT_API_KEY_DERIVATION_INFO], &AES_128_GCM)
+                .and_then(|okm| okm.fill(&mut account_key))
+                .map_err(|_| anyhow!("Failed to derive report key encryption key"))?;
+
+            Ok(Aes128Gcm::new(&account_key))
+        }
+        _ => bail!("Invalid report key value: Unsupported key version {key_version}"),
+    }
+}
 
 #[derive(Clone, Debug, Deserialize)]
 pub(crate) struct ReportApiKey {
@@ -24,26 +57,47 @@ pub(crate) struct ReportApiKey {
     description: Option<String>,
     created_at: Option<DateTime<Utc>>,
     created_by: User,
-    #[allow(dead_code)]
     revoked_at: Option<DateTime<Utc>>,
     #[allow(dead_code)]
     revoked_by: Option<User>,
+    #[serde(default)]
+    hard_revoked: bool,
+    restorable_until: Option<DateTime<Utc>>,
+    expires_at: Option<DateTime<Utc>>,
+    replacement_report_api_key_id: Option<u32>,
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, JsonSchema, Serialize)]
 pub(crate) struct ReportApiKeyPublic {
     #[serde(deserialize_with = "surrealdb_deserializers::u32::deserialize")]
     id: u32,
     description: Option<String>,
     created_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    revoked_at: Option<DateTime<Utc>>,
+    // Time until which a revoked key can be restored, unset once it can no longer be restored
+    #[serde(skip_serializing_if = "Option::is_none")]
+    restorable_until: Option<DateTime<Utc>>,
+    // Time after which values of a rotated key are no longer accepted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<DateTime<Utc>>,
+    // Key that replaced a rotated key
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replacement_report_api_key_id: Option<u32>,
 }
 
 impl From<ReportApiKey> for ReportApiKeyPublic {
     fn from(record: ReportApiKey) -> Self {
+        let restorable_until = record.restorable_until();
+
         Self {
             id: record.id,
             description: record.description,
             created_at: record.created_at,
+            revoked_at: record.revoked_at,
+            restorable_until,
+            expires_at: record.expires_at,
+            replacement_report_api_key_id: record.replacement_report_api_key_id,
         }
     }
 }
@@ -57,6 +111,10 @@ impl ReportApiKey {
             created_by,
             revoked_at: None,
             revoked_by: None,
+            hard_revoked: false,
+            restorable_until: None,
+            expires_at: None,
+            replacement_report_api_key_id: None,
         }
     }
 
@@ -64,31 +122,82 @@ impl ReportApiKey {
         self.id
     }
 
+    pub(crate) fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    pub(crate) fn created_by(&self) -> &User {
+        &self.created_by
+    }
+
+    pub(crate) fn revoked_at(&self) -> Option<DateTime<Utc>> {
+        self.revoked_at
+    }
+
+    // Set once the key was rotated, whether or not it has expired yet
+    pub(crate) fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.expires_at
+    }
+
+    pub(crate) fn hard_revoked(&self) -> bool {
+        self.hard_revoked
+    }
+
+    // Keys that were revoked, but not hard-revoked, can be restored until the restore window that applied when they
+    // were revoked has passed
+    pub(crate) fn restorable_until(&self) -> Option<DateTime<Utc>> {
+        if self.hard_revoked {
+            return None;
+        }
+
+        self.restorable_until
+            .filter(|restorable_until| *restorable_until > Utc::now())
+    }
+
     #[instrument(err)]
     pub(crate) async fn generate_value(
         &self,
         account_id: &str,
         account_salt: Vec<u8>,
     ) -> anyhow::Result<String> {
-        let cipher = Aes128Gcm::new(&Env::api_private_key().await);
         let nonce = Aes128Gcm::generate_nonce(&mut rand::rngs::OsRng);
 
+        Self::value_with_nonce(self.id, account_id, account_salt, &nonce).await
+    }
+
+    // Values are encrypted deterministically for a given nonce, so the value of a key can be derived again from the
+    // nonce it was generated with. Offline bundles identify the value that signed them this way.
+    pub(crate) async fn value_with_nonce(
+        key_id: u32,
+        account_id: &str,
+        account_salt: Vec<u8>,
+        nonce: &[u8],
+    ) -> anyhow::Result<String> {
+        ensure!(
+            nonce.len() == 12,
+            "Report key value nonce is not 12 bytes long"
+        );
+
+        let cipher = report_api_key_cipher(REPORT_API_KEY_KEY_VERSION, &account_salt).await?;
+        let nonce = aead::Nonce::<Aes128Gcm>::from_slice(nonce);
+
         let message = proto::ReportApiKeyEncryptedContents {
             account_id: account_id.parse::<u64>().context("Invalid account ID")?,
         };
 
         let aad = proto::ReportApiKeyEncryptedAad {
-            key_id: self.id,
+            key_id,
             #[cfg(feature = "archodex-com")]
             endpoint: Some(Env::endpoint().to_owned()),
             #[cfg(not(feature = "archodex-com"))]
             endpoint: None,
             account_salt: account_salt.clone(),
+            key_version: REPORT_API_KEY_KEY_VERSION,
         };
 
         let encrypted_account_id = cipher
             .encrypt(
-                &nonce,
+                nonce,
                 aead::Payload {
                     msg: &message.encode_to_vec(),
                     aad: &aad.encode_to_vec(),
@@ -105,21 +214,22 @@ impl ReportApiKey {
             account_salt,
             nonce: nonce.as_slice().to_vec(),
             encrypted_contents: encrypted_account_id,
+            key_version: REPORT_API_KEY_KEY_VERSION,
         };
 
         Ok(format!(
-            "archodex_report_api_key_{}_{}",
-            self.id,
+            "archodex_report_api_key_{key_id}_{}",
             BASE64_STANDARD.encode(report_api_key.encode_to_vec())
         ))
     }
 
-    // This method validates a report key value contains the correct endpoint and returns the account and key IDs. The
-    // caller must still validate the key ID exists for the account and has not been revoked.
+    // This method validates a report key value contains the correct endpoint and returns the account and key IDs, and
+    // the account salt the value was issued with. The caller must still validate the salt is the account's salt, and
+    // that the key ID exists for the account and has not been revoked.
     #[instrument(err, skip_all)]
     pub(crate) async fn validate_value(
         report_api_key_value: &str,
-    ) -> anyhow::Result<(String, u32)> {
+    ) -> anyhow::Result<(String, u32, Vec<u8>)> {
         let Some(key_id) = report_api_key_value.strip_prefix("archodex_report_api_key_") else {
             bail!("Invalid report key value: Missing prefix");
         };
@@ -175,12 +285,13 @@ impl ReportApiKey {
         );
 
         let nonce = aead::Nonce::<Aes128Gcm>::from_slice(&value.nonce);
-        let cipher = Aes128Gcm::new(&Env::api_private_key().await);
+        let cipher = report_api_key_cipher(value.key_version, &value.account_salt).await?;
 
         let aad = proto::ReportApiKeyEncryptedAad {
             key_id,
             endpoint: value.endpoint,
             account_salt: value.account_salt,
+            key_version: value.key_version,
         };
 
         let decrypted_message = cipher
@@ -205,22 +316,34 @@ impl ReportApiKey {
             "Invalid report key value: Account ID is out of range"
         );
 
-        Ok((encrypted_contents.account_id.to_string(), key_id))
+        Ok((
+            encrypted_contents.account_id.to_string(),
+            key_id,
+            aad.account_salt,
+        ))
     }
 }
 
-pub(crate) trait ReportApiKeyQueries<'r, C: surrealdb::Connection> {
-    fn list_report_api_keys_query(&'r self) -> surrealdb::method::Query<'r, C>;
+pub(crate) const ACTIVE_REPORT_API_KEY_CONDITION: &str =
+    "type::is::none(revoked_at) AND (expires_at IS NONE OR expires_at > time::now())";
+
+pub(crate) trait ReportApiKeyQueries<'r> {
+    fn list_report_api_keys_query(&'r self) -> InstrumentedQuery<'r>;
+    fn list_revoked_report_api_keys_query(&'r self) -> InstrumentedQuery<'r>;
     fn create_report_api_key_query(
         &'r self,
         report_api_key: &ReportApiKey,
-    ) -> surrealdb::method::Query<'r, C>;
+        value_reveal_token_hash: String,
+        value_reveal_expires_at: DateTime<Utc>,
+    ) -> InstrumentedQuery<'r>;
     fn revoke_report_api_key_query(
         &'r self,
         report_api_key_id: u32,
-        revoked_by: &User,
-    ) -> surrealdb::method::Query<'r, C>;
-    fn report_api_key_is_valid_query(&'r self, id: u32) -> surrealdb::method::Query<'r, C>;
+        revoked_by: Option<&User>,
+        hard: bool,
+        restore_window_days: u32,
+    ) -> InstrumentedQuery<'r>;
+    fn report_api_key_is_valid_query(&'r self, id: u32) -> InstrumentedQuery<'r>;
     type ReportApiKeyIsValidQueryResponse;
 }
 
@@ -235,55 +358,94 @@ impl ReportApiKeyIsValidQueryResponse {
     }
 }
 
-impl<'r, C: surrealdb::Connection> ReportApiKeyQueries<'r, C> for surrealdb::Surreal<C> {
-    fn list_report_api_keys_query(&'r self) -> surrealdb::method::Query<'r, C> {
-        self.query("SELECT * FROM report_api_key WHERE type::is::none(revoked_at)")
+impl<'r> ReportApiKeyQueries<'r> for DBConnection {
+    // Rotated keys are active until they expire
+    fn list_report_api_keys_query(&'r self) -> InstrumentedQuery<'r> {
+        self.query(format!(
+            "SELECT * FROM report_api_key WHERE {ACTIVE_REPORT_API_KEY_CONDITION}"
+        ))
     }
 
+    // Includes rotated keys that expired, ordered by when they expired
+    fn list_revoked_report_api_keys_query(&'r self) -> InstrumentedQuery<'r> {
+        self.query(format!(
+            "SELECT *, revoked_at ?? expires_at AS inactive_since FROM report_api_key WHERE !({ACTIVE_REPORT_API_KEY_CONDITION}) ORDER BY inactive_since DESC"
+        ))
+    }
+
+    // The key value is not stored. It is revealed once to the creator of the key in exchange for the value reveal token
+    // whose hash is stored here.
     fn create_report_api_key_query(
         &'r self,
         report_api_key: &ReportApiKey,
-    ) -> surrealdb::method::Query<'r, C> {
+        value_reveal_token_hash: String,
+        value_reveal_expires_at: DateTime<Utc>,
+    ) -> InstrumentedQuery<'r> {
         let report_api_key_binding = next_binding();
         let description_binding = next_binding();
         let created_by_binding = next_binding();
+        let value_reveal_token_hash_binding = next_binding();
+        let value_reveal_expires_at_binding = next_binding();
 
         self
-            .query(format!("CREATE ${report_api_key_binding} CONTENT {{ description: ${description_binding}, created_by: ${created_by_binding} }}"))
+            .query(format!("CREATE ${report_api_key_binding} CONTENT {{ description: ${description_binding}, created_by: ${created_by_binding}, value_reveal_token_hash: ${value_reveal_token_hash_binding}, value_reveal_expires_at: ${value_reveal_expires_at_binding} }}"))
             .bind((report_api_key_binding, surrealdb::sql::Thing::from(report_api_key)))
             .bind((description_binding, report_api_key.description.clone()))
             .bind((created_by_binding, surrealdb::sql::Thing::from(&report_api_key.created_by)))
+            .bind((value_reveal_token_hash_binding, value_reveal_token_hash))
+            .bind((value_reveal_expires_at_binding, surrealdb::sql::Datetime::from(value_reveal_expires_at)))
     }
 
+    // Hard revocation also applies to keys that are already revoked, so they can no longer be restored. Keys revoked by
+    // operators have no `revoked_by` user. The restore deadline is set when the key is revoked, so later changes to the
+    // account's restore window don't affect keys that are already revoked.
     fn revoke_report_api_key_query(
         &'r self,
         report_api_key_id: u32,
-        revoked_by: &User,
-    ) -> surrealdb::method::Query<'r, C> {
+        revoked_by: Option<&User>,
+        hard: bool,
+        restore_window_days: u32,
+    ) -> InstrumentedQuery<'r> {
         let report_api_key_binding = next_binding();
         let revoked_by_binding = next_binding();
+        let restorable_until_binding = next_binding();
 
-        self.query(
-            format!("UPDATE ${report_api_key_binding} SET revoked_at = time::now(), revoked_by = ${revoked_by_binding} WHERE revoked_at IS NONE"),
-        )
-        .bind((
-            report_api_key_binding,
-            surrealdb::sql::Thing::from((
-                "report_api_key",
-                surrealdb::sql::Id::from(i64::from(report_api_key_id)),
-            )),
-        ))
-        .bind((revoked_by_binding, surrealdb::sql::Thing::from(revoked_by)))
+        let query = if hard {
+            format!(
+                "UPDATE ${report_api_key_binding} SET revoked_at = revoked_at ?? time::now(), revoked_by = revoked_by ?? ${revoked_by_binding}, hard_revoked = true, restorable_until = NONE WHERE hard_revoked != true"
+            )
+        } else {
+            format!(
+                "UPDATE ${report_api_key_binding} SET revoked_at = time::now(), revoked_by = ${revoked_by_binding}, restorable_until = ${restorable_until_binding} WHERE revoked_at IS NONE"
+            )
+        };
+
+        let restorable_until = (!hard && restore_window_days > 0).then(|| {
+            surrealdb::sql::Datetime::from(
+                Utc::now() + chrono::Duration::days(restore_window_days.into()),
+            )
+        });
+
+        self.query(query)
+            .bind((
+                report_api_key_binding,
+                surrealdb::sql::Thing::from((
+                    "report_api_key",
+                    surrealdb::sql::Id::from(i64::from(report_api_key_id)),
+                )),
+            ))
+            .bind((
+                revoked_by_binding,
+                revoked_by.map(surrealdb::sql::Thing::from),
+            ))
+            .bind((restorable_until_binding, restorable_until))
     }
 
-    fn report_api_key_is_valid_query(
-        &'r self,
-        report_api_key_id: u32,
-    ) -> surrealdb::method::Query<'r, C> {
+    fn report_api_key_is_valid_query(&'r self, report_api_key_id: u32) -> InstrumentedQuery<'r> {
         let report_api_key_binding = next_binding();
 
         self.query(format!(
-            "SELECT type::is::none(revoked_at) AS valid FROM ${report_api_key_binding}"
+            "SELECT {ACTIVE_REPORT_API_KEY_CONDITION} AS valid FROM ${report_api_key_binding}"
         ))
         .bind((
             report_api_key_binding,
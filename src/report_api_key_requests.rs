@@ -0,0 +1,333 @@
+// Accounts may require report keys created by account members to be approved by an account admin. While approval is
+// required, creating a report key as a member records a pending `report_api_key_request` instead. Admins approve or
+// reject pending requests. Approving a request creates its report key on behalf of the requester, who may then retrieve
+// the key value exactly once.
+
+use std::collections::HashMap;
+
+use axum::{Extension, Json, extract::Path};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use surrealdb::{
+    Uuid,
+    sql::statements::{BeginStatement, CommitStatement},
+};
+use tracing::{info, instrument};
+
+use archodex_error::{
+    anyhow::{Context as _, bail},
+    conflict, not_found,
+};
+
+use crate::{
+    Result,
+    account::Account,
+    auth::DashboardAuth,
+    db::{DBConnection, QueryCheckFirstRealError as _},
+    notifications::{self, NewNotification, NotificationKind},
+    report_api_key::{ReportApiKey, ReportApiKeyPublic},
+    report_api_keys::ensure_report_api_key_entitlement,
+    surrealdb_deserializers,
+    user::User,
+};
+
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ReportApiKeyRequestStatus {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
+pub(crate) struct ReportApiKeyRequest {
+    #[serde(deserialize_with = "surrealdb_deserializers::string::deserialize")]
+    id: String,
+    description: Option<String>,
+    requested_at: DateTime<Utc>,
+    requested_by: User,
+    status: ReportApiKeyRequestStatus,
+    decided_at: Option<DateTime<Utc>>,
+    decided_by: Option<User>,
+    report_api_key_id: Option<u32>,
+    value_retrieved_at: Option<DateTime<Utc>>,
+}
+
+fn report_api_key_request_thing(report_api_key_request_id: &str) -> surrealdb::sql::Thing {
+    surrealdb::sql::Thing::from(("report_api_key_request", report_api_key_request_id))
+}
+
+fn report_api_key_request_id(params: &HashMap<String, String>) -> Result<String> {
+    let Some(report_api_key_request_id) = params.get("report_api_key_request_id") else {
+        bail!("Missing report_api_key_request_id");
+    };
+
+    Ok(report_api_key_request_id.clone())
+}
+
+#[instrument(err, skip(account, db))]
+pub(crate) async fn request_report_api_key(
+    account: &Account,
+    db: &DBConnection,
+    description: Option<String>,
+    requested_by: &User,
+) -> Result<ReportApiKeyRequest> {
+    let report_api_key_request = db
+        .query("CREATE $report_api_key_request CONTENT { description: $description, requested_by: $requested_by }")
+        .bind((
+            "report_api_key_request",
+            report_api_key_request_thing(&Uuid::now_v7().to_string()),
+        ))
+        .bind(("description", description))
+        .bind(("requested_by", surrealdb::sql::Thing::from(requested_by)))
+        .await
+        .context("Failed to submit query to create report key request")?
+        .check_first_real_error()
+        .context("Failed to create report key request")?
+        .take::<Option<ReportApiKeyRequest>>(0)?
+        .expect("Create report key request query should return a report key request instance");
+
+    info!(
+        report_api_key_request_id = report_api_key_request.id,
+        "Report key request is pending admin approval"
+    );
+
+    notifications::notify_account_members(
+        account,
+        NewNotification {
+            kind: NotificationKind::ReportApiKeyRequested,
+            message: "A report key was requested and is pending admin approval".to_string(),
+            details: HashMap::from([(
+                "report_api_key_request_id".to_string(),
+                report_api_key_request.id.clone(),
+            )]),
+        },
+    )
+    .await;
+
+    Ok(report_api_key_request)
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct ListReportApiKeyRequestsResponse {
+    report_api_key_requests: Vec<ReportApiKeyRequest>,
+}
+
+// Admins see the requests of all account members, members only see their own requests
+#[instrument(err, skip_all)]
+pub(crate) async fn list_report_api_key_requests(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+) -> Result<Json<ListReportApiKeyRequestsResponse>> {
+    let requested_by = if auth.is_account_admin(account.id()).await? {
+        None
+    } else {
+        Some(surrealdb::sql::Thing::from(auth.principal()))
+    };
+
+    let report_api_key_requests = account
+        .resources_db()
+        .await?
+        .query("SELECT * FROM report_api_key_request WHERE $requested_by IS NONE OR requested_by == $requested_by ORDER BY id DESC")
+        .bind(("requested_by", requested_by))
+        .await
+        .context("Failed to submit query to list report key requests")?
+        .check_first_real_error()
+        .context("Failed to list report key requests")?
+        .take::<Vec<ReportApiKeyRequest>>(0)?;
+
+    Ok(Json(ListReportApiKeyRequestsResponse {
+        report_api_key_requests,
+    }))
+}
+
+async fn pending_report_api_key_request(
+    db: &DBConnection,
+    report_api_key_request_id: &str,
+) -> Result<ReportApiKeyRequest> {
+    let Some(report_api_key_request) = db
+        .query("SELECT * FROM $report_api_key_request")
+        .bind((
+            "report_api_key_request",
+            report_api_key_request_thing(report_api_key_request_id),
+        ))
+        .await
+        .context("Failed to submit query to get report key request")?
+        .check_first_real_error()
+        .context("Failed to get report key request")?
+        .take::<Option<ReportApiKeyRequest>>(0)?
+    else {
+        not_found!("Report key request not found");
+    };
+
+    if report_api_key_request.status != ReportApiKeyRequestStatus::Pending {
+        conflict!("Report key request has already been decided");
+    }
+
+    Ok(report_api_key_request)
+}
+
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn approve_report_api_key_request(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<ReportApiKeyRequest>> {
+    auth.ensure_account_admin(account.id()).await?;
+
+    let report_api_key_request_id = report_api_key_request_id(&params)?;
+
+    let db = account.resources_db().await?;
+
+    let report_api_key_request =
+        pending_report_api_key_request(&db, &report_api_key_request_id).await?;
+
+    ensure_report_api_key_entitlement(&account, &db).await?;
+
+    let report_api_key = ReportApiKey::new(
+        report_api_key_request.description,
+        report_api_key_request.requested_by,
+    );
+
+    // The request may have been decided by another admin since it was fetched above. SurrealDB returns no results for
+    // `BEGIN` and `COMMIT`, so the updated request is the third result.
+    let report_api_key_request = db
+        .query(BeginStatement::default())
+        .query("IF $report_api_key_request.status != \"pending\" { THROW \"Report key request has already been decided\" };")
+        .query("CREATE $report_api_key CONTENT { description: $description, created_by: $created_by } RETURN NONE;")
+        .query("UPDATE $report_api_key_request SET status = \"approved\", decided_at = time::now(), decided_by = $user, report_api_key_id = $report_api_key_id RETURN AFTER;")
+        .query(CommitStatement::default())
+        .bind((
+            "report_api_key_request",
+            report_api_key_request_thing(&report_api_key_request_id),
+        ))
+        .bind(("report_api_key", surrealdb::sql::Thing::from(&report_api_key)))
+        .bind(("report_api_key_id", report_api_key.id()))
+        .bind(("description", report_api_key.description().map(str::to_owned)))
+        .bind((
+            "created_by",
+            surrealdb::sql::Thing::from(report_api_key.created_by()),
+        ))
+        .bind(("user", surrealdb::sql::Thing::from(auth.principal())))
+        .await
+        .context("Failed to submit query to approve report key request")?
+        .check_first_real_error()
+        .context("Failed to approve report key request")?
+        .take::<Option<ReportApiKeyRequest>>(2)?
+        .expect("Approve report key request query should return a report key request instance");
+
+    info!(
+        report_api_key_request_id,
+        report_api_key_id = report_api_key.id(),
+        "Approved report key request"
+    );
+
+    Ok(Json(report_api_key_request))
+}
+
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn reject_report_api_key_request(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<ReportApiKeyRequest>> {
+    auth.ensure_account_admin(account.id()).await?;
+
+    let report_api_key_request_id = report_api_key_request_id(&params)?;
+
+    let db = account.resources_db().await?;
+
+    pending_report_api_key_request(&db, &report_api_key_request_id).await?;
+
+    let Some(report_api_key_request) = db
+        .query("UPDATE $report_api_key_request SET status = \"rejected\", decided_at = time::now(), decided_by = $user WHERE status == \"pending\" RETURN AFTER")
+        .bind((
+            "report_api_key_request",
+            report_api_key_request_thing(&report_api_key_request_id),
+        ))
+        .bind(("user", surrealdb::sql::Thing::from(auth.principal())))
+        .await
+        .context("Failed to submit query to reject report key request")?
+        .check_first_real_error()
+        .context("Failed to reject report key request")?
+        .take::<Option<ReportApiKeyRequest>>(0)?
+    else {
+        conflict!("Report key request has already been decided");
+    };
+
+    info!(report_api_key_request_id, "Rejected report key request");
+
+    Ok(Json(report_api_key_request))
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct RetrieveReportApiKeyValueResponse {
+    report_api_key: ReportApiKeyPublic,
+    report_api_key_value: String,
+}
+
+// Returns the value of the report key created for an approved request. Only the requester may retrieve the value, and
+// only once.
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn retrieve_report_api_key_value(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<RetrieveReportApiKeyValueResponse>> {
+    let report_api_key_request_id = report_api_key_request_id(&params)?;
+
+    let db = account.resources_db().await?;
+
+    let Some(report_api_key_request) = db
+        .query("UPDATE $report_api_key_request SET value_retrieved_at = time::now() WHERE status == \"approved\" AND requested_by == $user AND value_retrieved_at IS NONE RETURN AFTER")
+        .bind((
+            "report_api_key_request",
+            report_api_key_request_thing(&report_api_key_request_id),
+        ))
+        .bind(("user", surrealdb::sql::Thing::from(auth.principal())))
+        .await
+        .context("Failed to submit query to claim report key value")?
+        .check_first_real_error()
+        .context("Failed to claim report key value")?
+        .take::<Option<ReportApiKeyRequest>>(0)?
+    else {
+        not_found!("No approved report key request with a value to retrieve found");
+    };
+
+    let Some(report_api_key_id) = report_api_key_request.report_api_key_id else {
+        bail!("Approved report key request {report_api_key_request_id} has no report key");
+    };
+
+    let Some(report_api_key) = db
+        .query("SELECT * FROM $report_api_key WHERE revoked_at IS NONE")
+        .bind((
+            "report_api_key",
+            surrealdb::sql::Thing::from((
+                "report_api_key",
+                surrealdb::sql::Id::from(i64::from(report_api_key_id)),
+            )),
+        ))
+        .await
+        .context("Failed to submit query to get report key")?
+        .check_first_real_error()
+        .context("Failed to get report key")?
+        .take::<Option<ReportApiKey>>(0)?
+    else {
+        not_found!("Report key has been revoked");
+    };
+
+    let report_api_key_value = report_api_key
+        .generate_value(account.id(), account.salt().to_owned())
+        .await?;
+
+    info!(
+        report_api_key_request_id,
+        report_api_key_id, "Retrieved value of approved report key"
+    );
+
+    Ok(Json(RetrieveReportApiKeyValueResponse {
+        report_api_key: ReportApiKeyPublic::from(report_api_key),
+        report_api_key_value,
+    }))
+}
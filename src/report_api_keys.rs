@@ -1,20 +1,70 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
 
-use axum::{Extension, Json, extract::Path};
+use axum::{
+    Extension, Json,
+    extract::{Path, Query},
+    http::StatusCode,
+};
+use chrono::{DateTime, Utc};
+use rand::Rng as _;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use surrealdb::sql::statements::{BeginStatement, CommitStatement};
 use tracing::{info, instrument};
 
-use archodex_error::{anyhow::bail, bad_request, not_found};
+use archodex_error::{
+    anyhow::{Context as _, bail},
+    bad_request, conflict, forbidden, not_found,
+};
 
 use crate::{
     Result,
     account::Account,
+    audit::AuditTarget,
     auth::DashboardAuth,
-    db::QueryCheckFirstRealError,
+    db::{DBConnection, QueryCheckFirstRealError},
     report_api_key::{ReportApiKey, ReportApiKeyPublic, ReportApiKeyQueries},
+    report_api_key_requests::{ReportApiKeyRequest, request_report_api_key},
 };
 
-#[derive(Serialize)]
+// How long the creator of a report key has to reveal its value
+pub(crate) const REPORT_API_KEY_VALUE_REVEAL_TTL: Duration = Duration::from_secs(10 * 60);
+
+// How long revoked report keys can be restored, unless the account sets its own window
+pub(crate) const DEFAULT_REPORT_API_KEY_RESTORE_WINDOW_DAYS: u32 = 7;
+
+pub(crate) const MAX_REPORT_API_KEY_RESTORE_WINDOW_DAYS: u32 = 90;
+
+// How long values of a rotated report key remain valid, unless the rotation sets its own grace period
+const DEFAULT_REPORT_API_KEY_ROTATION_GRACE_PERIOD_HOURS: u32 = 24;
+
+const MAX_REPORT_API_KEY_ROTATION_GRACE_PERIOD_HOURS: u32 = 30 * 24;
+
+// The rotated key may have been revoked or rotated since it was fetched, which aborts the transaction. SurrealDB
+// returns no results for the transaction's `BEGIN` and `COMMIT` statements, so results are indexed from the first
+// statement.
+const ROTATE_REPORT_API_KEY_STATEMENTS: [&str; 4] = [
+    "LET $rotated = UPDATE $rotated_report_api_key SET expires_at = time::now() + $grace_period, replacement_report_api_key_id = $report_api_key_id WHERE revoked_at IS NONE AND expires_at IS NONE RETURN AFTER;",
+    "IF array::len($rotated) == 0 { THROW \"Report key was changed while it was being rotated\" };",
+    "CREATE $report_api_key CONTENT { description: $description, created_by: $created_by, value_reveal_token_hash: $value_reveal_token_hash, value_reveal_expires_at: $value_reveal_expires_at };",
+    "RETURN $rotated[0];",
+];
+const ROTATE_REPORT_API_KEY_REPLACEMENT_RESULT: usize = 2;
+const ROTATE_REPORT_API_KEY_ROTATED_RESULT: usize = 3;
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ListReportApiKeysRequest {
+    // Lists revoked keys and expired rotated keys instead of active keys, most recently revoked or expired first
+    #[serde(default)]
+    revoked: bool,
+}
+
+#[derive(JsonSchema, Serialize)]
 pub(crate) struct ListReportApiKeysResponse {
     report_api_keys: Vec<ReportApiKeyPublic>,
 }
@@ -22,30 +72,84 @@ pub(crate) struct ListReportApiKeysResponse {
 #[instrument(err, skip_all)]
 pub(crate) async fn list_report_api_keys(
     Extension(account): Extension<Account>,
+    Query(req): Query<ListReportApiKeysRequest>,
 ) -> Result<Json<ListReportApiKeysResponse>> {
-    let report_api_keys = account
-        .resources_db()
-        .await?
-        .list_report_api_keys_query()
-        .await?
-        .check_first_real_error()?
-        .take::<Vec<ReportApiKey>>(0)?
-        .into_iter()
-        .map(ReportApiKeyPublic::from)
-        .collect();
+    let db = account.resources_db().await?;
+
+    let report_api_keys = if req.revoked {
+        db.list_revoked_report_api_keys_query()
+            .await?
+            .check_first_real_error()?
+            .take::<Vec<ReportApiKey>>(0)?
+            .into_iter()
+            .map(ReportApiKeyPublic::from)
+            .collect()
+    } else {
+        db.list_report_api_keys_query()
+            .await?
+            .check_first_real_error()?
+            .take::<Vec<ReportApiKey>>(0)?
+            .into_iter()
+            .map(ReportApiKeyPublic::from)
+            .collect()
+    };
 
     Ok(Json(ListReportApiKeysResponse { report_api_keys }))
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 pub(crate) struct CreateReportApiKeyRequest {
     description: Option<String>,
 }
 
-#[derive(Serialize)]
-pub(crate) struct CreateReportApiKeyResponse {
-    report_api_key: ReportApiKeyPublic,
-    report_api_key_value: String,
+#[derive(JsonSchema, Serialize)]
+#[serde(untagged)]
+pub(crate) enum CreateReportApiKeyResponse {
+    // The key value is not part of the response, so it never ends up in logs of responses. The creator exchanges the
+    // reveal token for the value exactly once.
+    Created {
+        report_api_key: ReportApiKeyPublic,
+        report_api_key_value_reveal_token: String,
+        report_api_key_value_reveal_expires_at: DateTime<Utc>,
+    },
+    // Returned to account members when the account requires report keys to be approved by an admin
+    PendingApproval {
+        report_api_key_request: ReportApiKeyRequest,
+    },
+}
+
+pub(crate) async fn ensure_report_api_key_entitlement(
+    account: &Account,
+    db: &DBConnection,
+) -> Result<()> {
+    ensure_report_api_keys_entitlement(account, db, 1, &HashSet::new()).await
+}
+
+// Checks that the account may have the given number of additional active keys once the given keys are revoked
+pub(crate) async fn ensure_report_api_keys_entitlement(
+    account: &Account,
+    db: &DBConnection,
+    created: usize,
+    revoked: &HashSet<u32>,
+) -> Result<()> {
+    if let Some(max_report_api_keys) = account.entitlements().max_report_api_keys {
+        let active_report_api_keys = db
+            .list_report_api_keys_query()
+            .await?
+            .check_first_real_error()?
+            .take::<Vec<ReportApiKey>>(0)?
+            .into_iter()
+            .filter(|report_api_key| !revoked.contains(&report_api_key.id()))
+            .count();
+
+        if active_report_api_keys + created > max_report_api_keys as usize {
+            forbidden!(
+                "Account plan allows at most {max_report_api_keys} active report API keys, revoke an existing key or upgrade the plan"
+            );
+        }
+    }
+
+    Ok(())
 }
 
 #[instrument(err, skip(auth, account))]
@@ -54,19 +158,37 @@ pub(crate) async fn create_report_api_key(
     Extension(account): Extension<Account>,
     Path(params): Path<HashMap<String, String>>,
     Json(req): Json<CreateReportApiKeyRequest>,
-) -> Result<Json<CreateReportApiKeyResponse>> {
+) -> Result<(StatusCode, Json<CreateReportApiKeyResponse>)> {
     let Some(account_id) = params.get("account_id") else {
         bail!("Missing account ID");
     };
 
+    let db = account.resources_db().await?;
+
+    if account.report_api_key_approval_required() && !auth.is_account_admin(account_id).await? {
+        let report_api_key_request =
+            request_report_api_key(&account, &db, req.description, auth.principal()).await?;
+
+        return Ok((
+            StatusCode::ACCEPTED,
+            Json(CreateReportApiKeyResponse::PendingApproval {
+                report_api_key_request,
+            }),
+        ));
+    }
+
+    ensure_report_api_key_entitlement(&account, &db).await?;
+
     let report_api_key = ReportApiKey::new(req.description, auth.principal().clone());
-    let report_api_key_value = report_api_key
-        .generate_value(account_id, account.salt().to_owned())
-        .await?;
 
-    let db = account.resources_db().await?;
+    let report_api_key_value_reveal_token = new_value_reveal_token();
+    let report_api_key_value_reveal_expires_at = Utc::now() + REPORT_API_KEY_VALUE_REVEAL_TTL;
 
-    let query = db.create_report_api_key_query(&report_api_key);
+    let query = db.create_report_api_key_query(
+        &report_api_key,
+        value_reveal_token_hash(&report_api_key_value_reveal_token),
+        report_api_key_value_reveal_expires_at,
+    );
 
     let report_api_key = query
         .await?
@@ -79,18 +201,86 @@ pub(crate) async fn create_report_api_key(
         "Created Report API Key"
     );
 
-    Ok(Json(CreateReportApiKeyResponse {
-        report_api_key: ReportApiKeyPublic::from(report_api_key),
-        report_api_key_value,
-    }))
+    Ok((
+        StatusCode::OK,
+        Json(CreateReportApiKeyResponse::Created {
+            report_api_key: ReportApiKeyPublic::from(report_api_key),
+            report_api_key_value_reveal_token,
+            report_api_key_value_reveal_expires_at,
+        }),
+    ))
 }
 
-#[instrument(err, skip(auth, account))]
-pub(crate) async fn revoke_report_api_key(
+pub(crate) fn new_value_reveal_token() -> String {
+    hex::encode(rand::thread_rng().r#gen::<[u8; 32]>())
+}
+
+pub(crate) fn value_reveal_token_hash(value_reveal_token: &str) -> String {
+    hex::encode(Sha256::digest(value_reveal_token.as_bytes()))
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RevealReportApiKeyValueRequest {
+    report_api_key_value_reveal_token: String,
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct RevealReportApiKeyValueResponse {
+    report_api_key: ReportApiKeyPublic,
+    report_api_key_value: String,
+}
+
+// Returns the value of a newly created report key in exchange for the reveal token returned when it was created. Only
+// the creator of the key may reveal its value, only before the token expires, and only once. The reveal is recorded on
+// the key.
+#[instrument(err, skip(auth, account, req))]
+pub(crate) async fn reveal_report_api_key_value(
     Extension(auth): Extension<DashboardAuth>,
     Extension(account): Extension<Account>,
     Path(params): Path<HashMap<String, String>>,
-) -> Result<Json<()>> {
+    Json(req): Json<RevealReportApiKeyValueRequest>,
+) -> Result<Json<RevealReportApiKeyValueResponse>> {
+    let report_api_key_id = report_api_key_id(&params)?;
+
+    let Some(report_api_key) = account
+        .resources_db()
+        .await?
+        .query("UPDATE $report_api_key SET value_reveal_token_hash = NONE, value_reveal_expires_at = NONE, value_revealed_at = time::now(), value_revealed_by = $user WHERE revoked_at IS NONE AND created_by == $user AND value_reveal_token_hash == $value_reveal_token_hash AND value_reveal_expires_at > time::now() RETURN AFTER")
+        .bind((
+            "report_api_key",
+            surrealdb::sql::Thing::from((
+                "report_api_key",
+                surrealdb::sql::Id::from(i64::from(report_api_key_id)),
+            )),
+        ))
+        .bind(("user", surrealdb::sql::Thing::from(auth.principal())))
+        .bind((
+            "value_reveal_token_hash",
+            value_reveal_token_hash(&req.report_api_key_value_reveal_token),
+        ))
+        .await
+        .context("Failed to submit query to claim report key value reveal")?
+        .check_first_real_error()
+        .context("Failed to claim report key value reveal")?
+        .take::<Option<ReportApiKey>>(0)?
+    else {
+        not_found!("No report key value to reveal found");
+    };
+
+    let report_api_key_value = report_api_key
+        .generate_value(account.id(), account.salt().to_owned())
+        .await?;
+
+    info!(report_api_key_id, "Revealed report key value");
+
+    Ok(Json(RevealReportApiKeyValueResponse {
+        report_api_key: ReportApiKeyPublic::from(report_api_key),
+        report_api_key_value,
+    }))
+}
+
+pub(crate) fn report_api_key_id(params: &HashMap<String, String>) -> Result<u32> {
     let Some(report_api_key_id_string) = params.get("report_api_key_id") else {
         bail!("Missing report_api_key_id");
     };
@@ -99,10 +289,41 @@ pub(crate) async fn revoke_report_api_key(
         bad_request!("Invalid route key ID");
     };
 
+    Ok(report_api_key_id)
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RevokeReportApiKeyRequest {
+    // Revokes the key permanently, so it cannot be restored. Also applies to keys that are already revoked. Only
+    // account admins may hard-revoke keys.
+    #[serde(default)]
+    hard: bool,
+}
+
+// Revoked keys can be restored by account admins within the account's restore window, see `restore_report_api_key`
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn revoke_report_api_key(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Path(params): Path<HashMap<String, String>>,
+    Query(req): Query<RevokeReportApiKeyRequest>,
+) -> Result<(Extension<AuditTarget>, Json<()>)> {
+    if req.hard {
+        auth.ensure_account_admin(account.id()).await?;
+    }
+
+    let report_api_key_id = report_api_key_id(&params)?;
+
     let report_api_key = account
         .resources_db()
         .await?
-        .revoke_report_api_key_query(report_api_key_id, auth.principal())
+        .revoke_report_api_key_query(
+            report_api_key_id,
+            Some(auth.principal()),
+            req.hard,
+            account.report_api_key_restore_window_days(),
+        )
         .await?
         .check_first_real_error()?
         .take::<Option<ReportApiKey>>(0)?;
@@ -111,5 +332,252 @@ pub(crate) async fn revoke_report_api_key(
         not_found!("Report key not found");
     }
 
-    Ok(Json(()))
+    let mut audit_target = HashMap::new();
+    if req.hard {
+        audit_target.insert("hard".to_string(), "true".to_string());
+    }
+
+    Ok((Extension(AuditTarget(audit_target)), Json(())))
+}
+
+// Reinstates a revoked report key, so its existing values are accepted again. Keys that were hard-revoked, or whose
+// restore window has passed since they were revoked, cannot be restored.
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn restore_report_api_key(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<ReportApiKeyPublic>> {
+    auth.ensure_account_admin(account.id()).await?;
+
+    let report_api_key_id = report_api_key_id(&params)?;
+
+    let report_api_key_thing = surrealdb::sql::Thing::from((
+        "report_api_key",
+        surrealdb::sql::Id::from(i64::from(report_api_key_id)),
+    ));
+
+    let db = account.resources_db().await?;
+
+    let Some(report_api_key) = db
+        .query("SELECT * FROM $report_api_key")
+        .bind(("report_api_key", report_api_key_thing.clone()))
+        .await
+        .context("Failed to submit query to get report key")?
+        .check_first_real_error()
+        .context("Failed to get report key")?
+        .take::<Option<ReportApiKey>>(0)?
+    else {
+        not_found!("Report key not found");
+    };
+
+    let Some(revoked_at) = report_api_key.revoked_at() else {
+        conflict!("Report key is not revoked");
+    };
+
+    if report_api_key.hard_revoked() {
+        conflict!("Report key was hard-revoked and cannot be restored");
+    }
+
+    if report_api_key.restorable_until().is_none() {
+        conflict!("Report key's restore window has passed and it can no longer be restored");
+    }
+
+    ensure_report_api_key_entitlement(&account, &db).await?;
+
+    // Guards against the key being restored or hard-revoked concurrently, and against its restore window passing since
+    // it was fetched
+    let Some(report_api_key) = db
+        .query("UPDATE $report_api_key SET revoked_at = NONE, revoked_by = NONE, restorable_until = NONE, restored_at = time::now(), restored_by = $user WHERE revoked_at == $revoked_at AND hard_revoked != true AND restorable_until > time::now() RETURN AFTER")
+        .bind(("report_api_key", report_api_key_thing))
+        .bind(("user", surrealdb::sql::Thing::from(auth.principal())))
+        .bind(("revoked_at", surrealdb::sql::Datetime::from(revoked_at)))
+        .await
+        .context("Failed to submit query to restore report key")?
+        .check_first_real_error()
+        .context("Failed to restore report key")?
+        .take::<Option<ReportApiKey>>(0)?
+    else {
+        conflict!("Report key was changed while it was being restored");
+    };
+
+    info!(report_api_key_id, "Restored Report API Key");
+
+    Ok(Json(ReportApiKeyPublic::from(report_api_key)))
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RotateReportApiKeyRequest {
+    // How long values of the rotated key remain valid after the rotation, so agents can switch to the value of the
+    // replacement key without downtime. Defaults to 24 hours, and `0` expires the rotated key immediately.
+    grace_period_hours: Option<u32>,
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct RotateReportApiKeyResponse {
+    // The replacement key. Its value is revealed to the user who rotated the key in exchange for the reveal token, as
+    // for newly created keys.
+    report_api_key: ReportApiKeyPublic,
+    report_api_key_value_reveal_token: String,
+    report_api_key_value_reveal_expires_at: DateTime<Utc>,
+    rotated_report_api_key: ReportApiKeyPublic,
+}
+
+// Replaces an active report key with a new key of the same description. The rotated key expires once the grace period
+// passes, and can't be rotated again. Accounts that require report keys to be approved only allow admins to rotate
+// keys, as rotating creates a key.
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn rotate_report_api_key(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Path(params): Path<HashMap<String, String>>,
+    Json(req): Json<RotateReportApiKeyRequest>,
+) -> Result<(StatusCode, Json<RotateReportApiKeyResponse>)> {
+    if account.report_api_key_approval_required() {
+        auth.ensure_account_admin(account.id()).await?;
+    }
+
+    let grace_period_hours = req
+        .grace_period_hours
+        .unwrap_or(DEFAULT_REPORT_API_KEY_ROTATION_GRACE_PERIOD_HOURS);
+    if grace_period_hours > MAX_REPORT_API_KEY_ROTATION_GRACE_PERIOD_HOURS {
+        bad_request!(
+            "Report key rotation `grace_period_hours` must be at most {MAX_REPORT_API_KEY_ROTATION_GRACE_PERIOD_HOURS}"
+        );
+    }
+
+    let rotated_report_api_key_id = report_api_key_id(&params)?;
+
+    let rotated_report_api_key_thing = surrealdb::sql::Thing::from((
+        "report_api_key",
+        surrealdb::sql::Id::from(i64::from(rotated_report_api_key_id)),
+    ));
+
+    let db = account.resources_db().await?;
+
+    let Some(rotated_report_api_key) = db
+        .query("SELECT * FROM $report_api_key")
+        .bind(("report_api_key", rotated_report_api_key_thing.clone()))
+        .await
+        .context("Failed to submit query to get report key")?
+        .check_first_real_error()
+        .context("Failed to get report key")?
+        .take::<Option<ReportApiKey>>(0)?
+    else {
+        not_found!("Report key not found");
+    };
+
+    if rotated_report_api_key.revoked_at().is_some() {
+        conflict!("Report key is revoked");
+    }
+
+    if rotated_report_api_key.expires_at().is_some() {
+        conflict!("Report key was already rotated");
+    }
+
+    // The rotated key stops counting against the plan's limit once it expires
+    ensure_report_api_keys_entitlement(
+        &account,
+        &db,
+        1,
+        &HashSet::from([rotated_report_api_key_id]),
+    )
+    .await?;
+
+    let report_api_key = ReportApiKey::new(
+        rotated_report_api_key.description().map(str::to_owned),
+        auth.principal().clone(),
+    );
+
+    let report_api_key_value_reveal_token = new_value_reveal_token();
+    let report_api_key_value_reveal_expires_at = Utc::now() + REPORT_API_KEY_VALUE_REVEAL_TTL;
+
+    let mut res = db
+        .query(BeginStatement::default())
+        .query(ROTATE_REPORT_API_KEY_STATEMENTS.join("\n"))
+        .query(CommitStatement::default())
+        .bind(("rotated_report_api_key", rotated_report_api_key_thing))
+        .bind((
+            "grace_period",
+            surrealdb::sql::Duration::from(Duration::from_secs(
+                u64::from(grace_period_hours) * 60 * 60,
+            )),
+        ))
+        .bind((
+            "report_api_key",
+            surrealdb::sql::Thing::from(&report_api_key),
+        ))
+        .bind(("report_api_key_id", report_api_key.id()))
+        .bind((
+            "description",
+            report_api_key.description().map(str::to_owned),
+        ))
+        .bind((
+            "created_by",
+            surrealdb::sql::Thing::from(report_api_key.created_by()),
+        ))
+        .bind((
+            "value_reveal_token_hash",
+            value_reveal_token_hash(&report_api_key_value_reveal_token),
+        ))
+        .bind((
+            "value_reveal_expires_at",
+            surrealdb::sql::Datetime::from(report_api_key_value_reveal_expires_at),
+        ))
+        .await
+        .context("Failed to submit query to rotate report key")?
+        .check_first_real_error()
+        .context("Failed to rotate report key")?;
+
+    let report_api_key = res
+        .take::<Option<ReportApiKey>>(ROTATE_REPORT_API_KEY_REPLACEMENT_RESULT)?
+        .expect("Rotate report key query should return the replacement report key instance");
+    let rotated_report_api_key = res
+        .take::<Option<ReportApiKey>>(ROTATE_REPORT_API_KEY_ROTATED_RESULT)?
+        .expect("Rotate report key query should return the rotated report key instance");
+
+    info!(
+        rotated_report_api_key_id,
+        report_api_key_id = report_api_key.id(),
+        grace_period_hours,
+        "Rotated Report API Key"
+    );
+
+    Ok((
+        StatusCode::CREATED,
+        Json(RotateReportApiKeyResponse {
+            report_api_key: ReportApiKeyPublic::from(report_api_key),
+            report_api_key_value_reveal_token,
+            report_api_key_value_reveal_expires_at,
+            rotated_report_api_key: ReportApiKeyPublic::from(rotated_report_api_key),
+        }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use surrealdb::sql::Statement;
+
+    use crate::db::transaction_results;
+
+    use super::{
+        ROTATE_REPORT_API_KEY_REPLACEMENT_RESULT, ROTATE_REPORT_API_KEY_ROTATED_RESULT,
+        ROTATE_REPORT_API_KEY_STATEMENTS,
+    };
+
+    #[test]
+    fn rotate_report_api_key_results() {
+        let results = transaction_results(&ROTATE_REPORT_API_KEY_STATEMENTS);
+
+        assert_eq!(results.len(), ROTATE_REPORT_API_KEY_STATEMENTS.len());
+        assert!(matches!(
+            results[ROTATE_REPORT_API_KEY_REPLACEMENT_RESULT],
+            Statement::Create(_)
+        ));
+        assert!(matches!(
+            results[ROTATE_REPORT_API_KEY_ROTATED_RESULT],
+            Statement::Output(_)
+        ));
+    }
 }
@@ -0,0 +1,120 @@
+// Report key values that recently failed to authenticate are remembered, so agents or attackers repeatedly presenting
+// the same invalid value are turned away with a retryable `rate_limited` error before the value is decrypted or its key
+// is looked up in the account database. Each consecutive failure doubles how long the value is refused, from
+// `INITIAL_BACKOFF` up to `MAX_BACKOFF`.
+//
+// Values are tracked by a fingerprint of the whole value rather than by key ID. Key IDs aren't secret, so backing off
+// by key ID would let anyone lock out a valid key by presenting garbage values with its ID. Fingerprints are kept in
+// memory, so each backend instance tracks the failures it sees independently.
+
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, RwLock},
+    time::{Duration, Instant},
+};
+
+use sha2::{Digest as _, Sha256};
+use tracing::warn;
+
+use archodex_error::{ErrorCode, PublicError};
+
+use crate::{
+    Result,
+    metrics::{REPORT_KEY_BACKOFF_REJECTIONS_TOTAL, REPORT_KEY_BACKOFF_TRACKED_VALUES},
+};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(15 * 60);
+
+// Values that haven't failed again for this long after their backoff ended are forgotten
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+
+// Bounds the memory used when many distinct values fail. Beyond this, new failures are not tracked until entries
+// expire.
+const MAX_TRACKED_VALUES: usize = 10_000;
+
+pub(crate) type Fingerprint = [u8; 16];
+
+struct Failures {
+    count: u32,
+    retry_at: Instant,
+}
+
+static FAILURES: LazyLock<RwLock<HashMap<Fingerprint, Failures>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+pub(crate) fn fingerprint(report_api_key_value: &str) -> Fingerprint {
+    let hash = Sha256::digest(report_api_key_value);
+    let mut fingerprint = [0u8; 16];
+    fingerprint.copy_from_slice(&hash[..16]);
+
+    fingerprint
+}
+
+// Refuses values whose backoff hasn't ended yet
+pub(crate) fn check(fingerprint: &Fingerprint) -> Result<()> {
+    let failures = FAILURES.read().expect("Report key backoff lock poisoned");
+
+    let Some(retry_after) = failures
+        .get(fingerprint)
+        .and_then(|entry| entry.retry_at.checked_duration_since(Instant::now()))
+    else {
+        return Ok(());
+    };
+
+    REPORT_KEY_BACKOFF_REJECTIONS_TOTAL.inc();
+
+    Err(PublicError::with_code(
+        ErrorCode::RateLimited,
+        "Report key value failed authentication too many times, retry later",
+    )
+    .retry_after(retry_after))
+}
+
+pub(crate) fn record_failure(fingerprint: Fingerprint) {
+    let now = Instant::now();
+
+    let mut failures = FAILURES.write().expect("Report key backoff lock poisoned");
+
+    if failures.len() >= MAX_TRACKED_VALUES && !failures.contains_key(&fingerprint) {
+        failures.retain(|_, entry| now < entry.retry_at + FORGET_AFTER);
+
+        if failures.len() >= MAX_TRACKED_VALUES {
+            warn!("Too many failing report key values to track, not backing off new value");
+            return;
+        }
+    }
+
+    let entry = failures.entry(fingerprint).or_insert(Failures {
+        count: 0,
+        retry_at: now,
+    });
+
+    if now >= entry.retry_at + FORGET_AFTER {
+        entry.count = 0;
+    }
+
+    entry.count = entry.count.saturating_add(1);
+    entry.retry_at = now
+        + INITIAL_BACKOFF
+            .saturating_mul(2u32.saturating_pow(entry.count - 1))
+            .min(MAX_BACKOFF);
+
+    REPORT_KEY_BACKOFF_TRACKED_VALUES.set(i64::try_from(failures.len()).unwrap_or(i64::MAX));
+}
+
+// Forgets past failures of a value once it authenticates, e.g. after its account was restored
+pub(crate) fn record_success(fingerprint: &Fingerprint) {
+    if !FAILURES
+        .read()
+        .expect("Report key backoff lock poisoned")
+        .contains_key(fingerprint)
+    {
+        return;
+    }
+
+    let mut failures = FAILURES.write().expect("Report key backoff lock poisoned");
+    failures.remove(fingerprint);
+
+    REPORT_KEY_BACKOFF_TRACKED_VALUES.set(i64::try_from(failures.len()).unwrap_or(i64::MAX));
+}
@@ -0,0 +1,67 @@
+// Every request is identified by the `X-Request-ID` header, so a response can be correlated with the log lines of the
+// request that produced it. Requests passing a valid request ID, e.g. one generated by a load balancer or an agent,
+// keep it. Other requests are assigned a new one.
+//
+// The request ID is recorded in the request span, which every log line of the request is nested within, and returned in
+// the `X-Request-ID` header of all responses and in the body of error responses.
+
+use axum::{
+    Json,
+    extract::Request,
+    http::{HeaderName, HeaderValue, header::CONTENT_LENGTH},
+    middleware::Next,
+    response::{IntoResponse as _, Response},
+};
+use uuid::Uuid;
+
+use archodex_error::PublicErrorMessage;
+
+pub(crate) const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+fn is_valid_request_id(request_id: &HeaderValue) -> bool {
+    let request_id = request_id.as_bytes();
+
+    !request_id.is_empty()
+        && request_id.len() <= MAX_REQUEST_ID_LENGTH
+        && request_id.iter().all(u8::is_ascii_graphic)
+}
+
+// Returns the request ID of a request that passed through `assign_request_id`
+pub(crate) fn request_id<B>(req: &axum::http::Request<B>) -> &str {
+    req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|request_id| request_id.to_str().ok())
+        .unwrap_or_default()
+}
+
+// Must be layered outside of every other layer, so that the request span and every error response see the request ID
+pub(crate) async fn assign_request_id(mut req: Request, next: Next) -> Response {
+    let request_id = match req.headers().get(REQUEST_ID_HEADER) {
+        Some(request_id) if is_valid_request_id(request_id) => request_id.clone(),
+        _ => {
+            let request_id = HeaderValue::try_from(Uuid::now_v7().to_string())
+                .expect("UUIDs are valid header values");
+            req.headers_mut()
+                .insert(REQUEST_ID_HEADER, request_id.clone());
+            request_id
+        }
+    };
+
+    let mut response = next.run(req).await;
+
+    if let Some(mut message) = response.extensions_mut().remove::<PublicErrorMessage>() {
+        if let Ok(request_id) = request_id.to_str() {
+            message.set_request_id(request_id.to_string());
+        }
+
+        let (mut parts, _) = response.into_parts();
+        parts.headers.remove(CONTENT_LENGTH);
+        response = Response::from_parts(parts, Json(message).into_response().into_body());
+    }
+
+    response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+
+    response
+}
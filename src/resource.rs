@@ -1,15 +1,24 @@
 use std::collections::HashSet;
 
-use axum::{Extension, Json};
+use axum::{Extension, Json, extract::Query};
+use base64::{Engine as _, prelude::BASE64_URL_SAFE_NO_PAD};
 use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use archodex_error::{anyhow, bail, ensure};
+use archodex_error::{anyhow, bad_request, bail, ensure, not_found};
 use tracing::instrument;
 
-use crate::account::Account;
+use crate::{
+    account::Account,
+    db::{QueryCheckFirstRealError as _, statement_timeout},
+    environments,
+    limits::Limit,
+    secret_hash::SecretHash,
+    sensor::Observation,
+};
 
-#[derive(Clone, Debug, Eq, Serialize, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, JsonSchema, Serialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub(crate) struct ResourceIdPart {
     pub(crate) r#type: String,
@@ -135,7 +144,7 @@ impl<'de> Deserialize<'de> for ResourceIdPart {
     }
 }
 
-#[derive(Clone, Debug, Eq, Serialize, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, JsonSchema, Serialize, PartialEq)]
 pub(crate) struct ResourceId(Vec<ResourceIdPart>);
 
 impl std::ops::Deref for ResourceId {
@@ -146,6 +155,19 @@ impl std::ops::Deref for ResourceId {
     }
 }
 
+impl FromIterator<ResourceIdPart> for ResourceId {
+    fn from_iter<T: IntoIterator<Item = ResourceIdPart>>(parts: T) -> Self {
+        ResourceId(parts.into_iter().collect())
+    }
+}
+
+impl ResourceId {
+    // The resource that contains this resource according to the hierarchy encoded in its ID, if any
+    pub(crate) fn container(&self) -> Option<ResourceId> {
+        (self.0.len() > 1).then(|| ResourceId(self.0[..self.0.len() - 1].to_vec()))
+    }
+}
+
 impl From<ResourceId> for surrealdb::sql::Array {
     fn from(value: ResourceId) -> Self {
         surrealdb::sql::Array::from(
@@ -284,26 +306,225 @@ impl<'de> Deserialize<'de> for ResourceId {
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, JsonSchema, Serialize, Deserialize)]
 pub(crate) struct Resource {
     pub(crate) id: ResourceId,
     #[serde(default, skip_serializing_if = "HashSet::is_empty")]
     pub(crate) environments: HashSet<String>,
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub(crate) tags: HashSet<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) first_seen_at: Option<DateTime<Utc>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) last_seen_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) archived_at: Option<DateTime<Utc>>,
+    // When a report removed the resource, see `report::remove_resources`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) gone_at: Option<DateTime<Utc>>,
+    // When the resource was shared with linked accounts, see `resource_sharing`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) shared_at: Option<DateTime<Utc>>,
+    // Sensors that observed the resource, see `sensor`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) observed_by: Vec<Observation>,
+    // How the ID of a Secret Value resource was hashed, if declared, see `secret_hash`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) secret_hash: Option<SecretHash>,
 }
 
+// Fields of resources as they are returned, with attribute values stored as attribute documents resolved, see
+// `fn::resolved_attributes`
+pub(crate) const RESOURCE_FIELDS: &str = "*, fn::resolved_attributes(attributes) AS attributes";
+
 impl Resource {
-    pub(crate) fn get_all() -> &'static str {
-        "$resources = SELECT * FROM resource WHERE id != resource:[] PARALLEL;"
+    pub(crate) fn get_all() -> String {
+        format!(
+            "$resources = SELECT {RESOURCE_FIELDS} FROM resource WHERE id != resource:[] {} PARALLEL;",
+            statement_timeout()
+        )
     }
 }
 
+const DEFAULT_LIST_RESOURCES_LIMIT: u32 = 100;
+const MAX_LIST_RESOURCES_LIMIT: u32 = 1000;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ListResourcesRequest {
+    pub(crate) resource_type: Option<String>,
+    pub(crate) environment: Option<String>,
+    pub(crate) tag: Option<String>,
+    // Attribute filter of the form `<name>=<value>`. Attribute values are compared as strings.
+    pub(crate) attribute: Option<String>,
+    pub(crate) first_seen_after: Option<DateTime<Utc>>,
+    pub(crate) first_seen_before: Option<DateTime<Utc>>,
+    pub(crate) last_seen_after: Option<DateTime<Utc>>,
+    pub(crate) last_seen_before: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub(crate) include_archived: bool,
+    pub(crate) limit: Option<u32>,
+    pub(crate) cursor: Option<String>,
+}
+
+#[derive(Debug, JsonSchema, Serialize)]
+pub(crate) struct ListResourcesResponse {
+    pub(crate) resources: Vec<Resource>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) next_cursor: Option<String>,
+}
+
+// Cursors are the opaque, URL safe encoding of the ID of the last resource of the previous page
+fn encode_cursor(resource_id: &ResourceId) -> crate::Result<String> {
+    Ok(BASE64_URL_SAFE_NO_PAD.encode(serde_json::to_vec(resource_id)?))
+}
+
+fn decode_cursor(cursor: &str) -> Option<ResourceId> {
+    let bytes = BASE64_URL_SAFE_NO_PAD.decode(cursor).ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+// Lists resources in resource ID order, one page at a time. Unlike the `all` query, this doesn't load the full resource
+// graph, so it remains fast for accounts with many resources.
+#[instrument(err, skip(account))]
+pub(crate) async fn list_resources(
+    Extension(account): Extension<Account>,
+    Query(req): Query<ListResourcesRequest>,
+) -> crate::Result<Json<ListResourcesResponse>> {
+    Ok(Json(list(&account, req).await?))
+}
+
+// Lists a page of resources, see `list_resources`
+#[instrument(err, skip(account))]
+pub(crate) async fn list(
+    account: &Account,
+    req: ListResourcesRequest,
+) -> crate::Result<ListResourcesResponse> {
+    let limit = req.limit.unwrap_or(DEFAULT_LIST_RESOURCES_LIMIT);
+    if limit == 0 || limit > MAX_LIST_RESOURCES_LIMIT {
+        bad_request!("Resource list `limit` must be between 1 and {MAX_LIST_RESOURCES_LIMIT}");
+    }
+
+    let mut conditions = vec!["id != resource:[]"];
+
+    let cursor = match &req.cursor {
+        Some(cursor) => {
+            let Some(cursor) = decode_cursor(cursor) else {
+                bad_request!("Invalid resource list `cursor`");
+            };
+            conditions.push("id > $cursor");
+            Some(surrealdb_thing_from_resource_id(cursor))
+        }
+        None => None,
+    };
+
+    let attribute = match &req.attribute {
+        Some(attribute) => {
+            let Some((name, value)) = attribute.split_once('=') else {
+                bad_request!(
+                    "Resource list `attribute` filter must be of the form `<name>=<value>`"
+                );
+            };
+            conditions.push(
+                "type::string(fn::resolve_attribute(attributes[$attribute_name])) = $attribute_value",
+            );
+            Some((name.to_owned(), value.to_owned()))
+        }
+        None => None,
+    };
+
+    let environment = account.scoped_environment(req.environment);
+
+    for (is_set, condition) in [
+        (!req.include_archived, "archived_at IS NONE"),
+        (environment.is_some(), "$environment INSIDE environments"),
+        (req.tag.is_some(), "$tag INSIDE tags"),
+        (
+            req.resource_type.is_some(),
+            "resource_type = $resource_type",
+        ),
+        (
+            req.first_seen_after.is_some(),
+            "first_seen_at >= $first_seen_after",
+        ),
+        (
+            req.first_seen_before.is_some(),
+            "first_seen_at < $first_seen_before",
+        ),
+        (
+            req.last_seen_after.is_some(),
+            "last_seen_at >= $last_seen_after",
+        ),
+        (
+            req.last_seen_before.is_some(),
+            "last_seen_at < $last_seen_before",
+        ),
+    ] {
+        if is_set {
+            conditions.push(condition);
+        }
+    }
+
+    let db = account.resources_db().await?;
+
+    // Fetch one extra resource to find out whether there is another page
+    let mut query = db
+        .query(format!(
+            "SELECT {RESOURCE_FIELDS} FROM resource WHERE {} ORDER BY id LIMIT $limit",
+            conditions.join(" AND ")
+        ))
+        .bind(("limit", limit + 1))
+        .bind(("cursor", cursor))
+        .bind(("resource_type", req.resource_type))
+        .bind(("environment", environment))
+        .bind(("tag", req.tag))
+        .bind((
+            "first_seen_after",
+            req.first_seen_after.map(surrealdb::sql::Datetime::from),
+        ))
+        .bind((
+            "first_seen_before",
+            req.first_seen_before.map(surrealdb::sql::Datetime::from),
+        ))
+        .bind((
+            "last_seen_after",
+            req.last_seen_after.map(surrealdb::sql::Datetime::from),
+        ))
+        .bind((
+            "last_seen_before",
+            req.last_seen_before.map(surrealdb::sql::Datetime::from),
+        ));
+
+    if let Some((name, value)) = attribute {
+        query = query
+            .bind(("attribute_name", name))
+            .bind(("attribute_value", value));
+    }
+
+    let mut resources = query
+        .await?
+        .check_first_real_error()?
+        .take::<Vec<Resource>>(0)?;
+
+    let next_cursor = if resources.len() > limit as usize {
+        resources.truncate(limit as usize);
+        resources
+            .last()
+            .map(|resource| encode_cursor(&resource.id))
+            .transpose()?
+    } else {
+        None
+    };
+
+    Ok(ListResourcesResponse {
+        resources,
+        next_cursor,
+    })
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
-pub(super) struct SetTagsRequest {
+pub(super) struct SetEnvironmentsRequest {
     resource_id: ResourceId,
     environments: HashSet<String>,
 }
@@ -311,15 +532,16 @@ pub(super) struct SetTagsRequest {
 #[instrument(err, skip(account))]
 pub(super) async fn set_environments(
     Extension(account): Extension<Account>,
-    Json(req): Json<SetTagsRequest>,
+    Json(req): Json<SetEnvironmentsRequest>,
 ) -> crate::Result<()> {
     const QUERY: &str =
         "BEGIN; UPDATE resource SET environments = $envs WHERE id = $resource_id; COMMIT;";
 
-    account
-        .resources_db()
-        .await?
-        .query(QUERY)
+    let db = account.resources_db().await?;
+
+    environments::ensure_defined(&db, &req.environments).await?;
+
+    db.query(QUERY)
         .bind(("envs", req.environments))
         .bind((
             "resource_id",
@@ -329,3 +551,68 @@ pub(super) async fn set_environments(
 
     Ok(())
 }
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(super) struct SetTagsRequest {
+    resource_id: ResourceId,
+    // Tags added to the resource
+    #[serde(default)]
+    add: HashSet<String>,
+    // Tags removed from the resource. Tags both added and removed are added.
+    #[serde(default)]
+    remove: HashSet<String>,
+}
+
+#[derive(Debug, JsonSchema, Serialize)]
+pub(super) struct SetTagsResponse {
+    tags: HashSet<String>,
+}
+
+// Adds and removes the user-defined tags of a resource
+#[instrument(err, skip(account))]
+pub(super) async fn set_tags(
+    Extension(account): Extension<Account>,
+    Json(req): Json<SetTagsRequest>,
+) -> crate::Result<Json<SetTagsResponse>> {
+    if let Some(tag) = req
+        .add
+        .iter()
+        .find(|tag| tag.trim().is_empty() || tag.trim() != *tag)
+    {
+        bad_request!("Invalid tag {tag:?}, tags must not have leading or trailing whitespace");
+    }
+
+    if let Some(tag) = req.add.iter().find(|tag| tag.len() > Limit::TagBytes.max()) {
+        return Err(Limit::TagBytes.exceeded_by(&format!("Size of tag {tag:?}"), Some(tag.len())));
+    }
+
+    let tags = "array::union(array::complement(tags, $remove), $add)";
+
+    let mut res = account
+        .resources_db()
+        .await?
+        .query("RETURN record::exists($resource_id)")
+        .query(format!(
+            "UPDATE ONLY $resource_id SET tags = {tags} WHERE array::len({tags}) <= $max_tags RETURN VALUE tags"
+        ))
+        .bind((
+            "resource_id",
+            surrealdb_thing_from_resource_id(req.resource_id),
+        ))
+        .bind(("add", req.add))
+        .bind(("remove", req.remove))
+        .bind(("max_tags", Limit::ResourceTags.max()))
+        .await?
+        .check_first_real_error()?;
+
+    if res.take::<Option<bool>>(0)? != Some(true) {
+        not_found!("Resource not found");
+    }
+
+    let Some(tags) = res.take::<Option<HashSet<String>>>(1)? else {
+        return Err(Limit::ResourceTags.exceeded(None));
+    };
+
+    Ok(Json(SetTagsResponse { tags }))
+}
@@ -0,0 +1,224 @@
+// Resources reported by mistake, e.g. by an agent pointed at the wrong account, can be purged by account members.
+// Unlike archived resources, deleted resources are removed from the account's resources database together with their
+// `contains` relations and events, and only return if a report observes them again.
+//
+// Deleting a resource that contains other resources requires `recursive`, which deletes its whole subtree. Resources
+// are deleted in transactions of at most `RESOURCE_DELETION_BATCH_SIZE` resources, contained resources before their
+// containers, so a deletion that fails partway never leaves resources whose container was deleted. Dry runs report what
+// would be deleted without deleting anything.
+
+use std::collections::{HashMap, HashSet};
+
+use axum::{Extension, Json};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::statements::{BeginStatement, CommitStatement};
+use tracing::{info, instrument};
+
+use archodex_error::{anyhow::Context as _, bad_request, conflict, not_found};
+
+use crate::{
+    Result,
+    account::Account,
+    audit::AuditTarget,
+    db::{DBConnection, QueryCheckFirstRealError as _},
+    lifecycle::subtree_statements,
+    limits::Limit,
+    next_binding,
+    resource::{ResourceId, surrealdb_thing_from_resource_id},
+    usage,
+};
+
+const RESOURCE_DELETION_BATCH_SIZE: usize = 500;
+
+// Assigns the `contains` relations and events of the resources bound to `$resources` to `$contains` and `$events`
+const RELATIONS_STATEMENTS: &str = "LET $contains = array::distinct(array::flatten(SELECT VALUE array::concat(->contains, <-contains) FROM $resources));
+LET $events = array::distinct(array::flatten(SELECT VALUE array::concat(->event, <-event) FROM $resources));";
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct DeleteResourcesRequest {
+    resource_ids: Vec<ResourceId>,
+    // Also deletes the resources contained by the resources, which is required to delete resources that contain others
+    #[serde(default)]
+    recursive: bool,
+    // Reports what would be deleted without deleting anything
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Debug, JsonSchema, Serialize)]
+pub(crate) struct DeleteResourcesResponse {
+    // Resources deleted, or that would be deleted by a dry run, including contained resources
+    resources: Vec<ResourceId>,
+    // Number of `contains` relations deleted
+    contains: usize,
+    events: usize,
+    dry_run: bool,
+}
+
+// Resolves the resources to delete, contained resources before their containers
+async fn resources_to_delete(
+    db: &DBConnection,
+    resource_ids: Vec<ResourceId>,
+    recursive: bool,
+) -> Result<Vec<ResourceId>> {
+    let mut query = Vec::new();
+    let mut bindings = Vec::new();
+    let mut subtrees = Vec::new();
+
+    for resource_id in resource_ids {
+        let subtree = next_binding();
+        let resource = next_binding();
+        let depth = next_binding();
+
+        query.extend(subtree_statements(&subtree, &resource, &depth));
+        bindings.push((
+            depth.clone(),
+            surrealdb::sql::Value::from(resource_id.len()),
+        ));
+        bindings.push((
+            resource.clone(),
+            surrealdb_thing_from_resource_id(resource_id),
+        ));
+        subtrees.push(format!("${subtree}"));
+    }
+
+    query.push(format!("RETURN [{}];", subtrees.join(", ")));
+
+    let statements = query.len();
+
+    let mut db_query = db.query(query.join("\n"));
+    for binding in bindings {
+        db_query = db_query.bind(binding);
+    }
+
+    let subtrees = db_query
+        .await
+        .context("Failed to submit query to find resources to delete")?
+        .check_first_real_error()
+        .context("Failed to find resources to delete")?
+        .take::<Vec<Vec<ResourceId>>>(statements - 1)?;
+
+    let mut resources = HashSet::new();
+
+    for subtree in subtrees {
+        if subtree.is_empty() {
+            not_found!("Resource not found");
+        }
+
+        if subtree.len() > 1 && !recursive {
+            conflict!("Resource contains other resources, set `recursive` to delete them too");
+        }
+
+        resources.extend(subtree);
+
+        if resources.len() > Limit::DeletedResources.max() {
+            return Err(Limit::DeletedResources.exceeded(Some(resources.len())));
+        }
+    }
+
+    // Resource IDs encode their hierarchy, so contained resources have longer IDs than their containers. Globally
+    // unique resources contained through `contains` relations may be deleted after their container, which leaves them
+    // uncontained rather than orphaned if deletion fails partway.
+    let mut resources = resources.into_iter().collect::<Vec<_>>();
+    resources.sort_by_key(|resource_id| std::cmp::Reverse(resource_id.len()));
+
+    Ok(resources)
+}
+
+fn resource_things(resources: &[ResourceId]) -> Vec<surrealdb::sql::Value> {
+    resources
+        .iter()
+        .cloned()
+        .map(surrealdb_thing_from_resource_id)
+        .collect()
+}
+
+#[instrument(err, skip(account))]
+pub(crate) async fn delete_resources(
+    Extension(account): Extension<Account>,
+    Json(req): Json<DeleteResourcesRequest>,
+) -> Result<(Extension<AuditTarget>, Json<DeleteResourcesResponse>)> {
+    if req.resource_ids.is_empty() {
+        bad_request!("At least one resource ID must be submitted");
+    }
+
+    if req.resource_ids.len() > Limit::DeleteResourceIds.max() {
+        return Err(Limit::DeleteResourceIds.exceeded(Some(req.resource_ids.len())));
+    }
+
+    if req
+        .resource_ids
+        .iter()
+        .any(|resource_id| resource_id.is_empty())
+    {
+        bad_request!("The root resource cannot be deleted");
+    }
+
+    let db = account.resources_db().await?;
+
+    let resources = resources_to_delete(&db, req.resource_ids, req.recursive).await?;
+
+    let (mut contains, mut events) = (0, 0);
+
+    if req.dry_run {
+        let mut res = db
+            .query(RELATIONS_STATEMENTS)
+            .query("RETURN [array::len($contains), array::len($events)];")
+            .bind(("resources", resource_things(&resources)))
+            .await
+            .context("Failed to submit query to count relations of resources to delete")?
+            .check_first_real_error()
+            .context("Failed to count relations of resources to delete")?;
+
+        [contains, events] = res
+            .take::<Option<[usize; 2]>>(2)?
+            .context("Count relations query should return relation counts")?;
+    } else {
+        usage::sample_peak_graph_size(&db).await?;
+
+        for batch in resources.chunks(RESOURCE_DELETION_BATCH_SIZE) {
+            let mut res = db
+                .query(BeginStatement::default())
+                .query(RELATIONS_STATEMENTS)
+                .query("DELETE $contains RETURN NONE;")
+                .query("DELETE $events RETURN NONE;")
+                .query("DELETE $resources RETURN NONE;")
+                .query("RETURN [array::len($contains), array::len($events)];")
+                .query(CommitStatement::default())
+                .bind(("resources", resource_things(batch)))
+                .await
+                .context("Failed to submit query to delete resources")?
+                .check_first_real_error()
+                .context("Failed to delete resources")?;
+
+            // SurrealDB returns no results for `BEGIN` and `COMMIT`
+            let [batch_contains, batch_events] = res
+                .take::<Option<[usize; 2]>>(5)?
+                .context("Delete resources query should return relation counts")?;
+            contains += batch_contains;
+            events += batch_events;
+        }
+
+        info!(
+            resources = resources.len(),
+            contains, events, "Deleted resources"
+        );
+    }
+
+    let mut audit_target = HashMap::from([("resources".to_string(), resources.len().to_string())]);
+    if req.dry_run {
+        audit_target.insert("dry_run".to_string(), "true".to_string());
+    }
+
+    Ok((
+        Extension(AuditTarget(audit_target)),
+        Json(DeleteResourcesResponse {
+            resources,
+            contains,
+            events,
+            dry_run: req.dry_run,
+        }),
+    ))
+}
@@ -0,0 +1,359 @@
+// Organizations running an account per team often share infrastructure, like S3 buckets of a platform team accessed by
+// every other team. Linked accounts let an account's queries resolve the globally unique resources it references with
+// what a linked account knows about them, like their container, environments, and tags.
+//
+// Admins of both accounts link them with `POST /account/:account_id/linked_accounts`. Links are symmetric, and either
+// account's admins may remove them. Members of an account choose which of its globally unique resources linked
+// accounts see with `POST /account/:account_id/resource/share`. Queries return the shared resources of linked accounts
+// that the account references but doesn't know the container of, which are read-only, as only the linked account's
+// reports and members modify them.
+//
+// Accounts of self-hosted backends can't be linked, as each self-hosted backend holds a single account.
+//
+// Query responses are cached per account, see `query_cache`, so changes to resources shared by linked accounts are seen
+// once the account's cached responses expire.
+
+use std::collections::{HashMap, HashSet};
+
+use axum::{Extension, Json, extract::Path};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::statements::{BeginStatement, CommitStatement};
+use tracing::{info, instrument, warn};
+
+use archodex_error::{
+    anyhow::{Context as _, bail},
+    bad_request, conflict, not_found,
+};
+
+use crate::{
+    Result,
+    account::Account,
+    account_cache,
+    audit::AuditTarget,
+    auth::DashboardAuth,
+    db::{QueryCheckFirstRealError as _, accounts_db},
+    global_container::GlobalContainer,
+    query_cache,
+    resource::{RESOURCE_FIELDS, Resource, ResourceId, surrealdb_thing_from_resource_id},
+    surrealdb_deserializers,
+    user::User,
+};
+
+const MAX_LINKED_ACCOUNTS: usize = 10;
+const MAX_SHARE_RESOURCE_IDS: usize = 100;
+
+fn account_thing(account_id: &str) -> surrealdb::sql::Thing {
+    surrealdb::sql::Thing::from(("account", account_id))
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
+pub(crate) struct LinkedAccount {
+    #[serde(deserialize_with = "surrealdb_deserializers::string::deserialize")]
+    account_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    created_at: DateTime<Utc>,
+    created_by: User,
+}
+
+// Links are stored as a `linked_account` relation in each direction
+async fn linked_accounts(account_id: &str) -> Result<Vec<LinkedAccount>> {
+    Ok(accounts_db()
+        .await?
+        .query("SELECT record::id(out) AS account_id, out.name AS name, created_at, created_by FROM linked_account WHERE in == $account AND out.deleted_at IS NONE ORDER BY created_at")
+        .bind(("account", account_thing(account_id)))
+        .await
+        .context("Failed to submit query to list linked accounts")?
+        .check_first_real_error()
+        .context("Failed to list linked accounts")?
+        .take::<Vec<LinkedAccount>>(0)?)
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct ListLinkedAccountsResponse {
+    linked_accounts: Vec<LinkedAccount>,
+}
+
+#[instrument(err, skip_all)]
+pub(crate) async fn list_linked_accounts(
+    Extension(account): Extension<Account>,
+) -> Result<Json<ListLinkedAccountsResponse>> {
+    Ok(Json(ListLinkedAccountsResponse {
+        linked_accounts: linked_accounts(account.id()).await?,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct LinkAccountRequest {
+    account_id: String,
+}
+
+// Requires the user to be an admin of both accounts
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn link_account(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Json(req): Json<LinkAccountRequest>,
+) -> Result<(Extension<AuditTarget>, Json<LinkedAccount>)> {
+    if req.account_id == account.id() {
+        bad_request!("Accounts can't be linked to themselves");
+    }
+
+    auth.ensure_account_admin(account.id()).await?;
+    auth.ensure_account_admin(&req.account_id).await?;
+
+    if account_cache::get_account(&req.account_id).await?.is_none() {
+        not_found!("Account not found");
+    }
+
+    for account_id in [account.id(), req.account_id.as_str()] {
+        let linked_accounts = linked_accounts(account_id).await?;
+
+        if linked_accounts
+            .iter()
+            .any(|linked_account| linked_account.account_id == req.account_id)
+        {
+            conflict!("Accounts are already linked");
+        }
+
+        if linked_accounts.len() >= MAX_LINKED_ACCOUNTS {
+            conflict!("Accounts may be linked to at most {MAX_LINKED_ACCOUNTS} other accounts");
+        }
+    }
+
+    let linked_account = accounts_db()
+        .await?
+        .query(BeginStatement::default())
+        .query("RELATE $account->linked_account->$linked_account SET created_by = $user RETURN NONE;")
+        .query("RELATE $linked_account->linked_account->$account SET created_by = $user RETURN NONE;")
+        .query("SELECT record::id(out) AS account_id, out.name AS name, created_at, created_by FROM linked_account WHERE in == $account AND out == $linked_account;")
+        .query(CommitStatement::default())
+        .bind(("account", account_thing(account.id())))
+        .bind(("linked_account", account_thing(&req.account_id)))
+        .bind(("user", surrealdb::sql::Thing::from(auth.principal())))
+        .await
+        .context("Failed to submit query to link accounts")?
+        .check_first_real_error()
+        .context("Failed to link accounts")?
+        .take::<Vec<LinkedAccount>>(2)?
+        .pop()
+        .expect("Link accounts query should return the linked account");
+
+    // Cached query responses of both accounts don't include each other's shared resources
+    query_cache::invalidate(&req.account_id);
+
+    info!(linked_account_id = req.account_id, "Linked accounts");
+
+    Ok((
+        Extension(AuditTarget(HashMap::from([(
+            "linked_account_id".to_string(),
+            req.account_id,
+        )]))),
+        Json(linked_account),
+    ))
+}
+
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn unlink_account(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<()>> {
+    auth.ensure_account_admin(account.id()).await?;
+
+    let Some(linked_account_id) = params.get("linked_account_id") else {
+        bail!("Missing linked_account_id");
+    };
+
+    let unlinked = accounts_db()
+        .await?
+        .query("DELETE linked_account WHERE (in == $account AND out == $linked_account) OR (in == $linked_account AND out == $account) RETURN BEFORE")
+        .bind(("account", account_thing(account.id())))
+        .bind(("linked_account", account_thing(linked_account_id)))
+        .await
+        .context("Failed to submit query to unlink accounts")?
+        .check_first_real_error()
+        .context("Failed to unlink accounts")?
+        .take::<Vec<surrealdb::sql::Object>>(0)?;
+
+    if unlinked.is_empty() {
+        not_found!("Linked account not found");
+    }
+
+    query_cache::invalidate(linked_account_id);
+
+    info!(linked_account_id, "Unlinked accounts");
+
+    Ok(Json(()))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ShareResourcesRequest {
+    resource_ids: Vec<ResourceId>,
+    // Stops sharing the resources if false
+    shared: bool,
+}
+
+#[derive(Debug, JsonSchema, Serialize)]
+pub(crate) struct ShareResourcesResponse {
+    // Resources whose sharing changed
+    resources: usize,
+}
+
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn share_resources(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Json(req): Json<ShareResourcesRequest>,
+) -> Result<Json<ShareResourcesResponse>> {
+    if req.resource_ids.is_empty() || req.resource_ids.len() > MAX_SHARE_RESOURCE_IDS {
+        bad_request!("Between 1 and {MAX_SHARE_RESOURCE_IDS} resource IDs must be submitted");
+    }
+
+    // Globally unique resources are the roots of their own hierarchy
+    if req
+        .resource_ids
+        .iter()
+        .any(|resource_id| resource_id.len() != 1)
+    {
+        bad_request!("Only globally unique resources can be shared");
+    }
+
+    let resources = req
+        .resource_ids
+        .into_iter()
+        .map(surrealdb_thing_from_resource_id)
+        .collect::<Vec<_>>();
+
+    let query = if req.shared {
+        "UPDATE $resources SET shared_at = time::now(), shared_by = $user WHERE shared_at IS NONE RETURN VALUE id"
+    } else {
+        "UPDATE $resources SET shared_at = NONE, shared_by = NONE WHERE shared_at IS NOT NONE RETURN VALUE id"
+    };
+
+    let mut res = account
+        .resources_db()
+        .await?
+        .query("RETURN array::all($resources.map(|$resource| record::exists($resource)))")
+        .query(query)
+        .bind(("resources", resources))
+        .bind(("user", surrealdb::sql::Thing::from(auth.principal())))
+        .await
+        .context("Failed to submit query to share resources")?
+        .check_first_real_error()
+        .context("Failed to share resources")?;
+
+    if res.take::<Option<bool>>(0)? != Some(true) {
+        not_found!("Resource not found");
+    }
+
+    let changed = res.take::<Vec<ResourceId>>(1)?;
+
+    info!(
+        resources = changed.len(),
+        shared = req.shared,
+        "Changed sharing of resources"
+    );
+
+    Ok(Json(ShareResourcesResponse {
+        resources: changed.len(),
+    }))
+}
+
+// Shared resources of a linked account, with the containers of the globally unique resources among them
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
+pub(crate) struct LinkedResources {
+    account_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    account_name: Option<String>,
+    resources: Vec<Resource>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    global_containers: Vec<GlobalContainer>,
+}
+
+#[derive(Deserialize)]
+struct SharedResources {
+    resources: Vec<Resource>,
+    global_containers: Vec<GlobalContainer>,
+}
+
+async fn shared_resources(
+    linked_account_id: &str,
+    resource_ids: &[ResourceId],
+) -> Result<SharedResources> {
+    let Some(linked_account) = account_cache::get_account(linked_account_id).await? else {
+        not_found!("Linked account not found");
+    };
+
+    let shared = linked_account
+        .resources_db()
+        .await?
+        .query(format!("LET $shared = SELECT {RESOURCE_FIELDS} FROM $resources WHERE shared_at IS NOT NONE AND archived_at IS NONE;"))
+        .query("RETURN { resources: $shared, global_containers: fn::fetch_global_containers($shared.id) };")
+        .bind((
+            "resources",
+            resource_ids
+                .iter()
+                .cloned()
+                .map(surrealdb_thing_from_resource_id)
+                .collect::<Vec<_>>(),
+        ))
+        .await
+        .context("Failed to submit query to get shared resources of linked account")?
+        .check_first_real_error()
+        .context("Failed to get shared resources of linked account")?
+        .take::<Option<SharedResources>>(1)?
+        .context("Shared resources query should return a result")?;
+
+    Ok(shared)
+}
+
+// Resolves the globally unique resources of a query response whose container the account doesn't know with the shared
+// resources of linked accounts. Linked accounts that fail to resolve are skipped rather than failing the query.
+#[instrument(err, skip_all)]
+pub(crate) async fn resolve(
+    account: &Account,
+    resources: &[Resource],
+    global_containers: &[GlobalContainer],
+) -> Result<Vec<LinkedResources>> {
+    let contained = global_containers
+        .iter()
+        .map(|global_container| &global_container.contains)
+        .collect::<HashSet<_>>();
+
+    let unresolved = resources
+        .iter()
+        .map(|resource| &resource.id)
+        .filter(|resource_id| resource_id.len() == 1 && !contained.contains(resource_id))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    if unresolved.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut linked_resources = Vec::new();
+
+    for linked_account in linked_accounts(account.id()).await? {
+        match shared_resources(&linked_account.account_id, &unresolved).await {
+            Ok(shared) if shared.resources.is_empty() => {}
+            Ok(shared) => linked_resources.push(LinkedResources {
+                account_id: linked_account.account_id,
+                account_name: linked_account.name,
+                resources: shared.resources,
+                global_containers: shared.global_containers,
+            }),
+            Err(err) => warn!(
+                ?err,
+                linked_account_id = linked_account.account_id,
+                "Failed to resolve shared resources of linked account"
+            ),
+        }
+    }
+
+    Ok(linked_resources)
+}
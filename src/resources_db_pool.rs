@@ -0,0 +1,206 @@
+// Connections to account resources databases are pooled by service data URL and account. SurrealDB connections track
+// the selected namespace for every clone of the connection, so each pooled connection selects its account's namespace
+// once when it is opened, and concurrent requests for different accounts never share a connection.
+//
+// Connections unused for `IDLE_TIMEOUT` are closed, and the least recently used connection is closed when the pool is
+// full. Connections unused for `HEALTH_CHECK_INTERVAL` are checked before they are reused, and are replaced if the
+// check fails, e.g. after the SurrealDB instance restarted. Requests using a connection when it is closed by the pool
+// complete their queries, as the connection is only closed once they drop it. Embedded databases have a single
+// connection and aren't pooled, see `db::resources_db`.
+
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use surrealdb::{Surreal, engine::any::Any};
+use tracing::{info, instrument, warn};
+
+use archodex_error::anyhow;
+
+use crate::{
+    db::{DBConnection, open_resources_db_connection, verify_resources_db_account},
+    metrics::SURREALDB_POOLED_CONNECTIONS,
+};
+
+// Bounds the number of open connections when many accounts are active at once
+const MAX_CONNECTIONS: usize = 1_000;
+
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Idle connections are closed by requests for connections rather than by a background job, so backends without
+// background jobs, like the Lambda function, close them too
+const IDLE_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// Service data URL and account ID
+type Key = (String, String);
+
+struct PooledConnection {
+    // Distinguishes a connection from the connection that replaced it
+    generation: u64,
+    db: Surreal<Any>,
+    last_used_at: Instant,
+    last_checked_at: Instant,
+}
+
+struct Pool {
+    connections: HashMap<Key, PooledConnection>,
+    next_generation: u64,
+    last_swept_at: Instant,
+}
+
+impl Pool {
+    fn record_size(&self) {
+        #[allow(clippy::cast_possible_wrap)]
+        SURREALDB_POOLED_CONNECTIONS.set(self.connections.len() as i64);
+    }
+
+    fn sweep_idle(&mut self, now: Instant) {
+        if now.duration_since(self.last_swept_at) < IDLE_SWEEP_INTERVAL {
+            return;
+        }
+
+        self.last_swept_at = now;
+
+        let connections = self.connections.len();
+
+        self.connections
+            .retain(|_, connection| now.duration_since(connection.last_used_at) < IDLE_TIMEOUT);
+
+        if self.connections.len() < connections {
+            info!(
+                closed = connections - self.connections.len(),
+                "Closed idle resources database connections"
+            );
+
+            self.record_size();
+        }
+    }
+
+    fn insert(&mut self, key: Key, db: Surreal<Any>, now: Instant) {
+        if self.connections.len() >= MAX_CONNECTIONS
+            && !self.connections.contains_key(&key)
+            && let Some(least_recently_used) = self
+                .connections
+                .iter()
+                .min_by_key(|(_, connection)| connection.last_used_at)
+                .map(|(key, _)| key.clone())
+        {
+            self.connections.remove(&least_recently_used);
+        }
+
+        self.connections.insert(
+            key,
+            PooledConnection {
+                generation: self.next_generation,
+                db,
+                last_used_at: now,
+                last_checked_at: now,
+            },
+        );
+        self.next_generation += 1;
+
+        self.record_size();
+    }
+
+    fn remove(&mut self, key: &Key, generation: u64) {
+        if self
+            .connections
+            .get(key)
+            .is_some_and(|connection| connection.generation == generation)
+        {
+            self.connections.remove(key);
+
+            self.record_size();
+        }
+    }
+}
+
+static POOL: LazyLock<Mutex<Pool>> = LazyLock::new(|| {
+    Mutex::new(Pool {
+        connections: HashMap::new(),
+        next_generation: 0,
+        last_swept_at: Instant::now(),
+    })
+});
+
+fn lock_pool() -> std::sync::MutexGuard<'static, Pool> {
+    POOL.lock()
+        .expect("Resources database connection pool lock poisoned")
+}
+
+// Returns the pooled connection to an account's resources database, its generation, and whether it must be checked
+// before it is used
+fn checkout(key: &Key) -> Option<(Surreal<Any>, u64, bool)> {
+    let now = Instant::now();
+
+    let mut pool = lock_pool();
+
+    pool.sweep_idle(now);
+
+    let connection = pool.connections.get_mut(key)?;
+
+    let check = now.duration_since(connection.last_checked_at) >= HEALTH_CHECK_INTERVAL;
+
+    connection.last_used_at = now;
+    if check {
+        connection.last_checked_at = now;
+    }
+
+    Some((connection.db.clone(), connection.generation, check))
+}
+
+async fn is_healthy(db: &Surreal<Any>) -> bool {
+    match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, db.health()).await {
+        Ok(Ok(())) => true,
+        Ok(Err(err)) => {
+            warn!(?err, "Resources database connection failed health check");
+            false
+        }
+        Err(_) => {
+            warn!(timeout = ?HEALTH_CHECK_TIMEOUT, "Resources database connection health check timed out");
+            false
+        }
+    }
+}
+
+#[instrument(err)]
+pub(crate) async fn get(
+    service_data_surrealdb_url: &str,
+    account_id: &str,
+) -> anyhow::Result<Surreal<Any>> {
+    let key = (
+        service_data_surrealdb_url.to_string(),
+        account_id.to_string(),
+    );
+
+    if let Some((db, generation, check)) = checkout(&key) {
+        if !check || is_healthy(&db).await {
+            return Ok(db);
+        }
+
+        // Another request may have replaced the connection already
+        lock_pool().remove(&key, generation);
+    }
+
+    let db = open_resources_db_connection(service_data_surrealdb_url, account_id, false).await?;
+
+    verify_resources_db_account(&DBConnection::Concurrent(db.clone()), account_id).await?;
+
+    let mut pool = lock_pool();
+
+    // Requests that opened a connection concurrently use the first one pooled, and close theirs
+    if let Some(connection) = pool.connections.get_mut(&key) {
+        connection.last_used_at = Instant::now();
+
+        return Ok(connection.db.clone());
+    }
+
+    pool.insert(key, db.clone(), Instant::now());
+
+    Ok(db)
+}
@@ -1,109 +1,528 @@
-use std::time::Duration;
+use std::{convert::Infallible, time::Duration};
 
 use axum::{
     Router,
+    extract::{DefaultBodyLimit, MatchedPath, Request, State},
     http::{
-        HeaderValue,
-        header::{AUTHORIZATION, CONTENT_TYPE},
+        HeaderValue, Method, StatusCode,
+        header::{ACCESS_CONTROL_REQUEST_METHOD, AUTHORIZATION, CONTENT_TYPE},
     },
-    middleware,
-    routing::{delete, get, post},
+    middleware::{self, Next},
+    response::{IntoResponse as _, Response},
+    routing::{delete, get, patch, post, put},
 };
-use tower::ServiceBuilder;
+use tower::{Layer as _, ServiceBuilder, ServiceExt as _, service_fn};
 use tower_http::{
     cors::{AllowMethods, AllowOrigin, CorsLayer},
     trace::{DefaultOnRequest, DefaultOnResponse, TraceLayer},
 };
 use tracing::{Level, Span, error_span};
-use uuid::Uuid;
 
+#[cfg(not(feature = "archodex-com"))]
+use crate::oidc;
+#[cfg(feature = "archodex-com")]
+use crate::{account_link, billing, federation, provisioning, resource_sharing};
 use crate::{
-    accounts,
+    accounts, admin, agents, api_tokens, audit,
     auth::{DashboardAuth, ReportApiKeyAuth},
+    aws_config, bundle,
+    consistency::CONSISTENCY_TOKEN_HEADER,
     db::{dashboard_auth_account, report_api_key_account},
+    dead_letter, debug_capture, dr,
     env::Env,
-    principal_chain, query, report, report_api_keys, resource,
+    environments, event_retention, export, export_encryption, graph_stream, graphql, health,
+    import, ingestion_token, integrity, job, lifecycle,
+    limits::{self, Limit},
+    members, metrics, notifications, openapi, operations, principal_chain, query, query_cache,
+    rate_limit, readiness, report, report_api_key_requests, report_api_keys,
+    request_id::{self, REQUEST_ID_HEADER},
+    resource, resource_deletion, saml, search, sensor, shard, span_context, standby, type_path,
+    typescript, usage, webhooks,
 };
 
-/// # Panics
-///
-/// Will panic if `Env::archodex_domain()` is not a valid domain.
+// Routes requested with methods other than GET that never write. They are neither audited as mutations nor refused by
+// standby instances, and account viewers may request them. OIDC authorization codes are exchanged with the identity
+// provider without writing anything.
+const READ_ONLY_ROUTES: &[&str] = &[
+    "/account/:account_id/graphql",
+    "/oidc/:provider/token",
+    "/report/validate",
+    dr::BACKUPS_PATH,
+    dr::DRILLS_PATH,
+];
+
+pub(crate) fn is_read_only(req: &Request) -> bool {
+    matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS)
+        || req
+            .extensions()
+            .get::<MatchedPath>()
+            .is_some_and(|path| READ_ONLY_ROUTES.contains(&path.as_str()))
+}
+
+// Origin allowed to make cross-origin requests, see `Env::allowed_origins`
+#[derive(Debug)]
+pub(crate) enum AllowedOrigin {
+    Exact(String),
+    // Any subdomain of the domain, but not the domain itself, e.g. `https://*.example.com`
+    Subdomains { scheme: String, domain: String },
+}
+
+impl AllowedOrigin {
+    fn matches(&self, origin: &HeaderValue) -> bool {
+        let Ok(origin) = origin.to_str() else {
+            return false;
+        };
+
+        match self {
+            AllowedOrigin::Exact(allowed) => origin.eq_ignore_ascii_case(allowed),
+            AllowedOrigin::Subdomains { scheme, domain } => origin
+                .to_ascii_lowercase()
+                .strip_prefix(scheme.as_str())
+                .and_then(|host| host.strip_suffix(domain.as_str()))
+                .and_then(|subdomain| subdomain.strip_suffix('.'))
+                .is_some_and(|subdomain| {
+                    !subdomain.is_empty()
+                        && subdomain
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+                }),
+        }
+    }
+}
+
+// Parses an origin of the form `<scheme>://<host>[:<port>]`, e.g. `https://dashboard.example.com`, where the host may
+// start with a `*.` wildcard to allow all subdomains, e.g. `https://*.example.com`
+pub(crate) fn parse_allowed_origin(origin: &str) -> Option<AllowedOrigin> {
+    let origin = origin.trim().to_ascii_lowercase();
+
+    let (scheme, host) = origin.split_once("://")?;
+
+    let (wildcard, host) = match host.strip_prefix("*.") {
+        Some(domain) => (true, domain),
+        None => (false, host),
+    };
+
+    if !matches!(scheme, "http" | "https")
+        || host.is_empty()
+        || !host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | ':'))
+    {
+        return None;
+    }
+
+    Some(if wildcard {
+        AllowedOrigin::Subdomains {
+            scheme: format!("{scheme}://"),
+            domain: host.to_string(),
+        }
+    } else {
+        AllowedOrigin::Exact(format!("{scheme}://{host}"))
+    })
+}
+
 pub fn router() -> Router {
     let cors_layer = CorsLayer::new()
         .allow_methods(AllowMethods::mirror_request())
-        .allow_origin(AllowOrigin::list([
-            HeaderValue::from_str(&format!("https://app.{}", Env::archodex_domain()))
-                .expect("Failed to parse archodex domain as HeaderValue"),
-            HeaderValue::from_str("http://localhost:5173")
-                .expect("Failed to parse localhost as HeaderValue"),
-        ]))
-        .allow_headers([AUTHORIZATION, CONTENT_TYPE])
-        .allow_credentials(true);
+        .allow_origin(AllowOrigin::predicate(|origin, _| {
+            Env::allowed_origins()
+                .iter()
+                .any(|allowed_origin| allowed_origin.matches(origin))
+        }))
+        .allow_headers([
+            AUTHORIZATION,
+            CONTENT_TYPE,
+            CONSISTENCY_TOKEN_HEADER,
+            REQUEST_ID_HEADER,
+        ])
+        .expose_headers([
+            REQUEST_ID_HEADER,
+            export_encryption::EXPORT_KEY_VERSION_HEADER,
+        ])
+        .allow_credentials(true)
+        .max_age(Env::cors_max_age());
 
     #[cfg(not(feature = "archodex-com"))]
     let cors_layer = cors_layer.allow_private_network(true);
 
-    let dashboard_authed_router = Router::new()
-        .nest(
-            "/account/:account_id",
-            Router::new()
-                .route(
-                    "/resource/set_environments",
-                    post(resource::set_environments),
-                )
-                .route("/query/:type", get(query::query))
-                .route("/principal_chain", get(principal_chain::get))
-                .route(
-                    "/report_api_keys",
-                    get(report_api_keys::list_report_api_keys),
-                )
-                .route(
-                    "/report_api_keys",
-                    post(report_api_keys::create_report_api_key),
-                )
-                .route(
-                    "/report_api_key/:report_api_key_id",
-                    delete(report_api_keys::revoke_report_api_key),
-                )
-                .route("/", delete(accounts::delete_account)),
+    let account_router = Router::new()
+        .route(
+            "/resource/set_environments",
+            post(resource::set_environments),
+        )
+        .route("/resource/tags", post(resource::set_tags))
+        .route("/environments", get(environments::list_environments))
+        .route("/environments", post(environments::create_environment))
+        .route(
+            "/environment/:environment",
+            put(environments::update_environment),
+        )
+        .route(
+            "/environment/:environment",
+            delete(environments::delete_environment),
+        )
+        .route("/resource/archive", post(lifecycle::archive_resource))
+        .route("/resource/unarchive", post(lifecycle::unarchive_resource))
+        .route(
+            "/resources/delete",
+            post(resource_deletion::delete_resources),
+        )
+        .route("/resources", get(resource::list_resources))
+        .route(
+            "/import",
+            post(import::import)
+                .layer(DefaultBodyLimit::max(Limit::ImportBodyBytes.max()))
+                .layer(middleware::from_fn_with_state(
+                    Limit::ImportBodyBytes,
+                    limits::body_limit_error,
+                )),
+        )
+        .route("/search", get(search::search_resources))
+        .route("/graphql", post(graphql::graphql))
+        .route("/query/:type", get(query::query))
+        .route("/principal_chain", get(principal_chain::get))
+        .route("/principal_chain/traverse", get(principal_chain::traverse))
+        .route("/type_paths", get(type_path::list_type_paths))
+        .route("/export", get(export::export))
+        .route(
+            "/export_encryption_key",
+            get(export_encryption::get_export_encryption_key),
+        )
+        .route(
+            "/export_encryption_key",
+            put(export_encryption::set_export_encryption_key),
+        )
+        .route(
+            "/export_encryption_key",
+            delete(export_encryption::delete_export_encryption_key),
+        )
+        .route(
+            "/report_api_keys",
+            get(report_api_keys::list_report_api_keys),
+        )
+        .route(
+            "/report_api_keys",
+            post(report_api_keys::create_report_api_key),
+        )
+        .route(
+            "/report_api_key/:report_api_key_id",
+            delete(report_api_keys::revoke_report_api_key),
+        )
+        .route(
+            "/report_api_key/:report_api_key_id/value",
+            post(report_api_keys::reveal_report_api_key_value),
+        )
+        .route(
+            "/report_api_key/:report_api_key_id/restore",
+            post(report_api_keys::restore_report_api_key),
+        )
+        .route(
+            "/report_api_key/:report_api_key_id/rotate",
+            post(report_api_keys::rotate_report_api_key),
+        )
+        .route(
+            "/report_api_key_requests",
+            get(report_api_key_requests::list_report_api_key_requests),
+        )
+        .route(
+            "/report_api_key_request/:report_api_key_request_id/approve",
+            post(report_api_key_requests::approve_report_api_key_request),
+        )
+        .route(
+            "/report_api_key_request/:report_api_key_request_id/reject",
+            post(report_api_key_requests::reject_report_api_key_request),
+        )
+        .route(
+            "/report_api_key_request/:report_api_key_request_id/value",
+            post(report_api_key_requests::retrieve_report_api_key_value),
+        )
+        .route("/ingestion_pipeline", get(accounts::get_ingestion_pipeline))
+        .route("/ingestion_pipeline", put(accounts::set_ingestion_pipeline))
+        .route(
+            "/report_api_key_approval",
+            put(accounts::set_report_api_key_approval),
+        )
+        .route("/statements", get(usage::list_statements))
+        .route("/usage", get(usage::get_usage))
+        .route("/agents", get(agents::list_agents))
+        .route("/agent/:agent_id/retire", post(agents::retire_agent))
+        .route("/coverage", get(sensor::coverage))
+        .route("/coverage/blind_spots", get(sensor::blind_spots))
+        .route("/operations", post(operations::execute))
+        .route("/health", get(health::health))
+        .route("/stream", get(graph_stream::stream))
+        .route("/members", get(members::list_members))
+        .route("/member/:user_id", put(members::set_member_role))
+        .route("/member/:user_id", delete(members::remove_member))
+        .route("/invitations", get(members::list_invitations))
+        .route("/invitations", post(members::create_invitation))
+        .route(
+            "/invitation/:invitation_id",
+            delete(members::revoke_invitation),
+        )
+        .route("/tokens", get(api_tokens::list_api_tokens))
+        .route("/tokens", post(api_tokens::create_api_token))
+        .route("/token/:token_id", delete(api_tokens::revoke_api_token))
+        .route("/saml", get(saml::get_saml_config))
+        .route("/saml", put(saml::set_saml_config))
+        .route("/saml", delete(saml::delete_saml_config))
+        .route("/webhooks", get(webhooks::list_webhooks))
+        .route("/webhooks", post(webhooks::create_webhook))
+        .route("/webhook/:webhook_id", put(webhooks::update_webhook))
+        .route("/webhook/:webhook_id", delete(webhooks::delete_webhook))
+        .route(
+            "/default_environment",
+            put(accounts::set_default_environment),
+        )
+        .route("/resource_staleness", put(accounts::set_resource_staleness))
+        .route("/dead_lettering", put(accounts::set_dead_lettering))
+        .route(
+            "/dead_letter_reports",
+            get(dead_letter::list_dead_letter_reports),
+        )
+        .route(
+            "/dead_letter_reports",
+            delete(dead_letter::purge_dead_letter_reports),
+        )
+        .route(
+            "/dead_letter_report/:dead_letter_report_id",
+            get(dead_letter::get_dead_letter_report),
+        )
+        .route(
+            "/dead_letter_report/:dead_letter_report_id",
+            delete(dead_letter::purge_dead_letter_report),
         )
+        .route(
+            "/dead_letter_report/:dead_letter_report_id/retry",
+            post(dead_letter::retry_dead_letter_report),
+        )
+        .route("/audit_log", get(audit::list_audit_log))
+        .route("/audit_log_retention", get(audit::get_audit_log_retention))
+        .route("/audit_log_retention", put(audit::set_audit_log_retention))
+        .route(
+            "/event_retention",
+            get(event_retention::get_event_retention),
+        )
+        .route(
+            "/event_retention",
+            put(event_retention::set_event_retention),
+        )
+        .route("/integrity_checks", get(integrity::list_integrity_checks))
+        .route("/integrity_checks", post(integrity::start_integrity_check))
+        .route(
+            "/integrity_check/:integrity_check_id",
+            get(integrity::get_integrity_check),
+        )
+        .route("/", patch(accounts::update_account))
+        .route("/", delete(accounts::delete_account));
+
+    #[cfg(feature = "archodex-com")]
+    let account_router = account_router
+        .route("/account_links", get(account_link::list_account_links))
+        .route("/account_links", post(account_link::create_account_link))
+        .route(
+            "/account_link/:account_link_id",
+            delete(account_link::revoke_account_link),
+        )
+        .route(
+            "/account_link/:account_link_id/federated/:linked_account_id/*path",
+            get(federation::federated_get),
+        )
+        .route(
+            "/linked_accounts",
+            get(resource_sharing::list_linked_accounts),
+        )
+        .route("/linked_accounts", post(resource_sharing::link_account))
+        .route(
+            "/linked_account/:linked_account_id",
+            delete(resource_sharing::unlink_account),
+        )
+        .route("/resource/share", post(resource_sharing::share_resources));
+
+    let account_router = account_router
+        .layer(ServiceBuilder::new().layer(middleware::from_fn(audit::record_mutation)))
+        .layer(ServiceBuilder::new().layer(middleware::from_fn(query_cache::invalidate_on_write)))
+        .layer(ServiceBuilder::new().layer(middleware::from_fn(debug_capture::capture)))
+        .layer(ServiceBuilder::new().layer(middleware::from_fn(span_context::record_tenant)));
+
+    let dashboard_authed_router = Router::new()
+        .nest("/account/:account_id", account_router)
         .layer(ServiceBuilder::new().layer(middleware::from_fn(dashboard_auth_account)))
         .route("/accounts", get(accounts::list_accounts))
         .route("/accounts", post(accounts::create_account))
+        .route("/search", get(search::search))
+        .route("/invitations/accept", post(members::accept_invitation))
+        .route("/jobs/:job_id/events", get(job::events))
+        .route("/notifications", get(notifications::list_notifications))
+        .route(
+            "/notifications/unread_count",
+            get(notifications::unread_notification_count),
+        )
+        .route(
+            "/notifications/read",
+            post(notifications::mark_all_notifications_read),
+        )
+        .route(
+            "/notification/:notification_id/read",
+            post(notifications::mark_notification_read),
+        )
+        .route(
+            "/notification/:notification_id/unread",
+            post(notifications::mark_notification_unread),
+        );
+
+    // Accounts are polled while they are provisioned, before `dashboard_auth_account` can find their records
+    #[cfg(feature = "archodex-com")]
+    let dashboard_authed_router = dashboard_authed_router.route(
+        "/account/:account_id/status",
+        get(provisioning::account_status),
+    );
+
+    let dashboard_authed_router = dashboard_authed_router
+        .layer(DefaultBodyLimit::max(Limit::RequestBodyBytes.max()))
+        .layer(middleware::from_fn_with_state(
+            Limit::RequestBodyBytes,
+            limits::body_limit_error,
+        ))
+        .layer(ServiceBuilder::new().layer(middleware::from_fn(span_context::record_tenant)))
         .layer(ServiceBuilder::new().layer(middleware::from_fn(DashboardAuth::authenticate)))
         .route("/health", get(|| async { "Ok" }))
+        .route("/health/ready", get(readiness::ready))
         .layer(cors_layer.clone());
 
+    let unauthed_router = Router::new()
+        .route("/report/schema/:version", get(report::schema))
+        .route("/types.d.ts", get(typescript::types))
+        .route("/openapi.json", get(openapi::openapi))
+        .route("/docs", get(openapi::docs))
+        .route("/docs/swagger-ui.css", get(openapi::swagger_ui_css))
+        .route(
+            "/docs/swagger-ui-bundle.js",
+            get(openapi::swagger_ui_bundle),
+        )
+        .route("/graphql/schema.graphql", get(graphql::schema))
+        .route(standby::PROMOTE_PATH, post(standby::promote));
+
+    // Link sync requests are authenticated by their link token
+    #[cfg(feature = "archodex-com")]
+    let unauthed_router =
+        unauthed_router.route("/link/sync", post(account_link::sync_account_link));
+
+    // Authorization codes are exchanged before the dashboard has an access token
+    #[cfg(not(feature = "archodex-com"))]
+    let unauthed_router = unauthed_router
+        .route("/oidc/providers", get(oidc::list_providers))
+        .route("/oidc/:provider/token", post(oidc::exchange_code));
+
+    // SAML responses are authenticated by the IdP's signature, and sign in codes are exchanged before the dashboard has
+    // a session token
+    let unauthed_router = unauthed_router
+        .route("/saml/:account_id/metadata", get(saml::metadata))
+        .route("/saml/:account_id/login", get(saml::login))
+        .route("/saml/:account_id/acs", post(saml::acs))
+        .route("/saml/:account_id/session", post(saml::create_session));
+
+    // Billing provider webhooks are authenticated by their signature rather than by user or report key credentials
+    #[cfg(feature = "archodex-com")]
+    let unauthed_router = unauthed_router.route("/billing/webhook", post(billing::webhook));
+
+    let unauthed_router = unauthed_router
+        .layer(DefaultBodyLimit::max(Limit::RequestBodyBytes.max()))
+        .layer(middleware::from_fn_with_state(
+            Limit::RequestBodyBytes,
+            limits::body_limit_error,
+        ));
+
     let report_api_key_authed_router = Router::new()
         .route("/report", post(report::report))
+        .route(
+            "/report/batch",
+            post(report::report_batch).layer(DefaultBodyLimit::disable()),
+        )
+        .route("/report/validate", post(report::validate))
+        .route(
+            "/report/aws_config",
+            post(aws_config::import)
+                .layer(DefaultBodyLimit::max(Limit::AwsConfigFileBytes.max()))
+                .layer(middleware::from_fn_with_state(
+                    Limit::AwsConfigFileBytes,
+                    limits::body_limit_error,
+                )),
+        )
+        .route("/report/token", post(ingestion_token::exchange))
+        .route("/report/agent", post(agents::register))
+        .layer(DefaultBodyLimit::max(Limit::ReportBodyBytes.max()))
+        .layer(middleware::from_fn_with_state(
+            Limit::ReportBodyBytes,
+            limits::body_limit_error,
+        ))
+        .layer(ServiceBuilder::new().layer(middleware::from_fn(query_cache::invalidate_on_write)))
+        .layer(ServiceBuilder::new().layer(middleware::from_fn(debug_capture::capture)))
+        .layer(ServiceBuilder::new().layer(middleware::from_fn(span_context::record_tenant)))
         .layer(ServiceBuilder::new().layer(middleware::from_fn(report_api_key_account)))
+        .layer(ServiceBuilder::new().layer(middleware::from_fn(shard::route_report_to_shard)))
+        .layer(ServiceBuilder::new().layer(middleware::from_fn(rate_limit::limit_report_api_key)))
         .layer(ServiceBuilder::new().layer(middleware::from_fn(ReportApiKeyAuth::authenticate)));
 
+    let admin_router = Router::new()
+        .route("/metrics", get(metrics::metrics))
+        .route("/admin/accounts", get(admin::list_accounts))
+        .route(dr::BACKUPS_PATH, get(dr::list_backups))
+        .route(dr::BACKUPS_PATH, post(dr::start_backup))
+        .route("/admin/dr/readiness", get(dr::get_restore_readiness))
+        .route(dr::DRILLS_PATH, post(dr::start_drill))
+        .route("/admin/dr/job/:job_id", get(dr::get_job))
+        .route(
+            "/admin/account/:account_id/migrate",
+            post(admin::migrate_account),
+        )
+        .route(
+            "/admin/account/:account_id/report_api_key/:report_api_key_id/revoke",
+            post(admin::revoke_report_api_key),
+        )
+        .route(
+            "/admin/account/:account_id/bundle",
+            post(bundle::import)
+                .layer(DefaultBodyLimit::max(Limit::BundleBodyBytes.max()))
+                .layer(middleware::from_fn_with_state(
+                    Limit::BundleBodyBytes,
+                    limits::body_limit_error,
+                )),
+        );
+
+    #[cfg(feature = "archodex-com")]
+    let admin_router = admin_router.route(
+        "/admin/account/:account_id/provisioning",
+        get(provisioning::admin_provisioning_status),
+    );
+
+    let admin_router = admin_router.route_layer(middleware::from_fn(admin::authenticate));
+
     let default_on_response_trace_handler = DefaultOnResponse::new().level(Level::INFO);
 
     Router::new()
         .merge(dashboard_authed_router)
         .merge(report_api_key_authed_router)
+        .merge(unauthed_router)
+        .merge(admin_router)
+        .route_layer(middleware::from_fn(standby::refuse_writes))
+        .route_layer(middleware::from_fn(metrics::record_request))
+        .layer(middleware::from_fn(span_context::capture_request_span))
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(|request: &axum::http::Request<_>| {
                     use tracing::field::Empty;
 
-                    let span = error_span!(
+                    error_span!(
                         "request",
                         method = %request.method(),
                         uri = %request.uri(),
-                        auth = Empty,
-                        request_id = %Uuid::now_v7(),
-                        "X-Request-ID" = Empty,
+                        account_id = Empty,
+                        user_id = Empty,
+                        report_api_key_id = Empty,
+                        request_id = request_id::request_id(request),
                         version = ?request.version(),
-                    );
-
-                    if let Some(x_request_id) = request.headers().get("X-Request-ID") {
-                        span.record("X-Request-ID", tracing::field::debug(x_request_id));
-                    }
-
-                    span
+                    )
                 })
                 .on_request(DefaultOnRequest::new().level(Level::INFO))
                 .on_response(
@@ -117,4 +536,26 @@ pub fn router() -> Router {
                     },
                 ),
         )
+        .layer(middleware::from_fn_with_state(cors_layer, preflight))
+        .layer(middleware::from_fn(request_id::assign_request_id))
+}
+
+// Answers CORS preflight requests before any other middleware runs. Preflight responses only depend on the CORS
+// configuration, so there is no need to trace, meter, authenticate, or look up accounts for them. Actual cross-origin
+// requests still receive their CORS headers from the CORS layer of the dashboard routes.
+async fn preflight(State(cors_layer): State<CorsLayer>, req: Request, next: Next) -> Response {
+    if req.method() != Method::OPTIONS || !req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+    {
+        return next.run(req).await;
+    }
+
+    // The CORS layer responds to preflight requests itself without calling the inner service
+    let Ok(response) = cors_layer
+        .layer(service_fn(|_req: Request| async {
+            Ok::<_, Infallible>(StatusCode::NO_CONTENT.into_response())
+        }))
+        .oneshot(req)
+        .await;
+
+    response
 }
@@ -0,0 +1,1091 @@
+// SAML 2.0 sign in, for enterprises whose users sign in to the dashboard with their own identity provider (IdP), e.g.
+// Okta, Microsoft Entra ID, or ADFS, rather than with Cognito or an OIDC provider (see `oidc`). The backend is the
+// service provider. Account admins register it with their IdP using the metadata at `GET /saml/:account_id/metadata`,
+// and configure the IdP for the account with `PUT /account/:account_id/saml`. SAML sign in is only enabled when
+// `SAML_BASE_URL` is set, see `Env::saml_base_url`.
+//
+// Users sign in at `GET /saml/:account_id/login`, which redirects them to the IdP, or from the IdP's portal. The IdP
+// posts its signed response to `POST /saml/:account_id/acs`, which maps the assertion to a user of the account and
+// redirects to the dashboard with a single-use code. The dashboard exchanges the code for a session token at
+// `POST /saml/:account_id/session`, and presents the token in place of an access token. Session tokens have the form
+// `archodex_saml_<session ID>.<hex encoded secret>`, are limited to their account, and expire after `SESSION_TTL` or
+// when the IdP ends the session, whichever is first. Only hashes of codes and session tokens are stored.
+//
+// Users are identified by the assertion's NameID, or by the attribute the account maps to user IDs. Their user IDs are
+// derived from the account and IdP, so an account's IdP can't sign in as users of other IdPs or accounts. Users who
+// aren't members of the account are added with the account's default SAML role, or refused if it has none. Assertion
+// IDs are recorded until the assertions expire, so responses can't be replayed. Encrypted assertions are not supported.
+
+use std::{collections::HashMap, io::Write as _};
+
+use axum::{
+    Extension, Form, Json,
+    extract::{Path, Query},
+    http::header::CONTENT_TYPE,
+    response::{IntoResponse, Redirect},
+};
+use base64::{Engine as _, prelude::BASE64_STANDARD};
+use chrono::{DateTime, SecondsFormat, TimeDelta, Utc};
+use flate2::{Compression, write::DeflateEncoder};
+use openssl::{pkey::Id, x509::X509};
+use rand::Rng as _;
+use roxmltree::{Document, Node};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+use archodex_error::{
+    anyhow::{self, Context as _, bail, ensure},
+    bad_request, forbidden, not_found, unauthorized,
+};
+
+use crate::{
+    Result,
+    account::{Account, is_well_formed_account_id},
+    audit::{self, AuditRequest},
+    auth::{AccountRole, DashboardAuth, StepUp},
+    db::{QueryCheckFirstRealError as _, accounts_db},
+    env::Env,
+    limits::Limit,
+    metrics::AUTH_FAILURES_TOTAL,
+    surrealdb_deserializers,
+    user::User,
+    xml_dsig::{self, child},
+};
+
+pub(crate) const SAML_SESSION_PREFIX: &str = "archodex_saml_";
+
+const SESSION_TTL: TimeDelta = TimeDelta::hours(8);
+const CODE_TTL: TimeDelta = TimeDelta::minutes(1);
+
+// Tolerated difference between the clocks of the IdP and the backend
+const CLOCK_SKEW: TimeDelta = TimeDelta::minutes(2);
+
+// IdPs rotating their signing certificate sign with either certificate while the rotation is in progress
+const MAX_CERTIFICATES: usize = 2;
+const MAX_ENTITY_ID_LENGTH: usize = 1024;
+const MAX_ATTRIBUTE_NAME_LENGTH: usize = 256;
+// SAML bindings limit `RelayState` to 80 bytes
+const MAX_RETURN_TO_LENGTH: usize = 80;
+
+const PROTOCOL_NS: &str = "urn:oasis:names:tc:SAML:2.0:protocol";
+const ASSERTION_NS: &str = "urn:oasis:names:tc:SAML:2.0:assertion";
+const METADATA_NS: &str = "urn:oasis:names:tc:SAML:2.0:metadata";
+const HTTP_POST_BINDING: &str = "urn:oasis:names:tc:SAML:2.0:bindings:HTTP-POST";
+const SUCCESS_STATUS: &str = "urn:oasis:names:tc:SAML:2.0:status:Success";
+const BEARER_CONFIRMATION: &str = "urn:oasis:names:tc:SAML:2.0:cm:bearer";
+const PERSISTENT_NAME_ID_FORMAT: &str = "urn:oasis:names:tc:SAML:2.0:nameid-format:persistent";
+
+// Which assertion attributes identify users. Attributes are named as the IdP sends them, e.g.
+// `http://schemas.xmlsoap.org/ws/2005/05/identity/claims/emailaddress`.
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SamlAttributeMapping {
+    // Attribute holding the stable user ID. Users are identified by the assertion's NameID when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user_id: Option<String>,
+    // Attribute holding the user's email, which is shown to account admins in the member list
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
+pub(crate) struct SamlIdentityProvider {
+    entity_id: String,
+    // Single sign-on endpoint of the IdP's HTTP-Redirect binding
+    sso_url: String,
+    // PEM encoded certificates the IdP signs responses or assertions with
+    certificates: Vec<String>,
+    #[serde(default)]
+    attribute_mapping: SamlAttributeMapping,
+    // Role of users added to the account when they first sign in. Users who aren't members are refused when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    default_role: Option<AccountRole>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SetSamlConfigRequest {
+    entity_id: String,
+    sso_url: String,
+    certificates: Vec<String>,
+    #[serde(default)]
+    attribute_mapping: SamlAttributeMapping,
+    default_role: Option<AccountRole>,
+}
+
+// Service provider details to register with the IdP
+#[derive(Debug, JsonSchema, Serialize)]
+pub(crate) struct SamlServiceProvider {
+    entity_id: String,
+    acs_url: String,
+    metadata_url: String,
+    login_url: String,
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct SamlConfig {
+    service_provider: SamlServiceProvider,
+    // Unset until an account admin configures the account's IdP
+    identity_provider: Option<SamlIdentityProvider>,
+}
+
+fn service_provider(account_id: &str) -> Result<SamlServiceProvider> {
+    let Some(base_url) = Env::saml_base_url() else {
+        not_found!("SAML sign in is not enabled");
+    };
+
+    let entity_id = format!("{base_url}/saml/{account_id}");
+
+    Ok(SamlServiceProvider {
+        acs_url: format!("{entity_id}/acs"),
+        metadata_url: format!("{entity_id}/metadata"),
+        login_url: format!("{entity_id}/login"),
+        entity_id,
+    })
+}
+
+// Account IDs of the unauthenticated SAML routes are only used in URLs and queries once they are known to be well
+// formed
+fn account_id(params: &HashMap<String, String>) -> Result<&str> {
+    let Some(account_id) = params.get("account_id") else {
+        bail!("Missing account_id");
+    };
+
+    if !is_well_formed_account_id(account_id) {
+        not_found!("Account not found");
+    }
+
+    Ok(account_id)
+}
+
+fn identity_provider_thing(account_id: &str) -> surrealdb::sql::Thing {
+    surrealdb::sql::Thing::from(("saml_identity_provider", account_id))
+}
+
+fn session_thing(session_id: &str) -> surrealdb::sql::Thing {
+    surrealdb::sql::Thing::from(("saml_session", session_id))
+}
+
+fn account_thing(account_id: &str) -> surrealdb::sql::Thing {
+    surrealdb::sql::Thing::from(("account", account_id))
+}
+
+#[instrument(err)]
+async fn identity_provider(account_id: &str) -> Result<Option<SamlIdentityProvider>> {
+    Ok(accounts_db()
+        .await?
+        .query("SELECT * FROM $identity_provider WHERE account.deleted_at IS NONE")
+        .bind(("identity_provider", identity_provider_thing(account_id)))
+        .await
+        .context("Failed to submit query to get SAML identity provider")?
+        .check_first_real_error()
+        .context("Failed to get SAML identity provider")?
+        .take::<Vec<SamlIdentityProvider>>(0)?
+        .pop())
+}
+
+fn parse_certificates(certificates: &[String]) -> anyhow::Result<Vec<X509>> {
+    certificates
+        .iter()
+        .map(|certificate| {
+            let certificate = X509::from_pem(certificate.as_bytes())
+                .context("Failed to parse PEM encoded certificate")?;
+
+            ensure!(
+                certificate.public_key()?.id() == Id::RSA,
+                "Only certificates with RSA keys are supported"
+            );
+
+            Ok(certificate)
+        })
+        .collect()
+}
+
+// Users of different accounts or IdPs never share a user ID, even if the IdP asserts the same user ID, or the user ID
+// of a user of another identity provider
+fn saml_user(account_id: &str, entity_id: &str, user_id: &str) -> User {
+    let hash = Sha256::digest(format!("saml\0{account_id}\0{entity_id}\0{user_id}"));
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&hash[..16]);
+
+    User::new(uuid::Builder::from_custom_bytes(bytes).into_uuid())
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Dashboard paths users return to after signing in, which must stay on the dashboard
+fn is_valid_return_to(return_to: &str) -> bool {
+    return_to.len() <= MAX_RETURN_TO_LENGTH
+        && return_to.starts_with('/')
+        && !return_to.starts_with("//")
+        && !return_to.contains('\\')
+}
+
+#[instrument(err, skip_all)]
+pub(crate) async fn get_saml_config(
+    Extension(account): Extension<Account>,
+) -> Result<Json<SamlConfig>> {
+    Ok(Json(SamlConfig {
+        service_provider: service_provider(account.id())?,
+        identity_provider: identity_provider(account.id()).await?,
+    }))
+}
+
+// Replacing the IdP hands sign in to the account to whoever controls the new IdP, so it requires a recent sign in
+#[instrument(err, skip(auth, account, req))]
+pub(crate) async fn set_saml_config(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    _step_up: StepUp,
+    Json(req): Json<SetSamlConfigRequest>,
+) -> Result<Json<SamlConfig>> {
+    auth.ensure_account_admin(account.id()).await?;
+
+    let service_provider = service_provider(account.id())?;
+
+    let entity_id = req.entity_id.trim().to_string();
+    if entity_id.is_empty() || entity_id.len() > MAX_ENTITY_ID_LENGTH {
+        bad_request!("IdP entity IDs must be between 1 and {MAX_ENTITY_ID_LENGTH} bytes");
+    }
+
+    if !reqwest::Url::parse(&req.sso_url).is_ok_and(|sso_url| sso_url.scheme() == "https") {
+        bad_request!("IdP single sign-on URLs must be HTTPS URLs");
+    }
+
+    if req.certificates.is_empty() || req.certificates.len() > MAX_CERTIFICATES {
+        bad_request!("IdPs must have between 1 and {MAX_CERTIFICATES} signing certificates");
+    }
+
+    if let Err(err) = parse_certificates(&req.certificates) {
+        warn!(?err, "Invalid IdP signing certificate");
+        bad_request!("Invalid IdP signing certificate: {err}");
+    }
+
+    let attribute_mapping = req.attribute_mapping;
+    if [&attribute_mapping.user_id, &attribute_mapping.email]
+        .into_iter()
+        .flatten()
+        .any(|name| name.is_empty() || name.len() > MAX_ATTRIBUTE_NAME_LENGTH)
+    {
+        bad_request!(
+            "Mapped attribute names must be between 1 and {MAX_ATTRIBUTE_NAME_LENGTH} bytes"
+        );
+    }
+
+    let identity_provider = accounts_db()
+        .await?
+        .query("UPSERT $identity_provider CONTENT { account: $account, entity_id: $entity_id, sso_url: $sso_url, certificates: $certificates, attribute_mapping: $attribute_mapping, default_role: $default_role, updated_by: $user }")
+        .bind(("identity_provider", identity_provider_thing(account.id())))
+        .bind(("account", surrealdb::sql::Thing::from(&account)))
+        .bind(("entity_id", entity_id))
+        .bind(("sso_url", req.sso_url))
+        .bind(("certificates", req.certificates))
+        .bind(("attribute_mapping", attribute_mapping))
+        .bind(("default_role", req.default_role))
+        .bind(("user", surrealdb::sql::Thing::from(auth.principal())))
+        .await
+        .context("Failed to submit query to set SAML identity provider")?
+        .check_first_real_error()
+        .context("Failed to set SAML identity provider")?
+        .take::<Option<SamlIdentityProvider>>(0)?
+        .expect("Upsert SAML identity provider query should return an identity provider");
+
+    info!(
+        entity_id = identity_provider.entity_id,
+        "Set SAML identity provider"
+    );
+
+    Ok(Json(SamlConfig {
+        service_provider,
+        identity_provider: Some(identity_provider),
+    }))
+}
+
+// Also ends the sessions of users signed in with SAML
+#[instrument(err, skip_all)]
+pub(crate) async fn delete_saml_config(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    _step_up: StepUp,
+) -> Result<Json<()>> {
+    auth.ensure_account_admin(account.id()).await?;
+
+    let deleted = accounts_db()
+        .await?
+        .query("DELETE $identity_provider RETURN BEFORE")
+        .query("DELETE saml_session WHERE account == $account")
+        .bind(("identity_provider", identity_provider_thing(account.id())))
+        .bind(("account", surrealdb::sql::Thing::from(&account)))
+        .await
+        .context("Failed to submit query to delete SAML identity provider")?
+        .check_first_real_error()
+        .context("Failed to delete SAML identity provider")?
+        .take::<Vec<surrealdb::sql::Object>>(0)?;
+
+    if deleted.is_empty() {
+        not_found!("SAML identity provider not found");
+    }
+
+    info!("Deleted SAML identity provider");
+
+    Ok(Json(()))
+}
+
+// Metadata only describes the service provider, so it is served whether or not the account has configured its IdP
+#[instrument(err)]
+pub(crate) async fn metadata(
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<impl IntoResponse> {
+    let service_provider = service_provider(account_id(&params)?)?;
+
+    let metadata = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<md:EntityDescriptor xmlns:md="{METADATA_NS}" entityID="{entity_id}">
+  <md:SPSSODescriptor AuthnRequestsSigned="false" WantAssertionsSigned="true" protocolSupportEnumeration="{PROTOCOL_NS}">
+    <md:NameIDFormat>{PERSISTENT_NAME_ID_FORMAT}</md:NameIDFormat>
+    <md:AssertionConsumerService Binding="{HTTP_POST_BINDING}" Location="{acs_url}" index="0" isDefault="true"/>
+  </md:SPSSODescriptor>
+</md:EntityDescriptor>
+"#,
+        entity_id = escape_xml(&service_provider.entity_id),
+        acs_url = escape_xml(&service_provider.acs_url),
+    );
+
+    Ok(([(CONTENT_TYPE, "application/samlmetadata+xml")], metadata))
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct LoginParams {
+    // Dashboard path to return to after signing in
+    return_to: Option<String>,
+    // Whether the IdP must authenticate the user again rather than reuse its session, e.g. to step up
+    #[serde(default)]
+    force_authn: bool,
+}
+
+// Redirects to the IdP with an authentication request of the HTTP-Redirect binding
+#[instrument(err)]
+pub(crate) async fn login(
+    Path(params): Path<HashMap<String, String>>,
+    Query(login_params): Query<LoginParams>,
+) -> Result<Redirect> {
+    let account_id = account_id(&params)?;
+    let service_provider = service_provider(account_id)?;
+
+    if let Some(return_to) = &login_params.return_to
+        && !is_valid_return_to(return_to)
+    {
+        bad_request!(
+            "Invalid return_to, it must be a dashboard path of at most {MAX_RETURN_TO_LENGTH} bytes"
+        );
+    }
+
+    let Some(identity_provider) = identity_provider(account_id).await? else {
+        not_found!("SAML sign in is not configured for this account");
+    };
+
+    let authn_request = format!(
+        r#"<samlp:AuthnRequest xmlns:samlp="{PROTOCOL_NS}" xmlns:saml="{ASSERTION_NS}" ID="_{id}" Version="2.0" IssueInstant="{issue_instant}" Destination="{destination}" AssertionConsumerServiceURL="{acs_url}" ProtocolBinding="{HTTP_POST_BINDING}" ForceAuthn="{force_authn}"><saml:Issuer>{entity_id}</saml:Issuer><samlp:NameIDPolicy AllowCreate="true"/></samlp:AuthnRequest>"#,
+        id = Uuid::new_v4().simple(),
+        issue_instant = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        destination = escape_xml(&identity_provider.sso_url),
+        acs_url = escape_xml(&service_provider.acs_url),
+        force_authn = login_params.force_authn,
+        entity_id = escape_xml(&service_provider.entity_id),
+    );
+
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(authn_request.as_bytes())
+        .context("Failed to deflate SAML authentication request")?;
+    let authn_request = encoder
+        .finish()
+        .context("Failed to deflate SAML authentication request")?;
+
+    let mut sso_url = reqwest::Url::parse(&identity_provider.sso_url)
+        .context("Failed to parse IdP single sign-on URL")?;
+
+    sso_url
+        .query_pairs_mut()
+        .append_pair("SAMLRequest", &BASE64_STANDARD.encode(authn_request));
+
+    if let Some(return_to) = &login_params.return_to {
+        sso_url
+            .query_pairs_mut()
+            .append_pair("RelayState", return_to);
+    }
+
+    Ok(Redirect::to(sso_url.as_str()))
+}
+
+struct VerifiedAssertion {
+    id: String,
+    user_id: String,
+    email: Option<String>,
+    authenticated_at: DateTime<Utc>,
+    // When the assertion may no longer be used to sign in
+    expires_at: DateTime<Utc>,
+    // When the IdP ends the user's session, if it limits it
+    session_expires_at: Option<DateTime<Utc>>,
+}
+
+fn parse_time(value: Option<&str>) -> anyhow::Result<Option<DateTime<Utc>>> {
+    value
+        .map(|value| {
+            DateTime::parse_from_rfc3339(value)
+                .map(|time| time.with_timezone(&Utc))
+                .with_context(|| format!("Failed to parse SAML time {value:?}"))
+        })
+        .transpose()
+}
+
+// Trimmed text of the element, or None if it is missing or empty
+fn text<'a>(node: Option<Node<'a, '_>>) -> anyhow::Result<Option<&'a str>> {
+    let Some(node) = node else {
+        return Ok(None);
+    };
+
+    let text = xml_dsig::text(node)?.trim();
+
+    Ok((!text.is_empty()).then_some(text))
+}
+
+// Verifies a SAML response and its assertion, and reads the user from the assertion. Only the signed elements are read,
+// so unsigned content injected elsewhere in the response is never trusted.
+fn verify_response(
+    response: &str,
+    identity_provider: &SamlIdentityProvider,
+    service_provider: &SamlServiceProvider,
+    now: DateTime<Utc>,
+) -> anyhow::Result<VerifiedAssertion> {
+    let certificates = parse_certificates(&identity_provider.certificates)?;
+
+    let document = Document::parse(response).context("Failed to parse SAML response")?;
+
+    let response = document.root_element();
+    ensure!(
+        response.has_tag_name((PROTOCOL_NS, "Response")),
+        "Document is not a SAML response"
+    );
+
+    if let Some(destination) = response.attribute("Destination") {
+        ensure!(
+            destination == service_provider.acs_url,
+            "SAML response destination {destination:?} is not this service provider"
+        );
+    }
+
+    let status = child(response, PROTOCOL_NS, "Status")
+        .and_then(|status| child(status, PROTOCOL_NS, "StatusCode"))
+        .and_then(|status_code| status_code.attribute("Value"));
+    ensure!(
+        status == Some(SUCCESS_STATUS),
+        "SAML response status is {status:?}"
+    );
+
+    ensure!(
+        !document
+            .descendants()
+            .any(|node| node.has_tag_name((ASSERTION_NS, "EncryptedAssertion"))),
+        "Encrypted assertions are not supported"
+    );
+
+    let mut assertions = document
+        .descendants()
+        .filter(|node| node.has_tag_name((ASSERTION_NS, "Assertion")));
+    let (Some(assertion), None) = (assertions.next(), assertions.next()) else {
+        bail!("SAML response must have exactly one assertion");
+    };
+
+    ensure!(
+        assertion.parent_element() == Some(response),
+        "Assertion is not a child of the SAML response"
+    );
+
+    // Either the assertion or the whole response must be signed. Every signature present must verify.
+    ensure!(
+        xml_dsig::is_signed(assertion) || xml_dsig::is_signed(response),
+        "Neither the SAML response nor its assertion is signed"
+    );
+    for element in [response, assertion] {
+        if xml_dsig::is_signed(element) {
+            xml_dsig::verify(element, &certificates)?;
+        }
+    }
+
+    let issuer = text(child(assertion, ASSERTION_NS, "Issuer"))?;
+    ensure!(
+        issuer == Some(identity_provider.entity_id.as_str()),
+        "Assertion issuer {issuer:?} is not the account's IdP"
+    );
+
+    // Responses need not name their issuer
+    if let Some(issuer) = text(child(response, ASSERTION_NS, "Issuer"))? {
+        ensure!(
+            issuer == identity_provider.entity_id,
+            "SAML response issuer {issuer:?} is not the account's IdP"
+        );
+    }
+
+    let Some(conditions) = child(assertion, ASSERTION_NS, "Conditions") else {
+        bail!("Assertion has no conditions");
+    };
+
+    if let Some(not_before) = parse_time(conditions.attribute("NotBefore"))? {
+        ensure!(now + CLOCK_SKEW >= not_before, "Assertion is not valid yet");
+    }
+
+    if let Some(not_on_or_after) = parse_time(conditions.attribute("NotOnOrAfter"))? {
+        ensure!(now - CLOCK_SKEW < not_on_or_after, "Assertion has expired");
+    }
+
+    // Assertions the IdP issued to other service providers must not sign in here
+    let mut audience_restrictions = conditions
+        .children()
+        .filter(|node| node.has_tag_name((ASSERTION_NS, "AudienceRestriction")))
+        .peekable();
+    ensure!(
+        audience_restrictions.peek().is_some(),
+        "Assertion has no audience restriction"
+    );
+    for audience_restriction in audience_restrictions {
+        let audiences = audience_restriction
+            .children()
+            .filter(|node| node.has_tag_name((ASSERTION_NS, "Audience")))
+            .map(|audience| text(Some(audience)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        ensure!(
+            audiences.contains(&Some(service_provider.entity_id.as_str())),
+            "Assertion is restricted to other audiences"
+        );
+    }
+
+    let Some(subject) = child(assertion, ASSERTION_NS, "Subject") else {
+        bail!("Assertion has no subject");
+    };
+
+    let Some(expires_at) = subject
+        .children()
+        .filter(|confirmation| {
+            confirmation.has_tag_name((ASSERTION_NS, "SubjectConfirmation"))
+                && confirmation.attribute("Method") == Some(BEARER_CONFIRMATION)
+        })
+        .filter_map(|confirmation| child(confirmation, ASSERTION_NS, "SubjectConfirmationData"))
+        .filter(|data| {
+            data.attribute("Recipient") == Some(service_provider.acs_url.as_str())
+                && !data.has_attribute("NotBefore")
+        })
+        .filter_map(|data| parse_time(data.attribute("NotOnOrAfter")).ok().flatten())
+        .filter(|not_on_or_after| now - CLOCK_SKEW < *not_on_or_after)
+        .max()
+    else {
+        bail!("Assertion has no valid bearer subject confirmation for this service provider");
+    };
+
+    let Some(authn_statement) = child(assertion, ASSERTION_NS, "AuthnStatement") else {
+        bail!("Assertion has no authentication statement");
+    };
+
+    let Some(authenticated_at) = parse_time(authn_statement.attribute("AuthnInstant"))? else {
+        bail!("Authentication statement has no authentication instant");
+    };
+
+    let session_expires_at = parse_time(authn_statement.attribute("SessionNotOnOrAfter"))?;
+
+    let attribute = |name: &str| {
+        text(
+            assertion
+                .children()
+                .filter(|node| node.has_tag_name((ASSERTION_NS, "AttributeStatement")))
+                .flat_map(|statement| statement.children())
+                .find(|node| {
+                    node.has_tag_name((ASSERTION_NS, "Attribute"))
+                        && node.attribute("Name") == Some(name)
+                })
+                .and_then(|attribute| child(attribute, ASSERTION_NS, "AttributeValue")),
+        )
+    };
+
+    let user_id = match &identity_provider.attribute_mapping.user_id {
+        Some(name) => attribute(name)?,
+        None => text(child(subject, ASSERTION_NS, "NameID"))?,
+    };
+    let Some(user_id) = user_id else {
+        bail!("Assertion does not identify the user");
+    };
+
+    let Some(id) = assertion.attribute("ID") else {
+        bail!("Assertion has no ID");
+    };
+
+    Ok(VerifiedAssertion {
+        id: id.to_string(),
+        user_id: user_id.to_string(),
+        email: identity_provider
+            .attribute_mapping
+            .email
+            .as_deref()
+            .map(attribute)
+            .transpose()?
+            .flatten()
+            .map(str::to_string),
+        authenticated_at,
+        expires_at: expires_at + CLOCK_SKEW,
+        session_expires_at,
+    })
+}
+
+// Records the assertion as consumed, returning false if it was consumed before
+#[instrument(err, skip(assertion))]
+async fn consume_assertion(
+    account_id: &str,
+    entity_id: &str,
+    assertion: &VerifiedAssertion,
+) -> Result<bool> {
+    let assertion_key = hex::encode(Sha256::digest(format!(
+        "{account_id}\0{entity_id}\0{}",
+        assertion.id
+    )));
+
+    Ok(accounts_db()
+        .await?
+        .query("DELETE saml_assertion WHERE expires_at <= time::now()")
+        .query("IF record::exists($assertion) { false } ELSE { CREATE $assertion CONTENT { account: $account, expires_at: $expires_at } RETURN NONE; true }")
+        .bind((
+            "assertion",
+            surrealdb::sql::Thing::from(("saml_assertion", assertion_key.as_str())),
+        ))
+        .bind(("account", account_thing(account_id)))
+        .bind((
+            "expires_at",
+            surrealdb::sql::Datetime::from(assertion.expires_at),
+        ))
+        .await
+        .context("Failed to submit query to record consumed SAML assertion")?
+        .check_first_real_error()
+        .context("Failed to record consumed SAML assertion")?
+        .take::<Option<bool>>(1)?
+        .unwrap_or(false))
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct AcsForm {
+    #[serde(rename = "SAMLResponse")]
+    saml_response: String,
+    #[serde(rename = "RelayState")]
+    relay_state: Option<String>,
+}
+
+// Assertion consumer service of the HTTP-POST binding. Responses that fail verification are logged and refused without
+// saying why, so they can't be used to probe the verification.
+#[instrument(err, skip(audit_request, form))]
+pub(crate) async fn acs(
+    Path(params): Path<HashMap<String, String>>,
+    audit_request: AuditRequest,
+    Form(form): Form<AcsForm>,
+) -> Result<Redirect> {
+    let account_id = account_id(&params)?;
+    let service_provider = service_provider(account_id)?;
+
+    let Some(identity_provider) = identity_provider(account_id).await? else {
+        not_found!("SAML sign in is not configured for this account");
+    };
+
+    let saml_response = form
+        .saml_response
+        .chars()
+        .filter(|c| !c.is_ascii_whitespace())
+        .collect::<String>();
+
+    let saml_response = BASE64_STANDARD
+        .decode(saml_response)
+        .context("Failed to decode SAML response");
+
+    // Oversized responses are refused before they are verified, so the error can say why
+    if let Ok(saml_response) = &saml_response
+        && saml_response.len() > Limit::SamlResponseBytes.max()
+    {
+        return Err(Limit::SamlResponseBytes.exceeded(Some(saml_response.len())));
+    }
+
+    let verified = saml_response.and_then(|saml_response| {
+        let saml_response =
+            String::from_utf8(saml_response).context("SAML response is not UTF-8")?;
+
+        verify_response(
+            &saml_response,
+            &identity_provider,
+            &service_provider,
+            Utc::now(),
+        )
+    });
+
+    let assertion = match verified {
+        Ok(assertion) => assertion,
+        Err(err) => {
+            warn!(?err, "Failed to verify SAML response");
+            AUTH_FAILURES_TOTAL.with_label_values(&["saml"]).inc();
+            unauthorized!();
+        }
+    };
+
+    if !consume_assertion(account_id, &identity_provider.entity_id, &assertion).await? {
+        warn!(
+            assertion_id = assertion.id,
+            "SAML assertion was already consumed"
+        );
+        AUTH_FAILURES_TOTAL.with_label_values(&["saml"]).inc();
+        unauthorized!();
+    }
+
+    let user = saml_user(account_id, &identity_provider.entity_id, &assertion.user_id);
+
+    let db = accounts_db().await?;
+
+    let is_member = !db
+        .query("UPSERT $user SET email = $email RETURN NONE")
+        .query("SELECT VALUE in FROM has_access WHERE in == $user AND out == $account")
+        .bind(("user", surrealdb::sql::Thing::from(&user)))
+        .bind(("email", assertion.email))
+        .bind(("account", account_thing(account_id)))
+        .await
+        .context("Failed to submit query to get SAML user membership")?
+        .check_first_real_error()
+        .context("Failed to get SAML user membership")?
+        .take::<Vec<surrealdb::RecordId>>(1)?
+        .is_empty();
+
+    if !is_member {
+        let Some(role) = identity_provider.default_role else {
+            warn!(
+                user_id = user.id_hash(),
+                "SAML user is not a member of the account"
+            );
+            forbidden!("You are not a member of this account, ask an account admin to invite you");
+        };
+
+        db.query("IF count(SELECT 1 FROM has_access WHERE in == $user AND out == $account) == 0 { RELATE $user->has_access->$account SET role = $role RETURN NONE }")
+            .bind(("user", surrealdb::sql::Thing::from(&user)))
+            .bind(("account", account_thing(account_id)))
+            .bind(("role", role))
+            .await
+            .context("Failed to submit query to add SAML user to account")?
+            .check_first_real_error()
+            .context("Failed to add SAML user to account")?;
+
+        info!(
+            user_id = user.id_hash(),
+            ?role,
+            "Added SAML user to account"
+        );
+
+        // Failures are logged by `audit::record`, and must not fail the sign in
+        let _ = audit::record(account_id, &user, "member.provision", None, audit_request).await;
+    }
+
+    let session_id = Uuid::now_v7().simple().to_string();
+    let secret = rand::thread_rng().r#gen::<[u8; 32]>();
+    let code = format!("{session_id}.{}", hex::encode(secret));
+
+    let now = Utc::now();
+    let expires_at = assertion
+        .session_expires_at
+        .map_or(now + SESSION_TTL, |session_expires_at| {
+            session_expires_at.min(now + SESSION_TTL)
+        });
+
+    db.query("DELETE saml_session WHERE account == $account AND expires_at <= time::now()")
+        .query("CREATE $session CONTENT { account: $account, user: $user, authenticated_at: $authenticated_at, code_hash: $code_hash, code_expires_at: $code_expires_at, expires_at: $expires_at } RETURN NONE")
+        .bind(("session", session_thing(&session_id)))
+        .bind(("account", account_thing(account_id)))
+        .bind(("user", surrealdb::sql::Thing::from(&user)))
+        .bind((
+            "authenticated_at",
+            surrealdb::sql::Datetime::from(assertion.authenticated_at),
+        ))
+        .bind((
+            "code_hash",
+            surrealdb::sql::Bytes::from(Sha256::digest(secret).to_vec()),
+        ))
+        .bind((
+            "code_expires_at",
+            surrealdb::sql::Datetime::from(now + CODE_TTL),
+        ))
+        .bind(("expires_at", surrealdb::sql::Datetime::from(expires_at)))
+        .await
+        .context("Failed to submit query to create SAML session")?
+        .check_first_real_error()
+        .context("Failed to create SAML session")?;
+
+    info!(session_id, user_id = user.id_hash(), "Signed in SAML user");
+
+    let mut callback_url = reqwest::Url::parse(&format!("{}/saml/callback", Env::dashboard_url()))
+        .context("Failed to parse dashboard URL")?;
+
+    callback_url
+        .query_pairs_mut()
+        .append_pair("account_id", account_id)
+        .append_pair("code", &code);
+
+    if let Some(return_to) = form
+        .relay_state
+        .as_deref()
+        .filter(|return_to| is_valid_return_to(return_to))
+    {
+        callback_url
+            .query_pairs_mut()
+            .append_pair("return_to", return_to);
+    }
+
+    Ok(Redirect::to(callback_url.as_str()))
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CreateSessionRequest {
+    code: String,
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct CreateSessionResponse {
+    token: String,
+    expires_at: DateTime<Utc>,
+}
+
+// Exchanges the single-use code of a sign in for the session's token
+#[instrument(err, skip(req))]
+pub(crate) async fn create_session(
+    Path(params): Path<HashMap<String, String>>,
+    Json(req): Json<CreateSessionRequest>,
+) -> Result<Json<CreateSessionResponse>> {
+    #[derive(Deserialize)]
+    struct Session {
+        expires_at: DateTime<Utc>,
+    }
+
+    let account_id = account_id(&params)?;
+
+    let Some((session_id, code_secret)) = req.code.split_once('.') else {
+        bad_request!("Invalid sign in code");
+    };
+
+    let Ok(code_secret) = hex::decode(code_secret) else {
+        bad_request!("Invalid sign in code");
+    };
+
+    let secret = rand::thread_rng().r#gen::<[u8; 32]>();
+
+    let Some(session) = accounts_db()
+        .await?
+        .query("UPDATE $session SET code_hash = NONE, code_expires_at = NONE, token_hash = $token_hash WHERE account == $account AND code_hash == $code_hash AND code_expires_at > time::now() RETURN expires_at")
+        .bind(("session", session_thing(session_id)))
+        .bind(("account", account_thing(account_id)))
+        .bind((
+            "code_hash",
+            surrealdb::sql::Bytes::from(Sha256::digest(code_secret).to_vec()),
+        ))
+        .bind((
+            "token_hash",
+            surrealdb::sql::Bytes::from(Sha256::digest(secret).to_vec()),
+        ))
+        .await
+        .context("Failed to submit query to create SAML session token")?
+        .check_first_real_error()
+        .context("Failed to create SAML session token")?
+        .take::<Vec<Session>>(0)?
+        .pop()
+    else {
+        warn!(session_id, "SAML session not found, code expired, or code is invalid");
+        unauthorized!();
+    };
+
+    Ok(Json(CreateSessionResponse {
+        token: format!("{SAML_SESSION_PREFIX}{session_id}.{}", hex::encode(secret)),
+        expires_at: session.expires_at,
+    }))
+}
+
+// Verifies a SAML session token, without its `archodex_saml_` prefix, and returns the user it was created for, the
+// account it is limited to, and when the user authenticated with the IdP
+#[instrument(err, skip_all)]
+pub(crate) async fn verify_session(token: &str) -> Result<(User, String, DateTime<Utc>)> {
+    #[derive(Deserialize)]
+    struct VerifiedSession {
+        #[serde(deserialize_with = "surrealdb_deserializers::string::deserialize")]
+        account_id: String,
+        user: User,
+        authenticated_at: DateTime<Utc>,
+    }
+
+    let Some((session_id, secret)) = token.split_once('.') else {
+        warn!("Invalid SAML session token format");
+        unauthorized!();
+    };
+
+    let Ok(secret) = hex::decode(secret) else {
+        warn!("Invalid SAML session token format");
+        unauthorized!();
+    };
+
+    let Some(session) = accounts_db()
+        .await?
+        .query("SELECT record::id(account) AS account_id, user, authenticated_at FROM $session WHERE token_hash == $token_hash AND expires_at > time::now() AND account.deleted_at IS NONE")
+        .bind(("session", session_thing(session_id)))
+        .bind((
+            "token_hash",
+            surrealdb::sql::Bytes::from(Sha256::digest(secret).to_vec()),
+        ))
+        .await
+        .context("Failed to submit query to verify SAML session")?
+        .check_first_real_error()
+        .context("Failed to verify SAML session")?
+        .take::<Vec<VerifiedSession>>(0)?
+        .pop()
+    else {
+        warn!(session_id, "SAML session not found, expired, or token is invalid");
+        unauthorized!();
+    };
+
+    Ok((session.user, session.account_id, session.authenticated_at))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+
+    use super::{
+        SamlAttributeMapping, SamlIdentityProvider, SamlServiceProvider, VerifiedAssertion,
+        verify_response,
+    };
+
+    const OKTA_RESPONSE: &str = include_str!("../fixtures/saml/okta_signed_assertion.xml");
+    const KEYCLOAK_RESPONSE: &str = include_str!("../fixtures/saml/keycloak_signed_response.xml");
+    const IDP_CERTIFICATE: &str = include_str!("../fixtures/saml/idp.pem");
+
+    fn service_provider() -> SamlServiceProvider {
+        let entity_id = "https://api.archodex.com/saml/1234567890".to_string();
+
+        SamlServiceProvider {
+            acs_url: format!("{entity_id}/acs"),
+            metadata_url: format!("{entity_id}/metadata"),
+            login_url: format!("{entity_id}/login"),
+            entity_id,
+        }
+    }
+
+    fn identity_provider(entity_id: &str, email_attribute: &str) -> SamlIdentityProvider {
+        SamlIdentityProvider {
+            entity_id: entity_id.to_string(),
+            sso_url: "https://idp.example.com/sso".to_string(),
+            certificates: vec![IDP_CERTIFICATE.to_string()],
+            attribute_mapping: SamlAttributeMapping {
+                user_id: None,
+                email: Some(email_attribute.to_string()),
+            },
+            default_role: None,
+        }
+    }
+
+    fn okta() -> SamlIdentityProvider {
+        identity_provider("http://www.okta.com/exk1a2b3c4d5e6f7g8h9", "email")
+    }
+
+    fn keycloak() -> SamlIdentityProvider {
+        identity_provider(
+            "https://keycloak.example.com/realms/archodex",
+            "urn:oid:1.2.840.113549.1.9.1",
+        )
+    }
+
+    fn now() -> DateTime<Utc> {
+        "2026-10-16T12:00:30Z".parse().unwrap()
+    }
+
+    fn verify(response: &str, identity_provider: &SamlIdentityProvider) -> VerifiedAssertion {
+        verify_response(response, identity_provider, &service_provider(), now()).unwrap()
+    }
+
+    #[test]
+    fn okta_signed_assertion() {
+        let assertion = verify(OKTA_RESPONSE, &okta());
+
+        assert_eq!(assertion.id, "id1734021984612345678901");
+        assert_eq!(assertion.user_id, "jane.doe@example.com");
+        assert_eq!(assertion.email.as_deref(), Some("jane.doe@example.com"));
+        assert_eq!(
+            assertion.authenticated_at,
+            "2026-10-16T11:59:58Z".parse::<DateTime<Utc>>().unwrap()
+        );
+    }
+
+    #[test]
+    fn keycloak_signed_response() {
+        let assertion = verify(KEYCLOAK_RESPONSE, &keycloak());
+
+        assert_eq!(assertion.user_id, "G-7c1f2a9e-3b4d-4c5e-8f6a-9b0c1d2e3f4a");
+        assert_eq!(assertion.email.as_deref(), Some("john.smith@example.com"));
+        assert_eq!(
+            assertion.session_expires_at,
+            Some("2026-10-16T22:00:00Z".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn other_identity_provider() {
+        assert!(verify_response(OKTA_RESPONSE, &keycloak(), &service_provider(), now()).is_err());
+    }
+
+    #[test]
+    fn expired() {
+        let now = "2026-10-16T12:10:00Z".parse().unwrap();
+
+        assert!(verify_response(OKTA_RESPONSE, &okta(), &service_provider(), now).is_err());
+    }
+
+    // The signature covers the text without comments, so the comment doesn't invalidate it. Reading only the text
+    // before the comment would sign in as a different user than the IdP asserted.
+    #[test]
+    fn comment_in_name_id() {
+        for (original, injected) in [
+            (
+                ">jane.doe@example.com</saml2:NameID>",
+                ">jane.doe@<!---->example.com</saml2:NameID>",
+            ),
+            (
+                ">http://www.okta.com/exk1a2b3c4d5e6f7g8h9</saml2:Issuer><ds:Signature",
+                ">http://www.okta.com/<!---->exk1a2b3c4d5e6f7g8h9</saml2:Issuer><ds:Signature",
+            ),
+        ] {
+            let response = OKTA_RESPONSE.replacen(original, injected, 1);
+            assert_ne!(response, OKTA_RESPONSE);
+
+            let Err(err) = verify_response(&response, &okta(), &service_provider(), now()) else {
+                panic!("Response with {injected} should not verify");
+            };
+            assert!(
+                err.to_string().contains("must only contain text"),
+                "{err:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn comment_in_attribute_value() {
+        let response = KEYCLOAK_RESPONSE.replacen(
+            ">john.smith@example.com<",
+            ">john.smith<!---->@example.com<",
+            1,
+        );
+
+        assert!(verify_response(&response, &keycloak(), &service_provider(), now()).is_err());
+    }
+}
@@ -0,0 +1,418 @@
+use axum::{Extension, Json, extract::Query};
+use base64::{Engine as _, prelude::BASE64_URL_SAFE_NO_PAD};
+use futures::{StreamExt as _, stream};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::{instrument, warn};
+
+use archodex_error::bad_request;
+
+use crate::{
+    Result,
+    account::Account,
+    auth::DashboardAuth,
+    db::QueryCheckFirstRealError,
+    resource::{RESOURCE_FIELDS, Resource},
+};
+
+// Bounds the number of account resources databases searched at the same time for a single request
+const MAX_CONCURRENT_ACCOUNT_SEARCHES: usize = 4;
+const DEFAULT_RESULTS_PER_ACCOUNT: u32 = 50;
+const MAX_RESULTS_PER_ACCOUNT: u32 = 500;
+const DEFAULT_ACCOUNT_SEARCH_LIMIT: u32 = 50;
+const MAX_ACCOUNT_SEARCH_LIMIT: u32 = 200;
+
+#[derive(Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SearchScope {
+    AllAccounts,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SearchRequest {
+    q: String,
+    scope: SearchScope,
+    environment: Option<String>,
+    tag: Option<String>,
+    limit: Option<u32>,
+}
+
+#[derive(Debug, JsonSchema, Serialize)]
+pub(crate) struct SearchResult {
+    account_id: String,
+    resource: Resource,
+}
+
+#[derive(Debug, JsonSchema, Serialize)]
+pub(crate) struct SearchResponse {
+    results: Vec<SearchResult>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    failed_account_ids: Vec<String>,
+}
+
+#[instrument(err, skip_all)]
+async fn search_account(
+    account: &Account,
+    q: &str,
+    environment: Option<String>,
+    tag: Option<String>,
+    limit: u32,
+) -> Result<Vec<Resource>> {
+    let environment = account.scoped_environment(environment);
+
+    Ok(account
+        .resources_db()
+        .await?
+        .query(format!(
+            "SELECT {RESOURCE_FIELDS} FROM resource
+            WHERE id != resource:[] AND string::contains(string::lowercase(resource_id), string::lowercase($q))
+                AND ($environment IS NONE OR $environment INSIDE environments)
+                AND ($tag IS NONE OR $tag INSIDE tags)
+            LIMIT $limit",
+        ))
+        .bind(("q", q.to_owned()))
+        .bind(("environment", environment))
+        .bind(("tag", tag))
+        .bind(("limit", limit))
+        .await?
+        .check_first_real_error()?
+        .take::<Vec<Resource>>(0)?)
+}
+
+// Searches for resources matching the query across all accounts the principal has access to. Each account's results are
+// scoped to the requested environment or the account's default environment. Accounts are searched concurrently, and
+// accounts that fail to be searched (e.g. self-hosted accounts without data in this backend) are reported separately
+// rather than failing the entire search.
+#[instrument(err, skip(auth))]
+pub(crate) async fn search(
+    Extension(auth): Extension<DashboardAuth>,
+    Query(req): Query<SearchRequest>,
+) -> Result<Json<SearchResponse>> {
+    let SearchScope::AllAccounts = req.scope;
+
+    let q = req.q.trim();
+    if q.is_empty() {
+        bad_request!("Search query `q` must not be empty");
+    }
+
+    let limit = req.limit.unwrap_or(DEFAULT_RESULTS_PER_ACCOUNT);
+    if limit == 0 || limit > MAX_RESULTS_PER_ACCOUNT {
+        bad_request!("Search `limit` must be between 1 and {MAX_RESULTS_PER_ACCOUNT}");
+    }
+
+    let accounts = auth.principal().list_accounts().await?;
+
+    let environment = &req.environment;
+    let tag = &req.tag;
+
+    let account_results = stream::iter(accounts)
+        .map(|account| async move {
+            let results =
+                search_account(&account, q, environment.clone(), tag.clone(), limit).await;
+            (account, results)
+        })
+        .buffer_unordered(MAX_CONCURRENT_ACCOUNT_SEARCHES)
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut results = vec![];
+    let mut failed_account_ids = vec![];
+
+    for (account, account_results) in account_results {
+        match account_results {
+            Ok(resources) => results.extend(resources.into_iter().map(|resource| SearchResult {
+                account_id: account.id().to_owned(),
+                resource,
+            })),
+            Err(err) => {
+                warn!(account_id = account.id(), %err, "Failed to search account");
+                failed_account_ids.push(account.id().to_owned());
+            }
+        }
+    }
+
+    Ok(Json(SearchResponse {
+        results,
+        failed_account_ids,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct AccountSearchRequest {
+    q: String,
+    environment: Option<String>,
+    tag: Option<String>,
+    #[serde(default)]
+    include_archived: bool,
+    limit: Option<u32>,
+    cursor: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, JsonSchema, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum MatchedField {
+    ResourceId,
+    Attribute,
+}
+
+#[derive(Debug, JsonSchema, Serialize)]
+pub(crate) struct SearchHighlight {
+    field: MatchedField,
+    // Type of the matched resource ID part, if the match is in the resource ID
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id_type: Option<String>,
+    // Name of the matched attribute, if the match is in an attribute value
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attribute: Option<String>,
+    // Matched resource ID part or attribute value
+    value: String,
+    // Character offsets of the matched terms in `value`, as `[start, end)` pairs
+    ranges: Vec<[u32; 2]>,
+}
+
+#[derive(Debug, JsonSchema, Serialize)]
+pub(crate) struct AccountSearchResult {
+    resource: Resource,
+    score: f64,
+    highlights: Vec<SearchHighlight>,
+}
+
+#[derive(Debug, JsonSchema, Serialize)]
+pub(crate) struct AccountSearchResponse {
+    results: Vec<AccountSearchResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AccountSearchRow {
+    resource: Resource,
+    score: f64,
+    attributes: Vec<(String, String)>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum CharacterClass {
+    Lowercase,
+    Uppercase,
+    Numeric,
+    Punctuation,
+    Separator,
+}
+
+impl From<char> for CharacterClass {
+    fn from(c: char) -> Self {
+        if c.is_alphabetic() {
+            if c.is_uppercase() {
+                Self::Uppercase
+            } else {
+                Self::Lowercase
+            }
+        } else if c.is_numeric() {
+            Self::Numeric
+        } else if c.is_ascii_punctuation() {
+            Self::Punctuation
+        } else {
+            Self::Separator
+        }
+    }
+}
+
+// Splits text into lowercased terms like the `class` and `camel` tokenizers of the `resource_search` analyzers, along
+// with the character offset of each term
+fn terms(text: &str) -> Vec<(u32, String)> {
+    let mut terms: Vec<(u32, String)> = vec![];
+    let mut previous = CharacterClass::Separator;
+
+    for (offset, c) in (0..).zip(text.chars()) {
+        let class = CharacterClass::from(c);
+
+        let starts_term = match (previous, class) {
+            (_, CharacterClass::Separator) => {
+                previous = class;
+                continue;
+            }
+            (CharacterClass::Lowercase, CharacterClass::Uppercase) => true,
+            (
+                CharacterClass::Lowercase | CharacterClass::Uppercase,
+                CharacterClass::Lowercase | CharacterClass::Uppercase,
+            ) => false,
+            (previous, class) => previous != class,
+        };
+        previous = class;
+
+        match terms.last_mut() {
+            Some((_, term)) if !starts_term => term.extend(c.to_lowercase()),
+            _ => terms.push((offset, c.to_lowercase().collect())),
+        }
+    }
+
+    terms
+}
+
+// Terms of the query as they can match indexed prefixes, which the `resource_search` analyzer's `edgengram(2, 32)`
+// filter limits to between 2 and 32 characters. Shorter terms are dropped, and longer terms are truncated.
+fn query_terms(q: &str) -> Vec<String> {
+    terms(q)
+        .into_iter()
+        .filter(|(_, term)| term.chars().count() >= 2)
+        .map(|(_, term)| term.chars().take(32).collect())
+        .collect()
+}
+
+// Ranges of the terms of the value that start with a query term
+fn highlight_ranges(value: &str, query_terms: &[String]) -> Vec<[u32; 2]> {
+    terms(value)
+        .into_iter()
+        .filter_map(|(offset, term)| {
+            let matched = query_terms
+                .iter()
+                .filter(|query_term| term.starts_with(query_term.as_str()))
+                .map(|query_term| query_term.chars().count() as u32)
+                .max()?;
+            Some([offset, offset + matched])
+        })
+        .collect()
+}
+
+impl AccountSearchResult {
+    fn new(row: AccountSearchRow, query_terms: &[String]) -> Self {
+        let mut highlights = vec![];
+
+        for part in row.resource.id.iter() {
+            let ranges = highlight_ranges(&part.id, query_terms);
+            if !ranges.is_empty() {
+                highlights.push(SearchHighlight {
+                    field: MatchedField::ResourceId,
+                    id_type: Some(part.r#type.clone()),
+                    attribute: None,
+                    value: part.id.clone(),
+                    ranges,
+                });
+            }
+        }
+
+        for (name, value) in row.attributes {
+            let ranges = highlight_ranges(&value, query_terms);
+            if !ranges.is_empty() {
+                highlights.push(SearchHighlight {
+                    field: MatchedField::Attribute,
+                    id_type: None,
+                    attribute: Some(name),
+                    value,
+                    ranges,
+                });
+            }
+        }
+
+        Self {
+            resource: row.resource,
+            score: row.score,
+            highlights,
+        }
+    }
+}
+
+// Cursors are the opaque, URL safe encoding of the number of results of the previous pages
+fn encode_cursor(start: u32) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(start.to_be_bytes())
+}
+
+fn decode_cursor(cursor: &str) -> Option<u32> {
+    let bytes = BASE64_URL_SAFE_NO_PAD.decode(cursor).ok()?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+// Full-text search of an account's resource IDs and attribute values, ranked by relevance. Query terms match terms of
+// resource ID parts and attribute values they are a prefix of, see the `resource_search` analyzer. Results are scoped
+// to the requested environment or the account's default environment. Pages are offsets into the ranking, so resources
+// reported between requests for consecutive pages may shift results across pages.
+#[instrument(err, skip(account))]
+pub(crate) async fn search_resources(
+    Extension(account): Extension<Account>,
+    Query(req): Query<AccountSearchRequest>,
+) -> Result<Json<AccountSearchResponse>> {
+    let query_terms = query_terms(&req.q);
+    if query_terms.is_empty() {
+        bad_request!("Search query `q` must contain a term of at least 2 characters");
+    }
+
+    let limit = req.limit.unwrap_or(DEFAULT_ACCOUNT_SEARCH_LIMIT);
+    if limit == 0 || limit > MAX_ACCOUNT_SEARCH_LIMIT {
+        bad_request!("Search `limit` must be between 1 and {MAX_ACCOUNT_SEARCH_LIMIT}");
+    }
+
+    let start = match &req.cursor {
+        Some(cursor) => {
+            let Some(start) = decode_cursor(cursor) else {
+                bad_request!("Invalid search `cursor`");
+            };
+            start
+        }
+        None => 0,
+    };
+
+    let environment = account.scoped_environment(req.environment);
+
+    // Fetch one extra result to find out whether there is another page
+    let mut rows = account
+        .resources_db()
+        .await?
+        .query(
+            "SELECT
+                id,
+                { id: id, environments: environments, tags: tags, first_seen_at: first_seen_at, last_seen_at: last_seen_at, archived_at: archived_at } AS resource,
+                (search::score(1) ?? 0) + (search::score(2) ?? 0) AS score,
+                fn::searchable_attributes(attributes) AS attributes
+            FROM resource
+            WHERE (id_search_terms @1@ $q OR attribute_search_terms @2@ $q)
+                AND id != resource:[]
+                AND ($include_archived OR archived_at IS NONE)
+                AND ($environment IS NONE OR $environment INSIDE environments)
+                AND ($tag IS NONE OR $tag INSIDE tags)
+            ORDER BY score DESC, id
+            LIMIT $limit START $start",
+        )
+        .bind(("q", query_terms.join(" ")))
+        .bind(("include_archived", req.include_archived))
+        .bind(("environment", environment))
+        .bind(("tag", req.tag))
+        .bind(("limit", limit + 1))
+        .bind(("start", start))
+        .await?
+        .check_first_real_error()?
+        .take::<Vec<AccountSearchRow>>(0)?;
+
+    let next_cursor = (rows.len() > limit as usize).then(|| {
+        rows.truncate(limit as usize);
+        encode_cursor(start + limit)
+    });
+
+    Ok(Json(AccountSearchResponse {
+        results: rows
+            .into_iter()
+            .map(|row| AccountSearchResult::new(row, &query_terms))
+            .collect(),
+        next_cursor,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{highlight_ranges, query_terms};
+
+    #[test]
+    fn query_terms_match_prefixes() {
+        assert_eq!(query_terms("Lambda"), ["lambda"]);
+        assert_eq!(query_terms("myFunction i-0abc"), ["my", "function", "abc"]);
+
+        let query_terms = query_terms("lambda");
+        assert_eq!(
+            highlight_ranges("LambdaFunction lambdas", &query_terms),
+            [[0, 6], [15, 21]]
+        );
+        assert!(highlight_ranges("label language", &query_terms).is_empty());
+    }
+}
@@ -0,0 +1,160 @@
+// Secret Value resources are identified by a hash of the secret value computed by agents, so the same value can be
+// found wherever it is used without the backend ever receiving it. Agents declare how they hashed a value in the
+// `secret_hash` field of its resource tree node, which is stored with the resource. Hashes are always salted or keyed
+// with the account salt, so they can't be looked up in precomputed tables or correlated across accounts. Declared
+// hashes are identified by `<algorithm>:<hash>`, so agents can move to new hash algorithms without hashes of different
+// algorithms being mistaken for one another.
+//
+// Secret Value resources reported without a declaration predate it, and are identified by the hex-encoded 32-byte hash
+// of the value salted with the account salt.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use archodex_error::bad_request;
+
+use crate::{Result, resource::ResourceIdPart};
+
+pub(crate) const SECRET_VALUE_RESOURCE_TYPE: &str = "Secret Value";
+
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SecretHashAlgorithm {
+    // SHA-256 of the secret value prefixed with the account salt
+    Sha256,
+    // SHA3-256 of the secret value prefixed with the account salt
+    Sha3_256,
+    // HMAC-SHA256 of the secret value keyed with the account salt
+    HmacSha256,
+}
+
+impl SecretHashAlgorithm {
+    fn as_str(self) -> &'static str {
+        match self {
+            SecretHashAlgorithm::Sha256 => "sha256",
+            SecretHashAlgorithm::Sha3_256 => "sha3_256",
+            SecretHashAlgorithm::HmacSha256 => "hmac_sha256",
+        }
+    }
+
+    fn output_bytes(self) -> usize {
+        match self {
+            SecretHashAlgorithm::Sha256
+            | SecretHashAlgorithm::Sha3_256
+            | SecretHashAlgorithm::HmacSha256 => 32,
+        }
+    }
+}
+
+// Hash algorithm of a Secret Value resource. The resource's ID is the algorithm and the hex-encoded hash, separated by
+// a colon.
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SecretHash {
+    algorithm: SecretHashAlgorithm,
+}
+
+impl SecretHash {
+    // Checks that the hash is declared for a Secret Value resource identified by a hash of the algorithm
+    pub(crate) fn validate(&self, resource_id: &ResourceIdPart) -> Result<()> {
+        if resource_id.r#type != SECRET_VALUE_RESOURCE_TYPE {
+            bad_request!(
+                "Resource {:?} of type {:?} declares a secret hash, which only {SECRET_VALUE_RESOURCE_TYPE} resources may declare",
+                resource_id.id,
+                resource_id.r#type
+            );
+        }
+
+        let algorithm = self.algorithm.as_str();
+        let hash_length = self.algorithm.output_bytes() * 2;
+
+        let hash = resource_id
+            .id
+            .strip_prefix(algorithm)
+            .and_then(|id| id.strip_prefix(':'));
+
+        if !hash.is_some_and(|hash| {
+            hash.len() == hash_length
+                && hash
+                    .bytes()
+                    .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+        }) {
+            bad_request!(
+                "{SECRET_VALUE_RESOURCE_TYPE} resource ID {:?} must be \"{algorithm}:\" followed by a {hash_length} character lowercase hex-encoded hash",
+                resource_id.id
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl From<SecretHash> for surrealdb::sql::Value {
+    fn from(value: SecretHash) -> Self {
+        let mut object = surrealdb::sql::Object::default();
+        object.insert("algorithm".to_string(), value.algorithm.as_str().into());
+
+        object.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SECRET_VALUE_RESOURCE_TYPE, SecretHash, SecretHashAlgorithm};
+    use crate::resource::ResourceIdPart;
+
+    const SHA3_256: SecretHash = SecretHash {
+        algorithm: SecretHashAlgorithm::Sha3_256,
+    };
+
+    fn secret_value(id: &str) -> ResourceIdPart {
+        ResourceIdPart {
+            r#type: SECRET_VALUE_RESOURCE_TYPE.to_string(),
+            id: id.to_string(),
+        }
+    }
+
+    #[test]
+    fn validate() {
+        let hash = "0123456789abcdef".repeat(4);
+
+        assert!(
+            SHA3_256
+                .validate(&secret_value(&format!("sha3_256:{hash}")))
+                .is_ok()
+        );
+
+        // Hashes must be prefixed with their own algorithm
+        for id in [
+            hash.clone(),
+            format!("sha256:{hash}"),
+            format!("sha3_256:{}", hash.to_uppercase()),
+            format!("sha3_256:{}", &hash[2..]),
+        ] {
+            assert!(
+                SHA3_256.validate(&secret_value(&id)).is_err(),
+                "{id} should not be valid"
+            );
+        }
+
+        assert!(
+            SecretHash {
+                algorithm: SecretHashAlgorithm::HmacSha256
+            }
+            .validate(&ResourceIdPart {
+                r#type: "AWS Secrets Manager Secret".to_string(),
+                id: format!("hmac_sha256:{hash}"),
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn unsalted_hashes_rejected() {
+        assert!(serde_json::from_str::<SecretHash>(r#"{"algorithm": "sha256"}"#).is_ok());
+        assert!(
+            serde_json::from_str::<SecretHash>(r#"{"algorithm": "sha256", "salted": false}"#)
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,296 @@
+// Sensors are the agents and report keys that observe an account's resources and events. Sensors see different parts of
+// an environment, e.g. a network scanner sees hosts that an agent reading cloud APIs doesn't and vice versa, so each
+// resource, `contains` relation, and event records the sensors that observed it and when in its `observed_by` field.
+// The coverage of each sensor is served by `GET /account/:account_id/coverage`, and
+// `GET /account/:account_id/coverage/blind_spots` lists the resources a sensor didn't observe even though it observes
+// other resources of their type, so gaps in an account's instrumentation can be found.
+//
+// Agents name themselves in the `X-Archodex-Agent` header of the reports they send, which must name an agent registered
+// under the report key, see `agents`. Reports without the header, e.g. imported AWS Config files, are observed by their
+// report key. Inventory documents imported by dashboard users are observed by the `import` sensor.
+
+use std::collections::BTreeMap;
+
+use axum::{
+    Extension, Json,
+    extract::Query,
+    http::{HeaderMap, HeaderName},
+};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use archodex_error::{anyhow::Context as _, bad_request};
+
+use crate::{
+    Result,
+    account::Account,
+    db::QueryCheckFirstRealError as _,
+    resource::{RESOURCE_FIELDS, Resource},
+    surrealdb_deserializers,
+};
+
+pub(crate) const AGENT_HEADER: HeaderName = HeaderName::from_static("x-archodex-agent");
+
+const AGENT_SENSOR_PREFIX: &str = "agent:";
+const REPORT_API_KEY_SENSOR_PREFIX: &str = "report_api_key:";
+const IMPORT_SENSOR: &str = "import";
+
+const DEFAULT_BLIND_SPOTS_LIMIT: u32 = 100;
+const MAX_BLIND_SPOTS_LIMIT: u32 = 1000;
+
+#[derive(Clone, Debug)]
+pub(crate) struct Sensor {
+    // Unset for imports
+    report_api_key_id: Option<u32>,
+    agent_id: Option<String>,
+}
+
+impl Sensor {
+    pub(crate) fn report_api_key(report_api_key_id: u32) -> Self {
+        Self {
+            report_api_key_id: Some(report_api_key_id),
+            agent_id: None,
+        }
+    }
+
+    // Sensor of inventory documents imported by dashboard users, see `import`
+    pub(crate) fn import() -> Self {
+        Self {
+            report_api_key_id: None,
+            agent_id: None,
+        }
+    }
+
+    // Sensor recorded with a report earlier, e.g. with a dead-lettered report
+    pub(crate) fn new(report_api_key_id: u32, agent_id: Option<String>) -> Self {
+        Self {
+            report_api_key_id: Some(report_api_key_id),
+            agent_id,
+        }
+    }
+
+    // Resolves the agent named by the `X-Archodex-Agent` header in the agent registry
+    #[instrument(err, skip(account, headers))]
+    pub(crate) async fn from_headers(
+        account: &Account,
+        headers: &HeaderMap,
+        report_api_key_id: u32,
+    ) -> Result<Self> {
+        let Some(name) = headers.get(AGENT_HEADER) else {
+            return Ok(Self::report_api_key(report_api_key_id));
+        };
+
+        let Ok(name) = name.to_str() else {
+            bad_request!("{AGENT_HEADER} header must be printable ASCII");
+        };
+
+        let agent_id = account
+            .resources_db()
+            .await?
+            .query("SELECT VALUE record::id(id) FROM agent WHERE report_api_key_id = $report_api_key_id AND name = $name")
+            .bind(("report_api_key_id", report_api_key_id))
+            .bind(("name", name.to_string()))
+            .await
+            .context("Failed to submit query to get agent of report")?
+            .check_first_real_error()
+            .context("Failed to get agent of report")?
+            .take::<Vec<String>>(0)?
+            .pop();
+
+        let Some(agent_id) = agent_id else {
+            bad_request!("Agent {name} is not registered under this report key");
+        };
+
+        Ok(Self {
+            report_api_key_id: Some(report_api_key_id),
+            agent_id: Some(agent_id),
+        })
+    }
+
+    pub(crate) fn report_api_key_id(&self) -> Option<u32> {
+        self.report_api_key_id
+    }
+
+    pub(crate) fn agent_id(&self) -> Option<&str> {
+        self.agent_id.as_deref()
+    }
+
+    // Identifier recorded in `observed_by`, e.g. `agent:<agent ID>`, `report_api_key:<report key ID>`, or `import`
+    pub(crate) fn id(&self) -> String {
+        match (&self.agent_id, self.report_api_key_id) {
+            (Some(agent_id), _) => format!("{AGENT_SENSOR_PREFIX}{agent_id}"),
+            (None, Some(report_api_key_id)) => {
+                format!("{REPORT_API_KEY_SENSOR_PREFIX}{report_api_key_id}")
+            }
+            (None, None) => IMPORT_SENSOR.to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
+pub(crate) struct Observation {
+    pub(crate) sensor: String,
+    pub(crate) first_seen_at: DateTime<Utc>,
+    pub(crate) last_seen_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SensorObservations {
+    sensor: String,
+    count: u64,
+    last_seen_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AgentName {
+    #[serde(deserialize_with = "surrealdb_deserializers::string::deserialize")]
+    id: String,
+    name: String,
+    #[serde(deserialize_with = "surrealdb_deserializers::u32::deserialize")]
+    report_api_key_id: u32,
+}
+
+#[derive(Debug, Default, JsonSchema, Serialize)]
+pub(crate) struct SensorCoverage {
+    sensor: String,
+    report_api_key_id: Option<u32>,
+    // Name of the agent, unless the sensor is a report key. Unset for agents that have since been deleted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    agent_name: Option<String>,
+    // Number of active resources the sensor observed
+    resources: u64,
+    // Number of events the sensor observed
+    events: u64,
+    last_seen_at: Option<DateTime<Utc>>,
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct CoverageResponse {
+    // Number of active resources, including resources observed before sensors were recorded
+    resources: u64,
+    sensors: Vec<SensorCoverage>,
+}
+
+#[instrument(err, skip_all, fields(account_id = account.id()))]
+pub(crate) async fn coverage(
+    Extension(account): Extension<Account>,
+) -> Result<Json<CoverageResponse>> {
+    let mut res = account
+        .resources_db()
+        .await?
+        .query("LET $resource_observations = array::flatten(SELECT VALUE observed_by FROM resource WHERE id != resource:[] AND archived_at IS NONE)")
+        .query("SELECT sensor, count() AS count, time::max(last_seen_at) AS last_seen_at FROM $resource_observations GROUP BY sensor")
+        .query("LET $event_observations = array::flatten(SELECT VALUE observed_by FROM event)")
+        .query("SELECT sensor, count() AS count, time::max(last_seen_at) AS last_seen_at FROM $event_observations GROUP BY sensor")
+        .query("SELECT id, name, report_api_key_id FROM agent")
+        .query("(SELECT count() AS count FROM resource WHERE id != resource:[] AND archived_at IS NONE GROUP ALL)[0].count ?? 0")
+        .await
+        .context("Failed to submit query to get sensor coverage")?
+        .check_first_real_error()
+        .context("Failed to get sensor coverage")?;
+
+    let resource_observations = res.take::<Vec<SensorObservations>>(1)?;
+    let event_observations = res.take::<Vec<SensorObservations>>(3)?;
+    let agents = res
+        .take::<Vec<AgentName>>(4)?
+        .into_iter()
+        .map(|agent| (agent.id, (agent.name, agent.report_api_key_id)))
+        .collect::<BTreeMap<_, _>>();
+    let resources = res.take::<Option<u64>>(5)?.unwrap_or_default();
+
+    let mut sensors = BTreeMap::<String, SensorCoverage>::new();
+
+    for (observations, is_event) in resource_observations
+        .into_iter()
+        .map(|observations| (observations, false))
+        .chain(
+            event_observations
+                .into_iter()
+                .map(|observations| (observations, true)),
+        )
+    {
+        let coverage = sensors
+            .entry(observations.sensor.clone())
+            .or_insert_with(|| SensorCoverage {
+                sensor: observations.sensor.clone(),
+                ..SensorCoverage::default()
+            });
+
+        if is_event {
+            coverage.events = observations.count;
+        } else {
+            coverage.resources = observations.count;
+        }
+
+        coverage.last_seen_at = coverage.last_seen_at.max(Some(observations.last_seen_at));
+    }
+
+    for coverage in sensors.values_mut() {
+        if let Some(agent_id) = coverage.sensor.strip_prefix(AGENT_SENSOR_PREFIX) {
+            if let Some((name, report_api_key_id)) = agents.get(agent_id) {
+                coverage.agent_name = Some(name.clone());
+                coverage.report_api_key_id = Some(*report_api_key_id);
+            }
+        } else if let Some(report_api_key_id) =
+            coverage.sensor.strip_prefix(REPORT_API_KEY_SENSOR_PREFIX)
+        {
+            coverage.report_api_key_id = report_api_key_id.parse().ok();
+        }
+    }
+
+    Ok(Json(CoverageResponse {
+        resources,
+        sensors: sensors.into_values().collect(),
+    }))
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct BlindSpotsParams {
+    // Sensor identifier, e.g. `agent:<agent ID>` or `report_api_key:<report key ID>`
+    sensor: String,
+    limit: Option<u32>,
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct BlindSpotsResponse {
+    resources: Vec<Resource>,
+}
+
+// Lists active resources observed by other sensors but not by the sensor, limited to types of resources the sensor
+// observes. Resources observed before sensors were recorded are left out.
+#[instrument(err, skip(account), fields(account_id = account.id()))]
+pub(crate) async fn blind_spots(
+    Extension(account): Extension<Account>,
+    Query(params): Query<BlindSpotsParams>,
+) -> Result<Json<BlindSpotsResponse>> {
+    if !params.sensor.starts_with(AGENT_SENSOR_PREFIX)
+        && !params.sensor.starts_with(REPORT_API_KEY_SENSOR_PREFIX)
+    {
+        bad_request!(
+            "Sensor must be formatted as {AGENT_SENSOR_PREFIX}<agent ID> or {REPORT_API_KEY_SENSOR_PREFIX}<report key ID>"
+        );
+    }
+
+    let limit = params.limit.unwrap_or(DEFAULT_BLIND_SPOTS_LIMIT);
+    if limit == 0 || limit > MAX_BLIND_SPOTS_LIMIT {
+        bad_request!("Limit must be between 1 and {MAX_BLIND_SPOTS_LIMIT}");
+    }
+
+    let resources = account
+        .resources_db()
+        .await?
+        .query("LET $types = array::distinct(SELECT VALUE resource_type FROM resource WHERE $sensor INSIDE observed_by.sensor)")
+        .query(format!("SELECT {RESOURCE_FIELDS} FROM resource WHERE resource_type INSIDE $types AND $sensor NOT INSIDE observed_by.sensor AND !array::is_empty(observed_by) AND archived_at IS NONE ORDER BY id LIMIT $limit"))
+        .bind(("sensor", params.sensor))
+        .bind(("limit", limit))
+        .await
+        .context("Failed to submit query to list sensor blind spots")?
+        .check_first_real_error()
+        .context("Failed to list sensor blind spots")?
+        .take::<Vec<Resource>>(1)?;
+
+    Ok(Json(BlindSpotsResponse { resources }))
+}
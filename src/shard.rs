@@ -0,0 +1,73 @@
+use std::ops::RangeInclusive;
+
+use axum::{
+    Extension,
+    extract::Request,
+    http::{StatusCode, uri::PathAndQuery},
+    middleware::Next,
+    response::{IntoResponse as _, Redirect, Response},
+};
+use sha2::{Digest as _, Sha256};
+use tracing::{instrument, warn};
+
+use archodex_error::PublicError;
+
+use crate::{Result, auth::ReportApiKeyAuth, env::Env};
+
+// Accounts are assigned to one of 65536 shard buckets using a stable hash of the account ID, so shard assignments don't
+// change between backend versions.
+pub(crate) fn account_shard_bucket(account_id: &str) -> u16 {
+    let hash = Sha256::digest(account_id.as_bytes());
+    u16::from_be_bytes([hash[0], hash[1]])
+}
+
+// Parses an inclusive shard bucket range of the form `<start>-<end>`, e.g. `0-32767`
+pub(crate) fn parse_shard_range(range: &str) -> Option<RangeInclusive<u16>> {
+    let (start, end) = range.trim().split_once('-')?;
+    let start = start.trim().parse::<u16>().ok()?;
+    let end = end.trim().parse::<u16>().ok()?;
+
+    (start <= end).then_some(start..=end)
+}
+
+// Rejects report requests for accounts outside of the shard bucket range owned by this instance with a redirect to
+// the instance that owns the account. Does nothing if ingestion sharding is not configured.
+#[instrument(err, skip_all)]
+pub(crate) async fn route_report_to_shard(
+    Extension(auth): Extension<ReportApiKeyAuth>,
+    req: Request,
+    next: Next,
+) -> Result<Response> {
+    let Some(owned_range) = Env::ingestion_shard_range() else {
+        return Ok(next.run(req).await);
+    };
+
+    let bucket = account_shard_bucket(auth.account_id());
+
+    if owned_range.contains(&bucket) {
+        return Ok(next.run(req).await);
+    }
+
+    let Some((_, shard_endpoint)) = Env::ingestion_shards()
+        .iter()
+        .find(|(range, _)| range.contains(&bucket))
+    else {
+        warn!(
+            account_id = auth.account_id(),
+            bucket, "No ingestion shard is configured for account"
+        );
+        return Err(PublicError::new(
+            StatusCode::MISDIRECTED_REQUEST,
+            "No ingestion shard is configured for this account",
+        ));
+    };
+
+    let path_and_query = req.uri().path_and_query().map_or("/", PathAndQuery::as_str);
+
+    // 307 preserves the request method and body, so agents can replay the report against the owning shard
+    Ok(Redirect::temporary(&format!(
+        "{}{path_and_query}",
+        shard_endpoint.trim_end_matches('/')
+    ))
+    .into_response())
+}
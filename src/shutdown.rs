@@ -0,0 +1,26 @@
+// The host initiates shutdown once it stops accepting connections, e.g. on SIGTERM during a rolling deploy. In-flight
+// requests, including report batches still being streamed, run to completion while connections drain. Responses that
+// would otherwise never complete, i.e. event streams, end once shutdown is initiated so their connections can drain
+// too. Clients reconnect to another instance.
+
+use std::sync::LazyLock;
+
+use tokio::sync::watch;
+
+static SHUTDOWN: LazyLock<watch::Sender<bool>> = LazyLock::new(|| watch::Sender::new(false));
+
+pub fn initiate() {
+    SHUTDOWN.send_replace(true);
+}
+
+pub(crate) fn is_initiated() -> bool {
+    *SHUTDOWN.borrow()
+}
+
+// Resolves once shutdown has been initiated
+pub async fn initiated() {
+    let mut receiver = SHUTDOWN.subscribe();
+
+    // The sender is static, so this can't fail
+    let _ = receiver.wait_for(|initiated| *initiated).await;
+}
@@ -0,0 +1,47 @@
+// Records who a request acts for into its request span once the request is authenticated, so every log line and span of
+// the request can be filtered by account, user, or report key without each handler adding the fields itself. User IDs
+// are hashed so that logs don't identify users.
+//
+// Authentication and account lookups run within spans of their own, so the request span is captured in the request's
+// extensions before any of them run.
+
+use axum::{extract::Request, middleware::Next, response::Response};
+use tracing::Span;
+
+use crate::{
+    account::Account,
+    auth::{DashboardAuth, ReportApiKeyAuth},
+};
+
+#[derive(Clone)]
+struct RequestSpan(Span);
+
+// Must be layered directly within the layer creating the request span
+pub(crate) async fn capture_request_span(mut req: Request, next: Next) -> Response {
+    req.extensions_mut().insert(RequestSpan(Span::current()));
+
+    next.run(req).await
+}
+
+// Records the tenant fields of the request span from the credentials and account resolved so far. Layered after each
+// middleware that resolves them.
+pub(crate) async fn record_tenant(req: Request, next: Next) -> Response {
+    let extensions = req.extensions();
+
+    if let Some(RequestSpan(span)) = extensions.get::<RequestSpan>() {
+        if let Some(auth) = extensions.get::<DashboardAuth>() {
+            span.record("user_id", auth.principal().id_hash());
+        }
+
+        if let Some(auth) = extensions.get::<ReportApiKeyAuth>() {
+            span.record("account_id", auth.account_id());
+            span.record("report_api_key_id", auth.key_id());
+        }
+
+        if let Some(account) = extensions.get::<Account>() {
+            span.record("account_id", account.id());
+        }
+    }
+
+    next.run(req).await
+}
@@ -0,0 +1,81 @@
+// A backend instance started with `STANDBY=true` is a warm standby. It serves read-only dashboard traffic, e.g. queries
+// and exports, while the primary instance is unavailable, but refuses every request that could write with a retryable
+// `read_only_standby` error. The standby neither migrates the accounts database nor runs background jobs, as its
+// database may be a read-only replica of the primary's.
+//
+// SAML sign in records consumed assertions, sign in codes, and sessions in the accounts database, so it is refused like
+// any other write while the instance is a standby. Users of accounts with SAML sign in can sign in once the standby is
+// promoted. OIDC sign in doesn't write, so it is served, see `router::is_read_only`.
+//
+// Operators promote a standby to a primary with `POST /standby/promote`, authenticated with the
+// `STANDBY_PROMOTION_TOKEN` bearer token, once its database accepts writes. Promotion migrates the accounts database,
+// starts accepting writes, and starts background jobs. Promotion can't be undone without restarting the instance.
+
+use std::{sync::LazyLock, time::Duration};
+
+use axum::{extract::Request, http::HeaderMap, middleware::Next, response::Response};
+use tokio::sync::watch;
+use tracing::{info, instrument};
+
+use archodex_error::{ErrorCode, PublicError, anyhow::Context as _, conflict, not_found};
+
+use crate::{Result, auth::ensure_operator_token, env::Env, router};
+
+pub(crate) const PROMOTE_PATH: &str = "/standby/promote";
+
+// Failovers to the primary instance, or promotions of the standby, are expected to take at least this long
+const STANDBY_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+static STANDBY: LazyLock<watch::Sender<bool>> =
+    LazyLock::new(|| watch::Sender::new(Env::standby()));
+
+/// Whether this instance is a read-only standby that has not been promoted
+#[must_use]
+pub fn is_standby() -> bool {
+    *STANDBY.borrow()
+}
+
+/// Resolves once this instance is a primary, i.e. immediately unless it was started as a standby
+pub async fn promoted() {
+    let mut standby = STANDBY.subscribe();
+
+    // The sender is static, so it is never dropped while receivers wait for it
+    let _ = standby.wait_for(|standby| !standby).await;
+}
+
+// Must be layered as a route layer, so that read-only routes can be recognized by their matched path
+pub(crate) async fn refuse_writes(req: Request, next: Next) -> Result<Response> {
+    if is_standby() && !router::is_read_only(&req) && req.uri().path() != PROMOTE_PATH {
+        return Err(PublicError::with_code(
+            ErrorCode::ReadOnlyStandby,
+            "This backend instance is a read-only standby and refuses writes",
+        )
+        .retry_after(STANDBY_RETRY_AFTER));
+    }
+
+    Ok(next.run(req).await)
+}
+
+#[instrument(err, skip_all)]
+pub(crate) async fn promote(headers: HeaderMap) -> Result<()> {
+    // Instances that can't be promoted don't reveal whether they are standbys
+    let Some(promotion_token) = Env::standby_promotion_token() else {
+        not_found!("Not found");
+    };
+
+    ensure_operator_token(&headers, promotion_token, "standby promotion")?;
+
+    if !is_standby() {
+        conflict!("Instance is not a standby");
+    }
+
+    migrator::migrate_accounts_database(Env::accounts_surrealdb_url(), Env::surrealdb_creds())
+        .await
+        .context("Failed to migrate accounts database on standby promotion")?;
+
+    if STANDBY.send_replace(false) {
+        info!("Promoted standby instance to primary");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,132 @@
+// Names of the SurrealDB namespaces and databases the backend stores data in. archodex.com stores each account's
+// resources database in a namespace of its own, while self-hosted instances serve a single account and keep both the
+// accounts and resources databases in one fixed namespace. Connections select databases through the naming of the
+// build, so names from one scheme are never used to address data laid out by the other.
+
+use std::borrow::Cow;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum TenantNaming {
+    // Resources databases are in namespace `a<account_id>`
+    NamespacePerAccount,
+    // Resources databases are in the accounts namespace
+    SingleTenant,
+}
+
+// Must match the names the migrator defines the accounts database with
+const ACCOUNTS_NAMESPACE: &str = "archodex";
+const ACCOUNTS_DATABASE: &str = "accounts";
+const RESOURCES_DATABASE: &str = "resources";
+
+impl TenantNaming {
+    pub(crate) const fn current() -> Self {
+        if cfg!(feature = "archodex-com") {
+            Self::NamespacePerAccount
+        } else {
+            Self::SingleTenant
+        }
+    }
+
+    pub(crate) fn accounts_namespace(self) -> &'static str {
+        ACCOUNTS_NAMESPACE
+    }
+
+    pub(crate) fn accounts_database(self) -> &'static str {
+        ACCOUNTS_DATABASE
+    }
+
+    pub(crate) fn resources_namespace(self, account_id: &str) -> Cow<'static, str> {
+        match self {
+            Self::NamespacePerAccount => Cow::Owned(format!("a{account_id}")),
+            Self::SingleTenant => Cow::Borrowed(ACCOUNTS_NAMESPACE),
+        }
+    }
+
+    pub(crate) fn resources_database(self) -> &'static str {
+        RESOURCES_DATABASE
+    }
+
+    // Whether the accounts and resources databases share a namespace, so that a single connection may switch between
+    // them with `USE DB`
+    #[cfg(feature = "rocksdb")]
+    pub(crate) fn shares_namespace(self) -> bool {
+        self == Self::SingleTenant
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TenantNaming;
+
+    const ACCOUNT_ID: &str = "1234567890";
+    const OTHER_ACCOUNT_ID: &str = "1234567891";
+
+    #[test]
+    fn current() {
+        #[cfg(feature = "archodex-com")]
+        assert_eq!(TenantNaming::current(), TenantNaming::NamespacePerAccount);
+        #[cfg(not(feature = "archodex-com"))]
+        assert_eq!(TenantNaming::current(), TenantNaming::SingleTenant);
+    }
+
+    #[test]
+    fn namespace_per_account() {
+        let naming = TenantNaming::NamespacePerAccount;
+
+        assert_eq!(naming.resources_namespace(ACCOUNT_ID), "a1234567890");
+        assert_ne!(
+            naming.resources_namespace(ACCOUNT_ID),
+            naming.resources_namespace(OTHER_ACCOUNT_ID)
+        );
+        assert_ne!(
+            naming.resources_namespace(ACCOUNT_ID),
+            naming.accounts_namespace()
+        );
+    }
+
+    #[test]
+    fn single_tenant() {
+        let naming = TenantNaming::SingleTenant;
+
+        assert_eq!(
+            naming.resources_namespace(ACCOUNT_ID),
+            naming.accounts_namespace()
+        );
+        assert_eq!(
+            naming.resources_namespace(ACCOUNT_ID),
+            naming.resources_namespace(OTHER_ACCOUNT_ID)
+        );
+    }
+
+    // Resources databases of one scheme must never be addressed by names of the other, and accounts databases are
+    // named the same by both schemes so the migrator can define them
+    #[test]
+    fn schemes_do_not_mix() {
+        let per_account = TenantNaming::NamespacePerAccount;
+        let single_tenant = TenantNaming::SingleTenant;
+
+        assert_ne!(
+            per_account.resources_namespace(ACCOUNT_ID),
+            single_tenant.resources_namespace(ACCOUNT_ID)
+        );
+        assert_eq!(
+            per_account.accounts_namespace(),
+            single_tenant.accounts_namespace()
+        );
+        assert_eq!(
+            per_account.accounts_database(),
+            single_tenant.accounts_database()
+        );
+
+        for naming in [per_account, single_tenant] {
+            assert_ne!(naming.accounts_database(), naming.resources_database());
+        }
+    }
+
+    #[cfg(feature = "rocksdb")]
+    #[test]
+    fn shares_namespace() {
+        assert!(!TenantNaming::NamespacePerAccount.shares_namespace());
+        assert!(TenantNaming::SingleTenant.shares_namespace());
+    }
+}
@@ -0,0 +1,59 @@
+// All API timestamps are RFC3339 datetimes in UTC, e.g. `2025-01-31T12:34:56.789Z`, which is how `DateTime<Utc>` fields
+// are serialized in responses. Older agents sent timestamps as integer epoch seconds, so request timestamps also accept
+// epoch seconds during a deprecation window, counted by `deprecated_epoch_timestamps_total`. Use with
+// `#[serde(deserialize_with = "crate::timestamp::deserialize")]`.
+
+use chrono::{DateTime, Utc};
+
+use crate::metrics::DEPRECATED_EPOCH_TIMESTAMPS_TOTAL;
+
+pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    struct Visitor;
+
+    impl serde::de::Visitor<'_> for Visitor {
+        type Value = DateTime<Utc>;
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            formatter.write_str("an RFC3339 datetime String or integer epoch seconds")
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            DateTime::parse_from_rfc3339(v)
+                .map(|datetime| datetime.with_timezone(&Utc))
+                .map_err(|_| serde::de::Error::invalid_value(serde::de::Unexpected::Str(v), &self))
+        }
+
+        fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            DEPRECATED_EPOCH_TIMESTAMPS_TOTAL.inc();
+
+            DateTime::from_timestamp(v, 0).ok_or_else(|| {
+                serde::de::Error::invalid_value(serde::de::Unexpected::Signed(v), &self)
+            })
+        }
+
+        fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            let Ok(v) = i64::try_from(v) else {
+                return Err(serde::de::Error::invalid_value(
+                    serde::de::Unexpected::Unsigned(v),
+                    &self,
+                ));
+            };
+
+            self.visit_i64(v)
+        }
+    }
+
+    deserializer.deserialize_any(Visitor)
+}
@@ -0,0 +1,43 @@
+// Containment type paths observed in the account's resources, e.g. `AWS Partition → AWS Account → AWS Region → S3
+// Bucket`, with the number of resources at the end of each path. Paths are maintained by the resources database as
+// resources are ingested, so listing them doesn't scan resources. The dashboard uses them for hierarchy pickers, and
+// unexpected paths point to agents reporting anomalous structures.
+
+use axum::{Extension, Json};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use archodex_error::anyhow::Context as _;
+
+use crate::{Result, account::Account, db::QueryCheckFirstRealError as _};
+
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
+pub(crate) struct TypePath {
+    path: Vec<String>,
+    resources: u64,
+    first_seen_at: DateTime<Utc>,
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct ListTypePathsResponse {
+    type_paths: Vec<TypePath>,
+}
+
+#[instrument(err, skip_all)]
+pub(crate) async fn list_type_paths(
+    Extension(account): Extension<Account>,
+) -> Result<Json<ListTypePathsResponse>> {
+    let type_paths = account
+        .resources_db()
+        .await?
+        .query("SELECT record::id(id) AS path, resources, first_seen_at FROM type_path WHERE resources > 0 ORDER BY path")
+        .await
+        .context("Failed to submit query to list type paths")?
+        .check_first_real_error()
+        .context("Failed to list type paths")?
+        .take::<Vec<TypePath>>(0)?;
+
+    Ok(Json(ListTypePathsResponse { type_paths }))
+}
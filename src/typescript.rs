@@ -0,0 +1,296 @@
+// TypeScript definitions of API response bodies, served at `GET /types.d.ts` so the dashboard can type its API client
+// from the backend it talks to. The definitions are rendered from JSON Schemas of the response types as they are
+// serialized, so they can't drift from the responses. `examples/generate_types.rs` writes them to `types/api.d.ts`,
+// making contract changes visible in diffs.
+
+use std::{fmt::Write as _, sync::LazyLock};
+
+use axum::{http::header::CONTENT_TYPE, response::IntoResponse};
+use schemars::{JsonSchema, SchemaGenerator, generate::SchemaSettings};
+use serde_json::{Map, Value};
+
+use crate::{
+    accounts, agents, api_tokens, audit, aws_config, bundle, dead_letter, environments,
+    event_retention, export_encryption, graph_stream, health, import, ingestion_token, integrity,
+    job, lifecycle, members, notifications, operations, principal_chain, query, report,
+    report_api_key_requests, report_api_keys, resource, resource_deletion, saml, search, sensor,
+    type_path, usage, webhooks,
+};
+
+const HEADER: &str = "// Generated from the API response types of archodex-backend. Do not edit.\n";
+
+static DEFINITIONS: LazyLock<String> = LazyLock::new(definitions);
+
+pub(crate) async fn types() -> impl IntoResponse {
+    (
+        [(CONTENT_TYPE, "application/typescript")],
+        DEFINITIONS.as_str(),
+    )
+}
+
+/// Renders TypeScript definitions of every API response body
+#[must_use]
+pub fn definitions() -> String {
+    let mut generator = SchemaSettings::draft2020_12()
+        .for_serialize()
+        .into_generator();
+
+    add::<archodex_error::PublicErrorMessage>(&mut generator);
+    add::<accounts::ListAccountsResponse>(&mut generator);
+    add::<accounts::IngestionPipelineConfig>(&mut generator);
+    add::<agents::ListAgentsResponse>(&mut generator);
+    add::<agents::RegisterAgentResponse>(&mut generator);
+    add::<api_tokens::ListApiTokensResponse>(&mut generator);
+    add::<api_tokens::CreateApiTokenResponse>(&mut generator);
+    add::<audit::ListAuditLogResponse>(&mut generator);
+    add::<audit::AuditLogRetention>(&mut generator);
+    add::<aws_config::ImportAwsConfigResponse>(&mut generator);
+    add::<bundle::ImportBundleResponse>(&mut generator);
+    add::<import::ImportResponse>(&mut generator);
+    add::<dead_letter::DeadLetterReport>(&mut generator);
+    add::<dead_letter::ListDeadLetterReportsResponse>(&mut generator);
+    add::<dead_letter::RetryDeadLetterReportResponse>(&mut generator);
+    add::<environments::Environment>(&mut generator);
+    add::<environments::ListEnvironmentsResponse>(&mut generator);
+    add::<event_retention::EventRetention>(&mut generator);
+    add::<export_encryption::ExportEncryptionKey>(&mut generator);
+    add::<export_encryption::GetExportEncryptionKeyResponse>(&mut generator);
+    add::<graph_stream::GraphUpdate>(&mut generator);
+    add::<health::AccountHealth>(&mut generator);
+    add::<ingestion_token::ExchangeResponse>(&mut generator);
+    add::<integrity::IntegrityCheck>(&mut generator);
+    add::<integrity::ListIntegrityChecksResponse>(&mut generator);
+    add::<job::JobProgress>(&mut generator);
+    add::<lifecycle::ArchiveResourceResponse>(&mut generator);
+    add::<resource_deletion::DeleteResourcesResponse>(&mut generator);
+    add::<members::ListMembersResponse>(&mut generator);
+    add::<members::ListInvitationsResponse>(&mut generator);
+    add::<members::CreateInvitationResponse>(&mut generator);
+    add::<notifications::ListNotificationsResponse>(&mut generator);
+    add::<notifications::UnreadNotificationCountResponse>(&mut generator);
+    add::<operations::ExecuteOperationsResponse>(&mut generator);
+    add::<principal_chain::GetResponse>(&mut generator);
+    add::<principal_chain::TraverseResponse>(&mut generator);
+    add::<query::QueryResponse>(&mut generator);
+    add::<report::ReportResponse>(&mut generator);
+    add::<report::ReportBatchResponse>(&mut generator);
+    add::<report::ReportValidationResponse>(&mut generator);
+    add::<report_api_key_requests::ListReportApiKeyRequestsResponse>(&mut generator);
+    add::<report_api_key_requests::RetrieveReportApiKeyValueResponse>(&mut generator);
+    add::<report_api_keys::ListReportApiKeysResponse>(&mut generator);
+    add::<report_api_keys::RevealReportApiKeyValueResponse>(&mut generator);
+    add::<report_api_keys::RotateReportApiKeyResponse>(&mut generator);
+    add::<resource::ListResourcesResponse>(&mut generator);
+    add::<resource::SetTagsResponse>(&mut generator);
+    add::<saml::SamlConfig>(&mut generator);
+    add::<saml::CreateSessionResponse>(&mut generator);
+    add::<search::SearchResponse>(&mut generator);
+    add::<search::AccountSearchResponse>(&mut generator);
+    add::<sensor::CoverageResponse>(&mut generator);
+    add::<sensor::BlindSpotsResponse>(&mut generator);
+    add::<type_path::ListTypePathsResponse>(&mut generator);
+    add::<usage::AccountUsage>(&mut generator);
+    add::<usage::ListStatementsResponse>(&mut generator);
+    add::<webhooks::ListWebhooksResponse>(&mut generator);
+    add::<webhooks::CreateWebhookResponse>(&mut generator);
+
+    #[cfg(not(feature = "archodex-com"))]
+    add::<crate::oidc::ListProvidersResponse>(&mut generator);
+
+    #[cfg(feature = "archodex-com")]
+    {
+        add::<crate::account_link::ListAccountLinksResponse>(&mut generator);
+        add::<crate::account_link::CreateAccountLinkResponse>(&mut generator);
+        add::<crate::provisioning::AccountStatusResponse>(&mut generator);
+        add::<crate::resource_sharing::ListLinkedAccountsResponse>(&mut generator);
+        add::<crate::resource_sharing::ShareResourcesResponse>(&mut generator);
+    }
+
+    let mut definitions = generator
+        .take_definitions(true)
+        .into_iter()
+        .collect::<Vec<_>>();
+    definitions.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut output = HEADER.to_string();
+
+    for (name, schema) in definitions {
+        output.push('\n');
+        write_description(&mut output, &schema, 0);
+        let _ = writeln!(output, "export type {name} = {};", ts_type(&schema, 0));
+    }
+
+    output
+}
+
+fn add<T: JsonSchema>(generator: &mut SchemaGenerator) {
+    generator.subschema_for::<T>();
+}
+
+fn write_description(output: &mut String, schema: &Value, indent: usize) {
+    let Some(description) = schema.get("description").and_then(Value::as_str) else {
+        return;
+    };
+
+    let indent = "  ".repeat(indent);
+
+    let _ = writeln!(output, "{indent}/**");
+    for line in description.lines() {
+        let _ = writeln!(output, "{indent} * {line}");
+    }
+    let _ = writeln!(output, "{indent} */");
+}
+
+// Renders the TypeScript type of a JSON Schema. Only the subset of JSON Schema generated for serde types is supported,
+// anything else is rendered as `unknown`.
+fn ts_type(schema: &Value, indent: usize) -> String {
+    let schema = match schema {
+        Value::Bool(true) => return "unknown".to_string(),
+        Value::Bool(false) => return "never".to_string(),
+        Value::Object(schema) => schema,
+        _ => return "unknown".to_string(),
+    };
+
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+        return reference
+            .rsplit('/')
+            .next()
+            .unwrap_or(reference)
+            .to_string();
+    }
+
+    if let Some(value) = schema.get("const") {
+        return value.to_string();
+    }
+
+    if let Some(Value::Array(values)) = schema.get("enum") {
+        return union(values.iter().map(Value::to_string));
+    }
+
+    for keyword in ["oneOf", "anyOf"] {
+        if let Some(Value::Array(variants)) = schema.get(keyword) {
+            return union(variants.iter().map(|variant| ts_type(variant, indent)));
+        }
+    }
+
+    if let Some(Value::Array(schemas)) = schema.get("allOf") {
+        return schemas
+            .iter()
+            .map(|schema| ts_type(schema, indent))
+            .collect::<Vec<_>>()
+            .join(" & ");
+    }
+
+    match schema.get("type") {
+        Some(Value::String(r#type)) => type_of(r#type, schema, indent),
+        Some(Value::Array(types)) => union(
+            types
+                .iter()
+                .filter_map(Value::as_str)
+                .map(|r#type| type_of(r#type, schema, indent)),
+        ),
+        None if schema.contains_key("properties") => object_type(schema, indent),
+        _ => "unknown".to_string(),
+    }
+}
+
+fn type_of(r#type: &str, schema: &Map<String, Value>, indent: usize) -> String {
+    match r#type {
+        "string" => "string".to_string(),
+        "integer" | "number" => "number".to_string(),
+        "boolean" => "boolean".to_string(),
+        "null" => "null".to_string(),
+        "array" => array_type(schema, indent),
+        "object" => object_type(schema, indent),
+        _ => "unknown".to_string(),
+    }
+}
+
+fn array_type(schema: &Map<String, Value>, indent: usize) -> String {
+    if let Some(Value::Array(items)) = schema.get("prefixItems") {
+        let items = items
+            .iter()
+            .map(|item| ts_type(item, indent))
+            .collect::<Vec<_>>();
+        return format!("[{}]", items.join(", "));
+    }
+
+    let item = schema
+        .get("items")
+        .map_or_else(|| "unknown".to_string(), |items| ts_type(items, indent));
+
+    if item.contains(' ') && !item.starts_with('{') {
+        format!("({item})[]")
+    } else {
+        format!("{item}[]")
+    }
+}
+
+fn object_type(schema: &Map<String, Value>, indent: usize) -> String {
+    let properties = schema.get("properties").and_then(Value::as_object);
+    let additional_properties = schema
+        .get("additionalProperties")
+        .filter(|additional_properties| **additional_properties != Value::Bool(false));
+
+    let Some(properties) = properties.filter(|properties| !properties.is_empty()) else {
+        let value = additional_properties.map_or_else(
+            || "unknown".to_string(),
+            |additional_properties| ts_type(additional_properties, indent),
+        );
+        return format!("Record<string, {value}>");
+    };
+
+    let required = schema
+        .get("required")
+        .and_then(Value::as_array)
+        .map(|required| {
+            required
+                .iter()
+                .filter_map(Value::as_str)
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    let property_indent = "  ".repeat(indent + 1);
+    let mut output = "{\n".to_string();
+
+    for (name, property) in properties {
+        write_description(&mut output, property, indent + 1);
+
+        let optional = if required.contains(&name.as_str()) {
+            ""
+        } else {
+            "?"
+        };
+
+        let _ = writeln!(
+            output,
+            "{property_indent}{name}{optional}: {};",
+            ts_type(property, indent + 1)
+        );
+    }
+
+    if let Some(additional_properties) = additional_properties {
+        let _ = writeln!(
+            output,
+            "{property_indent}[key: string]: {};",
+            ts_type(additional_properties, indent + 1)
+        );
+    }
+
+    output.push_str(&"  ".repeat(indent));
+    output.push('}');
+
+    output
+}
+
+fn union(types: impl Iterator<Item = String>) -> String {
+    let mut types = types.collect::<Vec<_>>();
+    types.dedup();
+
+    if types.is_empty() {
+        "never".to_string()
+    } else {
+        types.join(" | ")
+    }
+}
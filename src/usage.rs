@@ -0,0 +1,441 @@
+// Usage is metered per UTC day in each account's resources database. Every ingested report increments the counters of
+// the current day's `usage_daily` record in the same transaction as the report itself, and a periodic job and resource
+// deletions sample the size of the resource graph. Once a month has ended, the job rolls its daily records up into an
+// immutable `usage_statement` record that customers can reconcile invoices against.
+//
+// The job also samples the footprint of each account, i.e. its resources, events, storage, and reports ingested this
+// month, into its `account_usage` record in the accounts database. Reports are rejected once the latest sample reaches
+// a quota of the account's entitlements, so an account can exceed a quota by up to an hour of ingestion.
+
+use std::{
+    collections::HashMap,
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+
+use axum::{Extension, Json};
+use chrono::{DateTime, Datelike as _, Months, NaiveDate, NaiveTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use tracing::{info, instrument};
+
+use archodex_error::{
+    ErrorCode, PublicError,
+    anyhow::{Context as _, anyhow},
+    coded_error,
+};
+
+use crate::{
+    Result,
+    account::Account,
+    accounts::for_each_account_periodically,
+    db::{DBConnection, InstrumentedQuery, QueryCheckFirstRealError as _, accounts_db},
+};
+
+const USAGE_ROLLUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Samples only change hourly, so quota checks can use slightly stale samples instead of reading them on every report
+const USAGE_SAMPLE_CACHE_TTL: Duration = Duration::from_secs(60);
+
+// Tables whose records make up the storage footprint of an account
+const STORAGE_TABLES: &[&str] = &[
+    "resource",
+    "attribute_document",
+    "contains",
+    "type_path",
+    "principal_chain",
+    "event",
+    "dead_letter_report",
+];
+
+static USAGE_SAMPLES: LazyLock<RwLock<HashMap<String, CachedUsageSample>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+struct CachedUsageSample {
+    loaded_at: Instant,
+    sample: Option<UsageSample>,
+}
+
+pub(crate) fn usage_day(now: DateTime<Utc>) -> String {
+    now.format("%Y-%m-%d").to_string()
+}
+
+fn usage_month(now: DateTime<Utc>) -> String {
+    now.format("%Y-%m").to_string()
+}
+
+fn next_usage_month_start(now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    Some(
+        NaiveDate::from_ymd_opt(now.year(), now.month(), 1)?
+            .checked_add_months(Months::new(1))?
+            .and_time(NaiveTime::MIN)
+            .and_utc(),
+    )
+}
+
+// Adds a statement to a report ingestion transaction that meters the report against the current day
+pub(crate) fn meter_report(
+    query: InstrumentedQuery<'_>,
+    resources_ingested: u64,
+    events_recorded: u64,
+) -> InstrumentedQuery<'_> {
+    query
+        .query("UPSERT type::thing('usage_daily', $usage_day) SET reports_ingested += 1, resources_ingested += $usage_resources_ingested, events_recorded += $usage_events_recorded RETURN NONE;")
+        .bind(("usage_day", usage_day(Utc::now())))
+        .bind(("usage_resources_ingested", resources_ingested))
+        .bind(("usage_events_recorded", events_recorded))
+}
+
+// Resources only leave the graph when they are deleted, as resources removed by reports are only marked gone. Deletions
+// sample the graph size before deleting resources, so together with hourly sampling the daily peak is observed.
+const SAMPLE_PEAK_GRAPH_SIZE_STATEMENT: &str = "UPSERT type::thing('usage_daily', $usage_day) SET peak_graph_size = math::max([peak_graph_size ?? 0, (SELECT count() AS count FROM resource WHERE id != resource:[] GROUP ALL)[0].count ?? 0]) RETURN NONE;";
+
+#[instrument(err, skip_all)]
+pub(crate) async fn sample_peak_graph_size(db: &DBConnection) -> Result<()> {
+    db.query(SAMPLE_PEAK_GRAPH_SIZE_STATEMENT)
+        .bind(("usage_day", usage_day(Utc::now())))
+        .await
+        .context("Failed to submit query to sample peak graph size")?
+        .check_first_real_error()
+        .context("Failed to sample peak graph size")?;
+
+    Ok(())
+}
+
+// Failed reports are metered outside of the failed ingestion transaction, which has been rolled back
+#[instrument(err, skip_all)]
+pub(crate) async fn meter_failed_report(account: &Account) -> Result<()> {
+    account
+        .resources_db()
+        .await?
+        .query("UPSERT type::thing('usage_daily', $usage_day) SET reports_failed += 1 RETURN NONE;")
+        .bind(("usage_day", usage_day(Utc::now())))
+        .await
+        .context("Failed to submit query to meter failed report")?
+        .check_first_real_error()
+        .context("Failed to meter failed report")?;
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
+pub(crate) struct UsageStatement {
+    // Statement month, e.g. `2025-01`
+    #[serde(
+        rename(serialize = "month"),
+        deserialize_with = "crate::surrealdb_deserializers::string::deserialize"
+    )]
+    id: String,
+    period_start: DateTime<Utc>,
+    period_end: DateTime<Utc>,
+    reports_ingested: u64,
+    resources_ingested: u64,
+    events_recorded: u64,
+    peak_graph_size: u64,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct ListStatementsResponse {
+    statements: Vec<UsageStatement>,
+}
+
+// Footprint of an account at the time it was last sampled, stored in its `account_usage` record
+#[derive(Clone, Debug, Deserialize)]
+struct UsageSample {
+    sampled_at: DateTime<Utc>,
+    resources: u64,
+    events: u64,
+    storage_bytes: u64,
+    // UTC month that `reports_ingested` was counted in
+    month: String,
+    reports_ingested: u64,
+}
+
+impl UsageSample {
+    // Samples from earlier months don't count reports of the current month until the account is sampled again
+    fn reports_this_month(&self, now: DateTime<Utc>) -> u64 {
+        if self.month == usage_month(now) {
+            self.reports_ingested
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct QuotaUtilization {
+    pub(crate) used: u64,
+    pub(crate) limit: Option<u64>,
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct AccountUsage {
+    // When the footprint of the account was last sampled, or `None` if it has not been sampled yet
+    sampled_at: Option<DateTime<Utc>>,
+    resources: QuotaUtilization,
+    events: QuotaUtilization,
+    // Estimated from the serialized size of the account's records
+    storage_bytes: QuotaUtilization,
+    // Reports ingested in the current UTC month
+    reports_this_month: QuotaUtilization,
+}
+
+#[instrument(err)]
+async fn load_usage_sample(account_id: &str) -> Result<Option<UsageSample>> {
+    Ok(accounts_db()
+        .await?
+        .query("SELECT * FROM type::thing('account_usage', $account_id)")
+        .bind(("account_id", account_id.to_owned()))
+        .await
+        .context("Failed to submit query to get account usage")?
+        .check_first_real_error()
+        .context("Failed to get account usage")?
+        .take::<Option<UsageSample>>(0)?)
+}
+
+async fn cached_usage_sample(account_id: &str) -> Result<Option<UsageSample>> {
+    if let Some(cached) = USAGE_SAMPLES.read().await.get(account_id)
+        && cached.loaded_at.elapsed() < USAGE_SAMPLE_CACHE_TTL
+    {
+        return Ok(cached.sample.clone());
+    }
+
+    let sample = load_usage_sample(account_id).await?;
+
+    USAGE_SAMPLES.write().await.insert(
+        account_id.to_owned(),
+        CachedUsageSample {
+            loaded_at: Instant::now(),
+            sample: sample.clone(),
+        },
+    );
+
+    Ok(sample)
+}
+
+// Rejects reports to accounts whose latest usage sample has reached a quota. Quotas on the size of the account can only
+// be lifted by archiving resources or upgrading the plan, while the monthly report quota resets at the start of the
+// next month.
+#[instrument(err, skip_all, fields(account_id = account.id()))]
+pub(crate) async fn check_quotas(account: &Account) -> Result<()> {
+    let entitlements = account.entitlements();
+
+    if entitlements.max_resources.is_none()
+        && entitlements.max_events.is_none()
+        && entitlements.max_storage_bytes.is_none()
+        && entitlements.max_reports_per_month.is_none()
+    {
+        return Ok(());
+    }
+
+    let Some(sample) = cached_usage_sample(account.id()).await? else {
+        return Ok(());
+    };
+
+    for (quota, used, limit) in [
+        ("resources", sample.resources, entitlements.max_resources),
+        ("events", sample.events, entitlements.max_events),
+        (
+            "bytes of storage",
+            sample.storage_bytes,
+            entitlements.max_storage_bytes,
+        ),
+    ] {
+        if let Some(limit) = limit
+            && used >= limit
+        {
+            coded_error!(
+                ErrorCode::PlanQuotaExceeded,
+                "Account has reached its quota of {limit} {quota}, archive unused resources or upgrade the plan"
+            );
+        }
+    }
+
+    let now = Utc::now();
+
+    if let Some(limit) = entitlements.max_reports_per_month
+        && sample.reports_this_month(now) >= limit
+    {
+        let mut err = PublicError::with_code(
+            ErrorCode::QuotaExceeded,
+            format!("Account has reached its quota of {limit} reports this month"),
+        );
+
+        if let Some(retry_after) = next_usage_month_start(now)
+            .and_then(|next_month_start| (next_month_start - now).to_std().ok())
+        {
+            err = err.retry_after(retry_after);
+        }
+
+        return Err(err);
+    }
+
+    Ok(())
+}
+
+#[instrument(err, skip_all)]
+pub(crate) async fn get_usage(
+    Extension(account): Extension<Account>,
+) -> Result<Json<AccountUsage>> {
+    let entitlements = account.entitlements();
+    let sample = load_usage_sample(account.id()).await?;
+
+    let utilization = |used: fn(&UsageSample) -> u64, limit: Option<u64>| QuotaUtilization {
+        used: sample.as_ref().map(used).unwrap_or_default(),
+        limit,
+    };
+
+    let now = Utc::now();
+
+    Ok(Json(AccountUsage {
+        sampled_at: sample.as_ref().map(|sample| sample.sampled_at),
+        resources: utilization(|sample| sample.resources, entitlements.max_resources),
+        events: utilization(|sample| sample.events, entitlements.max_events),
+        storage_bytes: utilization(
+            |sample| sample.storage_bytes,
+            entitlements.max_storage_bytes,
+        ),
+        reports_this_month: QuotaUtilization {
+            used: sample
+                .as_ref()
+                .map(|sample| sample.reports_this_month(now))
+                .unwrap_or_default(),
+            limit: entitlements.max_reports_per_month,
+        },
+    }))
+}
+
+#[instrument(err, skip_all)]
+pub(crate) async fn list_statements(
+    Extension(account): Extension<Account>,
+) -> Result<Json<ListStatementsResponse>> {
+    let statements = account
+        .resources_db()
+        .await?
+        .query("SELECT * FROM usage_statement ORDER BY id DESC")
+        .await
+        .context("Failed to submit query to list usage statements")?
+        .check_first_real_error()
+        .context("Failed to list usage statements")?
+        .take::<Vec<UsageStatement>>(0)?;
+
+    Ok(Json(ListStatementsResponse { statements }))
+}
+
+/// Periodically samples the resource graph size and footprint of every account and rolls up usage of completed months
+/// into monthly statements. Statements are only ever created once, so multiple backend instances may run this
+/// concurrently.
+pub async fn roll_up_periodically() {
+    for_each_account_periodically(
+        USAGE_ROLLUP_INTERVAL,
+        "usage rollup",
+        |account| async move { roll_up(&account).await },
+    )
+    .await;
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Footprint {
+    resources: u64,
+    events: u64,
+    storage_bytes: u64,
+    reports_ingested: u64,
+}
+
+#[instrument(err, skip_all, fields(account_id = account.id()))]
+async fn roll_up(account: &Account) -> Result<()> {
+    let db = account.resources_db().await?;
+    let now = Utc::now();
+    let current_month = usage_month(now);
+
+    let mut res = db
+        .query(SAMPLE_PEAK_GRAPH_SIZE_STATEMENT)
+        .bind(("usage_day", usage_day(now)))
+        .query("array::distinct(SELECT VALUE string::slice(record::id(id), 0, 7) FROM usage_daily)")
+        .query("SELECT VALUE record::id(id) FROM usage_statement")
+        // The storage footprint is estimated from a bounded sample of each table, see `fn::estimated_storage_bytes`
+        .query(
+            "RETURN {
+                resources: (SELECT count() AS count FROM resource WHERE id != resource:[] GROUP ALL)[0].count ?? 0,
+                events: (SELECT count() AS count FROM event GROUP ALL)[0].count ?? 0,
+                storage_bytes: math::sum($storage_tables.map(|$table| fn::estimated_storage_bytes($table))),
+                reports_ingested: math::sum((SELECT VALUE reports_ingested FROM usage_daily WHERE string::starts_with(record::id(id), $usage_month))),
+            }",
+        )
+        .bind(("storage_tables", STORAGE_TABLES))
+        .bind(("usage_month", current_month.clone()))
+        .await
+        .context("Failed to submit query to sample account usage")?
+        .check_first_real_error()
+        .context("Failed to sample account usage")?;
+
+    let metered_months = res.take::<Vec<String>>(1)?;
+    let statement_months = res.take::<Vec<String>>(2)?;
+    let footprint = res
+        .take::<Option<Footprint>>(3)?
+        .context("Account footprint sample is missing")?;
+
+    accounts_db()
+        .await?
+        .query("UPSERT type::thing('account_usage', $account_id) SET sampled_at = time::now(), resources = $footprint.resources, events = $footprint.events, storage_bytes = $footprint.storage_bytes, month = $usage_month, reports_ingested = $footprint.reports_ingested RETURN NONE")
+        .bind(("account_id", account.id().to_owned()))
+        .bind(("footprint", footprint))
+        .bind(("usage_month", current_month.clone()))
+        .await
+        .context("Failed to submit query to record account usage")?
+        .check_first_real_error()
+        .context("Failed to record account usage")?;
+
+    for month in metered_months {
+        if month >= current_month || statement_months.contains(&month) {
+            continue;
+        }
+
+        create_statement(&db, &month).await?;
+    }
+
+    Ok(())
+}
+
+#[instrument(err, skip(db))]
+async fn create_statement(db: &DBConnection, month: &str) -> Result<()> {
+    let period_start = NaiveDate::parse_from_str(&format!("{month}-01"), "%Y-%m-%d")
+        .with_context(|| format!("Invalid usage month {month:?}"))?;
+    let period_end = period_start
+        .checked_add_months(Months::new(1))
+        .ok_or_else(|| anyhow!("Usage month {month:?} is out of range"))?;
+
+    // Another backend instance may have created the statement since the months were listed
+    db.query(
+        "IF !record::exists(type::thing('usage_statement', $month)) {
+            LET $days = SELECT * FROM usage_daily WHERE string::starts_with(record::id(id), $month);
+            CREATE type::thing('usage_statement', $month) CONTENT {
+                period_start: $period_start,
+                period_end: $period_end,
+                reports_ingested: math::sum($days.reports_ingested),
+                resources_ingested: math::sum($days.resources_ingested),
+                events_recorded: math::sum($days.events_recorded),
+                peak_graph_size: math::max($days.peak_graph_size) ?? 0,
+            } RETURN NONE;
+        };",
+    )
+    .bind(("month", month.to_owned()))
+    .bind((
+        "period_start",
+        surrealdb::sql::Datetime::from(period_start.and_time(NaiveTime::MIN).and_utc()),
+    ))
+    .bind((
+        "period_end",
+        surrealdb::sql::Datetime::from(period_end.and_time(NaiveTime::MIN).and_utc()),
+    ))
+    .await
+    .context("Failed to submit query to create usage statement")?
+    .check_first_real_error()
+    .context("Failed to create usage statement")?;
+
+    info!("Created usage statement");
+
+    Ok(())
+}
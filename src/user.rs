@@ -1,4 +1,6 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
 use surrealdb::Uuid;
 use tracing::instrument;
 
@@ -9,17 +11,31 @@ use crate::{
     surrealdb_deserializers,
 };
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Deserialize, JsonSchema, PartialEq, Serialize)]
 pub(crate) struct User {
     #[serde(deserialize_with = "surrealdb_deserializers::uuid::deserialize")]
     id: Uuid,
 }
 
+// Spans of functions taking a user record its `Debug` representation, which must not include the user's ID
+impl std::fmt::Debug for User {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("User")
+            .field("id_hash", &self.id_hash())
+            .finish()
+    }
+}
+
 impl User {
     pub(crate) fn new(id: Uuid) -> Self {
         Self { id }
     }
 
+    // Identifies the user in logs and traces without recording their ID
+    pub(crate) fn id_hash(&self) -> String {
+        hex::encode(&Sha256::digest(self.id.as_bytes())[..8])
+    }
+
     #[instrument(err)]
     pub(crate) async fn ensure_user_record_exists(&self) -> Result<()> {
         accounts_db()
@@ -34,39 +50,95 @@ impl User {
 
     #[cfg(feature = "archodex-com")]
     #[instrument(err)]
-    pub(crate) async fn next_account_id(&self) -> Result<String> {
+    pub(crate) async fn next_account_id(
+        &self,
+        endpoint: &str,
+        service_data_location: &crate::provisioning::ServiceDataLocation,
+    ) -> Result<String> {
         use crate::env::Env;
         use archodex_error::{anyhow::anyhow, conflict};
         use rand::Rng as _;
-        use tracing::info;
-
-        #[derive(Deserialize)]
-        struct NumUserAccountsResults {
-            num_user_accounts: u32,
-        }
-
-        let NumUserAccountsResults { num_user_accounts } = accounts_db()
-            .await?
-            .query("SELECT COUNT(->has_access->(account WHERE deleted_at IS NONE)) AS num_user_accounts FROM ONLY $user")
-            .bind(("user", surrealdb::sql::Thing::from(self)))
-            .await?
-            .check_first_real_error()?
-            .take::<Option<NumUserAccountsResults>>(0)?
-            .ok_or_else(|| anyhow!("Failed to query whether user has an account"))?;
-
-        info!(num_user_accounts, "Retrieved number of accounts for user");
-
-        if num_user_accounts >= Env::user_account_limit() {
-            conflict!("User account limit exceeded");
+        use surrealdb::sql::statements::{BeginStatement, CommitStatement};
+        use tracing::{info, warn};
+
+        const MAX_ACCOUNT_ID_ALLOCATION_ATTEMPTS: u32 = 5;
+        const USER_ACCOUNT_LIMIT_EXCEEDED: &str = "User account limit exceeded";
+
+        // Account IDs are chosen at random, so reserve the ID before any resources are provisioned for the account. The
+        // reservation fails to allocate the ID if an account or another reservation already uses it, in which case we
+        // try again with a new random ID. Accounts still being provisioned count toward the user's account limit.
+        // Concurrent reservations by the same user conflict on rewriting the user record, so they can't exceed the
+        // limit together. Reservations of the user whose provisioning failed for good are released, as the user is
+        // creating another account instead.
+        for _ in 0..MAX_ACCOUNT_ID_ALLOCATION_ATTEMPTS {
+            let account_id = rand::thread_rng()
+                .gen_range::<u64, _>(1_000_000_000..=9_999_999_999)
+                .to_string();
+
+            let res = accounts_db()
+                .await?
+                .query(BeginStatement::default())
+                .query("UPDATE $user SET email = email RETURN NONE;")
+                .query("DELETE account_id_reservation WHERE created_by = $user AND status = 'failed' AND !record::exists(type::thing('account', record::id(id))) RETURN NONE;")
+                .query("IF (SELECT VALUE count(->has_access->(account WHERE deleted_at IS NONE)) FROM ONLY $user) + count(SELECT 1 FROM account_id_reservation WHERE created_by = $user AND status = 'provisioning') >= $user_account_limit { THROW $user_account_limit_exceeded };")
+                .query(
+                    "IF record::exists($account) OR record::exists($reservation) {
+                        false
+                    } ELSE {
+                        CREATE $reservation CONTENT { endpoint: $endpoint, service_data_location: $service_data_location, created_by: $user } RETURN NONE;
+                        true
+                    };",
+                )
+                .query(CommitStatement::default())
+                .bind((
+                    "account",
+                    surrealdb::sql::Thing::from((
+                        "account",
+                        surrealdb::sql::Id::String(account_id.clone()),
+                    )),
+                ))
+                .bind((
+                    "reservation",
+                    surrealdb::sql::Thing::from((
+                        "account_id_reservation",
+                        surrealdb::sql::Id::String(account_id.clone()),
+                    )),
+                ))
+                .bind(("endpoint", endpoint.to_string()))
+                .bind(("service_data_location", service_data_location.clone()))
+                .bind(("user", surrealdb::sql::Thing::from(self)))
+                .bind(("user_account_limit", Env::user_account_limit()))
+                .bind(("user_account_limit_exceeded", USER_ACCOUNT_LIMIT_EXCEEDED))
+                .await?;
+
+            // SurrealDB returns no results for `BEGIN` and `COMMIT`, so the allocation is the fourth result
+            let allocated = match res.check_first_real_error() {
+                Err(surrealdb::Error::Db(surrealdb::error::Db::Thrown(message)))
+                    if message == USER_ACCOUNT_LIMIT_EXCEEDED =>
+                {
+                    conflict!("{USER_ACCOUNT_LIMIT_EXCEEDED}");
+                }
+                res => res?
+                    .take::<Option<bool>>(3)?
+                    .ok_or_else(|| anyhow!("Failed to query whether account ID was allocated"))?,
+            };
+
+            if allocated {
+                info!(account_id, "Allocated new account ID");
+
+                return Ok(account_id);
+            }
+
+            warn!(
+                account_id,
+                "Generated account ID is already in use, retrying"
+            );
         }
 
-        let account_id = rand::thread_rng()
-            .gen_range::<u64, _>(1_000_000_000..=9_999_999_999)
-            .to_string();
-
-        info!(account_id, "Generated new account ID");
-
-        Ok(account_id)
+        Err(anyhow!(
+            "Failed to allocate an unused account ID after {MAX_ACCOUNT_ID_ALLOCATION_ATTEMPTS} attempts"
+        )
+        .into())
     }
 
     #[instrument(err)]
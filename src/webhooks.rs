@@ -0,0 +1,417 @@
+// Accounts register webhooks to be notified when ingested reports contain resources or event types the account has not
+// seen before. Report ingestion detects new resources and event types and queues a `webhook_delivery` for every webhook
+// within the report's transaction, so notifications are only sent for reports that were ingested. A periodic worker
+// delivers queued notifications and retries failed deliveries with exponential backoff.
+//
+// Notifications are signed with the webhook secret, which is only returned when the webhook is created. The
+// `X-Archodex-Signature` header holds `sha256=<hex encoded HMAC-SHA256>` of `<X-Archodex-Timestamp>.<body>`. Deliveries
+// are at least once, so receivers should deduplicate notifications by their `delivery_id`. Redirects are not followed,
+// so endpoints must respond with a success status themselves.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{Extension, Json, extract::Path, http::header::CONTENT_TYPE};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac as _};
+use rand::Rng as _;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+use archodex_error::{
+    anyhow::{Context as _, bail, ensure},
+    bad_request, not_found,
+};
+
+use crate::{
+    Result,
+    account::Account,
+    accounts::for_each_account_periodically,
+    auth::DashboardAuth,
+    db::{InstrumentedQuery, QueryCheckFirstRealError as _},
+    outbound::{self, AllowedAddresses},
+    resource::ResourceId,
+    surrealdb_deserializers,
+    user::User,
+};
+
+const WEBHOOK_DELIVERY_INTERVAL: Duration = Duration::from_secs(30);
+const WEBHOOK_DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const WEBHOOK_DELIVERY_BATCH_SIZE: u32 = 100;
+const WEBHOOK_DELIVERY_MAX_ATTEMPTS: u32 = 8;
+
+// Claimed deliveries are not claimed again by other backend instances until this lease expires
+const WEBHOOK_DELIVERY_LEASE: &str = "5m";
+
+const WEBHOOK_DELIVERY_RETENTION: &str = "7d";
+
+// Webhooks of archodex.com accounts may only be sent to public addresses. Self-hosted instances may also send them to
+// their own network.
+#[cfg(feature = "archodex-com")]
+const WEBHOOK_ALLOWED_SCHEMES: &[&str] = &["https"];
+#[cfg(not(feature = "archodex-com"))]
+const WEBHOOK_ALLOWED_SCHEMES: &[&str] = &["https", "http"];
+const WEBHOOK_ALLOWED_ADDRESSES: AllowedAddresses = if cfg!(feature = "archodex-com") {
+    AllowedAddresses::Public
+} else {
+    AllowedAddresses::PublicAndPrivate
+};
+
+#[derive(Debug, Deserialize, JsonSchema, Serialize)]
+pub(crate) struct Webhook {
+    #[serde(deserialize_with = "surrealdb_deserializers::string::deserialize")]
+    id: String,
+    url: String,
+    description: Option<String>,
+    created_at: DateTime<Utc>,
+    created_by: User,
+}
+
+fn webhook_thing(webhook_id: &str) -> surrealdb::sql::Thing {
+    surrealdb::sql::Thing::from(("webhook", webhook_id))
+}
+
+fn webhook_id(params: &HashMap<String, String>) -> Result<String> {
+    let Some(webhook_id) = params.get("webhook_id") else {
+        bail!("Missing webhook_id");
+    };
+
+    Ok(webhook_id.clone())
+}
+
+// Managed accounts may only notify HTTPS endpoints. Self-hosted instances may notify plain HTTP endpoints on their own
+// network.
+fn validate_url(url: &str) -> Result<()> {
+    if let Err(err) = outbound::check_url(url, WEBHOOK_ALLOWED_SCHEMES, WEBHOOK_ALLOWED_ADDRESSES) {
+        bad_request!("Invalid webhook URL: {err}");
+    }
+
+    Ok(())
+}
+
+// Adds statements to a report ingestion transaction that record which of the report's resources and event types have
+// not been seen before. Must be added before the report's resources and events are upserted.
+pub(crate) fn detect_new_resources_and_event_types(
+    query: InstrumentedQuery<'_>,
+    resources: Vec<surrealdb::sql::Value>,
+    event_types: Vec<String>,
+) -> InstrumentedQuery<'_> {
+    query
+        .query("LET $webhooks = SELECT VALUE id FROM webhook;")
+        .query("LET $webhook_new_resources = IF array::is_empty($webhooks) { [] } ELSE { $webhook_resources.distinct().filter(|$resource| !record::exists($resource)) };")
+        .query("LET $webhook_new_event_types = IF array::is_empty($webhooks) { [] } ELSE { $webhook_event_types.distinct().filter(|$type| array::is_empty(SELECT VALUE id FROM event WHERE type == $type LIMIT 1)) };")
+        .bind(("webhook_resources", surrealdb::sql::Array::from(resources)))
+        .bind(("webhook_event_types", event_types))
+}
+
+// Adds a statement to a report ingestion transaction that queues a notification for every webhook if the report
+// contained new resources or event types
+pub(crate) fn queue_notifications(query: InstrumentedQuery<'_>) -> InstrumentedQuery<'_> {
+    query.query(
+        "IF !array::is_empty($webhook_new_resources) OR !array::is_empty($webhook_new_event_types) {
+            FOR $webhook IN $webhooks {
+                CREATE type::thing('webhook_delivery', <string> rand::uuid::v7()) CONTENT {
+                    webhook: $webhook,
+                    payload: {
+                        detected_at: time::now(),
+                        new_resources: $webhook_new_resources.map(|$resource| record::id($resource)),
+                        new_event_types: $webhook_new_event_types,
+                    },
+                } RETURN NONE;
+            };
+        };",
+    )
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct ListWebhooksResponse {
+    webhooks: Vec<Webhook>,
+}
+
+#[instrument(err, skip_all)]
+pub(crate) async fn list_webhooks(
+    Extension(account): Extension<Account>,
+) -> Result<Json<ListWebhooksResponse>> {
+    let webhooks = account
+        .resources_db()
+        .await?
+        .query("SELECT * OMIT secret FROM webhook ORDER BY id")
+        .await
+        .context("Failed to submit query to list webhooks")?
+        .check_first_real_error()
+        .context("Failed to list webhooks")?
+        .take::<Vec<Webhook>>(0)?;
+
+    Ok(Json(ListWebhooksResponse { webhooks }))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct WebhookRequest {
+    url: String,
+    description: Option<String>,
+}
+
+#[derive(JsonSchema, Serialize)]
+pub(crate) struct CreateWebhookResponse {
+    webhook: Webhook,
+    secret: String,
+}
+
+// The webhook secret is only available in the response to this request
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn create_webhook(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Json(req): Json<WebhookRequest>,
+) -> Result<Json<CreateWebhookResponse>> {
+    auth.ensure_account_admin(account.id()).await?;
+
+    validate_url(&req.url)?;
+
+    let webhook_id = Uuid::now_v7().simple().to_string();
+    let secret = hex::encode(rand::thread_rng().r#gen::<[u8; 32]>());
+
+    let webhook = account
+        .resources_db()
+        .await?
+        .query("CREATE $webhook CONTENT { url: $url, description: $description, secret: $secret, created_by: $created_by } RETURN id, url, description, created_at, created_by")
+        .bind(("webhook", webhook_thing(&webhook_id)))
+        .bind(("url", req.url))
+        .bind(("description", req.description))
+        .bind(("secret", secret.clone()))
+        .bind(("created_by", surrealdb::sql::Thing::from(auth.principal())))
+        .await
+        .context("Failed to submit query to create webhook")?
+        .check_first_real_error()
+        .context("Failed to create webhook")?
+        .take::<Option<Webhook>>(0)?
+        .expect("Create webhook query should return a webhook instance");
+
+    info!(webhook_id, "Created webhook");
+
+    Ok(Json(CreateWebhookResponse { webhook, secret }))
+}
+
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn update_webhook(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Path(params): Path<HashMap<String, String>>,
+    Json(req): Json<WebhookRequest>,
+) -> Result<Json<Webhook>> {
+    auth.ensure_account_admin(account.id()).await?;
+
+    let webhook_id = webhook_id(&params)?;
+
+    validate_url(&req.url)?;
+
+    let Some(webhook) = account
+        .resources_db()
+        .await?
+        .query("UPDATE $webhook SET url = $url, description = $description RETURN id, url, description, created_at, created_by")
+        .bind(("webhook", webhook_thing(&webhook_id)))
+        .bind(("url", req.url))
+        .bind(("description", req.description))
+        .await
+        .context("Failed to submit query to update webhook")?
+        .check_first_real_error()
+        .context("Failed to update webhook")?
+        .take::<Option<Webhook>>(0)?
+    else {
+        not_found!("Webhook not found");
+    };
+
+    info!(webhook_id, "Updated webhook");
+
+    Ok(Json(webhook))
+}
+
+// Queued deliveries of the webhook are deleted with it
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn delete_webhook(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<()>> {
+    auth.ensure_account_admin(account.id()).await?;
+
+    let webhook_id = webhook_id(&params)?;
+
+    let deleted = account
+        .resources_db()
+        .await?
+        .query("DELETE webhook_delivery WHERE webhook == $webhook")
+        .query("DELETE $webhook RETURN BEFORE")
+        .bind(("webhook", webhook_thing(&webhook_id)))
+        .await
+        .context("Failed to submit query to delete webhook")?
+        .check_first_real_error()
+        .context("Failed to delete webhook")?
+        .take::<Vec<surrealdb::sql::Object>>(1)?;
+
+    if deleted.is_empty() {
+        not_found!("Webhook not found");
+    }
+
+    info!(webhook_id, "Deleted webhook");
+
+    Ok(Json(()))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct WebhookPayload {
+    detected_at: DateTime<Utc>,
+    new_resources: Vec<ResourceId>,
+    new_event_types: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WebhookDelivery {
+    #[serde(deserialize_with = "surrealdb_deserializers::string::deserialize")]
+    id: String,
+    url: String,
+    secret: String,
+    attempts: u32,
+    payload: WebhookPayload,
+}
+
+#[derive(Serialize)]
+struct WebhookNotification<'a> {
+    delivery_id: &'a str,
+    account_id: &'a str,
+    #[serde(flatten)]
+    payload: &'a WebhookPayload,
+}
+
+/// Periodically delivers queued webhook notifications of every account. Deliveries are claimed before they are sent, so
+/// multiple backend instances may run this concurrently.
+pub async fn deliver_periodically() {
+    let client = &outbound::client(WEBHOOK_DELIVERY_TIMEOUT, WEBHOOK_ALLOWED_ADDRESSES);
+
+    for_each_account_periodically(
+        WEBHOOK_DELIVERY_INTERVAL,
+        "webhook delivery",
+        |account| async move { deliver(client, &account).await },
+    )
+    .await;
+}
+
+#[instrument(err, skip_all, fields(account_id = account.id()))]
+async fn deliver(client: &reqwest::Client, account: &Account) -> Result<()> {
+    // Claimed deliveries are leased, so the database connection is not held while notifications are sent
+    let deliveries = account
+        .resources_db()
+        .await?
+        .query(format!("DELETE webhook_delivery WHERE (delivered_at ?? failed_at) < time::now() - {WEBHOOK_DELIVERY_RETENTION};"))
+        .query(format!("LET $claimed = UPDATE (SELECT VALUE id FROM webhook_delivery WHERE delivered_at IS NONE AND failed_at IS NONE AND next_attempt_at <= time::now() ORDER BY id LIMIT {WEBHOOK_DELIVERY_BATCH_SIZE}) SET next_attempt_at = time::now() + {WEBHOOK_DELIVERY_LEASE} WHERE next_attempt_at <= time::now() RETURN VALUE id;"))
+        .query("SELECT id, webhook.url AS url, webhook.secret AS secret, attempts, payload FROM $claimed ORDER BY id;")
+        .await
+        .context("Failed to submit query to claim webhook deliveries")?
+        .check_first_real_error()
+        .context("Failed to claim webhook deliveries")?
+        .take::<Vec<WebhookDelivery>>(2)?;
+
+    for delivery in deliveries {
+        let result = send(client, account, &delivery).await;
+
+        let attempts = delivery.attempts + 1;
+
+        let db = account.resources_db().await?;
+        let query = match &result {
+            Ok(()) => db.query(
+                "UPDATE $delivery SET attempts = $attempts, delivered_at = time::now(), last_error = NONE RETURN NONE",
+            ),
+            Err(_) if attempts >= WEBHOOK_DELIVERY_MAX_ATTEMPTS => db.query(
+                "UPDATE $delivery SET attempts = $attempts, failed_at = time::now(), last_error = $error RETURN NONE",
+            ),
+            // Retries back off exponentially, starting at one minute
+            Err(_) => db
+                .query("UPDATE $delivery SET attempts = $attempts, next_attempt_at = time::now() + $backoff, last_error = $error RETURN NONE")
+                .bind((
+                    "backoff",
+                    surrealdb::sql::Duration::from(Duration::from_secs(60 << (attempts - 1))),
+                )),
+        };
+
+        query
+            .bind((
+                "delivery",
+                surrealdb::sql::Thing::from(("webhook_delivery", delivery.id.as_str())),
+            ))
+            .bind(("attempts", attempts))
+            .bind(("error", result.as_ref().err().map(|err| format!("{err:#}"))))
+            .await
+            .context("Failed to submit query to record webhook delivery attempt")?
+            .check_first_real_error()
+            .context("Failed to record webhook delivery attempt")?;
+
+        match result {
+            Ok(()) => info!(delivery_id = delivery.id, "Delivered webhook notification"),
+            Err(err) => warn!(
+                ?err,
+                delivery_id = delivery.id,
+                attempts,
+                "Failed to deliver webhook notification"
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+async fn send(
+    client: &reqwest::Client,
+    account: &Account,
+    delivery: &WebhookDelivery,
+) -> Result<()> {
+    let body = serde_json::to_vec(&WebhookNotification {
+        delivery_id: &delivery.id,
+        account_id: account.id(),
+        payload: &delivery.payload,
+    })?;
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("System time is before the Unix epoch")?
+        .as_secs()
+        .to_string();
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(delivery.secret.as_bytes())
+        .expect("HMAC can take a key of any size");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(&body);
+    let signature = hex::encode(mac.finalize().into_bytes());
+
+    // Host names are checked when they are resolved, but IP addresses must be checked here
+    let url = outbound::check_url(
+        &delivery.url,
+        WEBHOOK_ALLOWED_SCHEMES,
+        WEBHOOK_ALLOWED_ADDRESSES,
+    )?;
+
+    let response = client
+        .post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .header("X-Archodex-Delivery", &delivery.id)
+        .header("X-Archodex-Timestamp", &timestamp)
+        .header("X-Archodex-Signature", format!("sha256={signature}"))
+        .body(body)
+        .send()
+        .await
+        .context("Failed to send webhook notification")?;
+
+    ensure!(
+        response.status().is_success(),
+        "Webhook endpoint responded with status {}",
+        response.status()
+    );
+
+    Ok(())
+}
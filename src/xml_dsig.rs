@@ -0,0 +1,531 @@
+// Verification of enveloped XML signatures, which SAML identity providers sign responses and assertions with. Only what
+// identity providers use in practice is supported: a single reference to the signed element by its `ID`, the enveloped
+// signature and exclusive canonicalization (without comments) transforms, SHA-256 or SHA-512 digests, and RSA
+// signatures. Signatures are verified with the certificates configured for the identity provider, never with keys
+// embedded in the signature.
+//
+// Verified elements must be the only element of the document with their ID, so a signed element can't be substituted by
+// another element with the same ID elsewhere in the document. Callers must only read the verified element, see `saml`.
+
+use std::collections::HashMap;
+
+use base64::{Engine as _, prelude::BASE64_STANDARD};
+use openssl::{hash::MessageDigest, pkey::Id, sign::Verifier, x509::X509};
+use roxmltree::{Node, NodeType};
+use sha2::{Digest as _, Sha256, Sha512};
+
+use archodex_error::anyhow::{self, Context as _, bail, ensure};
+
+pub(crate) const DSIG_NS: &str = "http://www.w3.org/2000/09/xmldsig#";
+
+const EXC_C14N: &str = "http://www.w3.org/2001/10/xml-exc-c14n#";
+const ENVELOPED_SIGNATURE: &str = "http://www.w3.org/2000/09/xmldsig#enveloped-signature";
+const RSA_SHA256: &str = "http://www.w3.org/2001/04/xmldsig-more#rsa-sha256";
+const RSA_SHA512: &str = "http://www.w3.org/2001/04/xmldsig-more#rsa-sha512";
+const SHA256: &str = "http://www.w3.org/2001/04/xmlenc#sha256";
+const SHA512: &str = "http://www.w3.org/2001/04/xmlenc#sha512";
+
+// Prefix of the default namespace in `InclusiveNamespaces` prefix lists
+const DEFAULT_NAMESPACE_PREFIX: &str = "#default";
+
+pub(crate) fn child<'a, 'input>(
+    node: Node<'a, 'input>,
+    namespace: &str,
+    name: &str,
+) -> Option<Node<'a, 'input>> {
+    node.children()
+        .find(|child| child.is_element() && child.has_tag_name((namespace, name)))
+}
+
+// Text content of an element, which must be a single text node. `Node::text` only returns the first text child, so an
+// element split by a comment, like `<NameID>admin@example.com<!---->.evil.com</NameID>`, would be read differently than
+// it was signed, as comments are removed when canonicalizing. Elements with any other children are rejected instead.
+pub(crate) fn text<'a>(element: Node<'a, '_>) -> anyhow::Result<&'a str> {
+    let mut children = element.children();
+
+    match (children.next(), children.next()) {
+        (None, _) => Ok(""),
+        (Some(child), None) if child.is_text() => Ok(child.text().unwrap_or_default()),
+        _ => bail!(
+            "Element {:?} must only contain text",
+            element.tag_name().name()
+        ),
+    }
+}
+
+fn signature<'a, 'input>(element: Node<'a, 'input>) -> Option<Node<'a, 'input>> {
+    child(element, DSIG_NS, "Signature")
+}
+
+// Whether the element has an enveloped signature
+pub(crate) fn is_signed(element: Node) -> bool {
+    signature(element).is_some()
+}
+
+// Verifies the enveloped signature of the element against any of the certificates
+pub(crate) fn verify(element: Node, certificates: &[X509]) -> anyhow::Result<()> {
+    let Some(signature) = signature(element) else {
+        bail!("Element is not signed");
+    };
+
+    let Some(id) = element.attribute("ID") else {
+        bail!("Signed element has no ID");
+    };
+
+    ensure!(
+        element
+            .document()
+            .descendants()
+            .filter(|node| node.attribute("ID") == Some(id))
+            .count()
+            == 1,
+        "Signed element ID {id:?} is not unique"
+    );
+
+    let Some(signed_info) = signature
+        .first_element_child()
+        .filter(|node| node.has_tag_name((DSIG_NS, "SignedInfo")))
+    else {
+        bail!("Signature is missing SignedInfo");
+    };
+
+    let Some(signature_value) = signed_info
+        .next_sibling_element()
+        .filter(|node| node.has_tag_name((DSIG_NS, "SignatureValue")))
+    else {
+        bail!("Signature is missing SignatureValue");
+    };
+
+    let signed_info_prefixes = canonicalization_prefixes(
+        child(signed_info, DSIG_NS, "CanonicalizationMethod")
+            .context("SignedInfo is missing CanonicalizationMethod")?,
+    )?;
+
+    let message_digest = match child(signed_info, DSIG_NS, "SignatureMethod")
+        .and_then(|method| method.attribute("Algorithm"))
+    {
+        Some(RSA_SHA256) => MessageDigest::sha256(),
+        Some(RSA_SHA512) => MessageDigest::sha512(),
+        algorithm => bail!("Unsupported signature method {algorithm:?}"),
+    };
+
+    let mut references = signed_info
+        .children()
+        .filter(|node| node.has_tag_name((DSIG_NS, "Reference")));
+    let (Some(reference), None) = (references.next(), references.next()) else {
+        bail!("SignedInfo must have exactly one Reference");
+    };
+
+    ensure!(
+        reference.attribute("URI") == Some(&format!("#{id}")),
+        "Signature does not reference the signed element"
+    );
+
+    let mut enveloped = false;
+    let mut reference_prefixes = None;
+    for transform in child(reference, DSIG_NS, "Transforms")
+        .map(|transforms| {
+            transforms
+                .children()
+                .filter(Node::is_element)
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default()
+    {
+        match transform.attribute("Algorithm") {
+            Some(ENVELOPED_SIGNATURE) => enveloped = true,
+            Some(EXC_C14N) => reference_prefixes = Some(canonicalization_prefixes(transform)?),
+            algorithm => bail!("Unsupported transform {algorithm:?}"),
+        }
+    }
+
+    ensure!(enveloped, "Signature is not an enveloped signature");
+    let Some(reference_prefixes) = reference_prefixes else {
+        bail!("Signed element is not canonicalized with exclusive canonicalization");
+    };
+
+    let canonical_element = canonicalize(element, Some(signature), &reference_prefixes);
+
+    let digest = match child(reference, DSIG_NS, "DigestMethod")
+        .and_then(|method| method.attribute("Algorithm"))
+    {
+        Some(SHA256) => Sha256::digest(&canonical_element).to_vec(),
+        Some(SHA512) => Sha512::digest(&canonical_element).to_vec(),
+        algorithm => bail!("Unsupported digest method {algorithm:?}"),
+    };
+
+    let expected_digest = decode_base64(
+        child(reference, DSIG_NS, "DigestValue").context("Reference is missing DigestValue")?,
+    )
+    .context("Failed to decode DigestValue")?;
+
+    ensure!(
+        digest == expected_digest,
+        "Digest of signed element does not match"
+    );
+
+    let canonical_signed_info = canonicalize(signed_info, None, &signed_info_prefixes);
+    let signature_value =
+        decode_base64(signature_value).context("Failed to decode SignatureValue")?;
+
+    for certificate in certificates {
+        let public_key = certificate
+            .public_key()
+            .context("Failed to read certificate public key")?;
+
+        if public_key.id() != Id::RSA {
+            continue;
+        }
+
+        let mut verifier = Verifier::new(message_digest, &public_key)
+            .context("Failed to create signature verifier")?;
+
+        if verifier
+            .verify_oneshot(&signature_value, canonical_signed_info.as_bytes())
+            .unwrap_or(false)
+        {
+            return Ok(());
+        }
+    }
+
+    bail!("Signature does not verify with any configured certificate")
+}
+
+// Base64 values of signatures may be wrapped over multiple lines
+fn decode_base64(node: Node) -> anyhow::Result<Vec<u8>> {
+    let value = text(node)?
+        .chars()
+        .filter(|c| !c.is_ascii_whitespace())
+        .collect::<String>();
+
+    Ok(BASE64_STANDARD.decode(value)?)
+}
+
+// Returns the prefixes of the `InclusiveNamespaces` of an exclusive canonicalization method or transform
+fn canonicalization_prefixes<'a>(method: Node<'a, '_>) -> anyhow::Result<Vec<&'a str>> {
+    ensure!(
+        method.attribute("Algorithm") == Some(EXC_C14N),
+        "Unsupported canonicalization method {:?}",
+        method.attribute("Algorithm")
+    );
+
+    Ok(child(method, EXC_C14N, "InclusiveNamespaces")
+        .and_then(|inclusive_namespaces| inclusive_namespaces.attribute("PrefixList"))
+        .map(|prefix_list| prefix_list.split_ascii_whitespace().collect())
+        .unwrap_or_default())
+}
+
+// Qualified name of an element or attribute as written in the document, and its prefix
+fn qualified_name<'input>(node: Node<'_, 'input>) -> (&'input str, Option<&'input str>) {
+    let input = node.document().input_text();
+
+    let name = input[node.range()]
+        .trim_start_matches('<')
+        .split(|c: char| c.is_ascii_whitespace() || c == '>' || c == '/')
+        .next()
+        .unwrap_or_default();
+
+    (name, name.split_once(':').map(|(prefix, _)| prefix))
+}
+
+// Canonicalizes the element with exclusive XML canonicalization without comments, leaving out the excluded descendant,
+// e.g. the enveloped signature. Namespaces of the inclusive prefixes are rendered wherever they are in scope, rather
+// than only where they are used.
+fn canonicalize(element: Node, excluded: Option<Node>, inclusive_prefixes: &[&str]) -> String {
+    let mut output = String::new();
+
+    write_element(
+        &mut output,
+        element,
+        excluded,
+        inclusive_prefixes,
+        &HashMap::new(),
+    );
+
+    output
+}
+
+fn write_element<'a>(
+    output: &mut String,
+    element: Node<'a, '_>,
+    excluded: Option<Node>,
+    inclusive_prefixes: &[&str],
+    rendered: &HashMap<&'a str, &'a str>,
+) {
+    let input = element.document().input_text();
+    let (name, prefix) = qualified_name(element);
+
+    let attributes = element
+        .attributes()
+        .map(|attribute| {
+            let name = &input[attribute.range_qname()];
+            (
+                attribute,
+                name,
+                name.split_once(':').map(|(prefix, _)| prefix),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    // Prefixes visibly used by the element and its attributes, and inclusive prefixes in scope. The empty prefix is the
+    // default namespace.
+    let mut prefixes = vec![prefix.unwrap_or_default()];
+    prefixes.extend(attributes.iter().filter_map(|(_, _, prefix)| *prefix));
+    prefixes.extend(
+        inclusive_prefixes
+            .iter()
+            .map(|prefix| {
+                if *prefix == DEFAULT_NAMESPACE_PREFIX {
+                    ""
+                } else {
+                    prefix
+                }
+            })
+            .filter(|prefix| {
+                prefix.is_empty() || element.lookup_namespace_uri(Some(prefix)).is_some()
+            }),
+    );
+    prefixes.sort_unstable();
+    prefixes.dedup();
+
+    let mut rendered = rendered.clone();
+    let mut namespaces = Vec::new();
+    for prefix in prefixes {
+        if prefix == "xml" {
+            continue;
+        }
+
+        let uri = element
+            .lookup_namespace_uri((!prefix.is_empty()).then_some(prefix))
+            .unwrap_or_default();
+
+        // An empty default namespace is only declared to undeclare a default namespace rendered by an ancestor
+        let previous = rendered.get(prefix).copied().unwrap_or_default();
+        if previous != uri {
+            namespaces.push((prefix, uri));
+            rendered.insert(prefix, uri);
+        }
+    }
+
+    let mut attributes = attributes
+        .into_iter()
+        .map(|(attribute, name, _)| {
+            (
+                attribute.namespace().unwrap_or_default(),
+                attribute.name(),
+                name,
+                attribute.value(),
+            )
+        })
+        .collect::<Vec<_>>();
+    attributes.sort_unstable_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+
+    output.push('<');
+    output.push_str(name);
+
+    for (prefix, uri) in namespaces {
+        if prefix.is_empty() {
+            output.push_str(" xmlns=\"");
+        } else {
+            output.push_str(" xmlns:");
+            output.push_str(prefix);
+            output.push_str("=\"");
+        }
+        escape_attribute(output, uri);
+        output.push('"');
+    }
+
+    for (_, _, name, value) in attributes {
+        output.push(' ');
+        output.push_str(name);
+        output.push_str("=\"");
+        escape_attribute(output, value);
+        output.push('"');
+    }
+
+    output.push('>');
+
+    for child in element.children() {
+        if excluded.is_some_and(|excluded| excluded == child) {
+            continue;
+        }
+
+        match child.node_type() {
+            NodeType::Element => {
+                write_element(output, child, excluded, inclusive_prefixes, &rendered);
+            }
+            NodeType::Text => escape_text(output, child.text().unwrap_or_default()),
+            NodeType::PI => {
+                if let Some(pi) = child.pi() {
+                    output.push_str("<?");
+                    output.push_str(pi.target);
+                    if let Some(value) = pi.value {
+                        output.push(' ');
+                        output.push_str(value);
+                    }
+                    output.push_str("?>");
+                }
+            }
+            NodeType::Comment | NodeType::Root => {}
+        }
+    }
+
+    output.push_str("</");
+    output.push_str(name);
+    output.push('>');
+}
+
+fn escape_text(output: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            '\r' => output.push_str("&#xD;"),
+            c => output.push(c),
+        }
+    }
+}
+
+fn escape_attribute(output: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '"' => output.push_str("&quot;"),
+            '\t' => output.push_str("&#x9;"),
+            '\n' => output.push_str("&#xA;"),
+            '\r' => output.push_str("&#xD;"),
+            c => output.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::x509::X509;
+    use roxmltree::Document;
+
+    use super::{child, text, verify};
+
+    // Fixtures are shaped like responses of the named IdPs. They were canonicalized with xmllint and signed with
+    // openssl rather than by this module, so canonicalization is checked against an independent implementation.
+    const OKTA_RESPONSE: &str = include_str!("../fixtures/saml/okta_signed_assertion.xml");
+    const KEYCLOAK_RESPONSE: &str = include_str!("../fixtures/saml/keycloak_signed_response.xml");
+    const ASSERTION_NS: &str = "urn:oasis:names:tc:SAML:2.0:assertion";
+
+    fn certificate(pem: &str) -> Vec<X509> {
+        vec![X509::from_pem(pem.as_bytes()).unwrap()]
+    }
+
+    fn idp_certificate() -> Vec<X509> {
+        certificate(include_str!("../fixtures/saml/idp.pem"))
+    }
+
+    #[test]
+    fn signed_assertion() {
+        let document = Document::parse(OKTA_RESPONSE).unwrap();
+        let assertion = child(document.root_element(), ASSERTION_NS, "Assertion").unwrap();
+
+        verify(assertion, &idp_certificate()).unwrap();
+        assert!(verify(document.root_element(), &idp_certificate()).is_err());
+    }
+
+    #[test]
+    fn signed_response() {
+        let document = Document::parse(KEYCLOAK_RESPONSE).unwrap();
+
+        verify(document.root_element(), &idp_certificate()).unwrap();
+    }
+
+    #[test]
+    fn other_certificate() {
+        let document = Document::parse(OKTA_RESPONSE).unwrap();
+        let assertion = child(document.root_element(), ASSERTION_NS, "Assertion").unwrap();
+
+        assert!(
+            verify(
+                assertion,
+                &certificate(include_str!("../fixtures/saml/other.pem"))
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn modified_content() {
+        for (response, original, modified) in [
+            (
+                OKTA_RESPONSE,
+                ">jane.doe@example.com</saml2:NameID>",
+                ">admin@example.com</saml2:NameID>",
+            ),
+            (
+                KEYCLOAK_RESPONSE,
+                "<samlp:StatusCode Value=\"urn:oasis:names:tc:SAML:2.0:status:Success\"/>\n",
+                "<samlp:StatusCode Value=\"urn:oasis:names:tc:SAML:2.0:status:Success\"/>\n\n",
+            ),
+        ] {
+            let response = response.replacen(original, modified, 1);
+            let document = Document::parse(&response).unwrap();
+            let root = document.root_element();
+            let signed = child(root, ASSERTION_NS, "Assertion")
+                .filter(|assertion| super::is_signed(*assertion))
+                .unwrap_or(root);
+
+            assert!(verify(signed, &idp_certificate()).is_err());
+        }
+    }
+
+    #[test]
+    fn duplicate_id() {
+        let response = OKTA_RESPONSE.replacen(
+            "</saml2p:Response>",
+            "<saml2p:Extensions ID=\"id1734021984612345678901\"/></saml2p:Response>",
+            1,
+        );
+        let document = Document::parse(&response).unwrap();
+        let assertion = child(document.root_element(), ASSERTION_NS, "Assertion").unwrap();
+
+        assert!(verify(assertion, &idp_certificate()).is_err());
+    }
+
+    // Comments are removed when canonicalizing, so they don't invalidate signatures, but elements split by them must
+    // not be read
+    #[test]
+    fn comment_in_text() {
+        let response = OKTA_RESPONSE.replacen(
+            ">jane.doe@example.com</saml2:NameID>",
+            ">jane.doe@<!---->example.com</saml2:NameID>",
+            1,
+        );
+        let document = Document::parse(&response).unwrap();
+        let assertion = child(document.root_element(), ASSERTION_NS, "Assertion").unwrap();
+        let name_id = child(
+            child(assertion, ASSERTION_NS, "Subject").unwrap(),
+            ASSERTION_NS,
+            "NameID",
+        )
+        .unwrap();
+
+        verify(assertion, &idp_certificate()).unwrap();
+        assert!(text(name_id).is_err());
+    }
+
+    #[test]
+    fn element_text() {
+        for (xml, expected) in [
+            ("<a>text</a>", Some("text")),
+            ("<a/>", Some("")),
+            ("<a>te<![CDATA[xt]]></a>", Some("text")),
+            ("<a>te<!---->xt</a>", None),
+            ("<a>te<?pi?>xt</a>", None),
+            ("<a>te<b/>xt</a>", None),
+            ("<a><b>text</b></a>", None),
+        ] {
+            let document = Document::parse(xml).unwrap();
+
+            assert_eq!(text(document.root_element()).ok(), expected, "{xml} text");
+        }
+    }
+}
@@ -0,0 +1,695 @@
+// Generated from the API response types of archodex-backend. Do not edit.
+
+export type AccountHealth = {
+  ingestion_allowed: boolean;
+  last_report_at: string | null;
+  report_api_keys: ReportApiKeyHealth[];
+  report_error_rate: number | null;
+  daily: DailyHealth[];
+  quotas: Quotas;
+  pending_report_api_key_requests: number;
+};
+
+export type AccountPublic = {
+  id: string;
+  name?: string | null;
+  default_environment?: string | null;
+};
+
+export type AccountRole = "admin" | "member" | "viewer";
+
+export type AccountSearchResponse = {
+  results: AccountSearchResult[];
+  next_cursor?: string | null;
+};
+
+export type AccountSearchResult = {
+  resource: Resource;
+  score: number;
+  highlights: SearchHighlight[];
+};
+
+export type AccountUsage = {
+  sampled_at: string | null;
+  resources: QuotaUtilization;
+  events: QuotaUtilization;
+  storage_bytes: QuotaUtilization;
+  reports_this_month: QuotaUtilization;
+};
+
+export type AgentStatus = {
+  id: string;
+  report_api_key_id: number;
+  name: string;
+  version: string;
+  platform: string | null;
+  capabilities: string[];
+  report_schema_version: number;
+  registered_at: string;
+  last_registered_at: string;
+  retired_at: string | null;
+  retired_by: User | null;
+  report_schema_outdated: boolean;
+};
+
+export type ApiToken = {
+  id: string;
+  name: string;
+  scopes: ApiTokenScope[];
+  created_at: string;
+  created_by: User;
+  expires_at: string;
+  last_used_at: string | null;
+};
+
+export type ApiTokenScope = "list" | "query" | "read_only";
+
+export type ArchiveResourceResponse = {
+  resources: number;
+};
+
+export type AuditLogEntry = {
+  id: string;
+  occurred_at: string;
+  actor: User;
+  action: string;
+  target?: Record<string, string> | null;
+  request: AuditRequest;
+};
+
+export type AuditLogRetention = {
+  days: number | null;
+  write_once: boolean;
+};
+
+export type AuditRequest = {
+  method: string;
+  path: string;
+  user_agent?: string | null;
+  forwarded_for?: string | null;
+  request_id?: string | null;
+};
+
+export type BlindSpotsResponse = {
+  resources: Resource[];
+};
+
+export type CoverageResponse = {
+  resources: number;
+  sensors: SensorCoverage[];
+};
+
+export type CreateApiTokenResponse = {
+  api_token: ApiToken;
+  token: string;
+};
+
+export type CreateInvitationResponse = {
+  invitation: Invitation;
+  invitation_token: string;
+};
+
+export type CreateSessionResponse = {
+  token: string;
+  expires_at: string;
+};
+
+export type CreateWebhookResponse = {
+  webhook: Webhook;
+  secret: string;
+};
+
+export type DailyHealth = {
+  day: string;
+  reports_ingested: number;
+  reports_failed: number;
+  peak_graph_size: number;
+};
+
+export type DeadLetterReport = {
+  id: string;
+  report_api_key_id: number;
+  agent_id: string | null;
+  error: string;
+  request_id: string | null;
+  failed_at: string;
+  last_failed_at: string;
+  attempts: number;
+  payload_bytes: number;
+  payload?: string | null;
+};
+
+export type DeleteResourcesResponse = {
+  resources: ResourceId[];
+  contains: number;
+  events: number;
+  dry_run: boolean;
+};
+
+export type Environment = {
+  name: string;
+  color?: string | null;
+  description?: string | null;
+  position: number;
+  created_at: string;
+};
+
+/**
+ * Machine-readable error codes returned in the `code` field of error responses. Agents use these to distinguish
+ * errors they should retry later from errors that require them to stop sending requests.
+ */
+export type ErrorCode = "quota_exceeded" | "scope_violation" | "account_suspended" | "ingestion_paused" | "schema_version_unsupported" | "storage_throttled" | "rate_limited" | "reauthentication_required" | "limit_exceeded" | "plan_quota_exceeded" | "read_only_standby" | "sequence_regression";
+
+export type Event = {
+  principal: ResourceId;
+  type: string;
+  resource: ResourceId;
+  principal_chains: PrincipalChainId[];
+  first_seen_at: string;
+  last_seen_at: string;
+  gone_at?: string | null;
+  observed_by?: Observation[];
+};
+
+export type EventRetention = {
+  days: number | null;
+};
+
+/**
+ * Limit exceeded by a request, returned in the `limit` field of `limit_exceeded` error responses
+ */
+export type ExceededLimit = {
+  /**
+   * Name of the limit, e.g. `report_body_bytes`
+   */
+  name: string;
+  /**
+   * Largest value allowed by the limit
+   */
+  limit: number;
+  /**
+   * Value of the request, if known
+   */
+  observed?: number | null;
+  /**
+   * How to bring the request within the limit
+   */
+  remediation: string;
+};
+
+export type ExchangeResponse = {
+  ingestion_token: string;
+  expires_at: string;
+};
+
+export type ExecuteOperationsResponse = {
+  results: OperationResult[];
+};
+
+export type ExportEncryptionKey = {
+  version: number;
+  recipient: string;
+  required: boolean;
+  created_at: string;
+};
+
+export type Finding = {
+  check: string;
+  severity: Severity;
+  description: string;
+  count: number;
+  examples: string[];
+};
+
+export type GetExportEncryptionKeyResponse = {
+  key: ExportEncryptionKey | null;
+};
+
+export type GetResponse = {
+  first_seen_at: string;
+  last_seen_at: string;
+};
+
+export type GlobalContainer = {
+  id: ResourceId;
+  contains: ResourceId;
+};
+
+export type GraphUpdate = {
+  resources: number;
+  events: number;
+  event_types: string[];
+  consistency_token: string;
+};
+
+export type ImportAwsConfigResponse = {
+  imported_resources: number;
+};
+
+export type ImportBundleResponse = {
+  ingested_reports: number;
+};
+
+export type ImportResponse = {
+  imported_resources: number;
+  skipped_resources: number;
+};
+
+export type IngestionPipelineConfig = {
+  processors: ProcessorConfig[];
+};
+
+export type IntegrityCheck = {
+  id: string;
+  status: IntegrityCheckStatus;
+  started_at: string;
+  started_by: User;
+  finished_at: string | null;
+  findings: Finding[];
+  error: string | null;
+};
+
+export type IntegrityCheckStatus = "running" | "completed" | "failed" | "interrupted";
+
+export type Invitation = {
+  id: string;
+  email: string;
+  role: AccountRole;
+  created_at: string;
+  created_by: User;
+  expires_at: string;
+};
+
+export type JobProgress = {
+  status: JobStatus;
+  percent: number;
+  step: string | null;
+};
+
+export type JobStatus = "pending" | "running" | "succeeded" | "failed";
+
+export type ListAccountsResponse = {
+  accounts: AccountPublic[];
+};
+
+export type ListAgentsResponse = {
+  agents: AgentStatus[];
+  current_report_schema_version: number;
+};
+
+export type ListApiTokensResponse = {
+  api_tokens: ApiToken[];
+};
+
+export type ListAuditLogResponse = {
+  entries: AuditLogEntry[];
+  next_cursor?: string | null;
+};
+
+export type ListDeadLetterReportsResponse = {
+  dead_letter_reports: DeadLetterReport[];
+};
+
+export type ListEnvironmentsResponse = {
+  environments: Environment[];
+};
+
+export type ListIntegrityChecksResponse = {
+  integrity_checks: IntegrityCheck[];
+};
+
+export type ListInvitationsResponse = {
+  invitations: Invitation[];
+};
+
+export type ListMembersResponse = {
+  members: Member[];
+};
+
+export type ListNotificationsResponse = {
+  notifications: Notification[];
+  next_cursor?: string | null;
+};
+
+export type ListProvidersResponse = {
+  providers: OidcProviderPublic[];
+};
+
+export type ListReportApiKeyRequestsResponse = {
+  report_api_key_requests: ReportApiKeyRequest[];
+};
+
+export type ListReportApiKeysResponse = {
+  report_api_keys: ReportApiKeyPublic[];
+};
+
+export type ListResourcesResponse = {
+  resources: Resource[];
+  next_cursor?: string | null;
+};
+
+export type ListStatementsResponse = {
+  statements: UsageStatement[];
+};
+
+export type ListTypePathsResponse = {
+  type_paths: TypePath[];
+};
+
+export type ListWebhooksResponse = {
+  webhooks: Webhook[];
+};
+
+export type MatchedField = "resource_id" | "attribute";
+
+export type Member = {
+  user: User;
+  role: AccountRole;
+  email?: string | null;
+  created_at: string;
+};
+
+export type Notification = {
+  id: string;
+  account_id?: string | null;
+  kind: NotificationKind;
+  message: string;
+  details?: Record<string, string>;
+  created_at: string;
+  read_at?: string | null;
+};
+
+export type NotificationKind = "integrity_check_finished" | "stale_resources_archived" | "report_api_key_requested";
+
+export type Observation = {
+  sensor: string;
+  first_seen_at: string;
+  last_seen_at: string;
+};
+
+export type OidcProviderPublic = {
+  name: string;
+  issuer: string;
+  client_id: string;
+  authorization_endpoint: string | null;
+};
+
+export type OperationResult = {
+  report_api_key: ReportApiKeyPublic;
+  report_api_key_value_reveal_token: string;
+  report_api_key_value_reveal_expires_at: string;
+  op: "create_report_api_key";
+} | {
+  op: "revoke_report_api_key";
+} | {
+  op: "set_resource_environments";
+} | {
+  resources: number;
+  op: "archive_resource";
+} | {
+  resources: number;
+  op: "unarchive_resource";
+} | {
+  op: "retire_agent";
+};
+
+export type PrincipalChainId = PrincipalChainIdPart[];
+
+export type PrincipalChainIdPart = {
+  id: ResourceId;
+  event?: string | null;
+};
+
+export type ProcessorConfig = {
+  attributes: string[];
+  processor: "drop_attributes";
+};
+
+/**
+ * Body of error responses
+ */
+export type PublicErrorMessage = {
+  message: string;
+  code?: ErrorCode | null;
+  retryable?: boolean | null;
+  limit?: ExceededLimit | null;
+  /**
+   * ID of the request, also returned in the `X-Request-ID` response header. Include it when reporting errors.
+   */
+  request_id?: string | null;
+};
+
+export type QueryResponse = {
+  resources: Resource[];
+  global_containers?: GlobalContainer[];
+  events?: Event[] | null;
+};
+
+export type QuotaUtilization = {
+  used: number;
+  limit: number | null;
+};
+
+export type Quotas = {
+  report_api_keys: QuotaUtilization;
+};
+
+export type RegisterAgentResponse = {
+  agent: AgentStatus;
+  current_report_schema_version: number;
+  report_limits: ReportLimits;
+};
+
+export type ReportApiKeyHealth = {
+  id: number;
+  description: string | null;
+  last_report_at: string | null;
+  last_sequence: number | null;
+  sequence_gaps: number;
+  sequence_regressions: number;
+  last_sequence_regression_at: string | null;
+};
+
+export type ReportApiKeyPublic = {
+  id: number;
+  description: string | null;
+  created_at: string | null;
+  revoked_at?: string | null;
+  restorable_until?: string | null;
+  expires_at?: string | null;
+  replacement_report_api_key_id?: number | null;
+};
+
+export type ReportApiKeyRequest = {
+  id: string;
+  description: string | null;
+  requested_at: string;
+  requested_by: User;
+  status: ReportApiKeyRequestStatus;
+  decided_at: string | null;
+  decided_by: User | null;
+  report_api_key_id: number | null;
+  value_retrieved_at: string | null;
+};
+
+export type ReportApiKeyRequestStatus = "pending" | "approved" | "rejected";
+
+export type ReportBatchResponse = {
+  batches: ReportBatchResult[];
+  consistency_token: string;
+};
+
+export type ReportBatchResult = {
+  index: number;
+  status: ReportBatchStatus;
+  error?: string | null;
+};
+
+export type ReportBatchStatus = "ingested" | "failed";
+
+export type ReportLimits = {
+  max_body_bytes: number;
+  max_resource_tree_depth: number;
+  max_resources: number;
+  max_events: number;
+  max_edges: number;
+  max_attribute_bytes: number;
+};
+
+export type ReportResponse = {
+  consistency_token: string;
+};
+
+export type ReportValidationChange = "create" | "update" | "remove";
+
+export type ReportValidationResponse = {
+  resources: ValidatedResource[];
+  events: ValidatedEvent[];
+};
+
+export type Resource = {
+  id: ResourceId;
+  environments?: string[];
+  tags?: string[];
+  first_seen_at?: string | null;
+  last_seen_at?: string | null;
+  archived_at?: string | null;
+  gone_at?: string | null;
+  shared_at?: string | null;
+  observed_by?: Observation[];
+  secret_hash?: SecretHash | null;
+};
+
+export type ResourceId = ResourceIdPart[];
+
+export type ResourceIdPart = {
+  type: string;
+  id: string;
+};
+
+export type RetrieveReportApiKeyValueResponse = {
+  report_api_key: ReportApiKeyPublic;
+  report_api_key_value: string;
+};
+
+export type RetryDeadLetterReportResponse = {
+  consistency_token: string;
+};
+
+export type RevealReportApiKeyValueResponse = {
+  report_api_key: ReportApiKeyPublic;
+  report_api_key_value: string;
+};
+
+export type RotateReportApiKeyResponse = {
+  report_api_key: ReportApiKeyPublic;
+  report_api_key_value_reveal_token: string;
+  report_api_key_value_reveal_expires_at: string;
+  rotated_report_api_key: ReportApiKeyPublic;
+};
+
+export type SamlAttributeMapping = {
+  user_id?: string | null;
+  email?: string | null;
+};
+
+export type SamlConfig = {
+  service_provider: SamlServiceProvider;
+  identity_provider: SamlIdentityProvider | null;
+};
+
+export type SamlIdentityProvider = {
+  entity_id: string;
+  sso_url: string;
+  certificates: string[];
+  attribute_mapping: SamlAttributeMapping;
+  default_role?: AccountRole | null;
+};
+
+export type SamlServiceProvider = {
+  entity_id: string;
+  acs_url: string;
+  metadata_url: string;
+  login_url: string;
+};
+
+export type SearchHighlight = {
+  field: MatchedField;
+  id_type?: string | null;
+  attribute?: string | null;
+  value: string;
+  ranges: number[][];
+};
+
+export type SearchResponse = {
+  results: SearchResult[];
+  failed_account_ids?: string[];
+};
+
+export type SearchResult = {
+  account_id: string;
+  resource: Resource;
+};
+
+export type SecretHash = {
+  algorithm: SecretHashAlgorithm;
+};
+
+export type SecretHashAlgorithm = "sha256" | "sha3_256" | "hmac_sha256";
+
+export type SensorCoverage = {
+  sensor: string;
+  report_api_key_id: number | null;
+  agent_name?: string | null;
+  resources: number;
+  events: number;
+  last_seen_at: string | null;
+};
+
+export type SetTagsResponse = {
+  tags: string[];
+};
+
+export type Severity = "error" | "warning";
+
+export type TraverseResponse = {
+  events: TraversedEvent[];
+  truncated: boolean;
+};
+
+export type TraversedEvent = {
+  depth: number;
+  principal: ResourceId;
+  type: string;
+  resource: ResourceId;
+  principal_chains: PrincipalChainId[];
+  first_seen_at: string;
+  last_seen_at: string;
+  gone_at?: string | null;
+  observed_by?: Observation[];
+};
+
+export type TypePath = {
+  path: string[];
+  resources: number;
+  first_seen_at: string;
+};
+
+export type UnreadNotificationCountResponse = {
+  unread: number;
+};
+
+export type UsageStatement = {
+  month: string;
+  period_start: string;
+  period_end: string;
+  reports_ingested: number;
+  resources_ingested: number;
+  events_recorded: number;
+  peak_graph_size: number;
+  created_at: string;
+};
+
+export type User = {
+  id: string;
+};
+
+export type ValidatedEvent = {
+  principal: ResourceId;
+  resource: ResourceId;
+  type: string;
+  change: ReportValidationChange;
+};
+
+export type ValidatedResource = {
+  id: ResourceId;
+  change: ReportValidationChange;
+};
+
+export type Webhook = {
+  id: string;
+  url: string;
+  description: string | null;
+  created_at: string;
+  created_by: User;
+};